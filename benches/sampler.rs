@@ -0,0 +1,36 @@
+//! Host-side sampling over a full vocabulary, one stream at a time.
+//! Run with `cargo bench --bench sampler`.
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use fastrand::Rng;
+use web_rwkv::sampler::{argmax, sample_top_p, softmax, Sampler};
+
+const NUM_VOCAB: usize = 65536;
+
+fn logits(rng: &mut Rng) -> Vec<f32> {
+    (0..NUM_VOCAB).map(|_| 10.0 * rng.f32() - 5.0).collect()
+}
+
+fn bench_sampler(c: &mut Criterion) {
+    let mut rng = Rng::with_seed(42);
+    let logits = logits(&mut rng);
+    let probs = softmax(&logits);
+
+    c.bench_function("softmax", |b| b.iter(|| softmax(black_box(&logits))));
+    c.bench_function("argmax", |b| b.iter(|| argmax(black_box(&probs))));
+    c.bench_function("top_p", |b| {
+        b.iter(|| sample_top_p(black_box(&probs), 0.5, 1.0, &mut rng))
+    });
+
+    // top-a needs the probabilities sorted, which is the path every sample took before
+    let sorted = Sampler {
+        top_a: f32::MIN_POSITIVE,
+        ..Default::default()
+    };
+    c.bench_function("top_p_sorted", |b| {
+        b.iter(|| sorted.sample(black_box(&probs), &mut rng))
+    });
+}
+
+criterion_group!(benches, bench_sampler);
+criterion_main!(benches);
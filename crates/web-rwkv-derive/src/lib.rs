@@ -1,6 +1,6 @@
 use proc_macro::TokenStream;
 use quote::quote;
-use syn::{parse_macro_input, Data, DeriveInput, Fields};
+use syn::{parse_macro_input, Data, DeriveInput, Fields, Index};
 
 #[proc_macro_derive(Deref)]
 pub fn derive_deref(input: TokenStream) -> TokenStream {
@@ -118,3 +118,95 @@ pub fn derive_kind(input: TokenStream) -> TokenStream {
     }
     .into()
 }
+
+/// Pack a struct into bytes laid out as WGSL lays it out in the uniform address space,
+/// padding the struct to a multiple of 16 bytes.
+/// All fields must implement `IntoBytes` and `UniformLayout`, which must be in scope.
+///
+/// The layout is checked at compile time: each field must start at a multiple of its WGSL alignment
+/// right after the one before it, so that padding is declared as fields, the same as in the kernel,
+/// instead of being inserted where it is easily overlooked.
+#[proc_macro_derive(IntoBytes)]
+pub fn derive_into_bytes(input: TokenStream) -> TokenStream {
+    let ast = parse_macro_input!(input as DeriveInput);
+    let name = &ast.ident;
+    let (impl_generics, ty_generics, where_clause) = ast.generics.split_for_impl();
+
+    let fields = match ast.data {
+        Data::Struct(data) => data.fields,
+        _ => panic!("Expected a struct"),
+    };
+    let (members, types): (Vec<_>, Vec<_>) = fields
+        .iter()
+        .enumerate()
+        .map(|(index, field)| {
+            let member = match &field.ident {
+                Some(ident) => quote! { #ident },
+                None => {
+                    let index = Index::from(index);
+                    quote! { #index }
+                }
+            };
+            (member, &field.ty)
+        })
+        .unzip();
+
+    // the layout of generic structs is checked once it is used with concrete types
+    let check = match ast.generics.params.is_empty() {
+        true => quote! { const _: usize = <#name as UniformLayout>::SIZE; },
+        false => quote! {},
+    };
+
+    quote! {
+        impl #impl_generics UniformLayout for #name #ty_generics #where_clause {
+            const ALIGN: usize = {
+                let mut align: usize = 16;
+                #(
+                    if <#types as UniformLayout>::ALIGN > align {
+                        align = <#types as UniformLayout>::ALIGN;
+                    }
+                )*
+                align
+            };
+            const SIZE: usize = {
+                let mut size: usize = 0;
+                #(
+                    assert!(
+                        <#types as UniformLayout>::ALIGN.is_power_of_two(),
+                        concat!("the alignment of field `", stringify!(#members), "` is not a power of two"),
+                    );
+                    assert!(
+                        size % <#types as UniformLayout>::ALIGN == 0,
+                        concat!(
+                            "field `", stringify!(#members), "` is misaligned for WGSL, ",
+                            "add padding fields before it or reorder the fields"
+                        ),
+                    );
+                    size += <#types as UniformLayout>::SIZE;
+                )*
+                size.next_multiple_of(<Self as UniformLayout>::ALIGN)
+            };
+        }
+
+        #check
+
+        impl #impl_generics IntoBytes for #name #ty_generics #where_clause {
+            fn into_bytes(self) -> Vec<u8> {
+                let mut bytes = Vec::with_capacity(<Self as UniformLayout>::SIZE);
+                #(
+                    let field = IntoBytes::into_bytes(self.#members);
+                    debug_assert_eq!(
+                        field.len(),
+                        <#types as UniformLayout>::SIZE,
+                        "field `{}` packs into a size other than its layout",
+                        stringify!(#members),
+                    );
+                    bytes.extend(field);
+                )*
+                bytes.resize(<Self as UniformLayout>::SIZE, 0);
+                bytes
+            }
+        }
+    }
+    .into()
+}
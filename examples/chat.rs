@@ -91,10 +91,7 @@ async fn create_context() -> Result<Context> {
     let adapter = instance
         .adapter(wgpu::PowerPreference::HighPerformance)
         .await?;
-    let context = ContextBuilder::new(adapter)
-        .with_default_pipelines()
-        .build()
-        .await?;
+    let context = ContextBuilder::new(adapter).build().await?;
     println!("{:#?}", context.adapter.get_info());
     Ok(context)
 }
@@ -136,6 +133,7 @@ where
                 .add_lora(Lora {
                     data: map.to_vec(),
                     blend: Default::default(),
+                    checksum: None,
                 })
                 .build()
         }
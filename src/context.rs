@@ -1,22 +1,43 @@
-use std::{borrow::Cow, collections::HashMap, str::FromStr, sync::Arc};
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    sync::{Arc, Condvar, Mutex, OnceLock, RwLock, Weak},
+    time::Duration,
+};
+
+#[cfg(feature = "dev")]
+use std::{
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
 
+#[cfg(feature = "dev")]
+use itertools::Itertools;
 use web_rwkv_derive::{Deref, DerefMut, Id};
 use wgpu::{
-    util::{BufferInitDescriptor, DeviceExt},
-    Adapter, Backends, BindGroupLayoutDescriptor, BindGroupLayoutEntry, Buffer, BufferUsages,
-    ComputePipeline, ComputePipelineDescriptor, Device, DeviceDescriptor, Features, Limits,
-    PipelineLayoutDescriptor, PowerPreference, Queue, RequestAdapterOptions,
-    ShaderModuleDescriptor, ShaderStages,
+    util::{BufferInitDescriptor, DeviceExt, StagingBelt},
+    Adapter, Backends, BindGroupLayoutDescriptor, BindGroupLayoutEntry, BindingType, Buffer,
+    BufferBindingType, BufferSize, BufferUsages, CommandEncoderDescriptor, ComputePipeline,
+    ComputePipelineDescriptor, Device, DeviceDescriptor, Dx12Compiler, Features,
+    InstanceDescriptor, InstanceFlags, Limits, PipelineLayoutDescriptor, PowerPreference, Queue,
+    RequestAdapterOptions, ShaderModuleDescriptor, ShaderStages,
 };
 
 use crate::tensor::{
-    cache::ResourceCache,
+    cache::{CacheCounter, CacheStats, ResourceCache},
     shape::{IntoBytes, Shape},
     TensorError, View,
 };
 
-#[derive(Deref)]
-pub struct Instance(wgpu::Instance);
+/// The entry point to the GPU, from which adapters are requested.
+///
+/// The underlying instance is created on first use, so the options below can be chained after [`Instance::new`].
+pub struct Instance {
+    backends: Backends,
+    flags: InstanceFlags,
+    dx12_shader_compiler: Dx12Compiler,
+    instance: OnceLock<wgpu::Instance>,
+}
 
 impl Default for Instance {
     fn default() -> Self {
@@ -24,10 +45,60 @@ impl Default for Instance {
     }
 }
 
+impl std::ops::Deref for Instance {
+    type Target = wgpu::Instance;
+
+    fn deref(&self) -> &Self::Target {
+        self.instance.get_or_init(|| {
+            wgpu::Instance::new(InstanceDescriptor {
+                backends: self.backends,
+                flags: self.flags,
+                dx12_shader_compiler: self.dx12_shader_compiler.clone(),
+                ..Default::default()
+            })
+        })
+    }
+}
+
 impl Instance {
     pub fn new() -> Self {
-        let instance = wgpu::Instance::new(Default::default());
-        Self(instance)
+        let descriptor = InstanceDescriptor::default();
+        Self {
+            backends: descriptor.backends,
+            flags: descriptor.flags,
+            dx12_shader_compiler: descriptor.dx12_shader_compiler,
+            instance: OnceLock::new(),
+        }
+    }
+
+    /// Only look for adapters on `backends`, e.g. to force Vulkan on a machine where DX12 misbehaves.
+    pub fn with_backends(self, backends: Backends) -> Self {
+        Self {
+            backends,
+            instance: OnceLock::new(),
+            ..self
+        }
+    }
+
+    /// The compiler of shaders for DX12: FXC by default, or DXC if its libraries are available.
+    pub fn with_dx12_compiler(self, dx12_shader_compiler: Dx12Compiler) -> Self {
+        Self {
+            dx12_shader_compiler,
+            instance: OnceLock::new(),
+            ..self
+        }
+    }
+
+    /// Turn the validation layers of the backend (e.g. of Vulkan) on or off.
+    /// They catch API misuse at a large cost in speed, and are on by default only in debug builds.
+    pub fn with_validation(self, validation: bool) -> Self {
+        let mut flags = self.flags;
+        flags.set(InstanceFlags::VALIDATION, validation);
+        Self {
+            flags,
+            instance: OnceLock::new(),
+            ..self
+        }
     }
 
     pub fn select_adapter(
@@ -64,26 +135,532 @@ pub struct ContextInner {
     pub device: Device,
     pub queue: Queue,
 
-    pipelines: HashMap<String, ComputePipeline>,
+    /// Pipelines compiled so far.
+    pipelines: RwLock<HashMap<PipelineKey, Arc<ComputePipeline>>>,
+    pipeline_counter: CacheCounter,
+    /// Pipelines registered by the user, which take precedence over built-in ones.
+    sources: HashMap<String, PipelineSource<'static>>,
+    /// Whether every pipeline is compiled with the `DETERMINISTIC` symbol.
+    deterministic: bool,
+    #[cfg(feature = "dev")]
+    shader_dir: PathBuf,
+    #[cfg(feature = "dev")]
+    watch: ShaderWatch,
 
     shape_cache: ResourceCache<Shape, Buffer>,
     view_cache: ResourceCache<View, Buffer>,
+    /// Uniforms of a `vec4<f32>` splatted from one value, keyed by its bits.
+    value_cache: ResourceCache<u32, Buffer>,
+
+    /// Buffers allocated through the context that may still be alive.
+    allocations: Mutex<Vec<Allocation>>,
+    /// Category new allocations are accounted to, set by [`Context::memory_scope`].
+    category: Mutex<MemoryCategory>,
+
+    staging: Mutex<StagingBelt>,
+    /// Turns to submit work to the queue, see [`Context::submission`].
+    turns: Turns,
+    /// Whether a thread polls the device, see [`ContextBuilder::with_poll_thread`].
+    poller: bool,
 }
 
+/// A handle to a device and its queue, cheap to clone.
+///
+/// Several models may be built on one context and run concurrently from different threads,
+/// e.g. a draft and a target model, or an embedder next to a generator; they share compiled pipelines and cached uniforms.
+/// Each model runs with its own states, and takes turns with the others to submit work, see [`Context::submission`].
+/// A single model must not be run from two threads at once, since its runtime buffers are shared by its runs.
 #[derive(Debug, Clone, Deref, DerefMut)]
 pub struct Context(Arc<ContextInner>);
 
+/// Hands out turns to submit work to the queue in the order they are asked for.
+#[derive(Debug, Default)]
+struct Turns {
+    /// The next ticket to hand out, and the ticket whose turn it is.
+    tickets: Mutex<(usize, usize)>,
+    next: Condvar,
+}
+
+/// A turn to encode and submit work to the queue of a [`Context`], passed on to the next in line when dropped.
+#[derive(Debug)]
+pub struct Submission<'a> {
+    turns: &'a Turns,
+}
+
+impl Drop for Submission<'_> {
+    fn drop(&mut self) {
+        let mut tickets = self.turns.tickets.lock().unwrap();
+        tickets.1 += 1;
+        self.turns.next.notify_all();
+    }
+}
+
+/// What a device buffer is used for.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MemoryCategory {
+    /// Model parameters.
+    Weights,
+    /// Model states.
+    State,
+    /// Intermediate buffers of model runs.
+    Runtime,
+    /// Shape and view uniforms kept by the context.
+    Cache,
+    /// Anything allocated outside of a [`Context::memory_scope`].
+    #[default]
+    Other,
+}
+
+/// Statistics of the caches of a [`Context`], see [`Context::cache_stats`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ContextCacheStats {
+    pub shape: CacheStats,
+    pub view: CacheStats,
+    pub value: CacheStats,
+    pub pipeline: CacheStats,
+}
+
+/// Bytes of device memory currently held, by category.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryUsage {
+    pub weights: u64,
+    pub state: u64,
+    pub runtime: u64,
+    pub cache: u64,
+    pub other: u64,
+}
+
+impl MemoryUsage {
+    pub fn total(&self) -> u64 {
+        self.weights + self.state + self.runtime + self.cache + self.other
+    }
+
+    pub fn get(&self, category: MemoryCategory) -> u64 {
+        match category {
+            MemoryCategory::Weights => self.weights,
+            MemoryCategory::State => self.state,
+            MemoryCategory::Runtime => self.runtime,
+            MemoryCategory::Cache => self.cache,
+            MemoryCategory::Other => self.other,
+        }
+    }
+
+    fn get_mut(&mut self, category: MemoryCategory) -> &mut u64 {
+        match category {
+            MemoryCategory::Weights => &mut self.weights,
+            MemoryCategory::State => &mut self.state,
+            MemoryCategory::Runtime => &mut self.runtime,
+            MemoryCategory::Cache => &mut self.cache,
+            MemoryCategory::Other => &mut self.other,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Allocation {
+    category: MemoryCategory,
+    size: u64,
+    buffer: Weak<Buffer>,
+}
+
+/// Accounts allocations on the context to a category until dropped, see [`Context::memory_scope`].
+#[derive(Debug)]
+pub struct MemoryScope {
+    context: Context,
+    previous: MemoryCategory,
+}
+
+impl Drop for MemoryScope {
+    fn drop(&mut self) {
+        *self.context.category.lock().unwrap() = self.previous;
+    }
+}
+
 pub struct ContextBuilder<'a> {
     adapter: Adapter,
     features: Features,
     limits: Limits,
-    pipelines: HashMap<&'a str, (&'a str, &'a str, Option<&'a [BindGroupLayoutEntry]>)>,
+    required_limits: Option<Limits>,
+    poll_interval: Option<Duration>,
+    pipelines: HashMap<&'a str, PipelineSource<'a>>,
+    deterministic: bool,
+    #[cfg(feature = "dev")]
+    shader_dir: PathBuf,
+}
+
+#[derive(Debug, Clone)]
+struct PipelineSource<'a> {
+    shader: Cow<'a, str>,
+    entry_point: Cow<'a, str>,
+    layout: Option<Cow<'a, [BindGroupLayoutEntry]>>,
+}
+
+impl PipelineSource<'_> {
+    fn into_owned(self) -> PipelineSource<'static> {
+        PipelineSource {
+            shader: Cow::Owned(self.shader.into_owned()),
+            entry_point: Cow::Owned(self.entry_point.into_owned()),
+            layout: self.layout.map(|layout| Cow::Owned(layout.into_owned())),
+        }
+    }
+}
+
+/// A compiled pipeline: its name, and the symbols and constants its shader was preprocessed with.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct PipelineKey {
+    name: &'static str,
+    defines: Vec<&'static str>,
+    constants: Vec<(&'static str, u32)>,
+}
+
+impl PipelineKey {
+    fn new(
+        name: &'static str,
+        defines: &[&'static str],
+        constants: &[(&'static str, u32)],
+    ) -> Self {
+        let mut defines = defines.to_vec();
+        defines.sort_unstable();
+        defines.dedup();
+        let mut constants = constants.to_vec();
+        constants.sort_unstable();
+        constants.dedup();
+        Self {
+            name,
+            defines,
+            constants,
+        }
+    }
+}
+
+impl std::fmt::Display for PipelineKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let symbols = self
+            .defines
+            .iter()
+            .map(|symbol| symbol.to_string())
+            .chain(
+                self.constants
+                    .iter()
+                    .map(|(name, value)| format!("{name}={value}")),
+            )
+            .collect::<Vec<_>>();
+        match symbols.is_empty() {
+            true => write!(f, "{}", self.name),
+            false => write!(f, "{}[{}]", self.name, symbols.join(", ")),
+        }
+    }
+}
+
+/// A built-in pipeline, compiled the first time it is requested.
+struct Builtin {
+    name: &'static str,
+    /// File name of the shader under `src/shaders`.
+    #[cfg_attr(not(feature = "dev"), allow(dead_code))]
+    file: &'static str,
+    shader: &'static str,
+    entry_point: &'static str,
+    layout: Option<&'static [BindGroupLayoutEntry]>,
+}
+
+impl Builtin {
+    fn find(name: &str) -> Option<&'static Builtin> {
+        BUILTINS.iter().find(|builtin| builtin.name == name)
+    }
+
+    fn source(&self) -> PipelineSource<'static> {
+        PipelineSource {
+            shader: Cow::Borrowed(self.shader),
+            entry_point: Cow::Borrowed(self.entry_point),
+            layout: self.layout.map(Cow::Borrowed),
+        }
+    }
+}
+
+macro_rules! builtin {
+    ($name:literal, $file:literal, $entry_point:literal) => {
+        Builtin {
+            name: $name,
+            file: $file,
+            shader: include_str!(concat!("shaders/", $file)),
+            entry_point: $entry_point,
+            layout: None,
+        }
+    };
+    ($name:literal, $file:literal, $entry_point:literal, $layout:expr) => {
+        Builtin {
+            name: $name,
+            file: $file,
+            shader: include_str!(concat!("shaders/", $file)),
+            entry_point: $entry_point,
+            layout: Some($layout),
+        }
+    };
+}
+
+/// Wait on a future that native backends resolve right away, such as that of [`Device::pop_error_scope`].
+#[cfg(not(target_arch = "wasm32"))]
+fn block_on<F: std::future::Future>(future: F) -> F::Output {
+    use std::task::{Poll, Waker};
+
+    let mut future = std::pin::pin!(future);
+    let mut cx = std::task::Context::from_waker(Waker::noop());
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+        std::thread::yield_now();
+    }
+}
+
+/// Poll the device of `context` every `interval` on a thread of its own, until the context is dropped.
+#[cfg(not(target_arch = "wasm32"))]
+fn spawn_poller(context: &Context, interval: Duration) {
+    let context = Arc::downgrade(&context.0);
+    std::thread::Builder::new()
+        .name("web-rwkv-poll".into())
+        .spawn(move || {
+            while let Some(context) = context.upgrade() {
+                context.device.poll(wgpu::MaintainBase::Poll);
+                drop(context);
+                std::thread::sleep(interval);
+            }
+        })
+        .expect("spawn poll thread");
+}
+
+/// Resolve `#ifdef`, `#ifndef`, `#else` and `#endif` lines in a shader, keeping the lines whose conditions hold.
+fn preprocess(shader: &str, defines: &[&str]) -> String {
+    // Each entry records whether the enclosing branch is active.
+    let mut stack: Vec<bool> = vec![];
+    let mut output = String::with_capacity(shader.len());
+    for line in shader.lines() {
+        let active = stack.last().copied().unwrap_or(true);
+        let trimmed = line.trim();
+        if let Some(symbol) = trimmed.strip_prefix("#ifdef") {
+            stack.push(active && defines.contains(&symbol.trim()));
+        } else if let Some(symbol) = trimmed.strip_prefix("#ifndef") {
+            stack.push(active && !defines.contains(&symbol.trim()));
+        } else if trimmed.starts_with("#else") {
+            let outer = stack.len() < 2 || stack[stack.len() - 2];
+            if let Some(branch) = stack.last_mut() {
+                *branch = outer && !*branch;
+            }
+        } else if trimmed.starts_with("#endif") {
+            stack.pop();
+        } else if active {
+            output.push_str(line);
+            output.push('\n');
+        }
+    }
+    output
+}
+
+/// Turn `override NAME: u32;` and `override NAME: u32 = default;` lines of a shader into constants,
+/// set to the values given in `constants` or else to their defaults.
+///
+/// This stands in for pipeline-overridable constants, which the device API doesn't take yet;
+/// the values are baked in before compiling, so that the compiler can unroll loops over them.
+fn specialize(shader: &str, constants: &[(&str, u32)]) -> String {
+    let mut output = String::with_capacity(shader.len());
+    for line in shader.lines() {
+        let declaration = line
+            .trim()
+            .strip_prefix("override ")
+            .and_then(|declaration| declaration.strip_suffix(';'))
+            .and_then(|declaration| declaration.split_once(':'));
+        let Some((name, ty)) = declaration else {
+            output.push_str(line);
+            output.push('\n');
+            continue;
+        };
+        let name = name.trim();
+        let (ty, default) = match ty.split_once('=') {
+            Some((ty, default)) => (ty.trim(), Some(default.trim().to_owned())),
+            None => (ty.trim(), None),
+        };
+        let value = constants
+            .iter()
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| format!("{value}u"))
+            .or(default);
+        match value {
+            Some(value) => output.push_str(&format!("const {name}: {ty} = {value};\n")),
+            // left as it is, so that the compiler reports the missing value
+            None => {
+                output.push_str(line);
+                output.push('\n');
+            }
+        }
+    }
+    output
+}
+
+const fn layout_entry(binding: u32, ty: BufferBindingType) -> BindGroupLayoutEntry {
+    BindGroupLayoutEntry {
+        binding,
+        visibility: ShaderStages::COMPUTE,
+        ty: BindingType::Buffer {
+            ty,
+            has_dynamic_offset: false,
+            min_binding_size: None,
+        },
+        count: None,
+    }
+}
+
+const QUANT_MAT_INT8_LAYOUT: &[BindGroupLayoutEntry] = &[
+    layout_entry(0, BufferBindingType::Uniform),
+    layout_entry(1, BufferBindingType::Storage { read_only: false }),
+    layout_entry(2, BufferBindingType::Storage { read_only: false }),
+    layout_entry(3, BufferBindingType::Storage { read_only: false }),
+    layout_entry(4, BufferBindingType::Storage { read_only: false }),
+    layout_entry(5, BufferBindingType::Storage { read_only: false }),
+    layout_entry(6, BufferBindingType::Storage { read_only: false }),
+];
+
+const QUANT_MAT_NF4_LAYOUT: &[BindGroupLayoutEntry] = &[
+    layout_entry(0, BufferBindingType::Uniform),
+    layout_entry(1, BufferBindingType::Uniform),
+    layout_entry(2, BufferBindingType::Storage { read_only: true }),
+    layout_entry(3, BufferBindingType::Storage { read_only: false }),
+    layout_entry(4, BufferBindingType::Storage { read_only: false }),
+];
+
+const BUILTINS: &[Builtin] = &[
+    builtin!("layer_norm", "layer_norm.wgsl", "layer_norm"),
+    builtin!("group_norm", "group_norm.wgsl", "group_norm"),
+    builtin!("matmul_vec_fp16", "matmul_vec_fp16.wgsl", "matmul"),
+    builtin!("matmul_vec_int8", "matmul_vec_int8.wgsl", "matmul"),
+    builtin!(
+        "matmul_vec_int8_asym",
+        "matmul_vec_int8_asym.wgsl",
+        "matmul"
+    ),
+    builtin!("matmul_vec_nf4", "matmul_vec_nf4.wgsl", "matmul"),
+    builtin!("matmul_mat_fp16", "matmul_mat_fp16.wgsl", "matmul"),
+    builtin!("matmul_mat_int8", "matmul_mat_int8.wgsl", "matmul"),
+    builtin!(
+        "matmul_mat_int8_asym",
+        "matmul_mat_int8_asym.wgsl",
+        "matmul"
+    ),
+    builtin!("token_shift", "token_shift.wgsl", "token_shift"),
+    builtin!("time_mix", "time_mix.wgsl", "time_mix"),
+    builtin!("time_mix_v5", "time_mix_v5.wgsl", "time_mix"),
+    builtin!("add", "add.wgsl", "add"),
+    builtin!("silu", "silu.wgsl", "silu"),
+    builtin!("squared_relu", "squared_relu.wgsl", "squared_relu"),
+    builtin!("channel_mix", "channel_mix.wgsl", "channel_mix"),
+    builtin!("softmax", "softmax.wgsl", "softmax"),
+    builtin!("penalty", "penalty.wgsl", "penalty"),
+    builtin!("mask", "mask.wgsl", "mask_logits"),
+    builtin!("verify", "verify.wgsl", "verify"),
+    builtin!("score", "score.wgsl", "score"),
+    builtin!("pool", "pool.wgsl", "pool"),
+    builtin!("l2_norm", "l2_norm.wgsl", "l2_norm"),
+    builtin!("stats", "stats.wgsl", "stats"),
+    builtin!("cosine", "cosine.wgsl", "cosine"),
+    builtin!("blit", "blit.wgsl", "blit"),
+    builtin!("fill", "fill.wgsl", "fill"),
+    builtin!("cast_f16", "cast.wgsl", "cast_f16"),
+    builtin!("cast_f32", "cast.wgsl", "cast_f32"),
+    builtin!("blend", "blend.wgsl", "blend"),
+    builtin!("blend_lora", "blend_lora.wgsl", "blend_lora"),
+    builtin!("discount", "discount.wgsl", "discount"),
+    builtin!("half", "discount.wgsl", "half"),
+    builtin!("rand_uniform", "rand.wgsl", "rand_uniform"),
+    builtin!("rand_normal", "rand.wgsl", "rand_normal"),
+    builtin!(
+        "quant_mat_int8",
+        "quant_mat_int8.wgsl",
+        "quantize",
+        QUANT_MAT_INT8_LAYOUT
+    ),
+    builtin!(
+        "quant_mat_int8_mx",
+        "quant_mat_int8.wgsl",
+        "compute_mx",
+        QUANT_MAT_INT8_LAYOUT
+    ),
+    builtin!(
+        "quant_mat_int8_my",
+        "quant_mat_int8.wgsl",
+        "compute_my",
+        QUANT_MAT_INT8_LAYOUT
+    ),
+    builtin!(
+        "quant_mat_int8_rx",
+        "quant_mat_int8.wgsl",
+        "compute_rx",
+        QUANT_MAT_INT8_LAYOUT
+    ),
+    builtin!(
+        "quant_mat_int8_ry",
+        "quant_mat_int8.wgsl",
+        "compute_ry",
+        QUANT_MAT_INT8_LAYOUT
+    ),
+    builtin!(
+        "quant_mat_int8_asym",
+        "quant_mat_int8_asym.wgsl",
+        "quantize"
+    ),
+    builtin!(
+        "quant_mat_nf4_absmax",
+        "quant_mat_nf4.wgsl",
+        "compute_absmax",
+        QUANT_MAT_NF4_LAYOUT
+    ),
+    builtin!(
+        "quant_mat_nf4",
+        "quant_mat_nf4.wgsl",
+        "quantize",
+        QUANT_MAT_NF4_LAYOUT
+    ),
+    builtin!("quant_fp16", "quant_fp16.wgsl", "quantize"),
+];
+
+fn create_pipeline(
+    device: &Device,
+    name: &str,
+    shader: &str,
+    entry_point: &str,
+    layout: Option<&[BindGroupLayoutEntry]>,
+) -> ComputePipeline {
+    let module = &device.create_shader_module(ShaderModuleDescriptor {
+        label: Some(name),
+        source: wgpu::ShaderSource::Wgsl(Cow::from(shader)),
+    });
+    let layout = layout.map(|entries| {
+        let layout = &device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            label: None,
+            entries,
+        });
+        device.create_pipeline_layout(&PipelineLayoutDescriptor {
+            label: None,
+            bind_group_layouts: &[layout],
+            push_constant_ranges: &[],
+        })
+    });
+    device.create_compute_pipeline(&ComputePipelineDescriptor {
+        label: Some(name),
+        layout: layout.as_ref(),
+        module,
+        entry_point,
+    })
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum CreateEnvironmentError {
     RequestAdapterFailed,
     RequestDeviceFailed,
+    /// The adapter supports less of `limit` than set with [`ContextBuilder::with_required_limits`].
+    UnsupportedLimit {
+        limit: &'static str,
+        required: u64,
+        supported: u64,
+    },
 }
 
 impl std::fmt::Display for CreateEnvironmentError {
@@ -91,85 +668,184 @@ impl std::fmt::Display for CreateEnvironmentError {
         match self {
             CreateEnvironmentError::RequestAdapterFailed => write!(f, "failed to request adaptor"),
             CreateEnvironmentError::RequestDeviceFailed => write!(f, "failed to request device"),
+            CreateEnvironmentError::UnsupportedLimit {
+                limit,
+                required,
+                supported,
+            } => write!(
+                f,
+                "{required} of {limit} is required, but the adapter supports only {supported}"
+            ),
         }
     }
 }
 
 impl std::error::Error for CreateEnvironmentError {}
 
+/// Raise each limit in `limits` to at least that in `required`, checking that the adapter supports it.
+fn raise_limits(
+    limits: Limits,
+    required: &Limits,
+    supported: &Limits,
+) -> Result<Limits, CreateEnvironmentError> {
+    let limits = Limits {
+        max_buffer_size: limits.max_buffer_size.max(required.max_buffer_size),
+        max_storage_buffer_binding_size: limits
+            .max_storage_buffer_binding_size
+            .max(required.max_storage_buffer_binding_size),
+        max_storage_buffers_per_shader_stage: limits
+            .max_storage_buffers_per_shader_stage
+            .max(required.max_storage_buffers_per_shader_stage),
+        max_compute_invocations_per_workgroup: limits
+            .max_compute_invocations_per_workgroup
+            .max(required.max_compute_invocations_per_workgroup),
+        max_compute_workgroup_size_x: limits
+            .max_compute_workgroup_size_x
+            .max(required.max_compute_workgroup_size_x),
+        max_compute_workgroup_storage_size: limits
+            .max_compute_workgroup_storage_size
+            .max(required.max_compute_workgroup_storage_size),
+        ..limits
+    };
+
+    let checks = [
+        (
+            "max_buffer_size",
+            limits.max_buffer_size,
+            supported.max_buffer_size,
+        ),
+        (
+            "max_storage_buffer_binding_size",
+            limits.max_storage_buffer_binding_size as u64,
+            supported.max_storage_buffer_binding_size as u64,
+        ),
+        (
+            "max_storage_buffers_per_shader_stage",
+            limits.max_storage_buffers_per_shader_stage as u64,
+            supported.max_storage_buffers_per_shader_stage as u64,
+        ),
+        (
+            "max_compute_invocations_per_workgroup",
+            limits.max_compute_invocations_per_workgroup as u64,
+            supported.max_compute_invocations_per_workgroup as u64,
+        ),
+        (
+            "max_compute_workgroup_size_x",
+            limits.max_compute_workgroup_size_x as u64,
+            supported.max_compute_workgroup_size_x as u64,
+        ),
+        (
+            "max_compute_workgroup_storage_size",
+            limits.max_compute_workgroup_storage_size as u64,
+            supported.max_compute_workgroup_storage_size as u64,
+        ),
+    ];
+    match checks
+        .into_iter()
+        .find(|(_, required, supported)| required > supported)
+    {
+        Some((limit, required, supported)) => Err(CreateEnvironmentError::UnsupportedLimit {
+            limit,
+            required,
+            supported,
+        }),
+        None => Ok(limits),
+    }
+}
+
 impl<'a> ContextBuilder<'a> {
     pub fn new(adapter: Adapter) -> Self {
         Self {
             adapter,
             pipelines: HashMap::new(),
+            deterministic: false,
             features: Features::empty(),
             limits: Default::default(),
+            required_limits: None,
+            poll_interval: None,
+            #[cfg(feature = "dev")]
+            shader_dir: PathBuf::from(concat!(env!("CARGO_MANIFEST_DIR"), "/src/shaders")),
         }
     }
 
     pub async fn build(self) -> Result<Context, CreateEnvironmentError> {
+        let limits = match &self.required_limits {
+            Some(required) => raise_limits(self.limits, required, &self.adapter.limits())?,
+            None => self.limits,
+        };
         let (device, queue) = self
             .adapter
             .request_device(
                 &DeviceDescriptor {
                     label: None,
                     features: self.features,
-                    limits: self.limits,
+                    limits,
                 },
                 None,
             )
             .await
             .map_err(|_| CreateEnvironmentError::RequestDeviceFailed)?;
-        let pipelines = self
+        let sources = self
             .pipelines
             .into_iter()
-            .map(|(name, (shader, entry_point, layout))| {
-                let module = &device.create_shader_module(ShaderModuleDescriptor {
-                    label: Some(name),
-                    source: wgpu::ShaderSource::Wgsl(Cow::from(shader)),
-                });
-                let layout = layout.map(|entries| {
-                    let layout = &device.create_bind_group_layout(&BindGroupLayoutDescriptor {
-                        label: None,
-                        entries,
-                    });
-                    device.create_pipeline_layout(&PipelineLayoutDescriptor {
-                        label: None,
-                        bind_group_layouts: &[layout],
-                        push_constant_ranges: &[],
-                    })
-                });
-                let pipeline = device.create_compute_pipeline(&ComputePipelineDescriptor {
-                    label: Some(name),
-                    layout: layout.as_ref(),
-                    module,
-                    entry_point,
-                });
-                (String::from_str(name).expect("bad pipeline name"), pipeline)
-            })
+            .map(|(name, source)| (name.to_string(), source.into_owned()))
             .collect();
-        Ok(Context(
+        let poll_interval = self
+            .poll_interval
+            .filter(|_| cfg!(not(target_arch = "wasm32")));
+        let context = Context(
             ContextInner {
                 id: ContextId::new(),
                 adapter: self.adapter,
                 device,
                 queue,
-                pipelines,
+                pipelines: Default::default(),
+                pipeline_counter: Default::default(),
+                sources,
+                deterministic: self.deterministic,
+                #[cfg(feature = "dev")]
+                watch: ShaderWatch::new(&self.shader_dir),
+                #[cfg(feature = "dev")]
+                shader_dir: self.shader_dir,
                 shape_cache: Default::default(),
                 view_cache: Default::default(),
+                value_cache: Default::default(),
+                allocations: Default::default(),
+                category: Default::default(),
+                staging: Mutex::new(StagingBelt::new(Context::STAGING_CHUNK_SIZE)),
+                turns: Default::default(),
+                poller: poll_interval.is_some(),
             }
             .into(),
-        ))
+        );
+        #[cfg(not(target_arch = "wasm32"))]
+        if let Some(interval) = poll_interval {
+            spawn_poller(&context, interval);
+        }
+        Ok(context)
     }
 
     pub fn with_limits(self, limits: Limits) -> Self {
         Self { limits, ..self }
     }
 
+    /// Raise the requested limits to at least `limits`, e.g. those a model needs
+    /// (see [`ModelInfo::limits`](crate::model::ModelInfo::limits)),
+    /// so that [`ContextBuilder::build`] fails right away if the adapter can't provide them, instead of the model build.
+    pub fn with_required_limits(self, limits: Limits) -> Self {
+        let required_limits = Some(limits);
+        Self {
+            required_limits,
+            ..self
+        }
+    }
+
     pub fn with_features(self, features: Features) -> Self {
         Self { features, ..self }
     }
 
+    /// Register a custom pipeline, which is compiled the first time it is requested.
+    /// A pipeline registered with the name of a built-in one replaces it.
     pub fn with_pipeline(
         self,
         name: &'a str,
@@ -178,257 +854,95 @@ impl<'a> ContextBuilder<'a> {
         layout: Option<&'a [BindGroupLayoutEntry]>,
     ) -> Self {
         let mut pipelines = self.pipelines;
-        pipelines.insert(name, (shader, entry_point, layout));
+        pipelines.insert(
+            name,
+            PipelineSource {
+                shader: Cow::Borrowed(shader),
+                entry_point: Cow::Borrowed(entry_point),
+                layout: layout.map(Cow::Borrowed),
+            },
+        );
         Self { pipelines, ..self }
     }
 
+    /// Poll the device every `interval` on a thread owned by the context, so that futures such as
+    /// [`TensorBack`](crate::tensor::TensorBack) resolve on any executor without the application polling it.
+    /// The thread stops once the context is dropped. On the web, where the browser polls, this does nothing.
+    pub fn with_poll_thread(self, interval: Duration) -> Self {
+        Self {
+            poll_interval: Some(interval),
+            ..self
+        }
+    }
+
+    /// Compile every pipeline with the `DETERMINISTIC` symbol, which makes normalization and softmax kernels
+    /// sum in a fixed order, so that the same model gives matching logits across backends within a tight tolerance.
+    /// This costs an extra pass over the input in layer and group normalization.
+    pub fn with_deterministic(self, deterministic: bool) -> Self {
+        Self {
+            deterministic,
+            ..self
+        }
+    }
+
+    /// Directory to load built-in shaders from, instead of the `src/shaders` directory of this crate.
+    #[cfg(feature = "dev")]
+    pub fn with_shader_dir(self, shader_dir: impl Into<PathBuf>) -> Self {
+        Self {
+            shader_dir: shader_dir.into(),
+            ..self
+        }
+    }
+
+    /// Built-in pipelines are now compiled on first use, so this does nothing.
+    #[deprecated(note = "built-in pipelines are compiled on first use")]
     pub fn with_default_pipelines(self) -> Self {
-        self.with_core_pipelines()
-            .with_util_pipelines()
-            .with_quant_pipelines()
-    }
-
-    fn with_core_pipelines(self) -> Self {
-        self.with_pipeline(
-            "layer_norm",
-            include_str!("shaders/layer_norm.wgsl"),
-            "layer_norm",
-            None,
-        )
-        .with_pipeline(
-            "group_norm",
-            include_str!("shaders/group_norm.wgsl"),
-            "group_norm",
-            None,
-        )
-        .with_pipeline(
-            "matmul_vec_fp16",
-            include_str!("shaders/matmul_vec_fp16.wgsl"),
-            "matmul",
-            None,
-        )
-        .with_pipeline(
-            "matmul_vec_int8",
-            include_str!("shaders/matmul_vec_int8.wgsl"),
-            "matmul",
-            None,
-        )
-        .with_pipeline(
-            "matmul_vec_nf4",
-            include_str!("shaders/matmul_vec_nf4.wgsl"),
-            "matmul",
-            None,
-        )
-        .with_pipeline(
-            "matmul_mat_fp16",
-            include_str!("shaders/matmul_mat_fp16.wgsl"),
-            "matmul",
-            None,
-        )
-        .with_pipeline(
-            "matmul_mat_int8",
-            include_str!("shaders/matmul_mat_int8.wgsl"),
-            "matmul",
-            None,
-        )
-        .with_pipeline(
-            "token_shift",
-            include_str!("shaders/token_shift.wgsl"),
-            "token_shift",
-            None,
-        )
-        .with_pipeline(
-            "time_mix",
-            include_str!("shaders/time_mix.wgsl"),
-            "time_mix",
-            None,
-        )
-        .with_pipeline(
-            "time_mix_v5",
-            include_str!("shaders/time_mix_v5.wgsl"),
-            "time_mix",
-            None,
-        )
-        .with_pipeline("add", include_str!("shaders/add.wgsl"), "add", None)
-        .with_pipeline("silu", include_str!("shaders/silu.wgsl"), "silu", None)
-        .with_pipeline(
-            "squared_relu",
-            include_str!("shaders/squared_relu.wgsl"),
-            "squared_relu",
-            None,
-        )
-        .with_pipeline(
-            "channel_mix",
-            include_str!("shaders/channel_mix.wgsl"),
-            "channel_mix",
-            None,
-        )
-        .with_pipeline(
-            "softmax",
-            include_str!("shaders/softmax.wgsl"),
-            "softmax",
-            None,
-        )
-    }
-
-    fn with_util_pipelines(self) -> Self {
-        self.with_pipeline("blit", include_str!("shaders/blit.wgsl"), "blit", None)
-            .with_pipeline("blend", include_str!("shaders/blend.wgsl"), "blend", None)
-            .with_pipeline(
-                "blend_lora",
-                include_str!("shaders/blend_lora.wgsl"),
-                "blend_lora",
-                None,
-            )
-            .with_pipeline("half", include_str!("shaders/discount.wgsl"), "half", None)
-    }
-
-    fn with_quant_pipelines(self) -> Self {
-        let shader = include_str!("shaders/quant_mat_int8.wgsl");
-        let entries = &[
-            BindGroupLayoutEntry {
-                binding: 0,
-                visibility: ShaderStages::COMPUTE,
-                ty: wgpu::BindingType::Buffer {
-                    ty: wgpu::BufferBindingType::Uniform,
-                    has_dynamic_offset: false,
-                    min_binding_size: None,
-                },
-                count: None,
-            },
-            BindGroupLayoutEntry {
-                binding: 1,
-                visibility: ShaderStages::COMPUTE,
-                ty: wgpu::BindingType::Buffer {
-                    ty: wgpu::BufferBindingType::Storage { read_only: false },
-                    has_dynamic_offset: false,
-                    min_binding_size: None,
-                },
-                count: None,
-            },
-            BindGroupLayoutEntry {
-                binding: 2,
-                visibility: ShaderStages::COMPUTE,
-                ty: wgpu::BindingType::Buffer {
-                    ty: wgpu::BufferBindingType::Storage { read_only: false },
-                    has_dynamic_offset: false,
-                    min_binding_size: None,
-                },
-                count: None,
-            },
-            BindGroupLayoutEntry {
-                binding: 3,
-                visibility: ShaderStages::COMPUTE,
-                ty: wgpu::BindingType::Buffer {
-                    ty: wgpu::BufferBindingType::Storage { read_only: false },
-                    has_dynamic_offset: false,
-                    min_binding_size: None,
-                },
-                count: None,
-            },
-            BindGroupLayoutEntry {
-                binding: 4,
-                visibility: ShaderStages::COMPUTE,
-                ty: wgpu::BindingType::Buffer {
-                    ty: wgpu::BufferBindingType::Storage { read_only: false },
-                    has_dynamic_offset: false,
-                    min_binding_size: None,
-                },
-                count: None,
-            },
-            BindGroupLayoutEntry {
-                binding: 5,
-                visibility: ShaderStages::COMPUTE,
-                ty: wgpu::BindingType::Buffer {
-                    ty: wgpu::BufferBindingType::Storage { read_only: false },
-                    has_dynamic_offset: false,
-                    min_binding_size: None,
-                },
-                count: None,
-            },
-            BindGroupLayoutEntry {
-                binding: 6,
-                visibility: ShaderStages::COMPUTE,
-                ty: wgpu::BindingType::Buffer {
-                    ty: wgpu::BufferBindingType::Storage { read_only: false },
-                    has_dynamic_offset: false,
-                    min_binding_size: None,
-                },
-                count: None,
-            },
-        ];
-        let layout: Option<&[BindGroupLayoutEntry]> = Some(entries);
-        let context = self
-            .with_pipeline("quant_mat_int8", shader, "quantize", layout)
-            .with_pipeline("quant_mat_int8_mx", shader, "compute_mx", layout)
-            .with_pipeline("quant_mat_int8_my", shader, "compute_my", layout)
-            .with_pipeline("quant_mat_int8_rx", shader, "compute_rx", layout)
-            .with_pipeline("quant_mat_int8_ry", shader, "compute_ry", layout);
-
-        let shader = include_str!("shaders/quant_mat_nf4.wgsl");
-        let entries = &[
-            BindGroupLayoutEntry {
-                binding: 0,
-                visibility: ShaderStages::COMPUTE,
-                ty: wgpu::BindingType::Buffer {
-                    ty: wgpu::BufferBindingType::Uniform,
-                    has_dynamic_offset: false,
-                    min_binding_size: None,
-                },
-                count: None,
-            },
-            BindGroupLayoutEntry {
-                binding: 1,
-                visibility: ShaderStages::COMPUTE,
-                ty: wgpu::BindingType::Buffer {
-                    ty: wgpu::BufferBindingType::Uniform,
-                    has_dynamic_offset: false,
-                    min_binding_size: None,
-                },
-                count: None,
-            },
-            BindGroupLayoutEntry {
-                binding: 2,
-                visibility: ShaderStages::COMPUTE,
-                ty: wgpu::BindingType::Buffer {
-                    ty: wgpu::BufferBindingType::Storage { read_only: true },
-                    has_dynamic_offset: false,
-                    min_binding_size: None,
-                },
-                count: None,
-            },
-            BindGroupLayoutEntry {
-                binding: 3,
-                visibility: ShaderStages::COMPUTE,
-                ty: wgpu::BindingType::Buffer {
-                    ty: wgpu::BufferBindingType::Storage { read_only: false },
-                    has_dynamic_offset: false,
-                    min_binding_size: None,
-                },
-                count: None,
-            },
-            BindGroupLayoutEntry {
-                binding: 4,
-                visibility: ShaderStages::COMPUTE,
-                ty: wgpu::BindingType::Buffer {
-                    ty: wgpu::BufferBindingType::Storage { read_only: false },
-                    has_dynamic_offset: false,
-                    min_binding_size: None,
-                },
-                count: None,
-            },
-        ];
-        let layout: Option<&[BindGroupLayoutEntry]> = Some(entries);
-        let context = context
-            .with_pipeline("quant_mat_nf4_absmax", shader, "compute_absmax", layout)
-            .with_pipeline("quant_mat_nf4", shader, "quantize", layout);
+        self
+    }
+}
+
+/// Built-in shader files and their last modification times, for hot reloading.
+#[cfg(feature = "dev")]
+#[derive(Debug)]
+struct ShaderWatch {
+    modified: Mutex<HashMap<PathBuf, Option<SystemTime>>>,
+}
+
+#[cfg(feature = "dev")]
+impl ShaderWatch {
+    fn new(shader_dir: &Path) -> Self {
+        let modified = BUILTINS
+            .iter()
+            .map(|builtin| {
+                let path = shader_dir.join(builtin.file);
+                let time = Self::modified(&path);
+                (path, time)
+            })
+            .collect();
+        Self {
+            modified: Mutex::new(modified),
+        }
+    }
+
+    fn modified(path: &Path) -> Option<SystemTime> {
+        std::fs::metadata(path)
+            .and_then(|meta| meta.modified())
+            .ok()
+    }
 
-        context.with_pipeline(
-            "quant_fp16",
-            include_str!("shaders/quant_fp16.wgsl"),
-            "quantize",
-            None,
-        )
+    /// Files modified since the last check.
+    fn changed(&self) -> Vec<PathBuf> {
+        let mut modified = self.modified.lock().unwrap();
+        modified
+            .iter_mut()
+            .filter_map(|(path, time)| {
+                let current = Self::modified(path);
+                (current != *time).then(|| {
+                    *time = current;
+                    path.clone()
+                })
+            })
+            .collect()
     }
 }
 
@@ -441,27 +955,727 @@ impl PartialEq for Context {
 impl Eq for Context {}
 
 impl Context {
-    pub fn pipeline(&self, name: &'static str) -> Result<&ComputePipeline, TensorError> {
-        self.pipelines.get(name).ok_or(TensorError::Pipeline(name))
+    /// Size of each chunk of the staging belt used for uploads.
+    pub const STAGING_CHUNK_SIZE: u64 = 1 << 20;
+    /// Uploads smaller than this are written through the queue, which batches them into its next submission.
+    pub const STAGING_MIN_SIZE: u64 = 1 << 16;
+
+    /// Get a pipeline by name, compiling it if this is the first time it is requested.
+    pub fn pipeline(&self, name: &'static str) -> Result<Arc<ComputePipeline>, TensorError> {
+        self.pipeline_with(name, &[])
+    }
+
+    /// Get a pipeline by name, with its shader compiled for the given `#ifdef` symbols.
+    /// Each distinct set of symbols is compiled once, the first time it is requested.
+    pub fn pipeline_with(
+        &self,
+        name: &'static str,
+        defines: &[&'static str],
+    ) -> Result<Arc<ComputePipeline>, TensorError> {
+        self.pipeline_specialized(name, defines, &[])
+    }
+
+    /// Get a pipeline by name, with its shader compiled for the given `#ifdef` symbols
+    /// and with its `override` constants set to the given values.
+    /// Each distinct set of symbols and constants is compiled once, the first time it is requested.
+    pub fn pipeline_specialized(
+        &self,
+        name: &'static str,
+        defines: &[&'static str],
+        constants: &[(&'static str, u32)],
+    ) -> Result<Arc<ComputePipeline>, TensorError> {
+        let key = match self.deterministic {
+            true => PipelineKey::new(name, &[defines, &["DETERMINISTIC"]].concat(), constants),
+            false => PipelineKey::new(name, defines, constants),
+        };
+        if let Some(pipeline) = self.pipelines.read().unwrap().get(&key) {
+            self.pipeline_counter.hit();
+            return Ok(pipeline.clone());
+        }
+        self.pipeline_counter.miss();
+
+        let source = match self.sources.get(name) {
+            Some(source) => source.clone(),
+            None => self
+                .builtin_source(name)
+                .ok_or(TensorError::Pipeline(name))?,
+        };
+        let shader = specialize(&preprocess(&source.shader, &key.defines), &key.constants);
+        let pipeline = create_pipeline(
+            &self.device,
+            name,
+            &shader,
+            &source.entry_point,
+            source.layout.as_deref(),
+        );
+
+        let mut pipelines = self.pipelines.write().unwrap();
+        let pipeline = pipelines.entry(key).or_insert(pipeline.into());
+        Ok(pipeline.clone())
+    }
+
+    /// Source of a built-in pipeline. In dev mode, shaders are read from disk if possible.
+    fn builtin_source(&self, name: &str) -> Option<PipelineSource<'static>> {
+        let builtin = Builtin::find(name)?;
+        #[cfg(feature = "dev")]
+        if let Ok(shader) = std::fs::read_to_string(self.shader_dir.join(builtin.file)) {
+            return Some(PipelineSource {
+                shader: Cow::Owned(shader),
+                ..builtin.source()
+            });
+        }
+        Some(builtin.source())
     }
 
     pub fn request_shape_uniform(&self, shape: Shape) -> Arc<Buffer> {
-        self.shape_cache.request(shape, || {
+        let mut created = false;
+        let buffer = self.shape_cache.request(shape, || {
+            created = true;
             self.device.create_buffer_init(&BufferInitDescriptor {
                 label: None,
                 contents: &shape.into_bytes(),
                 usage: BufferUsages::UNIFORM,
             })
-        })
+        });
+        if created {
+            self.track_as(MemoryCategory::Cache, &buffer);
+        }
+        buffer
     }
 
     pub fn request_view_uniform(&self, view: View) -> Arc<Buffer> {
-        self.view_cache.request(view, || {
+        let mut created = false;
+        let buffer = self.view_cache.request(view, || {
+            created = true;
             self.device.create_buffer_init(&BufferInitDescriptor {
                 label: None,
                 contents: &view.into_bytes(),
                 usage: BufferUsages::UNIFORM,
             })
-        })
+        });
+        if created {
+            self.track_as(MemoryCategory::Cache, &buffer);
+        }
+        buffer
+    }
+
+    /// A uniform holding `value` in each of the 4 components of a `vec4<f32>`.
+    pub fn request_value_uniform(&self, value: f32) -> Arc<Buffer> {
+        let mut created = false;
+        let buffer = self.value_cache.request(value.to_bits(), || {
+            created = true;
+            self.device.create_buffer_init(&BufferInitDescriptor {
+                label: None,
+                contents: bytemuck::cast_slice(&[value; 4]),
+                usage: BufferUsages::UNIFORM,
+            })
+        });
+        if created {
+            self.track_as(MemoryCategory::Cache, &buffer);
+        }
+        buffer
+    }
+
+    /// Hits, misses and entries of the shape, view and value uniform caches and of the compiled pipelines.
+    pub fn cache_stats(&self) -> ContextCacheStats {
+        let pipelines = self.pipelines.read().unwrap().len();
+        ContextCacheStats {
+            shape: self.shape_cache.stats(),
+            view: self.view_cache.stats(),
+            value: self.value_cache.stats(),
+            pipeline: self.pipeline_counter.stats(pipelines),
+        }
+    }
+
+    /// Drop the cached shape, view and value uniforms. Those still bound by live ops are freed once the ops are.
+    pub fn clear_uniform_caches(&self) {
+        self.shape_cache.clear();
+        self.view_cache.clear();
+        self.value_cache.clear();
+    }
+
+    /// Drop the compiled pipelines; they are compiled again the next time they are requested.
+    pub fn clear_pipelines(&self) {
+        self.pipelines.write().unwrap().clear();
+    }
+
+    /// Tear down the device resources of the context now: drop the compiled pipelines and cached uniforms,
+    /// destroy every live buffer allocated through it, and wait for the device to release them.
+    ///
+    /// Tensors, states and models built on the context must not be used afterwards.
+    /// Call this when done with a context instead of relying on drop order, e.g. before loading another model.
+    pub fn close(&self) {
+        self.clear_pipelines();
+        self.clear_uniform_caches();
+
+        let allocations = std::mem::take(&mut *self.allocations.lock().unwrap());
+        for allocation in allocations {
+            if let Some(buffer) = allocation.buffer.upgrade() {
+                buffer.destroy();
+            }
+        }
+        self.device.poll(wgpu::MaintainBase::Wait);
+    }
+
+    /// Whether a thread owned by the context polls the device, see [`ContextBuilder::with_poll_thread`].
+    pub fn has_poll_thread(&self) -> bool {
+        self.poller
+    }
+
+    /// Wait for a turn to encode and submit work to the queue, held until the returned guard is dropped.
+    ///
+    /// Turns are handed out first come, first served, so that a model run in a loop on one thread
+    /// doesn't keep another model on the same context from submitting. Turns don't nest.
+    pub fn submission(&self) -> Submission<'_> {
+        let mut tickets = self.turns.tickets.lock().unwrap();
+        let ticket = tickets.0;
+        tickets.0 += 1;
+        let _tickets = self
+            .turns
+            .next
+            .wait_while(tickets, |(_, turn)| *turn != ticket)
+            .unwrap();
+        Submission { turns: &self.turns }
+    }
+
+    /// Run `f` with the errors it raises on the device captured, instead of handed to the uncaptured error handler,
+    /// which panics by default. Returns an out-of-memory error before any other, since those often follow from it.
+    /// Error scopes are shared by the whole device, so errors of other threads at the same time may be captured too.
+    /// On the web, where the scope can't be waited on, errors are not captured.
+    pub fn capture_errors<T>(&self, f: impl FnOnce() -> T) -> (T, Option<wgpu::Error>) {
+        #[cfg(target_arch = "wasm32")]
+        {
+            (f(), None)
+        }
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            self.device.push_error_scope(wgpu::ErrorFilter::Validation);
+            self.device.push_error_scope(wgpu::ErrorFilter::OutOfMemory);
+            let output = f();
+            let out_of_memory = block_on(self.device.pop_error_scope());
+            let validation = block_on(self.device.pop_error_scope());
+            (output, out_of_memory.or(validation))
+        }
+    }
+
+    /// Account buffers allocated on this context to `category` until the returned guard is dropped.
+    /// Scopes nest; dropping the guard restores the category of the enclosing scope.
+    /// The category is shared by every thread using the context.
+    pub fn memory_scope(&self, category: MemoryCategory) -> MemoryScope {
+        let previous = std::mem::replace(&mut *self.category.lock().unwrap(), category);
+        MemoryScope {
+            context: self.clone(),
+            previous,
+        }
+    }
+
+    /// Account `buffer` to the category of the current [`Context::memory_scope`] for as long as it lives.
+    pub fn track(&self, buffer: &Arc<Buffer>) {
+        let category = *self.category.lock().unwrap();
+        self.track_as(category, buffer);
+    }
+
+    fn track_as(&self, category: MemoryCategory, buffer: &Arc<Buffer>) {
+        let mut allocations = self.allocations.lock().unwrap();
+        // prune dropped buffers only when about to grow, so that tracking stays amortized O(1)
+        if allocations.len() == allocations.capacity() {
+            allocations.retain(|allocation| allocation.buffer.strong_count() > 0);
+        }
+        allocations.push(Allocation {
+            category,
+            size: buffer.size(),
+            buffer: Arc::downgrade(buffer),
+        });
+    }
+
+    /// Bytes of device memory held by live buffers allocated through this context, by category.
+    pub fn memory_usage(&self) -> MemoryUsage {
+        let mut allocations = self.allocations.lock().unwrap();
+        allocations.retain(|allocation| allocation.buffer.strong_count() > 0);
+        allocations
+            .iter()
+            .fold(MemoryUsage::default(), |mut usage, allocation| {
+                *usage.get_mut(allocation.category) += allocation.size;
+                usage
+            })
+    }
+
+    /// Upload `data` into `buffer` at `offset`. Uploads of at least [`Context::STAGING_MIN_SIZE`] go through
+    /// the staging belt, whose chunks are reused across uploads once the device is done copying from them;
+    /// smaller ones are written through the queue.
+    /// Both `offset` and the length of `data` must be multiples of [`wgpu::COPY_BUFFER_ALIGNMENT`].
+    pub fn write_buffer(&self, buffer: &Buffer, offset: u64, data: &[u8]) {
+        let Some(size) = BufferSize::new(data.len() as u64) else {
+            return;
+        };
+        if size.get() < Self::STAGING_MIN_SIZE {
+            self.queue.write_buffer(buffer, offset, data);
+            return;
+        }
+
+        let mut staging = self.staging.lock().unwrap();
+        // reclaim the chunks recalled by earlier uploads whose copies are done, without waiting for the others
+        self.device.poll(wgpu::MaintainBase::Poll);
+
+        let mut encoder = self
+            .device
+            .create_command_encoder(&CommandEncoderDescriptor::default());
+        staging
+            .write_buffer(&mut encoder, buffer, offset, size, &self.device)
+            .copy_from_slice(data);
+        staging.finish();
+
+        self.queue.submit(Some(encoder.finish()));
+        staging.recall();
+    }
+}
+
+#[cfg(feature = "dev")]
+impl Context {
+    /// Rebuild compiled built-in pipelines whose shader files changed on disk since the last call.
+    /// Shaders that fail to compile are logged and their old pipelines are kept.
+    /// Returns the names of the rebuilt pipelines, followed by their `#ifdef` symbols and constants in brackets if any.
+    pub fn reload_shaders(&self) -> Vec<String> {
+        let mut reloaded = vec![];
+        for path in self.watch.changed() {
+            let shader = match std::fs::read_to_string(&path) {
+                Ok(shader) => shader,
+                Err(err) => {
+                    log::error!("failed to read {}: {err}", path.display());
+                    continue;
+                }
+            };
+            let mut pipelines = self.pipelines.write().unwrap();
+            let keys = pipelines
+                .keys()
+                .filter(|key| !self.sources.contains_key(key.name))
+                .filter_map(|key| Some((key.clone(), Builtin::find(key.name)?)))
+                .filter(|(_, builtin)| self.shader_dir.join(builtin.file) == path)
+                .collect_vec();
+            for (key, builtin) in keys {
+                let shader = specialize(&preprocess(&shader, &key.defines), &key.constants);
+                let module = match crate::tensor::kernel::parse_wgsl(&shader) {
+                    Ok((module, _)) => module,
+                    Err(err) => {
+                        log::error!("failed to reload {key} from {}: {err}", path.display());
+                        continue;
+                    }
+                };
+                if !module
+                    .entry_points
+                    .iter()
+                    .any(|entry| entry.name == builtin.entry_point)
+                {
+                    log::error!("entry point {} of {key} not found", builtin.entry_point);
+                    continue;
+                }
+                let pipeline = create_pipeline(
+                    &self.device,
+                    key.name,
+                    &shader,
+                    builtin.entry_point,
+                    builtin.layout,
+                );
+                log::info!("reloaded pipeline {key}");
+                reloaded.push(key.to_string());
+                pipelines.insert(key, pipeline.into());
+            }
+        }
+        reloaded.sort();
+        reloaded
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use anyhow::Result;
+    use itertools::Itertools;
+    use wgpu::{Backends, PowerPreference};
+
+    use super::{preprocess, specialize, Builtin, Context, ContextBuilder, Instance};
+    use crate::{
+        model::{
+            synthetic::{
+                testing::{is_approx_eps, run},
+                SyntheticBuilder,
+            },
+            v4, v5, ModelBuilder, ModelVersion, StateBuilder,
+        },
+        tensor::{shape::Shape, ReadWrite, TensorError, TensorGpu},
+    };
+
+    fn create_context() -> Result<Context> {
+        let adapter = pollster::block_on(async {
+            let instance = Instance::new();
+            instance.adapter(PowerPreference::HighPerformance).await
+        })?;
+        let context = pollster::block_on(async { ContextBuilder::new(adapter).build().await })?;
+        Ok(context)
+    }
+
+    #[test]
+    fn test_instance_backends() {
+        // no backend, no adapter
+        let instance = Instance::new().with_backends(Backends::empty());
+        assert!(pollster::block_on(instance.adapter(PowerPreference::HighPerformance)).is_err());
+        assert!(instance.select_adapter(Backends::all(), 0).is_err());
+    }
+
+    #[test]
+    fn test_preprocess() {
+        let shader = "a\n#ifdef X\nb\n#ifndef Y\nc\n#else\nd\n#endif\n#else\ne\n#endif\nf\n";
+        assert_eq!(preprocess(shader, &[]), "a\ne\nf\n");
+        assert_eq!(preprocess(shader, &["X"]), "a\nb\nc\nf\n");
+        assert_eq!(preprocess(shader, &["X", "Y"]), "a\nb\nd\nf\n");
+        assert_eq!(preprocess(shader, &["Y"]), "a\ne\nf\n");
+    }
+
+    #[test]
+    fn test_specialize() {
+        let shader = "override A: u32;\n  override B: u32 = 4u;\noverride C: u32;\nlet x = A;\n";
+        assert_eq!(
+            specialize(shader, &[("A", 64), ("C", 1)]),
+            "const A: u32 = 64u;\nconst B: u32 = 4u;\nconst C: u32 = 1u;\nlet x = A;\n"
+        );
+        assert_eq!(
+            specialize(shader, &[("B", 8)]),
+            "override A: u32;\nconst B: u32 = 8u;\noverride C: u32;\nlet x = A;\n"
+        );
+    }
+
+    #[test]
+    fn test_shader_variants() -> Result<()> {
+        for name in ["layer_norm", "group_norm", "softmax"] {
+            let builtin = Builtin::find(name).unwrap();
+            for defines in [
+                &[][..],
+                &["ACT_F16"],
+                &["DETERMINISTIC"],
+                &["ACT_F16", "DETERMINISTIC"],
+            ] {
+                crate::tensor::kernel::parse_wgsl(&preprocess(builtin.shader, defines))?;
+            }
+        }
+        for name in [
+            "penalty", "mask", "verify", "score", "pool", "l2_norm", "cosine", "stats",
+        ] {
+            let builtin = Builtin::find(name).unwrap();
+            crate::tensor::kernel::parse_wgsl(&preprocess(builtin.shader, &[]))?;
+        }
+        let softmax = Builtin::find("softmax").unwrap();
+        for defines in [&["TEMPERATURE"][..], &["TEMPERATURE", "DETERMINISTIC"]] {
+            crate::tensor::kernel::parse_wgsl(&preprocess(softmax.shader, defines))?;
+        }
+        for (name, constant) in [
+            ("time_mix_v5", "HEAD_SIZE"),
+            ("matmul_vec_fp16", "COLUMNS"),
+            ("matmul_vec_int8", "COLUMNS"),
+        ] {
+            let builtin = Builtin::find(name).unwrap();
+            let shader = specialize(&preprocess(builtin.shader, &[]), &[(constant, 64)]);
+            crate::tensor::kernel::parse_wgsl(&shader)?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_pipeline() -> Result<()> {
+        let context = match create_context() {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+
+        // compiled once on first use, then shared
+        let softmax = context.pipeline("softmax")?;
+        assert!(Arc::ptr_eq(&softmax, &context.pipeline("softmax")?));
+        context.pipeline("quant_mat_nf4")?;
+        assert!(matches!(
+            context.pipeline("missing"),
+            Err(TensorError::Pipeline("missing"))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_cache_stats() -> Result<()> {
+        let context = match create_context() {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+
+        context.pipeline("softmax")?;
+        context.pipeline("softmax")?;
+        let stats = context.cache_stats().pipeline;
+        assert_eq!((stats.hits, stats.misses, stats.entries), (1, 1, 1));
+
+        let shape = Shape::new(4, 3, 2, 1);
+        let uniform = context.request_shape_uniform(shape);
+        assert!(Arc::ptr_eq(&uniform, &context.request_shape_uniform(shape)));
+        assert_eq!(context.cache_stats().shape.hits, 1);
+        let value = context.request_value_uniform(0.5);
+        assert!(Arc::ptr_eq(&value, &context.request_value_uniform(0.5)));
+        assert!(!Arc::ptr_eq(&value, &context.request_value_uniform(-0.5)));
+        assert_eq!(context.cache_stats().value.entries, 2);
+
+        context.clear_uniform_caches();
+        context.clear_pipelines();
+        let stats = context.cache_stats();
+        assert_eq!(
+            (
+                stats.shape.entries,
+                stats.value.entries,
+                stats.pipeline.entries
+            ),
+            (0, 0, 0)
+        );
+
+        // cleared entries are created again on request
+        assert!(!Arc::ptr_eq(
+            &uniform,
+            &context.request_shape_uniform(shape)
+        ));
+        context.pipeline("softmax")?;
+        assert_eq!(context.cache_stats().pipeline.misses, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_destroy() -> Result<()> {
+        let context = match create_context() {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+
+        let shape = Shape::new(1024, 1, 1, 1);
+        let x: TensorGpu<f32, ReadWrite> = context.tensor_init(shape);
+        let y = x.clone();
+        let size = context.memory_usage().other;
+        assert!(size > 0);
+
+        // the buffer is shared with `y`, so it is left alone
+        assert!(!x.destroy());
+        assert_eq!(context.memory_usage().other, size);
+        assert!(y.destroy());
+        assert_eq!(context.memory_usage().other, 0);
+
+        let _z: TensorGpu<f32, ReadWrite> = context.tensor_init(shape);
+        context.pipeline("softmax")?;
+        context.close();
+        assert_eq!(context.memory_usage().total(), 0);
+        assert_eq!(context.cache_stats().pipeline.entries, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_capture_errors() -> Result<()> {
+        let context = match create_context() {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+
+        let buffer = |size: u64| {
+            context.device.create_buffer(&wgpu::BufferDescriptor {
+                label: None,
+                size,
+                usage: wgpu::BufferUsages::STORAGE,
+                mapped_at_creation: false,
+            })
+        };
+        let (_, error) = context.capture_errors(|| buffer(1024));
+        assert!(error.is_none());
+
+        // a buffer over the limit is an error of the scope, not a panic
+        let size = context.device.limits().max_buffer_size + 1024;
+        let (_, error) = context.capture_errors(|| buffer(size));
+        assert!(error.is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn test_submission() -> Result<()> {
+        let context = match create_context() {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+
+        let order = Mutex::new(vec![]);
+        let first = context.submission();
+        std::thread::scope(|scope| {
+            let handle = scope.spawn(|| {
+                let _submission = context.submission();
+                order.lock().unwrap().push(1);
+            });
+            std::thread::sleep(Duration::from_millis(50));
+            order.lock().unwrap().push(0);
+            drop(first);
+            handle.join().unwrap();
+        });
+        assert_eq!(order.into_inner().unwrap(), vec![0, 1]);
+
+        // turns are passed on, so taking another doesn't block
+        drop(context.submission());
+        Ok(())
+    }
+
+    #[cfg(feature = "dev")]
+    #[test]
+    fn test_reload_shaders() -> Result<()> {
+        use std::{fs::File, time::SystemTime};
+
+        let shader_dir = std::env::temp_dir().join("web-rwkv-test-reload-shaders");
+        std::fs::create_dir_all(&shader_dir)?;
+        let path = shader_dir.join("cast.wgsl");
+        std::fs::write(&path, include_str!("shaders/cast.wgsl"))?;
+
+        let adapter = match pollster::block_on(async {
+            let instance = Instance::new();
+            instance.adapter(PowerPreference::HighPerformance).await
+        }) {
+            Ok(adapter) => adapter,
+            Err(_) => return Ok(()),
+        };
+        let context = pollster::block_on(async {
+            ContextBuilder::new(adapter)
+                .with_shader_dir(&shader_dir)
+                .build()
+                .await
+        })?;
+        context.pipeline("cast_f16")?;
+        context.pipeline("cast_f32")?;
+        assert!(context.reload_shaders().is_empty());
+
+        let touch = |time: SystemTime| -> Result<()> {
+            File::options()
+                .write(true)
+                .open(&path)?
+                .set_modified(time)?;
+            Ok(())
+        };
+
+        // both compiled entry points of the file are rebuilt
+        touch(SystemTime::now() + Duration::from_secs(1))?;
+        assert_eq!(context.reload_shaders(), vec!["cast_f16", "cast_f32"]);
+        assert!(context.reload_shaders().is_empty());
+
+        // broken shaders keep the old pipelines
+        std::fs::write(&path, "fn broken(")?;
+        touch(SystemTime::now() + Duration::from_secs(2))?;
+        assert!(context.reload_shaders().is_empty());
+        assert!(context.pipeline("cast_f16").is_ok());
+
+        std::fs::remove_dir_all(&shader_dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_shared_context() -> Result<()> {
+        let context = match create_context() {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+
+        // e.g. a draft and a target model on the same device
+        let builder = SyntheticBuilder::new(ModelVersion::V5).with_num_layer(1);
+        let draft_info = builder.info();
+        let draft_data = builder.build()?;
+        let builder = SyntheticBuilder::new(ModelVersion::V4);
+        let target_info = builder.info();
+        let target_data = builder.build()?;
+
+        let draft: v5::Model = ModelBuilder::new(&context, &draft_data)
+            .with_token_chunk_size(4)
+            .build()?;
+        let target: v4::Model = ModelBuilder::new(&context, &target_data)
+            .with_token_chunk_size(4)
+            .build()?;
+
+        let tokens = vec![vec![5u16, 23, 177, 2, 94, 31, 8]];
+        let expected_draft = {
+            let state: v5::ModelState = StateBuilder::new(&context, &draft_info).build();
+            run(&draft, &state, &tokens)?
+        };
+        let expected_target = {
+            let state: v4::ModelState = StateBuilder::new(&context, &target_info).build();
+            run(&target, &state, &tokens)?
+        };
+
+        // both models run in a loop at the same time, taking turns to submit
+        let (drafts, targets) = std::thread::scope(|scope| {
+            let drafts = scope.spawn(|| -> Result<_> {
+                (0..4)
+                    .map(|_| {
+                        let state: v5::ModelState =
+                            StateBuilder::new(&context, &draft_info).build();
+                        run(&draft, &state, &tokens)
+                    })
+                    .collect::<Result<Vec<_>>>()
+            });
+            let targets = scope.spawn(|| -> Result<_> {
+                (0..4)
+                    .map(|_| {
+                        let state: v4::ModelState =
+                            StateBuilder::new(&context, &target_info).build();
+                        run(&target, &state, &tokens)
+                    })
+                    .collect::<Result<Vec<_>>>()
+            });
+            (drafts.join().unwrap(), targets.join().unwrap())
+        });
+
+        let check = |output: Vec<Option<Vec<f32>>>, expected: &[Option<Vec<f32>>]| {
+            let output = output[0].as_ref().unwrap();
+            let expected = expected[0].as_ref().unwrap();
+            for (&a, &b) in output.iter().zip_eq(expected) {
+                assert!(is_approx_eps(a, b, 1.0e-3), "{a} != {b}");
+            }
+        };
+        for output in drafts? {
+            check(output, &expected_draft);
+        }
+        for output in targets? {
+            check(output, &expected_target);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_memory_usage() -> Result<()> {
+        let context = match create_context() {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+
+        let builder = SyntheticBuilder::new(ModelVersion::V5);
+        let info = builder.info();
+        let data = builder.build()?;
+
+        let model: v5::Model = ModelBuilder::new(&context, &data).build()?;
+        let usage = context.memory_usage();
+        assert!(usage.weights > 0);
+        assert_eq!(usage.state, 0);
+
+        let state: v5::ModelState = StateBuilder::new(&context, &info)
+            .with_chunk_size(1)
+            .build();
+        let state_size = (info.num_layer
+            * (info.num_emb * (info.num_emb / info.num_head + 2))
+            * std::mem::size_of::<f32>()) as u64;
+        assert_eq!(context.memory_usage().state, state_size);
+
+        run(&model, &state, &[vec![0, 1, 2]])?;
+        let usage = context.memory_usage();
+        assert!(usage.runtime > 0 && usage.cache > 0);
+
+        drop(state);
+        assert_eq!(context.memory_usage().state, 0);
+        drop(model);
+        let usage = context.memory_usage();
+        assert_eq!((usage.weights, usage.runtime), (0, 0));
+        Ok(())
     }
 }
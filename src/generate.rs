@@ -0,0 +1,1082 @@
+//! Driving batched generation, one stream of tokens per batch of the state.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    convert::Infallible,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use fastrand::Rng;
+use itertools::Itertools;
+
+use crate::{
+    model::{
+        cache::PromptCache,
+        slot::{SlotKey, Slots},
+        FromBuilder, Model, ModelState, StateBuilder,
+    },
+    processor::{LogitsProcessor, ProcessorChain},
+    sampler::{par_map, Sampler},
+    tensor::TensorCpu,
+    tokenizer::Tokenizer,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GenerateError {
+    /// A stream must be given at least one prompt token to produce logits from.
+    EmptyPrompt,
+    /// All batches of the state are taken by other streams.
+    Full,
+}
+
+impl std::fmt::Display for GenerateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GenerateError::EmptyPrompt => write!(f, "prompt has no tokens"),
+            GenerateError::Full => write!(f, "all batches are taken"),
+        }
+    }
+}
+
+impl std::error::Error for GenerateError {}
+
+/// When a stream stops generating. Whichever is met first wins.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StopCondition {
+    /// Stop after generating this many tokens.
+    pub max_tokens: Option<usize>,
+    /// Stop when sampling any of these. The stop token is not part of the output.
+    pub stop_tokens: Vec<u16>,
+    /// Stop once the output text contains any of these. The output is cut before it.
+    pub stop_strings: Vec<String>,
+    /// Stop once the prompt and the generated tokens add up to this many.
+    pub max_total_tokens: Option<usize>,
+    /// Stop this long after the stream is queued or pushed, even in the middle of the prompt.
+    /// Checked once per [`Generator::step`], so a stream may overrun it by the time of one step.
+    pub deadline: Option<Duration>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FinishReason {
+    /// Reached `max_tokens`.
+    Length,
+    /// Sampled this stop token.
+    Token(u16),
+    /// Generated this stop string.
+    String(String),
+    /// Ran out of `max_total_tokens` or past the `deadline`.
+    Budget,
+}
+
+/// Picks the next token given the probabilities.
+/// It is `Send` so that the streams can be sampled in parallel with the `rayon` feature.
+pub type BoxedSampler<'a> = Box<dyn FnMut(&[f32]) -> u16 + Send + 'a>;
+
+/// A prompt to generate from, and how to sample and when to stop.
+pub struct Stream<'a> {
+    pub prompt: Vec<u16>,
+    pub stop: StopCondition,
+    pub sampler: BoxedSampler<'a>,
+    /// Embeddings run before the prompt, see [`Stream::with_soft_prompt`].
+    pub soft_prompt: Option<TensorCpu<'a, f32>>,
+    /// Number of leading prompt tokens to look up in and store to the prompt cache, see [`Stream::with_cached_prefix`].
+    pub cached_prefix: usize,
+}
+
+impl<'a> Stream<'a> {
+    /// Run `embeds`, e.g. the learned prefix of a prefix-tuned adapter, before the prompt when the stream starts.
+    /// - `embeds` shape: `[C, T, 1]`; see [`Model::run_embeds`].
+    pub fn with_soft_prompt(self, embeds: TensorCpu<'a, f32>) -> Self {
+        Self {
+            soft_prompt: Some(embeds),
+            ..self
+        }
+    }
+
+    /// Start from the state after the first `len` prompt tokens, e.g. a system prompt, if the prompt cache of the generator has it,
+    /// or store it there after running them. See [`Generator::with_prompt_cache`].
+    /// The last prompt token is always run, and streams with a soft prompt bypass the cache.
+    pub fn with_cached_prefix(self, len: usize) -> Self {
+        Self {
+            cached_prefix: len,
+            ..self
+        }
+    }
+}
+
+/// Where the time of a stream went, from when it was queued or pushed until it finished.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Timing {
+    /// Waiting in the queue for a free batch.
+    pub queue: Duration,
+    /// From taking a batch to sampling the first token, i.e., the time to first token after the queue.
+    pub prefill: Duration,
+    /// From the first sampled token to the end.
+    pub decode: Duration,
+}
+
+impl Timing {
+    pub fn total(&self) -> Duration {
+        self.queue + self.prefill + self.decode
+    }
+}
+
+/// The output of a finished stream.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Generation {
+    pub key: SlotKey,
+    /// Sampled tokens, including the one completing a stop string but not a stop token.
+    pub tokens: Vec<u16>,
+    /// The output, converted to text with invalid UTF-8 replaced; see `bytes` for the output as it is.
+    pub text: String,
+    /// The output as decoded, before the conversion to `text`.
+    pub bytes: Vec<u8>,
+    pub reason: FinishReason,
+    /// Number of prompt tokens, including those restored from the prompt cache.
+    pub prompt_tokens: usize,
+    /// Prompt tokens restored from the prompt cache instead of run.
+    pub cached_tokens: usize,
+    pub timing: Timing,
+}
+
+/// What happened to a stream during a [`Generator::step`].
+#[derive(Debug)]
+pub enum GenerationEvent {
+    /// A stream from [`Generator::enqueue`] took a batch.
+    Started { ticket: usize, key: SlotKey },
+    /// Some prompt tokens were run, `processed` out of `total` so far.
+    /// The prompt is done once they are equal.
+    PromptProcessed {
+        key: SlotKey,
+        processed: usize,
+        total: usize,
+    },
+    /// A token was sampled, with the bytes it decodes to, which may split a UTF-8 character.
+    Token {
+        key: SlotKey,
+        token: u16,
+        bytes: Vec<u8>,
+    },
+    /// The stream met its stop condition and left its batch; the reason is in the generation.
+    Finished(Generation),
+    /// The stream failed and left its batch. Other streams are unaffected.
+    Error { key: SlotKey, error: anyhow::Error },
+}
+
+struct Active<'a> {
+    stop: StopCondition,
+    sampler: BoxedSampler<'a>,
+    /// Number of prompt tokens, and how many of them have been run.
+    prompt: (usize, usize),
+    /// Prompt tokens restored from the prompt cache.
+    cached: usize,
+    /// When the stream was queued or pushed, from which the deadline counts.
+    since: Instant,
+    /// When the stream took a batch.
+    started: Instant,
+    /// When the first token was sampled.
+    first_token: Option<Instant>,
+    /// Tokens still to be fed to the model.
+    input: Vec<u16>,
+    tokens: Vec<u16>,
+    text: Vec<u8>,
+}
+
+impl Active<'_> {
+    /// Take a sampled token, returning why the stream finished, if it did.
+    fn push(&mut self, tokenizer: &Tokenizer, token: u16) -> Result<Option<FinishReason>> {
+        self.first_token.get_or_insert_with(Instant::now);
+        if self.stop.stop_tokens.contains(&token) {
+            return Ok(Some(FinishReason::Token(token)));
+        }
+
+        // only the tail that the new token touches can hold a new stop string
+        let start = self.text.len();
+        self.tokens.push(token);
+        tokenizer.decode_into(&[token], &mut self.text)?;
+        for stop in &self.stop.stop_strings {
+            let stop = stop.as_bytes();
+            let from = start.saturating_sub(stop.len().saturating_sub(1));
+            if let Some(index) = self.text[from..]
+                .windows(stop.len().max(1))
+                .position(|window| window == stop)
+            {
+                self.text.truncate(from + index);
+                let stop = String::from_utf8_lossy(stop).into();
+                return Ok(Some(FinishReason::String(stop)));
+            }
+        }
+
+        if self
+            .stop
+            .max_tokens
+            .is_some_and(|max| self.tokens.len() >= max)
+        {
+            return Ok(Some(FinishReason::Length));
+        }
+        if self
+            .stop
+            .max_total_tokens
+            .is_some_and(|max| self.prompt.0 + self.tokens.len() >= max)
+        {
+            return Ok(Some(FinishReason::Budget));
+        }
+
+        self.input = vec![token];
+        Ok(None)
+    }
+
+    fn finish(self, key: SlotKey, reason: FinishReason) -> Generation {
+        let now = Instant::now();
+        let first_token = self.first_token.unwrap_or(now);
+        let timing = Timing {
+            queue: self.started - self.since,
+            prefill: first_token - self.started,
+            decode: now - first_token,
+        };
+        Generation {
+            key,
+            tokens: self.tokens,
+            text: String::from_utf8_lossy(&self.text).into(),
+            bytes: self.text,
+            reason,
+            prompt_tokens: self.prompt.0,
+            cached_tokens: self.cached,
+            timing,
+        }
+    }
+}
+
+/// Throughput and load of a [`Generator`] since it was created or its metrics were reset.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Metrics {
+    /// Prompt tokens run.
+    pub prefill_tokens: usize,
+    /// Share of step time spent on prompt tokens, split by the number of tokens run.
+    pub prefill_time: Duration,
+    /// Sampled tokens fed back to the model.
+    pub decode_tokens: usize,
+    /// Share of step time spent on sampled tokens.
+    pub decode_time: Duration,
+    /// Streams waiting for a free batch.
+    pub queue_depth: usize,
+    /// Streams holding a batch.
+    pub active_slots: usize,
+    /// Time spent waiting on the device to run the model and softmax.
+    /// Measured on the host, so it includes submission and read back.
+    pub gpu_time: Duration,
+}
+
+impl Metrics {
+    /// Prompt tokens per second.
+    pub fn prefill_rate(&self) -> f64 {
+        rate(self.prefill_tokens, self.prefill_time)
+    }
+
+    /// Sampled tokens per second.
+    pub fn decode_rate(&self) -> f64 {
+        rate(self.decode_tokens, self.decode_time)
+    }
+}
+
+fn rate(tokens: usize, time: Duration) -> f64 {
+    match time.is_zero() {
+        true => 0.0,
+        false => tokens as f64 / time.as_secs_f64(),
+    }
+}
+
+/// Runs many streams through the batches of one state, each with its own stop condition.
+/// A finished stream frees its batch, so a pending one can take it while the others carry on.
+pub struct Generator<'a, M: Model> {
+    model: &'a M,
+    state: &'a M::ModelState,
+    tokenizer: &'a Tokenizer,
+    /// Loaded into a batch before a stream starts in it.
+    initial: <M::ModelState as ModelState>::BackedState,
+    slots: Slots<Active<'a>>,
+    /// Streams waiting for a free batch, with their tickets and when they were queued.
+    queue: VecDeque<(usize, Instant, Stream<'a>)>,
+    num_ticket: usize,
+    metrics: Metrics,
+    /// Applied to the logits of all streams before sampling.
+    processor: ProcessorChain<'a>,
+    /// Snapshots of the state after the cached prefixes of streams.
+    cache: Option<PromptCache>,
+}
+
+impl<'a, M: Model> Generator<'a, M> {
+    /// `initial` is a single batch state, e.g. built with [`StateBuilder::build_backed`](crate::model::StateBuilder::build_backed).
+    pub fn new(
+        model: &'a M,
+        state: &'a M::ModelState,
+        tokenizer: &'a Tokenizer,
+        initial: <M::ModelState as ModelState>::BackedState,
+    ) -> Self {
+        Self {
+            model,
+            state,
+            tokenizer,
+            initial,
+            slots: Slots::new(state.max_batch()),
+            queue: VecDeque::new(),
+            num_ticket: 0,
+            metrics: Default::default(),
+            processor: ProcessorChain::new(),
+            cache: None,
+        }
+    }
+
+    /// Apply `processor` to the logits before sampling, after the ones added before it.
+    pub fn with_processor(self, processor: impl LogitsProcessor + 'a) -> Self {
+        Self {
+            processor: self.processor.with(processor),
+            ..self
+        }
+    }
+
+    /// Look up the cached prefixes of streams in `cache` before running their prompts, and store the ones it misses.
+    /// The cache must be of the model of the generator and a state like `initial`.
+    pub fn with_prompt_cache(self, cache: PromptCache) -> Self {
+        Self {
+            cache: Some(cache),
+            ..self
+        }
+    }
+
+    /// Number of streams generating or waiting in the queue.
+    pub fn len(&self) -> usize {
+        self.slots.len() + self.queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.slots.is_empty() && self.queue.is_empty()
+    }
+
+    /// Whether all batches are taken, so no stream can be pushed until one finishes.
+    pub fn is_full(&self) -> bool {
+        self.slots.len() == self.slots.max_batch()
+    }
+
+    pub fn metrics(&self) -> Metrics {
+        Metrics {
+            queue_depth: self.queue.len(),
+            active_slots: self.slots.len(),
+            ..self.metrics.clone()
+        }
+    }
+
+    /// Zero the counters and timers of the metrics.
+    pub fn reset_metrics(&mut self) {
+        self.metrics = Default::default();
+    }
+
+    /// Queue a stream to start in the first batch that frees up, returning its ticket.
+    /// [`GenerationEvent::Started`] tells which batch it took.
+    pub fn enqueue(&mut self, stream: Stream<'a>) -> Result<usize> {
+        if stream.prompt.is_empty() {
+            return Err(GenerateError::EmptyPrompt.into());
+        }
+        let ticket = self.num_ticket;
+        self.num_ticket += 1;
+        self.queue.push_back((ticket, Instant::now(), stream));
+        Ok(ticket)
+    }
+
+    /// Start a stream in a free batch.
+    pub fn push(&mut self, stream: Stream<'a>) -> Result<SlotKey> {
+        self.push_since(stream, Instant::now())
+    }
+
+    fn push_since(&mut self, stream: Stream<'a>, since: Instant) -> Result<SlotKey> {
+        if stream.prompt.is_empty() {
+            return Err(GenerateError::EmptyPrompt.into());
+        }
+        let started = Instant::now();
+        let Stream {
+            prompt,
+            stop,
+            sampler,
+            soft_prompt,
+            cached_prefix,
+        } = stream;
+        let active = Active {
+            stop,
+            sampler,
+            prompt: (prompt.len(), 0),
+            cached: 0,
+            since,
+            started,
+            first_token: None,
+            input: prompt.clone(),
+            tokens: vec![],
+            text: vec![],
+        };
+        let key = self.slots.allocate(active).ok_or(GenerateError::Full)?;
+        let reset = self
+            .state
+            .load_batch(&self.initial, key.batch())
+            .and_then(|_| match &soft_prompt {
+                Some(embeds) => self.model.run_embeds(embeds, key.batch(), self.state),
+                None => Ok(()),
+            })
+            .and_then(|_| self.processor.reset(key.batch()))
+            .and_then(|_| self.processor.extend(key.batch(), &prompt));
+        let prefix = match soft_prompt {
+            Some(_) => 0,
+            None => cached_prefix.min(prompt.len() - 1),
+        };
+        let cached = match reset.and_then(|_| self.warm_start(&prompt[..prefix], key.batch())) {
+            Ok(cached) => cached,
+            Err(err) => {
+                self.slots.free(key);
+                return Err(err);
+            }
+        };
+        if prefix > 0 && self.cache.is_some() {
+            let active = self.slots.get_mut(key).expect("stream is active");
+            active.prompt.1 = prefix;
+            active.cached = cached;
+            active.input = prompt[prefix..].to_vec();
+        }
+        Ok(key)
+    }
+
+    /// Bring `batch` to the state after `prefix` with the prompt cache, running the part of it not cached
+    /// and storing the state after it. Returns the number of tokens restored from the cache.
+    fn warm_start(&mut self, prefix: &[u16], batch: usize) -> Result<usize> {
+        let Some(cache) = &self.cache else {
+            return Ok(0);
+        };
+        if prefix.is_empty() {
+            return Ok(0);
+        }
+
+        let len = cache.longest_prefix(prefix).unwrap_or_default();
+        let start = match cache.load(&prefix[..len], &self.initial)? {
+            Some(backed) if len > 0 => {
+                self.state.load_batch(&backed, batch)?;
+                len
+            }
+            _ => 0,
+        };
+        if start == prefix.len() {
+            return Ok(start);
+        }
+
+        let instant = Instant::now();
+        let mut tokens = vec![vec![]; self.state.max_batch()];
+        tokens[batch] = prefix[start..].to_vec();
+        while !tokens[batch].is_empty() {
+            self.model.run(&mut tokens, self.state)?;
+        }
+        let backed = self.state.back_batch(batch)?;
+        cache.save(prefix, &backed)?;
+
+        self.metrics.prefill_tokens += prefix.len() - start;
+        self.metrics.prefill_time += instant.elapsed();
+        Ok(start)
+    }
+
+    /// Run the model once, sampling a token for each stream done with its input.
+    /// Errors of a single stream are reported as events; only a failed run is returned as an error.
+    pub fn step(&mut self) -> Result<Vec<GenerationEvent>> {
+        let mut events = vec![];
+        while !self.is_full() {
+            let Some((ticket, since, stream)) = self.queue.pop_front() else {
+                break;
+            };
+            let key = self.push_since(stream, since)?;
+            events.push(GenerationEvent::Started { ticket, key });
+        }
+        if self.slots.is_empty() {
+            return Ok(events);
+        }
+        let instant = Instant::now();
+
+        let input = self
+            .slots
+            .occupied()
+            .map(|(key, active)| (key, active.input.clone()))
+            .collect::<Vec<_>>();
+        let keys = input.iter().map(|(key, _)| *key).collect::<Vec<_>>();
+        let mut tokens = self.slots.tokens(input);
+        let gpu_instant = Instant::now();
+        let mut logits = self.model.run(&mut tokens, self.state)?;
+        let mut gpu_time = gpu_instant.elapsed();
+
+        let (mut num_prefill, mut num_decode) = (0, 0);
+        for key in &keys {
+            let active = self.slots.get_mut(*key).expect("stream is active");
+            let input = std::mem::take(&mut tokens[key.batch()]);
+            let num_token = active.input.len() - input.len();
+            let (total, processed) = &mut active.prompt;
+            if *processed == *total {
+                num_decode += num_token;
+            }
+            if *processed < *total && num_token > 0 {
+                num_prefill += num_token;
+                *processed += num_token;
+                events.push(GenerationEvent::PromptProcessed {
+                    key: *key,
+                    processed: *processed,
+                    total: *total,
+                });
+            }
+            active.input = input;
+        }
+        if logits.iter().any(Option::is_some) {
+            self.processor.process(&mut logits)?;
+            let gpu_instant = Instant::now();
+            let probs = self.model.softmax(logits)?;
+            gpu_time += gpu_instant.elapsed();
+            let tokens = self.sample(probs, &mut events);
+            self.processor.update(&tokens)?;
+        }
+        self.expire(&mut events);
+
+        let time = instant.elapsed();
+        let num_token = (num_prefill + num_decode).max(1) as u32;
+        self.metrics.gpu_time += gpu_time;
+        self.metrics.prefill_tokens += num_prefill;
+        self.metrics.prefill_time += time * num_prefill as u32 / num_token;
+        self.metrics.decode_tokens += num_decode;
+        self.metrics.decode_time += time * num_decode as u32 / num_token;
+        Ok(events)
+    }
+
+    /// Finish the streams past their deadlines with what they have generated so far.
+    fn expire(&mut self, events: &mut Vec<GenerationEvent>) {
+        let expired = self
+            .slots
+            .occupied()
+            .filter(|(_, active)| {
+                let deadline = active.stop.deadline;
+                deadline.is_some_and(|deadline| active.since.elapsed() >= deadline)
+            })
+            .map(|(key, _)| key)
+            .collect_vec();
+        for key in expired {
+            let active = self.slots.free(key).expect("stream is active");
+            let generation = active.finish(key, FinishReason::Budget);
+            events.push(GenerationEvent::Finished(generation));
+        }
+    }
+
+    /// Sample a token for each stream with output, returning the tokens by batch.
+    /// Streams are sampled in parallel if the `rayon` feature is enabled, then take their tokens in batch order.
+    fn sample(
+        &mut self,
+        probs: Vec<Option<Vec<f32>>>,
+        events: &mut Vec<GenerationEvent>,
+    ) -> Vec<Option<u16>> {
+        let jobs = self
+            .slots
+            .occupied_mut()
+            .filter_map(|(key, active)| Some((key, active, probs[key.batch()].as_deref()?)))
+            .collect_vec();
+        let picks = par_map(jobs, |(key, active, probs)| (key, (active.sampler)(probs)));
+
+        let mut tokens = vec![None; probs.len()];
+        for (key, token) in picks {
+            let active = self.slots.get_mut(key).expect("stream is active");
+            tokens[key.batch()] = Some(token);
+            let start = active.text.len();
+            let reason = match active.push(self.tokenizer, token) {
+                Ok(reason) => reason,
+                Err(error) => {
+                    self.slots.free(key);
+                    events.push(GenerationEvent::Error { key, error });
+                    continue;
+                }
+            };
+            if matches!(
+                reason,
+                None | Some(FinishReason::Length) | Some(FinishReason::Budget)
+            ) {
+                let bytes = active.text[start..].to_vec();
+                events.push(GenerationEvent::Token { key, token, bytes });
+            }
+            if let Some(reason) = reason {
+                let active = self.slots.free(key).expect("stream is active");
+                let generation = active.finish(key, reason);
+                events.push(GenerationEvent::Finished(generation));
+            }
+        }
+        tokens
+    }
+}
+
+/// Options of [`GenerateBatch::generate_batch`], shared by all prompts.
+#[derive(Debug, Clone)]
+pub struct GenerateParams {
+    /// How many prompts are generated at the same time.
+    pub max_batch: usize,
+    pub sampler: Sampler,
+    pub stop: StopCondition,
+    /// Seed for sampling; random if not set.
+    pub seed: Option<u64>,
+}
+
+impl Default for GenerateParams {
+    fn default() -> Self {
+        Self {
+            max_batch: 4,
+            sampler: Default::default(),
+            stop: StopCondition {
+                max_tokens: Some(256),
+                ..Default::default()
+            },
+            seed: None,
+        }
+    }
+}
+
+pub trait GenerateBatch: Model {
+    /// Generate a completion for each prompt, returned in the order of `prompts`.
+    /// Prompts are prefilled together and decoded in parallel on a state allocated for the call.
+    fn generate_batch(
+        &self,
+        tokenizer: &Tokenizer,
+        prompts: &[impl AsRef<str>],
+        params: &GenerateParams,
+    ) -> Result<Vec<Generation>>;
+}
+
+impl<M> GenerateBatch for M
+where
+    M: Model,
+    M::ModelState: FromBuilder<Builder<'static> = StateBuilder, Error = Infallible>,
+    <M::ModelState as ModelState>::BackedState:
+        FromBuilder<Builder<'static> = StateBuilder, Error = Infallible>,
+{
+    fn generate_batch(
+        &self,
+        tokenizer: &Tokenizer,
+        prompts: &[impl AsRef<str>],
+        params: &GenerateParams,
+    ) -> Result<Vec<Generation>> {
+        let max_batch = params.max_batch.clamp(1, prompts.len().max(1));
+        let state: M::ModelState = StateBuilder::new(self.context(), self.info())
+            .with_max_batch(max_batch)
+            .build();
+        let initial = StateBuilder::new(self.context(), self.info()).build_backed();
+        let mut generator = Generator::new(self, &state, tokenizer, initial);
+
+        let mut rng = params.seed.map(Rng::with_seed).unwrap_or_default();
+        let mut streams = Vec::with_capacity(prompts.len());
+        for prompt in prompts {
+            let mut rng = Rng::with_seed(rng.u64(..));
+            let sampler = params.sampler;
+            streams.push(Stream {
+                prompt: tokenizer.encode(prompt.as_ref().as_bytes())?,
+                stop: params.stop.clone(),
+                sampler: Box::new(move |probs| sampler.sample(probs, &mut rng)),
+                soft_prompt: None,
+                cached_prefix: 0,
+            });
+        }
+
+        // tickets of a fresh generator count from zero, in the order of the prompts
+        for stream in streams {
+            generator.enqueue(stream)?;
+        }
+        let mut indices = HashMap::new();
+        let mut outputs = vec![None; prompts.len()];
+        while !generator.is_empty() {
+            for event in generator.step()? {
+                match event {
+                    GenerationEvent::Started { ticket, key } => {
+                        indices.insert(key, ticket);
+                    }
+                    GenerationEvent::Finished(generation) => {
+                        let index = indices[&generation.key];
+                        outputs[index] = Some(generation);
+                    }
+                    GenerationEvent::Error { error, .. } => return Err(error),
+                    _ => {}
+                }
+            }
+        }
+        Ok(outputs.into_iter().flatten().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, time::Duration};
+
+    use anyhow::Result;
+    use itertools::Itertools;
+
+    use super::{
+        FinishReason, GenerateBatch, GenerateParams, GenerationEvent, Generator, StopCondition,
+        Stream,
+    };
+    use crate::{
+        model::{
+            cache::PromptCache,
+            synthetic::{testing::create_context, SyntheticBuilder},
+            v5, Checksum, Model, ModelBuilder, ModelVersion, StateBuilder,
+        },
+        sampler::Sampler,
+        tokenizer::Tokenizer,
+    };
+
+    fn argmax(probs: &[f32]) -> u16 {
+        probs
+            .iter()
+            .position_max_by(|x, y| x.total_cmp(y))
+            .unwrap_or_default() as u16
+    }
+
+    /// A vocabulary with each token written as `<token>`.
+    fn tokenizer(num_vocab: usize) -> Result<Tokenizer> {
+        let vocab = (0..num_vocab)
+            .map(|token| (token.to_string(), format!("<{token}>")))
+            .collect::<HashMap<_, _>>();
+        Ok(Tokenizer::new(&serde_json::to_string(&vocab)?)?)
+    }
+
+    /// Greedily generate `count` tokens from `prompt`, one stream at a time.
+    fn greedy(model: &v5::Model, prompt: &[u16], count: usize) -> Result<Vec<u16>> {
+        let state: v5::ModelState = StateBuilder::new(model.context(), model.info()).build();
+        let mut tokens = vec![prompt.to_vec()];
+        let mut output = vec![];
+        while output.len() < count {
+            let logits = model.run(&mut tokens, &state)?;
+            if let Some(logits) = logits.into_iter().next().flatten() {
+                let token = argmax(&logits);
+                output.push(token);
+                tokens = vec![vec![token]];
+            }
+        }
+        Ok(output)
+    }
+
+    #[test]
+    fn test_stop_conditions() -> Result<()> {
+        let context = match create_context() {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+
+        let builder = SyntheticBuilder::new(ModelVersion::V5);
+        let info = builder.info();
+        let data = builder.build()?;
+        let model: v5::Model = ModelBuilder::new(&context, &data)
+            .with_head_chunk_size(info.num_vocab)
+            .with_token_chunk_size(4)
+            .build()?;
+        let tokenizer = tokenizer(info.num_vocab)?;
+
+        let prompts = [vec![12u16, 55, 8], vec![91, 200, 7, 7, 3], vec![64]];
+        let expected: Vec<_> = prompts
+            .iter()
+            .map(|prompt| greedy(&model, prompt, 6))
+            .try_collect()?;
+
+        let state: v5::ModelState = StateBuilder::new(&context, &info).with_max_batch(2).build();
+        let initial = StateBuilder::new(&context, &info).build_backed();
+        let mut generator = Generator::new(&model, &state, &tokenizer, initial);
+
+        let stops = [
+            StopCondition {
+                max_tokens: Some(3),
+                ..Default::default()
+            },
+            StopCondition {
+                stop_tokens: vec![expected[1][4]],
+                ..Default::default()
+            },
+            StopCondition {
+                stop_strings: vec![format!("<{}>", expected[2][2])],
+                ..Default::default()
+            },
+        ];
+        let mut streams = prompts
+            .iter()
+            .zip(stops)
+            .map(|(prompt, stop)| Stream {
+                prompt: prompt.clone(),
+                stop,
+                sampler: Box::new(argmax),
+                soft_prompt: None,
+                cached_prefix: 0,
+            })
+            .collect_vec();
+
+        // the third stream waits for a free batch
+        // the first two streams start right away, the third waits for a free batch
+        let first = generator.push(streams.remove(0))?;
+        let second = generator.push(streams.remove(0))?;
+        assert_eq!(generator.enqueue(streams.remove(0))?, 0);
+        assert_eq!(generator.metrics().queue_depth, 1);
+
+        let mut keys = vec![first, second];
+        let mut finished = HashMap::new();
+        let mut prefill = HashMap::new();
+        let mut sampled = HashMap::<_, Vec<_>>::new();
+        while !generator.is_empty() {
+            for event in generator.step()? {
+                match event {
+                    GenerationEvent::Started { ticket, key } => {
+                        assert_eq!(ticket, 0);
+                        keys.push(key);
+                    }
+                    GenerationEvent::PromptProcessed {
+                        key,
+                        processed,
+                        total,
+                    } => {
+                        let last = prefill.insert(key, (processed, total));
+                        assert!(last.map_or(0, |(processed, _)| processed) < processed);
+                    }
+                    GenerationEvent::Token { key, token, .. } => {
+                        // tokens are only sampled once the prompt is done
+                        assert!(prefill[&key].0 == prefill[&key].1);
+                        sampled.entry(key).or_default().push(token);
+                    }
+                    GenerationEvent::Finished(generation) => {
+                        finished.insert(generation.key, generation);
+                    }
+                    GenerationEvent::Error { error, .. } => return Err(error),
+                }
+            }
+        }
+
+        let metrics = generator.metrics();
+        let num_prompt = prompts.iter().map(Vec::len).sum::<usize>();
+        assert_eq!(metrics.prefill_tokens, num_prompt);
+        assert!(metrics.decode_tokens > 0);
+        assert_eq!((metrics.queue_depth, metrics.active_slots), (0, 0));
+        generator.reset_metrics();
+        assert_eq!(generator.metrics(), Default::default());
+
+        for (key, prompt) in keys.iter().zip_eq(prompts.iter()) {
+            assert_eq!(prefill[key], (prompt.len(), prompt.len()));
+        }
+        assert_eq!(keys[2].batch(), keys[0].batch());
+
+        let first = &finished[&keys[0]];
+        assert_eq!(first.tokens, expected[0][..3]);
+        assert_eq!(first.reason, FinishReason::Length);
+        assert_eq!(sampled[&keys[0]], first.tokens);
+
+        let stop = expected[1][4];
+        let end = expected[1].iter().position(|&token| token == stop).unwrap();
+        let second = &finished[&keys[1]];
+        assert_eq!(second.tokens, expected[1][..end]);
+        assert_eq!(second.reason, FinishReason::Token(stop));
+        assert_eq!(sampled.remove(&keys[1]).unwrap_or_default(), second.tokens);
+
+        let stop = expected[2][2];
+        let end = expected[2].iter().position(|&token| token == stop).unwrap();
+        let third = &finished[&keys[2]];
+        assert_eq!(third.tokens, expected[2][..=end]);
+        let text = expected[2][..end].iter().map(|token| format!("<{token}>"));
+        assert_eq!(third.text, text.collect::<String>());
+        assert_eq!(third.reason, FinishReason::String(format!("<{stop}>")));
+        Ok(())
+    }
+
+    #[test]
+    fn test_generate_batch() -> Result<()> {
+        let context = match create_context() {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+
+        let builder = SyntheticBuilder::new(ModelVersion::V5);
+        let info = builder.info();
+        let data = builder.build()?;
+        let model: v5::Model = ModelBuilder::new(&context, &data)
+            .with_head_chunk_size(info.num_vocab)
+            .build()?;
+        let tokenizer = tokenizer(info.num_vocab)?;
+
+        let prompts = [vec![12u16, 55, 8], vec![91, 200, 7, 7, 3], vec![64]];
+        let text = |tokens: &[u16]| tokens.iter().map(|token| format!("<{token}>")).join("");
+        let params = GenerateParams {
+            max_batch: 2,
+            sampler: Sampler {
+                top_p: 0.0,
+                ..Default::default()
+            },
+            stop: StopCondition {
+                max_tokens: Some(4),
+                ..Default::default()
+            },
+            seed: Some(0),
+        };
+        let generations = model.generate_batch(
+            &tokenizer,
+            &prompts.iter().map(|x| text(x)).collect_vec(),
+            &params,
+        )?;
+
+        assert_eq!(generations.len(), 3);
+        for (generation, prompt) in generations.iter().zip_eq(prompts.iter()) {
+            let expected = greedy(&model, prompt, 4)?;
+            assert_eq!(generation.tokens, expected);
+            assert_eq!(generation.text, text(&expected));
+            assert_eq!(generation.bytes, generation.text.as_bytes());
+            assert_eq!(generation.reason, FinishReason::Length);
+            assert_eq!(generation.prompt_tokens, prompt.len());
+            assert_eq!(generation.cached_tokens, 0);
+            let timing = generation.timing;
+            assert!(timing.prefill > Duration::ZERO);
+            assert_eq!(
+                timing.total(),
+                timing.queue + timing.prefill + timing.decode
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_budget() -> Result<()> {
+        let context = match create_context() {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+
+        let builder = SyntheticBuilder::new(ModelVersion::V5);
+        let info = builder.info();
+        let data = builder.build()?;
+        let model: v5::Model = ModelBuilder::new(&context, &data)
+            .with_head_chunk_size(info.num_vocab)
+            .with_token_chunk_size(4)
+            .build()?;
+        let tokenizer = tokenizer(info.num_vocab)?;
+
+        let prompt = vec![12u16, 55, 8, 91, 200, 7, 7, 3, 64];
+        let expected = greedy(&model, &prompt, 2)?;
+        let state: v5::ModelState = StateBuilder::new(&context, &info).with_max_batch(2).build();
+        let initial = StateBuilder::new(&context, &info).build_backed();
+        let mut generator = Generator::new(&model, &state, &tokenizer, initial);
+
+        let stream = |stop| Stream {
+            prompt: prompt.clone(),
+            stop,
+            sampler: Box::new(argmax),
+            soft_prompt: None,
+            cached_prefix: 0,
+        };
+        let total = generator.push(stream(StopCondition {
+            max_total_tokens: Some(prompt.len() + 2),
+            ..Default::default()
+        }))?;
+        // the deadline passes before the prompt is done
+        let deadline = generator.push(stream(StopCondition {
+            deadline: Some(Duration::ZERO),
+            ..Default::default()
+        }))?;
+
+        let mut finished = HashMap::new();
+        let mut steps = 0;
+        while !generator.is_empty() {
+            steps += 1;
+            for event in generator.step()? {
+                match event {
+                    GenerationEvent::Finished(generation) => {
+                        finished.insert(generation.key, (steps, generation));
+                    }
+                    GenerationEvent::Error { error, .. } => return Err(error),
+                    _ => {}
+                }
+            }
+        }
+
+        let (_, generation) = &finished[&total];
+        assert_eq!(generation.tokens, expected);
+        assert_eq!(generation.reason, FinishReason::Budget);
+
+        let (steps, generation) = &finished[&deadline];
+        assert_eq!(*steps, 1);
+        assert!(generation.tokens.is_empty());
+        assert_eq!(generation.reason, FinishReason::Budget);
+        Ok(())
+    }
+
+    #[test]
+    fn test_prompt_cache() -> Result<()> {
+        let context = match create_context() {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+
+        let builder = SyntheticBuilder::new(ModelVersion::V5);
+        let info = builder.info();
+        let data = builder.build()?;
+        let model: v5::Model = ModelBuilder::new(&context, &data)
+            .with_head_chunk_size(info.num_vocab)
+            .with_token_chunk_size(4)
+            .build()?;
+        let tokenizer = tokenizer(info.num_vocab)?;
+
+        let dir = std::env::temp_dir().join("web-rwkv-test-prompt-cache");
+        let cache = PromptCache::new(&dir, Checksum::sha256(&data));
+        cache.clear()?;
+
+        let prompt = vec![12u16, 55, 8, 91, 200, 7, 7, 3, 64];
+        let expected = greedy(&model, &prompt, 4)?;
+        let state: v5::ModelState = StateBuilder::new(&context, &info).build();
+
+        // the first run stores the prefix, the second starts from it
+        for (num_prefill, num_cached) in [(prompt.len(), 0), (3, 6)] {
+            let initial = StateBuilder::new(&context, &info).build_backed();
+            let mut generator = Generator::new(&model, &state, &tokenizer, initial)
+                .with_prompt_cache(cache.clone());
+            let stream = Stream {
+                prompt: prompt.clone(),
+                stop: StopCondition {
+                    max_tokens: Some(4),
+                    ..Default::default()
+                },
+                sampler: Box::new(argmax),
+                soft_prompt: None,
+                cached_prefix: 0,
+            };
+            let key = generator.push(stream.with_cached_prefix(6))?;
+            let mut finished = None;
+            while !generator.is_empty() {
+                for event in generator.step()? {
+                    match event {
+                        GenerationEvent::Finished(generation) => finished = Some(generation),
+                        GenerationEvent::Error { error, .. } => return Err(error),
+                        _ => {}
+                    }
+                }
+            }
+            assert!(cache.contains(&prompt[..6]));
+            assert_eq!(generator.metrics().prefill_tokens, num_prefill);
+            let generation = finished.expect("stream finished");
+            assert_eq!(generation.key, key);
+            assert_eq!(generation.tokens, expected);
+            assert_eq!(generation.prompt_tokens, prompt.len());
+            assert_eq!(generation.cached_tokens, num_cached);
+        }
+
+        // a longer cached prefix picks up from the snapshot of the shorter one
+        let initial = StateBuilder::new(&context, &info).build_backed();
+        let mut generator =
+            Generator::new(&model, &state, &tokenizer, initial).with_prompt_cache(cache.clone());
+        assert_eq!(cache.longest_prefix(&prompt[..8]), Some(6));
+        let stream = Stream {
+            prompt: prompt.clone(),
+            stop: Default::default(),
+            sampler: Box::new(argmax),
+            soft_prompt: None,
+            cached_prefix: 8,
+        };
+        generator.push(stream)?;
+        assert_eq!(generator.metrics().prefill_tokens, 2);
+        assert_eq!(cache.longest_prefix(&prompt), Some(8));
+
+        cache.clear()?;
+        assert!(!dir.join(Checksum::sha256(&data).to_string()).exists());
+        Ok(())
+    }
+}
@@ -1,7 +1,18 @@
 pub mod context;
+#[cfg(feature = "tokenizer")]
+pub mod generate;
 pub mod model;
 pub mod num;
+pub mod processor;
+#[cfg(feature = "hub")]
+pub mod repo;
+pub mod sampler;
+pub mod score;
+pub mod speculative;
+#[cfg(feature = "tokenizer")]
+pub mod template;
 pub mod tensor;
+#[cfg(feature = "tokenizer")]
 pub mod tokenizer;
 
 pub use wgpu;
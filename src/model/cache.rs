@@ -0,0 +1,156 @@
+//! A directory of state snapshots after token prefixes, e.g. long system prompts, kept across process restarts.
+
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+use itertools::Itertools;
+use safetensors::{tensor::TensorView, Dtype, SafeTensors};
+use sha2::{Digest, Sha256};
+
+use super::{BackedState, Checksum};
+use crate::tensor::{shape::Shape, TensorError};
+
+/// State snapshots on disk, each after a prefix of tokens, keyed by the checksum of the model and a hash of the prefix.
+///
+/// Snapshots of a model are stored under `dir/<model checksum>/` as safetensors files, one per prefix,
+/// so caches of different models can share a directory.
+/// A snapshot is written to a temporary file first, so an interrupted write never leaves a broken one behind.
+#[derive(Debug, Clone)]
+pub struct PromptCache {
+    dir: PathBuf,
+    model: Checksum,
+}
+
+impl PromptCache {
+    /// Extension of the snapshot files.
+    pub const EXTENSION: &'static str = "st";
+
+    /// `model` is the checksum of the model file, e.g. [`Checksum::sha256`] of its bytes.
+    /// Snapshots of other models, or of the same model with other LoRAs, must not share it.
+    pub fn new(dir: impl Into<PathBuf>, model: Checksum) -> Self {
+        let dir = dir.into().join(model.to_string());
+        Self { dir, model }
+    }
+
+    /// The directory with the snapshots of this model.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Hash of the model checksum and `tokens`, naming the snapshot after them.
+    pub fn key(&self, tokens: &[u16]) -> Checksum {
+        let mut hasher = Sha256::new();
+        hasher.update(self.model.0);
+        tokens
+            .iter()
+            .for_each(|token| hasher.update(token.to_le_bytes()));
+        Checksum(hasher.finalize().into())
+    }
+
+    fn path(&self, key: Checksum) -> PathBuf {
+        self.dir
+            .join(key.to_string())
+            .with_extension(Self::EXTENSION)
+    }
+
+    pub fn contains(&self, tokens: &[u16]) -> bool {
+        self.path(self.key(tokens)).is_file()
+    }
+
+    /// Length of the longest prefix of `tokens` with a snapshot, at most `tokens.len()`.
+    pub fn longest_prefix(&self, tokens: &[u16]) -> Option<usize> {
+        if !self.dir.is_dir() {
+            return None;
+        }
+        // the hash of each prefix carries on from that of the one before it
+        let mut hasher = Sha256::new();
+        hasher.update(self.model.0);
+        let mut longest = None;
+        for len in 1..=tokens.len() {
+            hasher.update(tokens[len - 1].to_le_bytes());
+            let key = Checksum(hasher.clone().finalize().into());
+            if self.path(key).is_file() {
+                longest = Some(len);
+            }
+        }
+        longest
+    }
+
+    /// Store `backed`, the state after running `tokens`, replacing any snapshot of the same tokens.
+    pub fn save<B: BackedState>(&self, tokens: &[u16], backed: &B) -> Result<()> {
+        let tensors = backed.tensors();
+        let views: Vec<_> = tensors
+            .iter()
+            .enumerate()
+            .map(|(index, (shape, data))| {
+                let shape = vec![shape[0], shape[1], shape[2], shape[3]];
+                TensorView::new(Dtype::F32, shape, bytemuck::cast_slice(data))
+                    .map(|view| (format!("state.{index}"), view))
+            })
+            .try_collect()?;
+        let metadata = HashMap::from([
+            ("model".to_string(), self.model.to_string()),
+            ("num_token".to_string(), tokens.len().to_string()),
+        ]);
+        let data = safetensors::serialize(views, &Some(metadata))?;
+
+        fs::create_dir_all(&self.dir)?;
+        let path = self.path(self.key(tokens));
+        let part = path.with_extension("part");
+        fs::write(&part, data)?;
+        fs::rename(&part, &path)?;
+        Ok(())
+    }
+
+    /// Load the snapshot after `tokens`, if there is one, into a copy of `template`,
+    /// a state of the same model and shape, e.g. the initial one.
+    pub fn load<B: BackedState + Clone>(&self, tokens: &[u16], template: &B) -> Result<Option<B>> {
+        let path = self.path(self.key(tokens));
+        if !path.is_file() {
+            return Ok(None);
+        }
+        let data = fs::read(&path)?;
+        let model = SafeTensors::deserialize(&data)?;
+
+        let shapes = template.tensors().into_iter().map(|(shape, _)| shape);
+        let tensors: Vec<_> = shapes
+            .enumerate()
+            .map(|(index, shape)| -> Result<Vec<f32>> {
+                let tensor = model.tensor(&format!("state.{index}"))?;
+                if tensor.dtype() != Dtype::F32 {
+                    return Err(TensorError::Type.into());
+                }
+                let actual = Shape::from_slice(tensor.shape());
+                if actual != shape {
+                    return Err(TensorError::Shape(actual, shape).into());
+                }
+                Ok(bytemuck::pod_collect_to_vec(tensor.data()))
+            })
+            .try_collect()?;
+
+        let mut backed = template.clone();
+        backed.load_tensors(tensors)?;
+        Ok(Some(backed))
+    }
+
+    /// Remove the snapshot after `tokens`, returning whether there was one.
+    pub fn remove(&self, tokens: &[u16]) -> Result<bool> {
+        let path = self.path(self.key(tokens));
+        match path.is_file() {
+            true => fs::remove_file(path).map(|_| true).map_err(Into::into),
+            false => Ok(false),
+        }
+    }
+
+    /// Remove all snapshots of this model.
+    pub fn clear(&self) -> Result<()> {
+        if self.dir.is_dir() {
+            fs::remove_dir_all(&self.dir)?;
+        }
+        Ok(())
+    }
+}
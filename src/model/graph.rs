@@ -0,0 +1,270 @@
+//! Describing what a model dispatches in one run, e.g. to document or debug it, or to draw it with an external tool.
+
+use half::f16;
+use serde::Serialize;
+
+use super::{matrix::Matrix, ModelVersion};
+use crate::{
+    num::Scalar,
+    tensor::{shape::Shape, Kind, TensorGpu, TensorShape},
+};
+
+/// A tensor read or written by the ops of a [`Graph`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct TensorNode {
+    pub name: String,
+    pub shape: [usize; 4],
+    pub dtype: String,
+    /// Size of the buffer in bytes.
+    pub size: usize,
+}
+
+/// A kernel dispatch, or a copy between buffers, in the order it is encoded.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct OpNode {
+    /// The name of the kernel, or `copy` for buffer copies.
+    pub kind: String,
+    /// Names of the tensors read.
+    pub inputs: Vec<String>,
+    /// Names of the tensors written.
+    pub outputs: Vec<String>,
+}
+
+/// The ops of one part of the model, e.g. the embedding, a layer or the head.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Stage {
+    pub name: String,
+    pub ops: Vec<OpNode>,
+}
+
+/// The ops a model dispatches in one run of `num_token` tokens spread over `num_batch` batches,
+/// along with every tensor they touch.
+///
+/// The embeddings of the tokens are looked up on the host and uploaded as `input` before the ops run.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Graph {
+    pub version: ModelVersion,
+    pub num_batch: usize,
+    pub num_token: usize,
+    pub tensors: Vec<TensorNode>,
+    pub stages: Vec<Stage>,
+}
+
+impl Graph {
+    pub fn to_json(&self) -> Result<String, serde_json::Error> {
+        serde_json::to_string_pretty(self)
+    }
+}
+
+/// Collects the tensors and ops of a [`Graph`] as a model walks through what it would encode.
+#[derive(Debug)]
+pub(crate) struct GraphBuilder {
+    graph: Graph,
+}
+
+impl GraphBuilder {
+    pub fn new(version: ModelVersion, num_batch: usize, num_token: usize) -> Self {
+        Self {
+            graph: Graph {
+                version,
+                num_batch,
+                num_token,
+                tensors: vec![],
+                stages: vec![],
+            },
+        }
+    }
+
+    /// Register a tensor of the given shape, if not yet, returning its name.
+    pub fn tensor<T: Scalar>(&mut self, name: impl Into<String>, shape: Shape) -> String {
+        let name = name.into();
+        if self.graph.tensors.iter().all(|tensor| tensor.name != name) {
+            self.graph.tensors.push(TensorNode {
+                name: name.clone(),
+                shape: [shape[0], shape[1], shape[2], shape[3]],
+                dtype: format!("{:?}", T::DATA_TYPE).to_lowercase(),
+                size: shape.len() * T::size(),
+            });
+        }
+        name
+    }
+
+    /// Register a tensor of the model, if not yet, returning its name.
+    pub fn weight<T: Scalar, K: Kind>(
+        &mut self,
+        name: impl Into<String>,
+        tensor: &TensorGpu<T, K>,
+    ) -> String {
+        self.tensor::<T>(name, tensor.shape())
+    }
+
+    /// Start a new stage; ops are added to the last one.
+    pub fn stage(&mut self, name: impl Into<String>) {
+        self.graph.stages.push(Stage {
+            name: name.into(),
+            ops: vec![],
+        });
+    }
+
+    pub fn op(&mut self, kind: impl Into<String>, inputs: &[&str], outputs: &[&str]) {
+        let op = OpNode {
+            kind: kind.into(),
+            inputs: inputs.iter().map(|name| name.to_string()).collect(),
+            outputs: outputs.iter().map(|name| name.to_string()).collect(),
+        };
+        match self.graph.stages.last_mut() {
+            Some(stage) => stage.ops.push(op),
+            None => self.graph.stages.push(Stage {
+                name: String::new(),
+                ops: vec![op],
+            }),
+        }
+    }
+
+    pub fn copy(&mut self, input: &str, output: &str) {
+        self.op("copy", &[input], &[output]);
+    }
+
+    /// A layer or group normalization of `x` in place, with weights `{name}.weight` and `{name}.bias`.
+    pub fn norm<K: Kind>(
+        &mut self,
+        kind: &str,
+        name: &str,
+        w: &TensorGpu<f16, K>,
+        b: &TensorGpu<f16, K>,
+        x: &str,
+    ) {
+        let w = self.weight(format!("{name}.weight"), w);
+        let b = self.weight(format!("{name}.bias"), b);
+        self.op(kind, &[&w, &b, x], &[x]);
+    }
+
+    /// The ops of multiplying `input` by `matrix`, following [`Matrix::matmul_vec_op`] and [`Matrix::matmul_mat_op`].
+    pub fn matmul(
+        &mut self,
+        matrix: &Matrix,
+        name: &str,
+        half: &str,
+        input: &str,
+        output: &str,
+        turbo: bool,
+    ) {
+        match (matrix, turbo) {
+            (Matrix::Fp16(w), false) => {
+                let w = self.weight(name, w);
+                self.op("matmul_vec_fp16", &[&w, input], &[output]);
+            }
+            (Matrix::Fp16(w), true) => {
+                let w = self.weight(name, w);
+                self.op("quant_fp16", &[input], &[half]);
+                self.op("matmul_mat_fp16", &[&w, half], &[output]);
+            }
+            (Matrix::Int8 { w, mx, rx, my, ry }, turbo) => {
+                let names = [
+                    self.weight(format!("{name}.w"), w.as_ref()),
+                    self.weight(format!("{name}.mx"), mx.as_ref()),
+                    self.weight(format!("{name}.rx"), rx.as_ref()),
+                    self.weight(format!("{name}.my"), my.as_ref()),
+                    self.weight(format!("{name}.ry"), ry.as_ref()),
+                ];
+                let names = names.iter().map(String::as_str);
+                match turbo {
+                    false => {
+                        let inputs: Vec<_> = names.chain([input]).collect();
+                        self.op("matmul_vec_int8", &inputs, &[output]);
+                    }
+                    true => {
+                        let inputs: Vec<_> = names.chain([half]).collect();
+                        self.op("quant_fp16", &[input], &[half]);
+                        self.op("matmul_mat_int8", &inputs, &[output]);
+                    }
+                }
+            }
+            (Matrix::Int8Asym { w, s, z }, turbo) => {
+                let names = [
+                    self.weight(format!("{name}.w"), w.as_ref()),
+                    self.weight(format!("{name}.s"), s.as_ref()),
+                    self.weight(format!("{name}.z"), z.as_ref()),
+                ];
+                let names = names.iter().map(String::as_str);
+                match turbo {
+                    false => {
+                        let inputs: Vec<_> = names.chain([input]).collect();
+                        self.op("matmul_vec_int8_asym", &inputs, &[output]);
+                    }
+                    true => {
+                        let inputs: Vec<_> = names.chain([half]).collect();
+                        self.op("quant_fp16", &[input], &[half]);
+                        self.op("matmul_mat_int8_asym", &inputs, &[output]);
+                    }
+                }
+            }
+            (Matrix::NF4 { w, m, q }, _) => {
+                let names = [
+                    self.weight(format!("{name}.w"), w.as_ref()),
+                    self.weight(format!("{name}.m"), m.as_ref()),
+                    self.weight(format!("{name}.q"), q.as_ref()),
+                ];
+                let inputs: Vec<_> = names.iter().map(String::as_str).chain([half]).collect();
+                self.op("quant_fp16", &[input], &[half]);
+                self.op("matmul_vec_nf4", &inputs, &[output]);
+            }
+        }
+    }
+
+    pub fn build(self) -> Graph {
+        self.graph
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use crate::model::{
+        synthetic::{testing::create_context, SyntheticBuilder},
+        v5, ModelBuilder, ModelVersion, Quant,
+    };
+
+    #[test]
+    fn test_model_graph() -> Result<()> {
+        let context = match create_context() {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+
+        let builder = SyntheticBuilder::new(ModelVersion::V5).with_num_layer(2);
+        let info = builder.info();
+        let data = builder.build()?;
+        let model: v5::Model = ModelBuilder::new(&context, &data)
+            .with_quant([(0, Quant::Int8)].into())
+            .build()?;
+
+        let graph = model.graph(2, 5);
+        let stages = graph.stages.iter().map(|stage| stage.name.as_str());
+        itertools::assert_equal(stages, ["embed", "blocks.0", "blocks.1", "head"]);
+
+        // every op touches registered tensors only
+        for op in graph.stages.iter().flat_map(|stage| &stage.ops) {
+            for name in op.inputs.iter().chain(&op.outputs) {
+                assert!(graph.tensors.iter().any(|tensor| &tensor.name == name));
+            }
+        }
+
+        let tensor = |name: &str| graph.tensors.iter().find(|tensor| tensor.name == name);
+        let key = tensor("blocks.0.att.key.weight.w").unwrap();
+        assert_eq!(key.dtype, "u8");
+        assert_eq!(key.size, info.num_emb * info.num_emb);
+        assert!(tensor("blocks.1.att.key.weight").is_some());
+
+        // with more tokens than outputs, the last token of each batch is gathered for the head
+        let head = &graph.stages[3].ops;
+        assert_eq!(head[0].kind, "blit");
+        assert_eq!(tensor("head_o").unwrap().shape, [info.num_vocab, 2, 1, 1]);
+
+        let json: serde_json::Value = serde_json::from_str(&graph.to_json()?)?;
+        assert_eq!(json["num_token"], 5);
+        assert_eq!(json["stages"].as_array().unwrap().len(), 4);
+        Ok(())
+    }
+}
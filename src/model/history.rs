@@ -0,0 +1,345 @@
+//! Rolling back a batch of the state over its last few tokens, or to an explicit save point.
+
+use std::collections::VecDeque;
+
+use anyhow::Result;
+
+use super::{ModelError, ModelState};
+
+#[derive(Debug, Clone)]
+struct Slot<B> {
+    /// Number of tokens consumed when the oldest snapshot was taken.
+    base: usize,
+    /// Tokens consumed since the oldest snapshot.
+    tokens: Vec<u16>,
+    /// Snapshots of the batch and the number of tokens consumed when taken, oldest first.
+    snapshots: VecDeque<(usize, B)>,
+}
+
+impl<B> Default for Slot<B> {
+    fn default() -> Self {
+        Self {
+            base: 0,
+            tokens: vec![],
+            snapshots: VecDeque::new(),
+        }
+    }
+}
+
+impl<B> Slot<B> {
+    #[inline]
+    fn position(&self) -> usize {
+        self.base + self.tokens.len()
+    }
+}
+
+/// A ring buffer of host snapshots of each batch, taken every `interval` tokens.
+///
+/// After each run, [`StateHistory::record`] the tokens a batch consumed.
+/// [`StateHistory::rollback`] then restores the batch to an earlier token by loading the nearest snapshot before it,
+/// and returns the tokens to run again to get there, which are at most `interval` long.
+#[derive(Debug, Clone)]
+pub struct StateHistory<B> {
+    interval: usize,
+    capacity: usize,
+    slots: Vec<Slot<B>>,
+}
+
+impl<B: Clone> StateHistory<B> {
+    pub fn new(max_batch: usize) -> Self {
+        Self {
+            interval: 32,
+            capacity: 8,
+            slots: vec![Slot::default(); max_batch],
+        }
+    }
+
+    /// Take a snapshot every `value` tokens. Defaults to 32.
+    pub fn with_interval(self, value: usize) -> Self {
+        Self {
+            interval: value.max(1),
+            ..self
+        }
+    }
+
+    /// Keep at most `value` snapshots of each batch, dropping the oldest. Defaults to 8.
+    pub fn with_capacity(self, value: usize) -> Self {
+        Self {
+            capacity: value.max(1),
+            ..self
+        }
+    }
+
+    fn slot(&mut self, batch: usize) -> Result<&mut Slot<B>, ModelError> {
+        let max = self.slots.len();
+        self.slots
+            .get_mut(batch)
+            .ok_or(ModelError::BatchOutOfRange { batch, max })
+    }
+
+    /// Number of tokens recorded for `batch` since its history started.
+    pub fn position(&self, batch: usize) -> usize {
+        self.slots
+            .get(batch)
+            .map(Slot::position)
+            .unwrap_or_default()
+    }
+
+    /// How many tokens `batch` can currently roll back.
+    pub fn available(&self, batch: usize) -> usize {
+        self.slots
+            .get(batch)
+            .map(|slot| slot.tokens.len())
+            .unwrap_or_default()
+    }
+
+    /// Forget the history of `batch` and start it over from its current state.
+    pub fn reset<S>(&mut self, state: &S, batch: usize) -> Result<()>
+    where
+        S: ModelState<BackedState = B>,
+    {
+        let backed = state.back_batch(batch)?;
+        let slot = self.slot(batch)?;
+        *slot = Slot::default();
+        slot.snapshots.push_back((0, backed));
+        Ok(())
+    }
+
+    /// Record that `batch` of `state` has just consumed `tokens`.
+    /// If this is the first record of the batch, its history starts after these tokens.
+    pub fn record<S>(&mut self, state: &S, batch: usize, tokens: &[u16]) -> Result<()>
+    where
+        S: ModelState<BackedState = B>,
+    {
+        let (interval, capacity) = (self.interval, self.capacity);
+        let slot = self.slot(batch)?;
+        if slot.snapshots.is_empty() {
+            slot.base += tokens.len();
+            slot.snapshots
+                .push_back((slot.base, state.back_batch(batch)?));
+            return Ok(());
+        }
+
+        slot.tokens.extend_from_slice(tokens);
+        let position = slot.position();
+        let last = slot.snapshots.back().map(|(index, _)| *index).unwrap_or(0);
+        if position - last < interval {
+            return Ok(());
+        }
+
+        slot.snapshots
+            .push_back((position, state.back_batch(batch)?));
+        while slot.snapshots.len() > capacity {
+            slot.snapshots.pop_front();
+            let base = slot.snapshots[0].0;
+            slot.tokens.drain(..base - slot.base);
+            slot.base = base;
+        }
+        Ok(())
+    }
+
+    /// Restore `batch` of `state` to before its last `num_token` tokens.
+    /// Returns the tokens that must be run (and recorded) again to reach that point.
+    pub fn rollback<S>(&mut self, state: &S, batch: usize, num_token: usize) -> Result<Vec<u16>>
+    where
+        S: ModelState<BackedState = B>,
+    {
+        let slot = self.slot(batch)?;
+        let max = slot.tokens.len();
+        if num_token > max || slot.snapshots.is_empty() {
+            return Err(ModelError::RollbackOutOfRange {
+                batch,
+                tokens: num_token,
+                max,
+            }
+            .into());
+        }
+
+        let target = slot.position() - num_token;
+        while slot
+            .snapshots
+            .back()
+            .is_some_and(|(index, _)| *index > target)
+        {
+            slot.snapshots.pop_back();
+        }
+        let (index, backed) = slot.snapshots.back().expect("the oldest snapshot is kept");
+        state.load_batch(backed, batch)?;
+
+        let start = index - slot.base;
+        let replay = slot.tokens[start..target - slot.base].to_vec();
+        slot.tokens.truncate(start);
+        Ok(replay)
+    }
+}
+
+/// A saved batch of a state, restorable with [`SavePoints::restore`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SavePoint {
+    index: usize,
+    batch: usize,
+}
+
+impl SavePoint {
+    /// The batch of the state this was saved from.
+    pub fn batch(&self) -> usize {
+        self.batch
+    }
+}
+
+/// A stack of save points kept on device, for decoders that backtrack a step when they hit a dead end.
+///
+/// Saving and restoring are single device copies between a batch of the state and a batch of `buffer`,
+/// a state built like the one being saved with as many batches as save points needed.
+#[derive(Debug)]
+pub struct SavePoints<S> {
+    buffer: S,
+    points: Vec<SavePoint>,
+}
+
+impl<S: ModelState> SavePoints<S> {
+    pub fn new(buffer: S) -> Self {
+        Self {
+            buffer,
+            points: vec![],
+        }
+    }
+
+    /// Number of save points currently held.
+    pub fn len(&self) -> usize {
+        self.points.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.points.is_empty()
+    }
+
+    /// Save `batch` of `state` on top of the stack.
+    pub fn save(&mut self, state: &S, batch: usize) -> Result<SavePoint> {
+        let index = self.points.len();
+        if index >= self.buffer.max_batch() {
+            return Err(ModelError::SavePointsFull(self.buffer.max_batch()).into());
+        }
+        state.blit_batch(&self.buffer, batch, index)?;
+        let point = SavePoint { index, batch };
+        self.points.push(point);
+        Ok(point)
+    }
+
+    /// Restore the batch saved at `point`, dropping every save point taken after it.
+    /// The point itself is kept, so it can be restored again after trying another token.
+    pub fn restore(&mut self, state: &S, point: SavePoint) -> Result<()> {
+        if self.points.get(point.index) != Some(&point) {
+            return Err(ModelError::BatchOutOfRange {
+                batch: point.index,
+                max: self.points.len(),
+            }
+            .into());
+        }
+        self.buffer.blit_batch(state, point.index, point.batch)?;
+        self.points.truncate(point.index + 1);
+        Ok(())
+    }
+
+    /// Drop `point` and every save point taken after it, without touching the state.
+    pub fn release(&mut self, point: SavePoint) {
+        if self.points.get(point.index) == Some(&point) {
+            self.points.truncate(point.index);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use super::{SavePoints, StateHistory};
+    use crate::model::{
+        synthetic::{
+            testing::{create_context, run},
+            SyntheticBuilder,
+        },
+        v5, ModelBuilder, ModelVersion, StateBuilder,
+    };
+
+    #[test]
+    fn test_rollback() -> Result<()> {
+        let context = match create_context() {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+
+        let builder = SyntheticBuilder::new(ModelVersion::V5);
+        let info = builder.info();
+        let data = builder.build()?;
+
+        let model: v5::Model = ModelBuilder::new(&context, &data)
+            .with_head_chunk_size(info.num_vocab)
+            .build()?;
+        let state: v5::ModelState = StateBuilder::new(&context, &info).with_max_batch(2).build();
+        let mut history = StateHistory::new(2).with_interval(2).with_capacity(3);
+        history.reset(&state, 1)?;
+
+        let tokens = [12u16, 55, 8, 91, 200, 7, 7, 64];
+        let mut logits = vec![];
+        for &token in &tokens {
+            let output = run(&model, &state, &[vec![], vec![token]])?;
+            history.record(&state, 1, &[token])?;
+            logits.push(output[1].clone());
+        }
+        assert_eq!(history.position(1), 8);
+        // snapshots at 4, 6 and 8 are kept
+        assert_eq!(history.available(1), 4);
+
+        let replay = history.rollback(&state, 1, 3)?;
+        assert_eq!(replay, tokens[4..5]);
+        let output = run(&model, &state, &[vec![], replay.clone()])?;
+        history.record(&state, 1, &replay)?;
+        assert_eq!(output[1], logits[4]);
+        assert_eq!(history.position(1), 5);
+
+        assert!(history.rollback(&state, 1, 2).is_err());
+        assert!(history.rollback(&state, 0, 1).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_save_points() -> Result<()> {
+        let context = match create_context() {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+
+        let builder = SyntheticBuilder::new(ModelVersion::V5);
+        let info = builder.info();
+        let data = builder.build()?;
+
+        let model: v5::Model = ModelBuilder::new(&context, &data)
+            .with_head_chunk_size(info.num_vocab)
+            .build()?;
+        let state: v5::ModelState = StateBuilder::new(&context, &info).with_max_batch(2).build();
+        let buffer = StateBuilder::new(&context, &info).with_max_batch(2).build();
+        let mut points = SavePoints::new(buffer);
+
+        run(&model, &state, &[vec![], vec![12u16, 55, 8]])?;
+        let first = points.save(&state, 1)?;
+        let expected = run(&model, &state, &[vec![], vec![91]])?;
+
+        // a dead end two steps deep
+        let second = points.save(&state, 1)?;
+        run(&model, &state, &[vec![], vec![200]])?;
+        assert!(points.save(&state, 1).is_err());
+
+        points.restore(&state, first)?;
+        assert_eq!(points.len(), 1);
+        assert!(points.restore(&state, second).is_err());
+        assert_eq!(run(&model, &state, &[vec![], vec![91]])?, expected);
+
+        // the point survives restoring, until released
+        points.restore(&state, first)?;
+        assert_eq!(run(&model, &state, &[vec![], vec![91]])?, expected);
+        points.release(first);
+        assert!(points.is_empty());
+        Ok(())
+    }
+}
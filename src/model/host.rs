@@ -0,0 +1,361 @@
+//! Layers run on the host instead of the device, for models a little too big for device memory even quantized.
+//!
+//! Host layers work on the residual stream of one token at a time, and on the rows of their state laid out as on the device,
+//! so the state of a page is read back before they run and written back after.
+
+use std::ops::Range;
+
+use anyhow::Result;
+use half::f16;
+use itertools::Itertools;
+
+use super::{loader::Loader, ModelInfo, ModelVersion};
+
+const LAYER_NORM_EPS: f32 = 1.0e-5;
+const GROUP_NORM_EPS: f32 = 64.0e-5;
+
+#[derive(Debug, Clone)]
+struct LayerNorm {
+    w: Vec<f32>,
+    b: Vec<f32>,
+}
+
+impl LayerNorm {
+    fn load(loader: &Loader, name: &str) -> Result<Self> {
+        Ok(Self {
+            w: load_vector(loader, &format!("{name}.weight"))?,
+            b: load_vector(loader, &format!("{name}.bias"))?,
+        })
+    }
+
+    fn apply(&self, x: &[f32], num_group: usize, eps: f32) -> Vec<f32> {
+        let size = x.len() / num_group;
+        x.chunks_exact(size)
+            .zip_eq(self.w.chunks_exact(size))
+            .zip_eq(self.b.chunks_exact(size))
+            .flat_map(|((x, w), b)| {
+                let mean = x.iter().sum::<f32>() / size as f32;
+                let variance = x.iter().map(|x| (x - mean) * (x - mean)).sum::<f32>() / size as f32;
+                let deviation = 1.0 / (variance + eps).sqrt();
+                x.iter()
+                    .zip_eq(w.iter().zip_eq(b.iter()))
+                    .map(move |(x, (w, b))| (x - mean) * deviation * w + b)
+            })
+            .collect()
+    }
+}
+
+/// A row-major matrix, kept in half precision as stored in the checkpoint.
+#[derive(Debug, Clone)]
+struct Matrix {
+    data: Vec<f16>,
+    num_col: usize,
+}
+
+impl Matrix {
+    fn load(loader: &Loader, name: &str, discount: f32) -> Result<Self> {
+        let (shape, data) = loader.load_host_f16(name)?;
+        let data = match discount == 1.0 {
+            true => data,
+            false => data
+                .into_iter()
+                .map(|x| f16::from_f32(x.to_f32() * discount))
+                .collect(),
+        };
+        Ok(Self {
+            data,
+            num_col: shape[1],
+        })
+    }
+
+    fn apply(&self, x: &[f32]) -> Vec<f32> {
+        assert_eq!(x.len(), self.num_col);
+        self.data
+            .chunks_exact(self.num_col)
+            .map(|row| row.iter().zip_eq(x).map(|(w, x)| w.to_f32() * x).sum())
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Att {
+    time_decay: Vec<f32>,
+    time_first: Vec<f32>,
+
+    time_mix_k: Vec<f32>,
+    time_mix_v: Vec<f32>,
+    time_mix_r: Vec<f32>,
+    time_mix_g: Option<Vec<f32>>,
+
+    w_k: Matrix,
+    w_v: Matrix,
+    w_r: Matrix,
+    w_g: Option<Matrix>,
+    w_o: Matrix,
+
+    group_norm: Option<LayerNorm>,
+}
+
+#[derive(Debug, Clone)]
+struct Ffn {
+    time_mix_k: Vec<f32>,
+    time_mix_r: Vec<f32>,
+
+    w_k: Matrix,
+    w_v: Matrix,
+    w_r: Matrix,
+}
+
+/// One layer of the model with its weights in host memory.
+#[derive(Debug, Clone)]
+struct HostLayer {
+    info: ModelInfo,
+    att_layer_norm: LayerNorm,
+    ffn_layer_norm: LayerNorm,
+    att: Att,
+    ffn: Ffn,
+}
+
+/// The first layers of a model, with the embedding layer norm before them, run on the host;
+/// see [`ModelBuilder::with_host_layers`](super::ModelBuilder::with_host_layers).
+#[derive(Debug, Clone, Default)]
+pub struct HostLayers {
+    layer_norm: Option<LayerNorm>,
+    layers: Vec<HostLayer>,
+    /// Half the activations every this many layers, if set, as on the device.
+    rescale: Option<usize>,
+}
+
+impl HostLayers {
+    /// Load the first `num_layer` layers of the checkpoint, none at all if it is 0. LoRAs of the loader are not blended in.
+    pub fn load(
+        loader: &Loader,
+        info: &ModelInfo,
+        num_layer: usize,
+        rescale: Option<usize>,
+    ) -> Result<Self> {
+        if num_layer == 0 {
+            return Ok(Self::default());
+        }
+        let layers = (0..num_layer)
+            .map(|layer| {
+                let discount = match rescale {
+                    Some(every) => 2.0_f32.powi(-((layer / every) as i32)),
+                    None => 1.0,
+                };
+                HostLayer::load(loader, info, layer, discount)
+            })
+            .try_collect()?;
+        Ok(Self {
+            layer_norm: Some(LayerNorm::load(loader, "blocks.0.ln0")?),
+            layers,
+            rescale,
+        })
+    }
+
+    /// Number of layers run on the host.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.layers.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.layers.is_empty()
+    }
+
+    /// Normalize `x`, the embedding of one token, and run it through `layers`.
+    /// `state` holds the rows of each host layer for the batch of the token, as on the device.
+    pub fn run(&self, x: &mut [f32], state: &mut [&mut [f32]], layers: Range<usize>) {
+        if let Some(layer_norm) = &self.layer_norm {
+            let y = layer_norm.apply(x, 1, LAYER_NORM_EPS);
+            x.copy_from_slice(&y);
+        }
+        for (index, (layer, state)) in self
+            .layers
+            .iter()
+            .zip(state.iter_mut())
+            .enumerate()
+            .take(layers.end)
+            .skip(layers.start)
+        {
+            layer.run(x, state);
+            if self
+                .rescale
+                .is_some_and(|every| (index + 1).is_multiple_of(every))
+            {
+                x.iter_mut().for_each(|x| *x *= 0.5);
+            }
+        }
+    }
+}
+
+fn load_vector(loader: &Loader, name: &str) -> Result<Vec<f32>> {
+    let (_, data) = loader.load_host_f16(name)?;
+    Ok(data.into_iter().map(f16::to_f32).collect())
+}
+
+fn sigmoid(x: f32) -> f32 {
+    1.0 / (1.0 + (-x).exp())
+}
+
+fn token_shift(mix: &[f32], x: &[f32], sx: &[f32]) -> Vec<f32> {
+    itertools::izip!(mix, x, sx)
+        .map(|(mix, x, sx)| x * mix + sx * (1.0 - mix))
+        .collect()
+}
+
+impl HostLayer {
+    /// Load `layer` from the checkpoint, with its output matrices scaled by `discount` as those on the device are when rescaling.
+    fn load(loader: &Loader, info: &ModelInfo, layer: usize, discount: f32) -> Result<Self> {
+        let att = format!("blocks.{layer}.att");
+        let ffn = format!("blocks.{layer}.ffn");
+        let v5 = info.version == ModelVersion::V5;
+        let vector = |name: String| load_vector(loader, &name);
+        let matrix = |name: String| Matrix::load(loader, &name, 1.0);
+
+        let att = Att {
+            time_decay: vector(format!("{att}.time_decay"))?,
+            time_first: vector(format!("{att}.time_first"))?,
+            time_mix_k: vector(format!("{att}.time_mix_k"))?,
+            time_mix_v: vector(format!("{att}.time_mix_v"))?,
+            time_mix_r: vector(format!("{att}.time_mix_r"))?,
+            time_mix_g: v5
+                .then(|| vector(format!("{att}.time_mix_g")))
+                .transpose()?,
+            w_k: matrix(format!("{att}.key.weight"))?,
+            w_v: matrix(format!("{att}.value.weight"))?,
+            w_r: matrix(format!("{att}.receptance.weight"))?,
+            w_g: v5
+                .then(|| matrix(format!("{att}.gate.weight")))
+                .transpose()?,
+            w_o: Matrix::load(loader, &format!("{att}.output.weight"), discount)?,
+            group_norm: v5
+                .then(|| LayerNorm::load(loader, &format!("{att}.ln_x")))
+                .transpose()?,
+        };
+        let ffn = Ffn {
+            time_mix_k: vector(format!("{ffn}.time_mix_k"))?,
+            time_mix_r: vector(format!("{ffn}.time_mix_r"))?,
+            w_k: matrix(format!("{ffn}.key.weight"))?,
+            w_v: Matrix::load(loader, &format!("{ffn}.value.weight"), discount)?,
+            w_r: matrix(format!("{ffn}.receptance.weight"))?,
+        };
+
+        Ok(Self {
+            info: info.clone(),
+            att_layer_norm: LayerNorm::load(loader, &format!("blocks.{layer}.ln1"))?,
+            ffn_layer_norm: LayerNorm::load(loader, &format!("blocks.{layer}.ln2"))?,
+            att,
+            ffn,
+        })
+    }
+
+    /// Run one token through the layer, adding its output to `x`, the residual stream of the token.
+    /// `state` holds the rows of the layer for one batch as on the device: those of the attention, then that of the FFN.
+    fn run(&self, x: &mut [f32], state: &mut [f32]) {
+        let num_emb = self.info.num_emb;
+        let (att_state, ffn_state) = state.split_at_mut(state.len() - num_emb);
+        let (att_x, att_kv) = att_state.split_at_mut(num_emb);
+
+        let xx = self.att_layer_norm.apply(x, 1, LAYER_NORM_EPS);
+        let o = match self.info.version {
+            ModelVersion::V4 => self.time_mix_v4(&xx, att_x, att_kv),
+            ModelVersion::V5 => self.time_mix_v5(&xx, att_x, att_kv),
+        };
+        att_x.copy_from_slice(&xx);
+        x.iter_mut().zip_eq(o).for_each(|(x, o)| *x += o);
+
+        let xx = self.ffn_layer_norm.apply(x, 1, LAYER_NORM_EPS);
+        let o = self.channel_mix(&xx, ffn_state);
+        ffn_state.copy_from_slice(&xx);
+        x.iter_mut().zip_eq(o).for_each(|(x, o)| *x += o);
+    }
+
+    fn time_mix_v4(&self, x: &[f32], sx: &[f32], kv: &mut [f32]) -> Vec<f32> {
+        let num_emb = self.info.num_emb;
+        let att = &self.att;
+        let k = att.w_k.apply(&token_shift(&att.time_mix_k, x, sx));
+        let v = att.w_v.apply(&token_shift(&att.time_mix_v, x, sx));
+        let r = att.w_r.apply(&token_shift(&att.time_mix_r, x, sx));
+
+        let (aa, rest) = kv.split_at_mut(num_emb);
+        let (bb, pp) = rest.split_at_mut(num_emb);
+
+        let y = (0..num_emb)
+            .map(|i| {
+                let w = -att.time_decay[i].exp();
+                let u = att.time_first[i];
+
+                let ww = u + k[i];
+                let q = pp[i].max(ww);
+                let e1 = (pp[i] - q).exp();
+                let e2 = (ww - q).exp();
+                let y = sigmoid(r[i]) * (e1 * aa[i] + e2 * v[i]) / (e1 * bb[i] + e2);
+
+                let ww = w + pp[i];
+                let q = ww.max(k[i]);
+                let e1 = (ww - q).exp();
+                let e2 = (k[i] - q).exp();
+                aa[i] = e1 * aa[i] + e2 * v[i];
+                bb[i] = e1 * bb[i] + e2;
+                pp[i] = q;
+
+                y
+            })
+            .collect_vec();
+        att.w_o.apply(&y)
+    }
+
+    fn time_mix_v5(&self, x: &[f32], sx: &[f32], kv: &mut [f32]) -> Vec<f32> {
+        let num_emb = self.info.num_emb;
+        let num_head = self.info.num_head;
+        let head_size = num_emb / num_head;
+        let att = &self.att;
+
+        let time_mix_g = att.time_mix_g.as_ref().expect("v5 gate mix");
+        let w_g = att.w_g.as_ref().expect("v5 gate");
+        let group_norm = att.group_norm.as_ref().expect("v5 group norm");
+
+        let k = att.w_k.apply(&token_shift(&att.time_mix_k, x, sx));
+        let v = att.w_v.apply(&token_shift(&att.time_mix_v, x, sx));
+        let r = att.w_r.apply(&token_shift(&att.time_mix_r, x, sx));
+        let g = w_g.apply(&token_shift(time_mix_g, x, sx));
+
+        let mut y = vec![0.0; num_emb];
+        for head in 0..num_head {
+            for i in (0..head_size).map(|i| head * head_size + i) {
+                for j in 0..head_size {
+                    let kj = head * head_size + j;
+                    let w = (-att.time_decay[kj].exp()).exp();
+                    let u = att.time_first[kj];
+
+                    let kv_ = k[kj] * v[i];
+                    let s = &mut kv[j * num_emb + i];
+                    y[i] += r[kj] * (u * kv_ + *s);
+                    *s = w * *s + kv_;
+                }
+            }
+        }
+
+        let y = group_norm.apply(&y, num_head, GROUP_NORM_EPS);
+        let y = y
+            .into_iter()
+            .zip_eq(g)
+            .map(|(y, g)| y * g * sigmoid(g))
+            .collect_vec();
+        att.w_o.apply(&y)
+    }
+
+    fn channel_mix(&self, x: &[f32], sx: &[f32]) -> Vec<f32> {
+        let ffn = &self.ffn;
+        let k = ffn.w_k.apply(&token_shift(&ffn.time_mix_k, x, sx));
+        let k = k.into_iter().map(|k| k.max(0.0).powi(2)).collect_vec();
+        let v = ffn.w_v.apply(&k);
+        let r = ffn.w_r.apply(&token_shift(&ffn.time_mix_r, x, sx));
+        r.into_iter()
+            .zip_eq(v)
+            .map(|(r, v)| sigmoid(r) * v)
+            .collect()
+    }
+}
@@ -1,25 +1,216 @@
+use std::{
+    borrow::Cow,
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    io::{Read, Seek, SeekFrom},
+    ops::Range,
+    path::PathBuf,
+};
+
 use anyhow::Result;
 use derive_getters::Getters;
 use half::f16;
 use itertools::Itertools;
-use safetensors::SafeTensors;
+use regex::Regex;
+use safetensors::{
+    tensor::{Metadata, TensorInfo, TensorView},
+    Dtype, SafeTensorError, SafeTensors,
+};
 use wgpu::{CommandEncoderDescriptor, ComputePassDescriptor};
 
-use super::{Lora, ModelInfo, ModelVersion};
+use super::{Lora, LoraPatternError, ModelInfo, ModelVersion};
+#[cfg(feature = "tokenizer")]
+use crate::tokenizer::Tokenizer;
 use crate::{
     context::Context,
     tensor::{
+        arena::TensorArena,
         ops::{TensorOp, TensorPass},
         shape::{Shape, TensorDimension},
-        ReadWrite, TensorCpu, TensorGpu, TensorInit, TensorReshape, TensorShape,
+        ReadWrite, TensorCpu, TensorError, TensorGpu, TensorInit, TensorReshape, TensorShape,
     },
 };
 
+/// The largest header accepted from a stream, same as the limit of `safetensors`.
+const MAX_HEADER_SIZE: u64 = 100_000_000;
+
+/// A seekable stream of a safetensors file, for platforms where it can't be memory-mapped.
+pub trait Reader: Read + Seek {}
+
+impl<T: Read + Seek> Reader for T {}
+
+/// Files looked for next to the checkpoint by [`Loader::tokenizer`], after `<model>.tokenizer.json`.
+#[cfg(feature = "tokenizer")]
+pub const TOKENIZER_FILES: [&str; 3] =
+    ["tokenizer.json", "vocab.json", "rwkv_vocab_v20230424.json"];
+
 #[derive(Getters)]
 pub struct Loader<'a> {
     context: Context,
-    model: SafeTensors<'a>,
+    #[getter(skip)]
+    source: Source<'a>,
+    /// The free-form `__metadata__` of the checkpoint.
+    metadata: HashMap<String, String>,
+    path: Option<PathBuf>,
     lora: Vec<Lora>,
+    /// The `lora_alpha` of each LoRA, from its metadata.
+    #[getter(skip)]
+    lora_alpha: Vec<LoraAlpha>,
+    /// Shared buffers for the small per-layer vectors.
+    #[getter(skip)]
+    arena: TensorArena,
+}
+
+/// Where the model tensors are read from.
+enum Source<'a> {
+    /// The whole file in memory, usually memory-mapped.
+    Bytes(SafeTensors<'a>),
+    /// A stream that tensors are read from one at a time.
+    Stream {
+        reader: RefCell<&'a mut dyn Reader>,
+        tensors: HashMap<String, TensorInfo>,
+        offset: u64,
+    },
+}
+
+/// A tensor either borrowed from memory or read from a stream.
+struct SourceTensor<'a> {
+    dtype: Dtype,
+    shape: Vec<usize>,
+    data: Cow<'a, [u8]>,
+}
+
+impl SourceTensor<'_> {
+    fn view(&self) -> Result<TensorView<'_>, SafeTensorError> {
+        TensorView::new(self.dtype, self.shape.clone(), &self.data)
+    }
+}
+
+impl<'a> Source<'a> {
+    fn stream(reader: &'a mut dyn Reader) -> Result<(Self, HashMap<String, String>)> {
+        let mut len = [0; 8];
+        reader.seek(SeekFrom::Start(0))?;
+        reader.read_exact(&mut len)?;
+        let len = u64::from_le_bytes(len);
+        if len > MAX_HEADER_SIZE {
+            return Err(SafeTensorError::HeaderTooLarge.into());
+        }
+
+        let mut header = vec![0; len as usize];
+        reader.read_exact(&mut header)?;
+        let metadata: Metadata = serde_json::from_slice(&header)
+            .map_err(|_| SafeTensorError::InvalidHeaderDeserialization)?;
+        let tensors = metadata
+            .tensors()
+            .into_iter()
+            .map(|(name, info)| (name, info.clone()))
+            .collect();
+
+        let source = Self::Stream {
+            reader: RefCell::new(reader),
+            tensors,
+            offset: 8 + len,
+        };
+        Ok((source, metadata.metadata().clone().unwrap_or_default()))
+    }
+
+    fn names(&self) -> Vec<&str> {
+        match self {
+            Source::Bytes(model) => model.names().into_iter().map(|x| x.as_str()).collect(),
+            Source::Stream { tensors, .. } => tensors.keys().map(|x| x.as_str()).collect(),
+        }
+    }
+
+    fn shape(&self, name: &str) -> Result<Vec<usize>, SafeTensorError> {
+        match self {
+            Source::Bytes(model) => Ok(model.tensor(name)?.shape().to_vec()),
+            Source::Stream { tensors, .. } => tensors
+                .get(name)
+                .map(|info| info.shape.clone())
+                .ok_or_else(|| SafeTensorError::TensorNotFound(name.into())),
+        }
+    }
+
+    fn dtype(&self, name: &str) -> Result<Dtype, SafeTensorError> {
+        match self {
+            Source::Bytes(model) => Ok(model.tensor(name)?.dtype()),
+            Source::Stream { tensors, .. } => tensors
+                .get(name)
+                .map(|info| info.dtype)
+                .ok_or_else(|| SafeTensorError::TensorNotFound(name.into())),
+        }
+    }
+
+    /// Read `range` in bytes of a tensor's data.
+    fn read(&self, name: &str, range: Range<usize>) -> Result<Cow<'_, [u8]>> {
+        match self {
+            Source::Bytes(model) => Ok(model.tensor(name)?.data()[range].into()),
+            Source::Stream { .. } => {
+                let mut data = vec![0; range.len()];
+                self.read_into(name, range, &mut data)?;
+                Ok(data.into())
+            }
+        }
+    }
+
+    /// Read `range` in bytes of a tensor's data into `data`, which must be as long.
+    fn read_into(&self, name: &str, range: Range<usize>, data: &mut [u8]) -> Result<()> {
+        if range.start > range.end || range.len() != data.len() {
+            return Err(SafeTensorError::InvalidOffset(name.into()).into());
+        }
+        match self {
+            Source::Bytes(model) => {
+                let tensor = model.tensor(name)?;
+                let source = tensor
+                    .data()
+                    .get(range)
+                    .ok_or_else(|| SafeTensorError::InvalidOffset(name.into()))?;
+                data.copy_from_slice(source);
+            }
+            Source::Stream {
+                reader,
+                tensors,
+                offset,
+            } => {
+                let info = tensors
+                    .get(name)
+                    .ok_or_else(|| SafeTensorError::TensorNotFound(name.into()))?;
+                let (start, end) = info.data_offsets;
+                if start + range.end > end {
+                    return Err(SafeTensorError::InvalidOffset(name.into()).into());
+                }
+
+                let mut reader = reader.borrow_mut();
+                reader.seek(SeekFrom::Start(offset + (start + range.start) as u64))?;
+                reader.read_exact(data)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn tensor(&self, name: &str) -> Result<SourceTensor<'_>> {
+        match self {
+            Source::Bytes(model) => {
+                let tensor = model.tensor(name)?;
+                Ok(SourceTensor {
+                    dtype: tensor.dtype(),
+                    shape: tensor.shape().to_vec(),
+                    data: tensor.data().into(),
+                })
+            }
+            Source::Stream { tensors, .. } => {
+                let info = tensors
+                    .get(name)
+                    .ok_or_else(|| SafeTensorError::TensorNotFound(name.into()))?;
+                let (start, end) = info.data_offsets;
+                Ok(SourceTensor {
+                    dtype: info.dtype,
+                    shape: info.shape.clone(),
+                    data: self.read(name, 0..end - start)?,
+                })
+            }
+        }
+    }
 }
 
 struct LoraVector {
@@ -30,29 +221,179 @@ struct LoraVector {
 struct LoraMatrix {
     a: TensorGpu<f16, ReadWrite>,
     b: TensorGpu<f16, ReadWrite>,
-    rank: usize,
-    alpha: f32,
+    /// The blend factor times the scale of the product of the matrices.
+    factor: f32,
+}
+
+/// The `lora_alpha` a LoRA was trained with, as PEFT stores it in the metadata: one for all matrices,
+/// and those of the matrices matching the patterns of `alpha_pattern`. See [`LoraScale`](super::LoraScale).
+#[derive(Debug, Clone, Default)]
+struct LoraAlpha {
+    alpha: Option<f32>,
+    patterns: Vec<(Regex, f32)>,
+}
+
+impl LoraAlpha {
+    fn from_metadata(metadata: &HashMap<String, String>) -> Result<Self> {
+        let alpha = metadata
+            .get("lora_alpha")
+            .map(|alpha| alpha.trim().parse())
+            .transpose()?;
+        let patterns = match metadata.get("alpha_pattern") {
+            Some(patterns) => {
+                let patterns: HashMap<String, f32> = serde_json::from_str(patterns)?;
+                patterns
+                    .into_iter()
+                    .sorted_by(|x, y| x.0.cmp(&y.0))
+                    .map(|(pattern, alpha)| Ok((Regex::new(&pattern)?, alpha)))
+                    .collect::<Result<_>>()?
+            }
+            None => vec![],
+        };
+        Ok(Self { alpha, patterns })
+    }
+
+    /// The `lora_alpha` of the matrix `name`, that of the last matching pattern if any.
+    fn get(&self, name: &str) -> Option<f32> {
+        self.patterns
+            .iter()
+            .rev()
+            .find(|(pattern, _)| pattern.is_match(name))
+            .map(|(_, alpha)| *alpha)
+            .or(self.alpha)
+    }
 }
 
 impl<'a> Loader<'a> {
     pub fn new(context: &Context, data: &'a [u8], lora: Vec<Lora>) -> Result<Loader<'a>> {
-        let model = SafeTensors::deserialize(data)?;
-        let lora = lora
+        let source = Source::Bytes(SafeTensors::deserialize(data)?);
+        let (_, metadata) = SafeTensors::read_metadata(data)?;
+        let metadata = metadata.metadata().clone().unwrap_or_default();
+        Self::from_source(context, source, metadata, lora)
+    }
+
+    /// Create a loader that reads tensors one at a time from a stream instead of a memory map.
+    pub fn from_reader(
+        context: &Context,
+        reader: &'a mut dyn Reader,
+        lora: Vec<Lora>,
+    ) -> Result<Loader<'a>> {
+        let (source, metadata) = Source::stream(reader)?;
+        Self::from_source(context, source, metadata, lora)
+    }
+
+    fn from_source(
+        context: &Context,
+        source: Source<'a>,
+        metadata: HashMap<String, String>,
+        lora: Vec<Lora>,
+    ) -> Result<Loader<'a>> {
+        let model: HashSet<_> = source.names().into_iter().collect();
+        let (lora, lora_alpha): (Vec<_>, Vec<_>) = lora
             .into_iter()
-            .map(|lora| -> Result<_> {
-                let _ = SafeTensors::deserialize(&lora.data)?;
-                Ok(lora)
+            .enumerate()
+            .map(|(index, lora)| -> Result<_> {
+                // the tensors of the model the LoRA has something for, either a vector or the two halves of a matrix
+                let data = SafeTensors::deserialize(&lora.data)?;
+                let names = data
+                    .names()
+                    .into_iter()
+                    .map(|name| {
+                        let name = name.as_str();
+                        let name = name.strip_suffix(".lora.0").unwrap_or(name);
+                        name.strip_suffix(".lora.1").unwrap_or(name)
+                    })
+                    .filter(|name| model.contains(name))
+                    .unique()
+                    .collect_vec();
+                let patterns = lora.blend.unmatched(&names);
+                if !patterns.is_empty() {
+                    let patterns = patterns.into_iter().map(String::from).collect();
+                    return Err(LoraPatternError {
+                        lora: index,
+                        patterns,
+                    }
+                    .into());
+                }
+
+                let (_, metadata) = SafeTensors::read_metadata(&lora.data)?;
+                let metadata = metadata.metadata().clone().unwrap_or_default();
+                let alpha = LoraAlpha::from_metadata(&metadata)?;
+                Ok((lora, alpha))
             })
-            .try_collect()?;
+            .process_results(|iter| iter.unzip())?;
         Ok(Self {
             context: context.clone(),
-            model,
+            source,
+            metadata,
+            path: None,
             lora,
+            lora_alpha,
+            arena: TensorArena::new(context),
         })
     }
 
+    /// The file the checkpoint was read from, so that files next to it can be found.
+    pub fn with_path(self, path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: Some(path.into()),
+            ..self
+        }
+    }
+
+    /// Find the tokenizer that goes with the checkpoint. In order, this tries
+    /// 1. the `tokenizer` entry of the metadata, either a vocabulary inlined as JSON or a file name
+    ///    relative to the checkpoint;
+    /// 2. `<model>.tokenizer.json` and then [`TOKENIZER_FILES`] next to the checkpoint, if its path is known.
+    #[cfg(feature = "tokenizer")]
+    pub fn tokenizer(&self) -> Result<Option<Tokenizer>> {
+        use std::path::Path;
+
+        let dir = self
+            .path
+            .as_deref()
+            .and_then(Path::parent)
+            .unwrap_or(Path::new(""));
+
+        if let Some(tokenizer) = self.metadata.get("tokenizer") {
+            let vocab = match tokenizer.trim_start().starts_with('{') {
+                true => tokenizer.clone(),
+                false => std::fs::read_to_string(dir.join(tokenizer))?,
+            };
+            return Ok(Some(Tokenizer::new(&vocab)?));
+        }
+
+        let Some(path) = &self.path else {
+            return Ok(None);
+        };
+        let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+        let candidates = std::iter::once(format!("{stem}.tokenizer.json"))
+            .chain(TOKENIZER_FILES.iter().map(|name| name.to_string()));
+        for name in candidates {
+            let file = dir.join(name);
+            if file.is_file() {
+                let vocab = std::fs::read_to_string(file)?;
+                return Ok(Some(Tokenizer::new(&vocab)?));
+            }
+        }
+        Ok(None)
+    }
+
     pub fn info(data: &'a [u8]) -> Result<ModelInfo> {
-        let model = SafeTensors::deserialize(data)?;
+        Self::source_info(&Source::Bytes(SafeTensors::deserialize(data)?))
+    }
+
+    /// Read the model info from the header of a stream.
+    pub fn info_from_reader(reader: &'a mut dyn Reader) -> Result<ModelInfo> {
+        Self::source_info(&Source::stream(reader)?.0)
+    }
+
+    /// The info of the model being loaded.
+    pub fn model_info(&self) -> Result<ModelInfo> {
+        Self::source_info(&self.source)
+    }
+
+    fn source_info(model: &Source) -> Result<ModelInfo> {
         let num_layers = {
             let mut r: usize = 0;
             for i in model.names() {
@@ -65,18 +406,18 @@ impl<'a> Loader<'a> {
             r + 1
         };
 
-        let embed = model.tensor("emb.weight")?;
-        let ffn = model.tensor("blocks.0.ffn.key.weight")?;
-        let time_decay = model.tensor("blocks.0.att.time_decay")?;
-        let version = match model.tensor("blocks.0.att.gate.weight") {
+        let embed = model.shape("emb.weight")?;
+        let ffn = model.shape("blocks.0.ffn.key.weight")?;
+        let time_decay = model.shape("blocks.0.att.time_decay")?;
+        let version = match model.shape("blocks.0.att.gate.weight") {
             Ok(_) => ModelVersion::V5,
             Err(_) => ModelVersion::V4,
         };
 
-        let num_emb = embed.shape()[1];
-        let num_hidden = ffn.shape()[0];
-        let num_vocab = embed.shape()[0];
-        let num_head = time_decay.shape()[0];
+        let num_emb = embed[1];
+        let num_hidden = ffn[0];
+        let num_vocab = embed[0];
+        let num_head = time_decay[0];
 
         Ok(ModelInfo {
             version,
@@ -121,7 +462,8 @@ impl<'a> Loader<'a> {
         let name = name.as_ref();
         self.lora
             .iter()
-            .filter_map(|lora| {
+            .zip_eq(self.lora_alpha.iter())
+            .filter_map(|(lora, lora_alpha)| {
                 let data = SafeTensors::deserialize(&lora.data).ok()?;
                 lora.blend
                     .iter()
@@ -159,10 +501,16 @@ impl<'a> Loader<'a> {
                         // context.queue.submit(Some(encoder.finish()));
 
                         let rank = a.shape()[0];
-                        let alpha = blend.alpha;
+                        let scale = blend.scale.factor(rank, lora_alpha.get(name));
+                        let factor = blend.alpha * scale;
 
-                        log::info!("loaded lora {}, alpha: {}", name, blend.alpha);
-                        Some(LoraMatrix { a, b, rank, alpha })
+                        log::info!(
+                            "loaded lora {}, alpha: {}, scale: {}",
+                            name,
+                            blend.alpha,
+                            scale
+                        );
+                        Some(LoraMatrix { a, b, factor })
                     })
             })
             .collect()
@@ -170,11 +518,11 @@ impl<'a> Loader<'a> {
 
     pub fn load_vector_f32(&self, name: impl AsRef<str>) -> Result<TensorGpu<f32, ReadWrite>> {
         use TensorDimension::{Auto, Dimension};
-        let tensor = self.model.tensor(name.as_ref())?;
-        let tensor = TensorCpu::<f16>::from_safetensors(&self.context, tensor)?
+        let tensor = self.source.tensor(name.as_ref())?;
+        let tensor = TensorCpu::<f16>::from_safetensors(&self.context, tensor.view()?)?
             .map(|x| x.to_f32())
-            .reshape(Auto, Dimension(1), Dimension(1), Dimension(1))?
-            .into();
+            .reshape(Auto, Dimension(1), Dimension(1), Dimension(1))?;
+        let tensor = self.arena.upload(&tensor)?;
 
         let mut encoder = self
             .context
@@ -195,11 +543,11 @@ impl<'a> Loader<'a> {
 
     pub fn load_vector_exp_f32(&self, name: impl AsRef<str>) -> Result<TensorGpu<f32, ReadWrite>> {
         use TensorDimension::{Auto, Dimension};
-        let tensor = self.model.tensor(name.as_ref())?;
-        let tensor = TensorCpu::<f16>::from_safetensors(&self.context, tensor)?
+        let tensor = self.source.tensor(name.as_ref())?;
+        let tensor = TensorCpu::<f16>::from_safetensors(&self.context, tensor.view()?)?
             .map(|x| -x.to_f32().exp())
-            .reshape(Auto, Dimension(1), Dimension(1), Dimension(1))?
-            .into();
+            .reshape(Auto, Dimension(1), Dimension(1), Dimension(1))?;
+        let tensor = self.arena.upload(&tensor)?;
 
         let mut encoder = self
             .context
@@ -223,12 +571,12 @@ impl<'a> Loader<'a> {
         name: impl AsRef<str>,
     ) -> Result<TensorGpu<f32, ReadWrite>> {
         use TensorDimension::{Auto, Dimension};
-        let tensor = self.model.tensor(name.as_ref())?;
-        let tensor = TensorCpu::<f16>::from_safetensors(&self.context, tensor)?
+        let tensor = self.source.tensor(name.as_ref())?;
+        let tensor = TensorCpu::<f16>::from_safetensors(&self.context, tensor.view()?)?
             .map(|x| -x.to_f32().exp())
             .map(|x| x.exp())
-            .reshape(Auto, Dimension(1), Dimension(1), Dimension(1))?
-            .into();
+            .reshape(Auto, Dimension(1), Dimension(1), Dimension(1))?;
+        let tensor = self.arena.upload(&tensor)?;
 
         let mut encoder = self
             .context
@@ -251,20 +599,21 @@ impl<'a> Loader<'a> {
         use TensorDimension::{Auto, Dimension};
         let context = &self.context;
         let lora = self.lora_vectors(name.as_ref());
-        let tensor = self.model.tensor(name.as_ref())?;
+        let tensor = self.source.tensor(name.as_ref())?;
         let tensor = if lora.is_empty() {
-            TensorGpu::from_safetensors(context, tensor)?.reshape(
+            let tensor = TensorCpu::from_safetensors(context, tensor.view()?)?.reshape(
                 Auto,
                 Dimension(1),
                 Dimension(1),
                 Dimension(1),
-            )?
+            )?;
+            self.arena.upload(&tensor)?
         } else {
-            let tensor_f32 = TensorCpu::<f16>::from_safetensors(context, tensor)?
+            let tensor_f32 = TensorCpu::<f16>::from_safetensors(context, tensor.view()?)?
                 .map(|x| x.to_f32())
                 .reshape(Auto, Dimension(1), Dimension(1), Dimension(1))?;
             let tensor_f32 = TensorGpu::from(tensor_f32);
-            let tensor_f16 = context.tensor_init(tensor_f32.shape());
+            let tensor_f16 = self.arena.tensor_init(tensor_f32.shape());
 
             let mut encoder = context
                 .device
@@ -290,46 +639,15 @@ impl<'a> Loader<'a> {
     }
 
     pub fn load_matrix_f16(&self, name: impl AsRef<str>) -> Result<TensorGpu<f16, ReadWrite>> {
-        use TensorDimension::{Dimension, Full};
-        let context = &self.context;
-        let lora = self.lora_matrices(name.as_ref());
-        let tensor = self.model.tensor(name.as_ref())?;
-        let tensor = if lora.is_empty() {
-            TensorGpu::from_safetensors(context, tensor)?.reshape(
-                Full,
-                Full,
-                Dimension(1),
-                Dimension(1),
-            )?
-        } else {
-            let tensor = TensorGpu::from_safetensors(context, tensor)?.reshape(
-                Full,
-                Full,
-                Dimension(1),
-                Dimension(1),
-            )?;
-
-            let mut encoder = context
-                .device
-                .create_command_encoder(&CommandEncoderDescriptor::default());
-            for lora in lora {
-                let factor = vec![lora.alpha / lora.rank as f32, 1.0, 0.0, 0.0];
-                let factor = TensorGpu::from_data(context, Shape::new(4, 1, 1, 1), &factor)?;
-                let ops = TensorOp::List(vec![TensorOp::blend_lora(
-                    &factor,
-                    lora.b.view(.., .., .., ..)?,
-                    lora.a.view(.., .., .., ..)?,
-                    tensor.view(.., .., .., ..)?,
-                )?]);
-                let mut pass = encoder.begin_compute_pass(&ComputePassDescriptor::default());
-                pass.execute_tensor_op(&ops);
-            }
-            context.queue.submit(Some(encoder.finish()));
-            tensor
-        };
-        Ok(tensor)
+        self.load_matrix_f16_discount(name, 1.0)
     }
 
+    /// Load a matrix scaled by `discount`. Without LoRAs, the raw matrix is uploaded as is and scaled on the device,
+    /// so no host copy of it is made, even when reading from a stream.
+    ///
+    /// With LoRAs, the matrix is widened to f32 on the device, the deltas are blended in and the sum is scaled there,
+    /// and only then rounded to f16, so that the deltas are discounted along with it and are not lost to rounding
+    /// before the matrix is quantized. This takes twice the memory of the matrix while loading.
     pub fn load_matrix_f16_discount(
         &self,
         name: impl AsRef<str>,
@@ -339,65 +657,548 @@ impl<'a> Loader<'a> {
         let context = &self.context;
 
         let lora = self.lora_matrices(name.as_ref());
-        let tensor = self.model.tensor(name.as_ref())?;
+        let factor = vec![discount, 1.0, 0.0, 0.0];
+        let factor = TensorGpu::from_data(context, Shape::new(4, 1, 1, 1), &factor)?;
+
+        let mut encoder = context
+            .device
+            .create_command_encoder(&CommandEncoderDescriptor::default());
+
         let tensor = if lora.is_empty() {
-            let tensor = TensorCpu::<f16>::from_safetensors(context, tensor)?
-                .map(|&x| f16::from_f32(discount * x.to_f32()))
-                .reshape(Full, Full, Dimension(1), Dimension(1))?;
-            TensorGpu::from(tensor)
+            let shape = Shape::from_safetensors(&self.source.shape(name.as_ref())?)?;
+            let size = shape.len() * std::mem::size_of::<f16>();
+            let tensor = self.upload_f16(name.as_ref(), 0..size, shape)?.reshape(
+                Full,
+                Full,
+                Dimension(1),
+                Dimension(1),
+            )?;
+            if discount != 1.0 {
+                let op = TensorOp::discount(&factor, &tensor)?;
+                let mut pass = encoder.begin_compute_pass(&ComputePassDescriptor::default());
+                pass.execute_tensor_op(&op);
+            }
+            tensor
         } else {
-            let tensor = TensorCpu::<f16>::from_safetensors(context, tensor)?
-                .map(|x| f16::from_f32(discount * x.to_f32()))
+            let tensor = self.source.tensor(name.as_ref())?;
+            let tensor_f32 = TensorCpu::<f16>::from_safetensors(context, tensor.view()?)?
+                .map(|x| x.to_f32())
                 .reshape(Full, Full, Dimension(1), Dimension(1))?;
-            let tensor = TensorGpu::from(tensor);
+            let tensor_f32 = TensorGpu::from(tensor_f32);
+            let tensor_f16 = context.tensor_init(tensor_f32.shape());
 
-            let mut encoder = context
-                .device
-                .create_command_encoder(&CommandEncoderDescriptor::default());
             for lora in lora {
-                let factor = vec![lora.alpha / lora.rank as f32, 1.0, 0.0, 0.0];
+                let factor = vec![lora.factor, 1.0, 0.0, 0.0];
                 let factor = TensorGpu::from_data(context, Shape::new(4, 1, 1, 1), &factor)?;
-                let ops = TensorOp::List(vec![TensorOp::blend_lora(
+                let op = TensorOp::blend_lora(
                     &factor,
                     lora.b.view(.., .., .., ..)?,
                     lora.a.view(.., .., .., ..)?,
-                    tensor.view(.., .., .., ..)?,
-                )?]);
+                    tensor_f32.view(.., .., .., ..)?,
+                )?;
                 let mut pass = encoder.begin_compute_pass(&ComputePassDescriptor::default());
-                pass.execute_tensor_op(&ops);
+                pass.execute_tensor_op(&op);
             }
-            context.queue.submit(Some(encoder.finish()));
-            tensor
+
+            let mut ops = vec![];
+            if discount != 1.0 {
+                ops.push(TensorOp::discount(&factor, &tensor_f32)?);
+            }
+            ops.push(TensorOp::quantize_fp16(&tensor_f32, &tensor_f16)?);
+            let op = TensorOp::List(ops);
+            let mut pass = encoder.begin_compute_pass(&ComputePassDescriptor::default());
+            pass.execute_tensor_op(&op);
+            drop(pass);
+            tensor_f16
         };
+        context.queue.submit(Some(encoder.finish()));
+
+        self.flush();
         Ok(tensor)
     }
 
     pub fn load_embed<'b>(&self) -> Result<TensorCpu<'b, f16>> {
-        let embed = self.model.tensor("emb.weight")?;
-        let num_emb = embed.shape()[1];
-        let num_vocab = embed.shape()[0];
+        let embed = self.source.tensor("emb.weight")?;
+        let num_emb = embed.shape[1];
+        let num_vocab = embed.shape[0];
         let tensor = self.context.tensor_from_data(
             Shape::new(num_emb, num_vocab, 1, 1),
-            bytemuck::pod_collect_to_vec(embed.data()),
+            bytemuck::pod_collect_to_vec(&embed.data),
         )?;
         Ok(tensor)
     }
 
+    /// Read a tensor into host memory with its shape as stored, for layers run on the host.
+    /// LoRAs are not blended in.
+    pub fn load_host_f16(&self, name: impl AsRef<str>) -> Result<(Vec<usize>, Vec<f16>)> {
+        let tensor = self.source.tensor(name.as_ref())?;
+        Ok((tensor.shape, bytemuck::pod_collect_to_vec(&tensor.data)))
+    }
+
+    /// Whether the checkpoint ties its head to the embedding by omitting `head.weight`.
+    /// A `head.weight` is always used when present, even if it is a copy of `emb.weight`,
+    /// so that the two are never read in full just to compare them.
+    pub fn tied_embed(&self) -> bool {
+        self.source.shape("emb.weight").is_ok() && self.source.shape("head.weight").is_err()
+    }
+
+    /// Load the head matrix in chunks of `chunk_size` rows, the last of which holds the rows left over.
+    /// For tied checkpoints the chunks are read from `emb.weight`.
     pub fn load_head(&self, chunk_size: usize) -> Result<Vec<TensorGpu<f16, ReadWrite>>> {
-        let context = &self.context;
-        let tensor = self.model.tensor("head.weight")?;
-        let shape = tensor.shape();
+        let name = match self.tied_embed() {
+            true => "emb.weight",
+            false => "head.weight",
+        };
+        let shape = self.source.shape(name)?;
         let shape = Shape::new(shape[1], shape[0], 1, 1);
-        let chunks = shape[1] / chunk_size;
-        let data = bytemuck::cast_slice(tensor.data());
+        let chunks = shape[1].div_ceil(chunk_size);
+        let row = shape[0] * std::mem::size_of::<f16>();
 
         let head = (0..chunks)
-            .map(|chunk| {
-                let start = (chunk * chunk_size) * shape[0];
-                let end = start + chunk_size * shape[0];
-                context.tensor_from_data(Shape::new(shape[0], chunk_size, 1, 1), &data[start..end])
+            .map(|chunk| -> Result<_> {
+                let start = chunk * chunk_size;
+                let end = (start + chunk_size).min(shape[1]);
+                let range = start * row..end * row;
+                let shape = Shape::new(shape[0], end - start, 1, 1);
+                let chunk = self.upload_f16(name, range, shape)?;
+                self.flush();
+                Ok(chunk)
             })
             .try_collect()?;
         Ok(head)
     }
+
+    /// Upload `range` in bytes of an f16 tensor, which must hold `shape`, straight into a new buffer,
+    /// without copying it to host memory first: a memory-mapped file is copied from in place,
+    /// and a stream is read into the mapped buffer.
+    fn upload_f16(
+        &self,
+        name: &str,
+        range: Range<usize>,
+        shape: Shape,
+    ) -> Result<TensorGpu<f16, ReadWrite>> {
+        if self.source.dtype(name)? != Dtype::F16 {
+            return Err(TensorError::Type.into());
+        }
+        let size = std::mem::size_of::<f16>();
+        if range.len() != shape.len() * size {
+            return Err(TensorError::Size(shape.len(), range.len() / size).into());
+        }
+        TensorGpu::init_with(&self.context, shape, |data| {
+            self.source.read_into(name, range, data)
+        })
+    }
+
+    /// Submit pending uploads and wait for them, so that their staging buffers are freed
+    /// before the next tensor is read.
+    fn flush(&self) {
+        self.context.queue.submit(None);
+        self.context.device.poll(wgpu::MaintainBase::Wait);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use anyhow::Result;
+    use half::f16;
+    use itertools::Itertools;
+    use safetensors::{tensor::TensorView, Dtype, SafeTensors};
+
+    use super::Loader;
+    use crate::model::{
+        reference,
+        synthetic::{
+            testing::{create_context, is_approx_eps, relative_error, run},
+            SyntheticBuilder,
+        },
+        v4, v5, Lora, LoraBlend, LoraBlendPattern, LoraPatternError, LoraScale, ModelBuilder,
+        ModelVersion, Quant, StateBuilder,
+    };
+
+    /// Merge a LoRA into the model on CPU, the way the loader blends it on GPU.
+    fn merge_lora(data: &[u8], lora: &[u8], alpha: f32) -> Result<Vec<u8>> {
+        let model = SafeTensors::deserialize(data)?;
+        let lora = SafeTensors::deserialize(lora)?;
+        let read = |tensor: TensorView| -> Vec<f32> {
+            let data: Vec<f16> = bytemuck::pod_collect_to_vec(tensor.data());
+            data.into_iter().map(f16::to_f32).collect()
+        };
+
+        let tensors: Vec<(String, Vec<usize>, Vec<f16>)> = model
+            .tensors()
+            .into_iter()
+            .map(|(name, tensor)| -> Result<_> {
+                let shape = tensor.shape().to_vec();
+                let mut data = read(tensor);
+                if let Ok(vector) = lora.tensor(&name) {
+                    for (x, y) in data.iter_mut().zip_eq(read(vector)) {
+                        *x = alpha * y + (1.0 - alpha) * *x;
+                    }
+                }
+                if let (Ok(a), Ok(b)) = (
+                    lora.tensor(&format!("{name}.lora.0")),
+                    lora.tensor(&format!("{name}.lora.1")),
+                ) {
+                    let rank = a.shape()[1];
+                    let (a, b) = (read(a), read(b));
+                    for (index, x) in data.iter_mut().enumerate() {
+                        let (row, col) = (index / shape[1], index % shape[1]);
+                        let a = &a[row * rank..(row + 1) * rank];
+                        let b = &b[col * rank..(col + 1) * rank];
+                        let delta: f32 = a.iter().zip_eq(b.iter()).map(|(a, b)| a * b).sum();
+                        *x += alpha / rank as f32 * delta;
+                    }
+                }
+                let data = data.into_iter().map(f16::from_f32).collect();
+                Ok((name, shape, data))
+            })
+            .try_collect()?;
+
+        let views: Vec<_> = tensors
+            .iter()
+            .map(|(name, shape, data)| {
+                TensorView::new(Dtype::F16, shape.clone(), bytemuck::cast_slice(data))
+                    .map(|view| (name, view))
+            })
+            .try_collect()?;
+        Ok(safetensors::serialize(views, &None)?)
+    }
+
+    #[test]
+    fn test_reader() -> Result<()> {
+        let context = match create_context() {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+        let tokens = [vec![5u16, 23, 177, 2, 94]];
+
+        for tied_embed in [false, true] {
+            let builder = SyntheticBuilder::new(ModelVersion::V4).with_tied_embed(tied_embed);
+            let info = builder.info();
+            let data = builder.build()?;
+
+            let mut reader = Cursor::new(&data);
+            assert_eq!(Loader::info_from_reader(&mut reader)?, info);
+            let loader = Loader::from_reader(&context, &mut reader, vec![])?;
+            assert_eq!(loader.tied_embed(), tied_embed);
+
+            let model: v4::Model = ModelBuilder::new(&context, &data)
+                .with_head_chunk_size(64)
+                .build()?;
+            let state: v4::ModelState = StateBuilder::new(&context, &info).build();
+            let expected = run(&model, &state, &tokens)?;
+
+            // the head is read from the stream one chunk at a time
+            let model: v4::Model = ModelBuilder::from_reader(&context, Cursor::new(&data))
+                .with_head_chunk_size(64)
+                .build()?;
+            let state: v4::ModelState = StateBuilder::new(&context, &info).build();
+            assert_eq!(run(&model, &state, &tokens)?, expected);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_upload_f16() -> Result<()> {
+        let context = match create_context() {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+        let data = SyntheticBuilder::new(ModelVersion::V4).build()?;
+        // the file at an odd address, so that its f16 data can't be borrowed as `[f16]`
+        let mut unaligned = vec![0u8; data.len() + 1];
+        unaligned[1..].copy_from_slice(&data);
+        let unaligned = &unaligned[1..];
+
+        let name = "blocks.0.att.key.weight";
+        let model = SafeTensors::deserialize(&data)?;
+        let expected: Vec<f16> = bytemuck::pod_collect_to_vec(model.tensor(name)?.data());
+
+        let mut reader = Cursor::new(&data);
+        let loaders = [
+            Loader::new(&context, &data, vec![])?,
+            Loader::new(&context, unaligned, vec![])?,
+            Loader::from_reader(&context, &mut reader, vec![])?,
+        ];
+        for loader in loaders {
+            assert_eq!(loader.load_matrix_f16(name)?.back().to_vec(), expected);
+        }
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "tokenizer")]
+    fn test_tokenizer_discovery() -> Result<()> {
+        let context = match create_context() {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+
+        const VOCAB: &str = r#"{"1": "a", "2": "b", "3": "ab"}"#;
+        let data = SyntheticBuilder::new(ModelVersion::V4).build()?;
+        let dir = std::env::temp_dir().join("web-rwkv-test-tokenizer");
+        std::fs::create_dir_all(&dir)?;
+        let path = dir.join("model.st");
+
+        // nothing to find without metadata or a path
+        assert!(Loader::new(&context, &data, vec![])?.tokenizer()?.is_none());
+        let loader = Loader::new(&context, &data, vec![])?.with_path(&path);
+        assert!(loader.tokenizer()?.is_none());
+
+        std::fs::write(dir.join("vocab.json"), VOCAB)?;
+        let tokenizer = loader.tokenizer()?.expect("tokenizer next to the model");
+        assert_eq!(tokenizer.encode(b"aab")?, vec![1, 3]);
+
+        // metadata takes precedence, either inlined or naming a file
+        let with_metadata = |tokenizer: &str| -> Result<Vec<u8>> {
+            let model = SafeTensors::deserialize(&data)?;
+            let metadata = [("tokenizer".to_string(), tokenizer.to_string())].into();
+            Ok(safetensors::serialize(model.tensors(), &Some(metadata))?)
+        };
+        let inline = with_metadata(r#"{"1": "b", "2": "a"}"#)?;
+        let loader = Loader::new(&context, &inline, vec![])?;
+        assert_eq!(loader.tokenizer()?.unwrap().encode(b"ab")?, vec![2, 1]);
+
+        let named = with_metadata("missing.json")?;
+        let loader = Loader::new(&context, &named, vec![])?.with_path(&path);
+        assert!(loader.tokenizer().is_err());
+
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_lora() -> Result<()> {
+        let context = match create_context() {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+
+        const ALPHA: f32 = 0.5;
+        let builder = SyntheticBuilder::new(ModelVersion::V5);
+        let info = builder.info();
+        let data = builder.clone().build()?;
+        let lora = builder.with_seed(7).build_lora(8)?;
+
+        let merged = merge_lora(&data, &lora, ALPHA)?;
+        let reference = reference::Model::from_safetensors(&merged)?;
+
+        let model: v5::Model = ModelBuilder::new(&context, &data)
+            .with_head_chunk_size(info.num_vocab)
+            .add_lora(Lora {
+                data: lora,
+                blend: LoraBlend::full(ALPHA),
+                checksum: None,
+            })
+            .build()?;
+        let state: v5::ModelState = StateBuilder::new(&context, &info).build();
+
+        let tokens = vec![31u16, 4, 159, 26];
+        let logits = run(&model, &state, std::slice::from_ref(&tokens))?
+            .remove(0)
+            .unwrap();
+        let expected = reference.run(&tokens, &mut reference.init_state()).unwrap();
+
+        for (&a, &b) in logits.iter().zip_eq(expected.iter()) {
+            assert!(is_approx_eps(a, b, 1.0e-2), "logits: {a} vs {b}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_lora_scale() -> Result<()> {
+        assert_eq!(LoraScale::Auto.factor(8, None), 0.125);
+        assert_eq!(LoraScale::Auto.factor(8, Some(16.0)), 2.0);
+        assert_eq!(LoraScale::Rank.factor(8, Some(16.0)), 0.125);
+        assert_eq!(LoraScale::Alpha(4.0).factor(8, Some(16.0)), 0.5);
+        assert_eq!(LoraScale::Fixed(3.0).factor(8, Some(16.0)), 3.0);
+
+        let context = match create_context() {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+
+        const ALPHA: f32 = 0.5;
+        const PATTERN: &str = r"blocks\.[0-9]+\.([0-9a-zA-Z\.\_]+)";
+        let builder = SyntheticBuilder::new(ModelVersion::V5);
+        let info = builder.info();
+        let data = builder.clone().build()?;
+        let lora = builder.with_seed(7).build_lora(8)?;
+
+        let with_metadata = |metadata: &[(&str, &str)]| -> Result<Vec<u8>> {
+            let lora = SafeTensors::deserialize(&lora)?;
+            let metadata = metadata
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect();
+            Ok(safetensors::serialize(lora.tensors(), &Some(metadata))?)
+        };
+        let tokens = [vec![31u16, 4, 159, 26]];
+        let run_lora = |lora: Vec<u8>, blend: LoraBlend| -> Result<Vec<f32>> {
+            let model: v5::Model = ModelBuilder::new(&context, &data)
+                .with_head_chunk_size(info.num_vocab)
+                .add_lora(Lora {
+                    data: lora,
+                    blend,
+                    checksum: None,
+                })
+                .build()?;
+            let state: v5::ModelState = StateBuilder::new(&context, &info).build();
+            Ok(run(&model, &state, &tokens)?.remove(0).unwrap())
+        };
+        let assert_close = |logits: &[f32], expected: &[f32]| {
+            for (&a, &b) in logits.iter().zip_eq(expected.iter()) {
+                assert!(is_approx_eps(a, b, 1.0e-5), "logits: {a} vs {b}");
+            }
+        };
+
+        // `lora_alpha` in the metadata is picked up unless overridden
+        let tagged = with_metadata(&[("lora_alpha", "16")])?;
+        let expected = run_lora(
+            lora.clone(),
+            LoraBlend::full(ALPHA).with_scale(LoraScale::Alpha(16.0)),
+        )?;
+        assert_close(
+            &run_lora(tagged.clone(), LoraBlend::full(ALPHA))?,
+            &expected,
+        );
+        let fixed = LoraScale::Fixed(2.0);
+        assert_close(
+            &run_lora(lora.clone(), LoraBlend::full(ALPHA).with_scale(fixed))?,
+            &expected,
+        );
+
+        let logits = run_lora(tagged, LoraBlend::full(ALPHA).with_scale(LoraScale::Rank))?;
+        assert_close(&logits, &run_lora(lora.clone(), LoraBlend::full(ALPHA))?);
+        assert!(relative_error(&logits, &expected) > 0.01);
+
+        // so is `alpha_pattern`, for the matrices it matches
+        let tagged = with_metadata(&[("lora_alpha", "16"), ("alpha_pattern", r#"{"ffn": 4}"#)])?;
+        let blend = LoraBlend(vec![
+            LoraBlendPattern::new(PATTERN, ALPHA)?.with_scale(LoraScale::Alpha(16.0)),
+            LoraBlendPattern::new(r"ffn", ALPHA)?.with_scale(LoraScale::Alpha(4.0)),
+        ]);
+        let expected = run_lora(lora.clone(), blend)?;
+        assert_close(&run_lora(tagged, LoraBlend::full(ALPHA))?, &expected);
+
+        let tagged = with_metadata(&[("lora_alpha", "sixteen")])?;
+        assert!(run_lora(tagged, LoraBlend::full(ALPHA)).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_lora_patterns() -> Result<()> {
+        let pattern = LoraBlendPattern::glob("blocks.?.att.*", 1.0)?;
+        assert_eq!(pattern.pattern(), r"^blocks\..\.att\..*$");
+        let names = ["blocks.0.att.key.weight", "blocks.10.att.key.weight"];
+        assert!(LoraBlend(vec![pattern]).unmatched(&names).is_empty());
+        let pattern = LoraBlendPattern::glob("blocks.?.att.*", 1.0)?;
+        assert_eq!(LoraBlend(vec![pattern]).unmatched(&names[1..]).len(), 1);
+
+        let context = match create_context() {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+
+        const ALPHA: f32 = 0.5;
+        let builder = SyntheticBuilder::new(ModelVersion::V4);
+        let info = builder.info();
+        let data = builder.clone().build()?;
+        let lora = builder.with_seed(7).build_lora(8)?;
+
+        let build = |blend: LoraBlend| {
+            ModelBuilder::new(&context, &data)
+                .with_head_chunk_size(info.num_vocab)
+                .add_lora(Lora {
+                    data: lora.clone(),
+                    blend,
+                    checksum: None,
+                })
+                .build::<v4::Model>()
+        };
+        let tokens = [vec![31u16, 4, 159, 26]];
+        let state: v4::ModelState = StateBuilder::new(&context, &info).build();
+        let expected = run(&build(LoraBlend::full(ALPHA))?, &state, &tokens)?;
+        let state: v4::ModelState = StateBuilder::new(&context, &info).build();
+        let blend = LoraBlend(vec![LoraBlendPattern::glob("blocks.*", ALPHA)?]);
+        assert_eq!(run(&build(blend)?, &state, &tokens)?, expected);
+
+        // a typo leaves the LoRA unapplied where it was meant to go, so it fails the build
+        let blend = LoraBlend(vec![
+            LoraBlendPattern::glob("blocks.*.att.*", ALPHA)?,
+            LoraBlendPattern::glob("blocks.*.attn.*", ALPHA)?,
+            LoraBlendPattern::new(r"head\.weight", ALPHA)?,
+        ]);
+        let error = build(blend)
+            .err()
+            .and_then(|err| err.downcast::<LoraPatternError>().ok());
+        assert_eq!(
+            error,
+            Some(LoraPatternError {
+                lora: 0,
+                patterns: vec![r"^blocks\..*\.attn\..*$".into(), r"head\.weight".into()],
+            })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_lora_quant() -> Result<()> {
+        let context = match create_context() {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+
+        const ALPHA: f32 = 0.5;
+        let tokens = [vec![31u16, 4, 159, 26]];
+        for version in [ModelVersion::V4, ModelVersion::V5] {
+            let builder = SyntheticBuilder::new(version).with_num_layer(3);
+            let info = builder.info();
+            let data = builder.clone().build()?;
+            let lora = builder.with_seed(7).build_lora(8)?;
+            let merged = merge_lora(&data, &lora, ALPHA)?;
+
+            // rescaling discounts the blended matrices, so the deltas have to be discounted along with them
+            for (quant, rescale) in [(Quant::Int8, 0), (Quant::Int8, 1), (Quant::NF4, 2)] {
+                let build = |data: &[u8], lora: Option<&[u8]>| -> Result<Vec<f32>> {
+                    let layers = (0..info.num_layer).map(|layer| (layer, quant)).collect();
+                    let mut builder = ModelBuilder::new(&context, data)
+                        .with_head_chunk_size(info.num_vocab)
+                        .with_quant(layers)
+                        .with_rescale(rescale);
+                    if let Some(lora) = lora {
+                        builder = builder.add_lora(Lora {
+                            data: lora.to_vec(),
+                            blend: LoraBlend::full(ALPHA),
+                            checksum: None,
+                        });
+                    }
+                    let logits = match version {
+                        ModelVersion::V4 => {
+                            let model: v4::Model = builder.build()?;
+                            let state: v4::ModelState = StateBuilder::new(&context, &info).build();
+                            run(&model, &state, &tokens)?
+                        }
+                        ModelVersion::V5 => {
+                            let model: v5::Model = builder.build()?;
+                            let state: v5::ModelState = StateBuilder::new(&context, &info).build();
+                            run(&model, &state, &tokens)?
+                        }
+                    };
+                    Ok(logits[0].clone().expect("batch output"))
+                };
+
+                let expected = build(&merged, None)?;
+                let logits = build(&data, Some(&lora))?;
+                let error = relative_error(&logits, &expected);
+                assert!(
+                    error < 0.01,
+                    "{version:?} {quant:?} rescale {rescale} relative error: {error}"
+                );
+            }
+        }
+        Ok(())
+    }
 }
@@ -1,10 +1,16 @@
 use half::f16;
+use web_rwkv_derive::Deref;
 use wgpu::{CommandEncoderDescriptor, ComputePassDescriptor};
 
-use crate::tensor::{
-    ops::{TensorOp, TensorPass},
-    shape::Shape,
-    ReadWrite, TensorError, TensorGpu, TensorShape, TensorView, Uniform,
+use super::Quant;
+use crate::{
+    context::Context,
+    num::Float,
+    tensor::{
+        ops::{TensorOp, TensorPass},
+        shape::Shape,
+        ReadWrite, TensorError, TensorGpu, TensorShape, TensorView, Uniform,
+    },
 };
 
 #[derive(Debug)]
@@ -17,6 +23,11 @@ pub enum Matrix {
         my: Box<TensorGpu<f32, ReadWrite>>,
         ry: Box<TensorGpu<f32, ReadWrite>>,
     },
+    Int8Asym {
+        w: Box<TensorGpu<u8, ReadWrite>>,
+        s: Box<TensorGpu<f32, ReadWrite>>,
+        z: Box<TensorGpu<f32, ReadWrite>>,
+    },
     NF4 {
         w: Box<TensorGpu<u8, ReadWrite>>,
         m: Box<TensorGpu<f16, ReadWrite>>,
@@ -25,17 +36,91 @@ pub enum Matrix {
 }
 
 impl Matrix {
-    pub fn matmul_vec_op<'a>(
+    fn context(&self) -> &Context {
+        match self {
+            Matrix::Fp16(matrix) => &matrix.context,
+            Matrix::Int8 { w, .. } => &w.context,
+            Matrix::Int8Asym { w, .. } => &w.context,
+            Matrix::NF4 { w, .. } => &w.context,
+        }
+    }
+
+    /// Copy the matrix and its quantization parameters to another context, through the host.
+    pub fn transfer(&self, to: &Context) -> Result<Self, TensorError> {
+        let matrix = match self {
+            Matrix::Fp16(matrix) => Matrix::Fp16(matrix.transfer(to)?),
+            Matrix::Int8 { w, mx, rx, my, ry } => Matrix::Int8 {
+                w: Box::new(w.transfer(to)?),
+                mx: Box::new(mx.transfer(to)?),
+                rx: Box::new(rx.transfer(to)?),
+                my: Box::new(my.transfer(to)?),
+                ry: Box::new(ry.transfer(to)?),
+            },
+            Matrix::Int8Asym { w, s, z } => Matrix::Int8Asym {
+                w: Box::new(w.transfer(to)?),
+                s: Box::new(s.transfer(to)?),
+                z: Box::new(z.transfer(to)?),
+            },
+            Matrix::NF4 { w, m, q } => Matrix::NF4 {
+                w: Box::new(w.transfer(to)?),
+                m: Box::new(m.transfer(to)?),
+                q: Box::new(q.transfer(to)?),
+            },
+        };
+        Ok(matrix)
+    }
+
+    /// Free the device memory of the matrix and of its quantization parameters now, see [`TensorGpu::destroy`].
+    pub fn destroy(self) {
+        match self {
+            Matrix::Fp16(matrix) => {
+                matrix.destroy();
+            }
+            Matrix::Int8 { w, mx, rx, my, ry } => {
+                w.destroy();
+                mx.destroy();
+                rx.destroy();
+                my.destroy();
+                ry.destroy();
+            }
+            Matrix::Int8Asym { w, s, z } => {
+                w.destroy();
+                s.destroy();
+                z.destroy();
+            }
+            Matrix::NF4 { w, m, q } => {
+                w.destroy();
+                m.destroy();
+                q.destroy();
+            }
+        }
+    }
+
+    /// The shape `[C, R]` of the matrix before quantization.
+    pub fn shape(&self) -> Shape {
+        match self {
+            Matrix::Fp16(matrix) => matrix.shape(),
+            Matrix::Int8 { w, .. } => w.shape(),
+            Matrix::Int8Asym { w, .. } => w.shape(),
+            Matrix::NF4 { w, .. } => {
+                let shape = w.shape();
+                Shape::new(shape[0] * 2, shape[1], shape[2], shape[3])
+            }
+        }
+    }
+
+    pub fn matmul_vec_op<'a, I: Float, O: Float>(
         &'a self,
         half: TensorView<'a, f16>,
-        input: TensorView<'a, f32>,
-        output: TensorView<'a, f32>,
-    ) -> Result<TensorOp<'a>, TensorError> {
+        input: TensorView<'a, I>,
+        output: TensorView<'a, O>,
+    ) -> Result<TensorOp, TensorError> {
         match self {
             Matrix::Fp16(matrix) => TensorOp::matmul_vec_fp16(matrix, input, output),
             Matrix::Int8 { w, mx, rx, my, ry } => {
                 TensorOp::matmul_vec_int8(w, mx, rx, my, ry, input, output)
             }
+            Matrix::Int8Asym { w, s, z } => TensorOp::matmul_vec_int8_asym(w, s, z, input, output),
             Matrix::NF4 { w, m, q } => Ok(TensorOp::List(vec![
                 TensorOp::quantize_fp16(input.tensor, half.tensor)?,
                 TensorOp::matmul_vec_nf4(w, m, q, half, output)?,
@@ -43,12 +128,12 @@ impl Matrix {
         }
     }
 
-    pub fn matmul_mat_op<'a>(
+    pub fn matmul_mat_op<'a, I: Float, O: Float>(
         &'a self,
         half: TensorView<'a, f16>,
-        input: TensorView<'a, f32>,
-        output: TensorView<'a, f32>,
-    ) -> Result<TensorOp<'a>, TensorError> {
+        input: TensorView<'a, I>,
+        output: TensorView<'a, O>,
+    ) -> Result<TensorOp, TensorError> {
         match self {
             Matrix::Fp16(matrix) => Ok(TensorOp::List(vec![
                 TensorOp::quantize_fp16(input.tensor, half.tensor)?,
@@ -58,6 +143,10 @@ impl Matrix {
                 TensorOp::quantize_fp16(input.tensor, half.tensor)?,
                 TensorOp::matmul_mat_int8(w.view(.., .., .., ..)?, mx, rx, my, ry, half, output)?,
             ])),
+            Matrix::Int8Asym { w, s, z } => Ok(TensorOp::List(vec![
+                TensorOp::quantize_fp16(input.tensor, half.tensor)?,
+                TensorOp::matmul_mat_int8_asym(w.view(.., .., .., ..)?, s, z, half, output)?,
+            ])),
             Matrix::NF4 { w, m, q } => Ok(TensorOp::List(vec![
                 TensorOp::quantize_fp16(input.tensor, half.tensor)?,
                 TensorOp::matmul_vec_nf4(w, m, q, half, output)?,
@@ -65,6 +154,16 @@ impl Matrix {
         }
     }
 
+    /// Quantize the matrix with the given scheme.
+    pub fn quant(matrix: TensorGpu<f16, ReadWrite>, quant: Quant) -> Result<Self, TensorError> {
+        match quant {
+            Quant::None => Ok(Matrix::Fp16(matrix)),
+            Quant::Int8 => Self::quant_u8(matrix),
+            Quant::Int8Asym => Self::quant_u8_asym(matrix),
+            Quant::NF4 => Self::quant_nf4(matrix),
+        }
+    }
+
     pub fn quant_u8(matrix: TensorGpu<f16, ReadWrite>) -> Result<Self, TensorError> {
         let context = &matrix.context;
         let shape = matrix.shape();
@@ -102,6 +201,30 @@ impl Matrix {
         Ok(Matrix::Int8 { w, mx, rx, my, ry })
     }
 
+    pub fn quant_u8_asym(matrix: TensorGpu<f16, ReadWrite>) -> Result<Self, TensorError> {
+        let context = &matrix.context;
+        let shape = matrix.shape();
+
+        let w = Box::new(context.tensor_init(matrix.shape()));
+        let s = Box::new(context.tensor_init(Shape::new(shape[1], 1, 1, 1)));
+        let z = Box::new(context.tensor_init(Shape::new(shape[1], 1, 1, 1)));
+
+        let op = TensorOp::quantize_mat_int8_asym(&matrix, &s, &z, &w)?;
+
+        let mut encoder = context
+            .device
+            .create_command_encoder(&CommandEncoderDescriptor::default());
+
+        let mut pass = encoder.begin_compute_pass(&ComputePassDescriptor::default());
+        pass.execute_tensor_op(&op);
+        drop(pass);
+
+        context.queue.submit(Some(encoder.finish()));
+        matrix.destroy();
+
+        Ok(Matrix::Int8Asym { w, s, z })
+    }
+
     pub fn quant_nf4(matrix: TensorGpu<f16, ReadWrite>) -> Result<Self, TensorError> {
         let context = &matrix.context;
         let shape = matrix.shape();
@@ -153,3 +276,324 @@ impl Matrix {
         Ok(Matrix::NF4 { w, m, q })
     }
 }
+
+impl Matrix {
+    /// Number of columns reconstructed in one pass.
+    const RECONSTRUCT_CHUNK_SIZE: usize = 256;
+
+    /// Recover the weights the matrix effectively applies, in row-major order `[R, C]`.
+    /// This multiplies the matrix with identity using the same kernels inference does.
+    pub fn reconstruct(&self) -> Result<Vec<f32>, TensorError> {
+        let context = self.context();
+        let shape = self.shape();
+        let (num_col, num_row) = (shape[0], shape[1]);
+
+        let mut data = vec![0.0; num_col * num_row];
+        for start in (0..num_col).step_by(Self::RECONSTRUCT_CHUNK_SIZE) {
+            let len = Self::RECONSTRUCT_CHUNK_SIZE.min(num_col - start);
+            let mut identity = vec![0.0; num_col * len];
+            for token in 0..len {
+                identity[token * num_col + start + token] = 1.0;
+            }
+
+            let input: TensorGpu<f32, ReadWrite> =
+                context.tensor_from_data(Shape::new(num_col, len, 1, 1), identity)?;
+            let half: TensorGpu<f16, ReadWrite> = context.tensor_init(input.shape());
+            let output: TensorGpu<f32, ReadWrite> =
+                context.tensor_init(Shape::new(num_row, len, 1, 1));
+
+            let op = self.matmul_vec_op(
+                half.view(.., .., .., ..)?,
+                input.view(.., .., .., ..)?,
+                output.view(.., .., .., ..)?,
+            )?;
+            let mut encoder = context
+                .device
+                .create_command_encoder(&CommandEncoderDescriptor::default());
+            let mut pass = encoder.begin_compute_pass(&ComputePassDescriptor::default());
+            pass.execute_tensor_op(&op);
+            drop(pass);
+            context.queue.submit(Some(encoder.finish()));
+
+            let output = output.back().to_vec();
+            for (token, column) in output.chunks_exact(num_row).enumerate() {
+                for (row, &value) in column.iter().enumerate() {
+                    data[row * num_col + start + token] = value;
+                }
+            }
+        }
+        Ok(data)
+    }
+}
+
+/// Reconstruction error of one quantized matrix.
+#[derive(Debug, Clone, PartialEq)]
+pub struct QuantizationRecord {
+    pub name: String,
+    pub layer: usize,
+    pub quant: Quant,
+    /// Mean squared error between the original and the reconstructed weights.
+    pub mse: f32,
+    /// Maximum absolute error between the original and the reconstructed weights.
+    pub max_abs: f32,
+}
+
+impl std::fmt::Display for QuantizationRecord {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}\t{:?}\tmse: {:.3e}\tmax abs: {:.3e}",
+            self.name, self.quant, self.mse, self.max_abs
+        )
+    }
+}
+
+/// Reconstruction errors of all quantized matrices of a model, in loading order.
+#[derive(Debug, Clone, Default, PartialEq, Deref)]
+pub struct QuantizationReport(pub Vec<QuantizationRecord>);
+
+impl QuantizationReport {
+    /// Quantize `matrix`, recording how well the quantized matrix reconstructs the original.
+    pub fn quant(
+        &mut self,
+        name: impl Into<String>,
+        layer: usize,
+        matrix: TensorGpu<f16, ReadWrite>,
+        quant: Quant,
+    ) -> Result<Matrix, TensorError> {
+        if quant == Quant::None {
+            return Ok(Matrix::Fp16(matrix));
+        }
+
+        let original = matrix.back().to_vec();
+        let matrix = Matrix::quant(matrix, quant)?;
+        let reconstructed = matrix.reconstruct()?;
+
+        let (sum, max_abs) = original.iter().zip(reconstructed.iter()).fold(
+            (0.0, 0.0_f32),
+            |(sum, max_abs), (x, y)| {
+                let error = x.to_f32() - y;
+                (sum + error * error, max_abs.max(error.abs()))
+            },
+        );
+        self.0.push(QuantizationRecord {
+            name: name.into(),
+            layer,
+            quant,
+            mse: sum / original.len() as f32,
+            max_abs,
+        });
+        Ok(matrix)
+    }
+
+    /// Records sorted from the largest mean squared error to the smallest.
+    pub fn worst(&self) -> Vec<&QuantizationRecord> {
+        let mut records: Vec<_> = self.0.iter().collect();
+        records.sort_by(|x, y| y.mse.total_cmp(&x.mse));
+        records
+    }
+
+    /// Mean squared error of each quantized layer, averaged over its matrices.
+    pub fn layer_mse(&self) -> Vec<(usize, f32)> {
+        let mut layers: Vec<(usize, f32, usize)> = vec![];
+        for record in &self.0 {
+            match layers
+                .iter_mut()
+                .find(|(layer, _, _)| *layer == record.layer)
+            {
+                Some((_, sum, count)) => {
+                    *sum += record.mse;
+                    *count += 1;
+                }
+                None => layers.push((record.layer, record.mse, 1)),
+            }
+        }
+        layers
+            .into_iter()
+            .map(|(layer, sum, count)| (layer, sum / count as f32))
+            .collect()
+    }
+}
+
+impl std::fmt::Display for QuantizationReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for record in &self.0 {
+            writeln!(f, "{record}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use half::f16;
+    use itertools::Itertools;
+
+    use super::Matrix;
+    use crate::{
+        model::{
+            synthetic::{
+                testing::{create_context, relative_error, run, run_with},
+                SyntheticBuilder,
+            },
+            v4, v5, ModelBuilder, ModelVersion, Quant, StateBuilder,
+        },
+        tensor::{shape::Shape, ReadWrite, TensorGpu},
+    };
+
+    #[test]
+    fn test_quant() -> Result<()> {
+        let context = match create_context() {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+
+        let builder = SyntheticBuilder::new(ModelVersion::V5);
+        let info = builder.info();
+        let data = builder.build()?;
+        let tokens = [vec![5u16, 23, 177, 2, 94]];
+
+        let model: v5::Model = ModelBuilder::new(&context, &data)
+            .with_head_chunk_size(info.num_vocab)
+            .build()?;
+        let state: v5::ModelState = StateBuilder::new(&context, &info).build();
+        let expected = run(&model, &state, &tokens)?.remove(0).unwrap();
+
+        for (quant, eps) in [
+            (Quant::Int8, 0.01),
+            (Quant::Int8Asym, 0.01),
+            (Quant::NF4, 0.3),
+        ] {
+            let layers = (0..info.num_layer).map(|layer| (layer, quant)).collect();
+            let model: v5::Model = ModelBuilder::new(&context, &data)
+                .with_head_chunk_size(info.num_vocab)
+                .with_quant(layers)
+                .build()?;
+            let state: v5::ModelState = StateBuilder::new(&context, &info).build();
+            let logits = run(&model, &state, &tokens)?.remove(0).unwrap();
+
+            let error = relative_error(&logits, &expected);
+            assert!(error < eps, "{quant:?} relative error: {error}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_quant_int8_unaligned() -> Result<()> {
+        let context = match create_context() {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+
+        // rows of a length not divisible by 16 can't be read as `vec4<u32>`, and take the other path
+        let builder = SyntheticBuilder::new(ModelVersion::V4)
+            .with_num_emb(72)
+            .with_num_hidden(264);
+        let data = builder.build()?;
+        let tokens = [vec![5u16, 23, 177, 2, 94]];
+        let expected = run_with::<v4::Model>(&context, &data, Quant::None, false, &tokens)?;
+        let logits = run_with::<v4::Model>(&context, &data, Quant::Int8, false, &tokens)?;
+        let error = relative_error(&logits, &expected);
+        assert!(error < 0.01, "relative error: {error}");
+        Ok(())
+    }
+
+    #[test]
+    fn test_quant_int8_asym() -> Result<()> {
+        let context = match create_context() {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+
+        // skewed rows, where a symmetric range would waste half of the levels
+        let (num_col, num_row) = (256, 64);
+        let data = (0..num_row)
+            .flat_map(|row| {
+                (0..num_col).map(move |col| {
+                    let x = ((row * 131 + col * 17) % 97) as f32 / 97.0;
+                    f16::from_f32(0.5 + x * (1.0 + row as f32 / 8.0))
+                })
+            })
+            .collect_vec();
+        let matrix: TensorGpu<f16, ReadWrite> =
+            context.tensor_from_data(Shape::new(num_col, num_row, 1, 1), data.clone())?;
+        let matrix = Matrix::quant(matrix, Quant::Int8Asym)?;
+        let output = matrix.reconstruct()?;
+
+        for (row, (expected, output)) in data
+            .chunks_exact(num_col)
+            .zip_eq(output.chunks_exact(num_col))
+            .enumerate()
+        {
+            let expected = expected.iter().map(|x| x.to_f32()).collect_vec();
+            let max = expected.iter().fold(0.0f32, |acc, x| acc.max(*x));
+            // half a quantization step, plus some room for the fp16 input
+            let eps = max / 255.0 / 2.0 + max * 1e-3;
+            for (a, b) in expected.iter().zip_eq(output.iter()) {
+                assert!((a - b).abs() <= eps, "row {row}: {a} vs {b}");
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_quant_int8_asym_turbo() -> Result<()> {
+        let context = match create_context() {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+
+        // runs of 4 tokens take the matrix-matrix path, the rest the matrix-vector one
+        let data = SyntheticBuilder::new(ModelVersion::V5).build()?;
+        let tokens = [vec![5u16, 23, 177, 2, 94, 31]];
+        let expected = run_with::<v5::Model>(&context, &data, Quant::Int8Asym, false, &tokens)?;
+        let logits = run_with::<v5::Model>(&context, &data, Quant::Int8Asym, true, &tokens)?;
+        let error = relative_error(&logits, &expected);
+        assert!(error < 0.01, "relative error: {error}");
+        Ok(())
+    }
+
+    #[test]
+    fn test_quant_report() -> Result<()> {
+        let context = match create_context() {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+
+        let builder = SyntheticBuilder::new(ModelVersion::V4).with_num_layer(3);
+        let info = builder.info();
+        let data = builder.build()?;
+
+        let model: v4::Model = ModelBuilder::new(&context, &data)
+            .with_head_chunk_size(info.num_vocab)
+            .with_quant([(0, Quant::Int8), (1, Quant::NF4)].into())
+            .build()?;
+        assert!(model.quant_report().is_none());
+
+        let model: v4::Model = ModelBuilder::new(&context, &data)
+            .with_head_chunk_size(info.num_vocab)
+            .with_quant([(0, Quant::Int8), (1, Quant::NF4)].into())
+            .with_quant_report(true)
+            .build()?;
+        let report = model.quant_report().expect("quantization report");
+
+        // 7 matrices in each of the 2 quantized layers
+        assert_eq!(report.len(), 14);
+        for record in report.iter() {
+            let quant = [Quant::Int8, Quant::NF4][record.layer];
+            assert_eq!(record.quant, quant);
+            assert!(record
+                .name
+                .starts_with(&format!("blocks.{}.", record.layer)));
+            assert!(record.mse > 0.0);
+            assert!(record.max_abs >= record.mse.sqrt());
+        }
+
+        let layers = report.layer_mse();
+        assert_eq!(layers.iter().map(|(layer, _)| *layer).collect_vec(), [0, 1]);
+        assert!(layers[0].1 < layers[1].1);
+        assert_eq!(report.worst()[0].quant, Quant::NF4);
+        Ok(())
+    }
+}
@@ -1,18 +1,52 @@
-use std::{collections::HashMap, convert::Infallible};
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    io::{Read, Seek, SeekFrom},
+    ops::Range,
+    str::FromStr,
+    sync::{atomic::AtomicBool, Arc},
+};
 
 use anyhow::Result;
+use half::f16;
+use itertools::Itertools;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use web_rwkv_derive::{Deref, DerefMut};
+use wgpu::Limits;
 
-use crate::{context::Context, tensor::TensorError};
+use self::{
+    loader::{Loader, Reader},
+    slot::Slots,
+};
+pub use crate::num::Precision;
+use crate::{
+    context::Context,
+    tensor::{
+        ops::TensorOp, shape::Shape, ReadWrite, TensorCpu, TensorError, TensorGpu, TensorView,
+    },
+};
 
+pub mod cache;
+pub mod graph;
+pub mod history;
+pub mod host;
 pub mod loader;
 pub mod matrix;
+#[cfg(any(test, feature = "tools"))]
+pub mod reference;
+pub mod slot;
+#[cfg(any(test, feature = "tools"))]
+pub mod synthetic;
 pub mod v4;
 pub mod v5;
+pub mod window;
 
 pub const RESCALE_LAYER: usize = 6;
+/// Fewest tokens in a run for turbo to multiply them as matrices, unless the token chunk is smaller;
+/// shorter runs are faster with the vector kernels.
+pub const TURBO_MIN_TOKEN: usize = 32;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum ModelVersion {
@@ -24,7 +58,51 @@ pub enum ModelVersion {
 pub enum ModelError {
     InvalidChunkSize(usize),
     BatchSize(usize, usize),
-    BatchOutOfRange { batch: usize, max: usize },
+    BatchOutOfRange {
+        batch: usize,
+        max: usize,
+    },
+    /// A checksum string that is not 64 hex digits.
+    InvalidChecksum,
+    /// The model file, or the LoRA at index `lora`, doesn't hash to the expected digest.
+    ChecksumMismatch {
+        lora: Option<usize>,
+        expected: Checksum,
+        actual: Checksum,
+    },
+    /// The save point buffer has no free batch among its `0`.
+    SavePointsFull(usize),
+    /// Batch `batch` can only roll back `max` tokens, but `tokens` were requested.
+    RollbackOutOfRange {
+        batch: usize,
+        tokens: usize,
+        max: usize,
+    },
+    /// Layers `start..end` are empty, not within the `max` layers of the model, or all run on the host.
+    LayerRange {
+        start: usize,
+        end: usize,
+        max: usize,
+    },
+    /// No tokens were given where at least one is needed.
+    EmptyTokens,
+    /// Token `token` is not within the `max` tokens of the vocabulary.
+    TokenOutOfRange {
+        token: u16,
+        max: usize,
+    },
+    /// `tokens` tokens can't be run in a single pass of at most `max` tokens.
+    ChunkOverflow {
+        tokens: usize,
+        max: usize,
+    },
+    /// Building was cancelled through [`ModelBuilder::with_cancel`].
+    Cancelled,
+    /// The device ran out of memory while building a model or state, after it had allocated `allocated` bytes
+    /// of buffers, the failed ones included. Callers may retry with more layers quantized or fewer batches.
+    OutOfMemory {
+        allocated: u64,
+    },
 }
 
 impl std::fmt::Display for ModelError {
@@ -35,12 +113,92 @@ impl std::fmt::Display for ModelError {
             ModelError::BatchOutOfRange { batch, max } => {
                 write!(f, "batch {batch} out of range of max {max}")
             }
+            ModelError::InvalidChecksum => write!(f, "checksum is not 64 hex digits"),
+            ModelError::ChecksumMismatch {
+                lora,
+                expected,
+                actual,
+            } => match lora {
+                Some(index) => write!(f, "lora {index} checksum {actual} not match {expected}"),
+                None => write!(f, "model checksum {actual} not match {expected}"),
+            },
+            ModelError::SavePointsFull(max) => write!(f, "all {max} save points are taken"),
+            ModelError::RollbackOutOfRange { batch, tokens, max } => {
+                write!(
+                    f,
+                    "cannot roll back {tokens} tokens of batch {batch}, only {max} kept"
+                )
+            }
+            ModelError::LayerRange { start, end, max } => {
+                write!(f, "layers {start}..{end} not within {max} layers")
+            }
+            ModelError::EmptyTokens => write!(f, "no tokens given"),
+            ModelError::TokenOutOfRange { token, max } => {
+                write!(f, "token {token} out of range of vocabulary size {max}")
+            }
+            ModelError::ChunkOverflow { tokens, max } => {
+                write!(f, "cannot run {tokens} tokens in one pass of at most {max}")
+            }
+            ModelError::Cancelled => write!(f, "model building cancelled"),
+            ModelError::OutOfMemory { allocated } => {
+                write!(f, "out of device memory after allocating {allocated} bytes")
+            }
         }
     }
 }
 
 impl std::error::Error for ModelError {}
 
+/// The blend patterns of the LoRA at index `lora` that match none of the tensors it has for the model,
+/// which would otherwise leave it silently unapplied.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct LoraPatternError {
+    pub lora: usize,
+    pub patterns: Vec<String>,
+}
+
+impl std::fmt::Display for LoraPatternError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Self { lora, patterns } = self;
+        write!(f, "patterns of lora {lora} match no tensor: ")?;
+        write!(f, "{}", patterns.join(", "))
+    }
+}
+
+impl std::error::Error for LoraPatternError {}
+
+/// A SHA-256 digest of a model or LoRA file, written as 64 hex digits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Checksum(pub [u8; 32]);
+
+impl Checksum {
+    pub fn sha256(data: &[u8]) -> Self {
+        Self(Sha256::digest(data).into())
+    }
+}
+
+impl std::fmt::Display for Checksum {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.iter().try_for_each(|x| write!(f, "{x:02x}"))
+    }
+}
+
+impl FromStr for Checksum {
+    type Err = ModelError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.len() != 64 || !s.is_ascii() {
+            return Err(ModelError::InvalidChecksum);
+        }
+        let mut digest = [0; 32];
+        for (x, hex) in digest.iter_mut().zip(s.as_bytes().chunks(2)) {
+            let hex = std::str::from_utf8(hex).map_err(|_| ModelError::InvalidChecksum)?;
+            *x = u8::from_str_radix(hex, 16).map_err(|_| ModelError::InvalidChecksum)?;
+        }
+        Ok(Self(digest))
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct ModelInfo {
     pub version: ModelVersion,
@@ -51,6 +209,29 @@ pub struct ModelInfo {
     pub num_head: usize,
 }
 
+impl ModelInfo {
+    /// Device limits a model of this shape needs: its largest matrix, stored in f16, has to fit in one storage binding,
+    /// and the kernels need as many bindings and as large workgroups as the largest of them.
+    /// The head and the runtime buffers are chunked to fit whatever binding size the device has.
+    /// Pass it to [`ContextBuilder::with_required_limits`](crate::context::ContextBuilder::with_required_limits)
+    /// to request them; limits the model needs less of than the defaults there are left as they are.
+    pub fn limits(&self) -> Limits {
+        let matrix = self.num_emb * self.num_emb.max(self.num_hidden) * std::mem::size_of::<f16>();
+        let binding = u32::try_from(matrix).unwrap_or(u32::MAX);
+        let block_size = TensorOp::BLOCK_SIZE;
+        let limits = Limits::downlevel_defaults();
+        Limits {
+            max_buffer_size: matrix as u64,
+            max_storage_buffer_binding_size: binding,
+            max_storage_buffers_per_shader_stage: 8,
+            max_compute_invocations_per_workgroup: block_size,
+            max_compute_workgroup_size_x: block_size,
+            max_compute_workgroup_storage_size: 16384,
+            ..limits
+        }
+    }
+}
+
 pub trait FromBuilder: Sized {
     type Builder<'a>;
     type Error;
@@ -64,10 +245,15 @@ pub trait BackedState {
 
     /// Extract the embedding from a given layer of the state.
     fn embed(&self, batch: usize, layer: usize) -> Vec<f32>;
+
+    /// The data of the state as tensors with their shapes, e.g. to store it on disk.
+    fn tensors(&self) -> Vec<(Shape, &[f32])>;
+    /// Replace the data of the state with `tensors`, of the same shapes as those [`BackedState::tensors`] gives.
+    fn load_tensors(&mut self, tensors: Vec<Vec<f32>>) -> Result<(), TensorError>;
 }
 
 pub trait ModelState {
-    type BackedState: BackedState;
+    type BackedState: BackedState + Clone;
 
     fn context(&self) -> &Context;
     fn max_batch(&self) -> usize;
@@ -82,6 +268,11 @@ pub trait ModelState {
     fn back_batch(&self, batch: usize) -> Result<Self::BackedState>;
     /// Copy one device state to another. Their shapes must match.
     fn blit(&self, other: &Self) -> Result<(), TensorError>;
+    /// Allocate a state of `num_batch` batches and copy over, on device, the batches that still fit.
+    /// Added batches start fresh; the original state is left untouched.
+    fn resize(&self, num_batch: usize) -> Result<Self>
+    where
+        Self: Sized;
     /// Copy one batch from the source state to another.
     fn blit_batch(
         &self,
@@ -97,9 +288,21 @@ pub trait Model {
     fn context(&self) -> &Context;
     fn info(&self) -> &ModelInfo;
 
+    /// Look up the embeddings of `tokens` as a `[C, T, 1]` tensor, without running the model.
+    /// If `layer_norm` is set, the embeddings are normalized by the input layer norm, as the first layer sees them.
+    fn embed_tokens(&self, tokens: &[u16], layer_norm: bool) -> Result<TensorGpu<f32, ReadWrite>>;
+
     /// Softmax of the input tensors.
     fn softmax(&self, input: Vec<Option<Vec<f32>>>) -> Result<Vec<Option<Vec<f32>>>>;
 
+    /// Softmax of the input tensors, each divided by the temperature of its slot in the same pass.
+    /// The length of `temperature` must match that of `input`.
+    fn softmax_with_temperature(
+        &self,
+        input: Vec<Option<Vec<f32>>>,
+        temperature: &[f32],
+    ) -> Result<Vec<Option<Vec<f32>>>>;
+
     /// Run the model for a batch of tokens as input.
     /// The length of `tokens` must match the number of batches in `state`.
     /// `tokens` may have slots with no tokens, for which `run` won't compute that batch and will return an empty vector in that corresponding slot.
@@ -108,6 +311,247 @@ pub trait Model {
         tokens: &mut Vec<Vec<u16>>,
         state: &Self::ModelState,
     ) -> Result<Vec<Option<Vec<f32>>>>;
+
+    /// Like [`Model::run`], but only through `layers`, feeding the output of the last of them straight to the head.
+    /// Layers outside the range are skipped, and their part of `state` is left as is.
+    /// This gives a cheaper, rougher prediction from the same weights, e.g. to draft tokens.
+    fn run_layers(
+        &self,
+        tokens: &mut Vec<Vec<u16>>,
+        state: &Self::ModelState,
+        layers: Range<usize>,
+    ) -> Result<Vec<Option<Vec<f32>>>>;
+
+    /// Like [`Model::run`], but returns the logits after every token consumed from each batch in this call,
+    /// including those of batches with tokens left.
+    fn run_full(
+        &self,
+        tokens: &mut Vec<Vec<u16>>,
+        state: &Self::ModelState,
+    ) -> Result<Vec<Vec<Vec<f32>>>>;
+
+    /// Run `tokens` on `batch` in one pass, and check on the GPU whether each of them after the first
+    /// is the greedy choice of the model after those before it.
+    /// Returns the number of tokens accepted in a row after the first, and the greedy choice after them.
+    /// All of `tokens` go into `state`, so it has to be rolled back if any of them is rejected.
+    /// There must be at least one token, and no more than the token chunk size.
+    fn verify(
+        &self,
+        tokens: &[u16],
+        batch: usize,
+        state: &Self::ModelState,
+    ) -> Result<(usize, u16)>;
+
+    /// Run `tokens` on `batch` through the full model, a chunk at a time, and keep the logits after each of them
+    /// on the GPU, writing them into `output` from position `offset` on.
+    /// - `output` shape: `[num_vocab, T, 1]`, with at least `offset + tokens.len()` positions.
+    fn prefill(
+        &self,
+        tokens: &[u16],
+        batch: usize,
+        state: &Self::ModelState,
+        output: &TensorGpu<f32, ReadWrite>,
+        offset: usize,
+    ) -> Result<()>;
+
+    /// Run `embeds` on `batch` in place of tokens, a chunk at a time, e.g. a learned soft prompt before a tokenized one.
+    /// The embeddings are those the embedding matrix would give, before the input layer norm.
+    /// - `embeds` shape: `[C, T, 1]`, with at least one embedding.
+    fn run_embeds(
+        &self,
+        embeds: &TensorCpu<f32>,
+        batch: usize,
+        state: &Self::ModelState,
+    ) -> Result<()>;
+
+    /// Run `tokens` on `batch` through the full model, and project the hidden state of the last token after each layer
+    /// through the final layer norm and the head, as if the layers after it were skipped (the "logit lens").
+    /// Returns the logits of every layer; those of the last are the ones [`Model::run`] gives.
+    fn logit_lens(
+        &self,
+        tokens: &[u16],
+        batch: usize,
+        state: &Self::ModelState,
+    ) -> Result<Vec<Vec<f32>>>;
+
+    /// Score each of `options` as a continuation of `prompt`, e.g. to pick an answer of a multiple-choice question.
+    /// The prompt is run once, and its batch forked into a batch per option with [`Slots::fork`],
+    /// so all options are then run side by side.
+    /// Returns the log-likelihood of each option normalized over all of them, i.e., their probabilities sum to 1.
+    /// `state` needs a batch per option, and its batches are overwritten.
+    fn choose(
+        &self,
+        prompt: &[u16],
+        options: &[Vec<u16>],
+        state: &Self::ModelState,
+    ) -> Result<Vec<f32>> {
+        let max_batch = state.max_batch();
+        if prompt.is_empty() {
+            return Err(ModelError::EmptyTokens.into());
+        }
+        if options.len() > max_batch {
+            return Err(ModelError::BatchSize(options.len(), max_batch).into());
+        }
+        if options.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let mut slots = Slots::new(max_batch);
+        let root = slots.allocate(0).expect("state has a batch");
+        let mut tokens = slots.tokens([(root, prompt.to_vec())]);
+        let mut logits = vec![];
+        while !tokens[root.batch()].is_empty() {
+            if let Some(output) = self.run(&mut tokens, state)?.swap_remove(root.batch()) {
+                logits = output;
+            }
+        }
+
+        let mut keys = vec![root];
+        for index in 1..options.len() {
+            let key = slots.fork(state, root, index)?;
+            keys.push(key.expect("a free batch for each option"));
+        }
+
+        // the first token of each option is scored by the prompt, the rest by running all but the last
+        let mut scores = options
+            .iter()
+            .map(|option| {
+                option
+                    .first()
+                    .map_or(0.0, |&token| log_softmax(&logits, token))
+            })
+            .collect_vec();
+        let mut positions = vec![1; options.len()];
+        let mut tokens = slots.tokens(keys.iter().zip(options).map(|(&key, option)| {
+            let len = option.len().saturating_sub(1);
+            (key, option[..len].to_vec())
+        }));
+        while tokens.iter().any(|tokens| !tokens.is_empty()) {
+            let outputs = self.run_full(&mut tokens, state)?;
+            for (index, (key, option)) in keys.iter().zip(options).enumerate() {
+                for logits in &outputs[key.batch()] {
+                    scores[index] += log_softmax(logits, option[positions[index]]);
+                    positions[index] += 1;
+                }
+            }
+        }
+
+        let max = scores.iter().fold(f32::NEG_INFINITY, |a, &b| a.max(b));
+        let sum: f32 = scores.iter().map(|score| (score - max).exp()).sum();
+        let norm = max + sum.ln();
+        Ok(scores.into_iter().map(|score| score - norm).collect())
+    }
+
+    /// Run `tokens` on `batch` through the full model, and pool the normalized hidden states after all of them
+    /// into one embedding on the GPU, scaled to unit length if `normalize` is set.
+    fn embed_sequence(
+        &self,
+        tokens: &[u16],
+        batch: usize,
+        state: &Self::ModelState,
+        pooling: Pooling,
+        normalize: bool,
+    ) -> Result<Vec<f32>>;
+
+    /// Compile the pipelines that runs of up to `max_batch` batches and `max_tokens` tokens at a time use,
+    /// and allocate the runtime buffers of the largest of them, so that the first request doesn't wait for it.
+    /// Runs are only recorded against a scratch state, not submitted; the softmax of `max_batch` batches is run once.
+    fn warmup(&self, max_batch: usize, max_tokens: usize) -> Result<()>;
+}
+
+/// Log-softmax of `logits`, picked at `token`.
+fn log_softmax(logits: &[f32], token: u16) -> f32 {
+    let max = logits.iter().fold(f32::NEG_INFINITY, |a, &b| a.max(b));
+    let sum: f32 = logits.iter().map(|x| (x - max).exp()).sum();
+    logits[token as usize] - max - sum.ln()
+}
+
+/// What a run does with the tokens it outputs.
+enum RunOutput<'a> {
+    /// Logits of the head, read back to the host.
+    Logits,
+    /// Hidden states after the final layer norm, written into the view instead of going through the head.
+    Hidden(TensorView<'a, f32>),
+    /// Logits of the hidden states after each layer run, as if the layers after it were skipped.
+    /// - Shape: `[num_vocab, num_header, num_layer]`, with the logits of layer `i` in batch `i`.
+    Lens(&'a TensorGpu<f32, ReadWrite>),
+}
+
+/// An op in a layer that can be replaced with a custom one, see `Model::with_op_override`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum OpKind {
+    /// Projections of the token-shifted input into the keys, values and receptance (and gate, in v5) of attention.
+    AttMatmul,
+    /// The recurrent part of attention, reading and updating the attention state.
+    TimeMix,
+    /// Projections of the token-shifted input into the keys, values and receptance of the FFN.
+    FfnMatmul,
+    /// The gated output of the FFN, reading and updating the FFN state.
+    ChannelMix,
+}
+
+/// Statistics of the hidden states output by a layer, over all tokens run since they were last reset.
+/// Values that aren't finite, e.g. from an `f16` overflow, are left out of all but `non_finite`.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ActivationStats {
+    pub min: f32,
+    pub max: f32,
+    pub mean: f32,
+    pub abs_max: f32,
+    /// Number of finite values seen.
+    pub count: usize,
+    /// Number of infinities and NaNs seen.
+    pub non_finite: usize,
+}
+
+impl ActivationStats {
+    /// Read the statistics of each layer out of the buffer [`TensorOp::stats`](crate::tensor::ops::TensorOp::stats) folds into.
+    fn from_raw(raw: &[u32]) -> Vec<Self> {
+        raw.chunks_exact(8)
+            .map(|raw| {
+                let min = f32::from_bits(raw[0]);
+                let max = f32::from_bits(raw[1]);
+                let sum = f32::from_bits(raw[2]);
+                let count = raw[3] as usize;
+                Self {
+                    min,
+                    max,
+                    mean: sum / count.max(1) as f32,
+                    abs_max: min.abs().max(max.abs()),
+                    count,
+                    non_finite: raw[4] as usize,
+                }
+            })
+            .collect()
+    }
+}
+
+/// How the hidden states of the tokens of a sequence are pooled into one embedding.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Pooling {
+    /// The hidden state of the last token, which has seen all the others.
+    #[default]
+    Last,
+    /// The mean of the hidden states of all tokens.
+    Mean,
+    /// The mean of the hidden states of all tokens, weighted by their positions `1, 2, ..., T`.
+    WeightedMean,
+}
+
+impl Pooling {
+    /// Weight of each of `num_token` tokens in the pooled embedding. They sum to 1.
+    pub fn weights(&self, num_token: usize) -> Vec<f32> {
+        match self {
+            Pooling::Last => (0..num_token)
+                .map(|index| if index + 1 == num_token { 1.0 } else { 0.0 })
+                .collect(),
+            Pooling::Mean => vec![1.0 / num_token as f32; num_token],
+            Pooling::WeightedMean => {
+                let sum = (num_token * (num_token + 1) / 2) as f32;
+                (1..=num_token).map(|index| index as f32 / sum).collect()
+            }
+        }
+    }
 }
 
 #[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
@@ -117,6 +561,8 @@ pub enum Quant {
     None,
     /// Use `Int8` quantization.
     Int8,
+    /// Use asymmetric `Int8` quantization with a per-row scale and zero point.
+    Int8Asym,
     /// Use `NF4` quantization.
     NF4,
 }
@@ -125,6 +571,8 @@ pub enum Quant {
 pub struct Lora {
     pub data: Vec<u8>,
     pub blend: LoraBlend,
+    /// If set, the LoRA is checked against this digest before it is loaded.
+    pub checksum: Option<Checksum>,
 }
 
 #[derive(Debug, Clone, Deref, DerefMut)]
@@ -136,6 +584,20 @@ impl LoraBlend {
             .expect("default blend pattern");
         Self(vec![pattern])
     }
+
+    /// Scale the matrices matching any of the patterns this way; see [`LoraBlendPattern::with_scale`].
+    pub fn with_scale(self, scale: LoraScale) -> Self {
+        let patterns = self.0.into_iter().map(|x| x.with_scale(scale)).collect();
+        Self(patterns)
+    }
+
+    /// The patterns matching none of `names`.
+    pub fn unmatched(&self, names: &[&str]) -> Vec<&str> {
+        self.iter()
+            .filter(|blend| !names.iter().any(|name| blend.pattern.is_match(name)))
+            .map(|blend| blend.pattern())
+            .collect()
+    }
 }
 
 impl Default for LoraBlend {
@@ -150,43 +612,272 @@ pub struct LoraBlendPattern {
     pattern: Regex,
     /// The blend factor.
     alpha: f32,
+    /// How the product of the two matrices of the LoRA is scaled before it is blended in.
+    scale: LoraScale,
 }
 
 impl LoraBlendPattern {
+    /// Blend tensors with names matching the regex `pattern` anywhere in them.
     #[inline]
     pub fn new(pattern: &str, alpha: f32) -> Result<Self> {
         Ok(Self {
             pattern: Regex::new(pattern)?,
             alpha,
+            scale: LoraScale::default(),
         })
     }
 
+    /// Blend tensors with whole names matching `glob`, in which `*` stands for any run of characters
+    /// and `?` for any one, e.g. `blocks.*.att.*`.
+    pub fn glob(glob: &str, alpha: f32) -> Result<Self> {
+        let pattern = glob
+            .split('*')
+            .map(|part| part.split('?').map(regex::escape).join("."))
+            .join(".*");
+        Self::new(&format!("^{pattern}$"), alpha)
+    }
+
+    /// The regex the pattern matches names with.
+    #[inline]
+    pub fn pattern(&self) -> &str {
+        self.pattern.as_str()
+    }
+
+    /// Scale the matrices matching the pattern this way, instead of as the metadata of the LoRA says.
+    pub fn with_scale(self, scale: LoraScale) -> Self {
+        Self { scale, ..self }
+    }
+
     #[inline]
     pub fn alpha(&self) -> f32 {
         self.alpha
     }
+
+    #[inline]
+    pub fn scale(&self) -> LoraScale {
+        self.scale
+    }
+}
+
+/// How the product `B·A` of the two matrices of a LoRA of rank `r` is scaled before it is blended in,
+/// on top of the blend factor of its pattern.
+///
+/// Adapters are trained with a `lora_alpha`, and expect to be applied scaled by `lora_alpha / r`.
+/// It can be stored in the metadata of the LoRA file under the keys PEFT uses: `lora_alpha` for all matrices,
+/// and `alpha_pattern`, a JSON object of regex patterns matching tensor names to the `lora_alpha` of those matrices.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum LoraScale {
+    /// `lora_alpha / r` with `lora_alpha` from the metadata of the LoRA if there, `1 / r` otherwise.
+    #[default]
+    Auto,
+    /// `1 / r`, whatever the metadata says.
+    Rank,
+    /// `lora_alpha / r` with this `lora_alpha`.
+    Alpha(f32),
+    /// This factor, whatever the rank.
+    Fixed(f32),
+}
+
+impl LoraScale {
+    /// The factor for a matrix of `rank`, given the `lora_alpha` of it in the metadata, if any.
+    pub fn factor(&self, rank: usize, metadata: Option<f32>) -> f32 {
+        match *self {
+            LoraScale::Auto => metadata.unwrap_or(1.0) / rank as f32,
+            LoraScale::Rank => 1.0 / rank as f32,
+            LoraScale::Alpha(alpha) => alpha / rank as f32,
+            LoraScale::Fixed(factor) => factor,
+        }
+    }
+}
+
+/// Where a [`ModelBuilder`] reads the model from.
+enum ModelSource<'a> {
+    Bytes(Box<dyn AsRef<[u8]> + 'a>),
+    Reader(Box<dyn Reader + 'a>),
+}
+
+impl ModelSource<'_> {
+    /// Hash the whole source. A stream is read through once and rewound.
+    fn checksum(&mut self) -> Result<Checksum> {
+        match self {
+            ModelSource::Bytes(data) => Ok(Checksum::sha256((**data).as_ref())),
+            ModelSource::Reader(reader) => {
+                let mut hasher = Sha256::new();
+                let mut buffer = vec![0; 1 << 20];
+                reader.seek(SeekFrom::Start(0))?;
+                loop {
+                    match reader.read(&mut buffer)? {
+                        0 => break,
+                        len => hasher.update(&buffer[..len]),
+                    }
+                }
+                reader.seek(SeekFrom::Start(0))?;
+                Ok(Checksum(hasher.finalize().into()))
+            }
+        }
+    }
+
+    /// Verify the source and the LoRAs against their expected checksums, then create a loader.
+    fn loader(
+        &mut self,
+        context: &Context,
+        lora: Vec<Lora>,
+        checksum: Option<Checksum>,
+    ) -> Result<Loader<'_>> {
+        if let Some(expected) = checksum {
+            let actual = self.checksum()?;
+            if actual != expected {
+                return Err(ModelError::ChecksumMismatch {
+                    lora: None,
+                    expected,
+                    actual,
+                }
+                .into());
+            }
+        }
+        for (index, lora) in lora.iter().enumerate() {
+            if let Some(expected) = lora.checksum {
+                let actual = Checksum::sha256(&lora.data);
+                if actual != expected {
+                    return Err(ModelError::ChecksumMismatch {
+                        lora: Some(index),
+                        expected,
+                        actual,
+                    }
+                    .into());
+                }
+            }
+        }
+
+        match self {
+            ModelSource::Bytes(data) => Loader::new(context, (**data).as_ref(), lora),
+            ModelSource::Reader(reader) => Loader::from_reader(context, reader.as_mut(), lora),
+        }
+    }
+}
+
+/// Largest token chunk [`ChunkSize::Auto`] picks; beyond it, runs get longer without getting much faster.
+pub const MAX_TOKEN_CHUNK_SIZE: usize = 256;
+
+/// How many tokens a model runs at once, or how many rows of the head are stored in one buffer.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ChunkSize {
+    /// Derived from the limits of the device and the shape of the model when it is built.
+    #[default]
+    Auto,
+    /// This many, which must be a power of 2.
+    Fixed(usize),
+}
+
+impl ChunkSize {
+    pub fn auto() -> Self {
+        Self::Auto
+    }
+
+    /// The largest power of 2 whose chunk of the head fits in a storage binding,
+    /// but no larger than it takes to cover the vocabulary in one chunk.
+    /// The last chunk of the head is shorter if the vocabulary is not a multiple of it.
+    pub(crate) fn head(self, limits: &Limits, info: &ModelInfo) -> usize {
+        match self {
+            ChunkSize::Fixed(value) => value,
+            ChunkSize::Auto => {
+                let row = info.num_emb * std::mem::size_of::<f16>();
+                let fit = limits.max_storage_buffer_binding_size as usize / row.max(1);
+                let vocab = info.num_vocab.next_power_of_two();
+                prev_power_of_two(fit).min(vocab).max(1)
+            }
+        }
+    }
+
+    /// The largest power of 2 up to [`MAX_TOKEN_CHUNK_SIZE`] whose runtime buffers, stored in f32 at most,
+    /// each fit in a storage binding.
+    pub(crate) fn token(self, limits: &Limits, info: &ModelInfo) -> usize {
+        match self {
+            ChunkSize::Fixed(value) => value,
+            ChunkSize::Auto => {
+                let row = info.num_emb.max(info.num_hidden) * std::mem::size_of::<f32>();
+                let fit = limits.max_storage_buffer_binding_size as usize / row.max(1);
+                prev_power_of_two(fit).clamp(1, MAX_TOKEN_CHUNK_SIZE)
+            }
+        }
+    }
+}
+
+impl From<usize> for ChunkSize {
+    fn from(value: usize) -> Self {
+        Self::Fixed(value)
+    }
+}
+
+/// The largest power of 2 no greater than `value`, or 0 if it is 0.
+fn prev_power_of_two(value: usize) -> usize {
+    match value {
+        0 => 0,
+        value => 1 << (usize::BITS - 1 - value.leading_zeros()),
+    }
+}
+
+/// Progress of [`ModelBuilder::build`], reported after each layer is loaded and quantized.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BuildProgress {
+    /// Layers on the device so far.
+    pub layer: usize,
+    pub num_layer: usize,
 }
 
 pub struct ModelBuilder<'a> {
     context: Context,
-    data: &'a [u8],
+    source: ModelSource<'a>,
+    checksum: Option<Checksum>,
     lora: Vec<Lora>,
     quant: HashMap<usize, Quant>,
+    quant_report: bool,
     turbo: bool,
-    head_chunk_size: usize,
-    token_chunk_size: usize,
+    rescale: Option<usize>,
+    head_chunk_size: ChunkSize,
+    token_chunk_size: ChunkSize,
+    capture: bool,
+    host_layers: usize,
+    envelope: Option<(usize, usize)>,
+    progress: Option<Box<dyn FnMut(BuildProgress) + 'a>>,
+    cancel: Option<Arc<AtomicBool>>,
 }
 
 impl<'a> ModelBuilder<'a> {
     pub fn new(context: &Context, data: &'a [u8]) -> Self {
+        Self::new_owned(context, data)
+    }
+
+    /// Build from data the builder takes ownership of, such as a memory-mapped file.
+    /// Tensors are uploaded one by one, and the data is dropped as soon as the last one is read,
+    /// so the mapping is released before the model is returned.
+    pub fn new_owned(context: &Context, data: impl AsRef<[u8]> + 'a) -> Self {
+        Self::with_source(context, ModelSource::Bytes(Box::new(data)))
+    }
+
+    /// Build by reading tensors one at a time from a stream, without memory-mapping the file.
+    /// This is slower, but works where mapping a large file isn't possible, e.g. on the web.
+    pub fn from_reader(context: &Context, reader: impl Reader + 'a) -> Self {
+        Self::with_source(context, ModelSource::Reader(Box::new(reader)))
+    }
+
+    fn with_source(context: &Context, source: ModelSource<'a>) -> Self {
         Self {
             context: context.clone(),
-            data,
+            source,
+            checksum: None,
             lora: vec![],
             quant: Default::default(),
+            quant_report: false,
             turbo: false,
-            head_chunk_size: 4096,
-            token_chunk_size: 32,
+            rescale: None,
+            head_chunk_size: ChunkSize::Auto,
+            token_chunk_size: ChunkSize::Auto,
+            capture: false,
+            host_layers: 0,
+            envelope: None,
+            progress: None,
+            cancel: None,
         }
     }
 
@@ -194,34 +885,139 @@ impl<'a> ModelBuilder<'a> {
         Self { quant, ..self }
     }
 
+    /// Measure the reconstruction error of every quantized matrix while loading.
+    /// This takes extra time, so it is off by default.
+    pub fn with_quant_report(self, quant_report: bool) -> Self {
+        Self {
+            quant_report,
+            ..self
+        }
+    }
+
+    /// Check the model file against a SHA-256 digest before loading it,
+    /// failing with [`ModelError::ChecksumMismatch`] if it was corrupted.
+    pub fn with_checksum(self, checksum: Checksum) -> Self {
+        Self {
+            checksum: Some(checksum),
+            ..self
+        }
+    }
+
     pub fn add_lora(mut self, lora: Lora) -> Self {
         self.lora.push(lora);
         self
     }
 
+    /// Multiply the tokens of a run as matrices when there are at least [`TURBO_MIN_TOKEN`] of them
+    /// (or a full token chunk, if that is smaller), which speeds up prompt ingestion a lot.
+    /// Activations are rounded to f16 on the way in.
+    /// Matmuls accumulate in f32 either way; this rounding and the storage type `F` of the model are
+    /// the only places activations lose precision, so disable turbo or use an f32 model to rule them out.
     pub fn with_turbo(self, turbo: bool) -> Self {
         Self { turbo, ..self }
     }
 
-    pub fn with_head_chunk_size(self, head_chunk_size: usize) -> Self {
+    /// Halve the activations every `every` layers to keep them within the range of f16,
+    /// compensating with pre-scaled output weights. A value of 0 disables rescaling.
+    /// If not set, rescaling happens every [`RESCALE_LAYER`] layers in turbo or NF4 mode.
+    pub fn with_rescale(self, every: usize) -> Self {
+        Self {
+            rescale: Some(every),
+            ..self
+        }
+    }
+
+    /// Store the head in chunks of this many rows, since the whole of it is usually too big for one storage buffer.
+    /// Defaults to [`ChunkSize::Auto`].
+    pub fn with_head_chunk_size(self, head_chunk_size: impl Into<ChunkSize>) -> Self {
+        Self {
+            head_chunk_size: head_chunk_size.into(),
+            ..self
+        }
+    }
+
+    /// Run at most this many tokens at once, so that a long prompt doesn't hold the device for too long.
+    /// Defaults to [`ChunkSize::Auto`].
+    pub fn with_token_chunk_size(self, token_chunk_size: impl Into<ChunkSize>) -> Self {
+        Self {
+            token_chunk_size: token_chunk_size.into(),
+            ..self
+        }
+    }
+
+    /// Record the commands of a run the first time a shape of it (state page, number of tokens, outputs and layers) is seen,
+    /// and replay them for later runs of the same shape instead of building every op again, which saves host time in decoding.
+    ///
+    /// A few recent shapes are kept, along with the runtime buffers they bind and the state pages they run on,
+    /// so dropped states are only freed once their captures are evicted.
+    /// Runs outputting hidden states are never captured.
+    pub fn with_capture(self, capture: bool) -> Self {
+        Self { capture, ..self }
+    }
+
+    /// Run the first `host_layers` layers on the host instead of the device, so that a model a little too big
+    /// for device memory even quantized still fits, at the cost of speed. At least the last layer stays on the device.
+    ///
+    /// Before each run reaching into them, the state of the page is read back, and written back once they are done.
+    /// Host layers are kept in f16 as stored; LoRAs are not blended into them, and quantization,
+    /// op overrides, activation statistics and logit lenses don't cover them.
+    pub fn with_host_layers(self, host_layers: usize) -> Self {
+        Self {
+            host_layers,
+            ..self
+        }
+    }
+
+    /// Allocate the runtime buffers of runs of up to `max_batch` batches and `max_token` tokens once at build time,
+    /// and run every shape within them in the front of those, instead of allocating buffers for each new shape.
+    /// `max_token` is usually the token chunk size; shapes outside the envelope still get buffers of their own.
+    pub fn with_envelope(self, max_batch: usize, max_token: usize) -> Self {
+        Self {
+            envelope: Some((max_batch, max_token)),
+            ..self
+        }
+    }
+
+    /// Report progress after each layer, e.g. to show how far quantizing a large model has come.
+    pub fn with_progress(self, progress: impl FnMut(BuildProgress) + 'a) -> Self {
         Self {
-            head_chunk_size,
+            progress: Some(Box::new(progress)),
             ..self
         }
     }
 
-    pub fn with_token_chunk_size(self, token_chunk_size: usize) -> Self {
+    /// Stop building before the next layer once `cancel` is set, failing with [`ModelError::Cancelled`].
+    /// The layers already uploaded are freed.
+    pub fn with_cancel(self, cancel: Arc<AtomicBool>) -> Self {
         Self {
-            token_chunk_size,
+            cancel: Some(cancel),
             ..self
         }
     }
 
+    /// Build the model, failing with [`ModelError::OutOfMemory`] if its buffers don't fit on the device.
     pub fn build<M>(self) -> Result<M>
     where
         M: Model + FromBuilder<Builder<'a> = Self, Error = anyhow::Error>,
     {
-        M::from_builder(self)
+        let context = self.context.clone();
+        catch_out_of_memory(&context, || M::from_builder(self))?
+    }
+}
+
+/// Run `f`, which allocates buffers on `context`, turning running out of device memory into [`ModelError::OutOfMemory`]
+/// and other device errors into plain errors, instead of panics.
+fn catch_out_of_memory<T>(context: &Context, f: impl FnOnce() -> T) -> Result<T> {
+    let before = context.memory_usage().total();
+    let (output, error) = context.capture_errors(f);
+    match error {
+        Some(wgpu::Error::OutOfMemory { .. }) => {
+            // the buffers of the output are still alive here, failed ones included
+            let allocated = context.memory_usage().total().saturating_sub(before);
+            Err(ModelError::OutOfMemory { allocated }.into())
+        }
+        Some(error) => Err(anyhow::Error::msg(error.to_string())),
+        None => Ok(output),
     }
 }
 
@@ -234,6 +1030,8 @@ pub struct StateBuilder {
     info: ModelInfo,
     max_batch: usize,
     chunk_size: usize,
+    page_size: Option<usize>,
+    dtype: Precision,
 }
 
 impl<'a> StateBuilder {
@@ -243,6 +1041,8 @@ impl<'a> StateBuilder {
             info: info.clone(),
             max_batch: 1,
             chunk_size: info.num_layer,
+            page_size: None,
+            dtype: Precision::F32,
         }
     }
 
@@ -260,6 +1060,22 @@ impl<'a> StateBuilder {
         }
     }
 
+    /// Split the batches into pages of at most `value` slots, each kept in its own buffers,
+    /// so that no single binding exceeds the adapter's limit when there are many slots.
+    /// A run only advances the slots of one page at a time. By default all slots share one page.
+    pub fn with_page_size(self, value: usize) -> Self {
+        Self {
+            page_size: Some(value),
+            ..self
+        }
+    }
+
+    /// Store the device state in half precision, roughly halving its memory.
+    /// Kernels still accumulate in f32. Only V5 honors this; V4 states are always f32.
+    pub fn with_dtype(self, dtype: Precision) -> Self {
+        Self { dtype, ..self }
+    }
+
     pub fn build<S>(self) -> S
     where
         S: ModelState + FromBuilder<Builder<'a> = Self, Error = Infallible>,
@@ -267,9 +1083,932 @@ impl<'a> StateBuilder {
         S::from_builder(self).expect("build model state")
     }
 
+    /// Like [`StateBuilder::build`], but fails with [`ModelError::OutOfMemory`] if the state doesn't fit on the device.
+    pub fn try_build<S>(self) -> Result<S>
+    where
+        S: ModelState + FromBuilder<Builder<'a> = Self, Error = Infallible>,
+    {
+        let context = self.context.clone();
+        catch_out_of_memory(&context, || self.build())
+    }
+
     pub fn build_backed<B: BackedState + FromBuilder<Builder<'a> = Self, Error = Infallible>>(
         self,
     ) -> B {
         B::from_builder(self).expect("build backed state")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Cursor,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+    };
+
+    use anyhow::Result;
+    use half::f16;
+    use itertools::Itertools;
+    use wgpu::PowerPreference;
+
+    use super::{
+        loader::Loader,
+        reference,
+        synthetic::{
+            testing::{create_context, is_approx_eps, relative_error, run, run_with},
+            SyntheticBuilder,
+        },
+        v4, v5, Checksum, ChunkSize, Lora, LoraBlend, Model, ModelBuilder, ModelError, ModelInfo,
+        ModelState, ModelVersion, Pooling, Precision, Quant, StateBuilder, MAX_TOKEN_CHUNK_SIZE,
+    };
+    use crate::{
+        context::{ContextBuilder, Instance},
+        score::Prefill,
+        tensor::{shape::Shape, TensorCpu},
+    };
+
+    #[test]
+    fn test_owned_data() -> Result<()> {
+        let context = match create_context() {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+
+        let builder = SyntheticBuilder::new(ModelVersion::V5);
+        let info = builder.info();
+        let data = builder.build()?;
+        let tokens = [vec![5u16, 23, 177, 2, 94]];
+
+        let model: v5::Model = ModelBuilder::new(&context, &data)
+            .with_head_chunk_size(info.num_vocab)
+            .with_rescale(0)
+            .build()?;
+        let state: v5::ModelState = StateBuilder::new(&context, &info).build();
+        let expected = run(&model, &state, &tokens)?.remove(0).unwrap();
+
+        // rescaling every layer discounts each layer's output weights on the device
+        let model: v5::Model = ModelBuilder::new_owned(&context, data.clone())
+            .with_head_chunk_size(info.num_vocab)
+            .with_rescale(1)
+            .build()?;
+        let state: v5::ModelState = StateBuilder::new(&context, &info).build();
+        let logits = run(&model, &state, &tokens)?.remove(0).unwrap();
+
+        let error = relative_error(&logits, &expected);
+        assert!(error < 0.01, "relative error: {error}");
+        Ok(())
+    }
+
+    #[test]
+    fn test_checksum() -> Result<()> {
+        let expected = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+        let checksum = Checksum::sha256(b"abc");
+        assert_eq!(checksum.to_string(), expected);
+        assert_eq!(expected.parse::<Checksum>()?, checksum);
+        assert!("ba78".parse::<Checksum>().is_err());
+        assert!(expected.replace('a', "g").parse::<Checksum>().is_err());
+
+        let context = match create_context() {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+
+        let builder = SyntheticBuilder::new(ModelVersion::V5);
+        let data = builder.clone().build()?;
+        let lora = builder.with_seed(7).build_lora(8)?;
+        let checksum = Checksum::sha256(&data);
+        let wrong = Checksum::sha256(&lora);
+
+        let _: v5::Model = ModelBuilder::new(&context, &data)
+            .with_checksum(checksum)
+            .build()?;
+        let _: v5::Model = ModelBuilder::from_reader(&context, Cursor::new(&data))
+            .with_checksum(checksum)
+            .build()?;
+
+        let error = ModelBuilder::from_reader(&context, Cursor::new(&data))
+            .with_checksum(wrong)
+            .build::<v5::Model>()
+            .err()
+            .and_then(|err| err.downcast::<ModelError>().ok());
+        assert_eq!(
+            error,
+            Some(ModelError::ChecksumMismatch {
+                lora: None,
+                expected: wrong,
+                actual: checksum,
+            })
+        );
+
+        let error = ModelBuilder::new(&context, &data)
+            .add_lora(Lora {
+                data: lora,
+                blend: LoraBlend::full(0.5),
+                checksum: Some(checksum),
+            })
+            .build::<v5::Model>()
+            .err()
+            .and_then(|err| err.downcast::<ModelError>().ok());
+        assert_eq!(
+            error,
+            Some(ModelError::ChecksumMismatch {
+                lora: Some(0),
+                expected: checksum,
+                actual: wrong,
+            })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_rescale() -> Result<()> {
+        let context = match create_context() {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+
+        let builder = SyntheticBuilder::new(ModelVersion::V5).with_num_layer(4);
+        let info = builder.info();
+        let data = builder.build()?;
+        let tokens = [vec![5u16, 23, 177, 2, 94]];
+
+        let model: v5::Model = ModelBuilder::new(&context, &data)
+            .with_head_chunk_size(info.num_vocab)
+            .with_rescale(0)
+            .build()?;
+        let state: v5::ModelState = StateBuilder::new(&context, &info).build();
+        let expected = run(&model, &state, &tokens)?.remove(0).unwrap();
+
+        // layer norms are scale-invariant, so halving the activations must not change the logits
+        for every in [1, 3] {
+            let model: v5::Model = ModelBuilder::new(&context, &data)
+                .with_head_chunk_size(info.num_vocab)
+                .with_rescale(every)
+                .build()?;
+            let state: v5::ModelState = StateBuilder::new(&context, &info).build();
+            let logits = run(&model, &state, &tokens)?.remove(0).unwrap();
+            for (a, b) in logits.iter().zip_eq(expected.iter()) {
+                assert!(is_approx_eps(*a, *b, 1e-2), "every {every}: {a} vs {b}");
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_soft_prompt() -> Result<()> {
+        let context = match create_context() {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+
+        let builder = SyntheticBuilder::new(ModelVersion::V5);
+        let info = builder.info();
+        let data = builder.build()?;
+        let reference = reference::Model::from_safetensors(&data)?;
+        let model: v5::Model = ModelBuilder::new(&context, &data)
+            .with_token_chunk_size(2)
+            .build()?;
+
+        // the embeddings of some tokens, run as a soft prompt, act as the tokens themselves
+        let prefix = [31u16, 4, 159];
+        let embeds = prefix
+            .iter()
+            .flat_map(|&token| reference.embed(token, false))
+            .collect_vec();
+        let embeds: TensorCpu<f32> =
+            context.tensor_from_data(Shape::new(info.num_emb, prefix.len(), 1, 1), embeds)?;
+
+        let state: v5::ModelState = StateBuilder::new(&context, &info).with_max_batch(2).build();
+        model.run_embeds(&embeds, 1, &state)?;
+        let logits = run(&model, &state, &[vec![], vec![26]])?.remove(1).unwrap();
+
+        let state: v5::ModelState = StateBuilder::new(&context, &info).with_max_batch(2).build();
+        let expected = run(&model, &state, &[vec![], vec![31, 4, 159, 26]])?
+            .remove(1)
+            .unwrap();
+        for (&a, &b) in logits.iter().zip_eq(expected.iter()) {
+            assert!(is_approx_eps(a, b, 1.0e-3), "logits: {a} vs {b}");
+        }
+
+        let empty: TensorCpu<f32> = context.zeros(Shape::new(info.num_emb, 0, 1, 1));
+        assert!(model.run_embeds(&empty, 0, &state).is_err());
+        Ok(())
+    }
+
+    fn check_logit_lens<M: Model>(
+        model: &M,
+        state: impl Fn() -> M::ModelState,
+        tokens: &[u16],
+    ) -> Result<()> {
+        let num_layer = model.info().num_layer;
+        let lens = model.logit_lens(tokens, 0, &state())?;
+        assert_eq!(lens.len(), num_layer);
+
+        // the lens of each layer is the prediction of the model cut off after it
+        for (layer, lens) in lens.iter().enumerate() {
+            let state = state();
+            let mut input = vec![tokens.to_vec()];
+            let mut logits = None;
+            while !input[0].is_empty() {
+                logits = model
+                    .run_layers(&mut input, &state, 0..layer + 1)?
+                    .remove(0);
+            }
+            let expected = logits.unwrap();
+            for (&a, &b) in lens.iter().zip_eq(expected.iter()) {
+                assert!(is_approx_eps(a, b, 1.0e-3), "layer {layer}: {a} vs {b}");
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_logit_lens() -> Result<()> {
+        let context = match create_context() {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+
+        let tokens = [12u16, 55, 8, 91, 200, 3];
+        for version in [ModelVersion::V4, ModelVersion::V5] {
+            let builder = SyntheticBuilder::new(version).with_num_layer(3);
+            let info = builder.info();
+            let data = builder.build()?;
+            let state = || StateBuilder::new(&context, &info);
+            match version {
+                ModelVersion::V4 => {
+                    let model: v4::Model = ModelBuilder::new(&context, &data)
+                        .with_token_chunk_size(4)
+                        .build()?;
+                    check_logit_lens(&model, || state().build(), &tokens)?
+                }
+                ModelVersion::V5 => {
+                    let model: v5::Model = ModelBuilder::new(&context, &data)
+                        .with_token_chunk_size(4)
+                        .build()?;
+                    check_logit_lens(&model, || state().build(), &tokens)?
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn check_state<M: Model>(model: &M, state: &M::ModelState) -> Result<()> {
+        let prompts = [vec![12u16, 55, 8], vec![91, 200, 7, 7]];
+        let next = [vec![64u16, 3], vec![128]];
+
+        run(model, state, &prompts)?;
+        let backed = state.back();
+        let expected = run(model, state, &next)?;
+
+        // restore the whole state and replay
+        state.load(&backed)?;
+        assert_eq!(run(model, state, &next)?, expected);
+
+        // restore the batches swapped and replay
+        state.load(&backed)?;
+        let first = state.back_batch(0)?;
+        let second = state.back_batch(1)?;
+        state.load_batch(&second, 0)?;
+        state.load_batch(&first, 1)?;
+        let mut swapped = run(model, state, &[next[1].clone(), next[0].clone()])?;
+        swapped.swap(0, 1);
+        assert_eq!(swapped, expected);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_state_v4() -> Result<()> {
+        let context = match create_context() {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+
+        let builder = SyntheticBuilder::new(ModelVersion::V4);
+        let info = builder.info();
+        let data = builder.build()?;
+
+        let model: v4::Model = ModelBuilder::new(&context, &data)
+            .with_head_chunk_size(info.num_vocab)
+            .build()?;
+        let state: v4::ModelState = StateBuilder::new(&context, &info).with_max_batch(2).build();
+        check_state(&model, &state)
+    }
+
+    #[test]
+    fn test_state_v5() -> Result<()> {
+        let context = match create_context() {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+
+        let builder = SyntheticBuilder::new(ModelVersion::V5);
+        let info = builder.info();
+        let data = builder.build()?;
+
+        let model: v5::Model = ModelBuilder::new(&context, &data)
+            .with_head_chunk_size(info.num_vocab)
+            .build()?;
+        let state: v5::ModelState = StateBuilder::new(&context, &info)
+            .with_max_batch(2)
+            .with_chunk_size(1)
+            .build();
+        check_state(&model, &state)
+    }
+
+    fn check_paged<M: Model>(
+        model: &M,
+        paged: &M::ModelState,
+        expected: &M::ModelState,
+    ) -> Result<()> {
+        let tokens = [vec![5u16, 23, 177], vec![2, 94], vec![31, 8, 8, 64]];
+        let logits = run(model, paged, &tokens)?;
+        let expected_logits = run(model, expected, &tokens)?;
+        for (logits, expected) in logits.iter().zip_eq(expected_logits.iter()) {
+            let (logits, expected) = (logits.as_ref().unwrap(), expected.as_ref().unwrap());
+            for (a, b) in logits.iter().zip_eq(expected.iter()) {
+                assert!(is_approx_eps(*a, *b, 1e-4), "{a} vs {b}");
+            }
+        }
+
+        // backing and loading across pages sees the same layout as a single page
+        let backed = paged.back();
+        expected.load(&backed)?;
+        assert_eq!(run(model, paged, &tokens)?, run(model, expected, &tokens)?);
+
+        // copy a batch from the last page into the first
+        paged.blit_batch(paged, 2, 0)?;
+        let logits = run(model, paged, &[vec![64u16], vec![], vec![64]])?;
+        assert_eq!(logits[0], logits[2]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_paged_state() -> Result<()> {
+        let context = match create_context() {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+
+        for version in [ModelVersion::V4, ModelVersion::V5] {
+            let builder = SyntheticBuilder::new(version);
+            let info = builder.info();
+            let data = builder.build()?;
+            let paged = StateBuilder::new(&context, &info)
+                .with_max_batch(3)
+                .with_page_size(2);
+            let expected = StateBuilder::new(&context, &info).with_max_batch(3);
+            let single = StateBuilder::new(&context, &info)
+                .with_max_batch(2)
+                .with_page_size(1);
+
+            match version {
+                ModelVersion::V4 => {
+                    let model: v4::Model = ModelBuilder::new(&context, &data)
+                        .with_head_chunk_size(info.num_vocab)
+                        .build()?;
+                    check_paged(&model, &paged.build(), &expected.build())?;
+                    check_state(&model, &single.build::<v4::ModelState>())?;
+                }
+                ModelVersion::V5 => {
+                    let model: v5::Model = ModelBuilder::new(&context, &data)
+                        .with_head_chunk_size(info.num_vocab)
+                        .build()?;
+                    check_paged(&model, &paged.build(), &expected.build())?;
+                    check_state(&model, &single.build::<v5::ModelState>())?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn check_resize<M: Model>(model: &M, state: &M::ModelState) -> Result<()> {
+        let prompts = [vec![12u16, 55, 8], vec![91, 200, 7, 7]];
+        let next = [vec![64u16, 3], vec![128], vec![64, 3]];
+        run(model, state, &prompts)?;
+
+        let grown = state.resize(3)?;
+        let shrunk = state.resize(1)?;
+        let fresh = state.resize(0)?.resize(1)?;
+        assert_eq!(grown.max_batch(), 3);
+
+        let expected = run(model, state, &next[..2])?;
+        let logits = run(model, &grown, &next)?;
+        assert_eq!(logits[..2], expected);
+        assert_eq!(run(model, &shrunk, &next[..1])?[0], expected[0]);
+        // the added batch starts fresh, like that of a new state
+        assert_eq!(logits[2], run(model, &fresh, &next[2..])?[0]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_resize() -> Result<()> {
+        let context = match create_context() {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+
+        for page_size in [2, 1] {
+            let builder = SyntheticBuilder::new(ModelVersion::V4);
+            let info = builder.info();
+            let data = builder.build()?;
+            let model: v4::Model = ModelBuilder::new(&context, &data)
+                .with_head_chunk_size(info.num_vocab)
+                .build()?;
+            let state: v4::ModelState = StateBuilder::new(&context, &info)
+                .with_max_batch(2)
+                .with_page_size(page_size)
+                .build();
+            check_resize(&model, &state)?;
+
+            let builder = SyntheticBuilder::new(ModelVersion::V5);
+            let info = builder.info();
+            let data = builder.build()?;
+            let model: v5::Model = ModelBuilder::new(&context, &data)
+                .with_head_chunk_size(info.num_vocab)
+                .build()?;
+            let state: v5::ModelState = StateBuilder::new(&context, &info)
+                .with_max_batch(2)
+                .with_page_size(page_size)
+                .with_dtype(Precision::F16)
+                .build();
+            check_resize(&model, &state)?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_choose() -> Result<()> {
+        let context = match create_context() {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+
+        let builder = SyntheticBuilder::new(ModelVersion::V5).with_num_layer(2);
+        let info = builder.info();
+        let data = builder.build()?;
+        let model: v5::Model = ModelBuilder::new(&context, &data)
+            .with_head_chunk_size(info.num_vocab)
+            .with_token_chunk_size(4)
+            .build()?;
+
+        let prompt = [12u16, 55, 8, 91, 200];
+        let options = vec![vec![7u16, 40, 2], vec![99], vec![3, 3, 3, 3, 3, 3]];
+
+        // the same scores, from the prompt prefilled once and each option run after it in turn
+        let state: v5::ModelState = StateBuilder::new(&context, &info).build();
+        let expected = Prefill::new(&model, &state, 0, &prompt)?.score(&options)?;
+
+        let state: v5::ModelState = StateBuilder::new(&context, &info).with_max_batch(3).build();
+        let scores = model.choose(&prompt, &options, &state)?;
+        let norm = scores[0] - expected[0];
+        for (a, b) in scores.iter().zip_eq(expected) {
+            assert!(is_approx_eps(*a, b + norm, 1e-2), "{a} vs {}", b + norm);
+        }
+        let sum: f32 = scores.iter().map(|score| score.exp()).sum();
+        assert!(is_approx_eps(sum, 1.0, 1e-4));
+
+        assert!(model.choose(&prompt, &vec![vec![1]; 4], &state).is_err());
+        assert!(model.choose(&[], &options, &state).is_err());
+        Ok(())
+    }
+
+    /// Check pooled embeddings against those of the last token of each prefix of `tokens`, each run from a fresh state.
+    fn check_embed_sequence<M: Model>(
+        model: &M,
+        state: impl Fn() -> M::ModelState,
+        tokens: &[u16],
+    ) -> Result<()> {
+        // the last hidden state of each prefix, pooled by hand
+        let hidden: Vec<_> = (1..=tokens.len())
+            .map(|len| model.embed_sequence(&tokens[..len], 0, &state(), Pooling::Last, false))
+            .try_collect()?;
+        for pooling in [Pooling::Mean, Pooling::WeightedMean] {
+            let weights = pooling.weights(tokens.len());
+            let embed = model.embed_sequence(tokens, 0, &state(), pooling, false)?;
+            for (index, a) in embed.iter().enumerate() {
+                let b: f32 = hidden.iter().zip(&weights).map(|(x, w)| w * x[index]).sum();
+                assert!(is_approx_eps(*a, b, 1e-3), "{a} vs {b}");
+            }
+        }
+
+        let embed = model.embed_sequence(tokens, 0, &state(), Pooling::Last, true)?;
+        let norm = hidden[tokens.len() - 1]
+            .iter()
+            .map(|x| x * x)
+            .sum::<f32>()
+            .sqrt();
+        for (a, b) in embed.iter().zip_eq(&hidden[tokens.len() - 1]) {
+            assert!(is_approx_eps(*a, b / norm, 1e-3), "{a} vs {}", b / norm);
+        }
+        assert!(model
+            .embed_sequence(&[], 0, &state(), Pooling::Mean, false)
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_embed_sequence() -> Result<()> {
+        let context = match create_context() {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+
+        let tokens = [12u16, 55, 8, 91, 200, 3];
+        for version in [ModelVersion::V4, ModelVersion::V5] {
+            let builder = SyntheticBuilder::new(version).with_num_layer(2);
+            let info = builder.info();
+            let data = builder.build()?;
+            match version {
+                ModelVersion::V4 => {
+                    let model: v4::Model = ModelBuilder::new(&context, &data)
+                        .with_token_chunk_size(4)
+                        .build()?;
+                    check_embed_sequence(
+                        &model,
+                        || StateBuilder::new(&context, &info).build(),
+                        &tokens,
+                    )?
+                }
+                ModelVersion::V5 => {
+                    let model: v5::Model = ModelBuilder::new(&context, &data)
+                        .with_token_chunk_size(4)
+                        .build()?;
+                    check_embed_sequence(
+                        &model,
+                        || StateBuilder::new(&context, &info).build(),
+                        &tokens,
+                    )?
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Run the same prompt and decoding steps on `model` and `captured`, comparing their logits at every step.
+    fn check_capture<M: Model>(
+        model: &M,
+        captured: &M,
+        state: &M::ModelState,
+        captured_state: &M::ModelState,
+    ) -> Result<()> {
+        for tokens in [vec![5u16, 23, 177], vec![2], vec![94], vec![31]] {
+            let tokens = vec![tokens];
+            let expected = run(model, state, &tokens)?[0].take().unwrap();
+            let output = run(captured, captured_state, &tokens)?[0].take().unwrap();
+            for (a, b) in output.into_iter().zip_eq(expected) {
+                assert!(is_approx_eps(a, b, 1.0e-3), "{a} != {b}");
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_capture() -> Result<()> {
+        let context = match create_context() {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+
+        let builder = SyntheticBuilder::new(ModelVersion::V4);
+        let info = builder.info();
+        let data = builder.build()?;
+        let model: v4::Model = ModelBuilder::new(&context, &data).build()?;
+        let captured: v4::Model = ModelBuilder::new(&context, &data)
+            .with_capture(true)
+            .build()?;
+        let state: v4::ModelState = StateBuilder::new(&context, &info).build();
+        let captured_state: v4::ModelState = StateBuilder::new(&context, &info).build();
+        check_capture(&model, &captured, &state, &captured_state)?;
+
+        let builder = SyntheticBuilder::new(ModelVersion::V5);
+        let info = builder.info();
+        let data = builder.build()?;
+        let model: v5::Model = ModelBuilder::new(&context, &data).build()?;
+        let captured: v5::Model = ModelBuilder::new(&context, &data)
+            .with_capture(true)
+            .build()?;
+        let state: v5::ModelState = StateBuilder::new(&context, &info).build();
+        let captured_state: v5::ModelState = StateBuilder::new(&context, &info).build();
+        check_capture(&model, &captured, &state, &captured_state)?;
+
+        // the prompt and the first decoding step are recorded, and the other steps replay the latter
+        let stats = captured.capture_stats();
+        assert_eq!((stats.misses, stats.hits), (2, 2));
+
+        // a new state has buffers of its own, so its runs are recorded again
+        let state: v5::ModelState = StateBuilder::new(&context, &info).build();
+        run(&captured, &state, &[vec![2]])?;
+        assert_eq!(captured.capture_stats().misses, 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_chunk_size() -> Result<()> {
+        let limits = wgpu::Limits::default();
+        let info = SyntheticBuilder::new(ModelVersion::V5).info();
+        assert_eq!(ChunkSize::Auto.head(&limits, &info), info.num_vocab);
+        assert_eq!(ChunkSize::Auto.token(&limits, &info), MAX_TOKEN_CHUNK_SIZE);
+        assert_eq!(ChunkSize::from(16).token(&limits, &info), 16);
+
+        // a 14B model on a device binding at most 128 MiB
+        let info = ModelInfo {
+            num_emb: 5120,
+            num_hidden: 17920,
+            num_vocab: 65536,
+            ..info
+        };
+        let limits = wgpu::Limits {
+            max_storage_buffer_binding_size: 128 << 20,
+            ..limits
+        };
+        assert_eq!(ChunkSize::Auto.head(&limits, &info), 8192);
+        // and on one binding only 4 MiB
+        let limits = wgpu::Limits {
+            max_storage_buffer_binding_size: 4 << 20,
+            ..limits
+        };
+        assert_eq!(ChunkSize::Auto.head(&limits, &info), 256);
+        assert_eq!(ChunkSize::Auto.token(&limits, &info), 32);
+
+        // an odd vocabulary is covered by whole chunks and a shorter last one
+        let info = ModelInfo {
+            num_emb: 768,
+            num_vocab: 50277,
+            ..info
+        };
+        assert_eq!(ChunkSize::Auto.head(&limits, &info), 2048);
+        let limits = wgpu::Limits::default();
+        assert_eq!(ChunkSize::Auto.head(&limits, &info), 65536);
+
+        let context = match create_context() {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+        let data = SyntheticBuilder::new(ModelVersion::V4).build()?;
+        let model: v4::Model = ModelBuilder::new(&context, &data).build()?;
+        let limits = context.device.limits();
+        let info = model.info();
+        assert_eq!(model.head_chunk_size(), ChunkSize::Auto.head(&limits, info));
+        assert_eq!(
+            model.token_chunk_size(),
+            ChunkSize::Auto.token(&limits, info)
+        );
+        assert!(ModelBuilder::new(&context, &data)
+            .with_token_chunk_size(24)
+            .build::<v4::Model>()
+            .is_err());
+
+        // the head of a vocabulary that isn't a multiple of the chunk size ends with a shorter chunk
+        let builder = SyntheticBuilder::new(ModelVersion::V5).with_num_vocab(250);
+        let info = builder.info();
+        let data = builder.build()?;
+        let tokens = [vec![5u16, 23, 177, 249]];
+        let mut logits = vec![];
+        for head_chunk_size in [64, 256] {
+            let model: v5::Model = ModelBuilder::new(&context, &data)
+                .with_head_chunk_size(head_chunk_size)
+                .build()?;
+            let state: v5::ModelState = StateBuilder::new(&context, &info).build();
+            logits.push(run(&model, &state, &tokens)?.remove(0).unwrap());
+        }
+        assert_eq!(logits[0].len(), 250);
+        for (a, b) in logits[0].iter().zip_eq(&logits[1]) {
+            assert!(is_approx_eps(*a, *b, 1.0e-3), "{a} != {b}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_model_limits() -> Result<()> {
+        // the feed-forward matrices of a 14B model take 175 MiB each in f16
+        let info = ModelInfo {
+            num_emb: 5120,
+            num_hidden: 17920,
+            num_vocab: 65536,
+            ..SyntheticBuilder::new(ModelVersion::V5).info()
+        };
+        let limits = info.limits();
+        assert_eq!(limits.max_buffer_size, 5120 * 17920 * 2);
+        assert_eq!(limits.max_storage_buffer_binding_size, 5120 * 17920 * 2);
+
+        // a small model needs less than the defaults, which are kept
+        let info = SyntheticBuilder::new(ModelVersion::V5).info();
+        assert_eq!(info.limits().max_buffer_size, 64 * 256 * 2);
+
+        let adapter = pollster::block_on(async {
+            let instance = Instance::new();
+            instance.adapter(PowerPreference::HighPerformance).await
+        });
+        let adapter = match adapter {
+            Ok(adapter) => adapter,
+            Err(_) => return Ok(()),
+        };
+        let builder = ContextBuilder::new(adapter).with_required_limits(info.limits());
+        let context = pollster::block_on(async { builder.build().await })?;
+        let limits = context.device.limits();
+        let defaults = wgpu::Limits::default();
+        assert_eq!(limits.max_buffer_size, defaults.max_buffer_size);
+        assert_eq!(
+            limits.max_storage_buffer_binding_size,
+            defaults.max_storage_buffer_binding_size
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_progress() -> Result<()> {
+        let context = match create_context() {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+
+        let data = SyntheticBuilder::new(ModelVersion::V5)
+            .with_num_layer(3)
+            .build()?;
+        let mut layers = vec![];
+        let _: v5::Model = ModelBuilder::new(&context, &data)
+            .with_quant([(0, Quant::Int8), (1, Quant::NF4)].into())
+            .with_progress(|progress| layers.push((progress.layer, progress.num_layer)))
+            .build()?;
+        assert_eq!(layers, vec![(1, 3), (2, 3), (3, 3)]);
+
+        // cancelling after the first layer stops before the second, with nothing left on the device
+        let cancel = Arc::new(AtomicBool::new(false));
+        let mut layers = vec![];
+        let error = ModelBuilder::new(&context, &data)
+            .with_cancel(cancel.clone())
+            .with_progress(|progress| {
+                layers.push(progress.layer);
+                cancel.store(true, Ordering::Relaxed);
+            })
+            .build::<v5::Model>()
+            .err()
+            .and_then(|err| err.downcast::<ModelError>().ok());
+        assert_eq!(layers, vec![1]);
+        assert_eq!(error, Some(ModelError::Cancelled));
+        assert_eq!(context.memory_usage().weights, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_model_destroy() -> Result<()> {
+        let context = match create_context() {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+
+        for _ in 0..2 {
+            let builder = SyntheticBuilder::new(ModelVersion::V4);
+            let info = builder.info();
+            let data = builder.build()?;
+            let model: v4::Model = ModelBuilder::new(&context, &data).build()?;
+            let state: v4::ModelState = StateBuilder::new(&context, &info).build();
+            run(&model, &state, &[vec![0, 1, 2]])?;
+            model.destroy();
+            drop(state);
+
+            let builder = SyntheticBuilder::new(ModelVersion::V5);
+            let info = builder.info();
+            let data = builder.build()?;
+            let model: v5::Model = ModelBuilder::new(&context, &data)
+                .with_quant([(0, Quant::Int8)].into())
+                .build()?;
+            let state: v5::ModelState = StateBuilder::new(&context, &info).build();
+            run(&model, &state, &[vec![0, 1, 2]])?;
+            model.destroy();
+            drop(state);
+
+            let usage = context.memory_usage();
+            assert_eq!((usage.weights, usage.state, usage.runtime), (0, 0, 0));
+        }
+
+        context.close();
+        assert_eq!(context.memory_usage().total(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_state_v5_f16() -> Result<()> {
+        let context = match create_context() {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+
+        let builder = SyntheticBuilder::new(ModelVersion::V5);
+        let info = builder.info();
+        let data = builder.build()?;
+
+        let model: v5::Model = ModelBuilder::new(&context, &data)
+            .with_head_chunk_size(info.num_vocab)
+            .build()?;
+        let state: v5::ModelState = StateBuilder::new(&context, &info)
+            .with_max_batch(2)
+            .with_chunk_size(1)
+            .with_dtype(Precision::F16)
+            .build();
+        check_state(&model, &state)?;
+
+        let tokens = [vec![5u16, 23, 177, 2, 94], vec![31, 8]];
+        let expected: v5::ModelState = StateBuilder::new(&context, &info).with_max_batch(2).build();
+        let expected = run(&model, &expected, &tokens)?;
+
+        let state: v5::ModelState = StateBuilder::new(&context, &info)
+            .with_max_batch(2)
+            .with_dtype(Precision::F16)
+            .build();
+        let logits = run(&model, &state, &tokens)?;
+        for (logits, expected) in logits.iter().zip_eq(expected.iter()) {
+            let (logits, expected) = (logits.as_ref().unwrap(), expected.as_ref().unwrap());
+            for (a, b) in logits.iter().zip_eq(expected.iter()) {
+                assert!(is_approx_eps(*a, *b, 1e-2), "{a} vs {b}");
+            }
+        }
+
+        // copying a batch within an f16 state replays identically
+        state.blit_batch(&state, 0, 1)?;
+        let logits = run(&model, &state, &[vec![64u16], vec![64]])?;
+        assert_eq!(logits[0], logits[1]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_activation_f16() -> Result<()> {
+        let context = match create_context() {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+
+        // runs of 4 tokens take the turbo path, the rest the vector one
+        let tokens = [vec![5u16, 23, 177, 2, 94, 31]];
+        for version in [ModelVersion::V4, ModelVersion::V5] {
+            let data = SyntheticBuilder::new(version).build()?;
+            for (quant, turbo) in [(Quant::None, false), (Quant::Int8, true)] {
+                let (expected, logits) = match version {
+                    ModelVersion::V4 => (
+                        run_with::<v4::Model>(&context, &data, quant, turbo, &tokens)?,
+                        run_with::<v4::Model<f16>>(&context, &data, quant, turbo, &tokens)?,
+                    ),
+                    ModelVersion::V5 => (
+                        run_with::<v5::Model>(&context, &data, quant, turbo, &tokens)?,
+                        run_with::<v5::Model<f16>>(&context, &data, quant, turbo, &tokens)?,
+                    ),
+                };
+                let error = relative_error(&logits, &expected);
+                assert!(
+                    error < 0.01,
+                    "{version:?} {quant:?} relative error: {error}"
+                );
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_warmup() -> Result<()> {
+        let context = match create_context() {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+
+        // a run past the turbo threshold, then single tokens, all within the warmed up envelope
+        let prompt = [(0..19).collect_vec(), (100..118).collect_vec()];
+        let tokens = [vec![7], vec![8]];
+        for version in [ModelVersion::V4, ModelVersion::V5] {
+            let data = SyntheticBuilder::new(version).build()?;
+            let info = Loader::info(&data)?;
+            let builder = ModelBuilder::new(&context, &data)
+                .with_token_chunk_size(64)
+                .with_turbo(true);
+            let misses = match version {
+                ModelVersion::V4 => {
+                    let model: v4::Model = builder.build()?;
+                    model.warmup(2, 64)?;
+                    let misses = context.cache_stats().pipeline.misses;
+                    let state: v4::ModelState =
+                        StateBuilder::new(&context, &info).with_max_batch(2).build();
+                    run(&model, &state, &prompt)?;
+                    run(&model, &state, &tokens)?;
+                    context.cache_stats().pipeline.misses - misses
+                }
+                ModelVersion::V5 => {
+                    let model: v5::Model = builder.build()?;
+                    model.warmup(2, 64)?;
+                    let misses = context.cache_stats().pipeline.misses;
+                    let state: v5::ModelState =
+                        StateBuilder::new(&context, &info).with_max_batch(2).build();
+                    run(&model, &state, &prompt)?;
+                    run(&model, &state, &tokens)?;
+                    context.cache_stats().pipeline.misses - misses
+                }
+            };
+            assert_eq!(misses, 0, "{version:?} compiled pipelines after warming up");
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,579 @@
+//! A straightforward CPU implementation of the RWKV forward pass.
+//!
+//! It is slow and processes one token at a time, but is simple enough to serve as the ground truth
+//! the GPU kernels are checked against.
+
+use anyhow::Result;
+use half::f16;
+use itertools::Itertools;
+use safetensors::SafeTensors;
+
+use super::{loader::Loader, ModelInfo, ModelVersion};
+
+/// Epsilon of the layer normalization, following the official implementation.
+pub const LAYER_NORM_EPS: f32 = 1.0e-5;
+/// Epsilon of the group normalization in V5 attention.
+pub const GROUP_NORM_EPS: f32 = 64.0e-5;
+
+#[derive(Debug, Clone)]
+struct LayerNorm {
+    w: Vec<f32>,
+    b: Vec<f32>,
+}
+
+impl LayerNorm {
+    fn apply(&self, x: &[f32], num_group: usize, eps: f32) -> Vec<f32> {
+        let size = x.len() / num_group;
+        x.chunks_exact(size)
+            .zip_eq(self.w.chunks_exact(size))
+            .zip_eq(self.b.chunks_exact(size))
+            .flat_map(|((x, w), b)| {
+                let mean = x.iter().sum::<f32>() / size as f32;
+                let variance = x.iter().map(|x| (x - mean) * (x - mean)).sum::<f32>() / size as f32;
+                let deviation = 1.0 / (variance + eps).sqrt();
+                x.iter()
+                    .zip_eq(w.iter().zip_eq(b.iter()))
+                    .map(move |(x, (w, b))| (x - mean) * deviation * w + b)
+            })
+            .collect()
+    }
+}
+
+/// A row-major matrix of shape `[R, C]`, as stored in the checkpoint.
+#[derive(Debug, Clone)]
+struct Matrix {
+    data: Vec<f32>,
+    num_col: usize,
+}
+
+impl Matrix {
+    fn apply(&self, x: &[f32]) -> Vec<f32> {
+        assert_eq!(x.len(), self.num_col);
+        self.data
+            .chunks_exact(self.num_col)
+            .map(|row| row.iter().zip_eq(x.iter()).map(|(w, x)| w * x).sum())
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Att {
+    time_decay: Vec<f32>,
+    time_first: Vec<f32>,
+
+    time_mix_k: Vec<f32>,
+    time_mix_v: Vec<f32>,
+    time_mix_r: Vec<f32>,
+    time_mix_g: Option<Vec<f32>>,
+
+    w_k: Matrix,
+    w_v: Matrix,
+    w_r: Matrix,
+    w_g: Option<Matrix>,
+    w_o: Matrix,
+
+    group_norm: Option<LayerNorm>,
+}
+
+#[derive(Debug, Clone)]
+struct Ffn {
+    time_mix_k: Vec<f32>,
+    time_mix_r: Vec<f32>,
+
+    w_k: Matrix,
+    w_v: Matrix,
+    w_r: Matrix,
+}
+
+#[derive(Debug, Clone)]
+struct Layer {
+    att_layer_norm: LayerNorm,
+    ffn_layer_norm: LayerNorm,
+    att: Att,
+    ffn: Ffn,
+}
+
+/// The reference model. Weights are converted to `f32` on load.
+#[derive(Debug, Clone)]
+pub struct Model {
+    info: ModelInfo,
+    embed: Matrix,
+    embed_layer_norm: LayerNorm,
+    head: Matrix,
+    head_layer_norm: LayerNorm,
+    layers: Vec<Layer>,
+}
+
+/// Recurrent state of one layer for a single sequence.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LayerState {
+    /// The normalized attention input of the last token.
+    pub att_x: Vec<f32>,
+    /// For V4, the `aa`, `bb` and `pp` rows of the WKV state.
+    /// For V5, `S` rows of the per-head `k ⊗ v` state, where row `j` holds the `j`-th key channel of each head.
+    pub att_kv: Vec<f32>,
+    /// The normalized FFN input of the last token.
+    pub ffn_x: Vec<f32>,
+}
+
+impl LayerState {
+    /// Flatten the state into the same row layout a device state uses for one layer of one batch.
+    pub fn to_vec(&self) -> Vec<f32> {
+        [self.att_x.as_slice(), &self.att_kv, &self.ffn_x].concat()
+    }
+}
+
+/// Recurrent state of the reference model for a single sequence.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ModelState(pub Vec<LayerState>);
+
+fn sigmoid(x: f32) -> f32 {
+    1.0 / (1.0 + (-x).exp())
+}
+
+fn token_shift(mix: &[f32], x: &[f32], sx: &[f32]) -> Vec<f32> {
+    itertools::izip!(mix, x, sx)
+        .map(|(mix, x, sx)| x * mix + sx * (1.0 - mix))
+        .collect()
+}
+
+impl Model {
+    pub fn from_safetensors(data: &[u8]) -> Result<Self> {
+        let info = Loader::info(data)?;
+        let model = SafeTensors::deserialize(data)?;
+
+        let vector = |name: &str| -> Result<Vec<f32>> {
+            let tensor = model.tensor(name)?;
+            let data: Vec<f16> = bytemuck::pod_collect_to_vec(tensor.data());
+            Ok(data.into_iter().map(f16::to_f32).collect())
+        };
+        let matrix = |name: &str| -> Result<Matrix> {
+            let num_col = model.tensor(name)?.shape()[1];
+            let data = vector(name)?;
+            Ok(Matrix { data, num_col })
+        };
+        let layer_norm = |name: &str| -> Result<LayerNorm> {
+            Ok(LayerNorm {
+                w: vector(&format!("{name}.weight"))?,
+                b: vector(&format!("{name}.bias"))?,
+            })
+        };
+
+        let layers = (0..info.num_layer)
+            .map(|layer| -> Result<_> {
+                let att = format!("blocks.{layer}.att");
+                let ffn = format!("blocks.{layer}.ffn");
+                let v5 = info.version == ModelVersion::V5;
+
+                let att = Att {
+                    time_decay: vector(&format!("{att}.time_decay"))?,
+                    time_first: vector(&format!("{att}.time_first"))?,
+                    time_mix_k: vector(&format!("{att}.time_mix_k"))?,
+                    time_mix_v: vector(&format!("{att}.time_mix_v"))?,
+                    time_mix_r: vector(&format!("{att}.time_mix_r"))?,
+                    time_mix_g: v5
+                        .then(|| vector(&format!("{att}.time_mix_g")))
+                        .transpose()?,
+                    w_k: matrix(&format!("{att}.key.weight"))?,
+                    w_v: matrix(&format!("{att}.value.weight"))?,
+                    w_r: matrix(&format!("{att}.receptance.weight"))?,
+                    w_g: v5
+                        .then(|| matrix(&format!("{att}.gate.weight")))
+                        .transpose()?,
+                    w_o: matrix(&format!("{att}.output.weight"))?,
+                    group_norm: v5.then(|| layer_norm(&format!("{att}.ln_x"))).transpose()?,
+                };
+                let ffn = Ffn {
+                    time_mix_k: vector(&format!("{ffn}.time_mix_k"))?,
+                    time_mix_r: vector(&format!("{ffn}.time_mix_r"))?,
+                    w_k: matrix(&format!("{ffn}.key.weight"))?,
+                    w_v: matrix(&format!("{ffn}.value.weight"))?,
+                    w_r: matrix(&format!("{ffn}.receptance.weight"))?,
+                };
+
+                Ok(Layer {
+                    att_layer_norm: layer_norm(&format!("blocks.{layer}.ln1"))?,
+                    ffn_layer_norm: layer_norm(&format!("blocks.{layer}.ln2"))?,
+                    att,
+                    ffn,
+                })
+            })
+            .try_collect()?;
+
+        Ok(Self {
+            embed: matrix("emb.weight")?,
+            embed_layer_norm: layer_norm("blocks.0.ln0")?,
+            head: matrix("head.weight").or_else(|_| matrix("emb.weight"))?,
+            head_layer_norm: layer_norm("ln_out")?,
+            layers,
+            info,
+        })
+    }
+
+    #[inline]
+    pub fn info(&self) -> &ModelInfo {
+        &self.info
+    }
+
+    /// Create a fresh state, matching the initial content of a device state.
+    pub fn init_state(&self) -> ModelState {
+        let num_emb = self.info.num_emb;
+        let att_kv = match self.info.version {
+            ModelVersion::V4 => [
+                vec![0.0; num_emb],
+                vec![0.0; num_emb],
+                vec![f32::MIN; num_emb],
+            ]
+            .concat(),
+            ModelVersion::V5 => vec![0.0; num_emb * num_emb / self.info.num_head],
+        };
+        let layer = LayerState {
+            att_x: vec![0.0; num_emb],
+            att_kv,
+            ffn_x: vec![0.0; num_emb],
+        };
+        ModelState(vec![layer; self.info.num_layer])
+    }
+
+    /// Run the model through `tokens` one by one, returning the logits of the last token.
+    pub fn run(&self, tokens: &[u16], state: &mut ModelState) -> Option<Vec<f32>> {
+        tokens
+            .iter()
+            .map(|&token| self.run_token(token, state))
+            .last()
+    }
+
+    /// The embedding of `token`, optionally normalized by the input layer norm.
+    pub fn embed(&self, token: u16, layer_norm: bool) -> Vec<f32> {
+        let num_emb = self.info.num_emb;
+        let start = token as usize * num_emb;
+        let x = &self.embed.data[start..start + num_emb];
+        match layer_norm {
+            true => self.embed_layer_norm.apply(x, 1, LAYER_NORM_EPS),
+            false => x.to_vec(),
+        }
+    }
+
+    fn run_token(&self, token: u16, state: &mut ModelState) -> Vec<f32> {
+        let mut x = self.embed(token, true);
+
+        for (layer, state) in self.layers.iter().zip_eq(state.0.iter_mut()) {
+            let xx = layer.att_layer_norm.apply(&x, 1, LAYER_NORM_EPS);
+            let o = match self.info.version {
+                ModelVersion::V4 => self.time_mix_v4(&layer.att, &xx, state),
+                ModelVersion::V5 => self.time_mix_v5(&layer.att, &xx, state),
+            };
+            state.att_x = xx;
+            x.iter_mut().zip_eq(o).for_each(|(x, o)| *x += o);
+
+            let xx = layer.ffn_layer_norm.apply(&x, 1, LAYER_NORM_EPS);
+            let o = self.channel_mix(&layer.ffn, &xx, state);
+            state.ffn_x = xx;
+            x.iter_mut().zip_eq(o).for_each(|(x, o)| *x += o);
+        }
+
+        let x = self.head_layer_norm.apply(&x, 1, LAYER_NORM_EPS);
+        self.head.apply(&x)
+    }
+
+    fn time_mix_v4(&self, att: &Att, x: &[f32], state: &mut LayerState) -> Vec<f32> {
+        let num_emb = self.info.num_emb;
+        let k = att
+            .w_k
+            .apply(&token_shift(&att.time_mix_k, x, &state.att_x));
+        let v = att
+            .w_v
+            .apply(&token_shift(&att.time_mix_v, x, &state.att_x));
+        let r = att
+            .w_r
+            .apply(&token_shift(&att.time_mix_r, x, &state.att_x));
+
+        let (aa, rest) = state.att_kv.split_at_mut(num_emb);
+        let (bb, pp) = rest.split_at_mut(num_emb);
+
+        let y = (0..num_emb)
+            .map(|i| {
+                let w = -att.time_decay[i].exp();
+                let u = att.time_first[i];
+
+                let ww = u + k[i];
+                let q = pp[i].max(ww);
+                let e1 = (pp[i] - q).exp();
+                let e2 = (ww - q).exp();
+                let y = sigmoid(r[i]) * (e1 * aa[i] + e2 * v[i]) / (e1 * bb[i] + e2);
+
+                let ww = w + pp[i];
+                let q = ww.max(k[i]);
+                let e1 = (ww - q).exp();
+                let e2 = (k[i] - q).exp();
+                aa[i] = e1 * aa[i] + e2 * v[i];
+                bb[i] = e1 * bb[i] + e2;
+                pp[i] = q;
+
+                y
+            })
+            .collect_vec();
+        att.w_o.apply(&y)
+    }
+
+    fn time_mix_v5(&self, att: &Att, x: &[f32], state: &mut LayerState) -> Vec<f32> {
+        let num_emb = self.info.num_emb;
+        let num_head = self.info.num_head;
+        let head_size = num_emb / num_head;
+
+        let time_mix_g = att.time_mix_g.as_ref().expect("v5 gate mix");
+        let w_g = att.w_g.as_ref().expect("v5 gate");
+        let group_norm = att.group_norm.as_ref().expect("v5 group norm");
+
+        let k = att
+            .w_k
+            .apply(&token_shift(&att.time_mix_k, x, &state.att_x));
+        let v = att
+            .w_v
+            .apply(&token_shift(&att.time_mix_v, x, &state.att_x));
+        let r = att
+            .w_r
+            .apply(&token_shift(&att.time_mix_r, x, &state.att_x));
+        let g = w_g.apply(&token_shift(time_mix_g, x, &state.att_x));
+
+        let mut y = vec![0.0; num_emb];
+        for head in 0..num_head {
+            for i in (0..head_size).map(|i| head * head_size + i) {
+                for j in 0..head_size {
+                    let kj = head * head_size + j;
+                    let w = (-att.time_decay[kj].exp()).exp();
+                    let u = att.time_first[kj];
+
+                    let kv = k[kj] * v[i];
+                    let s = &mut state.att_kv[j * num_emb + i];
+                    y[i] += r[kj] * (u * kv + *s);
+                    *s = w * *s + kv;
+                }
+            }
+        }
+
+        let y = group_norm.apply(&y, num_head, GROUP_NORM_EPS);
+        let y = y
+            .into_iter()
+            .zip_eq(g)
+            .map(|(y, g)| y * g * sigmoid(g))
+            .collect_vec();
+        att.w_o.apply(&y)
+    }
+
+    fn channel_mix(&self, ffn: &Ffn, x: &[f32], state: &LayerState) -> Vec<f32> {
+        let k = ffn
+            .w_k
+            .apply(&token_shift(&ffn.time_mix_k, x, &state.ffn_x));
+        let k = k.into_iter().map(|k| k.max(0.0).powi(2)).collect_vec();
+        let v = ffn.w_v.apply(&k);
+        let r = ffn
+            .w_r
+            .apply(&token_shift(&ffn.time_mix_r, x, &state.ffn_x));
+        r.into_iter()
+            .zip_eq(v)
+            .map(|(r, v)| sigmoid(r) * v)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use itertools::Itertools;
+
+    use super::Model as Reference;
+    use crate::model::{
+        loader::Loader,
+        synthetic::{
+            testing::{create_context, is_approx_eps},
+            SyntheticBuilder,
+        },
+        v4, v5, BackedState, Model, ModelBuilder, ModelState, ModelVersion, StateBuilder,
+    };
+
+    fn check_parity<M: Model>(
+        model: &M,
+        state: &M::ModelState,
+        reference: &Reference,
+    ) -> Result<()> {
+        let prompts = [vec![3u16, 141, 59, 26, 53, 58, 97, 93], vec![23, 84, 62]];
+        let next = [vec![38u16], vec![46]];
+        let num_batch = prompts.len();
+        let num_layer = reference.info().num_layer;
+
+        let tokens = [3u16, 141, 59];
+        for layer_norm in [false, true] {
+            let embed = model.embed_tokens(&tokens, layer_norm)?;
+            let embed = embed.back().to_vec();
+
+            let expected = tokens
+                .iter()
+                .flat_map(|&token| reference.embed(token, layer_norm))
+                .collect_vec();
+            for (&a, &b) in embed.iter().zip_eq(expected.iter()) {
+                assert!(is_approx_eps(a, b, 1.0e-3), "embed: {a} vs {b}");
+            }
+        }
+
+        let mut states = vec![reference.init_state(); num_batch];
+
+        for input in [prompts.to_vec(), next.to_vec()] {
+            let expected = input
+                .iter()
+                .zip_eq(states.iter_mut())
+                .map(|(tokens, state)| reference.run(tokens, state).unwrap())
+                .collect_vec();
+
+            let mut tokens = input.clone();
+            let mut logits = vec![None; num_batch];
+            while tokens.iter().any(|tokens| !tokens.is_empty()) {
+                for (logits, output) in logits.iter_mut().zip_eq(model.run(&mut tokens, state)?) {
+                    if output.is_some() {
+                        *logits = output;
+                    }
+                }
+            }
+
+            for (batch, (logits, expected)) in logits.iter().zip_eq(expected.iter()).enumerate() {
+                let logits = logits.as_ref().expect("batch output");
+                for (&a, &b) in logits.iter().zip_eq(expected.iter()) {
+                    assert!(
+                        is_approx_eps(a, b, 5.0e-3),
+                        "batch {batch} logits: {a} vs {b}"
+                    );
+                }
+            }
+
+            let backed = state.back();
+            for (batch, expected) in states.iter().enumerate() {
+                let backed_batch = state.back_batch(batch)?;
+                for (layer, expected) in expected.0.iter().enumerate() {
+                    let embed = backed.embed(batch, layer);
+                    let embed_batch = backed_batch.embed(0, layer);
+                    assert_eq!(embed, embed_batch);
+                    for (&a, &b) in embed.iter().zip_eq(expected.ffn_x.iter()) {
+                        assert!(
+                            is_approx_eps(a, b, 5.0e-3),
+                            "batch {batch} layer {layer} embed: {a} vs {b}"
+                        );
+                    }
+                }
+            }
+            assert_eq!(backed.num_layer(), num_layer);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parity_v4() -> Result<()> {
+        let context = match create_context() {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+
+        let data = SyntheticBuilder::new(ModelVersion::V4).build()?;
+        let reference = Reference::from_safetensors(&data)?;
+        let info = reference.info();
+
+        let model: v4::Model = ModelBuilder::new(&context, &data)
+            .with_head_chunk_size(info.num_vocab)
+            .build()?;
+        let state: v4::ModelState = StateBuilder::new(&context, info).with_max_batch(2).build();
+        check_parity(&model, &state, &reference)
+    }
+
+    #[test]
+    fn test_parity_v5() -> Result<()> {
+        let context = match create_context() {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+
+        for (num_emb, num_head) in [(64, 1), (128, 2)] {
+            let data = SyntheticBuilder::new(ModelVersion::V5)
+                .with_num_emb(num_emb)
+                .with_num_head(num_head)
+                .build()?;
+            let reference = Reference::from_safetensors(&data)?;
+            let info = reference.info();
+
+            let model: v5::Model = ModelBuilder::new(&context, &data)
+                .with_head_chunk_size(info.num_vocab)
+                .build()?;
+            let state: v5::ModelState = StateBuilder::new(&context, info)
+                .with_max_batch(2)
+                .with_chunk_size(1)
+                .build();
+            check_parity(&model, &state, &reference)?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_parity_tied() -> Result<()> {
+        let context = match create_context() {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+
+        let data = SyntheticBuilder::new(ModelVersion::V4)
+            .with_tied_embed(true)
+            .build()?;
+        assert!(Loader::new(&context, &data, vec![])?.tied_embed());
+
+        let reference = Reference::from_safetensors(&data)?;
+        let info = reference.info();
+
+        let model: v4::Model = ModelBuilder::new(&context, &data)
+            .with_head_chunk_size(info.num_vocab)
+            .build()?;
+        let state: v4::ModelState = StateBuilder::new(&context, info).with_max_batch(2).build();
+        check_parity(&model, &state, &reference)
+    }
+
+    #[test]
+    fn test_parity_host_layers() -> Result<()> {
+        let context = match create_context() {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+
+        let data = SyntheticBuilder::new(ModelVersion::V4)
+            .with_num_layer(3)
+            .build()?;
+        let reference = Reference::from_safetensors(&data)?;
+        let info = reference.info();
+
+        let model: v4::Model = ModelBuilder::new(&context, &data)
+            .with_head_chunk_size(info.num_vocab)
+            .with_host_layers(2)
+            .build()?;
+        let state: v4::ModelState = StateBuilder::new(&context, info).with_max_batch(2).build();
+        check_parity(&model, &state, &reference)?;
+
+        let data = SyntheticBuilder::new(ModelVersion::V5)
+            .with_num_layer(3)
+            .build()?;
+        let reference = Reference::from_safetensors(&data)?;
+        let info = reference.info();
+
+        // the host layer shares a chunk of the state with one on the device
+        let model: v5::Model = ModelBuilder::new(&context, &data)
+            .with_head_chunk_size(info.num_vocab)
+            .with_host_layers(1)
+            .build()?;
+        let state: v5::ModelState = StateBuilder::new(&context, info)
+            .with_max_batch(2)
+            .with_chunk_size(2)
+            .build();
+        check_parity(&model, &state, &reference)?;
+
+        let result = ModelBuilder::new(&context, &data)
+            .with_host_layers(3)
+            .build::<v5::Model>();
+        assert!(result.is_err());
+        Ok(())
+    }
+}
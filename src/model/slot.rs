@@ -0,0 +1,204 @@
+//! Keeping track of which batch of a state belongs to whom.
+
+use super::ModelState;
+use crate::tensor::TensorError;
+
+/// A handle to an allocated batch. It goes stale once the batch is freed,
+/// so a handle kept by mistake can't reach the next owner of the batch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SlotKey {
+    batch: usize,
+    generation: u32,
+}
+
+impl SlotKey {
+    /// The batch index in the state.
+    pub fn batch(&self) -> usize {
+        self.batch
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Entry<T> {
+    generation: u32,
+    value: Option<T>,
+}
+
+/// Allocator of the batches of a state, with a value (e.g. a conversation) attached to each taken batch.
+///
+/// A freed batch still holds the state of its last owner; load a fresh one into it after allocating.
+#[derive(Debug, Clone)]
+pub struct Slots<T> {
+    entries: Vec<Entry<T>>,
+}
+
+impl<T> Slots<T> {
+    pub fn new(max_batch: usize) -> Self {
+        let entries = (0..max_batch)
+            .map(|_| Entry {
+                generation: 0,
+                value: None,
+            })
+            .collect();
+        Self { entries }
+    }
+
+    #[inline]
+    pub fn max_batch(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Number of batches taken.
+    pub fn len(&self) -> usize {
+        self.entries
+            .iter()
+            .filter(|entry| entry.value.is_some())
+            .count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Take the first free batch for `value`, or return `None` if all are taken.
+    pub fn allocate(&mut self, value: T) -> Option<SlotKey> {
+        let (batch, entry) = self
+            .entries
+            .iter_mut()
+            .enumerate()
+            .find(|(_, entry)| entry.value.is_none())?;
+        entry.value = Some(value);
+        Some(SlotKey {
+            batch,
+            generation: entry.generation,
+        })
+    }
+
+    /// Take the first free batch for `value`, and copy the batch of `key` in `state` into it,
+    /// so that both go on from the same point. Returns `None` if `key` is stale or all batches are taken.
+    pub fn fork<S: ModelState>(
+        &mut self,
+        state: &S,
+        key: SlotKey,
+        value: T,
+    ) -> Result<Option<SlotKey>, TensorError> {
+        if !self.contains(key) {
+            return Ok(None);
+        }
+        let Some(fork) = self.allocate(value) else {
+            return Ok(None);
+        };
+        state.blit_batch(state, key.batch, fork.batch)?;
+        Ok(Some(fork))
+    }
+
+    /// Free the batch of `key`, returning its value. Stale keys return `None`.
+    pub fn free(&mut self, key: SlotKey) -> Option<T> {
+        let entry = self.entries.get_mut(key.batch)?;
+        if entry.generation != key.generation {
+            return None;
+        }
+        let value = entry.value.take()?;
+        entry.generation = entry.generation.wrapping_add(1);
+        Some(value)
+    }
+
+    pub fn get(&self, key: SlotKey) -> Option<&T> {
+        self.entries
+            .get(key.batch)
+            .filter(|entry| entry.generation == key.generation)
+            .and_then(|entry| entry.value.as_ref())
+    }
+
+    pub fn get_mut(&mut self, key: SlotKey) -> Option<&mut T> {
+        self.entries
+            .get_mut(key.batch)
+            .filter(|entry| entry.generation == key.generation)
+            .and_then(|entry| entry.value.as_mut())
+    }
+
+    pub fn contains(&self, key: SlotKey) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// Whether `batch` is taken.
+    pub fn is_occupied(&self, batch: usize) -> bool {
+        self.entries
+            .get(batch)
+            .is_some_and(|entry| entry.value.is_some())
+    }
+
+    /// Keys and values of the taken batches, in batch order.
+    pub fn occupied(&self) -> impl Iterator<Item = (SlotKey, &T)> {
+        self.entries
+            .iter()
+            .enumerate()
+            .filter_map(|(batch, entry)| {
+                let key = SlotKey {
+                    batch,
+                    generation: entry.generation,
+                };
+                entry.value.as_ref().map(|value| (key, value))
+            })
+    }
+
+    /// Keys and mutable values of the taken batches, in batch order.
+    pub fn occupied_mut(&mut self) -> impl Iterator<Item = (SlotKey, &mut T)> {
+        self.entries
+            .iter_mut()
+            .enumerate()
+            .filter_map(|(batch, entry)| {
+                let key = SlotKey {
+                    batch,
+                    generation: entry.generation,
+                };
+                entry.value.as_mut().map(|value| (key, value))
+            })
+    }
+
+    /// Lay out per-slot tokens as the input of [`Model::run`](super::Model::run).
+    /// Tokens for stale keys are dropped.
+    pub fn tokens(&self, input: impl IntoIterator<Item = (SlotKey, Vec<u16>)>) -> Vec<Vec<u16>> {
+        let mut tokens = vec![vec![]; self.max_batch()];
+        for (key, input) in input {
+            if self.contains(key) {
+                tokens[key.batch] = input;
+            }
+        }
+        tokens
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Slots;
+
+    #[test]
+    fn test_slots() {
+        let mut slots = Slots::new(2);
+        let a = slots.allocate("a").unwrap();
+        let b = slots.allocate("b").unwrap();
+        assert_eq!((a.batch(), b.batch()), (0, 1));
+        assert_eq!(slots.allocate("c"), None);
+
+        assert_eq!(slots.free(a), Some("a"));
+        assert!(!slots.is_occupied(0));
+        let c = slots.allocate("c").unwrap();
+        assert_eq!(c.batch(), 0);
+
+        // the old key of batch 0 no longer reaches it
+        assert_eq!(slots.get(a), None);
+        assert_eq!(slots.free(a), None);
+        assert_eq!(slots.get(c), Some(&"c"));
+
+        let tokens = slots.tokens([(a, vec![1]), (b, vec![2]), (c, vec![3])]);
+        assert_eq!(tokens, vec![vec![3], vec![2]]);
+        assert_eq!(
+            slots
+                .occupied()
+                .map(|(_, value)| *value)
+                .collect::<Vec<_>>(),
+            ["c", "b"]
+        );
+    }
+}
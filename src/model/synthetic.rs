@@ -0,0 +1,353 @@
+//! Randomly initialized checkpoints, so that loading, quantization, LoRA and state handling
+//! can be exercised without downloading real weights.
+
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+use half::f16;
+use itertools::Itertools;
+use safetensors::{tensor::TensorView, Dtype};
+
+use super::{ModelInfo, ModelVersion};
+
+/// Builds a random checkpoint in the safetensors format, entirely in memory.
+/// The default is a 2-layer, 64-dim model.
+#[derive(Debug, Clone)]
+pub struct SyntheticBuilder {
+    info: ModelInfo,
+    seed: u64,
+    tied_embed: bool,
+}
+
+struct Tensors {
+    rng: Rng,
+    tensors: BTreeMap<String, (Vec<usize>, Vec<f16>)>,
+}
+
+/// A small xorshift generator, so that checkpoints are reproducible across platforms.
+struct Rng(u64);
+
+impl Rng {
+    fn f32(&mut self) -> f32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 >> 40) as f32 / (1u64 << 24) as f32
+    }
+}
+
+impl Tensors {
+    fn insert(&mut self, name: impl Into<String>, shape: Vec<usize>, low: f32, high: f32) {
+        let data = (0..shape.iter().product())
+            .map(|_| f16::from_f32(low + (high - low) * self.rng.f32()))
+            .collect();
+        self.tensors.insert(name.into(), (shape, data));
+    }
+
+    fn serialize(self) -> Result<Vec<u8>> {
+        let views: Vec<_> = self
+            .tensors
+            .iter()
+            .map(|(name, (shape, data))| {
+                TensorView::new(Dtype::F16, shape.clone(), bytemuck::cast_slice(data))
+                    .map(|view| (name, view))
+            })
+            .try_collect()?;
+        Ok(safetensors::serialize(views, &None)?)
+    }
+}
+
+impl SyntheticBuilder {
+    pub fn new(version: ModelVersion) -> Self {
+        Self {
+            info: ModelInfo {
+                version,
+                num_layer: 2,
+                num_emb: 64,
+                num_hidden: 256,
+                num_vocab: 256,
+                num_head: 1,
+            },
+            seed: 42,
+            tied_embed: false,
+        }
+    }
+
+    pub fn with_num_layer(mut self, value: usize) -> Self {
+        self.info.num_layer = value;
+        self
+    }
+
+    pub fn with_num_emb(mut self, value: usize) -> Self {
+        self.info.num_emb = value;
+        self
+    }
+
+    pub fn with_num_hidden(mut self, value: usize) -> Self {
+        self.info.num_hidden = value;
+        self
+    }
+
+    pub fn with_num_vocab(mut self, value: usize) -> Self {
+        self.info.num_vocab = value;
+        self
+    }
+
+    /// Number of heads of V5 models. Ignored for V4.
+    pub fn with_num_head(mut self, value: usize) -> Self {
+        self.info.num_head = value;
+        self
+    }
+
+    pub fn with_seed(self, seed: u64) -> Self {
+        Self { seed, ..self }
+    }
+
+    /// Omit `head.weight`, so that the head shares the weights of the embedding.
+    pub fn with_tied_embed(self, tied_embed: bool) -> Self {
+        Self { tied_embed, ..self }
+    }
+
+    /// The info of the model to be built, as [`Loader::info`](super::loader::Loader::info) would report.
+    pub fn info(&self) -> ModelInfo {
+        let num_head = match self.info.version {
+            ModelVersion::V4 => self.info.num_emb,
+            ModelVersion::V5 => self.info.num_head,
+        };
+        ModelInfo {
+            num_head,
+            ..self.info.clone()
+        }
+    }
+
+    fn tensors(&self) -> Tensors {
+        Tensors {
+            // xorshift gets stuck at zero
+            rng: Rng(self.seed.max(1)),
+            tensors: BTreeMap::new(),
+        }
+    }
+
+    /// Names and shapes (in safetensors order) of all matrices within layers.
+    fn layer_matrices(&self) -> Vec<(String, [usize; 2])> {
+        let ModelInfo {
+            version,
+            num_layer,
+            num_emb: c,
+            num_hidden: h,
+            ..
+        } = self.info;
+
+        (0..num_layer)
+            .flat_map(|layer| {
+                let att = format!("blocks.{layer}.att");
+                let ffn = format!("blocks.{layer}.ffn");
+                let mut matrices = vec![
+                    (format!("{att}.key.weight"), [c, c]),
+                    (format!("{att}.value.weight"), [c, c]),
+                    (format!("{att}.receptance.weight"), [c, c]),
+                    (format!("{att}.output.weight"), [c, c]),
+                    (format!("{ffn}.key.weight"), [h, c]),
+                    (format!("{ffn}.value.weight"), [c, h]),
+                    (format!("{ffn}.receptance.weight"), [c, c]),
+                ];
+                if version == ModelVersion::V5 {
+                    matrices.push((format!("{att}.gate.weight"), [c, c]));
+                }
+                matrices
+            })
+            .collect()
+    }
+
+    /// Names of all token-mixing vectors within layers.
+    fn layer_mixes(&self) -> Vec<String> {
+        (0..self.info.num_layer)
+            .flat_map(|layer| {
+                let att = format!("blocks.{layer}.att");
+                let ffn = format!("blocks.{layer}.ffn");
+                let mut mixes = vec![
+                    format!("{att}.time_mix_k"),
+                    format!("{att}.time_mix_v"),
+                    format!("{att}.time_mix_r"),
+                    format!("{ffn}.time_mix_k"),
+                    format!("{ffn}.time_mix_r"),
+                ];
+                if self.info.version == ModelVersion::V5 {
+                    mixes.push(format!("{att}.time_mix_g"));
+                }
+                mixes
+            })
+            .collect()
+    }
+
+    /// Build the serialized checkpoint.
+    pub fn build(self) -> Result<Vec<u8>> {
+        let ModelInfo {
+            version,
+            num_layer,
+            num_emb: c,
+            num_vocab: v,
+            num_head,
+            ..
+        } = self.info;
+        let scale = |fan_in: usize| 1.0 / (fan_in as f32).sqrt();
+        let mut tensors = self.tensors();
+
+        tensors.insert("emb.weight", vec![v, c], -1.0, 1.0);
+        if !self.tied_embed {
+            tensors.insert("head.weight", vec![v, c], -scale(c), scale(c));
+        }
+
+        let mut norms = vec!["blocks.0.ln0".to_string(), "ln_out".to_string()];
+        for layer in 0..num_layer {
+            let att = format!("blocks.{layer}.att");
+            norms.push(format!("blocks.{layer}.ln1"));
+            norms.push(format!("blocks.{layer}.ln2"));
+            match version {
+                ModelVersion::V4 => {
+                    tensors.insert(format!("{att}.time_decay"), vec![c], -2.0, 1.0);
+                    tensors.insert(format!("{att}.time_first"), vec![c], -0.5, 0.5);
+                }
+                ModelVersion::V5 => {
+                    let shape = vec![num_head, c / num_head];
+                    tensors.insert(format!("{att}.time_decay"), shape.clone(), -3.0, 0.0);
+                    tensors.insert(format!("{att}.time_first"), shape, -0.5, 0.5);
+                    norms.push(format!("{att}.ln_x"));
+                }
+            }
+        }
+        for name in norms {
+            tensors.insert(format!("{name}.weight"), vec![c], 0.9, 1.1);
+            tensors.insert(format!("{name}.bias"), vec![c], -0.1, 0.1);
+        }
+        for name in self.layer_mixes() {
+            tensors.insert(name, vec![c], 0.0, 1.0);
+        }
+        for (name, [rows, cols]) in self.layer_matrices() {
+            tensors.insert(name, vec![rows, cols], -scale(cols), scale(cols));
+        }
+
+        tensors.serialize()
+    }
+
+    /// Build a serialized LoRA of `rank` for the model, covering all layer matrices and token-mixing vectors.
+    /// `rank` must be a multiple of 4.
+    pub fn build_lora(self, rank: usize) -> Result<Vec<u8>> {
+        let scale = |fan_in: usize| 1.0 / (fan_in as f32).sqrt();
+        let mut tensors = self.tensors();
+
+        for name in self.layer_mixes() {
+            tensors.insert(name, vec![self.info.num_emb], 0.0, 1.0);
+        }
+        for (name, [rows, cols]) in self.layer_matrices() {
+            let (low, high) = (-scale(cols), scale(cols));
+            tensors.insert(format!("{name}.lora.0"), vec![rows, rank], low, high);
+            tensors.insert(format!("{name}.lora.1"), vec![cols, rank], low, high);
+        }
+
+        tensors.serialize()
+    }
+}
+
+/// Helpers for the tests that run synthetic checkpoints.
+#[cfg(test)]
+pub(crate) mod testing {
+    use std::convert::Infallible;
+
+    use anyhow::Result;
+    use itertools::Itertools;
+    use wgpu::PowerPreference;
+
+    use crate::{
+        context::{Context, ContextBuilder, Instance},
+        model::{loader::Loader, FromBuilder, Model, ModelBuilder, Quant, StateBuilder},
+    };
+
+    pub fn is_approx_eps(a: f32, b: f32, eps: f32) -> bool {
+        (a - b).abs() <= f32::max(eps, f32::max(a.abs(), b.abs()) * eps)
+    }
+
+    pub fn create_context() -> Result<Context> {
+        let adapter = pollster::block_on(async {
+            let instance = Instance::new();
+            instance.adapter(PowerPreference::HighPerformance).await
+        })?;
+        let context = pollster::block_on(async { ContextBuilder::new(adapter).build().await })?;
+        Ok(context)
+    }
+
+    /// Run `tokens` through the model until consumed, returning the logits of the last token of each batch.
+    pub fn run<M: Model>(
+        model: &M,
+        state: &M::ModelState,
+        tokens: &[Vec<u16>],
+    ) -> Result<Vec<Option<Vec<f32>>>> {
+        let mut tokens = tokens.to_vec();
+        let mut logits = vec![None; tokens.len()];
+        while tokens.iter().any(|tokens| !tokens.is_empty()) {
+            for (logits, output) in logits.iter_mut().zip_eq(model.run(&mut tokens, state)?) {
+                if output.is_some() {
+                    *logits = output;
+                }
+            }
+        }
+        Ok(logits)
+    }
+
+    /// Relative error of the logits as a whole, since individual ones are too noisy.
+    pub fn relative_error(logits: &[f32], expected: &[f32]) -> f32 {
+        let error = logits
+            .iter()
+            .zip_eq(expected.iter())
+            .map(|(a, b)| (a - b).powi(2))
+            .sum::<f32>();
+        let norm = expected.iter().map(|x| x.powi(2)).sum::<f32>();
+        (error / norm).sqrt()
+    }
+
+    /// Build a model with the given options and run `tokens` through a fresh state.
+    pub fn run_with<'a, M>(
+        context: &Context,
+        data: &'a [u8],
+        quant: Quant,
+        turbo: bool,
+        tokens: &[Vec<u16>],
+    ) -> Result<Vec<f32>>
+    where
+        M: Model + FromBuilder<Builder<'a> = ModelBuilder<'a>, Error = anyhow::Error>,
+        M::ModelState: FromBuilder<Builder<'a> = StateBuilder, Error = Infallible>,
+    {
+        let info = Loader::info(data)?;
+        let layers = (0..info.num_layer).map(|layer| (layer, quant)).collect();
+        let model: M = ModelBuilder::new(context, data)
+            .with_head_chunk_size(info.num_vocab)
+            .with_token_chunk_size(4)
+            .with_quant(layers)
+            .with_turbo(turbo)
+            .build()?;
+        let state: M::ModelState = StateBuilder::new(context, &info).build();
+        Ok(run(&model, &state, tokens)?.remove(0).unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use super::SyntheticBuilder;
+    use crate::model::{loader::Loader, ModelVersion};
+
+    #[test]
+    fn test_info() -> Result<()> {
+        for version in [ModelVersion::V4, ModelVersion::V5] {
+            let builder = SyntheticBuilder::new(version)
+                .with_num_layer(3)
+                .with_num_emb(128)
+                .with_num_head(2);
+            let info = builder.info();
+            let data = builder.build()?;
+            assert_eq!(Loader::info(&data)?, info);
+        }
+        Ok(())
+    }
+}
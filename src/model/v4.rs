@@ -1,51 +1,112 @@
-use std::{convert::Infallible, sync::Arc};
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    fmt,
+    ops::Range,
+    sync::{atomic::Ordering, Arc},
+};
 
 use anyhow::Result;
 use half::f16;
 use itertools::Itertools;
-use web_rwkv_derive::{Deref, DerefMut};
-use wgpu::{CommandEncoderDescriptor, ComputePassDescriptor};
+use wgpu::{BufferDescriptor, BufferUsages, CommandEncoderDescriptor, ComputePassDescriptor};
 
 use super::{
-    loader::Loader, matrix::Matrix, FromBuilder, ModelBuilder, ModelError, ModelInfo, Quant,
-    StateBuilder,
+    graph::{Graph, GraphBuilder},
+    host::HostLayers,
+    matrix::{Matrix, QuantizationReport},
+    ActivationStats, BuildProgress, FromBuilder, ModelBuilder, ModelError, ModelInfo, ModelVersion,
+    OpKind, Pooling, Quant, RunOutput, StateBuilder,
 };
 use crate::{
-    context::Context,
-    model::RESCALE_LAYER,
+    context::{Context, MemoryCategory},
+    model::{RESCALE_LAYER, TURBO_MIN_TOKEN},
+    num::{Float, Scalar},
     tensor::{
-        cache::ResourceCache,
-        ops::{TensorCommand, TensorOp, TensorPass},
+        arena::TensorArena,
+        cache::{CacheStats, ResourceCache},
+        ops::{TensorCommand, TensorOp, TensorPass, TensorSequence},
         shape::{Shape, TensorDimension},
         DeepClone, IntoPackedCursors, ReadBack, ReadWrite, TensorCpu, TensorError, TensorGpu,
         TensorInit, TensorReshape, TensorShape, TensorStack, TensorView,
     },
 };
 
+/// The RWKV v4 model, with activations stored as `F`.
 #[derive(Debug)]
-pub struct Model<'a> {
+pub struct Model<'a, F: Float = f32> {
     context: Context,
     info: ModelInfo,
 
-    /// Whether to half the activations every [`RESCALE_LAYER`] layers.
-    rescale: bool,
+    /// Half the activations every this many layers, if set.
+    rescale: Option<usize>,
     /// Whether to use fp16 GEMM for matmul computations.
     turbo: bool,
     /// The head matrix is too big for a storage buffer so it's divided into chunks.
     head_chunk_size: usize,
     /// To prevent the GPU device from lost, this limits the maximum batch-token it processes one time.
     token_chunk_size: usize,
-
-    tensor: ModelTensor<'a>,
-    runtime_cache: ResourceCache<usize, Runtime>,
-    output_cache: ResourceCache<usize, Output>,
+    /// Whether to record the commands of runs and replay them for runs of the same shape.
+    capture: bool,
+
+    /// Weights on the device, shared by every handle made by [`Model::share`].
+    tensor: Arc<ModelTensor<'a>>,
+    /// Reconstruction errors of quantized matrices, if requested by the builder.
+    quant_report: Option<QuantizationReport>,
+    runtime_cache: ResourceCache<usize, Runtime<F>>,
+    output_cache: ResourceCache<usize, Output<F>>,
     softmax_cache: ResourceCache<usize, Softmax>,
+    capture_cache: ResourceCache<CaptureKey, Capture<F>>,
+    /// Batches and tokens of the largest runs the reserved buffers are allocated for, if declared.
+    envelope: Option<(usize, usize)>,
+    reserve: Option<Reserve<F>>,
+    /// Custom ops replacing some of those of the layers.
+    overrides: OpOverrides<F>,
+    /// Statistics of the output of each layer, folded in on every run if set; see [`Model::with_activation_stats`].
+    stats: Option<TensorGpu<u32, ReadWrite>>,
+}
+
+/// Builds a replacement for an op of a layer; see [`Model::with_op_override`].
+pub type OpOverride<F> = Arc<dyn Fn(&OpSite<'_, F>) -> Result<TensorOp, TensorError> + Send + Sync>;
+
+/// The op to be replaced, and the buffers a replacement may bind.
+pub struct OpSite<'b, F: Float> {
+    pub layer: usize,
+    pub kind: OpKind,
+    /// Number of tokens of the run, over all batches.
+    pub num_token: usize,
+    pub runtime: &'b Runtime<F>,
+    pub state: &'b ModelState,
+    pub page: usize,
+}
+
+struct OpOverrides<F: Float>(HashMap<(usize, OpKind), OpOverride<F>>);
+
+impl<F: Float> Default for OpOverrides<F> {
+    fn default() -> Self {
+        Self(HashMap::new())
+    }
+}
+
+impl<F: Float> Clone for OpOverrides<F> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<F: Float> fmt::Debug for OpOverrides<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.0.keys()).finish()
+    }
 }
 
 #[derive(Debug)]
 struct ModelTensor<'a> {
     embed: Embed<'a>,
     head: Head,
+    /// The first layers, if run on the host.
+    host: HostLayers,
+    /// The layers on the device, after those on the host.
     layers: Vec<Layer>,
 }
 
@@ -88,6 +149,8 @@ struct Layer {
     ffn: Ffn,
 }
 
+/// The embedding matrix is kept in host memory: only the rows of the tokens in a run are looked up
+/// and uploaded, so it takes no device memory however large the vocabulary.
 #[derive(Debug)]
 struct Embed<'a> {
     layer_norm: LayerNorm,
@@ -100,33 +163,168 @@ struct Head {
     w: Vec<TensorGpu<f16, ReadWrite>>,
 }
 
-/// Runtime buffers.
+impl LayerNorm {
+    fn transfer(&self, arena: &TensorArena) -> Result<Self, TensorError> {
+        Ok(Self {
+            w: arena.transfer(&self.w)?,
+            b: arena.transfer(&self.b)?,
+        })
+    }
+
+    fn destroy(self) {
+        self.w.destroy();
+        self.b.destroy();
+    }
+}
+
+impl Att {
+    fn transfer(&self, to: &Context, arena: &TensorArena) -> Result<Self, TensorError> {
+        Ok(Self {
+            time_decay: arena.transfer(&self.time_decay)?,
+            time_first: arena.transfer(&self.time_first)?,
+            time_mix_k: arena.transfer(&self.time_mix_k)?,
+            time_mix_v: arena.transfer(&self.time_mix_v)?,
+            time_mix_r: arena.transfer(&self.time_mix_r)?,
+            w_k: self.w_k.transfer(to)?,
+            w_v: self.w_v.transfer(to)?,
+            w_r: self.w_r.transfer(to)?,
+            w_o: self.w_o.transfer(to)?,
+        })
+    }
+
+    fn destroy(self) {
+        let Self {
+            time_decay,
+            time_first,
+            time_mix_k,
+            time_mix_v,
+            time_mix_r,
+            w_k,
+            w_v,
+            w_r,
+            w_o,
+        } = self;
+        for tensor in [time_decay, time_first] {
+            tensor.destroy();
+        }
+        for tensor in [time_mix_k, time_mix_v, time_mix_r] {
+            tensor.destroy();
+        }
+        for matrix in [w_k, w_v, w_r, w_o] {
+            matrix.destroy();
+        }
+    }
+}
+
+impl Ffn {
+    fn transfer(&self, to: &Context, arena: &TensorArena) -> Result<Self, TensorError> {
+        Ok(Self {
+            time_mix_k: arena.transfer(&self.time_mix_k)?,
+            time_mix_r: arena.transfer(&self.time_mix_r)?,
+            w_k: self.w_k.transfer(to)?,
+            w_v: self.w_v.transfer(to)?,
+            w_r: self.w_r.transfer(to)?,
+        })
+    }
+
+    fn destroy(self) {
+        let Self {
+            time_mix_k,
+            time_mix_r,
+            w_k,
+            w_v,
+            w_r,
+        } = self;
+        for tensor in [time_mix_k, time_mix_r] {
+            tensor.destroy();
+        }
+        for matrix in [w_k, w_v, w_r] {
+            matrix.destroy();
+        }
+    }
+}
+
+impl<'a> ModelTensor<'a> {
+    /// Copy every weight to another context, through the host.
+    fn transfer(&self, to: &Context) -> Result<Self, TensorError> {
+        let arena = TensorArena::new(to);
+        let mut w = self.embed.w.clone();
+        w.context = to.clone();
+        let embed = Embed {
+            layer_norm: self.embed.layer_norm.transfer(&arena)?,
+            w,
+        };
+        let head = Head {
+            layer_norm: self.head.layer_norm.transfer(&arena)?,
+            w: self.head.w.iter().map(|w| w.transfer(to)).try_collect()?,
+        };
+        let layers = self
+            .layers
+            .iter()
+            .map(|layer| -> Result<_, TensorError> {
+                let layer = Layer {
+                    att_layer_norm: layer.att_layer_norm.transfer(&arena)?,
+                    ffn_layer_norm: layer.ffn_layer_norm.transfer(&arena)?,
+                    att: layer.att.transfer(to, &arena)?,
+                    ffn: layer.ffn.transfer(to, &arena)?,
+                };
+                // wait for the layer to be uploaded, so that its staging buffers are freed before the next is read
+                to.queue.submit(None);
+                to.device.poll(wgpu::MaintainBase::Wait);
+                Ok(layer)
+            })
+            .try_collect()?;
+        Ok(Self {
+            embed,
+            head,
+            host: self.host.clone(),
+            layers,
+        })
+    }
+
+    /// Free the device memory of every weight now.
+    fn destroy(self) {
+        self.embed.layer_norm.destroy();
+        self.head.layer_norm.destroy();
+        for w in self.head.w {
+            w.destroy();
+        }
+        for layer in self.layers {
+            layer.att_layer_norm.destroy();
+            layer.ffn_layer_norm.destroy();
+            layer.att.destroy();
+            layer.ffn.destroy();
+        }
+    }
+}
+
+/// Runtime buffers, holding the activations of a run.
 #[derive(Debug)]
-struct Runtime {
-    cursors: TensorGpu<u32, ReadWrite>,
-    input: TensorGpu<f32, ReadWrite>,
-
-    att_x: TensorGpu<f32, ReadWrite>,
-    att_kx: TensorGpu<f32, ReadWrite>,
-    att_vx: TensorGpu<f32, ReadWrite>,
-    att_rx: TensorGpu<f32, ReadWrite>,
-    att_k: TensorGpu<f32, ReadWrite>,
-    att_v: TensorGpu<f32, ReadWrite>,
-    att_r: TensorGpu<f32, ReadWrite>,
-    att_o: TensorGpu<f32, ReadWrite>,
-
-    ffn_x: TensorGpu<f32, ReadWrite>,
-    ffn_kx: TensorGpu<f32, ReadWrite>,
-    ffn_rx: TensorGpu<f32, ReadWrite>,
-    ffn_k: TensorGpu<f32, ReadWrite>,
-    ffn_v: TensorGpu<f32, ReadWrite>,
-    ffn_r: TensorGpu<f32, ReadWrite>,
-
-    half_x: TensorGpu<f16, ReadWrite>,
-    half_k: TensorGpu<f16, ReadWrite>,
+pub struct Runtime<F: Float> {
+    pub cursors: TensorGpu<u32, ReadWrite>,
+    pub input: TensorGpu<F, ReadWrite>,
+
+    pub att_x: TensorGpu<F, ReadWrite>,
+    pub att_kx: TensorGpu<F, ReadWrite>,
+    pub att_vx: TensorGpu<F, ReadWrite>,
+    pub att_rx: TensorGpu<F, ReadWrite>,
+    pub att_k: TensorGpu<F, ReadWrite>,
+    pub att_v: TensorGpu<F, ReadWrite>,
+    pub att_r: TensorGpu<F, ReadWrite>,
+    pub att_o: TensorGpu<F, ReadWrite>,
+
+    pub ffn_x: TensorGpu<F, ReadWrite>,
+    pub ffn_kx: TensorGpu<F, ReadWrite>,
+    pub ffn_rx: TensorGpu<F, ReadWrite>,
+    pub ffn_k: TensorGpu<F, ReadWrite>,
+    pub ffn_v: TensorGpu<F, ReadWrite>,
+    pub ffn_r: TensorGpu<F, ReadWrite>,
+
+    pub half_x: TensorGpu<f16, ReadWrite>,
+    pub half_k: TensorGpu<f16, ReadWrite>,
 }
 
-impl Runtime {
+impl<F: Float> Runtime<F> {
     pub fn new(context: &Context, info: &ModelInfo, num_token: usize, max_token: usize) -> Self {
         let shape = Shape::new(info.num_emb, num_token, 1, 1);
         let cursors_shape = Shape::new(max_token, 1, 1, 1);
@@ -153,16 +351,44 @@ impl Runtime {
             half_k: context.tensor_init(hidden_shape),
         }
     }
+
+    /// The buffers of a run of `num_token` tokens over the front of these, which must have room for them.
+    fn shrink(&self, num_token: usize, max_token: usize) -> Result<Self, TensorError> {
+        let shape = Shape::new(self.input.shape()[0], num_token, 1, 1);
+        let cursors_shape = Shape::new(max_token, 1, 1, 1);
+        let hidden_shape = Shape::new(self.ffn_k.shape()[0], num_token, 1, 1);
+
+        Ok(Self {
+            cursors: self.cursors.shrink(cursors_shape)?,
+            input: self.input.shrink(shape)?,
+            att_x: self.att_x.shrink(shape)?,
+            att_kx: self.att_kx.shrink(shape)?,
+            att_vx: self.att_vx.shrink(shape)?,
+            att_rx: self.att_rx.shrink(shape)?,
+            att_k: self.att_k.shrink(shape)?,
+            att_v: self.att_v.shrink(shape)?,
+            att_r: self.att_r.shrink(shape)?,
+            att_o: self.att_o.shrink(shape)?,
+            ffn_x: self.ffn_x.shrink(shape)?,
+            ffn_kx: self.ffn_kx.shrink(shape)?,
+            ffn_rx: self.ffn_rx.shrink(shape)?,
+            ffn_k: self.ffn_k.shrink(hidden_shape)?,
+            ffn_v: self.ffn_v.shrink(shape)?,
+            ffn_r: self.ffn_r.shrink(shape)?,
+            half_x: self.half_x.shrink(shape)?,
+            half_k: self.half_k.shrink(hidden_shape)?,
+        })
+    }
 }
 
 #[derive(Debug)]
-struct Output {
-    head_x: TensorGpu<f32, ReadWrite>,
+struct Output<F: Float> {
+    head_x: TensorGpu<F, ReadWrite>,
     head_o: TensorGpu<f32, ReadWrite>,
     map: TensorGpu<f32, ReadBack>,
 }
 
-impl Output {
+impl<F: Float> Output<F> {
     pub fn new(context: &Context, info: &ModelInfo, num_batch: usize) -> Self {
         let head_shape = Shape::new(info.num_emb, num_batch, 1, 1);
         let output_shape = Shape::new(info.num_vocab, num_batch, 1, 1);
@@ -173,11 +399,23 @@ impl Output {
             map: context.tensor_init(output_shape),
         }
     }
+
+    fn shrink(&self, num_batch: usize) -> Result<Self, TensorError> {
+        let head_shape = Shape::new(self.head_x.shape()[0], num_batch, 1, 1);
+        let output_shape = Shape::new(self.head_o.shape()[0], num_batch, 1, 1);
+
+        Ok(Self {
+            head_x: self.head_x.shrink(head_shape)?,
+            head_o: self.head_o.shrink(output_shape)?,
+            map: self.map.shrink(output_shape)?,
+        })
+    }
 }
 
 #[derive(Debug)]
 struct Softmax {
     buffer: TensorGpu<f32, ReadWrite>,
+    temperature: TensorGpu<f32, ReadWrite>,
     map: TensorGpu<f32, ReadBack>,
 }
 
@@ -186,30 +424,182 @@ impl Softmax {
         let shape = Shape::new(info.num_vocab, 1, num_batch, 1);
         Self {
             buffer: context.tensor_init(shape),
+            temperature: context.tensor_init(Shape::new(num_batch, 1, 1, 1)),
             map: context.tensor_init(shape),
         }
     }
+
+    fn shrink(&self, num_batch: usize) -> Result<Self, TensorError> {
+        let shape = Shape::new(self.buffer.shape()[0], 1, num_batch, 1);
+        Ok(Self {
+            buffer: self.buffer.shrink(shape)?,
+            temperature: self.temperature.shrink(Shape::new(num_batch, 1, 1, 1))?,
+            map: self.map.shrink(shape)?,
+        })
+    }
+}
+
+/// Runtime buffers allocated at build time for the largest runs of the envelope declared with
+/// [`ModelBuilder::with_envelope`]; runs within it take the front of these instead of allocating their own.
+#[derive(Debug)]
+struct Reserve<F: Float> {
+    runtime: Runtime<F>,
+    output: Output<F>,
+    softmax: Softmax,
+}
+
+impl<F: Float> Reserve<F> {
+    fn new(
+        context: &Context,
+        info: &ModelInfo,
+        envelope: (usize, usize),
+        max_token: usize,
+    ) -> Self {
+        let _scope = context.memory_scope(MemoryCategory::Runtime);
+        let (max_batch, num_token) = envelope;
+        let max_token = max_token.max(num_token);
+        Self {
+            runtime: Runtime::new(context, info, num_token, max_token),
+            output: Output::new(context, info, max_batch),
+            softmax: Softmax::new(context, info, max_batch),
+        }
+    }
+}
+
+/// What a captured run depends on besides the model: replaying it for another key would touch the wrong buffers.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct CaptureKey {
+    state: usize,
+    num_token: usize,
+    headers: Vec<usize>,
+    layers: Range<usize>,
+    normalize: bool,
+}
+
+/// The commands of a run, recorded once and replayed into the encoder of every run of the same [`CaptureKey`].
+#[derive(Debug)]
+struct Capture<F: Float> {
+    /// Keeps the state page alive, so that its address is not taken by another state while the capture is cached.
+    _page: TensorGpu<f32, ReadWrite>,
+    runtime: Arc<Runtime<F>>,
+    output: Arc<Output<F>>,
+    sequence: TensorSequence,
 }
 
-#[derive(Debug, Clone, Deref, DerefMut)]
-pub struct ModelState(TensorGpu<f32, ReadWrite>);
+#[derive(Debug, Clone)]
+pub struct ModelState {
+    context: Context,
+    info: ModelInfo,
+    max_batch: usize,
+    page_size: Option<usize>,
+    pages: Vec<TensorGpu<f32, ReadWrite>>,
+}
 
 impl ModelState {
-    fn att(&self, layer: usize) -> Result<TensorView<f32>, TensorError> {
+    fn new(
+        context: &Context,
+        info: &ModelInfo,
+        max_batch: usize,
+        page_size: Option<usize>,
+    ) -> Self {
+        let _scope = context.memory_scope(MemoryCategory::State);
+        let mut state = Self {
+            context: context.clone(),
+            info: info.clone(),
+            max_batch,
+            page_size,
+            pages: vec![],
+        };
+        let page_size = state.page_size();
+        state.pages = (0..max_batch)
+            .step_by(page_size)
+            .map(|start| {
+                let num_batch = page_size.min(max_batch - start);
+                let data = (0..num_batch)
+                    .map(|_| {
+                        (0..info.num_layer)
+                            .map(|_| {
+                                [
+                                    vec![0.0; info.num_emb],
+                                    vec![0.0; info.num_emb],
+                                    vec![0.0; info.num_emb],
+                                    vec![f32::MIN; info.num_emb],
+                                    vec![0.0; info.num_emb],
+                                ]
+                                .concat()
+                            })
+                            .collect_vec()
+                            .concat()
+                    })
+                    .collect_vec()
+                    .concat();
+                context
+                    .tensor_from_data(
+                        Shape::new(info.num_emb, 5 * info.num_layer, num_batch, 1),
+                        data,
+                    )
+                    .unwrap()
+            })
+            .collect();
+        state
+    }
+
+    /// The attention state of `layer` in `page`.
+    pub fn att(&self, page: usize, layer: usize) -> Result<TensorView<f32>, TensorError> {
         let start = 5 * layer;
         let end = start + 4;
-        self.view(.., start..end, .., ..)
+        self.pages[page].view(.., start..end, .., ..)
     }
 
-    fn ffn(&self, layer: usize) -> Result<TensorView<f32>, TensorError> {
+    /// The FFN state of `layer` in `page`.
+    pub fn ffn(&self, page: usize, layer: usize) -> Result<TensorView<f32>, TensorError> {
         let start = 5 * layer + 4;
-        self.view(.., start..=start, .., ..)
+        self.pages[page].view(.., start..=start, .., ..)
+    }
+
+    /// Address of the buffer of `page`, which tells it apart from those of other states while it is alive.
+    #[inline]
+    fn page_id(&self, page: usize) -> usize {
+        Arc::as_ptr(&self.pages[page].data().buffer) as usize
+    }
+
+    /// Number of batches in each page but the last.
+    #[inline]
+    fn page_size(&self) -> usize {
+        self.page_size.unwrap_or(self.max_batch).max(1)
+    }
+
+    /// The page holding `batch`, and the index of the batch within it.
+    #[inline]
+    fn locate(&self, batch: usize) -> Result<(usize, usize), TensorError> {
+        let page_size = self.page_size();
+        match batch < self.max_batch {
+            true => Ok((batch / page_size, batch % page_size)),
+            false => Err(TensorError::BatchOutOfRange {
+                batch,
+                max: self.max_batch,
+            }),
+        }
+    }
+
+    #[inline]
+    fn shape(&self) -> Shape {
+        Shape::new(
+            self.info.num_emb,
+            5 * self.info.num_layer,
+            self.max_batch,
+            1,
+        )
     }
 }
 
 impl DeepClone for ModelState {
     fn deep_clone(&self) -> Self {
-        Self(self.0.deep_clone())
+        let pages = self.pages.iter().map(|page| page.deep_clone()).collect();
+        Self {
+            pages,
+            ..self.clone()
+        }
     }
 }
 
@@ -222,33 +612,10 @@ impl FromBuilder for ModelState {
             context,
             info,
             max_batch,
+            page_size,
             ..
         } = builder;
-        let data = (0..max_batch)
-            .map(|_| {
-                (0..info.num_layer)
-                    .map(|_| {
-                        [
-                            vec![0.0; info.num_emb],
-                            vec![0.0; info.num_emb],
-                            vec![0.0; info.num_emb],
-                            vec![f32::MIN; info.num_emb],
-                            vec![0.0; info.num_emb],
-                        ]
-                        .concat()
-                    })
-                    .collect_vec()
-                    .concat()
-            })
-            .collect_vec()
-            .concat();
-        let state = context
-            .tensor_from_data(
-                Shape::new(info.num_emb, 5 * info.num_layer, max_batch, 1),
-                data,
-            )
-            .unwrap();
-        Ok(Self(state))
+        Ok(Self::new(&context, &info, max_batch, page_size))
     }
 }
 
@@ -262,7 +629,7 @@ impl super::ModelState for ModelState {
 
     #[inline]
     fn max_batch(&self) -> usize {
-        self.0.shape()[2]
+        self.max_batch
     }
 
     fn load(&self, backed: &Self::BackedState) -> Result<()> {
@@ -270,8 +637,15 @@ impl super::ModelState for ModelState {
         if backed.max_batch() != self.max_batch() {
             return Err(ModelError::BatchSize(backed.max_batch(), self.max_batch()).into());
         }
-        let host = self.context.tensor_from_data(self.shape(), &backed.data)?;
-        self.0.load(&host).map_err(|err| err.into())
+        let stride = backed.shape[0] * backed.shape[1];
+        for (index, page) in self.pages.iter().enumerate() {
+            let start = index * self.page_size();
+            let end = start + page.shape()[2];
+            let data = &backed.data[start * stride..end * stride];
+            let host = self.context.tensor_from_data(page.shape(), data)?;
+            page.load(&host)?;
+        }
+        Ok(())
     }
 
     fn load_batch(&self, backed: &Self::BackedState, batch: usize) -> Result<()> {
@@ -279,38 +653,43 @@ impl super::ModelState for ModelState {
         if backed.max_batch() != 1 {
             return Err(ModelError::BatchSize(backed.max_batch(), 1).into());
         }
+        let (page, batch) = self.locate(batch)?;
         let shape = self.shape();
         let shape = Shape::new(shape[0], shape[1], 1, 1);
         let host = self.context.tensor_from_data(shape, &backed.data)?;
-        self.0.load_batch(&host, batch).map_err(|err| err.into())
+        self.pages[page]
+            .load_batch(&host, batch)
+            .map_err(|err| err.into())
     }
 
     fn back(&self) -> Self::BackedState {
-        let shape = self.shape();
-        let map = self.context.tensor_init(shape);
+        let maps = self
+            .pages
+            .iter()
+            .map(|page| self.context.tensor_init(page.shape()))
+            .collect_vec();
 
         let mut encoder = self
             .context
             .device
             .create_command_encoder(&CommandEncoderDescriptor::default());
-        encoder.copy_tensor(self, &map).expect("back entire state");
+        for (page, map) in self.pages.iter().zip(maps.iter()) {
+            encoder.copy_tensor(page, map).expect("back entire state");
+        }
         self.context.queue.submit(Some(encoder.finish()));
 
-        let host = TensorCpu::from(map);
+        let data = maps
+            .into_iter()
+            .flat_map(|map| TensorCpu::from(map).to_vec())
+            .collect();
         BackedState {
-            shape,
-            data: host.to_vec(),
+            shape: self.shape(),
+            data,
         }
     }
 
     fn back_batch(&self, batch: usize) -> Result<Self::BackedState> {
-        if batch >= self.max_batch() {
-            return Err(ModelError::BatchOutOfRange {
-                batch,
-                max: self.max_batch(),
-            }
-            .into());
-        }
+        let (page, batch) = self.locate(batch)?;
 
         let shape = self.shape();
         let shape = Shape::new(shape[0], shape[1], 1, 1);
@@ -320,7 +699,7 @@ impl super::ModelState for ModelState {
             .context
             .device
             .create_command_encoder(&CommandEncoderDescriptor::default());
-        encoder.copy_tensor_batch(self, &map, batch)?;
+        encoder.copy_tensor_batch(&self.pages[page], &map, batch)?;
         self.context.queue.submit(Some(encoder.finish()));
 
         let host = TensorCpu::from(map);
@@ -331,33 +710,73 @@ impl super::ModelState for ModelState {
     }
 
     fn blit(&self, other: &Self) -> Result<(), TensorError> {
+        if self.pages.len() != other.pages.len() {
+            return Err(TensorError::Shape(self.shape(), other.shape()));
+        }
         let mut encoder = self
             .context
             .device
             .create_command_encoder(&CommandEncoderDescriptor::default());
-        encoder.copy_tensor(self, other)?;
+        for (page, other) in self.pages.iter().zip(other.pages.iter()) {
+            encoder.copy_tensor(page, other)?;
+        }
         self.context.queue.submit(Some(encoder.finish()));
         Ok(())
     }
 
+    fn resize(&self, num_batch: usize) -> Result<Self> {
+        let state = Self::new(&self.context, &self.info, num_batch, self.page_size);
+        let mut encoder = self
+            .context
+            .device
+            .create_command_encoder(&CommandEncoderDescriptor::default());
+        // pages of both states are of the same size, so batches keep their place within a page
+        for (page, other) in self.pages.iter().zip(state.pages.iter()) {
+            let num_batch = page.shape()[2].min(other.shape()[2]);
+            let size = (f32::size() * page.shape()[0] * page.shape()[1] * num_batch) as u64;
+            encoder.copy_buffer_to_buffer(&page.buffer, 0, &other.buffer, 0, size);
+        }
+        self.context.queue.submit(Some(encoder.finish()));
+        Ok(state)
+    }
+
     fn blit_batch(
         &self,
         other: &Self,
         from_batch: usize,
         to_batch: usize,
     ) -> Result<(), TensorError> {
-        let op = TensorOp::blit(
-            self.view(.., .., from_batch, ..)?,
-            other.view(.., .., to_batch, ..)?,
-        )?;
+        let (from_page, from_batch) = self.locate(from_batch)?;
+        let (to_page, to_batch) = other.locate(to_batch)?;
+        let (tensor, other) = (&self.pages[from_page], &other.pages[to_page]);
+        let shape = tensor.shape();
+        let other_shape = other.shape();
+        if shape[0] != other_shape[0] || shape[1] != other_shape[1] {
+            return Err(TensorError::Shape(shape, other_shape));
+        }
+
+        // batches are contiguous, so the buffer range is copied instead of blitting views, which can't bind
+        // the same buffer twice when copying between batches of one page
+        let size = (f32::size() * shape[0] * shape[1]) as u64;
+        let from = tensor.offset + size * from_batch as u64;
+        let to = other.offset + size * to_batch as u64;
         let mut encoder = self
             .context
             .device
             .create_command_encoder(&CommandEncoderDescriptor::default());
-
-        let mut pass = encoder.begin_compute_pass(&ComputePassDescriptor::default());
-        pass.execute_tensor_op(&op);
-        drop(pass);
+        if Arc::ptr_eq(&tensor.buffer, &other.buffer) {
+            // a buffer cannot be copied onto itself, so go through a temporary one
+            let temp = self.context.device.create_buffer(&BufferDescriptor {
+                label: None,
+                size,
+                usage: BufferUsages::COPY_SRC | BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            });
+            encoder.copy_buffer_to_buffer(&tensor.buffer, from, &temp, 0, size);
+            encoder.copy_buffer_to_buffer(&temp, 0, &other.buffer, to, size);
+        } else {
+            encoder.copy_buffer_to_buffer(&tensor.buffer, from, &other.buffer, to, size);
+        }
 
         self.context.queue.submit(Some(encoder.finish()));
         Ok(())
@@ -409,31 +828,408 @@ impl super::BackedState for BackedState {
 
     #[inline]
     fn num_layer(&self) -> usize {
-        self.shape[1]
+        self.shape[1] / 5
     }
 
     fn embed(&self, batch: usize, layer: usize) -> Vec<f32> {
         let num_emb = self.shape[0];
-        let num_layer = self.shape[1];
+        let num_layer = self.num_layer();
 
         let start = ((batch * num_layer + layer) * 5 + 4) * num_emb;
         let end = start + num_emb;
 
         self.data[start..end].to_vec()
     }
+
+    fn tensors(&self) -> Vec<(Shape, &[f32])> {
+        vec![(self.shape, &self.data)]
+    }
+
+    fn load_tensors(&mut self, tensors: Vec<Vec<f32>>) -> Result<(), TensorError> {
+        let [data] = <[_; 1]>::try_from(tensors).map_err(|x| TensorError::Size(x.len(), 1))?;
+        if data.len() != self.shape.len() {
+            return Err(TensorError::Size(data.len(), self.shape.len()));
+        }
+        self.data = data;
+        Ok(())
+    }
 }
 
-impl<'a> Model<'a> {
+impl<'a, F: Float> Model<'a, F> {
+    /// Reconstruction errors of quantized matrices, if [`ModelBuilder::with_quant_report`] was set.
+    #[inline]
+    pub fn quant_report(&self) -> Option<&QuantizationReport> {
+        self.quant_report.as_ref()
+    }
+
+    /// Maximum number of tokens processed in one run.
+    #[inline]
+    pub fn token_chunk_size(&self) -> usize {
+        self.token_chunk_size
+    }
+
+    /// Number of rows of the head stored in each of its buffers.
+    #[inline]
+    pub fn head_chunk_size(&self) -> usize {
+        self.head_chunk_size
+    }
+
+    /// Another handle to the same model, which reuses its weights on the device instead of uploading them again.
+    ///
+    /// The handle has runtime buffers of its own, so it can be run alongside this one (see [`Context`]),
+    /// and its runtime options can be set apart with [`Model::with_token_chunk_size`] and [`Model::with_turbo`].
+    /// Options baked into the weights, such as quantization, rescaling and head chunking, are those of this model.
+    pub fn share(&self) -> Self {
+        Self {
+            context: self.context.clone(),
+            info: self.info.clone(),
+            rescale: self.rescale,
+            turbo: self.turbo,
+            head_chunk_size: self.head_chunk_size,
+            token_chunk_size: self.token_chunk_size,
+            capture: self.capture,
+            tensor: self.tensor.clone(),
+            quant_report: self.quant_report.clone(),
+            runtime_cache: ResourceCache::new(1),
+            output_cache: ResourceCache::new(1),
+            softmax_cache: ResourceCache::new(1),
+            capture_cache: ResourceCache::new(4),
+            envelope: self.envelope,
+            reserve: self.envelope.map(|envelope| {
+                Reserve::new(&self.context, &self.info, envelope, self.token_chunk_size)
+            }),
+            overrides: self.overrides.clone(),
+            stats: None,
+        }
+    }
+
+    /// Rebuild the model on another context, e.g. on a discrete GPU that became available, by copying its weights there.
+    /// This model is left as it is; destroy it once the transferred one is in use.
+    pub fn transfer(&self, to: &Context) -> Result<Self> {
+        let tensor = {
+            let _scope = to.memory_scope(MemoryCategory::Weights);
+            self.tensor.transfer(to)?
+        };
+        Ok(Self {
+            context: to.clone(),
+            info: self.info.clone(),
+            rescale: self.rescale,
+            turbo: self.turbo,
+            head_chunk_size: self.head_chunk_size,
+            token_chunk_size: self.token_chunk_size,
+            capture: self.capture,
+            tensor: tensor.into(),
+            quant_report: self.quant_report.clone(),
+            runtime_cache: ResourceCache::new(1),
+            output_cache: ResourceCache::new(1),
+            softmax_cache: ResourceCache::new(1),
+            capture_cache: ResourceCache::new(4),
+            envelope: self.envelope,
+            reserve: self
+                .envelope
+                .map(|envelope| Reserve::new(to, &self.info, envelope, self.token_chunk_size)),
+            overrides: self.overrides.clone(),
+            stats: None,
+        })
+    }
+
+    /// Describe the ops a run of `num_token` tokens spread over `num_batch` batches dispatches, and the tensors they touch,
+    /// when the last token of each batch is output. See [`Graph::to_json`] for exporting it.
+    pub fn graph(&self, num_batch: usize, num_token: usize) -> Graph {
+        let info = &self.info;
+        let tensor = &self.tensor;
+        let turbo = self.use_turbo(num_token);
+        let num_header = num_batch.min(num_token);
+
+        let mut graph = GraphBuilder::new(ModelVersion::V4, num_batch, num_token);
+        let shape = Shape::new(info.num_emb, num_token, 1, 1);
+        let hidden_shape = Shape::new(info.num_hidden, num_token, 1, 1);
+        let cursors = graph.tensor::<u32>("cursors", Shape::new(self.token_chunk_size, 1, 1, 1));
+        let mut activation = |name: &str| graph.tensor::<F>(name, shape);
+        let input = activation("input");
+        let att_x = activation("att_x");
+        let att_kx = activation("att_kx");
+        let att_vx = activation("att_vx");
+        let att_rx = activation("att_rx");
+        let att_k = activation("att_k");
+        let att_v = activation("att_v");
+        let att_r = activation("att_r");
+        let att_o = activation("att_o");
+        let ffn_x = activation("ffn_x");
+        let ffn_kx = activation("ffn_kx");
+        let ffn_rx = activation("ffn_rx");
+        let ffn_v = activation("ffn_v");
+        let ffn_r = activation("ffn_r");
+        let ffn_k = graph.tensor::<F>("ffn_k", hidden_shape);
+        let half_x = graph.tensor::<f16>("half_x", shape);
+        let half_k = graph.tensor::<f16>("half_k", hidden_shape);
+
+        // with host layers, the embedding is normalized on the host along with them
+        if tensor.host.is_empty() {
+            graph.stage("embed");
+            let layer_norm = &tensor.embed.layer_norm;
+            graph.norm(
+                "layer_norm",
+                "blocks.0.ln0",
+                &layer_norm.w,
+                &layer_norm.b,
+                &input,
+            );
+        }
+
+        for (index, layer) in (tensor.host.len()..).zip(&tensor.layers) {
+            graph.stage(format!("blocks.{index}"));
+            let state_att = graph.tensor::<f32>(
+                format!("state.{index}.att"),
+                Shape::new(info.num_emb, 4, num_batch, 1),
+            );
+            let state_ffn = graph.tensor::<f32>(
+                format!("state.{index}.ffn"),
+                Shape::new(info.num_emb, 1, num_batch, 1),
+            );
+
+            let att = format!("blocks.{index}.att");
+            graph.copy(&input, &att_x);
+            let layer_norm = &layer.att_layer_norm;
+            let name = format!("blocks.{index}.ln1");
+            graph.norm("layer_norm", &name, &layer_norm.w, &layer_norm.b, &att_x);
+            for (name, mix, output) in [
+                ("time_mix_k", &layer.att.time_mix_k, &att_kx),
+                ("time_mix_v", &layer.att.time_mix_v, &att_vx),
+                ("time_mix_r", &layer.att.time_mix_r, &att_rx),
+            ] {
+                let mix = graph.weight(format!("{att}.{name}"), mix);
+                graph.op(
+                    "token_shift",
+                    &[&cursors, &mix, &att_x, &state_att],
+                    &[output],
+                );
+            }
+            for (name, matrix, input, output) in [
+                ("key", &layer.att.w_k, &att_kx, &att_k),
+                ("value", &layer.att.w_v, &att_vx, &att_v),
+                ("receptance", &layer.att.w_r, &att_rx, &att_r),
+            ] {
+                let name = format!("{att}.{name}.weight");
+                graph.matmul(matrix, &name, &half_x, input, output, turbo);
+            }
+            let time_decay = graph.weight(format!("{att}.time_decay"), &layer.att.time_decay);
+            let time_first = graph.weight(format!("{att}.time_first"), &layer.att.time_first);
+            graph.op(
+                "time_mix",
+                &[
+                    &cursors,
+                    &time_decay,
+                    &time_first,
+                    &att_k,
+                    &att_v,
+                    &att_r,
+                    &att_x,
+                    &state_att,
+                ],
+                &[&att_x, &state_att],
+            );
+            let name = format!("{att}.output.weight");
+            graph.matmul(&layer.att.w_o, &name, &half_x, &att_x, &att_o, false);
+            graph.op("add", &[&input, &att_o], &[&att_o]);
+
+            let ffn = format!("blocks.{index}.ffn");
+            graph.copy(&att_o, &ffn_x);
+            let layer_norm = &layer.ffn_layer_norm;
+            let name = format!("blocks.{index}.ln2");
+            graph.norm("layer_norm", &name, &layer_norm.w, &layer_norm.b, &ffn_x);
+            for (name, mix, output) in [
+                ("time_mix_k", &layer.ffn.time_mix_k, &ffn_kx),
+                ("time_mix_r", &layer.ffn.time_mix_r, &ffn_rx),
+            ] {
+                let mix = graph.weight(format!("{ffn}.{name}"), mix);
+                graph.op(
+                    "token_shift",
+                    &[&cursors, &mix, &ffn_x, &state_ffn],
+                    &[output],
+                );
+            }
+            let name = format!("{ffn}.key.weight");
+            graph.matmul(&layer.ffn.w_k, &name, &half_x, &ffn_kx, &ffn_k, turbo);
+            graph.op("squared_relu", &[&ffn_k], &[&ffn_k]);
+            let name = format!("{ffn}.value.weight");
+            graph.matmul(&layer.ffn.w_v, &name, &half_k, &ffn_k, &ffn_v, turbo);
+            let name = format!("{ffn}.receptance.weight");
+            graph.matmul(&layer.ffn.w_r, &name, &half_x, &ffn_rx, &ffn_r, turbo);
+            graph.op(
+                "channel_mix",
+                &[&cursors, &ffn_r, &ffn_v, &ffn_x, &state_ffn],
+                &[&ffn_x, &state_ffn],
+            );
+            graph.op("add", &[&att_o, &ffn_x], &[&ffn_x]);
+
+            if self
+                .rescale
+                .is_some_and(|every| (index + 1).is_multiple_of(every))
+            {
+                graph.op("half", &[&ffn_x], &[&ffn_x]);
+            }
+            if index != info.num_layer - 1 {
+                graph.copy(&ffn_x, &input);
+            }
+        }
+
+        graph.stage("head");
+        let head_x = match num_token == 1 || num_token == num_header {
+            true => ffn_x,
+            false => {
+                let head_x =
+                    graph.tensor::<F>("head_x", Shape::new(info.num_emb, num_header, 1, 1));
+                graph.op("blit", &[&ffn_x], &[&head_x]);
+                head_x
+            }
+        };
+        let head_shape = Shape::new(info.num_vocab, num_header, 1, 1);
+        let head_o = graph.tensor::<f32>("head_o", head_shape);
+        let map = graph.tensor::<f32>("map", head_shape);
+        let layer_norm = &tensor.head.layer_norm;
+        graph.norm(
+            "layer_norm",
+            "ln_out",
+            &layer_norm.w,
+            &layer_norm.b,
+            &head_x,
+        );
+        for (chunk, matrix) in tensor.head.w.iter().enumerate() {
+            let matrix = graph.weight(format!("head.weight.{chunk}"), matrix);
+            graph.op("matmul_vec_fp16", &[&matrix, &head_x], &[&head_o]);
+        }
+        graph.copy(&head_o, &map);
+
+        graph.build()
+    }
+
+    /// Set the maximum number of tokens processed in one run, which must be a power of 2.
+    pub fn with_token_chunk_size(self, value: usize) -> Result<Self> {
+        if !value.is_power_of_two() {
+            return Err(ModelError::InvalidChunkSize(value).into());
+        }
+        self.runtime_cache.clear();
+        self.capture_cache.clear();
+        Ok(Self {
+            token_chunk_size: value,
+            ..self
+        })
+    }
+
+    /// Whether to use fp16 GEMM for matmul computations.
+    pub fn with_turbo(self, value: bool) -> Self {
+        self.capture_cache.clear();
+        Self {
+            turbo: value,
+            ..self
+        }
+    }
+
+    /// Whether a run of `num_token` tokens over all batches multiplies them as matrices.
+    fn use_turbo(&self, num_token: usize) -> bool {
+        self.turbo && num_token >= TURBO_MIN_TOKEN.min(self.token_chunk_size)
+    }
+
+    /// Replace the op of `kind` in `layer` with one built by `op`, e.g. to try out a custom kernel.
+    /// The replacement is built whenever a run of a new shape is recorded, and should read and write
+    /// the same buffers as the op it replaces. Registering another for the same place replaces it.
+    pub fn with_op_override(
+        self,
+        layer: usize,
+        kind: OpKind,
+        op: impl Fn(&OpSite<'_, F>) -> Result<TensorOp, TensorError> + Send + Sync + 'static,
+    ) -> Self {
+        self.capture_cache.clear();
+        let mut overrides = self.overrides;
+        overrides.0.insert((layer, kind), Arc::new(op));
+        Self { overrides, ..self }
+    }
+
+    /// The replacement registered for the op at `site`, or `op` itself if there is none.
+    fn override_op(&self, op: TensorOp, site: OpSite<'_, F>) -> Result<TensorOp, TensorError> {
+        match self.overrides.0.get(&(site.layer, site.kind)) {
+            Some(build) => build(&site),
+            None => Ok(op),
+        }
+    }
+
+    /// Whether to collect the min, max, mean and absolute max of the output of each layer on every run, e.g. to find
+    /// where activations overflow `f16` or where quantization hurts. Read them with [`Model::activation_stats`].
+    /// Turning it on starts afresh; handles from [`Model::share`] collect their own.
+    pub fn with_activation_stats(self, value: bool) -> Self {
+        self.capture_cache.clear();
+        let stats = value.then(|| {
+            let shape = Shape::new(8, self.info.num_layer, 1, 1);
+            let data = TensorOp::STATS_INIT.repeat(self.info.num_layer);
+            self.context
+                .tensor_from_data(shape, data)
+                .expect("stats shape matches its data")
+        });
+        Self { stats, ..self }
+    }
+
+    /// Statistics of the output of each layer over all runs since [`Model::with_activation_stats`]
+    /// or [`Model::reset_activation_stats`], if collected. This waits for the runs to finish.
+    pub fn activation_stats(&self) -> Result<Option<Vec<ActivationStats>>> {
+        let Some(stats) = &self.stats else {
+            return Ok(None);
+        };
+        let raw = Vec::from(stats.back());
+        Ok(Some(ActivationStats::from_raw(&raw)))
+    }
+
+    /// Clear the collected statistics, if any, so that they cover only the runs from now on.
+    pub fn reset_activation_stats(&self) -> Result<()> {
+        if let Some(stats) = &self.stats {
+            let data = TensorOp::STATS_INIT.repeat(self.info.num_layer);
+            stats.load(&self.context.tensor_from_data(stats.shape(), data)?)?;
+        }
+        Ok(())
+    }
+
+    /// Free the device memory of the weights and of the cached runtime buffers now,
+    /// instead of leaving it to the driver, so that models can be loaded and unloaded repeatedly.
+    ///
+    /// Runtime buffers still in use (e.g. by a run on another thread) are freed once released.
+    /// Weights still used by another handle from [`Model::share`] are left to it.
+    pub fn destroy(self) {
+        self.runtime_cache.clear();
+        self.output_cache.clear();
+        self.softmax_cache.clear();
+        self.capture_cache.clear();
+        if let Ok(tensor) = Arc::try_unwrap(self.tensor) {
+            tensor.destroy();
+        }
+        self.context.device.poll(wgpu::MaintainBase::Wait);
+    }
+
+    /// Hits and misses of the recorded runs, if built [`ModelBuilder::with_capture`].
+    pub fn capture_stats(&self) -> CacheStats {
+        self.capture_cache.stats()
+    }
+
     #[inline]
-    fn request_runtime(&self, num_token: usize) -> Arc<Runtime> {
+    fn request_runtime(&self, num_token: usize) -> Arc<Runtime<F>> {
         self.runtime_cache.request(num_token, || {
+            // runs within the envelope take the front of the reserved buffers
+            let reserve = self.reserve.as_ref();
+            let max_token = self.token_chunk_size;
+            if let Some(Ok(runtime)) = reserve.map(|x| x.runtime.shrink(num_token, max_token)) {
+                return runtime;
+            }
+            let _scope = self.context.memory_scope(MemoryCategory::Runtime);
             Runtime::new(&self.context, &self.info, num_token, self.token_chunk_size)
         })
     }
 
     #[inline]
-    fn request_output(&self, num_batch: usize) -> Arc<Output> {
+    fn request_output(&self, num_batch: usize) -> Arc<Output<F>> {
         self.output_cache.request(num_batch, || {
+            if let Some(Ok(output)) = self.reserve.as_ref().map(|x| x.output.shrink(num_batch)) {
+                return output;
+            }
+            let _scope = self.context.memory_scope(MemoryCategory::Runtime);
             Output::new(&self.context, &self.info, num_batch)
         })
     }
@@ -441,35 +1237,145 @@ impl<'a> Model<'a> {
     #[inline]
     fn request_softmax(&self, num_batch: usize) -> Arc<Softmax> {
         self.softmax_cache.request(num_batch, || {
+            if let Some(Ok(softmax)) = self.reserve.as_ref().map(|x| x.softmax.shrink(num_batch)) {
+                return softmax;
+            }
+            let _scope = self.context.memory_scope(MemoryCategory::Runtime);
             Softmax::new(&self.context, &self.info, num_batch)
         })
     }
 
-    #[inline]
-    fn head_shape(&self, num_batch: usize) -> Shape {
-        Shape::new(self.info.num_vocab, 1, num_batch, 1)
-    }
+    fn softmax_internal(
+        &self,
+        input: Vec<Option<Vec<f32>>>,
+        temperature: Option<&[f32]>,
+    ) -> Result<Vec<Option<Vec<f32>>>> {
+        let max_batch = input.len();
+        if let Some(temperature) = temperature {
+            if temperature.len() != max_batch {
+                return Err(ModelError::BatchSize(temperature.len(), max_batch).into());
+            }
+        }
+
+        let mut redirect = vec![None; max_batch];
+        let input: Vec<_> = input
+            .into_iter()
+            .enumerate()
+            .filter_map(|(batch, data)| data.map(|data| (batch, data)))
+            .map(|(batch, data)| {
+                TensorCpu::from_data(&self.context, self.head_shape(1), data)
+                    .map(|tensor| (batch, tensor))
+            })
+            .try_collect()?;
+        let input = TensorCpu::stack(
+            input
+                .into_iter()
+                .enumerate()
+                .map(|(index, (batch, tensor))| {
+                    redirect[batch] = Some(index);
+                    tensor
+                })
+                .collect_vec(),
+        )?;
+
+        let num_batch = input.shape()[2];
+        let softmax = self.request_softmax(num_batch);
+        let _submission = self.context.submission();
+        softmax.buffer.load(&input)?;
+
+        let op = match temperature {
+            Some(temperature) => {
+                let temperature = temperature
+                    .iter()
+                    .zip_eq(redirect.iter())
+                    .filter_map(|(temperature, redirect)| redirect.map(|_| *temperature))
+                    .collect_vec();
+                let temperature = TensorCpu::from_data(
+                    &self.context,
+                    Shape::new(num_batch, 1, 1, 1),
+                    temperature,
+                )?;
+                softmax.temperature.load(&temperature)?;
+                TensorOp::softmax_temperature(&softmax.buffer, &softmax.temperature)?
+            }
+            None => TensorOp::softmax(&softmax.buffer)?,
+        };
+
+        let mut encoder = self
+            .context
+            .device
+            .create_command_encoder(&CommandEncoderDescriptor::default());
+
+        let mut pass = encoder.begin_compute_pass(&ComputePassDescriptor::default());
+        pass.execute_tensor_op(&op);
+        drop(pass);
+
+        encoder.copy_tensor(&softmax.buffer, &softmax.map)?;
+        self.context.queue.submit(Some(encoder.finish()));
+
+        let output = TensorCpu::from(softmax.map.clone());
+        let mut output = output
+            .iter_batches()
+            .map(|tensor| Some(tensor.to_vec()))
+            .collect_vec();
+
+        let mut probs = vec![None; max_batch];
+        for (probs, redirect) in probs.iter_mut().zip_eq(redirect.into_iter()) {
+            if let Some(redirect) = redirect {
+                std::mem::swap(probs, &mut output[redirect]);
+            }
+        }
 
+        Ok(probs)
+    }
+
+    /// Fail on the first token that isn't within the vocabulary, so that the embeddings can be looked up unchecked.
+    fn check_tokens<'t>(
+        &self,
+        tokens: impl IntoIterator<Item = &'t u16>,
+    ) -> Result<(), ModelError> {
+        let max = self.info.num_vocab;
+        match tokens.into_iter().find(|&&token| token as usize >= max) {
+            Some(&token) => Err(ModelError::TokenOutOfRange { token, max }),
+            None => Ok(()),
+        }
+    }
+
+    #[inline]
+    fn head_shape(&self, num_batch: usize) -> Shape {
+        Shape::new(self.info.num_vocab, 1, num_batch, 1)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    #[allow(clippy::type_complexity)]
     fn run_internal(
         &self,
         tokens: Vec<Vec<u16>>,
         state: &ModelState,
+        page: usize,
         last: Option<usize>,
-    ) -> Result<(Arc<Output>, Vec<Option<usize>>)> {
+        layers: Range<usize>,
+        full: bool,
+        target: RunOutput<'_>,
+    ) -> Result<(Arc<Output<F>>, Vec<Option<Range<usize>>>)> {
         let context = &self.context;
         let tensor = &self.tensor;
+        self.check_tokens(tokens.iter().flatten())?;
 
         let input: Vec<_> = tokens
             .into_iter()
             .map(|tokens| -> Result<_, TensorError> {
-                let stack = TensorCpu::stack(
+                let stack = TensorCpu::stack_unchecked(
                     tokens
                         .into_iter()
-                        .map(|token| tensor.embed.w.slice(.., token as usize, .., ..))
-                        .try_collect()?,
+                        // SAFETY: all tokens are checked to be within the vocabulary
+                        .map(|token| unsafe {
+                            tensor.embed.w.slice_unchecked(.., token as usize, .., ..)
+                        })
+                        .collect(),
                 )
                 .unwrap_or_else(|_| context.zeros(Shape::new(self.info.num_emb, 1, 0, 1)));
-                stack.map(|x| x.to_f32()).reshape(
+                stack.map(|x| F::from_f32(x.to_f32())).reshape(
                     TensorDimension::Full,
                     TensorDimension::Auto,
                     TensorDimension::Dimension(1),
@@ -477,6 +1383,35 @@ impl<'a> Model<'a> {
                 )
             })
             .try_collect()?;
+        self.run_input(input, state, page, last, layers, full, target)
+    }
+
+    /// Like `run_internal`, but with the embeddings of the tokens of each batch already looked up, as `[C, T, 1]` tensors.
+    #[allow(clippy::too_many_arguments)]
+    #[allow(clippy::type_complexity)]
+    fn run_input(
+        &self,
+        input: Vec<TensorCpu<F>>,
+        state: &ModelState,
+        page: usize,
+        last: Option<usize>,
+        layers: Range<usize>,
+        full: bool,
+        target: RunOutput<'_>,
+    ) -> Result<(Arc<Output<F>>, Vec<Option<Range<usize>>>)> {
+        let context = &self.context;
+        // hold a turn until the work is submitted, so that other models on the context get theirs in order
+        let _submission = context.submission();
+
+        // layers on the host run first, and the device picks up after them with the embedding already normalized
+        let offset = self.tensor.host.len();
+        let (input, layers, normalize) = match layers.start < offset {
+            true => {
+                let input = self.run_host(input, state, page, layers.start..offset)?;
+                (input, offset..layers.end, false)
+            }
+            false => (input, layers, true),
+        };
 
         let input = TensorStack::try_from(input)?;
         let num_batch = input.num_batch();
@@ -487,26 +1422,124 @@ impl<'a> Model<'a> {
 
         // collect batch output copy commands for later
         let mut redirect = vec![None; num_batch];
-        let headers = input
-            .cursors
-            .iter()
-            .filter(|cursor| cursor.len > 0)
-            .filter(|cursor| !last.is_some_and(|index| cursor.batch == index))
-            .enumerate()
-            .map(|(index, cursor)| {
-                redirect[cursor.batch] = Some(index);
-                cursor.token + cursor.len - 1
+        let mut headers = vec![];
+        for cursor in input.cursors.iter().filter(|cursor| cursor.len > 0) {
+            let tokens = match full {
+                true => cursor.token..cursor.token + cursor.len,
+                false if last == Some(cursor.batch) => continue,
+                false => cursor.token + cursor.len - 1..cursor.token + cursor.len,
+            };
+            redirect[cursor.batch] = Some(headers.len()..headers.len() + tokens.len());
+            headers.extend(tokens);
+        }
+        let num_header = headers.len();
+
+        // runs writing into a tensor given by the caller are never captured
+        let logits = matches!(target, RunOutput::Logits);
+        let capture = match target {
+            RunOutput::Logits if self.capture => {
+                let key = CaptureKey {
+                    state: state.page_id(page),
+                    num_token,
+                    headers: headers.clone(),
+                    layers: layers.clone(),
+                    normalize,
+                };
+                self.capture_cache.try_request(key, || {
+                    let target = RunOutput::Logits;
+                    self.record(state, page, num_token, &headers, layers, normalize, target)
+                })?
+            }
+            _ => {
+                Arc::new(self.record(state, page, num_token, &headers, layers, normalize, target)?)
+            }
+        };
+        let buffer = &capture.runtime;
+        let output = &capture.output;
+
+        let mut cursors = input.cursors.into_cursors();
+        cursors.resize(self.token_chunk_size, 0);
+        let cursors = context.tensor_from_data(buffer.cursors.shape(), cursors)?;
+
+        buffer.input.load(&input.tensor)?;
+        buffer.cursors.load(&cursors)?;
+
+        let mut encoder = context
+            .device
+            .create_command_encoder(&CommandEncoderDescriptor::default());
+        capture.sequence.replay(&mut encoder);
+        if num_header > 0 && logits {
+            encoder.copy_tensor(&output.head_o, &output.map)?;
+        }
+
+        context.queue.submit(Some(encoder.finish()));
+        Ok((output.clone(), redirect))
+    }
+
+    /// Run the tokens of each batch of `input` through the host `layers`, reading the state of `page` back
+    /// and writing it once they are done, and return what the device picks up from.
+    fn run_host<'b>(
+        &self,
+        input: Vec<TensorCpu<'b, F>>,
+        state: &ModelState,
+        page: usize,
+        layers: Range<usize>,
+    ) -> Result<Vec<TensorCpu<'b, F>>> {
+        let context = &self.context;
+        let host = &self.tensor.host;
+        let num_emb = self.info.num_emb;
+        let num_layer = self.info.num_layer;
+
+        let map = context.tensor_init(state.pages[page].shape());
+        let mut encoder = context
+            .device
+            .create_command_encoder(&CommandEncoderDescriptor::default());
+        encoder.copy_tensor(&state.pages[page], &map)?;
+        context.queue.submit(Some(encoder.finish()));
+        let shape = map.shape();
+        let mut data = TensorCpu::from(map).to_vec();
+
+        let output = input
+            .into_iter()
+            .zip_eq(data.chunks_exact_mut(5 * num_layer * num_emb))
+            .map(|(input, state)| {
+                let mut state = state.chunks_exact_mut(5 * num_emb).collect_vec();
+                let shape = input.shape();
+                let mut x = input.map(|x| x.to_f32()).to_vec();
+                for x in x.chunks_exact_mut(num_emb) {
+                    host.run(x, &mut state, layers.clone());
+                }
+                let x = x.into_iter().map(F::from_f32).collect_vec();
+                context.tensor_from_data(shape, x)
             })
-            .collect_vec();
+            .try_collect()?;
+
+        state.pages[page].load(&context.tensor_from_data(shape, data)?)?;
+        Ok(output)
+    }
+
+    /// Record the commands of a run of `num_token` tokens on `page` of `state`,
+    /// outputting the tokens at `headers` as `target` asks.
+    /// The embedding layer norm is skipped unless `normalize` is set, e.g. when host layers have done it.
+    #[allow(clippy::too_many_arguments)]
+    fn record(
+        &self,
+        state: &ModelState,
+        page: usize,
+        num_token: usize,
+        headers: &[usize],
+        layers: Range<usize>,
+        normalize: bool,
+        target: RunOutput<'_>,
+    ) -> Result<Capture<F>, TensorError> {
+        let tensor = &self.tensor;
         let num_header = headers.len();
 
         let buffer = self.request_runtime(num_token);
         let output = self.request_output(num_header.max(1));
 
         // gather and group copy operations
-        let (head_ops, head_x) = if num_token == 1 || num_token == num_header {
-            (TensorOp::List(vec![]), &buffer.ffn_x)
-        } else {
+        let gather = || -> Result<TensorOp, TensorError> {
             let mut start = 0;
             let mut end = 1;
             let mut ops = vec![];
@@ -524,7 +1557,12 @@ impl<'a> Model<'a> {
                 }
                 end += 1;
             }
-            (TensorOp::List(ops), &output.head_x)
+            Ok(TensorOp::List(ops))
+        };
+        let (head_ops, head_x) = if num_token == 1 || num_token == num_header {
+            (TensorOp::List(vec![]), &buffer.ffn_x)
+        } else {
+            (gather()?, &output.head_x)
         };
 
         // let head_ops: Vec<_> = input
@@ -533,7 +1571,7 @@ impl<'a> Model<'a> {
         //     .filter(|cursor| cursor.len > 0)
         //     .filter(|cursor| !last.is_some_and(|index| cursor.batch == index))
         //     .enumerate()
-        //     .map(|(index, cursor)| -> Result<TensorOp<'_>, TensorError> {
+        //     .map(|(index, cursor)| -> Result<TensorOp, TensorError> {
         //         redirect[cursor.batch] = Some(index);
         //         let token = cursor.token + cursor.len - 1;
         //         let input = buffer.ffn_x.as_view((.., token, ..))?;
@@ -542,30 +1580,36 @@ impl<'a> Model<'a> {
         //     })
         //     .try_collect()?;
 
-        let mut cursors = input.cursors.into_cursors();
-        cursors.resize(self.token_chunk_size, 0);
-        let cursors = context.tensor_from_data(buffer.cursors.shape(), cursors)?;
+        let mut sequence = TensorSequence::new();
 
-        buffer.input.load(&input.tensor)?;
-        buffer.cursors.load(&cursors)?;
-
-        let mut encoder = context
-            .device
-            .create_command_encoder(&CommandEncoderDescriptor::default());
+        if normalize {
+            let op = TensorOp::layer_norm(
+                &tensor.embed.layer_norm.w,
+                &tensor.embed.layer_norm.b,
+                &buffer.input,
+            )?;
+            sequence.push(op);
+        }
 
-        let op = TensorOp::layer_norm(
-            &tensor.embed.layer_norm.w,
-            &tensor.embed.layer_norm.b,
-            &buffer.input,
-        )?;
-        let mut pass = encoder.begin_compute_pass(&ComputePassDescriptor::default());
-        pass.execute_tensor_op(&op);
-        drop(pass);
+        // the device holds the layers after those on the host
+        let offset = tensor.host.len();
+        for (index, layer) in (offset..)
+            .zip(&tensor.layers)
+            .take(layers.end - offset)
+            .skip(layers.start - offset)
+        {
+            let site = |kind| OpSite {
+                layer: index,
+                kind,
+                num_token,
+                runtime: &buffer,
+                state,
+                page,
+            };
 
-        for (index, layer) in tensor.layers.iter().enumerate() {
-            encoder.copy_tensor(&buffer.input, &buffer.att_x)?;
+            sequence.copy_tensor(&buffer.input, &buffer.att_x)?;
 
-            let matmul_ops = if self.turbo && num_token == self.token_chunk_size {
+            let matmul_ops = if self.use_turbo(num_token) {
                 TensorOp::List(vec![
                     layer.att.w_k.matmul_mat_op(
                         buffer.half_x.view(.., .., .., ..)?,
@@ -602,6 +1646,7 @@ impl<'a> Model<'a> {
                     )?,
                 ])
             };
+            let matmul_ops = self.override_op(matmul_ops, site(OpKind::AttMatmul))?;
             let ops = TensorOp::List(vec![
                 TensorOp::layer_norm(
                     &layer.att_layer_norm.w,
@@ -612,33 +1657,36 @@ impl<'a> Model<'a> {
                     &buffer.cursors,
                     &layer.att.time_mix_k,
                     &buffer.att_x,
-                    state.att(index)?,
+                    state.att(page, index)?,
                     &buffer.att_kx,
                 )?,
                 TensorOp::token_shift(
                     &buffer.cursors,
                     &layer.att.time_mix_v,
                     &buffer.att_x,
-                    state.att(index)?,
+                    state.att(page, index)?,
                     &buffer.att_vx,
                 )?,
                 TensorOp::token_shift(
                     &buffer.cursors,
                     &layer.att.time_mix_r,
                     &buffer.att_x,
-                    state.att(index)?,
+                    state.att(page, index)?,
                     &buffer.att_rx,
                 )?,
                 matmul_ops,
-                TensorOp::time_mix(
-                    &buffer.cursors,
-                    &layer.att.time_decay,
-                    &layer.att.time_first,
-                    &buffer.att_k,
-                    &buffer.att_v,
-                    &buffer.att_r,
-                    &buffer.att_x,
-                    state.att(index)?,
+                self.override_op(
+                    TensorOp::time_mix(
+                        &buffer.cursors,
+                        &layer.att.time_decay,
+                        &layer.att.time_first,
+                        &buffer.att_k,
+                        &buffer.att_v,
+                        &buffer.att_r,
+                        &buffer.att_x,
+                        state.att(page, index)?,
+                    )?,
+                    site(OpKind::TimeMix),
                 )?,
                 layer.att.w_o.matmul_vec_op(
                     buffer.half_x.view(.., .., .., ..)?,
@@ -648,12 +1696,10 @@ impl<'a> Model<'a> {
                 TensorOp::add(&buffer.input, &buffer.att_o)?,
             ]);
 
-            let mut pass = encoder.begin_compute_pass(&ComputePassDescriptor::default());
-            pass.execute_tensor_op(&ops);
-            drop(pass);
+            sequence.push(ops);
 
-            encoder.copy_tensor(&buffer.att_o, &buffer.ffn_x)?;
-            let matmul_ops = if self.turbo && num_token == self.token_chunk_size {
+            sequence.copy_tensor(&buffer.att_o, &buffer.ffn_x)?;
+            let matmul_ops = if self.use_turbo(num_token) {
                 TensorOp::List(vec![
                     layer.ffn.w_k.matmul_mat_op(
                         buffer.half_x.view(.., .., .., ..)?,
@@ -692,6 +1738,7 @@ impl<'a> Model<'a> {
                     )?,
                 ])
             };
+            let matmul_ops = self.override_op(matmul_ops, site(OpKind::FfnMatmul))?;
             let ops = TensorOp::List(vec![
                 TensorOp::layer_norm(
                     &layer.ffn_layer_norm.w,
@@ -702,99 +1749,239 @@ impl<'a> Model<'a> {
                     &buffer.cursors,
                     &layer.ffn.time_mix_k,
                     &buffer.ffn_x,
-                    state.ffn(index)?,
+                    state.ffn(page, index)?,
                     &buffer.ffn_kx,
                 )?,
                 TensorOp::token_shift(
                     &buffer.cursors,
                     &layer.ffn.time_mix_r,
                     &buffer.ffn_x,
-                    state.ffn(index)?,
+                    state.ffn(page, index)?,
                     &buffer.ffn_rx,
                 )?,
                 matmul_ops,
-                TensorOp::channel_mix(
-                    &buffer.cursors,
-                    &buffer.ffn_r,
-                    &buffer.ffn_v,
-                    &buffer.ffn_x,
-                    state.ffn(index)?,
+                self.override_op(
+                    TensorOp::channel_mix(
+                        &buffer.cursors,
+                        &buffer.ffn_r,
+                        &buffer.ffn_v,
+                        &buffer.ffn_x,
+                        state.ffn(page, index)?,
+                    )?,
+                    site(OpKind::ChannelMix),
                 )?,
                 TensorOp::add(&buffer.att_o, &buffer.ffn_x)?,
             ]);
 
-            let mut pass = encoder.begin_compute_pass(&ComputePassDescriptor::default());
-            pass.execute_tensor_op(&ops);
-            drop(pass);
+            sequence.push(ops);
 
-            if self.rescale && (index + 1) % RESCALE_LAYER == 0 {
+            if let Some(stats) = &self.stats {
+                let op = TensorOp::stats(&buffer.ffn_x, stats.view(.., index, .., ..)?)?;
+                sequence.push(op);
+            }
+
+            if self
+                .rescale
+                .is_some_and(|every| (index + 1).is_multiple_of(every))
+            {
                 let op = TensorOp::half(&buffer.ffn_x)?;
-                let mut pass = encoder.begin_compute_pass(&ComputePassDescriptor::default());
-                pass.execute_tensor_op(&op);
-                drop(pass);
+                sequence.push(op);
             }
 
-            if index != self.info.num_layer - 1 {
-                encoder.copy_tensor(&buffer.ffn_x, &buffer.input)?;
+            if let RunOutput::Lens(lens) = target {
+                // project a copy, so that the hidden states go on to the next layer untouched
+                let mut ops = vec![
+                    gather()?,
+                    TensorOp::layer_norm(
+                        &tensor.head.layer_norm.w,
+                        &tensor.head.layer_norm.b,
+                        &output.head_x,
+                    )?,
+                ];
+                for (chunk, matrix) in tensor.head.w.iter().enumerate() {
+                    let start = chunk * self.head_chunk_size;
+                    let end = start + matrix.shape()[1];
+                    let input = output.head_x.view(.., .., .., ..)?;
+                    let output = lens.view(start..end, .., index, ..)?;
+                    ops.push(TensorOp::matmul_vec_fp16(matrix, input, output)?);
+                }
+                sequence.push(TensorOp::List(ops));
+            }
+
+            if index != layers.end - 1 {
+                sequence.copy_tensor(&buffer.ffn_x, &buffer.input)?;
             }
         }
 
-        if num_header > 0 {
+        // with a lens, that of the last layer already holds the logits
+        let head = !matches!(target, RunOutput::Lens(_));
+        if num_header > 0 && head {
             let mut ops = vec![TensorOp::layer_norm(
                 &tensor.head.layer_norm.w,
                 &tensor.head.layer_norm.b,
                 head_x,
             )?];
 
-            for (chunk, matrix) in tensor.head.w.iter().enumerate() {
-                let start = chunk * self.head_chunk_size;
-                let end = start + self.head_chunk_size;
-                let input = head_x.view(.., .., .., ..)?;
-                let output = output.head_o.view(start..end, .., .., ..)?;
-                ops.push(TensorOp::matmul_vec_fp16(matrix, input, output)?);
+            match target {
+                RunOutput::Hidden(hidden) => {
+                    // only the normalized hidden states are wanted, so the head itself is skipped
+                    let input = head_x.view(.., .., .., ..)?;
+                    ops.push(TensorOp::blit_f32(input, hidden)?);
+                }
+                RunOutput::Logits | RunOutput::Lens(_) => {
+                    for (chunk, matrix) in tensor.head.w.iter().enumerate() {
+                        let start = chunk * self.head_chunk_size;
+                        let end = start + matrix.shape()[1];
+                        let input = head_x.view(.., .., .., ..)?;
+                        let output = output.head_o.view(start..end, .., .., ..)?;
+                        ops.push(TensorOp::matmul_vec_fp16(matrix, input, output)?);
+                    }
+                }
             }
 
             let ops = TensorOp::List(ops);
 
-            let mut pass = encoder.begin_compute_pass(&ComputePassDescriptor::default());
-            pass.execute_tensor_op(&head_ops);
-            pass.execute_tensor_op(&ops);
-            drop(pass);
+            sequence.push(head_ops);
+            sequence.push(ops);
+        }
 
-            encoder.copy_tensor(&output.head_o, &output.map)?;
+        Ok(Capture {
+            _page: state.pages[page].clone(),
+            runtime: buffer,
+            output,
+            sequence,
+        })
+    }
+
+    /// Run through `layers` only, returning the logits of the last token of each finished batch,
+    /// or of every token run if `full` is set.
+    fn run_with(
+        &self,
+        tokens: &mut [Vec<u16>],
+        state: &ModelState,
+        layers: Range<usize>,
+        full: bool,
+    ) -> Result<Vec<Option<Vec<Vec<f32>>>>> {
+        use super::ModelState;
+
+        let num_token: usize = tokens.iter().map(Vec::len).sum();
+        let max_batch = state.max_batch();
+
+        if tokens.len() != max_batch {
+            return Err(ModelError::BatchSize(tokens.len(), max_batch).into());
+        }
+        // at least one layer has to run on the device, since the head reads its output
+        let offset = self.tensor.host.len();
+        if layers.is_empty() || layers.end > self.info.num_layer || layers.end <= offset {
+            let (start, end, max) = (layers.start, layers.end, self.info.num_layer);
+            return Err(ModelError::LayerRange { start, end, max }.into());
+        }
+        if num_token == 0 {
+            return Ok(vec![None; max_batch]);
         }
 
-        context.queue.submit(Some(encoder.finish()));
-        Ok((output, redirect))
+        // only batches in the page of the first pending batch are run together
+        let page = tokens
+            .iter()
+            .position(|batch| !batch.is_empty())
+            .expect("there are tokens")
+            / state.page_size();
+        let start = page * state.page_size();
+        let end = (start + state.page_size()).min(max_batch);
+
+        // we only infer at most `token_chunk_size` tokens at a time
+        let num_token: usize = tokens[start..end].iter().map(Vec::len).sum();
+        let mut num_token = num_token.min(self.token_chunk_size);
+        let mut inputs = vec![vec![]; end - start];
+        let mut last = None;
+
+        // take `num_token` tokens out of all the inputs and put into `input`
+        for (index, (batch, input)) in tokens[start..end]
+            .iter_mut()
+            .zip(inputs.iter_mut())
+            .enumerate()
+        {
+            let mid = batch.len().min(num_token);
+            num_token -= mid;
+
+            let (head, tail) = batch.split_at(mid);
+            last = (!tail.is_empty()).then_some(index);
+            *input = head.to_vec();
+            *batch = tail.to_vec();
+
+            if num_token == 0 {
+                break;
+            }
+        }
+
+        let (output, redirect) =
+            self.run_internal(inputs, state, page, last, layers, full, RunOutput::Logits)?;
+        let output = TensorCpu::from(output.map.clone());
+
+        let mut outputs = vec![None; max_batch];
+        for (output_batch, index) in outputs[start..end].iter_mut().zip_eq(redirect) {
+            *output_batch = index.map(|index| {
+                index
+                    .map(|index| {
+                        output
+                            .slice(.., index, .., ..)
+                            .expect("this never happens")
+                            .to_vec()
+                    })
+                    .collect()
+            });
+        }
+        Ok(outputs)
     }
 }
 
-impl<'a> FromBuilder for Model<'a> {
+impl<'a, F: Float> FromBuilder for Model<'a, F> {
     type Builder<'b> = ModelBuilder<'b>;
     type Error = anyhow::Error;
 
     fn from_builder(builder: Self::Builder<'_>) -> Result<Self, Self::Error> {
         let ModelBuilder {
             context,
-            data,
+            mut source,
+            checksum,
             lora,
             quant,
+            quant_report,
             turbo,
+            rescale,
             head_chunk_size,
             token_chunk_size,
+            capture,
+            host_layers,
+            envelope,
+            mut progress,
+            cancel,
         } = builder;
+        let _scope = context.memory_scope(MemoryCategory::Weights);
+
+        let loader = source.loader(&context, lora, checksum)?;
+        let info = loader.model_info()?;
 
+        let limits = context.device.limits();
+        let head_chunk_size = head_chunk_size.head(&limits, &info);
+        let token_chunk_size = token_chunk_size.token(&limits, &info);
         if !head_chunk_size.is_power_of_two() {
             return Err(ModelError::InvalidChunkSize(head_chunk_size).into());
         }
         if !token_chunk_size.is_power_of_two() {
             return Err(ModelError::InvalidChunkSize(token_chunk_size).into());
         }
+        if host_layers >= info.num_layer {
+            let (end, max) = (host_layers, info.num_layer);
+            return Err(ModelError::LayerRange { start: 0, end, max }.into());
+        }
 
-        let loader = Loader::new(&context, data, lora)?;
-        let info = Loader::info(data)?;
-
-        let rescale = turbo || quant.iter().any(|(_, quant)| matches!(quant, Quant::NF4));
+        let rescale = match rescale {
+            Some(0) => None,
+            Some(every) => Some(every),
+            None => (turbo || quant.iter().any(|(_, quant)| matches!(quant, Quant::NF4)))
+                .then_some(RESCALE_LAYER),
+        };
 
         let embed = Embed {
             layer_norm: LayerNorm {
@@ -815,12 +2002,22 @@ impl<'a> FromBuilder for Model<'a> {
         context.queue.submit(None);
         context.device.poll(wgpu::MaintainBase::Wait);
 
-        let layers = (0..info.num_layer)
+        let host = HostLayers::load(&loader, &info, host_layers, rescale)?;
+
+        let mut report = quant_report.then(QuantizationReport::default);
+        let layers = (host_layers..info.num_layer)
             .map(|layer| {
+                if cancel
+                    .as_ref()
+                    .is_some_and(|cancel| cancel.load(Ordering::Relaxed))
+                {
+                    return Err(ModelError::Cancelled.into());
+                }
+
                 let quant = quant.get(&layer).copied().unwrap_or_default();
                 let discount = match rescale {
-                    true => 2.0_f32.powi(-((layer / RESCALE_LAYER) as i32)),
-                    false => 1.0,
+                    Some(every) => 2.0_f32.powi(-((layer / every) as i32)),
+                    None => 1.0,
                 };
 
                 let att_layer_norm = LayerNorm {
@@ -841,40 +2038,21 @@ impl<'a> FromBuilder for Model<'a> {
                 let w_o =
                     loader.load_matrix_f16_discount(format!("{att}.output.weight"), discount)?;
 
-                let att = match quant {
-                    Quant::None => Att {
-                        time_decay,
-                        time_first,
-                        time_mix_k,
-                        time_mix_v,
-                        time_mix_r,
-                        w_k: Matrix::Fp16(w_k),
-                        w_v: Matrix::Fp16(w_v),
-                        w_r: Matrix::Fp16(w_r),
-                        w_o: Matrix::Fp16(w_o),
-                    },
-                    Quant::Int8 => Att {
-                        time_decay,
-                        time_first,
-                        time_mix_k,
-                        time_mix_v,
-                        time_mix_r,
-                        w_k: Matrix::quant_u8(w_k)?,
-                        w_v: Matrix::quant_u8(w_v)?,
-                        w_r: Matrix::quant_u8(w_r)?,
-                        w_o: Matrix::quant_u8(w_o)?,
-                    },
-                    Quant::NF4 => Att {
-                        time_decay,
-                        time_first,
-                        time_mix_k,
-                        time_mix_v,
-                        time_mix_r,
-                        w_k: Matrix::quant_nf4(w_k)?,
-                        w_v: Matrix::quant_nf4(w_v)?,
-                        w_r: Matrix::quant_nf4(w_r)?,
-                        w_o: Matrix::quant_nf4(w_o)?,
-                    },
+                let mut quant_matrix = |name: String, matrix| match report.as_mut() {
+                    Some(report) => report.quant(name, layer, matrix, quant),
+                    None => Matrix::quant(matrix, quant),
+                };
+
+                let att = Att {
+                    time_decay,
+                    time_first,
+                    time_mix_k,
+                    time_mix_v,
+                    time_mix_r,
+                    w_k: quant_matrix(format!("{att}.key.weight"), w_k)?,
+                    w_v: quant_matrix(format!("{att}.value.weight"), w_v)?,
+                    w_r: quant_matrix(format!("{att}.receptance.weight"), w_r)?,
+                    w_o: quant_matrix(format!("{att}.output.weight"), w_o)?,
                 };
 
                 let ffn_layer_norm = LayerNorm {
@@ -884,40 +2062,31 @@ impl<'a> FromBuilder for Model<'a> {
 
                 let ffn = format!("blocks.{layer}.ffn");
                 let time_mix_k = loader.load_vector_f16(format!("{ffn}.time_mix_k"))?;
-                let time_mix_r = loader.load_vector_f16(format!("{ffn}.time_mix_k"))?;
+                let time_mix_r = loader.load_vector_f16(format!("{ffn}.time_mix_r"))?;
 
                 let w_r = loader.load_matrix_f16(format!("{ffn}.receptance.weight"))?;
                 let w_k = loader.load_matrix_f16(format!("{ffn}.key.weight"))?;
                 let w_v =
                     loader.load_matrix_f16_discount(format!("{ffn}.value.weight"), discount)?;
 
-                let ffn = match quant {
-                    Quant::None => Ffn {
-                        time_mix_k,
-                        time_mix_r,
-                        w_k: Matrix::Fp16(w_k),
-                        w_v: Matrix::Fp16(w_v),
-                        w_r: Matrix::Fp16(w_r),
-                    },
-                    Quant::Int8 => Ffn {
-                        time_mix_k,
-                        time_mix_r,
-                        w_k: Matrix::quant_u8(w_k)?,
-                        w_v: Matrix::quant_u8(w_v)?,
-                        w_r: Matrix::quant_u8(w_r)?,
-                    },
-                    Quant::NF4 => Ffn {
-                        time_mix_k,
-                        time_mix_r,
-                        w_k: Matrix::quant_nf4(w_k)?,
-                        w_v: Matrix::quant_nf4(w_v)?,
-                        w_r: Matrix::quant_nf4(w_r)?,
-                    },
+                let ffn = Ffn {
+                    time_mix_k,
+                    time_mix_r,
+                    w_k: quant_matrix(format!("{ffn}.key.weight"), w_k)?,
+                    w_v: quant_matrix(format!("{ffn}.value.weight"), w_v)?,
+                    w_r: quant_matrix(format!("{ffn}.receptance.weight"), w_r)?,
                 };
 
                 context.queue.submit(None);
                 context.device.poll(wgpu::MaintainBase::Wait);
 
+                if let Some(progress) = progress.as_mut() {
+                    progress(BuildProgress {
+                        layer: layer + 1,
+                        num_layer: info.num_layer,
+                    });
+                }
+
                 Ok(Layer {
                     att_layer_norm,
                     ffn_layer_norm,
@@ -927,14 +2096,21 @@ impl<'a> FromBuilder for Model<'a> {
             })
             .collect::<Result<Vec<_>>>()?;
 
+        // every tensor is on the device by now, so the source can go
+        drop(loader);
+        drop(source);
+
         context.queue.submit(None);
         context.device.poll(wgpu::MaintainBase::Wait);
 
         let tensor = ModelTensor {
             embed,
             head,
+            host,
             layers,
         };
+        let reserve =
+            envelope.map(|envelope| Reserve::new(&context, &info, envelope, token_chunk_size));
         Ok(Self {
             context,
             info,
@@ -942,15 +2118,22 @@ impl<'a> FromBuilder for Model<'a> {
             turbo,
             head_chunk_size,
             token_chunk_size,
-            tensor,
+            capture,
+            tensor: tensor.into(),
+            quant_report: report,
             runtime_cache: ResourceCache::new(1),
             output_cache: ResourceCache::new(1),
             softmax_cache: ResourceCache::new(1),
+            capture_cache: ResourceCache::new(4),
+            envelope,
+            reserve,
+            overrides: Default::default(),
+            stats: None,
         })
     }
 }
 
-impl super::Model for Model<'_> {
+impl<F: Float> super::Model for Model<'_, F> {
     type ModelState = ModelState;
 
     #[inline]
@@ -963,38 +2146,123 @@ impl super::Model for Model<'_> {
         &self.info
     }
 
+    fn embed_tokens(&self, tokens: &[u16], layer_norm: bool) -> Result<TensorGpu<f32, ReadWrite>> {
+        let context = &self.context;
+        let tensor = &self.tensor;
+        self.check_tokens(tokens)?;
+
+        let stack = TensorCpu::stack(
+            tokens
+                .iter()
+                // SAFETY: all tokens are checked to be within the vocabulary
+                .map(|&token| unsafe { tensor.embed.w.slice_unchecked(.., token as usize, .., ..) })
+                .collect(),
+        )?;
+        let stack = stack.map(|x| x.to_f32()).reshape(
+            TensorDimension::Full,
+            TensorDimension::Auto,
+            TensorDimension::Dimension(1),
+            TensorDimension::Full,
+        )?;
+        let output: TensorGpu<f32, ReadWrite> = stack.into();
+
+        if layer_norm {
+            let op = TensorOp::layer_norm(
+                &tensor.embed.layer_norm.w,
+                &tensor.embed.layer_norm.b,
+                &output,
+            )?;
+            let mut encoder = context
+                .device
+                .create_command_encoder(&CommandEncoderDescriptor::default());
+            let mut pass = encoder.begin_compute_pass(&ComputePassDescriptor::default());
+            pass.execute_tensor_op(&op);
+            drop(pass);
+            context.queue.submit(Some(encoder.finish()));
+        }
+
+        Ok(output)
+    }
+
     fn softmax(&self, input: Vec<Option<Vec<f32>>>) -> Result<Vec<Option<Vec<f32>>>> {
-        let max_batch = input.len();
+        self.softmax_internal(input, None)
+    }
 
-        let mut redirect = vec![None; max_batch];
-        let input: Vec<_> = input
+    fn softmax_with_temperature(
+        &self,
+        input: Vec<Option<Vec<f32>>>,
+        temperature: &[f32],
+    ) -> Result<Vec<Option<Vec<f32>>>> {
+        self.softmax_internal(input, Some(temperature))
+    }
+
+    fn run(
+        &self,
+        tokens: &mut Vec<Vec<u16>>,
+        state: &Self::ModelState,
+    ) -> Result<Vec<Option<Vec<f32>>>> {
+        self.run_layers(tokens, state, 0..self.info.num_layer)
+    }
+
+    fn run_layers(
+        &self,
+        tokens: &mut Vec<Vec<u16>>,
+        state: &Self::ModelState,
+        layers: Range<usize>,
+    ) -> Result<Vec<Option<Vec<f32>>>> {
+        let outputs = self.run_with(tokens, state, layers, false)?;
+        Ok(outputs
             .into_iter()
-            .enumerate()
-            .filter_map(|(batch, data)| data.map(|data| (batch, data)))
-            .map(|(batch, data)| {
-                TensorCpu::from_data(&self.context, self.head_shape(1), data)
-                    .map(|tensor| (batch, tensor))
-            })
-            .try_collect()?;
-        let input = TensorCpu::stack(
-            input
-                .into_iter()
-                .enumerate()
-                .map(|(index, (batch, tensor))| {
-                    redirect[batch] = Some(index);
-                    tensor
-                })
-                .collect_vec(),
-        )?;
+            .map(|output| output.and_then(|mut output| output.pop()))
+            .collect())
+    }
 
-        let num_batch = input.shape()[2];
-        let softmax = self.request_softmax(num_batch);
-        softmax.buffer.load(&input)?;
+    fn run_full(
+        &self,
+        tokens: &mut Vec<Vec<u16>>,
+        state: &Self::ModelState,
+    ) -> Result<Vec<Vec<Vec<f32>>>> {
+        let outputs = self.run_with(tokens, state, 0..self.info.num_layer, true)?;
+        Ok(outputs.into_iter().map(Option::unwrap_or_default).collect())
+    }
 
-        let op = TensorOp::softmax(&softmax.buffer)?;
+    fn verify(
+        &self,
+        tokens: &[u16],
+        batch: usize,
+        state: &Self::ModelState,
+    ) -> Result<(usize, u16)> {
+        use super::ModelState;
 
-        let mut encoder = self
-            .context
+        let max_batch = state.max_batch();
+        if batch >= max_batch {
+            let max = max_batch;
+            return Err(ModelError::BatchOutOfRange { batch, max }.into());
+        }
+        if tokens.is_empty() || tokens.len() > self.token_chunk_size {
+            let (tokens, max) = (tokens.len(), self.token_chunk_size);
+            return Err(ModelError::ChunkOverflow { tokens, max }.into());
+        }
+
+        let page = batch / state.page_size();
+        let start = page * state.page_size();
+        let end = (start + state.page_size()).min(max_batch);
+        let mut inputs = vec![vec![]; end - start];
+        inputs[batch - start] = tokens.to_vec();
+
+        let layers = 0..self.info.num_layer;
+        let (output, _) =
+            self.run_internal(inputs, state, page, None, layers, true, RunOutput::Logits)?;
+
+        let context = &self.context;
+        let tokens = tokens.iter().map(|&token| token as u32).collect_vec();
+        let tokens: TensorGpu<u32, ReadWrite> =
+            context.tensor_from_data(Shape::new(tokens.len(), 1, 1, 1), tokens)?;
+        let verify: TensorGpu<u32, ReadWrite> = context.tensor_init(Shape::new(2, 1, 1, 1));
+        let map: TensorGpu<u32, ReadBack> = context.tensor_init(Shape::new(2, 1, 1, 1));
+        let op = TensorOp::verify(&output.head_o, &tokens, &verify)?;
+
+        let mut encoder = context
             .device
             .create_command_encoder(&CommandEncoderDescriptor::default());
 
@@ -1002,76 +2270,330 @@ impl super::Model for Model<'_> {
         pass.execute_tensor_op(&op);
         drop(pass);
 
-        encoder.copy_tensor(&softmax.buffer, &softmax.map)?;
-        self.context.queue.submit(Some(encoder.finish()));
+        encoder.copy_tensor(&verify, &map)?;
+        context.queue.submit(Some(encoder.finish()));
 
-        let mut output = TensorCpu::from(softmax.map.clone())
-            .split(2)
-            .expect("split buffer map")
-            .into_iter()
-            .map(|tensor| Some(tensor.to_vec()))
-            .collect_vec();
+        let verify = Vec::from(TensorCpu::from(map));
+        Ok((verify[0] as usize, verify[1] as u16))
+    }
 
-        let mut probs = vec![None; max_batch];
-        for (probs, redirect) in probs.iter_mut().zip_eq(redirect.into_iter()) {
-            if let Some(redirect) = redirect {
-                std::mem::swap(probs, &mut output[redirect]);
-            }
+    fn prefill(
+        &self,
+        tokens: &[u16],
+        batch: usize,
+        state: &Self::ModelState,
+        output: &TensorGpu<f32, ReadWrite>,
+        offset: usize,
+    ) -> Result<()> {
+        use super::ModelState;
+
+        let max_batch = state.max_batch();
+        if batch >= max_batch {
+            let max = max_batch;
+            return Err(ModelError::BatchOutOfRange { batch, max }.into());
+        }
+        let shape = output.shape();
+        if shape[0] != self.info.num_vocab || shape[1] < offset + tokens.len() || shape[2] != 1 {
+            let shape = Shape::new(self.info.num_vocab, offset + tokens.len(), 1, 1);
+            return Err(TensorError::Shape(output.shape(), shape).into());
         }
 
-        Ok(probs)
+        let page = batch / state.page_size();
+        let start = page * state.page_size();
+        let end = (start + state.page_size()).min(max_batch);
+
+        let mut offset = offset;
+        for chunk in tokens.chunks(self.token_chunk_size) {
+            let mut inputs = vec![vec![]; end - start];
+            inputs[batch - start] = chunk.to_vec();
+
+            let layers = 0..self.info.num_layer;
+            let (logits, _) =
+                self.run_internal(inputs, state, page, None, layers, true, RunOutput::Logits)?;
+
+            // queued after the run, so the cached output is copied out before being reused
+            let op = TensorOp::blit(
+                logits.head_o.view(.., .., .., ..)?,
+                output.view(.., offset..offset + chunk.len(), .., ..)?,
+            )?;
+            let mut encoder = self
+                .context
+                .device
+                .create_command_encoder(&CommandEncoderDescriptor::default());
+            let mut pass = encoder.begin_compute_pass(&ComputePassDescriptor::default());
+            pass.execute_tensor_op(&op);
+            drop(pass);
+            self.context.queue.submit(Some(encoder.finish()));
+
+            offset += chunk.len();
+        }
+        Ok(())
     }
 
-    fn run(
+    fn run_embeds(
         &self,
-        tokens: &mut Vec<Vec<u16>>,
+        embeds: &TensorCpu<f32>,
+        batch: usize,
         state: &Self::ModelState,
-    ) -> Result<Vec<Option<Vec<f32>>>> {
+    ) -> Result<()> {
         use super::ModelState;
 
-        let num_token: usize = tokens.iter().map(Vec::len).sum();
         let max_batch = state.max_batch();
-
-        if tokens.len() != max_batch {
-            return Err(ModelError::BatchSize(tokens.len(), max_batch).into());
+        if batch >= max_batch {
+            let max = max_batch;
+            return Err(ModelError::BatchOutOfRange { batch, max }.into());
         }
+        let num_token = embeds.shape()[1];
+        embeds.check_shape(Shape::new(self.info.num_emb, num_token, 1, 1))?;
         if num_token == 0 {
-            return Ok(vec![None; max_batch]);
+            return Err(ModelError::EmptyTokens.into());
         }
 
-        // we only infer at most `token_chunk_size` tokens at a time
-        let mut num_token = num_token.min(self.token_chunk_size);
-        let mut inputs = vec![vec![]; max_batch];
-        let mut last = None;
+        let page = batch / state.page_size();
+        let start = page * state.page_size();
+        let end = (start + state.page_size()).min(max_batch);
+
+        for chunk in (0..num_token).step_by(self.token_chunk_size) {
+            let chunk = chunk..(chunk + self.token_chunk_size).min(num_token);
+            let inputs = (start..end)
+                .map(|index| match index == batch {
+                    true => Ok(embeds
+                        .slice(.., chunk.clone(), .., ..)?
+                        .map(|&x| F::from_f32(x))),
+                    false => Ok(self.context.zeros(Shape::new(self.info.num_emb, 0, 1, 1))),
+                })
+                .collect::<Result<Vec<_>, TensorError>>()?;
+
+            // the state is all that is wanted, so the head is skipped
+            let layers = 0..self.info.num_layer;
+            self.run_input(
+                inputs,
+                state,
+                page,
+                Some(batch),
+                layers,
+                false,
+                RunOutput::Logits,
+            )?;
+        }
+        Ok(())
+    }
 
-        // take `num_token` tokens out of all the inputs and put into `input`
-        for (index, (batch, input)) in tokens.iter_mut().zip(inputs.iter_mut()).enumerate() {
-            let mid = batch.len().min(num_token);
-            num_token -= mid;
+    fn logit_lens(
+        &self,
+        tokens: &[u16],
+        batch: usize,
+        state: &Self::ModelState,
+    ) -> Result<Vec<Vec<f32>>> {
+        use super::ModelState;
 
-            let (head, tail) = batch.split_at(mid);
-            last = (!tail.is_empty()).then_some(index);
-            *input = head.to_vec();
-            *batch = tail.to_vec();
+        let max_batch = state.max_batch();
+        if batch >= max_batch {
+            let max = max_batch;
+            return Err(ModelError::BatchOutOfRange { batch, max }.into());
+        }
+        if tokens.is_empty() {
+            return Err(ModelError::EmptyTokens.into());
+        }
 
-            if num_token == 0 {
-                break;
-            }
+        let context = &self.context;
+        let num_vocab = self.info.num_vocab;
+        let num_layer = self.info.num_layer;
+        let shape = Shape::new(num_vocab, 1, num_layer, 1);
+        let lens: TensorGpu<f32, ReadWrite> = context.tensor_init(shape);
+        let map: TensorGpu<f32, ReadBack> = context.tensor_init(shape);
+
+        let page = batch / state.page_size();
+        let start = page * state.page_size();
+        let end = (start + state.page_size()).min(max_batch);
+
+        let mut chunks = tokens.chunks(self.token_chunk_size).peekable();
+        while let Some(chunk) = chunks.next() {
+            let mut inputs = vec![vec![]; end - start];
+            inputs[batch - start] = chunk.to_vec();
+
+            let layers = 0..num_layer;
+            match chunks.peek() {
+                // only the state is wanted from the chunks before the last, so the head is skipped
+                Some(_) => self.run_internal(
+                    inputs,
+                    state,
+                    page,
+                    Some(batch),
+                    layers,
+                    false,
+                    RunOutput::Logits,
+                )?,
+                None => self.run_internal(
+                    inputs,
+                    state,
+                    page,
+                    None,
+                    layers,
+                    false,
+                    RunOutput::Lens(&lens),
+                )?,
+            };
         }
 
-        let (output, redirect) = self.run_internal(inputs, state, last)?;
-        let output = TensorCpu::from(output.map.clone());
+        let mut encoder = context
+            .device
+            .create_command_encoder(&CommandEncoderDescriptor::default());
+        encoder.copy_tensor(&lens, &map)?;
+        context.queue.submit(Some(encoder.finish()));
 
-        Ok(redirect
-            .into_iter()
-            .map(|index| {
-                index.map(|index| {
-                    output
-                        .slice(.., index, .., ..)
-                        .expect("this never happens")
-                        .to_vec()
-                })
-            })
-            .collect())
+        let lens = TensorCpu::from(map);
+        Ok(lens.iter_batches().map(|lens| lens.to_vec()).collect())
+    }
+
+    fn embed_sequence(
+        &self,
+        tokens: &[u16],
+        batch: usize,
+        state: &Self::ModelState,
+        pooling: Pooling,
+        normalize: bool,
+    ) -> Result<Vec<f32>> {
+        use super::ModelState;
+
+        let max_batch = state.max_batch();
+        if batch >= max_batch {
+            let max = max_batch;
+            return Err(ModelError::BatchOutOfRange { batch, max }.into());
+        }
+        if tokens.is_empty() {
+            return Err(ModelError::EmptyTokens.into());
+        }
+
+        let context = &self.context;
+        let num_emb = self.info.num_emb;
+        let hidden: TensorGpu<f32, ReadWrite> =
+            context.tensor_init(Shape::new(num_emb, tokens.len(), 1, 1));
+
+        let page = batch / state.page_size();
+        let start = page * state.page_size();
+        let end = (start + state.page_size()).min(max_batch);
+
+        let mut offset = 0;
+        for chunk in tokens.chunks(self.token_chunk_size) {
+            let mut inputs = vec![vec![]; end - start];
+            inputs[batch - start] = chunk.to_vec();
+
+            let layers = 0..self.info.num_layer;
+            let output = hidden.view(.., offset..offset + chunk.len(), .., ..)?;
+            self.run_internal(
+                inputs,
+                state,
+                page,
+                None,
+                layers,
+                true,
+                RunOutput::Hidden(output),
+            )?;
+
+            offset += chunk.len();
+        }
+
+        let weight = pooling.weights(tokens.len());
+        let weight: TensorGpu<f32, ReadWrite> =
+            context.tensor_from_data(Shape::new(tokens.len(), 1, 1, 1), weight)?;
+        let embed: TensorGpu<f32, ReadWrite> = context.tensor_init(Shape::new(num_emb, 1, 1, 1));
+        let map: TensorGpu<f32, ReadBack> = context.tensor_init(Shape::new(num_emb, 1, 1, 1));
+
+        let mut ops = vec![TensorOp::pool(&weight, &hidden, &embed)?];
+        if normalize {
+            ops.push(TensorOp::l2_norm(&embed)?);
+        }
+        let ops = TensorOp::List(ops);
+
+        let mut encoder = context
+            .device
+            .create_command_encoder(&CommandEncoderDescriptor::default());
+
+        let mut pass = encoder.begin_compute_pass(&ComputePassDescriptor::default());
+        pass.execute_tensor_op(&ops);
+        drop(pass);
+
+        encoder.copy_tensor(&embed, &map)?;
+        context.queue.submit(Some(encoder.finish()));
+
+        Ok(Vec::from(TensorCpu::from(map)))
+    }
+
+    fn warmup(&self, max_batch: usize, max_tokens: usize) -> Result<()> {
+        let max_batch = max_batch.max(1);
+        let max_tokens = max_tokens.clamp(1, self.token_chunk_size);
+        let state: ModelState = StateBuilder::new(&self.context, &self.info)
+            .with_max_batch(max_batch)
+            .build();
+
+        // a single token multiplies vectors, and a long enough run matrices; the largest run goes last to stay cached
+        let offset = self.tensor.host.len();
+        let (layers, normalize) = (offset..self.info.num_layer, offset == 0);
+        for num_token in [1, max_tokens].into_iter().dedup() {
+            let num_header = num_token.min(state.page_size());
+            let headers = (num_token - num_header..num_token).collect_vec();
+            let (layers, target) = (layers.clone(), RunOutput::Logits);
+            self.record(&state, 0, num_token, &headers, layers, normalize, target)?;
+        }
+
+        let input = vec![Some(vec![0.0; self.info.num_vocab]); max_batch];
+        self.softmax(input)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use super::{Model, ModelState};
+    use crate::{
+        model::{
+            synthetic::{
+                testing::{create_context, run},
+                SyntheticBuilder,
+            },
+            ModelBuilder, ModelVersion, OpKind, StateBuilder,
+        },
+        tensor::ops::TensorOp,
+    };
+
+    #[test]
+    fn test_op_override() -> Result<()> {
+        let context = match create_context() {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+
+        let builder = SyntheticBuilder::new(ModelVersion::V4);
+        let info = builder.info();
+        let data = builder.build()?;
+        let tokens = [vec![5u16, 23, 177]];
+        let logits = |model: &Model| -> Result<Vec<f32>> {
+            let state: ModelState = StateBuilder::new(&context, &info).build();
+            Ok(run(model, &state, &tokens)?.remove(0).unwrap())
+        };
+
+        let model: Model = ModelBuilder::new(&context, &data).build()?;
+        let expected = logits(&model)?;
+
+        // the same kernel, bound by the override, changes nothing
+        let model = model.with_op_override(1, OpKind::ChannelMix, |site| {
+            let runtime = site.runtime;
+            TensorOp::channel_mix(
+                &runtime.cursors,
+                &runtime.ffn_r,
+                &runtime.ffn_v,
+                &runtime.ffn_x,
+                site.state.ffn(site.page, site.layer)?,
+            )
+        });
+        assert_eq!(logits(&model)?, expected);
+
+        // while skipping it does
+        let model = model.with_op_override(1, OpKind::ChannelMix, |_| Ok(TensorOp::List(vec![])));
+        assert_ne!(logits(&model)?, expected);
+        Ok(())
     }
 }
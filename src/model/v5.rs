@@ -1,50 +1,114 @@
-use std::{convert::Infallible, sync::Arc};
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    fmt,
+    ops::Range,
+    sync::{atomic::Ordering, Arc},
+};
 
 use anyhow::Result;
 use half::f16;
 use itertools::Itertools;
-use wgpu::{CommandEncoderDescriptor, ComputePassDescriptor};
+use wgpu::{
+    BufferDescriptor, BufferUsages, CommandEncoder, CommandEncoderDescriptor, ComputePassDescriptor,
+};
 
 use super::{
-    loader::Loader, matrix::Matrix, FromBuilder, ModelBuilder, ModelError, ModelInfo, Quant,
-    StateBuilder,
+    graph::{Graph, GraphBuilder},
+    host::HostLayers,
+    matrix::{Matrix, QuantizationReport},
+    ActivationStats, BuildProgress, FromBuilder, ModelBuilder, ModelError, ModelInfo, ModelVersion,
+    OpKind, Pooling, Precision, Quant, RunOutput, StateBuilder,
 };
 use crate::{
-    context::Context,
-    model::RESCALE_LAYER,
+    context::{Context, MemoryCategory},
+    model::{RESCALE_LAYER, TURBO_MIN_TOKEN},
+    num::{Float, Scalar},
     tensor::{
-        cache::ResourceCache,
-        ops::{TensorCommand, TensorOp, TensorPass},
-        shape::{Shape, TensorDimension},
-        DeepClone, IntoPackedCursors, ReadBack, ReadWrite, TensorCpu, TensorError, TensorGpu,
-        TensorInit, TensorReshape, TensorShape, TensorStack, TensorView,
+        arena::TensorArena,
+        cache::{CacheStats, ResourceCache},
+        ops::{TensorCommand, TensorOp, TensorPass, TensorSequence},
+        shape::{Shape, TensorAxis, TensorDimension},
+        DeepClone, IntoPackedCursors, ReadBack, ReadWrite, StateView, TensorCpu, TensorError,
+        TensorGpu, TensorInit, TensorReshape, TensorShape, TensorStack,
     },
 };
 
+/// The RWKV v5 model, with activations stored as `F`.
 #[derive(Debug)]
-pub struct Model<'a> {
+pub struct Model<'a, F: Float = f32> {
     context: Context,
     info: ModelInfo,
 
-    /// Whether to half the activations every [`RESCALE_LAYER`] layers.
-    rescale: bool,
+    /// Half the activations every this many layers, if set.
+    rescale: Option<usize>,
     /// Whether to use fp16 GEMM for matmul computations.
     turbo: bool,
     /// The head matrix is too big for a storage buffer so it's divided into chunks.
     head_chunk_size: usize,
     /// To prevent the GPU device from lost, this limits the maximum batch-token it processes one time.
     token_chunk_size: usize,
-
-    tensor: ModelTensor<'a>,
-    runtime_cache: ResourceCache<usize, Runtime>,
-    output_cache: ResourceCache<usize, Output>,
+    /// Whether to record the commands of runs and replay them for runs of the same shape.
+    capture: bool,
+
+    /// Weights on the device, shared by every handle made by [`Model::share`].
+    tensor: Arc<ModelTensor<'a>>,
+    /// Reconstruction errors of quantized matrices, if requested by the builder.
+    quant_report: Option<QuantizationReport>,
+    runtime_cache: ResourceCache<usize, Runtime<F>>,
+    output_cache: ResourceCache<usize, Output<F>>,
     softmax_cache: ResourceCache<usize, Softmax>,
+    capture_cache: ResourceCache<CaptureKey, Capture<F>>,
+    /// Batches and tokens of the largest runs the reserved buffers are allocated for, if declared.
+    envelope: Option<(usize, usize)>,
+    reserve: Option<Reserve<F>>,
+    /// Custom ops replacing some of those of the layers.
+    overrides: OpOverrides<F>,
+    /// Statistics of the output of each layer, folded in on every run if set; see [`Model::with_activation_stats`].
+    stats: Option<TensorGpu<u32, ReadWrite>>,
+}
+
+/// Builds a replacement for an op of a layer; see [`Model::with_op_override`].
+pub type OpOverride<F> = Arc<dyn Fn(&OpSite<'_, F>) -> Result<TensorOp, TensorError> + Send + Sync>;
+
+/// The op to be replaced, and the buffers a replacement may bind.
+pub struct OpSite<'b, F: Float> {
+    pub layer: usize,
+    pub kind: OpKind,
+    /// Number of tokens of the run, over all batches.
+    pub num_token: usize,
+    pub runtime: &'b Runtime<F>,
+    pub state: &'b ModelState,
+    pub page: usize,
+}
+
+struct OpOverrides<F: Float>(HashMap<(usize, OpKind), OpOverride<F>>);
+
+impl<F: Float> Default for OpOverrides<F> {
+    fn default() -> Self {
+        Self(HashMap::new())
+    }
+}
+
+impl<F: Float> Clone for OpOverrides<F> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<F: Float> fmt::Debug for OpOverrides<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.0.keys()).finish()
+    }
 }
 
 #[derive(Debug)]
 struct ModelTensor<'a> {
     embed: Embed<'a>,
     head: Head,
+    /// The first layers, if run on the host.
+    host: HostLayers,
+    /// The layers on the device, after those on the host.
     layers: Vec<Layer>,
 }
 
@@ -91,6 +155,8 @@ struct Layer {
     ffn: Ffn,
 }
 
+/// The embedding matrix is kept in host memory: only the rows of the tokens in a run are looked up
+/// and uploaded, so it takes no device memory however large the vocabulary.
 #[derive(Debug)]
 struct Embed<'a> {
     layer_norm: LayerNorm,
@@ -103,35 +169,177 @@ struct Head {
     w: Vec<TensorGpu<f16, ReadWrite>>,
 }
 
-/// Runtime buffers.
+impl LayerNorm {
+    fn transfer(&self, arena: &TensorArena) -> Result<Self, TensorError> {
+        Ok(Self {
+            w: arena.transfer(&self.w)?,
+            b: arena.transfer(&self.b)?,
+        })
+    }
+
+    fn destroy(self) {
+        self.w.destroy();
+        self.b.destroy();
+    }
+}
+
+impl Att {
+    fn transfer(&self, to: &Context, arena: &TensorArena) -> Result<Self, TensorError> {
+        Ok(Self {
+            time_decay: arena.transfer(&self.time_decay)?,
+            time_first: arena.transfer(&self.time_first)?,
+            time_mix_k: arena.transfer(&self.time_mix_k)?,
+            time_mix_v: arena.transfer(&self.time_mix_v)?,
+            time_mix_r: arena.transfer(&self.time_mix_r)?,
+            time_mix_g: arena.transfer(&self.time_mix_g)?,
+            w_k: self.w_k.transfer(to)?,
+            w_v: self.w_v.transfer(to)?,
+            w_r: self.w_r.transfer(to)?,
+            w_g: self.w_g.transfer(to)?,
+            w_o: self.w_o.transfer(to)?,
+            group_norm: self.group_norm.transfer(arena)?,
+        })
+    }
+
+    fn destroy(self) {
+        let Self {
+            time_decay,
+            time_first,
+            time_mix_k,
+            time_mix_v,
+            time_mix_r,
+            time_mix_g,
+            w_k,
+            w_v,
+            w_r,
+            w_g,
+            w_o,
+            group_norm,
+        } = self;
+        for tensor in [time_decay, time_first] {
+            tensor.destroy();
+        }
+        for tensor in [time_mix_k, time_mix_v, time_mix_r, time_mix_g] {
+            tensor.destroy();
+        }
+        for matrix in [w_k, w_v, w_r, w_g, w_o] {
+            matrix.destroy();
+        }
+        group_norm.destroy();
+    }
+}
+
+impl Ffn {
+    fn transfer(&self, to: &Context, arena: &TensorArena) -> Result<Self, TensorError> {
+        Ok(Self {
+            time_mix_k: arena.transfer(&self.time_mix_k)?,
+            time_mix_r: arena.transfer(&self.time_mix_r)?,
+            w_k: self.w_k.transfer(to)?,
+            w_v: self.w_v.transfer(to)?,
+            w_r: self.w_r.transfer(to)?,
+        })
+    }
+
+    fn destroy(self) {
+        let Self {
+            time_mix_k,
+            time_mix_r,
+            w_k,
+            w_v,
+            w_r,
+        } = self;
+        for tensor in [time_mix_k, time_mix_r] {
+            tensor.destroy();
+        }
+        for matrix in [w_k, w_v, w_r] {
+            matrix.destroy();
+        }
+    }
+}
+
+impl<'a> ModelTensor<'a> {
+    /// Copy every weight to another context, through the host.
+    fn transfer(&self, to: &Context) -> Result<Self, TensorError> {
+        let arena = TensorArena::new(to);
+        let mut w = self.embed.w.clone();
+        w.context = to.clone();
+        let embed = Embed {
+            layer_norm: self.embed.layer_norm.transfer(&arena)?,
+            w,
+        };
+        let head = Head {
+            layer_norm: self.head.layer_norm.transfer(&arena)?,
+            w: self.head.w.iter().map(|w| w.transfer(to)).try_collect()?,
+        };
+        let layers = self
+            .layers
+            .iter()
+            .map(|layer| -> Result<_, TensorError> {
+                let layer = Layer {
+                    att_layer_norm: layer.att_layer_norm.transfer(&arena)?,
+                    ffn_layer_norm: layer.ffn_layer_norm.transfer(&arena)?,
+                    att: layer.att.transfer(to, &arena)?,
+                    ffn: layer.ffn.transfer(to, &arena)?,
+                };
+                // wait for the layer to be uploaded, so that its staging buffers are freed before the next is read
+                to.queue.submit(None);
+                to.device.poll(wgpu::MaintainBase::Wait);
+                Ok(layer)
+            })
+            .try_collect()?;
+        Ok(Self {
+            embed,
+            head,
+            host: self.host.clone(),
+            layers,
+        })
+    }
+
+    /// Free the device memory of every weight now.
+    fn destroy(self) {
+        self.embed.layer_norm.destroy();
+        self.head.layer_norm.destroy();
+        for w in self.head.w {
+            w.destroy();
+        }
+        for layer in self.layers {
+            layer.att_layer_norm.destroy();
+            layer.ffn_layer_norm.destroy();
+            layer.att.destroy();
+            layer.ffn.destroy();
+        }
+    }
+}
+
+/// Runtime buffers, holding the activations of a run.
 #[derive(Debug)]
-struct Runtime {
-    cursors: TensorGpu<u32, ReadWrite>,
-    input: TensorGpu<f32, ReadWrite>,
-
-    att_x: TensorGpu<f32, ReadWrite>,
-    att_kx: TensorGpu<f32, ReadWrite>,
-    att_vx: TensorGpu<f32, ReadWrite>,
-    att_rx: TensorGpu<f32, ReadWrite>,
-    att_gx: TensorGpu<f32, ReadWrite>,
-    att_k: TensorGpu<f32, ReadWrite>,
-    att_v: TensorGpu<f32, ReadWrite>,
-    att_r: TensorGpu<f32, ReadWrite>,
-    att_g: TensorGpu<f32, ReadWrite>,
-    att_o: TensorGpu<f32, ReadWrite>,
-
-    ffn_x: TensorGpu<f32, ReadWrite>,
-    ffn_kx: TensorGpu<f32, ReadWrite>,
-    ffn_rx: TensorGpu<f32, ReadWrite>,
-    ffn_k: TensorGpu<f32, ReadWrite>,
-    ffn_v: TensorGpu<f32, ReadWrite>,
-    ffn_r: TensorGpu<f32, ReadWrite>,
-
-    half_x: TensorGpu<f16, ReadWrite>,
-    half_k: TensorGpu<f16, ReadWrite>,
+pub struct Runtime<F: Float> {
+    pub cursors: TensorGpu<u32, ReadWrite>,
+    pub input: TensorGpu<F, ReadWrite>,
+
+    pub att_x: TensorGpu<F, ReadWrite>,
+    pub att_kx: TensorGpu<F, ReadWrite>,
+    pub att_vx: TensorGpu<F, ReadWrite>,
+    pub att_rx: TensorGpu<F, ReadWrite>,
+    pub att_gx: TensorGpu<F, ReadWrite>,
+    pub att_k: TensorGpu<F, ReadWrite>,
+    pub att_v: TensorGpu<F, ReadWrite>,
+    pub att_r: TensorGpu<F, ReadWrite>,
+    pub att_g: TensorGpu<F, ReadWrite>,
+    pub att_o: TensorGpu<F, ReadWrite>,
+
+    pub ffn_x: TensorGpu<F, ReadWrite>,
+    pub ffn_kx: TensorGpu<F, ReadWrite>,
+    pub ffn_rx: TensorGpu<F, ReadWrite>,
+    pub ffn_k: TensorGpu<F, ReadWrite>,
+    pub ffn_v: TensorGpu<F, ReadWrite>,
+    pub ffn_r: TensorGpu<F, ReadWrite>,
+
+    pub half_x: TensorGpu<f16, ReadWrite>,
+    pub half_k: TensorGpu<f16, ReadWrite>,
 }
 
-impl Runtime {
+impl<F: Float> Runtime<F> {
     pub fn new(context: &Context, info: &ModelInfo, num_token: usize, max_token: usize) -> Self {
         let shape = Shape::new(info.num_emb, num_token, 1, 1);
         let cursors_shape = Shape::new(max_token, 1, 1, 1);
@@ -160,16 +368,46 @@ impl Runtime {
             half_k: context.tensor_init(hidden_shape),
         }
     }
+
+    /// The buffers of a run of `num_token` tokens over the front of these, which must have room for them.
+    fn shrink(&self, num_token: usize, max_token: usize) -> Result<Self, TensorError> {
+        let shape = Shape::new(self.input.shape()[0], num_token, 1, 1);
+        let cursors_shape = Shape::new(max_token, 1, 1, 1);
+        let hidden_shape = Shape::new(self.ffn_k.shape()[0], num_token, 1, 1);
+
+        Ok(Self {
+            cursors: self.cursors.shrink(cursors_shape)?,
+            input: self.input.shrink(shape)?,
+            att_x: self.att_x.shrink(shape)?,
+            att_kx: self.att_kx.shrink(shape)?,
+            att_vx: self.att_vx.shrink(shape)?,
+            att_rx: self.att_rx.shrink(shape)?,
+            att_gx: self.att_gx.shrink(shape)?,
+            att_k: self.att_k.shrink(shape)?,
+            att_v: self.att_v.shrink(shape)?,
+            att_r: self.att_r.shrink(shape)?,
+            att_g: self.att_g.shrink(shape)?,
+            att_o: self.att_o.shrink(shape)?,
+            ffn_x: self.ffn_x.shrink(shape)?,
+            ffn_kx: self.ffn_kx.shrink(shape)?,
+            ffn_rx: self.ffn_rx.shrink(shape)?,
+            ffn_k: self.ffn_k.shrink(hidden_shape)?,
+            ffn_v: self.ffn_v.shrink(shape)?,
+            ffn_r: self.ffn_r.shrink(shape)?,
+            half_x: self.half_x.shrink(shape)?,
+            half_k: self.half_k.shrink(hidden_shape)?,
+        })
+    }
 }
 
 #[derive(Debug)]
-struct Output {
-    head_x: TensorGpu<f32, ReadWrite>,
+struct Output<F: Float> {
+    head_x: TensorGpu<F, ReadWrite>,
     head_o: TensorGpu<f32, ReadWrite>,
     map: TensorGpu<f32, ReadBack>,
 }
 
-impl Output {
+impl<F: Float> Output<F> {
     pub fn new(context: &Context, info: &ModelInfo, num_batch: usize) -> Self {
         let head_shape = Shape::new(info.num_emb, num_batch, 1, 1);
         let output_shape = Shape::new(info.num_vocab, num_batch, 1, 1);
@@ -180,11 +418,23 @@ impl Output {
             map: context.tensor_init(output_shape),
         }
     }
+
+    fn shrink(&self, num_batch: usize) -> Result<Self, TensorError> {
+        let head_shape = Shape::new(self.head_x.shape()[0], num_batch, 1, 1);
+        let output_shape = Shape::new(self.head_o.shape()[0], num_batch, 1, 1);
+
+        Ok(Self {
+            head_x: self.head_x.shrink(head_shape)?,
+            head_o: self.head_o.shrink(output_shape)?,
+            map: self.map.shrink(output_shape)?,
+        })
+    }
 }
 
 #[derive(Debug)]
 struct Softmax {
     buffer: TensorGpu<f32, ReadWrite>,
+    temperature: TensorGpu<f32, ReadWrite>,
     map: TensorGpu<f32, ReadBack>,
 }
 
@@ -193,9 +443,66 @@ impl Softmax {
         let shape = Shape::new(info.num_vocab, 1, num_batch, 1);
         Self {
             buffer: context.tensor_init(shape),
+            temperature: context.tensor_init(Shape::new(num_batch, 1, 1, 1)),
             map: context.tensor_init(shape),
         }
     }
+
+    fn shrink(&self, num_batch: usize) -> Result<Self, TensorError> {
+        let shape = Shape::new(self.buffer.shape()[0], 1, num_batch, 1);
+        Ok(Self {
+            buffer: self.buffer.shrink(shape)?,
+            temperature: self.temperature.shrink(Shape::new(num_batch, 1, 1, 1))?,
+            map: self.map.shrink(shape)?,
+        })
+    }
+}
+
+/// Runtime buffers allocated at build time for the largest runs of the envelope declared with
+/// [`ModelBuilder::with_envelope`]; runs within it take the front of these instead of allocating their own.
+#[derive(Debug)]
+struct Reserve<F: Float> {
+    runtime: Runtime<F>,
+    output: Output<F>,
+    softmax: Softmax,
+}
+
+impl<F: Float> Reserve<F> {
+    fn new(
+        context: &Context,
+        info: &ModelInfo,
+        envelope: (usize, usize),
+        max_token: usize,
+    ) -> Self {
+        let _scope = context.memory_scope(MemoryCategory::Runtime);
+        let (max_batch, num_token) = envelope;
+        let max_token = max_token.max(num_token);
+        Self {
+            runtime: Runtime::new(context, info, num_token, max_token),
+            output: Output::new(context, info, max_batch),
+            softmax: Softmax::new(context, info, max_batch),
+        }
+    }
+}
+
+/// What a captured run depends on besides the model: replaying it for another key would touch the wrong buffers.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct CaptureKey {
+    state: usize,
+    num_token: usize,
+    headers: Vec<usize>,
+    layers: Range<usize>,
+    normalize: bool,
+}
+
+/// The commands of a run, recorded once and replayed into the encoder of every run of the same [`CaptureKey`].
+#[derive(Debug)]
+struct Capture<F: Float> {
+    /// Keeps the state page alive, so that its address is not taken by another state while the capture is cached.
+    _page: Vec<StateTensor>,
+    runtime: Arc<Runtime<F>>,
+    output: Arc<Output<F>>,
+    sequence: TensorSequence,
 }
 
 #[derive(Debug, Clone)]
@@ -205,27 +512,317 @@ pub struct ModelState {
     max_batch: usize,
     chunk_size: usize,
     head_size: usize,
-    state: Vec<TensorGpu<f32, ReadWrite>>,
+    page_size: Option<usize>,
+    dtype: Precision,
+    /// Chunks of layers of each page of batches.
+    state: Vec<Vec<StateTensor>>,
+}
+
+/// One chunk of the device state, stored in the precision chosen by [`StateBuilder::with_dtype`].
+#[derive(Debug, Clone)]
+enum StateTensor {
+    F32(TensorGpu<f32, ReadWrite>),
+    F16(TensorGpu<f16, ReadWrite>),
+}
+
+impl StateTensor {
+    fn new(context: &Context, shape: Shape, dtype: Precision) -> Self {
+        match dtype {
+            Precision::F32 => Self::F32(context.tensor_init(shape)),
+            Precision::F16 => Self::F16(context.tensor_init(shape)),
+        }
+    }
+
+    fn context(&self) -> &Context {
+        match self {
+            StateTensor::F32(tensor) => &tensor.context,
+            StateTensor::F16(tensor) => &tensor.context,
+        }
+    }
+
+    fn view(
+        &self,
+        x: impl TensorAxis,
+        y: impl TensorAxis,
+        z: impl TensorAxis,
+        w: impl TensorAxis,
+    ) -> Result<StateView<'_>, TensorError> {
+        match self {
+            StateTensor::F32(tensor) => tensor.view(x, y, z, w).map(StateView::F32),
+            StateTensor::F16(tensor) => tensor.view(x, y, z, w).map(StateView::F16),
+        }
+    }
+
+    /// Upload host data of the given shape, either to the whole tensor or to one batch of it.
+    fn load(&self, shape: Shape, data: &[f32], batch: Option<usize>) -> Result<(), TensorError> {
+        let context = self.context();
+        match self {
+            StateTensor::F32(tensor) => {
+                let host = context.tensor_from_data(shape, data)?;
+                match batch {
+                    Some(batch) => tensor.load_batch(&host, batch),
+                    None => tensor.load(&host),
+                }
+            }
+            StateTensor::F16(tensor) => {
+                let data = data.iter().map(|x| f16::from_f32(*x)).collect_vec();
+                let host = context.tensor_from_data(shape, data)?;
+                match batch {
+                    Some(batch) => tensor.load_batch(&host, batch),
+                    None => tensor.load(&host),
+                }
+            }
+        }
+    }
+
+    /// Read back either the whole tensor or one batch of it, as f32.
+    fn back(&self, batch: Option<usize>) -> Result<(Shape, Vec<f32>), TensorError> {
+        fn back<T: Scalar>(
+            tensor: &TensorGpu<T, ReadWrite>,
+            batch: Option<usize>,
+        ) -> Result<(Shape, Vec<T>), TensorError> {
+            let shape = tensor.shape();
+            let shape = match batch {
+                Some(_) => Shape::new(shape[0], shape[1], 1, 1),
+                None => shape,
+            };
+            let map = tensor.context.tensor_init(shape);
+
+            let mut encoder = tensor
+                .context
+                .device
+                .create_command_encoder(&CommandEncoderDescriptor::default());
+            match batch {
+                Some(batch) => encoder.copy_tensor_batch(tensor, &map, batch)?,
+                None => encoder.copy_tensor(tensor, &map)?,
+            }
+            tensor.context.queue.submit(Some(encoder.finish()));
+
+            let host = TensorCpu::from(map);
+            Ok((shape, host.to_vec()))
+        }
+
+        match self {
+            StateTensor::F32(tensor) => back(tensor, batch),
+            StateTensor::F16(tensor) => {
+                let (shape, data) = back(tensor, batch)?;
+                Ok((shape, data.into_iter().map(f16::to_f32).collect()))
+            }
+        }
+    }
+
+    fn blit(&self, other: &Self) -> Result<(), TensorError> {
+        let mut encoder = self
+            .context()
+            .device
+            .create_command_encoder(&CommandEncoderDescriptor::default());
+        match (self, other) {
+            (StateTensor::F32(tensor), StateTensor::F32(other)) => {
+                encoder.copy_tensor(tensor, other)?
+            }
+            (StateTensor::F16(tensor), StateTensor::F16(other)) => {
+                encoder.copy_tensor(tensor, other)?
+            }
+            (StateTensor::F32(_), StateTensor::F16(_)) => return Err(TensorError::Type),
+            (StateTensor::F16(_), StateTensor::F32(_)) => return Err(TensorError::Type),
+        }
+        self.context().queue.submit(Some(encoder.finish()));
+        Ok(())
+    }
+
+    /// Record a copy of the first `num_batch` batches into `other`, whose batches are of the same size.
+    fn copy_batches(&self, encoder: &mut CommandEncoder, other: &Self, num_batch: usize) {
+        fn copy<T: Scalar>(
+            encoder: &mut CommandEncoder,
+            tensor: &TensorGpu<T, ReadWrite>,
+            other: &TensorGpu<T, ReadWrite>,
+            num_batch: usize,
+        ) {
+            let shape = tensor.shape();
+            let size = (T::size() * shape[0] * shape[1] * num_batch) as u64;
+            encoder.copy_buffer_to_buffer(&tensor.buffer, 0, &other.buffer, 0, size);
+        }
+
+        match (self, other) {
+            (StateTensor::F32(tensor), StateTensor::F32(other)) => {
+                copy(encoder, tensor, other, num_batch)
+            }
+            (StateTensor::F16(tensor), StateTensor::F16(other)) => {
+                copy(encoder, tensor, other, num_batch)
+            }
+            _ => unreachable!("states of the same dtype"),
+        }
+    }
+
+    fn blit_batch(
+        &self,
+        other: &Self,
+        from_batch: usize,
+        to_batch: usize,
+    ) -> Result<(), TensorError> {
+        // batches are contiguous, so the buffer range is copied instead of blitting views, which can't bind
+        // the same buffer twice when copying between batches of one state
+        fn copy<T: Scalar>(
+            context: &Context,
+            encoder: &mut CommandEncoder,
+            tensor: &TensorGpu<T, ReadWrite>,
+            other: &TensorGpu<T, ReadWrite>,
+            from_batch: usize,
+            to_batch: usize,
+        ) -> Result<(), TensorError> {
+            let shape = tensor.shape();
+            let other_shape = other.shape();
+            if shape[0] != other_shape[0] || shape[1] != other_shape[1] {
+                return Err(TensorError::Shape(shape, other_shape));
+            }
+            for (batch, max) in [(from_batch, shape[2]), (to_batch, other_shape[2])] {
+                if batch >= max {
+                    return Err(TensorError::BatchOutOfRange { batch, max });
+                }
+            }
+            let size = (T::size() * shape[0] * shape[1]) as u64;
+            let from = tensor.offset + size * from_batch as u64;
+            let to = other.offset + size * to_batch as u64;
+            if Arc::ptr_eq(&tensor.buffer, &other.buffer) {
+                // a buffer cannot be copied onto itself, so go through a temporary one
+                let temp = context.device.create_buffer(&BufferDescriptor {
+                    label: None,
+                    size,
+                    usage: BufferUsages::COPY_SRC | BufferUsages::COPY_DST,
+                    mapped_at_creation: false,
+                });
+                encoder.copy_buffer_to_buffer(&tensor.buffer, from, &temp, 0, size);
+                encoder.copy_buffer_to_buffer(&temp, 0, &other.buffer, to, size);
+            } else {
+                encoder.copy_buffer_to_buffer(&tensor.buffer, from, &other.buffer, to, size);
+            }
+            Ok(())
+        }
+
+        let context = self.context();
+        let mut encoder = context
+            .device
+            .create_command_encoder(&CommandEncoderDescriptor::default());
+        match (self, other) {
+            (StateTensor::F32(tensor), StateTensor::F32(other)) => {
+                copy(context, &mut encoder, tensor, other, from_batch, to_batch)?
+            }
+            (StateTensor::F16(tensor), StateTensor::F16(other)) => {
+                copy(context, &mut encoder, tensor, other, from_batch, to_batch)?
+            }
+            (StateTensor::F32(_), StateTensor::F16(_)) => return Err(TensorError::Type),
+            (StateTensor::F16(_), StateTensor::F32(_)) => return Err(TensorError::Type),
+        }
+        context.queue.submit(Some(encoder.finish()));
+        Ok(())
+    }
+}
+
+impl TensorShape for StateTensor {
+    #[inline]
+    fn shape(&self) -> Shape {
+        match self {
+            StateTensor::F32(tensor) => tensor.shape(),
+            StateTensor::F16(tensor) => tensor.shape(),
+        }
+    }
+}
+
+impl DeepClone for StateTensor {
+    fn deep_clone(&self) -> Self {
+        match self {
+            StateTensor::F32(tensor) => StateTensor::F32(tensor.deep_clone()),
+            StateTensor::F16(tensor) => StateTensor::F16(tensor.deep_clone()),
+        }
+    }
 }
 
 impl ModelState {
-    fn att(&self, layer: usize) -> Result<TensorView<f32>, TensorError> {
+    fn new(
+        context: &Context,
+        info: &ModelInfo,
+        max_batch: usize,
+        chunk_size: usize,
+        page_size: Option<usize>,
+        dtype: Precision,
+    ) -> Self {
+        let _scope = context.memory_scope(MemoryCategory::State);
+        let mut state = Self {
+            context: context.clone(),
+            info: info.clone(),
+            max_batch,
+            chunk_size,
+            head_size: info.num_emb / info.num_head,
+            page_size,
+            dtype,
+            state: vec![],
+        };
+        let num_chunk = (info.num_layer + chunk_size - 1) / chunk_size;
+        let head_size = state.head_size;
+        let page_size = state.page_size();
+        state.state = (0..max_batch)
+            .step_by(page_size)
+            .map(|start| {
+                let num_batch = page_size.min(max_batch - start);
+                (0..num_chunk)
+                    .map(|_| {
+                        let shape =
+                            Shape::new(info.num_emb, chunk_size * (head_size + 2), num_batch, 1);
+                        StateTensor::new(context, shape, dtype)
+                    })
+                    .collect()
+            })
+            .collect();
+        state
+    }
+
+    /// The attention state of `layer` in `page`.
+    pub fn att(&self, page: usize, layer: usize) -> Result<StateView<'_>, TensorError> {
         let chunk = layer / self.chunk_size;
         let offset = layer % self.chunk_size;
         let head_size = self.info.num_emb / self.info.num_head;
 
         let start = offset * (head_size + 2);
         let end = start + head_size + 1;
-        self.state[chunk].view(.., start..end, .., ..)
+        self.state[page][chunk].view(.., start..end, .., ..)
     }
 
-    fn ffn(&self, layer: usize) -> Result<TensorView<f32>, TensorError> {
+    /// The FFN state of `layer` in `page`.
+    pub fn ffn(&self, page: usize, layer: usize) -> Result<StateView<'_>, TensorError> {
         let chunk = layer / self.chunk_size;
         let offset = layer % self.chunk_size;
         let head_size = self.info.num_emb / self.info.num_head;
 
         let start = offset * (head_size + 2) + head_size + 1;
-        self.state[chunk].view(.., start..=start, .., ..)
+        self.state[page][chunk].view(.., start..=start, .., ..)
+    }
+
+    /// Address of the first buffer of `page`, which tells it apart from those of other states while it is alive.
+    #[inline]
+    fn page_id(&self, page: usize) -> usize {
+        match &self.state[page][0] {
+            StateTensor::F32(tensor) => Arc::as_ptr(&tensor.data().buffer) as usize,
+            StateTensor::F16(tensor) => Arc::as_ptr(&tensor.data().buffer) as usize,
+        }
+    }
+
+    /// Number of batches in each page but the last.
+    #[inline]
+    fn page_size(&self) -> usize {
+        self.page_size.unwrap_or(self.max_batch).max(1)
+    }
+
+    /// The page holding `batch`, and the index of the batch within it.
+    #[inline]
+    fn locate(&self, batch: usize) -> Result<(usize, usize), TensorError> {
+        let page_size = self.page_size();
+        match batch < self.max_batch {
+            true => Ok((batch / page_size, batch % page_size)),
+            false => Err(TensorError::BatchOutOfRange {
+                batch,
+                max: self.max_batch,
+            }),
+        }
     }
 }
 
@@ -234,7 +831,7 @@ impl DeepClone for ModelState {
         let state = self
             .state
             .iter()
-            .map(|tensor| tensor.deep_clone())
+            .map(|page| page.iter().map(|tensor| tensor.deep_clone()).collect())
             .collect();
         Self {
             state,
@@ -253,31 +850,12 @@ impl FromBuilder for ModelState {
             info,
             max_batch,
             chunk_size,
+            page_size,
+            dtype,
         } = builder;
-        let num_chunk = (info.num_layer + chunk_size - 1) / chunk_size;
-        let head_size = info.num_emb / info.num_head;
-        let state = (0..num_chunk)
-            .map(|_| {
-                let data = (0..max_batch)
-                    .map(|_| vec![0.0; chunk_size * info.num_emb * (head_size + 2)])
-                    .collect_vec()
-                    .concat();
-                context
-                    .tensor_from_data(
-                        Shape::new(info.num_emb, chunk_size * (head_size + 2), max_batch, 1),
-                        data,
-                    )
-                    .expect("state creation")
-            })
-            .collect();
-        Ok(Self {
-            context: context.clone(),
-            info: info.clone(),
-            max_batch,
-            chunk_size,
-            head_size,
-            state,
-        })
+        Ok(Self::new(
+            &context, &info, max_batch, chunk_size, page_size, dtype,
+        ))
     }
 }
 
@@ -299,9 +877,15 @@ impl super::ModelState for ModelState {
         if backed.max_batch() != self.max_batch() {
             return Err(ModelError::BatchSize(backed.max_batch(), self.max_batch()).into());
         }
-        for (state, (shape, backed)) in self.state.iter().zip(backed.data.iter()) {
-            let host = state.context.tensor_from_data(*shape, backed)?;
-            state.load(&host)?;
+        for (chunk, (shape, backed)) in backed.data.iter().enumerate().take(self.state[0].len()) {
+            let stride = shape[0] * shape[1];
+            for (page, state) in self.state.iter().enumerate() {
+                let start = page * self.page_size();
+                let num_batch = state[chunk].shape()[2];
+                let shape = Shape::new(shape[0], shape[1], num_batch, 1);
+                let data = &backed[start * stride..(start + num_batch) * stride];
+                state[chunk].load(shape, data, None)?;
+            }
         }
         Ok(())
     }
@@ -311,11 +895,11 @@ impl super::ModelState for ModelState {
         if backed.max_batch() != 1 {
             return Err(ModelError::BatchSize(backed.max_batch(), 1).into());
         }
-        for (state, (_, backed)) in self.state.iter().zip(backed.data.iter()) {
+        let (page, batch) = self.locate(batch)?;
+        for (state, (_, backed)) in self.state[page].iter().zip(backed.data.iter()) {
             let shape = state.shape();
             let shape = Shape::new(shape[0], shape[1], 1, 1);
-            let host = state.context.tensor_from_data(shape, backed)?;
-            state.load_batch(&host, batch)?;
+            state.load(shape, backed, Some(batch))?;
         }
         Ok(())
     }
@@ -325,26 +909,22 @@ impl super::ModelState for ModelState {
         let chunk_size = self.chunk_size;
         let head_size = self.head_size;
 
-        let data = self
-            .state
-            .iter()
-            .map(|state| {
-                let shape = state.shape();
-                let map = state.context.tensor_init(shape);
-
-                let mut encoder = state
-                    .context
-                    .device
-                    .create_command_encoder(&CommandEncoderDescriptor::default());
-                encoder.copy_tensor(state, &map).expect("back entire state");
-                state.context.queue.submit(Some(encoder.finish()));
-
-                let host = TensorCpu::from(map);
-                (shape, host.to_vec())
+        let data = (0..self.state[0].len())
+            .map(|chunk| {
+                let pages = self
+                    .state
+                    .iter()
+                    .map(|state| state[chunk].back(None).expect("back entire state"))
+                    .collect_vec();
+                let shape = pages[0].0;
+                let shape = Shape::new(shape[0], shape[1], max_batch, 1);
+                let data = pages.into_iter().flat_map(|(_, data)| data).collect();
+                (shape, data)
             })
             .collect();
         BackedState {
             max_batch,
+            num_layer: self.info.num_layer,
             chunk_size,
             head_size,
             data,
@@ -352,41 +932,19 @@ impl super::ModelState for ModelState {
     }
 
     fn back_batch(&self, batch: usize) -> Result<BackedState> {
-        let max_batch = self.max_batch;
         let chunk_size = self.chunk_size;
         let head_size = self.head_size;
 
-        if batch >= max_batch {
-            return Err(ModelError::BatchOutOfRange {
-                batch,
-                max: max_batch,
-            }
-            .into());
-        }
-
-        let data: Result<Vec<_>, _> = self
-            .state
+        let (page, batch) = self.locate(batch)?;
+        let data: Result<Vec<_>, _> = self.state[page]
             .iter()
-            .map(|state| -> Result<_, TensorError> {
-                let shape = state.shape();
-                let shape = Shape::new(shape[0], shape[1], 1, 1);
-                let map = state.context.tensor_init(shape);
-
-                let mut encoder = state
-                    .context
-                    .device
-                    .create_command_encoder(&CommandEncoderDescriptor::default());
-                encoder.copy_tensor_batch(state, &map, batch)?;
-                state.context.queue.submit(Some(encoder.finish()));
-
-                let host = TensorCpu::from(map);
-                Ok((shape, host.to_vec()))
-            })
+            .map(|state| state.back(Some(batch)))
             .collect();
         let data = data?;
 
         Ok(BackedState {
             max_batch: 1,
+            num_layer: self.info.num_layer,
             chunk_size,
             head_size,
             data,
@@ -394,39 +952,62 @@ impl super::ModelState for ModelState {
     }
 
     fn blit(&self, other: &ModelState) -> Result<(), TensorError> {
-        for (state, other) in self.state.iter().zip(other.state.iter()) {
+        if self.state.len() != other.state.len() {
+            let shape = |state: &ModelState| {
+                let shape = state.state[0][0].shape();
+                Shape::new(shape[0], shape[1], state.max_batch, 1)
+            };
+            return Err(TensorError::Shape(shape(self), shape(other)));
+        }
+        for (state, other) in self
+            .state
+            .iter()
+            .flatten()
+            .zip(other.state.iter().flatten())
+        {
             state.check_shape(other.shape())?;
-            let mut encoder = state
-                .context
-                .device
-                .create_command_encoder(&CommandEncoderDescriptor::default());
-            encoder.copy_tensor(state, other)?;
-            state.context.queue.submit(Some(encoder.finish()));
+            state.blit(other)?;
         }
         Ok(())
     }
 
+    fn resize(&self, num_batch: usize) -> Result<Self> {
+        let state = Self::new(
+            &self.context,
+            &self.info,
+            num_batch,
+            self.chunk_size,
+            self.page_size,
+            self.dtype,
+        );
+        let mut encoder = self
+            .context
+            .device
+            .create_command_encoder(&CommandEncoderDescriptor::default());
+        // pages of both states are of the same size, so batches keep their place within a page
+        for (page, other) in self.state.iter().zip(state.state.iter()) {
+            for (tensor, other) in page.iter().zip(other.iter()) {
+                let num_batch = tensor.shape()[2].min(other.shape()[2]);
+                tensor.copy_batches(&mut encoder, other, num_batch);
+            }
+        }
+        self.context.queue.submit(Some(encoder.finish()));
+        Ok(state)
+    }
+
     fn blit_batch(
         &self,
         other: &ModelState,
         from_batch: usize,
         to_batch: usize,
     ) -> Result<(), TensorError> {
-        for (state, other) in self.state.iter().zip(other.state.iter()) {
-            let op: TensorOp<'_> = TensorOp::blit(
-                state.view(.., .., from_batch, ..)?,
-                other.view(.., .., to_batch, ..)?,
-            )?;
-            let mut encoder = state
-                .context
-                .device
-                .create_command_encoder(&CommandEncoderDescriptor::default());
-
-            let mut pass = encoder.begin_compute_pass(&ComputePassDescriptor::default());
-            pass.execute_tensor_op(&op);
-            drop(pass);
-
-            state.context.queue.submit(Some(encoder.finish()));
+        let (from_page, from_batch) = self.locate(from_batch)?;
+        let (to_page, to_batch) = other.locate(to_batch)?;
+        for (state, other) in self.state[from_page]
+            .iter()
+            .zip(other.state[to_page].iter())
+        {
+            state.blit_batch(other, from_batch, to_batch)?;
         }
         Ok(())
     }
@@ -435,6 +1016,7 @@ impl super::ModelState for ModelState {
 #[derive(Debug, Clone)]
 pub struct BackedState {
     pub max_batch: usize,
+    pub num_layer: usize,
     pub chunk_size: usize,
     pub head_size: usize,
     pub data: Vec<(Shape, Vec<f32>)>,
@@ -453,7 +1035,7 @@ impl FromBuilder for BackedState {
         } = builder;
         let head_size = info.num_emb / info.num_head;
         let shape = Shape::new(info.num_emb, chunk_size * (head_size + 2), max_batch, 1);
-        let data = (0..info.num_layer)
+        let data = (0..info.num_layer.div_ceil(chunk_size))
             .map(|_| {
                 (0..max_batch)
                     .map(|_| vec![0.0; chunk_size * info.num_emb * (head_size + 2)])
@@ -464,6 +1046,7 @@ impl FromBuilder for BackedState {
             .collect();
         Ok(Self {
             max_batch,
+            num_layer: info.num_layer,
             chunk_size,
             head_size,
             data,
@@ -479,7 +1062,7 @@ impl super::BackedState for BackedState {
 
     #[inline]
     fn num_layer(&self) -> usize {
-        self.chunk_size * self.data.len()
+        self.num_layer
     }
 
     fn embed(&self, batch: usize, layer: usize) -> Vec<f32> {
@@ -489,60 +1072,572 @@ impl super::BackedState for BackedState {
         let chunk = &self.data[index];
         let num_emb = chunk.0[0];
 
-        let start = ((batch * self.chunk_size + offset) * (self.head_size + 2) + 1) * num_emb;
+        let start =
+            ((batch * self.chunk_size + offset) * (self.head_size + 2) + self.head_size + 1)
+                * num_emb;
         let end = start + num_emb;
 
         chunk.1[start..end].to_vec()
     }
-}
 
-impl<'a> Model<'a> {
-    #[inline]
-    fn request_runtime(&self, num_token: usize) -> Arc<Runtime> {
-        self.runtime_cache.request(num_token, || {
-            Runtime::new(&self.context, &self.info, num_token, self.token_chunk_size)
-        })
+    fn tensors(&self) -> Vec<(Shape, &[f32])> {
+        self.data
+            .iter()
+            .map(|(shape, data)| (*shape, data.as_slice()))
+            .collect()
     }
 
-    #[inline]
-    fn request_output(&self, num_batch: usize) -> Arc<Output> {
-        self.output_cache.request(num_batch, || {
-            Output::new(&self.context, &self.info, num_batch)
-        })
+    fn load_tensors(&mut self, tensors: Vec<Vec<f32>>) -> Result<(), TensorError> {
+        if tensors.len() != self.data.len() {
+            return Err(TensorError::Size(tensors.len(), self.data.len()));
+        }
+        for ((shape, _), tensor) in self.data.iter().zip(&tensors) {
+            if tensor.len() != shape.len() {
+                return Err(TensorError::Size(tensor.len(), shape.len()));
+            }
+        }
+        for ((_, data), tensor) in self.data.iter_mut().zip(tensors) {
+            *data = tensor;
+        }
+        Ok(())
+    }
+}
+
+impl<'a, F: Float> Model<'a, F> {
+    /// Reconstruction errors of quantized matrices, if [`ModelBuilder::with_quant_report`] was set.
+    #[inline]
+    pub fn quant_report(&self) -> Option<&QuantizationReport> {
+        self.quant_report.as_ref()
+    }
+
+    /// Maximum number of tokens processed in one run.
+    #[inline]
+    pub fn token_chunk_size(&self) -> usize {
+        self.token_chunk_size
+    }
+
+    /// Number of rows of the head stored in each of its buffers.
+    #[inline]
+    pub fn head_chunk_size(&self) -> usize {
+        self.head_chunk_size
+    }
+
+    /// Another handle to the same model, which reuses its weights on the device instead of uploading them again.
+    ///
+    /// The handle has runtime buffers of its own, so it can be run alongside this one (see [`Context`]),
+    /// and its runtime options can be set apart with [`Model::with_token_chunk_size`] and [`Model::with_turbo`].
+    /// Options baked into the weights, such as quantization, rescaling and head chunking, are those of this model.
+    pub fn share(&self) -> Self {
+        Self {
+            context: self.context.clone(),
+            info: self.info.clone(),
+            rescale: self.rescale,
+            turbo: self.turbo,
+            head_chunk_size: self.head_chunk_size,
+            token_chunk_size: self.token_chunk_size,
+            capture: self.capture,
+            tensor: self.tensor.clone(),
+            quant_report: self.quant_report.clone(),
+            runtime_cache: ResourceCache::new(1),
+            output_cache: ResourceCache::new(1),
+            softmax_cache: ResourceCache::new(1),
+            capture_cache: ResourceCache::new(4),
+            envelope: self.envelope,
+            reserve: self.envelope.map(|envelope| {
+                Reserve::new(&self.context, &self.info, envelope, self.token_chunk_size)
+            }),
+            overrides: self.overrides.clone(),
+            stats: None,
+        }
+    }
+
+    /// Rebuild the model on another context, e.g. on a discrete GPU that became available, by copying its weights there.
+    /// This model is left as it is; destroy it once the transferred one is in use.
+    pub fn transfer(&self, to: &Context) -> Result<Self> {
+        let tensor = {
+            let _scope = to.memory_scope(MemoryCategory::Weights);
+            self.tensor.transfer(to)?
+        };
+        Ok(Self {
+            context: to.clone(),
+            info: self.info.clone(),
+            rescale: self.rescale,
+            turbo: self.turbo,
+            head_chunk_size: self.head_chunk_size,
+            token_chunk_size: self.token_chunk_size,
+            capture: self.capture,
+            tensor: tensor.into(),
+            quant_report: self.quant_report.clone(),
+            runtime_cache: ResourceCache::new(1),
+            output_cache: ResourceCache::new(1),
+            softmax_cache: ResourceCache::new(1),
+            capture_cache: ResourceCache::new(4),
+            envelope: self.envelope,
+            reserve: self
+                .envelope
+                .map(|envelope| Reserve::new(to, &self.info, envelope, self.token_chunk_size)),
+            overrides: self.overrides.clone(),
+            stats: None,
+        })
+    }
+
+    /// Describe the ops a run of `num_token` tokens spread over `num_batch` batches dispatches, and the tensors they touch,
+    /// when the last token of each batch is output. See [`Graph::to_json`] for exporting it.
+    pub fn graph(&self, num_batch: usize, num_token: usize) -> Graph {
+        let info = &self.info;
+        let tensor = &self.tensor;
+        let head_size = info.num_emb / info.num_head;
+        let turbo = self.use_turbo(num_token);
+        let num_header = num_batch.min(num_token);
+
+        let mut graph = GraphBuilder::new(ModelVersion::V5, num_batch, num_token);
+        let shape = Shape::new(info.num_emb, num_token, 1, 1);
+        let hidden_shape = Shape::new(info.num_hidden, num_token, 1, 1);
+        let cursors = graph.tensor::<u32>("cursors", Shape::new(self.token_chunk_size, 1, 1, 1));
+        let mut activation = |name: &str| graph.tensor::<F>(name, shape);
+        let input = activation("input");
+        let att_x = activation("att_x");
+        let att_kx = activation("att_kx");
+        let att_vx = activation("att_vx");
+        let att_rx = activation("att_rx");
+        let att_gx = activation("att_gx");
+        let att_k = activation("att_k");
+        let att_v = activation("att_v");
+        let att_r = activation("att_r");
+        let att_g = activation("att_g");
+        let att_o = activation("att_o");
+        let ffn_x = activation("ffn_x");
+        let ffn_kx = activation("ffn_kx");
+        let ffn_rx = activation("ffn_rx");
+        let ffn_v = activation("ffn_v");
+        let ffn_r = activation("ffn_r");
+        let ffn_k = graph.tensor::<F>("ffn_k", hidden_shape);
+        let half_x = graph.tensor::<f16>("half_x", shape);
+        let half_k = graph.tensor::<f16>("half_k", hidden_shape);
+
+        // with host layers, the embedding is normalized on the host along with them
+        if tensor.host.is_empty() {
+            graph.stage("embed");
+            let layer_norm = &tensor.embed.layer_norm;
+            graph.norm(
+                "layer_norm",
+                "blocks.0.ln0",
+                &layer_norm.w,
+                &layer_norm.b,
+                &input,
+            );
+        }
+
+        for (index, layer) in (tensor.host.len()..).zip(&tensor.layers) {
+            graph.stage(format!("blocks.{index}"));
+            let state_att = graph.tensor::<f32>(
+                format!("state.{index}.att"),
+                Shape::new(info.num_emb, head_size + 1, num_batch, 1),
+            );
+            let state_ffn = graph.tensor::<f32>(
+                format!("state.{index}.ffn"),
+                Shape::new(info.num_emb, 1, num_batch, 1),
+            );
+
+            let att = format!("blocks.{index}.att");
+            graph.copy(&input, &att_x);
+            let layer_norm = &layer.att_layer_norm;
+            let name = format!("blocks.{index}.ln1");
+            graph.norm("layer_norm", &name, &layer_norm.w, &layer_norm.b, &att_x);
+            for (name, mix, output) in [
+                ("time_mix_k", &layer.att.time_mix_k, &att_kx),
+                ("time_mix_v", &layer.att.time_mix_v, &att_vx),
+                ("time_mix_r", &layer.att.time_mix_r, &att_rx),
+                ("time_mix_g", &layer.att.time_mix_g, &att_gx),
+            ] {
+                let mix = graph.weight(format!("{att}.{name}"), mix);
+                graph.op(
+                    "token_shift",
+                    &[&cursors, &mix, &att_x, &state_att],
+                    &[output],
+                );
+            }
+            for (name, matrix, input, output) in [
+                ("key", &layer.att.w_k, &att_kx, &att_k),
+                ("value", &layer.att.w_v, &att_vx, &att_v),
+                ("receptance", &layer.att.w_r, &att_rx, &att_r),
+                ("gate", &layer.att.w_g, &att_gx, &att_g),
+            ] {
+                let name = format!("{att}.{name}.weight");
+                graph.matmul(matrix, &name, &half_x, input, output, turbo);
+            }
+            let time_decay = graph.weight(format!("{att}.time_decay"), &layer.att.time_decay);
+            let time_first = graph.weight(format!("{att}.time_first"), &layer.att.time_first);
+            graph.op(
+                "time_mix_v5",
+                &[
+                    &cursors,
+                    &time_decay,
+                    &time_first,
+                    &att_k,
+                    &att_v,
+                    &att_r,
+                    &att_x,
+                    &state_att,
+                ],
+                &[&att_x, &state_att],
+            );
+            let group_norm = &layer.att.group_norm;
+            graph.norm(
+                "group_norm",
+                &format!("{att}.ln_x"),
+                &group_norm.w,
+                &group_norm.b,
+                &att_x,
+            );
+            graph.op("silu", &[&att_g, &att_x], &[&att_x]);
+            let name = format!("{att}.output.weight");
+            graph.matmul(&layer.att.w_o, &name, &half_x, &att_x, &att_o, false);
+            graph.op("add", &[&input, &att_o], &[&att_o]);
+
+            let ffn = format!("blocks.{index}.ffn");
+            graph.copy(&att_o, &ffn_x);
+            let layer_norm = &layer.ffn_layer_norm;
+            let name = format!("blocks.{index}.ln2");
+            graph.norm("layer_norm", &name, &layer_norm.w, &layer_norm.b, &ffn_x);
+            for (name, mix, output) in [
+                ("time_mix_k", &layer.ffn.time_mix_k, &ffn_kx),
+                ("time_mix_r", &layer.ffn.time_mix_r, &ffn_rx),
+            ] {
+                let mix = graph.weight(format!("{ffn}.{name}"), mix);
+                graph.op(
+                    "token_shift",
+                    &[&cursors, &mix, &ffn_x, &state_ffn],
+                    &[output],
+                );
+            }
+            let name = format!("{ffn}.key.weight");
+            graph.matmul(&layer.ffn.w_k, &name, &half_x, &ffn_kx, &ffn_k, turbo);
+            graph.op("squared_relu", &[&ffn_k], &[&ffn_k]);
+            let name = format!("{ffn}.value.weight");
+            graph.matmul(&layer.ffn.w_v, &name, &half_k, &ffn_k, &ffn_v, turbo);
+            let name = format!("{ffn}.receptance.weight");
+            graph.matmul(&layer.ffn.w_r, &name, &half_x, &ffn_rx, &ffn_r, turbo);
+            graph.op(
+                "channel_mix",
+                &[&cursors, &ffn_r, &ffn_v, &ffn_x, &state_ffn],
+                &[&ffn_x, &state_ffn],
+            );
+            graph.op("add", &[&att_o, &ffn_x], &[&ffn_x]);
+
+            if self
+                .rescale
+                .is_some_and(|every| (index + 1).is_multiple_of(every))
+            {
+                graph.op("half", &[&ffn_x], &[&ffn_x]);
+            }
+            if index != info.num_layer - 1 {
+                graph.copy(&ffn_x, &input);
+            }
+        }
+
+        graph.stage("head");
+        let head_x = match num_token == 1 || num_token == num_header {
+            true => ffn_x,
+            false => {
+                let head_x =
+                    graph.tensor::<F>("head_x", Shape::new(info.num_emb, num_header, 1, 1));
+                graph.op("blit", &[&ffn_x], &[&head_x]);
+                head_x
+            }
+        };
+        let head_shape = Shape::new(info.num_vocab, num_header, 1, 1);
+        let head_o = graph.tensor::<f32>("head_o", head_shape);
+        let map = graph.tensor::<f32>("map", head_shape);
+        let layer_norm = &tensor.head.layer_norm;
+        graph.norm(
+            "layer_norm",
+            "ln_out",
+            &layer_norm.w,
+            &layer_norm.b,
+            &head_x,
+        );
+        for (chunk, matrix) in tensor.head.w.iter().enumerate() {
+            let matrix = graph.weight(format!("head.weight.{chunk}"), matrix);
+            graph.op("matmul_vec_fp16", &[&matrix, &head_x], &[&head_o]);
+        }
+        graph.copy(&head_o, &map);
+
+        graph.build()
+    }
+
+    /// Set the maximum number of tokens processed in one run, which must be a power of 2.
+    pub fn with_token_chunk_size(self, value: usize) -> Result<Self> {
+        if !value.is_power_of_two() {
+            return Err(ModelError::InvalidChunkSize(value).into());
+        }
+        self.runtime_cache.clear();
+        self.capture_cache.clear();
+        Ok(Self {
+            token_chunk_size: value,
+            ..self
+        })
+    }
+
+    /// Whether to use fp16 GEMM for matmul computations.
+    pub fn with_turbo(self, value: bool) -> Self {
+        self.capture_cache.clear();
+        Self {
+            turbo: value,
+            ..self
+        }
+    }
+
+    /// Whether a run of `num_token` tokens over all batches multiplies them as matrices.
+    fn use_turbo(&self, num_token: usize) -> bool {
+        self.turbo && num_token >= TURBO_MIN_TOKEN.min(self.token_chunk_size)
+    }
+
+    /// Replace the op of `kind` in `layer` with one built by `op`, e.g. to try out a custom kernel.
+    /// The replacement is built whenever a run of a new shape is recorded, and should read and write
+    /// the same buffers as the op it replaces. Registering another for the same place replaces it.
+    pub fn with_op_override(
+        self,
+        layer: usize,
+        kind: OpKind,
+        op: impl Fn(&OpSite<'_, F>) -> Result<TensorOp, TensorError> + Send + Sync + 'static,
+    ) -> Self {
+        self.capture_cache.clear();
+        let mut overrides = self.overrides;
+        overrides.0.insert((layer, kind), Arc::new(op));
+        Self { overrides, ..self }
+    }
+
+    /// The replacement registered for the op at `site`, or `op` itself if there is none.
+    fn override_op(&self, op: TensorOp, site: OpSite<'_, F>) -> Result<TensorOp, TensorError> {
+        match self.overrides.0.get(&(site.layer, site.kind)) {
+            Some(build) => build(&site),
+            None => Ok(op),
+        }
+    }
+
+    /// Whether to collect the min, max, mean and absolute max of the output of each layer on every run, e.g. to find
+    /// where activations overflow `f16` or where quantization hurts. Read them with [`Model::activation_stats`].
+    /// Turning it on starts afresh; handles from [`Model::share`] collect their own.
+    pub fn with_activation_stats(self, value: bool) -> Self {
+        self.capture_cache.clear();
+        let stats = value.then(|| {
+            let shape = Shape::new(8, self.info.num_layer, 1, 1);
+            let data = TensorOp::STATS_INIT.repeat(self.info.num_layer);
+            self.context
+                .tensor_from_data(shape, data)
+                .expect("stats shape matches its data")
+        });
+        Self { stats, ..self }
+    }
+
+    /// Statistics of the output of each layer over all runs since [`Model::with_activation_stats`]
+    /// or [`Model::reset_activation_stats`], if collected. This waits for the runs to finish.
+    pub fn activation_stats(&self) -> Result<Option<Vec<ActivationStats>>> {
+        let Some(stats) = &self.stats else {
+            return Ok(None);
+        };
+        let raw = Vec::from(stats.back());
+        Ok(Some(ActivationStats::from_raw(&raw)))
+    }
+
+    /// Clear the collected statistics, if any, so that they cover only the runs from now on.
+    pub fn reset_activation_stats(&self) -> Result<()> {
+        if let Some(stats) = &self.stats {
+            let data = TensorOp::STATS_INIT.repeat(self.info.num_layer);
+            stats.load(&self.context.tensor_from_data(stats.shape(), data)?)?;
+        }
+        Ok(())
+    }
+
+    /// Free the device memory of the weights and of the cached runtime buffers now,
+    /// instead of leaving it to the driver, so that models can be loaded and unloaded repeatedly.
+    ///
+    /// Runtime buffers still in use (e.g. by a run on another thread) are freed once released.
+    /// Weights still used by another handle from [`Model::share`] are left to it.
+    pub fn destroy(self) {
+        self.runtime_cache.clear();
+        self.output_cache.clear();
+        self.softmax_cache.clear();
+        self.capture_cache.clear();
+        if let Ok(tensor) = Arc::try_unwrap(self.tensor) {
+            tensor.destroy();
+        }
+        self.context.device.poll(wgpu::MaintainBase::Wait);
+    }
+
+    /// Hits and misses of the recorded runs, if built [`ModelBuilder::with_capture`].
+    pub fn capture_stats(&self) -> CacheStats {
+        self.capture_cache.stats()
+    }
+
+    #[inline]
+    fn request_runtime(&self, num_token: usize) -> Arc<Runtime<F>> {
+        self.runtime_cache.request(num_token, || {
+            // runs within the envelope take the front of the reserved buffers
+            let reserve = self.reserve.as_ref();
+            let max_token = self.token_chunk_size;
+            if let Some(Ok(runtime)) = reserve.map(|x| x.runtime.shrink(num_token, max_token)) {
+                return runtime;
+            }
+            let _scope = self.context.memory_scope(MemoryCategory::Runtime);
+            Runtime::new(&self.context, &self.info, num_token, self.token_chunk_size)
+        })
+    }
+
+    #[inline]
+    fn request_output(&self, num_batch: usize) -> Arc<Output<F>> {
+        self.output_cache.request(num_batch, || {
+            if let Some(Ok(output)) = self.reserve.as_ref().map(|x| x.output.shrink(num_batch)) {
+                return output;
+            }
+            let _scope = self.context.memory_scope(MemoryCategory::Runtime);
+            Output::new(&self.context, &self.info, num_batch)
+        })
     }
 
     #[inline]
     fn request_softmax(&self, num_batch: usize) -> Arc<Softmax> {
         self.softmax_cache.request(num_batch, || {
+            if let Some(Ok(softmax)) = self.reserve.as_ref().map(|x| x.softmax.shrink(num_batch)) {
+                return softmax;
+            }
+            let _scope = self.context.memory_scope(MemoryCategory::Runtime);
             Softmax::new(&self.context, &self.info, num_batch)
         })
     }
 
+    fn softmax_internal(
+        &self,
+        input: Vec<Option<Vec<f32>>>,
+        temperature: Option<&[f32]>,
+    ) -> Result<Vec<Option<Vec<f32>>>> {
+        let max_batch = input.len();
+        if let Some(temperature) = temperature {
+            if temperature.len() != max_batch {
+                return Err(ModelError::BatchSize(temperature.len(), max_batch).into());
+            }
+        }
+
+        let mut redirect = vec![None; max_batch];
+        let input: Vec<_> = input
+            .into_iter()
+            .enumerate()
+            .filter_map(|(batch, data)| data.map(|data| (batch, data)))
+            .map(|(batch, data)| {
+                TensorCpu::from_data(&self.context, self.head_shape(1), data)
+                    .map(|tensor| (batch, tensor))
+            })
+            .try_collect()?;
+        let input = TensorCpu::stack(
+            input
+                .into_iter()
+                .enumerate()
+                .map(|(index, (batch, tensor))| {
+                    redirect[batch] = Some(index);
+                    tensor
+                })
+                .collect_vec(),
+        )?;
+
+        let num_batch = input.shape()[2];
+        let softmax = self.request_softmax(num_batch);
+        let _submission = self.context.submission();
+        softmax.buffer.load(&input)?;
+
+        let op = match temperature {
+            Some(temperature) => {
+                let temperature = temperature
+                    .iter()
+                    .zip_eq(redirect.iter())
+                    .filter_map(|(temperature, redirect)| redirect.map(|_| *temperature))
+                    .collect_vec();
+                let temperature = TensorCpu::from_data(
+                    &self.context,
+                    Shape::new(num_batch, 1, 1, 1),
+                    temperature,
+                )?;
+                softmax.temperature.load(&temperature)?;
+                TensorOp::softmax_temperature(&softmax.buffer, &softmax.temperature)?
+            }
+            None => TensorOp::softmax(&softmax.buffer)?,
+        };
+
+        let mut encoder = self
+            .context
+            .device
+            .create_command_encoder(&CommandEncoderDescriptor::default());
+
+        let mut pass = encoder.begin_compute_pass(&ComputePassDescriptor::default());
+        pass.execute_tensor_op(&op);
+        drop(pass);
+
+        encoder.copy_tensor(&softmax.buffer, &softmax.map)?;
+        self.context.queue.submit(Some(encoder.finish()));
+
+        let output = TensorCpu::from(softmax.map.clone());
+        let mut output = output
+            .iter_batches()
+            .map(|tensor| Some(tensor.to_vec()))
+            .collect_vec();
+
+        let mut probs = vec![None; max_batch];
+        for (probs, redirect) in probs.iter_mut().zip_eq(redirect.into_iter()) {
+            if let Some(redirect) = redirect {
+                std::mem::swap(probs, &mut output[redirect]);
+            }
+        }
+
+        Ok(probs)
+    }
+
+    /// Fail on the first token that isn't within the vocabulary, so that the embeddings can be looked up unchecked.
+    fn check_tokens<'t>(
+        &self,
+        tokens: impl IntoIterator<Item = &'t u16>,
+    ) -> Result<(), ModelError> {
+        let max = self.info.num_vocab;
+        match tokens.into_iter().find(|&&token| token as usize >= max) {
+            Some(&token) => Err(ModelError::TokenOutOfRange { token, max }),
+            None => Ok(()),
+        }
+    }
+
     #[inline]
     fn head_shape(&self, num_batch: usize) -> Shape {
         Shape::new(self.info.num_vocab, 1, num_batch, 1)
     }
 
+    #[allow(clippy::too_many_arguments)]
+    #[allow(clippy::type_complexity)]
     fn run_internal(
         &self,
         tokens: Vec<Vec<u16>>,
         state: &ModelState,
+        page: usize,
         last: Option<usize>,
-    ) -> Result<(Arc<Output>, Vec<Option<usize>>), TensorError> {
+        layers: Range<usize>,
+        full: bool,
+        target: RunOutput<'_>,
+    ) -> Result<(Arc<Output<F>>, Vec<Option<Range<usize>>>)> {
         let context = &self.context;
         let tensor = &self.tensor;
+        self.check_tokens(tokens.iter().flatten())?;
 
         let input: Vec<_> = tokens
             .into_iter()
             .map(|tokens| -> Result<_, TensorError> {
-                let stack = TensorCpu::stack(
+                let stack = TensorCpu::stack_unchecked(
                     tokens
                         .into_iter()
-                        .map(|token| tensor.embed.w.slice(.., token as usize, .., ..))
-                        .try_collect()?,
+                        // SAFETY: all tokens are checked to be within the vocabulary
+                        .map(|token| unsafe {
+                            tensor.embed.w.slice_unchecked(.., token as usize, .., ..)
+                        })
+                        .collect(),
                 )
                 .unwrap_or_else(|_| context.zeros(Shape::new(self.info.num_emb, 1, 0, 1)));
-                stack.map(|x| x.to_f32()).reshape(
+                stack.map(|x| F::from_f32(x.to_f32())).reshape(
                     TensorDimension::Full,
                     TensorDimension::Auto,
                     TensorDimension::Dimension(1),
@@ -550,38 +1645,171 @@ impl<'a> Model<'a> {
                 )
             })
             .try_collect()?;
+        Ok(self.run_input(input, state, page, last, layers, full, target)?)
+    }
+
+    /// Like `run_internal`, but with the embeddings of the tokens of each batch already looked up, as `[C, T, 1]` tensors.
+    #[allow(clippy::too_many_arguments)]
+    #[allow(clippy::type_complexity)]
+    fn run_input(
+        &self,
+        input: Vec<TensorCpu<F>>,
+        state: &ModelState,
+        page: usize,
+        last: Option<usize>,
+        layers: Range<usize>,
+        full: bool,
+        target: RunOutput<'_>,
+    ) -> Result<(Arc<Output<F>>, Vec<Option<Range<usize>>>), TensorError> {
+        let context = &self.context;
+        // hold a turn until the work is submitted, so that other models on the context get theirs in order
+        let _submission = context.submission();
+
+        // layers on the host run first, and the device picks up after them with the embedding already normalized
+        let offset = self.tensor.host.len();
+        let (input, layers, normalize) = match layers.start < offset {
+            true => {
+                let input = self.run_host(input, state, page, layers.start..offset)?;
+                (input, offset..layers.end, false)
+            }
+            false => (input, layers, true),
+        };
 
         let input = TensorStack::try_from(input)?;
         let num_batch = input.num_batch();
         let num_active_batch = input.num_active_batch();
         let num_token = input.num_token();
-        let head_size = self.info.num_emb / self.info.num_head;
         assert_ne!(num_token, 0);
         assert_ne!(num_active_batch, 0);
 
         // collect batch output copy commands for later
         let mut redirect = vec![None; num_batch];
-        let headers = input
-            .cursors
-            .iter()
-            .filter(|cursor| cursor.len > 0)
-            .filter(|cursor| !last.is_some_and(|index| cursor.batch == index))
-            .enumerate()
-            .map(|(index, cursor)| {
-                redirect[cursor.batch] = Some(index);
-                cursor.token + cursor.len - 1
+        let mut headers = vec![];
+        for cursor in input.cursors.iter().filter(|cursor| cursor.len > 0) {
+            let tokens = match full {
+                true => cursor.token..cursor.token + cursor.len,
+                false if last == Some(cursor.batch) => continue,
+                false => cursor.token + cursor.len - 1..cursor.token + cursor.len,
+            };
+            redirect[cursor.batch] = Some(headers.len()..headers.len() + tokens.len());
+            headers.extend(tokens);
+        }
+        let num_header = headers.len();
+
+        // runs writing into a tensor given by the caller are never captured
+        let logits = matches!(target, RunOutput::Logits);
+        let capture = match target {
+            RunOutput::Logits if self.capture => {
+                let key = CaptureKey {
+                    state: state.page_id(page),
+                    num_token,
+                    headers: headers.clone(),
+                    layers: layers.clone(),
+                    normalize,
+                };
+                self.capture_cache.try_request(key, || {
+                    let target = RunOutput::Logits;
+                    self.record(state, page, num_token, &headers, layers, normalize, target)
+                })?
+            }
+            _ => {
+                Arc::new(self.record(state, page, num_token, &headers, layers, normalize, target)?)
+            }
+        };
+        let buffer = &capture.runtime;
+        let output = &capture.output;
+
+        let mut cursors = input.cursors.into_cursors();
+        cursors.resize(self.token_chunk_size, 0);
+        let cursors = context.tensor_from_data(buffer.cursors.shape(), cursors)?;
+
+        buffer.input.load(&input.tensor)?;
+        buffer.cursors.load(&cursors)?;
+
+        let mut encoder = context
+            .device
+            .create_command_encoder(&CommandEncoderDescriptor::default());
+        capture.sequence.replay(&mut encoder);
+        if num_header > 0 && logits {
+            encoder.copy_tensor(&output.head_o, &output.map)?;
+        }
+
+        context.queue.submit(Some(encoder.finish()));
+        Ok((output.clone(), redirect))
+    }
+
+    /// Run the tokens of each batch of `input` through the host `layers`, reading the state chunks of `page` they use back
+    /// and writing them once they are done, and return what the device picks up from.
+    fn run_host<'b>(
+        &self,
+        input: Vec<TensorCpu<'b, F>>,
+        state: &ModelState,
+        page: usize,
+        layers: Range<usize>,
+    ) -> Result<Vec<TensorCpu<'b, F>>, TensorError> {
+        let context = &self.context;
+        let host = &self.tensor.host;
+        let num_emb = self.info.num_emb;
+        let layer_size = (state.head_size + 2) * num_emb;
+
+        let num_chunk = layers.end.div_ceil(state.chunk_size);
+        let chunks = &state.state[page][..num_chunk];
+        let mut data: Vec<_> = chunks.iter().map(|chunk| chunk.back(None)).try_collect()?;
+
+        // the rows of each layer of each batch, gathered over the chunks
+        let mut batches = input.iter().map(|_| vec![]).collect_vec();
+        for (_, data) in data.iter_mut() {
+            let data = data.chunks_exact_mut(state.chunk_size * layer_size);
+            for (batch, data) in batches.iter_mut().zip_eq(data) {
+                batch.extend(data.chunks_exact_mut(layer_size));
+            }
+        }
+
+        let output = input
+            .into_iter()
+            .zip_eq(batches.iter_mut())
+            .map(|(input, state)| {
+                let shape = input.shape();
+                let mut x = input.map(|x| x.to_f32()).to_vec();
+                for x in x.chunks_exact_mut(num_emb) {
+                    host.run(x, state, layers.clone());
+                }
+                let x = x.into_iter().map(F::from_f32).collect_vec();
+                context.tensor_from_data(shape, x)
             })
-            .collect_vec();
+            .try_collect()?;
+        drop(batches);
+
+        for (chunk, (shape, data)) in chunks.iter().zip_eq(data) {
+            chunk.load(shape, &data, None)?;
+        }
+        Ok(output)
+    }
+
+    /// Record the commands of a run of `num_token` tokens on `page` of `state`,
+    /// outputting the tokens at `headers` as `target` asks.
+    /// The embedding layer norm is skipped unless `normalize` is set, e.g. when host layers have done it.
+    #[allow(clippy::too_many_arguments)]
+    fn record(
+        &self,
+        state: &ModelState,
+        page: usize,
+        num_token: usize,
+        headers: &[usize],
+        layers: Range<usize>,
+        normalize: bool,
+        target: RunOutput<'_>,
+    ) -> Result<Capture<F>, TensorError> {
+        let tensor = &self.tensor;
         let num_header = headers.len();
+        let head_size = self.info.num_emb / self.info.num_head;
 
         let buffer = self.request_runtime(num_token);
         let output = self.request_output(num_header.max(1));
         // let stack = self.request_stack(num_active_batch);
 
         // gather and group copy operations
-        let (head_ops, head_x) = if num_token == 1 || num_token == num_header {
-            (TensorOp::List(vec![]), &buffer.ffn_x)
-        } else {
+        let gather = || -> Result<TensorOp, TensorError> {
             let mut start = 0;
             let mut end = 1;
             let mut ops = vec![];
@@ -599,7 +1827,12 @@ impl<'a> Model<'a> {
                 }
                 end += 1;
             }
-            (TensorOp::List(ops), &output.head_x)
+            Ok(TensorOp::List(ops))
+        };
+        let (head_ops, head_x) = if num_token == 1 || num_token == num_header {
+            (TensorOp::List(vec![]), &buffer.ffn_x)
+        } else {
+            (gather()?, &output.head_x)
         };
 
         // let head_ops: Vec<_> = input
@@ -608,7 +1841,7 @@ impl<'a> Model<'a> {
         //     .filter(|cursor| cursor.len > 0)
         //     .filter(|cursor| !last.is_some_and(|index| cursor.batch == index))
         //     .enumerate()
-        //     .map(|(index, cursor)| -> Result<TensorOp<'_>, TensorError> {
+        //     .map(|(index, cursor)| -> Result<TensorOp, TensorError> {
         //         redirect[cursor.batch] = Some(index);
         //         let token = cursor.token + cursor.len - 1;
         //         let input = buffer.ffn_x.as_view((.., token, ..))?;
@@ -617,27 +1850,24 @@ impl<'a> Model<'a> {
         //     })
         //     .try_collect()?;
 
-        let mut cursors = input.cursors.into_cursors();
-        cursors.resize(self.token_chunk_size, 0);
-        let cursors = context.tensor_from_data(buffer.cursors.shape(), cursors)?;
-
-        buffer.input.load(&input.tensor)?;
-        buffer.cursors.load(&cursors)?;
-
-        let mut encoder = context
-            .device
-            .create_command_encoder(&CommandEncoderDescriptor::default());
+        let mut sequence = TensorSequence::new();
 
-        let op = TensorOp::layer_norm(
-            &tensor.embed.layer_norm.w,
-            &tensor.embed.layer_norm.b,
-            &buffer.input,
-        )?;
-        let mut pass = encoder.begin_compute_pass(&ComputePassDescriptor::default());
-        pass.execute_tensor_op(&op);
-        drop(pass);
+        if normalize {
+            let op = TensorOp::layer_norm(
+                &tensor.embed.layer_norm.w,
+                &tensor.embed.layer_norm.b,
+                &buffer.input,
+            )?;
+            sequence.push(op);
+        }
 
-        for (index, layer) in tensor.layers.iter().enumerate() {
+        // the device holds the layers after those on the host
+        let offset = tensor.host.len();
+        for (index, layer) in (offset..)
+            .zip(&tensor.layers)
+            .take(layers.end - offset)
+            .skip(layers.start - offset)
+        {
             use TensorDimension::{Auto, Dimension};
             let time_first = layer.att.time_first.reshape(
                 Dimension(head_size),
@@ -676,9 +1906,18 @@ impl<'a> Model<'a> {
                 Dimension(1),
             )?;
 
-            encoder.copy_tensor(&buffer.input, &buffer.att_x)?;
+            let site = |kind| OpSite {
+                layer: index,
+                kind,
+                num_token,
+                runtime: &buffer,
+                state,
+                page,
+            };
+
+            sequence.copy_tensor(&buffer.input, &buffer.att_x)?;
 
-            let matmul_ops = if self.turbo && num_token == self.token_chunk_size {
+            let matmul_ops = if self.use_turbo(num_token) {
                 TensorOp::List(vec![
                     layer.att.w_k.matmul_mat_op(
                         buffer.half_x.view(.., .., .., ..)?,
@@ -725,6 +1964,7 @@ impl<'a> Model<'a> {
                     )?,
                 ])
             };
+            let matmul_ops = self.override_op(matmul_ops, site(OpKind::AttMatmul))?;
             let ops = TensorOp::List(vec![
                 TensorOp::layer_norm(
                     &layer.att_layer_norm.w,
@@ -735,40 +1975,43 @@ impl<'a> Model<'a> {
                     &buffer.cursors,
                     &layer.att.time_mix_k,
                     &buffer.att_x,
-                    state.att(index)?,
+                    state.att(page, index)?,
                     &buffer.att_kx,
                 )?,
                 TensorOp::token_shift(
                     &buffer.cursors,
                     &layer.att.time_mix_v,
                     &buffer.att_x,
-                    state.att(index)?,
+                    state.att(page, index)?,
                     &buffer.att_vx,
                 )?,
                 TensorOp::token_shift(
                     &buffer.cursors,
                     &layer.att.time_mix_r,
                     &buffer.att_x,
-                    state.att(index)?,
+                    state.att(page, index)?,
                     &buffer.att_rx,
                 )?,
                 TensorOp::token_shift(
                     &buffer.cursors,
                     &layer.att.time_mix_g,
                     &buffer.att_x,
-                    state.att(index)?,
+                    state.att(page, index)?,
                     &buffer.att_gx,
                 )?,
                 matmul_ops,
-                TensorOp::time_mix_v5(
-                    &buffer.cursors,
-                    &time_decay,
-                    &time_first,
-                    &att_k,
-                    &att_v,
-                    &att_r,
-                    &att_x,
-                    state.att(index)?,
+                self.override_op(
+                    TensorOp::time_mix_v5(
+                        &buffer.cursors,
+                        &time_decay,
+                        &time_first,
+                        &att_k,
+                        &att_v,
+                        &att_r,
+                        &att_x,
+                        state.att(page, index)?,
+                    )?,
+                    site(OpKind::TimeMix),
                 )?,
                 TensorOp::group_norm(&layer.att.group_norm.w, &layer.att.group_norm.b, &att_x)?,
                 TensorOp::silu(&buffer.att_g, &buffer.att_x)?,
@@ -780,13 +2023,11 @@ impl<'a> Model<'a> {
                 TensorOp::add(&buffer.input, &buffer.att_o)?,
             ]);
 
-            let mut pass = encoder.begin_compute_pass(&ComputePassDescriptor::default());
-            pass.execute_tensor_op(&ops);
-            drop(pass);
+            sequence.push(ops);
 
-            encoder.copy_tensor(&buffer.att_o, &buffer.ffn_x)?;
+            sequence.copy_tensor(&buffer.att_o, &buffer.ffn_x)?;
 
-            let matmul_ops = if self.turbo && num_token == self.token_chunk_size {
+            let matmul_ops = if self.use_turbo(num_token) {
                 TensorOp::List(vec![
                     layer.ffn.w_k.matmul_mat_op(
                         buffer.half_x.view(.., .., .., ..)?,
@@ -825,6 +2066,7 @@ impl<'a> Model<'a> {
                     )?,
                 ])
             };
+            let matmul_ops = self.override_op(matmul_ops, site(OpKind::FfnMatmul))?;
             let ops = TensorOp::List(vec![
                 TensorOp::layer_norm(
                     &layer.ffn_layer_norm.w,
@@ -835,99 +2077,237 @@ impl<'a> Model<'a> {
                     &buffer.cursors,
                     &layer.ffn.time_mix_k,
                     &buffer.ffn_x,
-                    state.ffn(index)?,
+                    state.ffn(page, index)?,
                     &buffer.ffn_kx,
                 )?,
                 TensorOp::token_shift(
                     &buffer.cursors,
                     &layer.ffn.time_mix_r,
                     &buffer.ffn_x,
-                    state.ffn(index)?,
+                    state.ffn(page, index)?,
                     &buffer.ffn_rx,
                 )?,
                 matmul_ops,
-                TensorOp::channel_mix(
-                    &buffer.cursors,
-                    &buffer.ffn_r,
-                    &buffer.ffn_v,
-                    &buffer.ffn_x,
-                    state.ffn(index)?,
+                self.override_op(
+                    TensorOp::channel_mix(
+                        &buffer.cursors,
+                        &buffer.ffn_r,
+                        &buffer.ffn_v,
+                        &buffer.ffn_x,
+                        state.ffn(page, index)?,
+                    )?,
+                    site(OpKind::ChannelMix),
                 )?,
                 TensorOp::add(&buffer.att_o, &buffer.ffn_x)?,
             ]);
 
-            let mut pass = encoder.begin_compute_pass(&ComputePassDescriptor::default());
-            pass.execute_tensor_op(&ops);
-            drop(pass);
+            sequence.push(ops);
+
+            if let Some(stats) = &self.stats {
+                let op = TensorOp::stats(&buffer.ffn_x, stats.view(.., index, .., ..)?)?;
+                sequence.push(op);
+            }
 
-            if self.rescale && (index + 1) % RESCALE_LAYER == 0 {
+            if self
+                .rescale
+                .is_some_and(|every| (index + 1).is_multiple_of(every))
+            {
                 let op = TensorOp::half(&buffer.ffn_x)?;
-                let mut pass = encoder.begin_compute_pass(&ComputePassDescriptor::default());
-                pass.execute_tensor_op(&op);
-                drop(pass);
+                sequence.push(op);
             }
 
-            if index != self.info.num_layer - 1 {
-                encoder.copy_tensor(&buffer.ffn_x, &buffer.input)?;
+            if let RunOutput::Lens(lens) = target {
+                // project a copy, so that the hidden states go on to the next layer untouched
+                let mut ops = vec![
+                    gather()?,
+                    TensorOp::layer_norm(
+                        &tensor.head.layer_norm.w,
+                        &tensor.head.layer_norm.b,
+                        &output.head_x,
+                    )?,
+                ];
+                for (chunk, matrix) in tensor.head.w.iter().enumerate() {
+                    let start = chunk * self.head_chunk_size;
+                    let end = start + matrix.shape()[1];
+                    let input = output.head_x.view(.., .., .., ..)?;
+                    let output = lens.view(start..end, .., index, ..)?;
+                    ops.push(TensorOp::matmul_vec_fp16(matrix, input, output)?);
+                }
+                sequence.push(TensorOp::List(ops));
+            }
+
+            if index != layers.end - 1 {
+                sequence.copy_tensor(&buffer.ffn_x, &buffer.input)?;
             }
         }
 
-        if num_header > 0 {
+        // with a lens, that of the last layer already holds the logits
+        let head = !matches!(target, RunOutput::Lens(_));
+        if num_header > 0 && head {
             let mut ops = vec![TensorOp::layer_norm(
                 &tensor.head.layer_norm.w,
                 &tensor.head.layer_norm.b,
                 head_x,
             )?];
 
-            for (chunk, matrix) in tensor.head.w.iter().enumerate() {
-                let start = chunk * self.head_chunk_size;
-                let end = start + self.head_chunk_size;
-                let input = head_x.view(.., .., .., ..)?;
-                let output = output.head_o.view(start..end, .., .., ..)?;
-                ops.push(TensorOp::matmul_vec_fp16(matrix, input, output)?);
+            match target {
+                RunOutput::Hidden(hidden) => {
+                    // only the normalized hidden states are wanted, so the head itself is skipped
+                    let input = head_x.view(.., .., .., ..)?;
+                    ops.push(TensorOp::blit_f32(input, hidden)?);
+                }
+                RunOutput::Logits | RunOutput::Lens(_) => {
+                    for (chunk, matrix) in tensor.head.w.iter().enumerate() {
+                        let start = chunk * self.head_chunk_size;
+                        let end = start + matrix.shape()[1];
+                        let input = head_x.view(.., .., .., ..)?;
+                        let output = output.head_o.view(start..end, .., .., ..)?;
+                        ops.push(TensorOp::matmul_vec_fp16(matrix, input, output)?);
+                    }
+                }
             }
 
             let ops = TensorOp::List(ops);
 
-            let mut pass = encoder.begin_compute_pass(&ComputePassDescriptor::default());
-            pass.execute_tensor_op(&head_ops);
-            pass.execute_tensor_op(&ops);
-            drop(pass);
+            sequence.push(head_ops);
+            sequence.push(ops);
+        }
 
-            encoder.copy_tensor(&output.head_o, &output.map)?;
+        Ok(Capture {
+            _page: state.state[page].clone(),
+            runtime: buffer,
+            output,
+            sequence,
+        })
+    }
+
+    /// Run through `layers` only, returning the logits of the last token of each finished batch,
+    /// or of every token run if `full` is set.
+    fn run_with(
+        &self,
+        tokens: &mut [Vec<u16>],
+        state: &ModelState,
+        layers: Range<usize>,
+        full: bool,
+    ) -> Result<Vec<Option<Vec<Vec<f32>>>>> {
+        let num_token: usize = tokens.iter().map(Vec::len).sum();
+        let max_batch = state.max_batch;
+
+        if tokens.len() != max_batch {
+            return Err(ModelError::BatchSize(tokens.len(), max_batch).into());
+        }
+        // at least one layer has to run on the device, since the head reads its output
+        let offset = self.tensor.host.len();
+        if layers.is_empty() || layers.end > self.info.num_layer || layers.end <= offset {
+            let (start, end, max) = (layers.start, layers.end, self.info.num_layer);
+            return Err(ModelError::LayerRange { start, end, max }.into());
+        }
+        if num_token == 0 {
+            return Ok(vec![None; max_batch]);
         }
 
-        context.queue.submit(Some(encoder.finish()));
-        Ok((output, redirect))
+        // only batches in the page of the first pending batch are run together
+        let page = tokens
+            .iter()
+            .position(|batch| !batch.is_empty())
+            .expect("there are tokens")
+            / state.page_size();
+        let start = page * state.page_size();
+        let end = (start + state.page_size()).min(max_batch);
+
+        // we only infer at most `token_chunk_size` tokens at a time
+        let num_token: usize = tokens[start..end].iter().map(Vec::len).sum();
+        let mut num_token = num_token.min(self.token_chunk_size);
+        let mut inputs = vec![vec![]; end - start];
+        let mut last = None;
+
+        // take `num_token` tokens out of all the inputs and put into `input`
+        for (index, (batch, input)) in tokens[start..end]
+            .iter_mut()
+            .zip(inputs.iter_mut())
+            .enumerate()
+        {
+            let mid = batch.len().min(num_token);
+            num_token -= mid;
+
+            let (head, tail) = batch.split_at(mid);
+            last = (!tail.is_empty()).then_some(index);
+            *input = head.to_vec();
+            *batch = tail.to_vec();
+
+            if num_token == 0 {
+                break;
+            }
+        }
+
+        let (output, redirect) =
+            self.run_internal(inputs, state, page, last, layers, full, RunOutput::Logits)?;
+        let output = TensorCpu::from(output.map.clone());
+
+        let mut outputs = vec![None; max_batch];
+        for (output_batch, index) in outputs[start..end].iter_mut().zip_eq(redirect) {
+            *output_batch = index.map(|index| {
+                index
+                    .map(|index| {
+                        output
+                            .slice(.., index, .., ..)
+                            .expect("this never happens")
+                            .to_vec()
+                    })
+                    .collect()
+            });
+        }
+        Ok(outputs)
     }
 }
 
-impl<'a> FromBuilder for Model<'a> {
+impl<'a, F: Float> FromBuilder for Model<'a, F> {
     type Builder<'b> = ModelBuilder<'b>;
     type Error = anyhow::Error;
 
     fn from_builder(builder: Self::Builder<'_>) -> Result<Self, Self::Error> {
         let ModelBuilder {
             context,
-            data,
+            mut source,
+            checksum,
             lora,
             quant,
+            quant_report,
             turbo,
+            rescale,
             head_chunk_size,
             token_chunk_size,
+            capture,
+            host_layers,
+            envelope,
+            mut progress,
+            cancel,
         } = builder;
+        let _scope = context.memory_scope(MemoryCategory::Weights);
 
+        let loader = source.loader(&context, lora, checksum)?;
+        let info = loader.model_info()?;
+
+        let limits = context.device.limits();
+        let head_chunk_size = head_chunk_size.head(&limits, &info);
+        let token_chunk_size = token_chunk_size.token(&limits, &info);
         if !head_chunk_size.is_power_of_two() {
             return Err(ModelError::InvalidChunkSize(head_chunk_size).into());
         }
         if !token_chunk_size.is_power_of_two() {
             return Err(ModelError::InvalidChunkSize(token_chunk_size).into());
         }
+        if host_layers >= info.num_layer {
+            let (end, max) = (host_layers, info.num_layer);
+            return Err(ModelError::LayerRange { start: 0, end, max }.into());
+        }
 
-        let loader = Loader::new(&context, data, lora)?;
-        let info = Loader::info(data)?;
-
-        let rescale = turbo || quant.iter().any(|(_, quant)| matches!(quant, Quant::NF4));
+        let rescale = match rescale {
+            Some(0) => None,
+            Some(every) => Some(every),
+            None => (turbo || quant.iter().any(|(_, quant)| matches!(quant, Quant::NF4)))
+                .then_some(RESCALE_LAYER),
+        };
 
         let embed = Embed {
             layer_norm: LayerNorm {
@@ -948,12 +2328,22 @@ impl<'a> FromBuilder for Model<'a> {
         context.queue.submit(None);
         context.device.poll(wgpu::MaintainBase::Wait);
 
-        let layers = (0..info.num_layer)
+        let host = HostLayers::load(&loader, &info, host_layers, rescale)?;
+
+        let mut report = quant_report.then(QuantizationReport::default);
+        let layers = (host_layers..info.num_layer)
             .map(|layer| {
+                if cancel
+                    .as_ref()
+                    .is_some_and(|cancel| cancel.load(Ordering::Relaxed))
+                {
+                    return Err(ModelError::Cancelled.into());
+                }
+
                 let quant = quant.get(&layer).copied().unwrap_or_default();
                 let discount = match rescale {
-                    true => 2.0_f32.powi(-((layer / RESCALE_LAYER) as i32)),
-                    false => 1.0,
+                    Some(every) => 2.0_f32.powi(-((layer / every) as i32)),
+                    None => 1.0,
                 };
 
                 let att_layer_norm = LayerNorm {
@@ -995,49 +2385,24 @@ impl<'a> FromBuilder for Model<'a> {
                         )?,
                 };
 
-                let att = match quant {
-                    Quant::None => Att {
-                        time_decay,
-                        time_first,
-                        time_mix_k,
-                        time_mix_v,
-                        time_mix_r,
-                        time_mix_g,
-                        w_k: Matrix::Fp16(w_k),
-                        w_v: Matrix::Fp16(w_v),
-                        w_r: Matrix::Fp16(w_r),
-                        w_g: Matrix::Fp16(w_g),
-                        w_o: Matrix::Fp16(w_o),
-                        group_norm,
-                    },
-                    Quant::Int8 => Att {
-                        time_decay,
-                        time_first,
-                        time_mix_k,
-                        time_mix_v,
-                        time_mix_r,
-                        time_mix_g,
-                        w_k: Matrix::quant_u8(w_k)?,
-                        w_v: Matrix::quant_u8(w_v)?,
-                        w_r: Matrix::quant_u8(w_r)?,
-                        w_g: Matrix::quant_u8(w_g)?,
-                        w_o: Matrix::quant_u8(w_o)?,
-                        group_norm,
-                    },
-                    Quant::NF4 => Att {
-                        time_decay,
-                        time_first,
-                        time_mix_k,
-                        time_mix_v,
-                        time_mix_r,
-                        time_mix_g,
-                        w_k: Matrix::quant_nf4(w_k)?,
-                        w_v: Matrix::quant_nf4(w_v)?,
-                        w_r: Matrix::quant_nf4(w_r)?,
-                        w_g: Matrix::quant_nf4(w_g)?,
-                        w_o: Matrix::quant_nf4(w_o)?,
-                        group_norm,
-                    },
+                let mut quant_matrix = |name: String, matrix| match report.as_mut() {
+                    Some(report) => report.quant(name, layer, matrix, quant),
+                    None => Matrix::quant(matrix, quant),
+                };
+
+                let att = Att {
+                    time_decay,
+                    time_first,
+                    time_mix_k,
+                    time_mix_v,
+                    time_mix_r,
+                    time_mix_g,
+                    w_k: quant_matrix(format!("{att}.key.weight"), w_k)?,
+                    w_v: quant_matrix(format!("{att}.value.weight"), w_v)?,
+                    w_r: quant_matrix(format!("{att}.receptance.weight"), w_r)?,
+                    w_g: quant_matrix(format!("{att}.gate.weight"), w_g)?,
+                    w_o: quant_matrix(format!("{att}.output.weight"), w_o)?,
+                    group_norm,
                 };
 
                 let ffn_layer_norm = LayerNorm {
@@ -1047,40 +2412,31 @@ impl<'a> FromBuilder for Model<'a> {
 
                 let ffn = format!("blocks.{layer}.ffn");
                 let time_mix_k = loader.load_vector_f16(format!("{ffn}.time_mix_k"))?;
-                let time_mix_r = loader.load_vector_f16(format!("{ffn}.time_mix_k"))?;
+                let time_mix_r = loader.load_vector_f16(format!("{ffn}.time_mix_r"))?;
 
                 let w_r = loader.load_matrix_f16(format!("{ffn}.receptance.weight"))?;
                 let w_k = loader.load_matrix_f16(format!("{ffn}.key.weight"))?;
                 let w_v =
                     loader.load_matrix_f16_discount(format!("{ffn}.value.weight"), discount)?;
 
-                let ffn = match quant {
-                    Quant::None => Ffn {
-                        time_mix_k,
-                        time_mix_r,
-                        w_k: Matrix::Fp16(w_k),
-                        w_v: Matrix::Fp16(w_v),
-                        w_r: Matrix::Fp16(w_r),
-                    },
-                    Quant::Int8 => Ffn {
-                        time_mix_k,
-                        time_mix_r,
-                        w_k: Matrix::quant_u8(w_k)?,
-                        w_v: Matrix::quant_u8(w_v)?,
-                        w_r: Matrix::quant_u8(w_r)?,
-                    },
-                    Quant::NF4 => Ffn {
-                        time_mix_k,
-                        time_mix_r,
-                        w_k: Matrix::quant_nf4(w_k)?,
-                        w_v: Matrix::quant_nf4(w_v)?,
-                        w_r: Matrix::quant_nf4(w_r)?,
-                    },
+                let ffn = Ffn {
+                    time_mix_k,
+                    time_mix_r,
+                    w_k: quant_matrix(format!("{ffn}.key.weight"), w_k)?,
+                    w_v: quant_matrix(format!("{ffn}.value.weight"), w_v)?,
+                    w_r: quant_matrix(format!("{ffn}.receptance.weight"), w_r)?,
                 };
 
                 context.queue.submit(None);
                 context.device.poll(wgpu::MaintainBase::Wait);
 
+                if let Some(progress) = progress.as_mut() {
+                    progress(BuildProgress {
+                        layer: layer + 1,
+                        num_layer: info.num_layer,
+                    });
+                }
+
                 Ok(Layer {
                     att_layer_norm,
                     ffn_layer_norm,
@@ -1090,14 +2446,21 @@ impl<'a> FromBuilder for Model<'a> {
             })
             .collect::<Result<Vec<_>>>()?;
 
+        // every tensor is on the device by now, so the source can go
+        drop(loader);
+        drop(source);
+
         context.queue.submit(None);
         context.device.poll(wgpu::MaintainBase::Wait);
 
         let tensor = ModelTensor {
             embed,
             head,
+            host,
             layers,
         };
+        let reserve =
+            envelope.map(|envelope| Reserve::new(&context, &info, envelope, token_chunk_size));
         Ok(Self {
             context,
             info,
@@ -1105,15 +2468,22 @@ impl<'a> FromBuilder for Model<'a> {
             turbo,
             head_chunk_size,
             token_chunk_size,
-            tensor,
+            capture,
+            tensor: tensor.into(),
+            quant_report: report,
             runtime_cache: ResourceCache::new(1),
             output_cache: ResourceCache::new(1),
             softmax_cache: ResourceCache::new(1),
+            capture_cache: ResourceCache::new(4),
+            envelope,
+            reserve,
+            overrides: Default::default(),
+            stats: None,
         })
     }
 }
 
-impl super::Model for Model<'_> {
+impl<F: Float> super::Model for Model<'_, F> {
     type ModelState = ModelState;
 
     #[inline]
@@ -1126,38 +2496,121 @@ impl super::Model for Model<'_> {
         &self.info
     }
 
+    fn embed_tokens(&self, tokens: &[u16], layer_norm: bool) -> Result<TensorGpu<f32, ReadWrite>> {
+        let context = &self.context;
+        let tensor = &self.tensor;
+        self.check_tokens(tokens)?;
+
+        let stack = TensorCpu::stack(
+            tokens
+                .iter()
+                // SAFETY: all tokens are checked to be within the vocabulary
+                .map(|&token| unsafe { tensor.embed.w.slice_unchecked(.., token as usize, .., ..) })
+                .collect(),
+        )?;
+        let stack = stack.map(|x| x.to_f32()).reshape(
+            TensorDimension::Full,
+            TensorDimension::Auto,
+            TensorDimension::Dimension(1),
+            TensorDimension::Full,
+        )?;
+        let output: TensorGpu<f32, ReadWrite> = stack.into();
+
+        if layer_norm {
+            let op = TensorOp::layer_norm(
+                &tensor.embed.layer_norm.w,
+                &tensor.embed.layer_norm.b,
+                &output,
+            )?;
+            let mut encoder = context
+                .device
+                .create_command_encoder(&CommandEncoderDescriptor::default());
+            let mut pass = encoder.begin_compute_pass(&ComputePassDescriptor::default());
+            pass.execute_tensor_op(&op);
+            drop(pass);
+            context.queue.submit(Some(encoder.finish()));
+        }
+
+        Ok(output)
+    }
+
     fn softmax(&self, input: Vec<Option<Vec<f32>>>) -> Result<Vec<Option<Vec<f32>>>> {
-        let max_batch = input.len();
+        self.softmax_internal(input, None)
+    }
 
-        let mut redirect = vec![None; max_batch];
-        let input: Vec<_> = input
+    fn softmax_with_temperature(
+        &self,
+        input: Vec<Option<Vec<f32>>>,
+        temperature: &[f32],
+    ) -> Result<Vec<Option<Vec<f32>>>> {
+        self.softmax_internal(input, Some(temperature))
+    }
+
+    fn run(
+        &self,
+        tokens: &mut Vec<Vec<u16>>,
+        state: &Self::ModelState,
+    ) -> Result<Vec<Option<Vec<f32>>>> {
+        self.run_layers(tokens, state, 0..self.info.num_layer)
+    }
+
+    fn run_layers(
+        &self,
+        tokens: &mut Vec<Vec<u16>>,
+        state: &Self::ModelState,
+        layers: Range<usize>,
+    ) -> Result<Vec<Option<Vec<f32>>>> {
+        let outputs = self.run_with(tokens, state, layers, false)?;
+        Ok(outputs
             .into_iter()
-            .enumerate()
-            .filter_map(|(batch, data)| data.map(|data| (batch, data)))
-            .map(|(batch, data)| {
-                TensorCpu::from_data(&self.context, self.head_shape(1), data)
-                    .map(|tensor| (batch, tensor))
-            })
-            .try_collect()?;
-        let input = TensorCpu::stack(
-            input
-                .into_iter()
-                .enumerate()
-                .map(|(index, (batch, tensor))| {
-                    redirect[batch] = Some(index);
-                    tensor
-                })
-                .collect_vec(),
-        )?;
+            .map(|output| output.and_then(|mut output| output.pop()))
+            .collect())
+    }
 
-        let num_batch = input.shape()[2];
-        let softmax = self.request_softmax(num_batch);
-        softmax.buffer.load(&input)?;
+    fn run_full(
+        &self,
+        tokens: &mut Vec<Vec<u16>>,
+        state: &Self::ModelState,
+    ) -> Result<Vec<Vec<Vec<f32>>>> {
+        let outputs = self.run_with(tokens, state, 0..self.info.num_layer, true)?;
+        Ok(outputs.into_iter().map(Option::unwrap_or_default).collect())
+    }
 
-        let op = TensorOp::softmax(&softmax.buffer)?;
+    fn verify(
+        &self,
+        tokens: &[u16],
+        batch: usize,
+        state: &Self::ModelState,
+    ) -> Result<(usize, u16)> {
+        let max_batch = state.max_batch;
+        if batch >= max_batch {
+            let max = max_batch;
+            return Err(ModelError::BatchOutOfRange { batch, max }.into());
+        }
+        if tokens.is_empty() || tokens.len() > self.token_chunk_size {
+            let (tokens, max) = (tokens.len(), self.token_chunk_size);
+            return Err(ModelError::ChunkOverflow { tokens, max }.into());
+        }
 
-        let mut encoder = self
-            .context
+        let page = batch / state.page_size();
+        let start = page * state.page_size();
+        let end = (start + state.page_size()).min(max_batch);
+        let mut inputs = vec![vec![]; end - start];
+        inputs[batch - start] = tokens.to_vec();
+
+        let layers = 0..self.info.num_layer;
+        let (output, _) =
+            self.run_internal(inputs, state, page, None, layers, true, RunOutput::Logits)?;
+
+        let context = &self.context;
+        let tokens = tokens.iter().map(|&token| token as u32).collect_vec();
+        let tokens: TensorGpu<u32, ReadWrite> =
+            context.tensor_from_data(Shape::new(tokens.len(), 1, 1, 1), tokens)?;
+        let verify: TensorGpu<u32, ReadWrite> = context.tensor_init(Shape::new(2, 1, 1, 1));
+        let map: TensorGpu<u32, ReadBack> = context.tensor_init(Shape::new(2, 1, 1, 1));
+        let op = TensorOp::verify(&output.head_o, &tokens, &verify)?;
+
+        let mut encoder = context
             .device
             .create_command_encoder(&CommandEncoderDescriptor::default());
 
@@ -1165,74 +2618,512 @@ impl super::Model for Model<'_> {
         pass.execute_tensor_op(&op);
         drop(pass);
 
-        encoder.copy_tensor(&softmax.buffer, &softmax.map)?;
-        self.context.queue.submit(Some(encoder.finish()));
+        encoder.copy_tensor(&verify, &map)?;
+        context.queue.submit(Some(encoder.finish()));
 
-        let mut output = TensorCpu::from(softmax.map.clone())
-            .split(2)
-            .expect("split buffer map")
-            .into_iter()
-            .map(|tensor| Some(tensor.to_vec()))
-            .collect_vec();
+        let verify = Vec::from(TensorCpu::from(map));
+        Ok((verify[0] as usize, verify[1] as u16))
+    }
 
-        let mut probs = vec![None; max_batch];
-        for (probs, redirect) in probs.iter_mut().zip_eq(redirect.into_iter()) {
-            if let Some(redirect) = redirect {
-                std::mem::swap(probs, &mut output[redirect]);
-            }
+    fn prefill(
+        &self,
+        tokens: &[u16],
+        batch: usize,
+        state: &Self::ModelState,
+        output: &TensorGpu<f32, ReadWrite>,
+        offset: usize,
+    ) -> Result<()> {
+        let max_batch = state.max_batch;
+        if batch >= max_batch {
+            let max = max_batch;
+            return Err(ModelError::BatchOutOfRange { batch, max }.into());
+        }
+        let shape = output.shape();
+        if shape[0] != self.info.num_vocab || shape[1] < offset + tokens.len() || shape[2] != 1 {
+            let shape = Shape::new(self.info.num_vocab, offset + tokens.len(), 1, 1);
+            return Err(TensorError::Shape(output.shape(), shape).into());
         }
 
-        Ok(probs)
+        let page = batch / state.page_size();
+        let start = page * state.page_size();
+        let end = (start + state.page_size()).min(max_batch);
+
+        let mut offset = offset;
+        for chunk in tokens.chunks(self.token_chunk_size) {
+            let mut inputs = vec![vec![]; end - start];
+            inputs[batch - start] = chunk.to_vec();
+
+            let layers = 0..self.info.num_layer;
+            let (logits, _) =
+                self.run_internal(inputs, state, page, None, layers, true, RunOutput::Logits)?;
+
+            // queued after the run, so the cached output is copied out before being reused
+            let op = TensorOp::blit(
+                logits.head_o.view(.., .., .., ..)?,
+                output.view(.., offset..offset + chunk.len(), .., ..)?,
+            )?;
+            let mut encoder = self
+                .context
+                .device
+                .create_command_encoder(&CommandEncoderDescriptor::default());
+            let mut pass = encoder.begin_compute_pass(&ComputePassDescriptor::default());
+            pass.execute_tensor_op(&op);
+            drop(pass);
+            self.context.queue.submit(Some(encoder.finish()));
+
+            offset += chunk.len();
+        }
+        Ok(())
     }
 
-    fn run(
+    fn run_embeds(
         &self,
-        tokens: &mut Vec<Vec<u16>>,
+        embeds: &TensorCpu<f32>,
+        batch: usize,
         state: &Self::ModelState,
-    ) -> Result<Vec<Option<Vec<f32>>>> {
-        let num_token: usize = tokens.iter().map(Vec::len).sum();
-        let max_batch = state.max_batch;
+    ) -> Result<()> {
+        use super::ModelState;
 
-        if tokens.len() != max_batch {
-            return Err(ModelError::BatchSize(tokens.len(), max_batch).into());
+        let max_batch = state.max_batch();
+        if batch >= max_batch {
+            let max = max_batch;
+            return Err(ModelError::BatchOutOfRange { batch, max }.into());
         }
+        let num_token = embeds.shape()[1];
+        embeds.check_shape(Shape::new(self.info.num_emb, num_token, 1, 1))?;
         if num_token == 0 {
-            return Ok(vec![None; max_batch]);
+            return Err(ModelError::EmptyTokens.into());
         }
 
-        // we only infer at most `token_chunk_size` tokens at a time
-        let mut num_token = num_token.min(self.token_chunk_size);
-        let mut inputs = vec![vec![]; max_batch];
-        let mut last = None;
+        let page = batch / state.page_size();
+        let start = page * state.page_size();
+        let end = (start + state.page_size()).min(max_batch);
+
+        for chunk in (0..num_token).step_by(self.token_chunk_size) {
+            let chunk = chunk..(chunk + self.token_chunk_size).min(num_token);
+            let inputs = (start..end)
+                .map(|index| match index == batch {
+                    true => Ok(embeds
+                        .slice(.., chunk.clone(), .., ..)?
+                        .map(|&x| F::from_f32(x))),
+                    false => Ok(self.context.zeros(Shape::new(self.info.num_emb, 0, 1, 1))),
+                })
+                .collect::<Result<Vec<_>, TensorError>>()?;
+
+            // the state is all that is wanted, so the head is skipped
+            let layers = 0..self.info.num_layer;
+            self.run_input(
+                inputs,
+                state,
+                page,
+                Some(batch),
+                layers,
+                false,
+                RunOutput::Logits,
+            )?;
+        }
+        Ok(())
+    }
 
-        // take `num_token` tokens out of all the inputs and put into `input`
-        for (index, (batch, input)) in tokens.iter_mut().zip(inputs.iter_mut()).enumerate() {
-            let mid = batch.len().min(num_token);
-            num_token -= mid;
+    fn logit_lens(
+        &self,
+        tokens: &[u16],
+        batch: usize,
+        state: &Self::ModelState,
+    ) -> Result<Vec<Vec<f32>>> {
+        use super::ModelState;
 
-            let (head, tail) = batch.split_at(mid);
-            last = (!tail.is_empty()).then_some(index);
-            *input = head.to_vec();
-            *batch = tail.to_vec();
+        let max_batch = state.max_batch();
+        if batch >= max_batch {
+            let max = max_batch;
+            return Err(ModelError::BatchOutOfRange { batch, max }.into());
+        }
+        if tokens.is_empty() {
+            return Err(ModelError::EmptyTokens.into());
+        }
 
-            if num_token == 0 {
-                break;
+        let context = &self.context;
+        let num_vocab = self.info.num_vocab;
+        let num_layer = self.info.num_layer;
+        let shape = Shape::new(num_vocab, 1, num_layer, 1);
+        let lens: TensorGpu<f32, ReadWrite> = context.tensor_init(shape);
+        let map: TensorGpu<f32, ReadBack> = context.tensor_init(shape);
+
+        let page = batch / state.page_size();
+        let start = page * state.page_size();
+        let end = (start + state.page_size()).min(max_batch);
+
+        let mut chunks = tokens.chunks(self.token_chunk_size).peekable();
+        while let Some(chunk) = chunks.next() {
+            let mut inputs = vec![vec![]; end - start];
+            inputs[batch - start] = chunk.to_vec();
+
+            let layers = 0..num_layer;
+            match chunks.peek() {
+                // only the state is wanted from the chunks before the last, so the head is skipped
+                Some(_) => self.run_internal(
+                    inputs,
+                    state,
+                    page,
+                    Some(batch),
+                    layers,
+                    false,
+                    RunOutput::Logits,
+                )?,
+                None => self.run_internal(
+                    inputs,
+                    state,
+                    page,
+                    None,
+                    layers,
+                    false,
+                    RunOutput::Lens(&lens),
+                )?,
+            };
+        }
+
+        let mut encoder = context
+            .device
+            .create_command_encoder(&CommandEncoderDescriptor::default());
+        encoder.copy_tensor(&lens, &map)?;
+        context.queue.submit(Some(encoder.finish()));
+
+        let lens = TensorCpu::from(map);
+        Ok(lens.iter_batches().map(|lens| lens.to_vec()).collect())
+    }
+
+    fn embed_sequence(
+        &self,
+        tokens: &[u16],
+        batch: usize,
+        state: &Self::ModelState,
+        pooling: Pooling,
+        normalize: bool,
+    ) -> Result<Vec<f32>> {
+        let max_batch = state.max_batch;
+        if batch >= max_batch {
+            let max = max_batch;
+            return Err(ModelError::BatchOutOfRange { batch, max }.into());
+        }
+        if tokens.is_empty() {
+            return Err(ModelError::EmptyTokens.into());
+        }
+
+        let context = &self.context;
+        let num_emb = self.info.num_emb;
+        let hidden: TensorGpu<f32, ReadWrite> =
+            context.tensor_init(Shape::new(num_emb, tokens.len(), 1, 1));
+
+        let page = batch / state.page_size();
+        let start = page * state.page_size();
+        let end = (start + state.page_size()).min(max_batch);
+
+        let mut offset = 0;
+        for chunk in tokens.chunks(self.token_chunk_size) {
+            let mut inputs = vec![vec![]; end - start];
+            inputs[batch - start] = chunk.to_vec();
+
+            let layers = 0..self.info.num_layer;
+            let output = hidden.view(.., offset..offset + chunk.len(), .., ..)?;
+            self.run_internal(
+                inputs,
+                state,
+                page,
+                None,
+                layers,
+                true,
+                RunOutput::Hidden(output),
+            )?;
+
+            offset += chunk.len();
+        }
+
+        let weight = pooling.weights(tokens.len());
+        let weight: TensorGpu<f32, ReadWrite> =
+            context.tensor_from_data(Shape::new(tokens.len(), 1, 1, 1), weight)?;
+        let embed: TensorGpu<f32, ReadWrite> = context.tensor_init(Shape::new(num_emb, 1, 1, 1));
+        let map: TensorGpu<f32, ReadBack> = context.tensor_init(Shape::new(num_emb, 1, 1, 1));
+
+        let mut ops = vec![TensorOp::pool(&weight, &hidden, &embed)?];
+        if normalize {
+            ops.push(TensorOp::l2_norm(&embed)?);
+        }
+        let ops = TensorOp::List(ops);
+
+        let mut encoder = context
+            .device
+            .create_command_encoder(&CommandEncoderDescriptor::default());
+
+        let mut pass = encoder.begin_compute_pass(&ComputePassDescriptor::default());
+        pass.execute_tensor_op(&ops);
+        drop(pass);
+
+        encoder.copy_tensor(&embed, &map)?;
+        context.queue.submit(Some(encoder.finish()));
+
+        Ok(Vec::from(TensorCpu::from(map)))
+    }
+
+    fn warmup(&self, max_batch: usize, max_tokens: usize) -> Result<()> {
+        let max_batch = max_batch.max(1);
+        let max_tokens = max_tokens.clamp(1, self.token_chunk_size);
+        let state: ModelState = StateBuilder::new(&self.context, &self.info)
+            .with_max_batch(max_batch)
+            .build();
+
+        // a single token multiplies vectors, and a long enough run matrices; the largest run goes last to stay cached
+        let offset = self.tensor.host.len();
+        let (layers, normalize) = (offset..self.info.num_layer, offset == 0);
+        for num_token in [1, max_tokens].into_iter().dedup() {
+            let num_header = num_token.min(state.page_size());
+            let headers = (num_token - num_header..num_token).collect_vec();
+            let (layers, target) = (layers.clone(), RunOutput::Logits);
+            self.record(&state, 0, num_token, &headers, layers, normalize, target)?;
+        }
+
+        let input = vec![Some(vec![0.0; self.info.num_vocab]); max_batch];
+        self.softmax(input)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use itertools::Itertools;
+
+    use super::{Model, ModelState};
+    use crate::model::{
+        loader::Loader,
+        reference,
+        synthetic::{
+            testing::{create_context, is_approx_eps, relative_error, run},
+            SyntheticBuilder,
+        },
+        Model as _, ModelBuilder, ModelVersion, Quant, StateBuilder,
+    };
+
+    #[test]
+    fn test_time_mix_v5() -> Result<()> {
+        let context = match create_context() {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+
+        let builder = SyntheticBuilder::new(ModelVersion::V5);
+        let info = builder.info();
+        let data = builder.build()?;
+        let reference = reference::Model::from_safetensors(&data)?;
+
+        let model: Model = ModelBuilder::new(&context, &data)
+            .with_head_chunk_size(info.num_vocab)
+            .build()?;
+        let state: ModelState = StateBuilder::new(&context, &info).with_max_batch(3).build();
+
+        // the kernel takes tokens in blocks of 4, so these make blocks spanning several batches
+        let prompts = [vec![31u16, 4, 159, 26, 5, 8, 97], vec![2, 200], vec![77]];
+        let logits = run(&model, &state, &prompts)?;
+        for (tokens, logits) in prompts.iter().zip_eq(logits) {
+            let logits = logits.unwrap();
+            let expected = reference.run(tokens, &mut reference.init_state()).unwrap();
+            for (&a, &b) in logits.iter().zip_eq(expected.iter()) {
+                assert!(is_approx_eps(a, b, 1.0e-2), "logits: {a} vs {b}");
             }
         }
+        Ok(())
+    }
 
-        let (output, redirect) = self.run_internal(inputs, state, last)?;
-        let output = TensorCpu::from(output.map.clone());
+    #[test]
+    fn test_share_model() -> Result<()> {
+        let context = match create_context() {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
 
-        Ok(redirect
-            .into_iter()
-            .map(|index| {
-                index.map(|index| {
-                    output
-                        .slice(.., index, .., ..)
-                        .expect("this never happens")
-                        .to_vec()
-                })
-            })
-            .collect())
+        let builder = SyntheticBuilder::new(ModelVersion::V5);
+        let info = builder.info();
+        let data = builder.build()?;
+        let model: Model = ModelBuilder::new(&context, &data)
+            .with_token_chunk_size(32)
+            .build()?;
+        let weights = context.memory_usage().weights;
+
+        // no weights are uploaded again for the new handle
+        let shared = model.share().with_token_chunk_size(2)?;
+        assert_eq!(context.memory_usage().weights, weights);
+        assert!(model.share().with_token_chunk_size(3).is_err());
+
+        let tokens = vec![vec![5u16, 23, 177, 2, 94]];
+        let state: ModelState = StateBuilder::new(&context, &info).build();
+        let expected = run(&model, &state, &tokens)?[0].take().unwrap();
+        let state: ModelState = StateBuilder::new(&context, &info).build();
+        let output = run(&shared, &state, &tokens)?[0].take().unwrap();
+        for (a, b) in output.into_iter().zip_eq(expected) {
+            assert!(is_approx_eps(a, b, 1.0e-3), "{a} != {b}");
+        }
+
+        // the weights go with the last handle
+        model.destroy();
+        assert_eq!(context.memory_usage().weights, weights);
+        shared.destroy();
+        assert_eq!(context.memory_usage().weights, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_activation_stats() -> Result<()> {
+        let context = match create_context() {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+
+        let builder = SyntheticBuilder::new(ModelVersion::V5);
+        let info = builder.info();
+        let data = builder.build()?;
+        let tokens = [vec![5u16, 23, 177, 40, 2]];
+        let logits = |model: &Model| -> Result<Vec<f32>> {
+            let state: ModelState = StateBuilder::new(&context, &info).build();
+            Ok(run(model, &state, &tokens)?.remove(0).unwrap())
+        };
+
+        let model: Model = ModelBuilder::new(&context, &data)
+            .with_token_chunk_size(4)
+            .build()?;
+        assert_eq!(model.activation_stats()?, None);
+        let expected = logits(&model)?;
+
+        // collecting the statistics leaves the output as it is
+        let model = model.with_activation_stats(true);
+        assert_eq!(logits(&model)?, expected);
+
+        let stats = model.activation_stats()?.unwrap();
+        assert_eq!(stats.len(), info.num_layer);
+        for stats in &stats {
+            assert_eq!(stats.count, info.num_emb * tokens[0].len());
+            assert_eq!(stats.non_finite, 0);
+            assert!(
+                stats.min <= stats.mean && stats.mean <= stats.max,
+                "{stats:?}"
+            );
+            assert!(stats.min < stats.max, "{stats:?}");
+            assert_eq!(stats.abs_max, stats.min.abs().max(stats.max.abs()));
+        }
+
+        // runs add up until reset
+        logits(&model)?;
+        let twice = model.activation_stats()?.unwrap();
+        for (a, b) in stats.iter().zip_eq(twice.iter()) {
+            assert_eq!(a.count * 2, b.count);
+            assert_eq!((a.min, a.max), (b.min, b.max));
+        }
+        model.reset_activation_stats()?;
+        let reset = model.activation_stats()?.unwrap();
+        assert!(reset.iter().all(|stats| stats.count == 0));
+        Ok(())
+    }
+
+    #[test]
+    fn test_transfer_model() -> Result<()> {
+        let (from, to) = match (create_context(), create_context()) {
+            (Ok(from), Ok(to)) => (from, to),
+            _ => return Ok(()),
+        };
+
+        let builder = SyntheticBuilder::new(ModelVersion::V5).with_num_layer(2);
+        let info = builder.info();
+        let data = builder.build()?;
+        let model: Model = ModelBuilder::new(&from, &data)
+            .with_quant([(0, Quant::Int8), (1, Quant::NF4)].into())
+            .build()?;
+        let transferred = model.transfer(&to)?;
+        assert_eq!(to.memory_usage().weights, from.memory_usage().weights);
+
+        let tokens = vec![vec![5u16, 23, 177, 2, 94]];
+        let state: ModelState = StateBuilder::new(&from, &info).build();
+        let expected = run(&model, &state, &tokens)?[0].take().unwrap();
+        model.destroy();
+
+        let state: ModelState = StateBuilder::new(&to, &info).build();
+        let output = run(&transferred, &state, &tokens)?[0].take().unwrap();
+        for (a, b) in output.into_iter().zip_eq(expected) {
+            assert!(is_approx_eps(a, b, 1.0e-3), "{a} != {b}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_turbo_partial() -> Result<()> {
+        let context = match create_context() {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+
+        // 37 tokens in all: past the turbo threshold, but neither a full chunk nor a multiple of 4
+        let tokens = [(0..19).collect_vec(), (100..118).collect_vec()];
+        let data = SyntheticBuilder::new(ModelVersion::V5).build()?;
+        let info = Loader::info(&data)?;
+        let run_turbo = |turbo: bool| -> Result<Vec<Option<Vec<f32>>>> {
+            let model: Model = ModelBuilder::new(&context, &data)
+                .with_token_chunk_size(64)
+                .with_turbo(turbo)
+                .build()?;
+            let state: ModelState = StateBuilder::new(&context, &info).with_max_batch(2).build();
+            run(&model, &state, &tokens)
+        };
+        let expected = run_turbo(false)?;
+        let logits = run_turbo(true)?;
+        for (logits, expected) in logits.into_iter().zip_eq(expected) {
+            let error = relative_error(&logits.unwrap(), &expected.unwrap());
+            assert!(error < 0.01, "relative error: {error}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_envelope() -> Result<()> {
+        let context = match create_context() {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+
+        // shapes of all sizes within the envelope, each run in the reserved buffers
+        let prompt = [(0..19).collect_vec(), (100..118).collect_vec()];
+        let tokens = [vec![7], vec![]];
+        let data = SyntheticBuilder::new(ModelVersion::V5).build()?;
+        let info = Loader::info(&data)?;
+        let build = |envelope: bool| -> Result<Model> {
+            let builder = ModelBuilder::new(&context, &data).with_token_chunk_size(64);
+            match envelope {
+                true => builder.with_envelope(2, 64).build(),
+                false => builder.build(),
+            }
+        };
+
+        let model = build(false)?;
+        let state: ModelState = StateBuilder::new(&context, &info).with_max_batch(2).build();
+        let expected = [run(&model, &state, &prompt)?, run(&model, &state, &tokens)?];
+        drop(model);
+
+        let model = build(true)?;
+        let state: ModelState = StateBuilder::new(&context, &info).with_max_batch(2).build();
+        let reserved = context.memory_usage().runtime;
+        let logits = [run(&model, &state, &prompt)?, run(&model, &state, &tokens)?];
+        let probs = model.softmax(logits[0].clone())?;
+        assert_eq!(context.memory_usage().runtime, reserved);
+
+        for (logits, expected) in logits.iter().flatten().zip_eq(expected.iter().flatten()) {
+            match (logits, expected) {
+                (Some(logits), Some(expected)) => {
+                    let error = relative_error(logits, expected);
+                    assert!(error < 1e-5, "relative error: {error}");
+                }
+                (logits, expected) => assert_eq!(logits.is_some(), expected.is_some()),
+            }
+        }
+        for probs in probs.into_iter().flatten() {
+            let sum: f32 = probs.iter().sum();
+            assert!((sum - 1.0).abs() < 1e-3, "sum of probabilities: {sum}");
+        }
+        Ok(())
     }
 }
@@ -0,0 +1,320 @@
+//! Keeping a conversation within a token budget, by condensing its old turns and prefilling it again from a checkpoint.
+//!
+//! The state of RWKV doesn't grow with the context, so going over the budget costs nothing by itself;
+//! the budget bounds how far back the model is asked to remember, and how long a rebuild takes.
+
+use anyhow::Result;
+
+use super::{Model, ModelError, ModelState};
+
+/// Decides what is left of the turns of a conversation once they go over budget.
+pub trait WindowPolicy {
+    /// Condense `turns`, oldest first, into at most `budget` tokens, to be prefilled after the checkpoint in their place.
+    /// The last of `turns` is the one just added to.
+    fn condense(&mut self, turns: &[Vec<u16>], budget: usize) -> Result<Vec<Vec<u16>>>;
+}
+
+/// Drops the oldest turns until the rest fit.
+/// The newest turn is always kept, and cut from the front if it alone is over budget.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Truncate;
+
+impl WindowPolicy for Truncate {
+    fn condense(&mut self, turns: &[Vec<u16>], budget: usize) -> Result<Vec<Vec<u16>>> {
+        let keep = recent(turns, budget).max(1).min(turns.len());
+        let mut turns = turns[turns.len() - keep..].to_vec();
+        if let Some(turn) = turns.first_mut() {
+            let len = turn.len().saturating_sub(budget);
+            turn.drain(..len);
+        }
+        Ok(turns)
+    }
+}
+
+/// Replaces the oldest turns with a summary of them, keeping the recent ones as they are.
+///
+/// The summary is made by the given function, e.g. by prompting the model itself.
+/// If the summary and the recent turns still don't fit, they are truncated.
+#[derive(Debug, Clone)]
+pub struct Summarize<F> {
+    summarize: F,
+    recent: Option<usize>,
+}
+
+impl<F> Summarize<F>
+where
+    F: FnMut(&[Vec<u16>]) -> Result<Vec<u16>>,
+{
+    pub fn new(summarize: F) -> Self {
+        Self {
+            summarize,
+            recent: None,
+        }
+    }
+
+    /// Keep as many recent turns as fit in `value` tokens as they are. Defaults to half the budget.
+    pub fn with_recent(self, value: usize) -> Self {
+        Self {
+            recent: Some(value),
+            ..self
+        }
+    }
+}
+
+impl<F> WindowPolicy for Summarize<F>
+where
+    F: FnMut(&[Vec<u16>]) -> Result<Vec<u16>>,
+{
+    fn condense(&mut self, turns: &[Vec<u16>], budget: usize) -> Result<Vec<Vec<u16>>> {
+        let num_recent = self.recent.unwrap_or(budget / 2).min(budget);
+        let keep = recent(turns, num_recent).max(1).min(turns.len());
+        let (old, recent) = turns.split_at(turns.len() - keep);
+
+        let mut output = vec![];
+        if !old.is_empty() {
+            output.push((self.summarize)(old)?);
+        }
+        output.extend_from_slice(recent);
+
+        match length(&output) > budget {
+            true => Truncate.condense(&output, budget),
+            false => Ok(output),
+        }
+    }
+}
+
+/// Total number of tokens in `turns`.
+fn length(turns: &[Vec<u16>]) -> usize {
+    turns.iter().map(Vec::len).sum()
+}
+
+/// Number of most recent turns that fit in `budget` tokens together.
+fn recent(turns: &[Vec<u16>], budget: usize) -> usize {
+    let mut len = 0;
+    turns
+        .iter()
+        .rev()
+        .take_while(|turn| {
+            len += turn.len();
+            len <= budget
+        })
+        .count()
+}
+
+/// A conversation on one batch of a state, kept within `budget` tokens after a checkpoint.
+///
+/// The checkpoint is a host copy of the batch taken when the window is created, e.g. right after a system prompt.
+/// Tokens are run as they come; once the turns after the checkpoint go over budget, the [`WindowPolicy`] condenses them,
+/// the checkpoint is loaded back into the batch, and what is left of the turns is prefilled from there.
+#[derive(Debug, Clone)]
+pub struct ContextWindow<B, P> {
+    batch: usize,
+    budget: usize,
+    checkpoint: B,
+    turns: Vec<Vec<u16>>,
+    policy: P,
+    rebuilds: usize,
+}
+
+impl<B, P: WindowPolicy> ContextWindow<B, P> {
+    /// Take `batch` of `state` as it is now as the checkpoint.
+    pub fn new<S>(state: &S, batch: usize, budget: usize, policy: P) -> Result<Self>
+    where
+        S: ModelState<BackedState = B>,
+    {
+        let checkpoint = state.back_batch(batch)?;
+        Ok(Self {
+            batch,
+            budget: budget.max(1),
+            checkpoint,
+            turns: vec![],
+            policy,
+            rebuilds: 0,
+        })
+    }
+
+    #[inline]
+    pub fn batch(&self) -> usize {
+        self.batch
+    }
+
+    #[inline]
+    pub fn budget(&self) -> usize {
+        self.budget
+    }
+
+    /// Turns since the checkpoint as they are in the state, oldest first.
+    #[inline]
+    pub fn turns(&self) -> &[Vec<u16>] {
+        &self.turns
+    }
+
+    /// Number of tokens since the checkpoint.
+    pub fn len(&self) -> usize {
+        length(&self.turns)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// How many times the batch has been rebuilt from the checkpoint.
+    #[inline]
+    pub fn rebuilds(&self) -> usize {
+        self.rebuilds
+    }
+
+    #[inline]
+    pub fn policy_mut(&mut self) -> &mut P {
+        &mut self.policy
+    }
+
+    /// Run `tokens` as a new turn, e.g. a user message, returning the logits after the last of them.
+    pub fn push<M, S>(&mut self, model: &M, state: &S, tokens: &[u16]) -> Result<Vec<f32>>
+    where
+        M: Model<ModelState = S>,
+        S: ModelState<BackedState = B>,
+    {
+        if tokens.is_empty() {
+            return Err(ModelError::EmptyTokens.into());
+        }
+        self.turns.push(vec![]);
+        self.extend(model, state, tokens)
+    }
+
+    /// Run `tokens` as part of the last turn, e.g. tokens generated in reply, returning the logits after the last of them.
+    pub fn extend<M, S>(&mut self, model: &M, state: &S, tokens: &[u16]) -> Result<Vec<f32>>
+    where
+        M: Model<ModelState = S>,
+        S: ModelState<BackedState = B>,
+    {
+        if tokens.is_empty() {
+            return Err(ModelError::EmptyTokens.into());
+        }
+        match self.turns.last_mut() {
+            Some(turn) => turn.extend_from_slice(tokens),
+            None => self.turns.push(tokens.to_vec()),
+        }
+
+        if self.len() <= self.budget {
+            return run(model, state, self.batch, tokens);
+        }
+
+        // whatever the policy leaves over budget is cut anyway
+        let turns = self.policy.condense(&self.turns, self.budget)?;
+        self.turns = match length(&turns) > self.budget {
+            true => Truncate.condense(&turns, self.budget)?,
+            false => turns,
+        };
+        self.rebuilds += 1;
+
+        state.load_batch(&self.checkpoint, self.batch)?;
+        run(model, state, self.batch, &self.turns.concat())
+    }
+}
+
+/// Run `tokens` on `batch` until consumed, returning the logits after the last of them.
+fn run<M: Model>(
+    model: &M,
+    state: &M::ModelState,
+    batch: usize,
+    tokens: &[u16],
+) -> Result<Vec<f32>> {
+    let mut input = vec![vec![]; state.max_batch()];
+    input[batch] = tokens.to_vec();
+    let mut logits = vec![];
+    while !input[batch].is_empty() {
+        if let Some(output) = model.run(&mut input, state)?.swap_remove(batch) {
+            logits = output;
+        }
+    }
+    Ok(logits)
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use super::{ContextWindow, Summarize, Truncate, WindowPolicy};
+    use crate::model::{
+        synthetic::{
+            testing::{create_context, run},
+            SyntheticBuilder,
+        },
+        v5, ModelBuilder, ModelVersion, StateBuilder,
+    };
+
+    #[test]
+    fn test_truncate() -> Result<()> {
+        let turns = vec![vec![1, 2, 3], vec![4, 5], vec![6, 7, 8]];
+        assert_eq!(
+            Truncate.condense(&turns, 6)?,
+            vec![vec![4, 5], vec![6, 7, 8]]
+        );
+        assert_eq!(Truncate.condense(&turns, 4)?, vec![vec![6, 7, 8]]);
+        assert_eq!(Truncate.condense(&turns, 2)?, vec![vec![7, 8]]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_summarize() -> Result<()> {
+        let turns = vec![vec![1, 2, 3], vec![4, 5], vec![6, 7, 8]];
+
+        let mut policy = Summarize::new(|turns: &[Vec<u16>]| Ok(vec![turns.len() as u16]));
+        assert_eq!(policy.condense(&turns, 7)?, vec![vec![2], vec![6, 7, 8]]);
+
+        let mut policy = policy.with_recent(5);
+        assert_eq!(
+            policy.condense(&turns, 7)?,
+            vec![vec![1], vec![4, 5], vec![6, 7, 8]]
+        );
+
+        // a summary too long to fit is truncated along with the rest
+        let mut policy = Summarize::new(|_: &[Vec<u16>]| Ok(vec![0; 10]));
+        assert_eq!(policy.condense(&turns, 7)?, vec![vec![6, 7, 8]]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_context_window() -> Result<()> {
+        let context = match create_context() {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+
+        let builder = SyntheticBuilder::new(ModelVersion::V5);
+        let info = builder.info();
+        let data = builder.build()?;
+
+        let model: v5::Model = ModelBuilder::new(&context, &data)
+            .with_head_chunk_size(info.num_vocab)
+            .build()?;
+        let state: v5::ModelState = StateBuilder::new(&context, &info).with_max_batch(2).build();
+
+        let system = [3u16, 14, 15];
+        run(&model, &state, &[vec![], system.to_vec()])?;
+        let mut window = ContextWindow::new(&state, 1, 6, Truncate)?;
+
+        window.push(&model, &state, &[12, 55, 8])?;
+        window.extend(&model, &state, &[91])?;
+        assert_eq!(window.len(), 4);
+        assert_eq!(window.rebuilds(), 0);
+
+        // the first turn is dropped, and the rest is run again after the system prompt
+        let logits = window.push(&model, &state, &[200, 7, 64])?;
+        assert_eq!(window.turns(), [vec![200, 7, 64]]);
+        assert_eq!(window.rebuilds(), 1);
+
+        let expected: v5::ModelState = StateBuilder::new(&context, &info).with_max_batch(2).build();
+        let output = run(
+            &model,
+            &expected,
+            &[vec![], [&system[..], &[200, 7, 64]].concat()],
+        )?;
+        assert_eq!(output[1].as_ref(), Some(&logits));
+
+        assert!(window.push(&model, &state, &[]).is_err());
+        assert_eq!(window.turns().len(), 1);
+        Ok(())
+    }
+}
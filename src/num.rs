@@ -1,6 +1,7 @@
 use bytemuck::Pod;
 use half::f16;
 use safetensors::Dtype;
+use serde::{Deserialize, Serialize};
 
 pub trait Zero: Sized + core::ops::Add<Self, Output = Self> {
     fn zero() -> Self;
@@ -94,6 +95,51 @@ impl Scalar for u32 {
     const DATA_TYPE: Dtype = Dtype::U32;
 }
 
+/// Floating point precision of tensors kept on the device.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Precision {
+    /// Full precision.
+    #[default]
+    F32,
+    /// Half precision, converted to f32 inside kernels.
+    F16,
+}
+
+/// Scalars that activations can be stored in. Kernels load and store them, but always compute in f32.
+pub trait Float: Scalar {
+    const PRECISION: Precision;
+
+    fn from_f32(value: f32) -> Self;
+    fn to_f32(self) -> f32;
+}
+
+impl Float for f32 {
+    const PRECISION: Precision = Precision::F32;
+
+    #[inline]
+    fn from_f32(value: f32) -> Self {
+        value
+    }
+
+    #[inline]
+    fn to_f32(self) -> f32 {
+        self
+    }
+}
+impl Float for f16 {
+    const PRECISION: Precision = Precision::F16;
+
+    #[inline]
+    fn from_f32(value: f32) -> Self {
+        f16::from_f32(value)
+    }
+
+    #[inline]
+    fn to_f32(self) -> f32 {
+        f16::to_f32(self)
+    }
+}
+
 mod sealed {
     use half::f16;
 
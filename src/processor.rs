@@ -0,0 +1,508 @@
+//! Adjusting logits between the head of the model and sampling.
+
+use std::collections::HashMap;
+
+use anyhow::Result;
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+use wgpu::{CommandEncoderDescriptor, ComputePassDescriptor};
+
+use crate::{
+    context::Context,
+    model::ModelError,
+    tensor::{
+        ops::{TensorOp, TensorPass},
+        shape::Shape,
+        ReadWrite, TensorCpu, TensorGpu, TensorInit, TensorShape,
+    },
+};
+
+/// Adjusts the logits of each batch before they are turned into probabilities.
+///
+/// Processors that keep per-batch history (e.g. token counts) learn about sampled tokens through
+/// [`LogitsProcessor::update`], and forget a batch when a new stream takes it through [`LogitsProcessor::reset`].
+pub trait LogitsProcessor {
+    /// Adjust `logits` on the host, one entry per batch. Batches with no output are `None`.
+    fn process(&mut self, logits: &mut [Option<Vec<f32>>]) -> Result<()>;
+
+    /// Adjust logits on the device, of shape `[num_vocab, 1, num_batch]`.
+    /// By default, they are read back, passed through [`LogitsProcessor::process`] and uploaded again.
+    fn process_gpu(&mut self, logits: &TensorGpu<f32, ReadWrite>) -> Result<()> {
+        let context = &logits.context;
+        let host = logits.back();
+        let mut host = host
+            .iter_batches()
+            .map(|tensor| Some(tensor.to_vec()))
+            .collect_vec();
+        self.process(&mut host)?;
+
+        let shape = Shape::new(logits.shape()[0], 1, 1, 1);
+        let host = host
+            .into_iter()
+            .map(|data| TensorCpu::from_data(context, shape, data.unwrap_or_default()))
+            .try_collect()?;
+        logits.load(&TensorCpu::stack(host)?)?;
+        Ok(())
+    }
+
+    /// Record tokens put into `batch` other than sampled ones, e.g. the prompt of a new stream.
+    fn extend(&mut self, _batch: usize, _tokens: &[u16]) -> Result<()> {
+        Ok(())
+    }
+
+    /// Record the token sampled for each batch, if any.
+    fn update(&mut self, _tokens: &[Option<u16>]) -> Result<()> {
+        Ok(())
+    }
+
+    /// Forget what was recorded for `batch`.
+    fn reset(&mut self, _batch: usize) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Processors applied one after another, in the order they were added.
+#[derive(Default)]
+pub struct ProcessorChain<'a>(Vec<Box<dyn LogitsProcessor + 'a>>);
+
+impl<'a> ProcessorChain<'a> {
+    pub fn new() -> Self {
+        Self(vec![])
+    }
+
+    pub fn with(mut self, processor: impl LogitsProcessor + 'a) -> Self {
+        self.0.push(Box::new(processor));
+        self
+    }
+
+    pub fn push(&mut self, processor: impl LogitsProcessor + 'a) {
+        self.0.push(Box::new(processor));
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl LogitsProcessor for ProcessorChain<'_> {
+    fn process(&mut self, logits: &mut [Option<Vec<f32>>]) -> Result<()> {
+        self.0
+            .iter_mut()
+            .try_for_each(|processor| processor.process(logits))
+    }
+
+    fn process_gpu(&mut self, logits: &TensorGpu<f32, ReadWrite>) -> Result<()> {
+        self.0
+            .iter_mut()
+            .try_for_each(|processor| processor.process_gpu(logits))
+    }
+
+    fn extend(&mut self, batch: usize, tokens: &[u16]) -> Result<()> {
+        self.0
+            .iter_mut()
+            .try_for_each(|processor| processor.extend(batch, tokens))
+    }
+
+    fn update(&mut self, tokens: &[Option<u16>]) -> Result<()> {
+        self.0
+            .iter_mut()
+            .try_for_each(|processor| processor.update(tokens))
+    }
+
+    fn reset(&mut self, batch: usize) -> Result<()> {
+        self.0
+            .iter_mut()
+            .try_for_each(|processor| processor.reset(batch))
+    }
+}
+
+/// Adds a fixed bias to the logits of some tokens in every batch.
+/// A bias of `f32::NEG_INFINITY` bans the token.
+#[derive(Debug, Clone, Default)]
+pub struct LogitBias(pub HashMap<u16, f32>);
+
+impl LogitsProcessor for LogitBias {
+    fn process(&mut self, logits: &mut [Option<Vec<f32>>]) -> Result<()> {
+        for logits in logits.iter_mut().flatten() {
+            for (&token, &bias) in &self.0 {
+                if let Some(logit) = logits.get_mut(token as usize) {
+                    *logit += bias;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Penalties on tokens that already appeared in a stream.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Penalty {
+    /// Positive logits of seen tokens are divided by this, and negative ones multiplied. `1.0` disables it.
+    pub repetition: f32,
+    /// Subtracted once from the logits of seen tokens.
+    pub presence: f32,
+    /// Subtracted from the logits of seen tokens once per appearance.
+    pub frequency: f32,
+}
+
+impl Default for Penalty {
+    fn default() -> Self {
+        Self {
+            repetition: 1.0,
+            presence: 0.0,
+            frequency: 0.0,
+        }
+    }
+}
+
+/// Applies a [`Penalty`] per batch on the device, keeping the token counts of each batch there.
+#[derive(Debug)]
+pub struct DevicePenalty {
+    context: Context,
+    num_vocab: usize,
+    penalties: Vec<Penalty>,
+    counts: Vec<u32>,
+    penalty: TensorGpu<f32, ReadWrite>,
+    count: TensorGpu<u32, ReadWrite>,
+}
+
+impl DevicePenalty {
+    /// One penalty for each batch. `num_vocab` must be a multiple of 4.
+    pub fn new(context: &Context, num_vocab: usize, penalties: Vec<Penalty>) -> Result<Self> {
+        let num_batch = penalties.len();
+        let penalty = context.tensor_init(Shape::new(4, num_batch, 1, 1));
+        let count = context.zeros(Shape::new(num_vocab, num_batch, 1, 1));
+        let processor = Self {
+            context: context.clone(),
+            num_vocab,
+            penalties,
+            counts: vec![0; num_vocab * num_batch],
+            penalty,
+            count,
+        };
+        processor.upload_penalties()?;
+        Ok(processor)
+    }
+
+    /// Change the penalty of `batch`, keeping its counts.
+    pub fn set(&mut self, batch: usize, penalty: Penalty) -> Result<()> {
+        let max = self.penalties.len();
+        let entry = self
+            .penalties
+            .get_mut(batch)
+            .ok_or(ModelError::BatchOutOfRange { batch, max })?;
+        *entry = penalty;
+        self.upload_penalties()
+    }
+
+    fn upload_penalties(&self) -> Result<()> {
+        let data = self
+            .penalties
+            .iter()
+            .flat_map(|penalty| [penalty.repetition, penalty.presence, penalty.frequency, 0.0])
+            .collect_vec();
+        let host = TensorCpu::from_data(&self.context, self.penalty.shape(), data)?;
+        self.penalty.load(&host)?;
+        Ok(())
+    }
+
+    fn upload_counts(&self) -> Result<()> {
+        let host = TensorCpu::from_data(&self.context, self.count.shape(), self.counts.clone())?;
+        self.count.load(&host)?;
+        Ok(())
+    }
+}
+
+impl LogitsProcessor for DevicePenalty {
+    fn process(&mut self, logits: &mut [Option<Vec<f32>>]) -> Result<()> {
+        let num_batch = self.penalties.len();
+        if logits.len() != num_batch {
+            return Err(ModelError::BatchSize(logits.len(), num_batch).into());
+        }
+
+        let shape = Shape::new(self.num_vocab, 1, 1, 1);
+        let host = logits
+            .iter()
+            .map(|logits| {
+                let data = logits.clone().unwrap_or_else(|| vec![0.0; self.num_vocab]);
+                TensorCpu::from_data(&self.context, shape, data)
+            })
+            .try_collect()?;
+        let tensor: TensorGpu<f32, ReadWrite> = TensorCpu::stack(host)?.into();
+        self.process_gpu(&tensor)?;
+
+        let output = tensor.back();
+        for (logits, output) in logits.iter_mut().zip_eq(output.iter_batches()) {
+            if let Some(logits) = logits {
+                *logits = output.to_vec();
+            }
+        }
+        Ok(())
+    }
+
+    fn process_gpu(&mut self, logits: &TensorGpu<f32, ReadWrite>) -> Result<()> {
+        let op = TensorOp::penalty(&self.penalty, &self.count, logits)?;
+        let mut encoder = self
+            .context
+            .device
+            .create_command_encoder(&CommandEncoderDescriptor::default());
+        let mut pass = encoder.begin_compute_pass(&ComputePassDescriptor::default());
+        pass.execute_tensor_op(&op);
+        drop(pass);
+        self.context.queue.submit(Some(encoder.finish()));
+        Ok(())
+    }
+
+    fn update(&mut self, tokens: &[Option<u16>]) -> Result<()> {
+        let mut changed = false;
+        for (batch, token) in tokens.iter().enumerate() {
+            if let Some(&token) = token.as_ref().filter(|_| batch < self.penalties.len()) {
+                if let Some(count) = self.counts.get_mut(batch * self.num_vocab + token as usize) {
+                    *count += 1;
+                    changed = true;
+                }
+            }
+        }
+        match changed {
+            true => self.upload_counts(),
+            false => Ok(()),
+        }
+    }
+
+    fn reset(&mut self, batch: usize) -> Result<()> {
+        let max = self.penalties.len();
+        if batch >= max {
+            return Err(ModelError::BatchOutOfRange { batch, max }.into());
+        }
+        let start = batch * self.num_vocab;
+        self.counts[start..start + self.num_vocab].fill(0);
+        self.upload_counts()
+    }
+}
+
+const NGRAM_HASH_BASE: u64 = 0x100000001b3;
+
+#[derive(Debug, Clone, Default)]
+struct NgramIndex {
+    tokens: Vec<u16>,
+    /// Rolling hash of the last `n - 1` tokens.
+    hash: u64,
+    /// Positions of tokens, by the hash of the `n - 1` tokens before them.
+    index: HashMap<u64, Vec<usize>>,
+}
+
+/// Forbids any token that would complete an n-gram already in the context of its batch.
+///
+/// Each batch keeps an index from the rolling hash of every `n - 1` tokens to the positions following them,
+/// so finding the banned tokens only looks at earlier occurrences of the current `n - 1` tokens.
+#[derive(Debug, Clone)]
+pub struct NoRepeatNgram {
+    n: usize,
+    /// `NGRAM_HASH_BASE` to the power of `n - 1`, to roll the oldest token out of the hash.
+    power: u64,
+    streams: Vec<NgramIndex>,
+}
+
+impl NoRepeatNgram {
+    pub fn new(n: usize) -> Self {
+        let n = n.max(1);
+        let power = (1..n).fold(1u64, |power, _| power.wrapping_mul(NGRAM_HASH_BASE));
+        Self {
+            n,
+            power,
+            streams: vec![],
+        }
+    }
+
+    fn stream(&mut self, batch: usize) -> &mut NgramIndex {
+        if batch >= self.streams.len() {
+            self.streams.resize_with(batch + 1, Default::default);
+        }
+        &mut self.streams[batch]
+    }
+
+    /// Append `token` to the context of `batch`.
+    pub fn push(&mut self, batch: usize, token: u16) {
+        let (k, power) = (self.n - 1, self.power);
+        let stream = self.stream(batch);
+        let len = stream.tokens.len();
+        if len >= k {
+            stream.index.entry(stream.hash).or_default().push(len);
+        }
+        if k > 0 {
+            stream.hash = stream
+                .hash
+                .wrapping_mul(NGRAM_HASH_BASE)
+                .wrapping_add(token as u64 + 1);
+            if len >= k {
+                let oldest = stream.tokens[len - k] as u64 + 1;
+                stream.hash = stream.hash.wrapping_sub(oldest.wrapping_mul(power));
+            }
+        }
+        stream.tokens.push(token);
+    }
+
+    /// Tokens that would complete an n-gram already in the context of `batch`.
+    pub fn banned(&self, batch: usize) -> Vec<u16> {
+        let k = self.n - 1;
+        let Some(stream) = self.streams.get(batch) else {
+            return vec![];
+        };
+        let len = stream.tokens.len();
+        if len < k {
+            return vec![];
+        }
+        let suffix = &stream.tokens[len - k..];
+        stream
+            .index
+            .get(&stream.hash)
+            .into_iter()
+            .flatten()
+            .filter(|&&position| &stream.tokens[position - k..position] == suffix)
+            .map(|&position| stream.tokens[position])
+            .unique()
+            .collect()
+    }
+}
+
+impl LogitsProcessor for NoRepeatNgram {
+    fn process(&mut self, logits: &mut [Option<Vec<f32>>]) -> Result<()> {
+        for (batch, logits) in logits.iter_mut().enumerate() {
+            let Some(logits) = logits else {
+                continue;
+            };
+            for token in self.banned(batch) {
+                if let Some(logit) = logits.get_mut(token as usize) {
+                    *logit = f32::NEG_INFINITY;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn extend(&mut self, batch: usize, tokens: &[u16]) -> Result<()> {
+        for &token in tokens {
+            self.push(batch, token);
+        }
+        Ok(())
+    }
+
+    fn update(&mut self, tokens: &[Option<u16>]) -> Result<()> {
+        for (batch, token) in tokens.iter().enumerate() {
+            if let Some(token) = *token {
+                self.push(batch, token);
+            }
+        }
+        Ok(())
+    }
+
+    fn reset(&mut self, batch: usize) -> Result<()> {
+        *self.stream(batch) = Default::default();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use anyhow::Result;
+    use wgpu::PowerPreference;
+
+    use super::{
+        DevicePenalty, LogitBias, LogitsProcessor, NoRepeatNgram, Penalty, ProcessorChain,
+    };
+    use crate::context::{Context, ContextBuilder, Instance};
+
+    fn create_context() -> Result<Context> {
+        let adapter = pollster::block_on(async {
+            let instance = Instance::new();
+            instance.adapter(PowerPreference::HighPerformance).await
+        })?;
+        let context = pollster::block_on(async { ContextBuilder::new(adapter).build().await })?;
+        Ok(context)
+    }
+
+    #[test]
+    fn test_processor_chain() -> Result<()> {
+        let context = match create_context() {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+
+        let penalty = Penalty {
+            repetition: 2.0,
+            presence: 0.5,
+            frequency: 0.25,
+        };
+        let mut chain = ProcessorChain::new()
+            .with(LogitBias(HashMap::from([(0, 1.0)])))
+            .with(DevicePenalty::new(
+                &context,
+                4,
+                vec![penalty, Default::default()],
+            )?);
+        chain.update(&[Some(0), Some(0)])?;
+        chain.update(&[Some(0), None])?;
+        chain.update(&[Some(3), None])?;
+
+        // the bias lifts token 0 before it is penalized; batch 1 has no penalty
+        let mut logits = vec![Some(vec![1.0, 1.0, 1.0, -1.0]), Some(vec![1.0; 4])];
+        chain.process(&mut logits)?;
+        assert_eq!(logits[0], Some(vec![0.0, 1.0, 1.0, -2.75]));
+        assert_eq!(logits[1], Some(vec![2.0, 1.0, 1.0, 1.0]));
+
+        // a new stream in batch 0 starts with no history
+        chain.reset(0)?;
+        let mut logits = vec![Some(vec![1.0; 4]), None];
+        chain.process(&mut logits)?;
+        assert_eq!(logits, vec![Some(vec![2.0, 1.0, 1.0, 1.0]), None]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_no_repeat_ngram() -> Result<()> {
+        let mut ngram = NoRepeatNgram::new(3);
+        ngram.extend(0, &[1, 2, 3, 4, 1, 2])?;
+        assert_eq!(ngram.banned(0), vec![3]);
+        ngram.update(&[Some(5), Some(1)])?;
+        assert!(ngram.banned(0).is_empty());
+
+        // the tokens following both earlier occurrences of `2 5` are banned
+        ngram.extend(0, &[2, 5, 6, 2])?;
+        ngram.update(&[Some(5), None])?;
+        let mut banned = ngram.banned(0);
+        banned.sort();
+        assert_eq!(banned, vec![2, 6]);
+
+        let mut logits = vec![Some(vec![0.0; 8]), Some(vec![0.0; 8])];
+        ngram.process(&mut logits)?;
+        let expected = [
+            0.0,
+            0.0,
+            f32::NEG_INFINITY,
+            0.0,
+            0.0,
+            0.0,
+            f32::NEG_INFINITY,
+            0.0,
+        ];
+        assert_eq!(logits[0].as_deref(), Some(&expected[..]));
+        assert_eq!(logits[1], Some(vec![0.0; 8]));
+
+        ngram.reset(0)?;
+        assert!(ngram.banned(0).is_empty());
+
+        // unigrams ban every token seen
+        let mut unigram = NoRepeatNgram::new(1);
+        unigram.extend(0, &[3, 1, 3])?;
+        let mut banned = unigram.banned(0);
+        banned.sort();
+        assert_eq!(banned, vec![1, 3]);
+        Ok(())
+    }
+}
@@ -0,0 +1,249 @@
+//! Download model files from the Hugging Face Hub into a local cache.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{Read, Write},
+    path::PathBuf,
+};
+
+use anyhow::Result;
+use memmap2::Mmap;
+
+pub const ENDPOINT: &str = "https://huggingface.co";
+pub const REVISION: &str = "main";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RepoError {
+    /// The server answered with a status other than success.
+    Status(u16),
+    /// The server closed the connection before sending the whole file.
+    Incomplete { downloaded: u64, total: u64 },
+}
+
+impl std::fmt::Display for RepoError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RepoError::Status(status) => write!(f, "server responded with status {status}"),
+            RepoError::Incomplete { downloaded, total } => {
+                write!(f, "download stopped at {downloaded} of {total} bytes")
+            }
+        }
+    }
+}
+
+impl std::error::Error for RepoError {}
+
+/// Progress of a download, reported after each chunk is written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Progress {
+    /// Bytes on disk so far, including those from a previous, interrupted download.
+    pub downloaded: u64,
+    /// Size of the file, if the server reported it.
+    pub total: Option<u64>,
+}
+
+/// Download `filename` from the model repo `repo` into the default cache and return its path.
+/// A file already in the cache is returned without touching the network.
+pub fn fetch(repo: &str, filename: &str) -> Result<PathBuf> {
+    Fetcher::new().fetch(repo, filename)
+}
+
+pub struct Fetcher {
+    endpoint: String,
+    revision: String,
+    cache_dir: PathBuf,
+    token: Option<String>,
+    progress: Option<Box<dyn FnMut(Progress)>>,
+}
+
+impl Default for Fetcher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Fetcher {
+    /// Files are cached under `$WEB_RWKV_CACHE` if set, otherwise `~/.cache/web-rwkv`.
+    /// The access token is taken from `$HF_TOKEN` if set.
+    pub fn new() -> Self {
+        let cache_dir = match std::env::var_os("WEB_RWKV_CACHE") {
+            Some(dir) => PathBuf::from(dir),
+            None => std::env::var_os("HOME")
+                .or_else(|| std::env::var_os("USERPROFILE"))
+                .map(PathBuf::from)
+                .unwrap_or_default()
+                .join(".cache")
+                .join("web-rwkv"),
+        };
+        Self {
+            endpoint: ENDPOINT.into(),
+            revision: REVISION.into(),
+            cache_dir,
+            token: std::env::var("HF_TOKEN").ok(),
+            progress: None,
+        }
+    }
+
+    pub fn with_endpoint(self, endpoint: impl Into<String>) -> Self {
+        Self {
+            endpoint: endpoint.into(),
+            ..self
+        }
+    }
+
+    /// A branch, tag or commit hash of the repo.
+    pub fn with_revision(self, revision: impl Into<String>) -> Self {
+        Self {
+            revision: revision.into(),
+            ..self
+        }
+    }
+
+    pub fn with_cache_dir(self, cache_dir: impl Into<PathBuf>) -> Self {
+        Self {
+            cache_dir: cache_dir.into(),
+            ..self
+        }
+    }
+
+    /// An access token for private or gated repos.
+    pub fn with_token(self, token: impl Into<String>) -> Self {
+        Self {
+            token: Some(token.into()),
+            ..self
+        }
+    }
+
+    pub fn with_progress(self, progress: impl FnMut(Progress) + 'static) -> Self {
+        Self {
+            progress: Some(Box::new(progress)),
+            ..self
+        }
+    }
+
+    /// Where `filename` of `repo` is cached, whether or not it has been downloaded.
+    pub fn cache_path(&self, repo: &str, filename: &str) -> PathBuf {
+        self.cache_dir
+            .join(repo.replace('/', "--"))
+            .join(&self.revision)
+            .join(filename)
+    }
+
+    /// Download `filename` from `repo` unless it is cached, and return its local path.
+    /// An interrupted download is kept next to the file as `.part` and resumed on the next call.
+    pub fn fetch(&mut self, repo: &str, filename: &str) -> Result<PathBuf> {
+        let path = self.cache_path(repo, filename);
+        if path.is_file() {
+            return Ok(path);
+        }
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        let part = path.with_extension(match path.extension() {
+            Some(ext) => format!("{}.part", ext.to_string_lossy()),
+            None => "part".into(),
+        });
+        let offset = fs::metadata(&part).map(|meta| meta.len()).unwrap_or(0);
+
+        let url = format!(
+            "{}/{repo}/resolve/{}/{filename}",
+            self.endpoint, self.revision
+        );
+        let mut request = ureq::get(&url);
+        if let Some(token) = &self.token {
+            request = request.set("Authorization", &format!("Bearer {token}"));
+        }
+        if offset > 0 {
+            request = request.set("Range", &format!("bytes={offset}-"));
+        }
+
+        let response = match request.call() {
+            Ok(response) => response,
+            // the part file already holds the whole file
+            Err(ureq::Error::Status(416, _)) if offset > 0 => {
+                fs::rename(&part, &path)?;
+                return Ok(path);
+            }
+            Err(ureq::Error::Status(status, _)) => return Err(RepoError::Status(status).into()),
+            Err(err) => return Err(err.into()),
+        };
+
+        // the server may ignore the range and send the whole file again
+        let resume = offset > 0 && response.status() == 206;
+        let mut downloaded = if resume { offset } else { 0 };
+        let total = response
+            .header("Content-Length")
+            .and_then(|len| len.parse::<u64>().ok())
+            .map(|len| downloaded + len);
+
+        let mut file = match resume {
+            true => OpenOptions::new().append(true).open(&part)?,
+            false => File::create(&part)?,
+        };
+        let mut reader = response.into_reader();
+        let mut buffer = vec![0; 1 << 20];
+        loop {
+            let len = reader.read(&mut buffer)?;
+            if len == 0 {
+                break;
+            }
+            file.write_all(&buffer[..len])?;
+            downloaded += len as u64;
+            if let Some(progress) = &mut self.progress {
+                progress(Progress { downloaded, total });
+            }
+        }
+        file.flush()?;
+        drop(file);
+
+        if let Some(total) = total {
+            if downloaded != total {
+                return Err(RepoError::Incomplete { downloaded, total }.into());
+            }
+        }
+        fs::rename(&part, &path)?;
+        Ok(path)
+    }
+
+    /// Fetch `filename` from `repo` and memory-map it, ready to be passed to the loader.
+    pub fn fetch_mmap(&mut self, repo: &str, filename: &str) -> Result<Mmap> {
+        let path = self.fetch(repo, filename)?;
+        let file = File::open(path)?;
+        // SAFETY: cached files are only ever written through a `.part` file that is renamed once complete.
+        let map = unsafe { Mmap::map(&file)? };
+        Ok(map)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use anyhow::Result;
+
+    use super::Fetcher;
+
+    #[test]
+    fn test_cache_hit() -> Result<()> {
+        let cache_dir = std::env::temp_dir().join("web-rwkv-test-cache");
+        let mut fetcher = Fetcher::new()
+            .with_cache_dir(&cache_dir)
+            .with_endpoint("http://localhost:0");
+
+        let path = fetcher.cache_path("BlinkDL/rwkv-5-world", "model.st");
+        assert_eq!(path, cache_dir.join("BlinkDL--rwkv-5-world/main/model.st"));
+
+        // a cached file is returned without a request, which would fail against this endpoint
+        fs::create_dir_all(path.parent().unwrap())?;
+        fs::write(&path, b"cached")?;
+        assert_eq!(fetcher.fetch("BlinkDL/rwkv-5-world", "model.st")?, path);
+        assert_eq!(
+            &fetcher.fetch_mmap("BlinkDL/rwkv-5-world", "model.st")?[..],
+            b"cached"
+        );
+
+        fs::remove_dir_all(cache_dir)?;
+        Ok(())
+    }
+}
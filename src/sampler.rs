@@ -0,0 +1,405 @@
+//! Picking the next token from the probabilities the model outputs.
+//!
+//! The batch functions here go over the batches on the rayon thread pool if the `rayon` feature is enabled,
+//! and one after another otherwise.
+
+use fastrand::Rng;
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+
+/// Truncates the distribution with top-a, tail-free and nucleus sampling, in that order,
+/// then samples from what is left with temperature.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Sampler {
+    /// Only sample from the most probable tokens whose cumulative probability reaches this.
+    /// `0.0` always picks the most probable token.
+    pub top_p: f32,
+    pub temperature: f32,
+    /// Drop tokens less probable than this times the square of the highest probability. `0.0` disables it.
+    pub top_a: f32,
+    /// Tail-free sampling: cut the tail where the curvature of the sorted probabilities,
+    /// accumulated from the head, exceeds this. `1.0` disables it.
+    pub tfs: f32,
+}
+
+impl Default for Sampler {
+    fn default() -> Self {
+        Self {
+            top_p: 0.5,
+            temperature: 1.0,
+            top_a: 0.0,
+            tfs: 1.0,
+        }
+    }
+}
+
+impl Sampler {
+    pub fn sample(&self, probs: &[f32], rng: &mut Rng) -> u16 {
+        if self.top_a <= 0.0 && self.tfs >= 1.0 {
+            return sample_top_p(probs, self.top_p, self.temperature, rng);
+        }
+
+        let mut sorted = probs
+            .iter()
+            .copied()
+            .enumerate()
+            .sorted_unstable_by(|(_, x), (_, y)| x.total_cmp(y).reverse())
+            .collect_vec();
+        self.truncate_top_a(&mut sorted);
+        self.truncate_tail_free(&mut sorted);
+
+        let sorted = sorted
+            .into_iter()
+            .scan(0.0, |cum, (id, x)| {
+                if *cum > self.top_p {
+                    None
+                } else {
+                    *cum += x;
+                    Some((id, x))
+                }
+            })
+            .map(|(id, x)| (id, x.powf(1.0 / self.temperature)))
+            .collect_vec();
+
+        let sum: f32 = sorted.iter().map(|(_, x)| x).sum();
+        let rand = rng.f32() * sum;
+        let token = sorted
+            .iter()
+            .scan(0.0, |cum, &(id, x)| {
+                *cum += x;
+                Some((id, *cum))
+            })
+            .find_or_first(|&(_, cum)| rand <= cum)
+            .map(|(id, _)| id)
+            .unwrap_or_default();
+        token as u16
+    }
+
+    /// Sample a token for each batch that has probabilities, with the random generator of that batch.
+    pub fn sample_batch(&self, probs: &[Option<Vec<f32>>], rngs: &mut [Rng]) -> Vec<Option<u16>> {
+        let jobs = probs.iter().zip_eq(rngs.iter_mut()).collect_vec();
+        par_map(jobs, |(probs, rng)| {
+            probs.as_ref().map(|probs| self.sample(probs, rng))
+        })
+    }
+
+    fn truncate_top_a(&self, sorted: &mut Vec<(usize, f32)>) {
+        let Some(&(_, max)) = sorted.first() else {
+            return;
+        };
+        if self.top_a <= 0.0 {
+            return;
+        }
+        let limit = self.top_a * max * max;
+        let len = sorted.iter().take_while(|(_, x)| *x >= limit).count();
+        sorted.truncate(len.max(1));
+    }
+
+    /// Follows the reference implementation: weigh each token by the absolute second difference of the
+    /// sorted probabilities, normalized, and keep the tokens before the cumulative weight exceeds `tfs`.
+    fn truncate_tail_free(&self, sorted: &mut Vec<(usize, f32)>) {
+        if self.tfs >= 1.0 || sorted.len() <= 2 {
+            return;
+        }
+        let first = sorted
+            .windows(2)
+            .map(|window| window[0].1 - window[1].1)
+            .collect_vec();
+        let second = first
+            .windows(2)
+            .map(|window| (window[0] - window[1]).abs())
+            .collect_vec();
+        let sum: f32 = second.iter().sum();
+
+        let mut cum = 0.0;
+        for (index, x) in second.iter().enumerate() {
+            cum += match sum > 1.0e-6 {
+                true => x / sum,
+                false => 1.0 / second.len() as f32,
+            };
+            if cum > self.tfs && index >= 1 {
+                sorted.truncate(index);
+                return;
+            }
+        }
+    }
+}
+
+/// Softmax of `logits` on the CPU, e.g. of logits already read back and processed on the host.
+pub fn softmax(logits: &[f32]) -> Vec<f32> {
+    let max = max(logits);
+    let mut output = logits.iter().map(|x| exp(x - max)).collect_vec();
+    let scale = 1.0 / sum(&output);
+    output.iter_mut().for_each(|x| *x *= scale);
+    output
+}
+
+/// Index of the most probable token, the first one on ties.
+pub fn argmax(probs: &[f32]) -> u16 {
+    let max = max(probs);
+    probs.iter().position(|&x| x == max).unwrap_or_default() as u16
+}
+
+/// Nucleus sampling without sorting the probabilities: bisect for the lowest probability
+/// whose tokens and those above it hold more than `top_p` of the mass, then sample among them with `temperature`.
+///
+/// It keeps the same tokens as [`Sampler::sample`] without top-a and tail-free sampling,
+/// but goes over them in the order of the vocabulary, so the same random number may pick another of them.
+pub fn sample_top_p(probs: &[f32], top_p: f32, temperature: f32, rng: &mut Rng) -> u16 {
+    let max = max(probs);
+    let threshold = match sum_above(probs, max) > top_p {
+        true => max,
+        false => {
+            let (mut low, mut high) = (0.0, max);
+            for _ in 0..32 {
+                let mid = 0.5 * (low + high);
+                match sum_above(probs, mid) > top_p {
+                    true => low = mid,
+                    false => high = mid,
+                }
+            }
+            low
+        }
+    };
+
+    let nucleus = probs
+        .iter()
+        .enumerate()
+        .filter(|(_, &x)| x >= threshold)
+        .map(|(id, x)| (id, x.powf(1.0 / temperature)))
+        .collect_vec();
+    let sum: f32 = nucleus.iter().map(|(_, x)| x).sum();
+    let rand = rng.f32() * sum;
+    let token = nucleus
+        .iter()
+        .scan(0.0, |cum, &(id, x)| {
+            *cum += x;
+            Some((id, *cum))
+        })
+        .find_or_first(|&(_, cum)| rand <= cum)
+        .map(|(id, _)| id)
+        .unwrap_or_default();
+    token as u16
+}
+
+/// Width of the chunks the loops below go over. Each lane accumulates on its own and they are combined at the end,
+/// which lets the compiler turn the loops into vector instructions without `std::simd`.
+const LANES: usize = 8;
+
+fn max(x: &[f32]) -> f32 {
+    let chunks = x.chunks_exact(LANES);
+    let rest = chunks.remainder();
+    let mut lanes = [f32::NEG_INFINITY; LANES];
+    for chunk in chunks {
+        for (lane, &x) in lanes.iter_mut().zip(chunk) {
+            *lane = if x > *lane { x } else { *lane };
+        }
+    }
+    lanes
+        .iter()
+        .chain(rest)
+        .fold(f32::NEG_INFINITY, |max, &x| max.max(x))
+}
+
+fn sum(x: &[f32]) -> f32 {
+    sum_above(x, f32::NEG_INFINITY)
+}
+
+/// Sum of the elements of `x` no less than `threshold`.
+fn sum_above(x: &[f32], threshold: f32) -> f32 {
+    let chunks = x.chunks_exact(LANES);
+    let rest = chunks.remainder();
+    let mut lanes = [0.0; LANES];
+    for chunk in chunks {
+        for (lane, &x) in lanes.iter_mut().zip(chunk) {
+            *lane += if x >= threshold { x } else { 0.0 };
+        }
+    }
+    lanes
+        .iter()
+        .chain(rest.iter().filter(|&&x| x >= threshold))
+        .sum()
+}
+
+/// `e^x` for `x <= 0`, without branches or calls so that it vectorizes.
+/// The relative error is below `1e-7` near 0 and grows to about `1e-5` at the bottom of the range.
+/// Results below the smallest normal `f32` are flushed to 0.
+#[inline]
+fn exp(x: f32) -> f32 {
+    use std::f32::consts::{LN_2, LOG2_E};
+
+    // e^x = 2^n * 2^f, with n the nearest integer to x * log2(e) and f in [-0.5, 0.5]
+    let y = x * LOG2_E;
+    let clamped = y.max(-126.0);
+    let n = (clamped - 0.5) as i32;
+    let f = clamped - n as f32;
+    let p = 1.0
+        + f * (LN_2
+            + f * (0.240_226_5
+                + f * (0.055_504_11
+                    + f * (0.009_618_13 + f * (0.001_333_36 + f * 0.000_154_035)))));
+    let scale = f32::from_bits(((n + 127) as u32) << 23);
+    if y < -126.0 {
+        0.0
+    } else {
+        p * scale
+    }
+}
+
+/// [`softmax`] of each batch that has logits.
+pub fn softmax_batch(logits: Vec<Option<Vec<f32>>>) -> Vec<Option<Vec<f32>>> {
+    par_map(logits, |logits| logits.map(|logits| softmax(&logits)))
+}
+
+/// Map `f` over `items`, in parallel if the `rayon` feature is enabled.
+pub(crate) fn par_map<T, U, F>(items: Vec<T>, f: F) -> Vec<U>
+where
+    T: Send,
+    U: Send,
+    F: Fn(T) -> U + Send + Sync,
+{
+    #[cfg(feature = "rayon")]
+    {
+        use rayon::prelude::*;
+        items.into_par_iter().map(f).collect()
+    }
+    #[cfg(not(feature = "rayon"))]
+    {
+        items.into_iter().map(f).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use fastrand::Rng;
+    use itertools::Itertools;
+
+    use super::{argmax, exp, sample_top_p, softmax, softmax_batch, Sampler};
+
+    #[test]
+    fn test_sample() {
+        let probs = [0.1, 0.6, 0.3];
+        let mut rng = Rng::with_seed(42);
+
+        let greedy = Sampler {
+            top_p: 0.0,
+            ..Default::default()
+        };
+        assert!((0..100).all(|_| greedy.sample(&probs, &mut rng) == 1));
+
+        // the nucleus of 0.8 leaves out the least probable token
+        let sampler = Sampler {
+            top_p: 0.8,
+            ..Default::default()
+        };
+        let counts = (0..1000).fold([0; 3], |mut counts, _| {
+            counts[sampler.sample(&probs, &mut rng) as usize] += 1;
+            counts
+        });
+        assert_eq!(counts[0], 0);
+        assert!(counts[1] > counts[2] && counts[2] > 0);
+    }
+
+    #[test]
+    fn test_truncate() {
+        let sorted = vec![(0, 0.5), (1, 0.3), (2, 0.1), (3, 0.06), (4, 0.04)];
+
+        // top-a of 0.5 drops tokens below 0.5 * 0.5 * 0.5 = 0.125
+        let sampler = Sampler {
+            top_a: 0.5,
+            ..Default::default()
+        };
+        let mut truncated = sorted.clone();
+        sampler.truncate_top_a(&mut truncated);
+        assert_eq!(truncated, sorted[..2]);
+
+        // second differences are 0.0, 0.16 and 0.02, cumulating to 0.0, 0.89 and 1.0 normalized
+        let sampler = Sampler {
+            tfs: 0.95,
+            ..Default::default()
+        };
+        let mut truncated = sorted.clone();
+        sampler.truncate_tail_free(&mut truncated);
+        assert_eq!(truncated, sorted[..2]);
+
+        let mut truncated = sorted.clone();
+        Sampler::default().truncate_tail_free(&mut truncated);
+        assert_eq!(truncated, sorted);
+    }
+
+    #[test]
+    fn test_batch() {
+        let logits = vec![Some(vec![1.0, 3.0, 2.0]), None, Some(vec![0.0, 0.0, 5.0])];
+        let probs = softmax_batch(logits.clone());
+        for (probs, logits) in probs.iter().zip(&logits) {
+            assert_eq!(probs, &logits.as_deref().map(softmax));
+        }
+        let sum: f32 = probs[0].iter().flatten().sum();
+        assert!((sum - 1.0).abs() < 1.0e-6);
+
+        // each batch draws from its own generator, as if sampled one by one
+        let sampler = Sampler::default();
+        let mut rngs = (0..3).map(Rng::with_seed).collect::<Vec<_>>();
+        let tokens = sampler.sample_batch(&probs, &mut rngs);
+        let mut rngs = (0..3).map(Rng::with_seed).collect::<Vec<_>>();
+        let expected = probs
+            .iter()
+            .zip(rngs.iter_mut())
+            .map(|(probs, rng)| probs.as_ref().map(|probs| sampler.sample(probs, rng)))
+            .collect::<Vec<_>>();
+        assert_eq!(tokens, expected);
+        assert_eq!(tokens[1], None);
+    }
+
+    #[test]
+    fn test_kernels() {
+        // longer than a few chunks, with a remainder
+        let logits = (0..1001)
+            .map(|x| ((x * 37) % 101) as f32 / 10.0 - 5.0)
+            .collect::<Vec<_>>();
+        let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+        let exp_sum: f32 = logits.iter().map(|x| (x - max).exp()).sum();
+        for (x, y) in softmax(&logits).iter().zip(&logits) {
+            let expected = (y - max).exp() / exp_sum;
+            assert!(
+                (x - expected).abs() <= 1.0e-6 * expected,
+                "{x} != {expected}"
+            );
+        }
+
+        for x in [0.0, -1.0e-3, -0.5, -1.0, -7.3, -40.0, -87.0] {
+            let expected = f32::exp(x);
+            assert!((exp(x) - expected).abs() <= 1.0e-5 * expected, "{x}");
+        }
+        assert_eq!(exp(-100.0), 0.0);
+        assert_eq!(exp(f32::NEG_INFINITY), 0.0);
+
+        let probs = softmax(&logits);
+        let expected = logits.iter().position(|&x| x == max).unwrap_or_default();
+        assert_eq!(argmax(&probs) as usize, expected);
+    }
+
+    #[test]
+    fn test_top_p() {
+        let probs = [0.05, 0.3, 0.05, 0.4, 0.2];
+        let mut rng = Rng::with_seed(42);
+
+        // the nucleus is the first tokens, most probable first, to go over `top_p`
+        for (top_p, nucleus) in [(0.0, vec![3]), (0.5, vec![1, 3]), (0.8, vec![1, 3, 4])] {
+            let mut sampled = (0..1000)
+                .map(|_| sample_top_p(&probs, top_p, 1.0, &mut rng))
+                .collect::<Vec<_>>();
+            sampled.sort_unstable();
+            sampled.dedup();
+            assert_eq!(sampled, nucleus);
+        }
+
+        // a nucleus of the whole mass leaves no token out
+        let sampled = (0..1000)
+            .map(|_| sample_top_p(&probs, 1.0, 1.0, &mut rng))
+            .unique()
+            .count();
+        assert_eq!(sampled, probs.len());
+    }
+}
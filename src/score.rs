@@ -0,0 +1,214 @@
+//! Scoring continuations of a prompt against the logits captured while prefilling it.
+
+use anyhow::Result;
+use itertools::Itertools;
+use wgpu::{CommandEncoderDescriptor, ComputePassDescriptor};
+
+use crate::{
+    model::{Model, ModelError, ModelState},
+    tensor::{
+        ops::{TensorCommand, TensorOp, TensorPass},
+        shape::Shape,
+        ReadBack, ReadWrite, TensorCpu, TensorGpu,
+    },
+};
+
+type BackedState<M> = <<M as Model>::ModelState as ModelState>::BackedState;
+
+/// A prompt run on one batch of a state, with the logits after each of its tokens kept on the GPU.
+///
+/// Continuations are scored by running only them from a host copy of the batch right after the prompt,
+/// so candidates sharing the prompt (e.g. for best-of-n reranking) don't recompute it.
+/// Scoring leaves the batch as it was right after the prompt.
+pub struct Prefill<'a, M: Model> {
+    model: &'a M,
+    state: &'a M::ModelState,
+    batch: usize,
+    prompt: Vec<u16>,
+    /// Logits after each token of the prompt, of shape `[num_vocab, prompt.len(), 1]`.
+    logits: TensorGpu<f32, ReadWrite>,
+    backed: BackedState<M>,
+}
+
+impl<'a, M: Model> Prefill<'a, M> {
+    /// Run `prompt` on `batch` of `state`, capturing its logits. The prompt must not be empty.
+    pub fn new(
+        model: &'a M,
+        state: &'a M::ModelState,
+        batch: usize,
+        prompt: &[u16],
+    ) -> Result<Self> {
+        let max = state.max_batch();
+        if batch >= max {
+            return Err(ModelError::BatchOutOfRange { batch, max }.into());
+        }
+        if prompt.is_empty() {
+            return Err(ModelError::EmptyTokens.into());
+        }
+
+        let shape = Shape::new(model.info().num_vocab, prompt.len(), 1, 1);
+        let logits = model.context().tensor_init(shape);
+        model.prefill(prompt, batch, state, &logits, 0)?;
+        let backed = state.back_batch(batch)?;
+
+        Ok(Self {
+            model,
+            state,
+            batch,
+            prompt: prompt.to_vec(),
+            logits,
+            backed,
+        })
+    }
+
+    #[inline]
+    pub fn prompt(&self) -> &[u16] {
+        &self.prompt
+    }
+
+    /// Captured logits after each token of the prompt, of shape `[num_vocab, T, 1]`.
+    #[inline]
+    pub fn logits(&self) -> &TensorGpu<f32, ReadWrite> {
+        &self.logits
+    }
+
+    /// Log-likelihood of each token of the prompt after the first, from the captured logits alone.
+    pub fn prompt_scores(&self) -> Result<Vec<f32>> {
+        // the logits after the last token predict nothing in the prompt, so a dummy token is scored there
+        let tokens = self.prompt[1..].iter().copied().chain([0]).collect_vec();
+        let mut scores = self.score_logits(&self.logits, &tokens)?;
+        scores.pop();
+        Ok(scores)
+    }
+
+    /// Total log-likelihood of each continuation after the prompt. An empty continuation scores 0.
+    pub fn score(&self, continuations: &[Vec<u16>]) -> Result<Vec<f32>> {
+        let context = self.model.context();
+        let num_vocab = self.model.info().num_vocab;
+        let last = self.prompt.len() - 1;
+
+        let mut scores = Vec::with_capacity(continuations.len());
+        for continuation in continuations {
+            if continuation.is_empty() {
+                scores.push(0.0);
+                continue;
+            }
+
+            // the first token is scored by the last logits of the prompt, the rest by running the continuation
+            let logits: TensorGpu<f32, ReadWrite> =
+                context.tensor_init(Shape::new(num_vocab, continuation.len(), 1, 1));
+            let op = TensorOp::blit(
+                self.logits.view(.., last..=last, .., ..)?,
+                logits.view(.., 0..1, .., ..)?,
+            )?;
+            let mut encoder = context
+                .device
+                .create_command_encoder(&CommandEncoderDescriptor::default());
+            let mut pass = encoder.begin_compute_pass(&ComputePassDescriptor::default());
+            pass.execute_tensor_op(&op);
+            drop(pass);
+            context.queue.submit(Some(encoder.finish()));
+
+            let (_, tokens) = continuation
+                .split_last()
+                .expect("continuation is not empty");
+            self.state.load_batch(&self.backed, self.batch)?;
+            self.model
+                .prefill(tokens, self.batch, self.state, &logits, 1)?;
+
+            let score = self.score_logits(&logits, continuation)?.into_iter().sum();
+            scores.push(score);
+        }
+
+        self.state.load_batch(&self.backed, self.batch)?;
+        Ok(scores)
+    }
+
+    /// Log-likelihood of each of `tokens` under `logits` at the same position, computed on the GPU.
+    fn score_logits(&self, logits: &TensorGpu<f32, ReadWrite>, tokens: &[u16]) -> Result<Vec<f32>> {
+        let context = self.model.context();
+        let shape = Shape::new(tokens.len(), 1, 1, 1);
+
+        let tokens = tokens.iter().map(|&token| token as u32).collect_vec();
+        let tokens: TensorGpu<u32, ReadWrite> = context.tensor_from_data(shape, tokens)?;
+        let output: TensorGpu<f32, ReadWrite> = context.tensor_init(shape);
+        let map: TensorGpu<f32, ReadBack> = context.tensor_init(shape);
+        let op = TensorOp::score(logits, &tokens, &output)?;
+
+        let mut encoder = context
+            .device
+            .create_command_encoder(&CommandEncoderDescriptor::default());
+        let mut pass = encoder.begin_compute_pass(&ComputePassDescriptor::default());
+        pass.execute_tensor_op(&op);
+        drop(pass);
+
+        encoder.copy_tensor(&output, &map)?;
+        context.queue.submit(Some(encoder.finish()));
+
+        Ok(Vec::from(TensorCpu::from(map)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use super::Prefill;
+    use crate::model::{
+        synthetic::{testing::create_context, SyntheticBuilder},
+        v5, Model, ModelBuilder, ModelVersion, StateBuilder,
+    };
+
+    fn log_softmax(logits: &[f32], token: u16) -> f32 {
+        let max = logits.iter().fold(f32::MIN, |a, &b| a.max(b));
+        let sum: f32 = logits.iter().map(|&x| (x - max).exp()).sum();
+        logits[token as usize] - max - sum.ln()
+    }
+
+    #[test]
+    fn test_prefill_score() -> Result<()> {
+        let context = match create_context() {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+
+        let builder = SyntheticBuilder::new(ModelVersion::V5).with_num_layer(2);
+        let info = builder.info();
+        let data = builder.build()?;
+        let model: v5::Model = ModelBuilder::new(&context, &data)
+            .with_head_chunk_size(info.num_vocab)
+            .with_token_chunk_size(4)
+            .build()?;
+
+        let prompt = [12u16, 55, 8, 91, 200, 3];
+        let continuations = vec![vec![7u16, 40, 2], vec![], vec![99]];
+
+        // reference: score every sequence from scratch with plain runs
+        let mut expected = vec![];
+        for continuation in &continuations {
+            let state: v5::ModelState = StateBuilder::new(&context, &info).build();
+            let mut score = 0.0;
+            let sequence = [&prompt[..], continuation].concat();
+            for (index, &token) in sequence.iter().enumerate().skip(1) {
+                let mut tokens = vec![vec![sequence[index - 1]]];
+                let logits = model.run(&mut tokens, &state)?[0].take().unwrap();
+                if index >= prompt.len() {
+                    score += log_softmax(&logits, token);
+                }
+            }
+            expected.push(score);
+        }
+
+        let state: v5::ModelState = StateBuilder::new(&context, &info).with_max_batch(2).build();
+        let prefill = Prefill::new(&model, &state, 1, &prompt)?;
+        let scores = prefill.score(&continuations)?;
+        for (a, b) in scores.into_iter().zip(expected) {
+            assert!((a - b).abs() < 1.0e-2, "{a} != {b}");
+        }
+
+        let prompt_scores = prefill.prompt_scores()?;
+        assert_eq!(prompt_scores.len(), prompt.len() - 1);
+        assert!(prompt_scores.iter().all(|score| *score <= 0.0));
+        Ok(())
+    }
+}
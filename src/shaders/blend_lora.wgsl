@@ -1,7 +1,8 @@
 struct View {
     stride: vec4<u32>,
     offset: vec4<u32>,
-    shape: vec4<u32>,  
+    shape: vec4<u32>,
+    step: vec4<u32>,
 };
 
 struct Input {
@@ -18,7 +19,11 @@ struct Input {
 
 @group(0) @binding(4) var<storage, read> xa: array<vec2<u32>>;              // (B, M, K)
 @group(0) @binding(5) var<storage, read> xb: array<vec2<u32>>;              // (B, N, K)
+#ifdef ACT_F16
 @group(0) @binding(6) var<storage, read_write> output: array<vec2<u32>>;    // (B, N, M)
+#else
+@group(0) @binding(6) var<storage, read_write> output: array<vec4<f32>>;    // (B, N, M)
+#endif
 
 var<workgroup> sa: array<array<vec2<u32>, 32u>, 32u>;
 var<workgroup> sb: array<array<vec2<u32>, 32u>, 32u>;
@@ -26,7 +31,7 @@ var<workgroup> sb: array<array<vec2<u32>, 32u>, 32u>;
 fn compute_index(view: View, z: u32, y: u32, x: u32) -> u32 {
     let stride = view.stride.x / 4u;
     let offset = view.offset.x / 4u;
-    return ((view.offset.z + z) * view.stride.y + view.offset.y + y) * stride + offset + x;
+    return ((view.offset.z + z * view.step.z) * view.stride.y + view.offset.y + y * view.step.y) * stride + offset + x;
 }
 
 fn unpack4x16float(x: vec2<u32>) -> vec4<f32> {
@@ -37,9 +42,25 @@ fn pack4x16float(x: vec4<f32>) -> vec2<u32> {
     return vec2<u32>(pack2x16float(x.xy), pack2x16float(x.zw));
 }
 
+fn load_output(index: u32) -> vec4<f32> {
+#ifdef ACT_F16
+    return unpack4x16float(output[index]);
+#else
+    return output[index];
+#endif
+}
+
+fn store_output(index: u32, value: vec4<f32>) {
+#ifdef ACT_F16
+    output[index] = pack4x16float(value);
+#else
+    output[index] = value;
+#endif
+}
+
 fn blend(v: vec4<f32>, z: u32, y: u32, x: u32) {
-    let u = unpack4x16float(output[compute_index(destination, z, y, x)]);
-    output[compute_index(destination, z, y, x)] = pack4x16float(factor.x * v + factor.y * u);
+    let index = compute_index(destination, z, y, x);
+    store_output(index, factor.x * v + factor.y * load_output(index));
 }
 
 @compute @workgroup_size(8, 8, 1)
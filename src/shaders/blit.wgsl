@@ -1,21 +1,51 @@
 struct View {
     stride: vec4<u32>,
     offset: vec4<u32>,
-    shape: vec4<u32>,  
+    shape: vec4<u32>,
+    step: vec4<u32>,
 };
 
 @group(0) @binding(0) var<uniform> source: View;
 @group(0) @binding(1) var<uniform> destination: View;
 
+#ifdef ACT_F16
+@group(0) @binding(2) var<storage, read> input: array<vec2<u32>>;           // (B, T, C)
+@group(0) @binding(3) var<storage, read_write> output: array<vec2<u32>>;    // (K, N, M)
+#else
 @group(0) @binding(2) var<storage, read> input: array<vec4<f32>>;           // (B, T, C)
 @group(0) @binding(3) var<storage, read_write> output: array<vec4<f32>>;    // (K, N, M)
+#endif
 
 const BLOCK_SIZE: u32 = 128u;
 
+fn pack4x16float(x: vec4<f32>) -> vec2<u32> {
+    return vec2<u32>(pack2x16float(x.xy), pack2x16float(x.zw));
+}
+
+fn unpack4x16float(x: vec2<u32>) -> vec4<f32> {
+    return vec4<f32>(unpack2x16float(x.x), unpack2x16float(x.y));
+}
+
+fn load_input(index: u32) -> vec4<f32> {
+#ifdef ACT_F16
+    return unpack4x16float(input[index]);
+#else
+    return input[index];
+#endif
+}
+
+fn store_output(index: u32, value: vec4<f32>) {
+#ifdef ACT_F16
+    output[index] = pack4x16float(value);
+#else
+    output[index] = value;
+#endif
+}
+
 fn compute_index(view: View, batch: u32, token: u32, index: u32) -> u32 {
     let stride = view.stride.x / 4u;
     let offset = view.offset.x / 4u;
-    return ((view.offset.z + batch) * view.stride.y + view.offset.y + token) * stride + offset + index;
+    return ((view.offset.z + batch * view.step.z) * view.stride.y + view.offset.y + token * view.step.y) * stride + offset + index;
 }
 
 @compute @workgroup_size(128, 1, 1)
@@ -26,6 +56,6 @@ fn blit(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
     let batch = invocation_id.z;
 
     if index < stride {
-        output[compute_index(destination, batch, token, index)] = input[compute_index(source, batch, token, index)];
+        store_output(compute_index(destination, batch, token, index), load_input(compute_index(source, batch, token, index)));
     }
 }
\ No newline at end of file
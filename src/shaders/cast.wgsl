@@ -0,0 +1,57 @@
+struct View {
+    stride: vec4<u32>,
+    offset: vec4<u32>,
+    shape: vec4<u32>,
+    step: vec4<u32>,
+};
+
+@group(0) @binding(0) var<uniform> source: View;
+@group(0) @binding(1) var<uniform> destination: View;
+
+// raw words, so that both precisions share the same bindings
+@group(0) @binding(2) var<storage, read> input: array<u32>;                 // (B, T, C)
+@group(0) @binding(3) var<storage, read_write> output: array<u32>;          // (B, T, C)
+
+const BLOCK_SIZE: u32 = 128u;
+
+fn compute_index(view: View, batch: u32, token: u32, index: u32) -> u32 {
+    let stride = view.stride.x / 4u;
+    let offset = view.offset.x / 4u;
+    return ((view.offset.z + batch * view.step.z) * view.stride.y + view.offset.y + token * view.step.y) * stride + offset + index;
+}
+
+@compute @workgroup_size(128, 1, 1)
+fn cast_f16(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+    let stride = destination.shape.x / 4u;
+    let index = invocation_id.x;
+    let token = invocation_id.y;
+    let batch = invocation_id.z;
+
+    if index < stride {
+        // 4 `f32` words in, 2 packed `f16` words out
+        let bti = compute_index(source, batch, token, index) * 4u;
+        let x = bitcast<vec4<f32>>(vec4<u32>(input[bti], input[bti + 1u], input[bti + 2u], input[bti + 3u]));
+        let btc = compute_index(destination, batch, token, index) * 2u;
+        output[btc] = pack2x16float(x.xy);
+        output[btc + 1u] = pack2x16float(x.zw);
+    }
+}
+
+@compute @workgroup_size(128, 1, 1)
+fn cast_f32(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+    let stride = destination.shape.x / 4u;
+    let index = invocation_id.x;
+    let token = invocation_id.y;
+    let batch = invocation_id.z;
+
+    if index < stride {
+        // 2 packed `f16` words in, 4 `f32` words out
+        let bti = compute_index(source, batch, token, index) * 2u;
+        let x = bitcast<vec4<u32>>(vec4<f32>(unpack2x16float(input[bti]), unpack2x16float(input[bti + 1u])));
+        let btc = compute_index(destination, batch, token, index) * 4u;
+        output[btc] = x.x;
+        output[btc + 1u] = x.y;
+        output[btc + 2u] = x.z;
+        output[btc + 3u] = x.w;
+    }
+}
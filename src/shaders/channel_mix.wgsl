@@ -1,7 +1,8 @@
 struct View {
     stride: vec4<u32>,
     offset: vec4<u32>,
-    shape: vec4<u32>,  
+    shape: vec4<u32>,
+    step: vec4<u32>,
 };
 
 struct Cursor {
@@ -14,18 +15,79 @@ struct Cursor {
 @group(0) @binding(1) var<uniform> view: View;                              // [C, 1, B] / [C, 5L, B]
 @group(0) @binding(2) var<storage, read> cursors: array<u32>;               // [A]
 
+#ifdef ACT_F16
+@group(0) @binding(3) var<storage, read> r: array<vec2<u32>>;               // (1, A, C)
+@group(0) @binding(4) var<storage, read> v: array<vec2<u32>>;               // (1, A, C)
+#else
 @group(0) @binding(3) var<storage, read> r: array<vec4<f32>>;               // (1, A, C)
 @group(0) @binding(4) var<storage, read> v: array<vec4<f32>>;               // (1, A, C)
+#endif
 
+#ifdef ACT_F16
+@group(0) @binding(5) var<storage, read_write> x: array<vec2<u32>>;         // (1, A, C)
+#else
 @group(0) @binding(5) var<storage, read_write> x: array<vec4<f32>>;         // (1, A, C)
+#endif
+#ifdef STATE_F16
+@group(0) @binding(6) var<storage, read_write> state: array<vec2<u32>>;     // (B, C)
+#else
 @group(0) @binding(6) var<storage, read_write> state: array<vec4<f32>>;     // (B, C)
+#endif
 
 const BLOCK_SIZE: u32 = 128u;
 
+fn pack4x16float(x: vec4<f32>) -> vec2<u32> {
+    return vec2<u32>(pack2x16float(x.xy), pack2x16float(x.zw));
+}
+
+fn unpack4x16float(x: vec2<u32>) -> vec4<f32> {
+    return vec4<f32>(unpack2x16float(x.x), unpack2x16float(x.y));
+}
+
+fn load_r(index: u32) -> vec4<f32> {
+#ifdef ACT_F16
+    return unpack4x16float(r[index]);
+#else
+    return r[index];
+#endif
+}
+
+fn load_v(index: u32) -> vec4<f32> {
+#ifdef ACT_F16
+    return unpack4x16float(v[index]);
+#else
+    return v[index];
+#endif
+}
+
+fn load_x(index: u32) -> vec4<f32> {
+#ifdef ACT_F16
+    return unpack4x16float(x[index]);
+#else
+    return x[index];
+#endif
+}
+
+fn store_x(index: u32, value: vec4<f32>) {
+#ifdef ACT_F16
+    x[index] = pack4x16float(value);
+#else
+    x[index] = value;
+#endif
+}
+
 fn compute_index(batch: u32, token: u32, index: u32) -> u32 {
     let stride = view.stride.x / 4u;
     let offset = view.offset.x / 4u;
-    return ((view.offset.z + batch) * view.stride.y + view.offset.y + token) * stride + offset + index;
+    return ((view.offset.z + batch * view.step.z) * view.stride.y + view.offset.y + token * view.step.y) * stride + offset + index;
+}
+
+fn store_state(index: u32, value: vec4<f32>) {
+#ifdef STATE_F16
+    state[index] = pack4x16float(value);
+#else
+    state[index] = value;
+#endif
 }
 
 fn compute_cursor(x: u32) -> Cursor {
@@ -45,12 +107,16 @@ fn channel_mix(@builtin(global_invocation_id) invocation_id: vec3<u32>, @builtin
     let cursor = compute_cursor(cursors[stack]);
     let token = stack - cursor.token;
 
+    if index >= stride {
+        return;
+    }
+
     let bti = stack * stride + index;
 
     if token + 1u == cursor.len {
-        state[compute_index(cursor.batch, 0u, index)] = x[bti];
+        store_state(compute_index(cursor.batch, 0u, index), load_x(bti));
     }
 
-    let rr = 1.0 / (1.0 + exp(-r[bti]));
-    x[bti] = rr * v[bti];
+    let rr = 1.0 / (1.0 + exp(-load_r(bti)));
+    store_x(bti, rr * load_v(bti));
 }
\ No newline at end of file
@@ -0,0 +1,50 @@
+@group(0) @binding(0) var<uniform> shape: vec4<u32>;                        // [C, N, 1]
+
+@group(0) @binding(1) var<storage, read> query: array<vec4<f32>>;           // (B, 1, C)
+@group(0) @binding(2) var<storage, read> matrix: array<vec4<f32>>;          // (1, N, C)
+@group(0) @binding(3) var<storage, read_write> output: array<f32>;          // (B, N)
+
+const BLOCK_SIZE: u32 = 128u;
+const EPSILON: f32 = 1.0e-12;
+
+// dot product, squared norm of the query and of the row, in that order
+var<workgroup> sketch: array<vec4<f32>, BLOCK_SIZE>;
+
+fn reduce_sum(index: u32, stride: u32) {
+    if index < stride {
+        sketch[index] += sketch[index + stride];
+    }
+    workgroupBarrier();
+}
+
+@compute @workgroup_size(128, 1, 1)
+fn cosine(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+    let stride = shape[0] / 4u;
+    let index = invocation_id.x;
+    let row = invocation_id.y;
+    let batch = invocation_id.z;
+
+    let bq = batch * stride;
+    let bm = row * stride;
+
+    sketch[index] = vec4<f32>(0.0);
+    for (var i = index; i < stride; i += BLOCK_SIZE) {
+        let q = query[bq + i];
+        let k = matrix[bm + i];
+        sketch[index] += vec4<f32>(dot(q, k), dot(q, q), dot(k, k), 0.0);
+    }
+    workgroupBarrier();
+
+    reduce_sum(index, 64u);
+    reduce_sum(index, 32u);
+    reduce_sum(index, 16u);
+    reduce_sum(index, 8u);
+    reduce_sum(index, 4u);
+    reduce_sum(index, 2u);
+    reduce_sum(index, 1u);
+
+    if index == 0u {
+        let sum = sketch[0];
+        output[batch * shape[1] + row] = sum.x / max(sqrt(sum.y * sum.z), EPSILON);
+    }
+}
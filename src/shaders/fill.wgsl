@@ -0,0 +1,31 @@
+struct View {
+    stride: vec4<u32>,
+    offset: vec4<u32>,
+    shape: vec4<u32>,
+    step: vec4<u32>,
+};
+
+@group(0) @binding(0) var<uniform> destination: View;
+@group(0) @binding(1) var<uniform> value: vec4<f32>;
+
+@group(0) @binding(2) var<storage, read_write> output: array<vec4<f32>>;    // (B, T, C)
+
+const BLOCK_SIZE: u32 = 128u;
+
+fn compute_index(view: View, batch: u32, token: u32, index: u32) -> u32 {
+    let stride = view.stride.x / 4u;
+    let offset = view.offset.x / 4u;
+    return ((view.offset.z + batch * view.step.z) * view.stride.y + view.offset.y + token * view.step.y) * stride + offset + index;
+}
+
+@compute @workgroup_size(128, 1, 1)
+fn fill(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+    let stride = destination.shape.x / 4u;
+    let index = invocation_id.x;
+    let token = invocation_id.y;
+    let batch = invocation_id.z;
+
+    if index < stride {
+        output[compute_index(destination, batch, token, index)] = value;
+    }
+}
@@ -2,7 +2,11 @@
 
 @group(0) @binding(1) var<storage, read> w: array<vec2<u32>>;               // (C)
 @group(0) @binding(2) var<storage, read> b: array<vec2<u32>>;               // (C)
+#ifdef ACT_F16
+@group(0) @binding(3) var<storage, read_write> x: array<vec2<u32>>;         // (B, T, C)
+#else
 @group(0) @binding(3) var<storage, read_write> x: array<vec4<f32>>;         // (B, T, C)
+#endif
 
 const BLOCK_SIZE: u32 = 128u;
 
@@ -15,6 +19,26 @@ fn unpack4x16float(x: vec2<u32>) -> vec4<f32> {
     return vec4<f32>(unpack2x16float(x.x), unpack2x16float(x.y));
 }
 
+fn pack4x16float(x: vec4<f32>) -> vec2<u32> {
+    return vec2<u32>(pack2x16float(x.xy), pack2x16float(x.zw));
+}
+
+fn load_x(index: u32) -> vec4<f32> {
+#ifdef ACT_F16
+    return unpack4x16float(x[index]);
+#else
+    return x[index];
+#endif
+}
+
+fn store_x(index: u32, value: vec4<f32>) {
+#ifdef ACT_F16
+    x[index] = pack4x16float(value);
+#else
+    x[index] = value;
+#endif
+}
+
 fn reduce_step(index: u32, stride: u32) {
     if index < stride {
         sum[index] += sum[index + stride];
@@ -23,6 +47,26 @@ fn reduce_step(index: u32, stride: u32) {
     workgroupBarrier();
 }
 
+#ifdef DETERMINISTIC
+fn reduce_sum(index: u32, stride: u32) {
+    if index < stride {
+        sum[index] += sum[index + stride];
+    }
+    workgroupBarrier();
+}
+
+fn reduce_sum_squared(index: u32, stride: u32) {
+    if index < stride {
+        sum_squared[index] += sum_squared[index + stride];
+    }
+    workgroupBarrier();
+}
+
+fn horizontal_sum(x: vec4<f32>) -> f32 {
+    return (x.x + x.y) + (x.z + x.w);
+}
+#endif
+
 @compute @workgroup_size(128, 1, 1)
 fn layer_norm(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
     let stride = shape[0] / 4u;
@@ -33,12 +77,54 @@ fn layer_norm(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
     let bb = (batch * shape[1] + token) * stride;
 
     for (var i = index; i < stride; i += BLOCK_SIZE) {
-        let value = x[bb + i];
+        let value = load_x(bb + i);
         sum[index] += value;
+#ifndef DETERMINISTIC
+        sum_squared[index] += value * value;
+#endif
+    }
+    workgroupBarrier();
+
+#ifdef DETERMINISTIC
+    // two passes with every sum in a fixed order: the mean first, then the squared deviations from it
+    reduce_sum(index, 64u);
+    reduce_sum(index, 32u);
+    reduce_sum(index, 16u);
+    reduce_sum(index, 8u);
+    reduce_sum(index, 4u);
+    reduce_sum(index, 2u);
+    reduce_sum(index, 1u);
+
+    if index == 0u {
+        mean = horizontal_sum(sum[0]) / f32(shape[0]);
+    }
+    workgroupBarrier();
+
+    for (var i = index; i < stride; i += BLOCK_SIZE) {
+        let value = load_x(bb + i) - mean;
         sum_squared[index] += value * value;
     }
     workgroupBarrier();
 
+    reduce_sum_squared(index, 64u);
+    reduce_sum_squared(index, 32u);
+    reduce_sum_squared(index, 16u);
+    reduce_sum_squared(index, 8u);
+    reduce_sum_squared(index, 4u);
+    reduce_sum_squared(index, 2u);
+    reduce_sum_squared(index, 1u);
+
+    if index == 0u {
+        deviation = 1.0 / sqrt(horizontal_sum(sum_squared[0]) / f32(shape[0]));
+    }
+    workgroupBarrier();
+
+    // `fma` may or may not be fused depending on the backend
+    for (var i = index; i < stride; i += BLOCK_SIZE) {
+        let value = (load_x(bb + i) - mean) * deviation;
+        store_x(bb + i, value * unpack4x16float(w[i]) + unpack4x16float(b[i]));
+    }
+#else
     reduce_step(index, 64u);
     reduce_step(index, 32u);
     reduce_step(index, 16u);
@@ -54,7 +140,8 @@ fn layer_norm(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
     workgroupBarrier();
 
     for (var i = index; i < stride; i += BLOCK_SIZE) {
-        let value = (x[bb + i] - mean) * deviation;
-        x[bb + i] = fma(value, unpack4x16float(w[i]), unpack4x16float(b[i]));
+        let value = (load_x(bb + i) - mean) * deviation;
+        store_x(bb + i, fma(value, unpack4x16float(w[i]), unpack4x16float(b[i])));
     }
+#endif
 }
\ No newline at end of file
@@ -0,0 +1,24 @@
+@group(0) @binding(0) var<uniform> shape: vec4<u32>;                        // [C, T, B]
+
+@group(0) @binding(1) var<storage, read> mask: array<u32>;                  // (B, C / 32)
+@group(0) @binding(2) var<storage, read_write> x: array<vec4<f32>>;         // (B, T, C)
+
+const BLOCK_SIZE: u32 = 128u;
+
+@compute @workgroup_size(128, 1, 1)
+fn mask_logits(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+    let stride = shape[0] / 4u;
+    let index = invocation_id.x;
+    let token = invocation_id.y;
+    let batch = invocation_id.z;
+
+    if index < stride {
+        let bti = (batch * shape[1] + token) * stride + index;
+        // each word holds the bits of 8 groups of 4 tokens
+        let words = (shape[0] + 31u) / 32u;
+        let bits = mask[batch * words + index / 8u] >> ((index % 8u) * 4u);
+        let allowed = ((vec4<u32>(bits) >> vec4<u32>(0u, 1u, 2u, 3u)) & vec4<u32>(1u)) == vec4<u32>(1u);
+        let banned = vec4<f32>(bitcast<f32>(0xff800000u));
+        x[bti] = select(banned, x[bti], allowed);
+    }
+}
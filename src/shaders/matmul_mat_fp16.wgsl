@@ -1,7 +1,8 @@
 struct View {
     stride: vec4<u32>,
     offset: vec4<u32>,
-    shape: vec4<u32>,  
+    shape: vec4<u32>,
+    step: vec4<u32>,
 };
 
 struct Input {
@@ -17,7 +18,11 @@ struct Input {
 
 @group(0) @binding(3) var<storage, read> xa: array<vec2<u32>>;              // (B, M, K)
 @group(0) @binding(4) var<storage, read> xb: array<vec2<u32>>;              // (B, N, K)
+#ifdef OUT_F16
+@group(0) @binding(5) var<storage, read_write> output: array<vec2<u32>>;    // (B, N, M)
+#else
 @group(0) @binding(5) var<storage, read_write> output: array<vec4<f32>>;    // (B, N, M)
+#endif
 
 var<workgroup> sa: array<array<vec2<u32>, 32u>, 32u>;
 var<workgroup> sb: array<array<vec2<u32>, 32u>, 32u>;
@@ -25,13 +30,25 @@ var<workgroup> sb: array<array<vec2<u32>, 32u>, 32u>;
 fn compute_index(view: View, z: u32, y: u32, x: u32) -> u32 {
     let stride = view.stride.x / 4u;
     let offset = view.offset.x / 4u;
-    return ((view.offset.z + z) * view.stride.y + view.offset.y + y) * stride + offset + x;
+    return ((view.offset.z + z * view.step.z) * view.stride.y + view.offset.y + y * view.step.y) * stride + offset + x;
 }
 
 fn unpack4x16float(x: vec2<u32>) -> vec4<f32> {
     return vec4<f32>(unpack2x16float(x.x), unpack2x16float(x.y));
 }
 
+fn pack4x16float(x: vec4<f32>) -> vec2<u32> {
+    return vec2<u32>(pack2x16float(x.xy), pack2x16float(x.zw));
+}
+
+fn store_output(index: u32, value: vec4<f32>) {
+#ifdef OUT_F16
+    output[index] = pack4x16float(value);
+#else
+    output[index] = value;
+#endif
+}
+
 @compute @workgroup_size(8, 8, 1)
 fn matmul(in: Input) {
     let b = in.bid.xy * 32u;
@@ -88,9 +105,11 @@ fn matmul(in: Input) {
     }
 
     if all(u < vec2<u32>(ra.y, rb.y)) {
-        output[compute_index(destination, in.uid.z, u.y, in.uid.x)] = local_sum[0];
-        output[compute_index(destination, in.uid.z, u.y + 1u, in.uid.x)] = local_sum[1];
-        output[compute_index(destination, in.uid.z, u.y + 2u, in.uid.x)] = local_sum[2];
-        output[compute_index(destination, in.uid.z, u.y + 3u, in.uid.x)] = local_sum[3];
+        // the last block of rows may be partial when the number of tokens isn't a multiple of 4
+        for (var j = 0u; j < 4u; j += 1u) {
+            if u.y + j < rb.y {
+                store_output(compute_index(destination, in.uid.z, u.y + j, in.uid.x), local_sum[j]);
+            }
+        }
     }
 }
\ No newline at end of file
@@ -0,0 +1,126 @@
+struct View {
+    stride: vec4<u32>,
+    offset: vec4<u32>,
+    shape: vec4<u32>,
+    step: vec4<u32>,
+};
+
+struct Input {
+    @builtin(workgroup_id) bid: vec3<u32>,
+    @builtin(global_invocation_id) uid: vec3<u32>,
+    @builtin(local_invocation_id) tid: vec3<u32>,
+    @builtin(local_invocation_index) index: u32,
+};
+
+@group(0) @binding(0) var<uniform> va: View;                                // [K, M, B]
+@group(0) @binding(1) var<uniform> vb: View;                                // [K, N, B]
+@group(0) @binding(2) var<uniform> destination: View;                       // [M, N, B]
+
+@group(0) @binding(3) var<storage, read> scale: array<vec4<f32>>;           // (B, M)
+@group(0) @binding(4) var<storage, read> zero: array<vec4<f32>>;            // (B, M)
+
+@group(0) @binding(5) var<storage, read> xa: array<u32>;                    // (B, M, K)
+@group(0) @binding(6) var<storage, read> xb: array<vec2<u32>>;              // (B, N, K)
+#ifdef OUT_F16
+@group(0) @binding(7) var<storage, read_write> output: array<vec2<u32>>;    // (B, N, M)
+#else
+@group(0) @binding(7) var<storage, read_write> output: array<vec4<f32>>;    // (B, N, M)
+#endif
+
+var<workgroup> sa: array<array<u32, 32u>, 32u>;
+var<workgroup> sb: array<array<vec2<u32>, 32u>, 32u>;
+
+fn compute_index(view: View, z: u32, y: u32, x: u32) -> u32 {
+    let stride = view.stride.x / 4u;
+    let offset = view.offset.x / 4u;
+    return ((view.offset.z + z * view.step.z) * view.stride.y + view.offset.y + y * view.step.y) * stride + offset + x;
+}
+
+fn unpack4x16float(x: vec2<u32>) -> vec4<f32> {
+    return vec4<f32>(unpack2x16float(x.x), unpack2x16float(x.y));
+}
+
+fn pack4x16float(x: vec4<f32>) -> vec2<u32> {
+    return vec2<u32>(pack2x16float(x.xy), pack2x16float(x.zw));
+}
+
+fn store_output(index: u32, value: vec4<f32>) {
+#ifdef OUT_F16
+    output[index] = pack4x16float(value);
+#else
+    output[index] = value;
+#endif
+}
+
+@compute @workgroup_size(8, 8, 1)
+fn matmul(in: Input) {
+    let b = in.bid.xy * 32u;
+    let u = in.uid.xy * 4u;
+    let t = in.tid.xy * 4u;
+    let ra = vec2<u32>(va.shape.x / 4u, va.shape.y);
+    let rb = vec2<u32>(vb.shape.x / 4u, vb.shape.y);
+    let stride = min(ra.x, rb.x);
+    let i = in.index & 31u;
+
+    // the dequantized weight is `(q - zero) * scale`, with `q` in [0, 255],
+    // so the sum over a row factors into `scale * (dot(q, x) - zero * sum(x))`
+    var local_sum: mat4x4<f32>;
+    var local_offset = vec4<f32>(0.0);
+    for (var k = 0u; k < stride; k += 32u) {
+        // load 8x4 rows from each of the matrix, each with 32x4 columns
+        var x = k + i;
+        for (var j = 0u; j < 32u; j += 1u) {
+            if in.index < 32u {
+                let y = b.x + j;
+                if all(vec2<u32>(x, y) < ra) {
+                    sa[j][i] = xa[compute_index(va, in.uid.z, y, x)];
+                } else {
+                    sa[j][i] = 0u;
+                }
+            } else {
+                let y = b.y + j;
+                if all(vec2<u32>(x, y) < rb) {
+                    sb[j][i] = xb[compute_index(vb, in.uid.z, y, x)];
+                } else {
+                    sb[j][i] = vec2<u32>(0u);
+                }
+            }
+        }
+        workgroupBarrier();
+
+        // each thread multiplies and sums up 4x4 blocks along the reduced dimension
+        if all(u < vec2<u32>(ra.y, rb.y)) {
+            let reduce = min(32u, stride - k);
+            for (x = 0u; x < reduce; x += 1u) {
+                let aa = mat4x4<f32>(
+                    unpack4x8unorm(sa[t.x][x]),
+                    unpack4x8unorm(sa[t.x + 1u][x]),
+                    unpack4x8unorm(sa[t.x + 2u][x]),
+                    unpack4x8unorm(sa[t.x + 3u][x])
+                );
+                let bb = mat4x4<f32>(
+                    unpack4x16float(sb[t.y][x]),
+                    unpack4x16float(sb[t.y + 1u][x]),
+                    unpack4x16float(sb[t.y + 2u][x]),
+                    unpack4x16float(sb[t.y + 3u][x])
+                );
+                local_sum += transpose(aa) * bb;
+                local_offset += vec4<f32>(1.0) * bb;
+            }
+        }
+        workgroupBarrier();
+    }
+
+    if all(u < vec2<u32>(ra.y, rb.y)) {
+        let channel = in.uid.z * (ra.y / 4u) + in.uid.x;
+        let s = scale[channel];
+        let z = zero[channel];
+        // the last block of rows may be partial when the number of tokens isn't a multiple of 4
+        for (var j = 0u; j < 4u; j += 1u) {
+            if u.y + j < rb.y {
+                let value = s * fma(local_sum[j], vec4<f32>(255.0), -z * local_offset[j]);
+                store_output(compute_index(destination, in.uid.z, u.y + j, in.uid.x), value);
+            }
+        }
+    }
+}
@@ -1,31 +1,61 @@
 struct View {
     stride: vec4<u32>,
     offset: vec4<u32>,
-    shape: vec4<u32>,  
+    shape: vec4<u32>,
+    step: vec4<u32>,
 };
 
-@group(0) @binding(0) var<uniform> shape: vec4<u32>;                        // [C, R]
 @group(0) @binding(1) var<uniform> source: View;                            // [R, T, B]
 @group(0) @binding(2) var<uniform> destination: View;                       // [R, T, B]
 
 @group(0) @binding(3) var<storage, read> matrix: array<vec2<u32>>;          // (R, C)
+#ifdef IN_F16
+@group(0) @binding(4) var<storage, read> input: array<vec2<u32>>;           // (B, T, C)
+#else
 @group(0) @binding(4) var<storage, read> input: array<vec4<f32>>;           // (B, T, C)
+#endif
+#ifdef OUT_F16
+@group(0) @binding(5) var<storage, read_write> output: array<vec2<u32>>;    // (B, T, R)
+#else
 @group(0) @binding(5) var<storage, read_write> output: array<vec4<f32>>;    // (B, T, R)
+#endif
 
 const BLOCK_SIZE: u32 = 128u;
+// the number of columns `C`, set when the pipeline is built
+override COLUMNS: u32;
 
 var<workgroup> sketch: array<vec4<f32>, BLOCK_SIZE>;
 
 fn compute_index(view: View, batch: u32, token: u32, index: u32) -> u32 {
     let stride = view.stride.x / 4u;
     let offset = view.offset.x / 4u;
-    return ((view.offset.z + batch) * view.stride.y + view.offset.y + token) * stride + offset + index;
+    return ((view.offset.z + batch * view.step.z) * view.stride.y + view.offset.y + token * view.step.y) * stride + offset + index;
 }
 
 fn unpack4x16float(x: vec2<u32>) -> vec4<f32> {
     return vec4<f32>(unpack2x16float(x.x), unpack2x16float(x.y));
 }
 
+fn pack4x16float(x: vec4<f32>) -> vec2<u32> {
+    return vec2<u32>(pack2x16float(x.xy), pack2x16float(x.zw));
+}
+
+fn load_input(index: u32) -> vec4<f32> {
+#ifdef IN_F16
+    return unpack4x16float(input[index]);
+#else
+    return input[index];
+#endif
+}
+
+fn store_output(index: u32, value: vec4<f32>) {
+#ifdef OUT_F16
+    output[index] = pack4x16float(value);
+#else
+    output[index] = value;
+#endif
+}
+
 fn reduce_sum(index: u32, stride: u32) {
     if index < stride {
         sketch[index] += sketch[index + stride];
@@ -35,7 +65,7 @@ fn reduce_sum(index: u32, stride: u32) {
 
 @compute @workgroup_size(128, 1, 1)
 fn matmul(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
-    let stride = shape.x / 4u;
+    let stride = COLUMNS / 4u;
     let index = invocation_id.x % BLOCK_SIZE;
     let channel = invocation_id.x / BLOCK_SIZE;     // 1 channel: 4 rows in matrix
     let token = invocation_id.y;
@@ -51,7 +81,7 @@ fn matmul(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
         var ci = cb + i;
 
         // read 4 elements from the input
-        let x = input[bti];
+        let x = load_input(bti);
 
         // read 4 rows from the matrix, each with 4 unpacked floats, forming a 4x4 sub-block
         var m: mat4x4<f32>;
@@ -76,6 +106,6 @@ fn matmul(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
     if index == 0u {
         // output[(batch * shape[1] + token) * stride.y + channel] = sketch[0];
         let btc = compute_index(destination, batch, token, channel);
-        output[btc] = sketch[0];
+        store_output(btc, sketch[0]);
     }
 }
\ No newline at end of file
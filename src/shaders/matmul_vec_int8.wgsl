@@ -1,82 +1,154 @@
 struct View {
     stride: vec4<u32>,
     offset: vec4<u32>,
-    shape: vec4<u32>,  
+    shape: vec4<u32>,
+    step: vec4<u32>,
 };
 
-@group(0) @binding(0) var<uniform> shape: vec4<u32>;                        // [C, R]
 @group(0) @binding(1) var<uniform> source: View;                            // [R, T, B]
 @group(0) @binding(2) var<uniform> destination: View;                       // [R, T, B]
 
+#ifdef WEIGHT_VEC4
+@group(0) @binding(3) var<storage, read> matrix: array<vec4<u32>>;          // (R, C)
+#else
 @group(0) @binding(3) var<storage, read> matrix: array<u32>;                // (R, C)
+#endif
 @group(0) @binding(4) var<storage, read> mx: array<vec4<f32>>;              // (C)
 @group(0) @binding(5) var<storage, read> rx: array<vec4<f32>>;              // (C)
 @group(0) @binding(6) var<storage, read> my: array<vec4<f32>>;              // (R)
 @group(0) @binding(7) var<storage, read> ry: array<vec4<f32>>;              // (R)
 
+#ifdef IN_F16
+@group(0) @binding(8) var<storage, read> input: array<vec2<u32>>;           // (B, T, C)
+#else
 @group(0) @binding(8) var<storage, read> input: array<vec4<f32>>;           // (B, T, C)
+#endif
+#ifdef OUT_F16
+@group(0) @binding(9) var<storage, read_write> output: array<vec2<u32>>;    // (B, T, R)
+#else
 @group(0) @binding(9) var<storage, read_write> output: array<vec4<f32>>;    // (B, T, R)
+#endif
 
 const BLOCK_SIZE: u32 = 128u;
+// the number of columns `C`, set when the pipeline is built
+override COLUMNS: u32;
 
 var<workgroup> sketch: array<vec4<f32>, BLOCK_SIZE>;
+var<workgroup> sketch_offset: array<vec2<f32>, BLOCK_SIZE>;
 
 fn compute_index(view: View, batch: u32, token: u32, index: u32) -> u32 {
     let stride = view.stride.x / 4u;
     let offset = view.offset.x / 4u;
-    return ((view.offset.z + batch) * view.stride.y + view.offset.y + token) * stride + offset + index;
+    return ((view.offset.z + batch * view.step.z) * view.stride.y + view.offset.y + token * view.step.y) * stride + offset + index;
 }
 
 fn unpack4x16float(x: vec2<u32>) -> vec4<f32> {
     return vec4<f32>(unpack2x16float(x.x), unpack2x16float(x.y));
 }
 
+fn pack4x16float(x: vec4<f32>) -> vec2<u32> {
+    return vec2<u32>(pack2x16float(x.xy), pack2x16float(x.zw));
+}
+
+fn load_input(index: u32) -> vec4<f32> {
+#ifdef IN_F16
+    return unpack4x16float(input[index]);
+#else
+    return input[index];
+#endif
+}
+
+fn store_output(index: u32, value: vec4<f32>) {
+#ifdef OUT_F16
+    output[index] = pack4x16float(value);
+#else
+    output[index] = value;
+#endif
+}
+
+// dot product of 16 packed weights with 16 inputs, 4 per component
+fn dot16(m: vec4<u32>, x: mat4x4<f32>) -> f32 {
+    let y = vec4<f32>(
+        dot(unpack4x8unorm(m.x), x[0]),
+        dot(unpack4x8unorm(m.y), x[1]),
+        dot(unpack4x8unorm(m.z), x[2]),
+        dot(unpack4x8unorm(m.w), x[3])
+    );
+    return dot(y, vec4<f32>(1.0));
+}
+
 fn reduce_sum(index: u32, stride: u32) {
     if index < stride {
         sketch[index] += sketch[index + stride];
+        sketch_offset[index] += sketch_offset[index + stride];
     }
     workgroupBarrier();
 }
 
 @compute @workgroup_size(128, 1, 1)
 fn matmul(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
-    let stride = shape.x / 4u;
+    let stride = COLUMNS / 4u;
     let index = invocation_id.x % BLOCK_SIZE;
     let channel = invocation_id.x / BLOCK_SIZE;     // 1 channel: 4 rows in matrix
     let token = invocation_id.y;
     let batch = invocation_id.z;
 
     let bb = compute_index(source, batch, token, 0u);
-    let cb = channel * 4u * stride;
-
-    // let myc = unpack4x16float(my[channel]);
-    // let ryc = unpack4x16float(ry[channel]);
-    let myc = my[channel];
-    let ryc = ry[channel];
 
+    // the dequantized weight is `q * ry * rx + my + mx`, so the sum over a row factors into
+    // `ry * dot(q, rx * x) + my * sum(x) + dot(mx, x)`, where only the first term depends on the row;
+    // this leaves a single dot product per row in the inner loop, on the weights as `unpack4x8unorm` unpacks them
     var local_sum = vec4<f32>(0.0);
+    var local_offset = vec2<f32>(0.0);
+#ifdef WEIGHT_VEC4
+    // each lane reads 16 elements of a row in one load, and neighbouring lanes read neighbouring words
+    let stride_4 = stride / 4u;
+    let cb = channel * 4u * stride_4;
+    for (var i = index; i < stride_4; i += BLOCK_SIZE) {
+        let bti = bb + i * 4u;
+        let ii = i * 4u;
+        var ci = cb + i;
+
+        // read 16 elements from the input
+        let x = mat4x4<f32>(load_input(bti), load_input(bti + 1u), load_input(bti + 2u), load_input(bti + 3u));
+        let xr = mat4x4<f32>(rx[ii] * x[0], rx[ii + 1u] * x[1], rx[ii + 2u] * x[2], rx[ii + 3u] * x[3]);
+        let sum_x = x[0] + x[1] + x[2] + x[3];
+        let mx_x = mx[ii] * x[0] + mx[ii + 1u] * x[1] + mx[ii + 2u] * x[2] + mx[ii + 3u] * x[3];
+        local_offset += vec2<f32>(dot(sum_x, vec4<f32>(1.0)), dot(mx_x, vec4<f32>(1.0)));
+
+        // read 4 rows from the matrix, each 16 elements packed in a `vec4<u32>`
+        let m0 = matrix[ci]; ci += stride_4;
+        let m1 = matrix[ci]; ci += stride_4;
+        let m2 = matrix[ci]; ci += stride_4;
+        let m3 = matrix[ci];
+        local_sum += vec4<f32>(dot16(m0, xr), dot16(m1, xr), dot16(m2, xr), dot16(m3, xr));
+    }
+#else
+    let cb = channel * 4u * stride;
     for (var i = index; i < stride; i += BLOCK_SIZE) {
         let bti = bb + i;
         var ci = cb + i;
 
         // read 4 elements from the input
-        let x = input[bti];
-
-        // let mxi = unpack4x16float(mx[i]);
-        // let rxi = unpack4x16float(rx[i]);
-        let mxi = mx[i];
-        let rxi = rx[i];
-
-        // read 4 rows from the matrix, each with 4 unpacked floats, forming a 4x4 sub-block
-        var m: mat4x4<f32>;
-
-        m[0] = fma(unpack4x8unorm(matrix[ci]), ryc[0] * rxi, myc[0] + mxi); ci += stride;
-        m[1] = fma(unpack4x8unorm(matrix[ci]), ryc[1] * rxi, myc[1] + mxi); ci += stride;
-        m[2] = fma(unpack4x8unorm(matrix[ci]), ryc[2] * rxi, myc[2] + mxi); ci += stride;
-        m[3] = fma(unpack4x8unorm(matrix[ci]), ryc[3] * rxi, myc[3] + mxi);
-        local_sum += transpose(m) * x;
+        let x = load_input(bti);
+        let xr = rx[i] * x;
+        local_offset += vec2<f32>(dot(x, vec4<f32>(1.0)), dot(mx[i], x));
+
+        // read 4 rows from the matrix, each packed in a single `u32`
+        let m0 = matrix[ci]; ci += stride;
+        let m1 = matrix[ci]; ci += stride;
+        let m2 = matrix[ci]; ci += stride;
+        let m3 = matrix[ci];
+        local_sum += vec4<f32>(
+            dot(unpack4x8unorm(m0), xr),
+            dot(unpack4x8unorm(m1), xr),
+            dot(unpack4x8unorm(m2), xr),
+            dot(unpack4x8unorm(m3), xr)
+        );
     }
+#endif
     sketch[index] = local_sum;
+    sketch_offset[index] = local_offset;
     workgroupBarrier();
 
     reduce_sum(index, 64u);
@@ -90,6 +162,6 @@ fn matmul(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
     if index == 0u {
         // output[(batch * shape[1] + token) * stride.y + channel] = sketch[0];
         let btc = compute_index(destination, batch, token, channel);
-        output[btc] = sketch[0];
+        store_output(btc, fma(sketch[0], ry[channel], fma(my[channel], vec4<f32>(sketch_offset[0].x), vec4<f32>(sketch_offset[0].y))));
     }
 }
\ No newline at end of file
@@ -0,0 +1,122 @@
+struct View {
+    stride: vec4<u32>,
+    offset: vec4<u32>,
+    shape: vec4<u32>,
+    step: vec4<u32>,
+};
+
+@group(0) @binding(0) var<uniform> shape: vec4<u32>;                        // [C, R]
+@group(0) @binding(1) var<uniform> source: View;                            // [R, T, B]
+@group(0) @binding(2) var<uniform> destination: View;                       // [R, T, B]
+
+@group(0) @binding(3) var<storage, read> matrix: array<u32>;                // (R, C)
+@group(0) @binding(4) var<storage, read> scale: array<vec4<f32>>;           // (R)
+@group(0) @binding(5) var<storage, read> zero: array<vec4<f32>>;            // (R)
+
+#ifdef IN_F16
+@group(0) @binding(6) var<storage, read> input: array<vec2<u32>>;           // (B, T, C)
+#else
+@group(0) @binding(6) var<storage, read> input: array<vec4<f32>>;           // (B, T, C)
+#endif
+#ifdef OUT_F16
+@group(0) @binding(7) var<storage, read_write> output: array<vec2<u32>>;    // (B, T, R)
+#else
+@group(0) @binding(7) var<storage, read_write> output: array<vec4<f32>>;    // (B, T, R)
+#endif
+
+const BLOCK_SIZE: u32 = 128u;
+
+var<workgroup> sketch: array<vec4<f32>, BLOCK_SIZE>;
+var<workgroup> sketch_offset: array<f32, BLOCK_SIZE>;
+
+fn compute_index(view: View, batch: u32, token: u32, index: u32) -> u32 {
+    let stride = view.stride.x / 4u;
+    let offset = view.offset.x / 4u;
+    return ((view.offset.z + batch * view.step.z) * view.stride.y + view.offset.y + token * view.step.y) * stride + offset + index;
+}
+
+fn unpack4x16float(x: vec2<u32>) -> vec4<f32> {
+    return vec4<f32>(unpack2x16float(x.x), unpack2x16float(x.y));
+}
+
+fn pack4x16float(x: vec4<f32>) -> vec2<u32> {
+    return vec2<u32>(pack2x16float(x.xy), pack2x16float(x.zw));
+}
+
+fn load_input(index: u32) -> vec4<f32> {
+#ifdef IN_F16
+    return unpack4x16float(input[index]);
+#else
+    return input[index];
+#endif
+}
+
+fn store_output(index: u32, value: vec4<f32>) {
+#ifdef OUT_F16
+    output[index] = pack4x16float(value);
+#else
+    output[index] = value;
+#endif
+}
+
+fn reduce_sum(index: u32, stride: u32) {
+    if index < stride {
+        sketch[index] += sketch[index + stride];
+        sketch_offset[index] += sketch_offset[index + stride];
+    }
+    workgroupBarrier();
+}
+
+@compute @workgroup_size(128, 1, 1)
+fn matmul(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+    let stride = shape.x / 4u;
+    let index = invocation_id.x % BLOCK_SIZE;
+    let channel = invocation_id.x / BLOCK_SIZE;     // 1 channel: 4 rows in matrix
+    let token = invocation_id.y;
+    let batch = invocation_id.z;
+
+    let bb = compute_index(source, batch, token, 0u);
+    let cb = channel * 4u * stride;
+
+    // the dequantized weight is `(q - zero) * scale`, with `q` in [0, 255],
+    // so the sum over a row factors into `scale * (dot(q, x) - zero * sum(x))`
+    var local_sum = vec4<f32>(0.0);
+    var local_offset = 0.0;
+    for (var i = index; i < stride; i += BLOCK_SIZE) {
+        let bti = bb + i;
+        var ci = cb + i;
+
+        // read 4 elements from the input
+        let x = load_input(bti);
+        local_offset += dot(x, vec4<f32>(1.0));
+
+        // read 4 rows from the matrix, each packed in a single `u32`
+        let m0 = matrix[ci]; ci += stride;
+        let m1 = matrix[ci]; ci += stride;
+        let m2 = matrix[ci]; ci += stride;
+        let m3 = matrix[ci];
+        local_sum += vec4<f32>(
+            dot(unpack4x8unorm(m0), x),
+            dot(unpack4x8unorm(m1), x),
+            dot(unpack4x8unorm(m2), x),
+            dot(unpack4x8unorm(m3), x)
+        );
+    }
+    sketch[index] = local_sum;
+    sketch_offset[index] = local_offset;
+    workgroupBarrier();
+
+    reduce_sum(index, 64u);
+    reduce_sum(index, 32u);
+    reduce_sum(index, 16u);
+    reduce_sum(index, 8u);
+    reduce_sum(index, 4u);
+    reduce_sum(index, 2u);
+    reduce_sum(index, 1u);
+
+    if index == 0u {
+        // output[(batch * shape[1] + token) * stride.y + channel] = sketch[0];
+        let btc = compute_index(destination, batch, token, channel);
+        store_output(btc, scale[channel] * fma(sketch[0], vec4<f32>(255.0), -zero[channel] * sketch_offset[0]));
+    }
+}
\ No newline at end of file
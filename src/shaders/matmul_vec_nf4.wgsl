@@ -1,7 +1,8 @@
 struct View {
     stride: vec4<u32>,
     offset: vec4<u32>,
-    shape: vec4<u32>,  
+    shape: vec4<u32>,
+    step: vec4<u32>,
 };
 
 @group(0) @binding(1) var<uniform> source: View;                            // [R, T, B]
@@ -12,7 +13,11 @@ struct View {
 @group(0) @binding(5) var<storage, read> absmax: array<u32>;
 
 @group(0) @binding(6) var<storage, read> input: array<vec4<u32>>;           // (B, T, C)
+#ifdef OUT_F16
+@group(0) @binding(7) var<storage, read_write> output: array<vec2<u32>>;    // (B, T, R)
+#else
 @group(0) @binding(7) var<storage, read_write> output: array<vec4<f32>>;    // (B, T, R)
+#endif
 
 const BLOCK_SIZE: u32 = 128u;
 const NF4_BLOCK_SIZE: u32 = 64u;
@@ -23,7 +28,7 @@ var<workgroup> q: array<f32, 16>;
 fn compute_index(view: View, batch: u32, token: u32, index: u32, step: u32) -> u32 {
     let stride = view.stride.x / step;
     let offset = view.offset.x / step;
-    return ((view.offset.z + batch) * view.stride.y + view.offset.y + token) * stride + offset + index;
+    return ((view.offset.z + batch * view.step.z) * view.stride.y + view.offset.y + token * view.step.y) * stride + offset + index;
 }
 
 fn unpack4x16float(x: vec2<u32>) -> vec4<f32> {
@@ -34,6 +39,14 @@ fn pack4x16float(x: vec4<f32>) -> vec2<u32> {
     return vec2<u32>(pack2x16float(x.xy), pack2x16float(x.zw));
 }
 
+fn store_output(index: u32, value: vec4<f32>) {
+#ifdef OUT_F16
+    output[index] = pack4x16float(value);
+#else
+    output[index] = value;
+#endif
+}
+
 fn unpack_absmax(index: u32) -> f32 {
     let i = index / (NF4_BLOCK_SIZE / 8u);              // 1 block of absmax: NF4_BLOCK_SIZE / 8u entries in matrix
     return unpack2x16float(absmax[i >> 1u])[i & 1u];
@@ -142,6 +155,6 @@ fn matmul(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
 
     if index == 0u {
         let btc = compute_index(destination, batch, token, channel, 4u);
-        output[btc] = sketch[0];
+        store_output(btc, sketch[0]);
     }
 }
\ No newline at end of file
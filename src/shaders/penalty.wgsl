@@ -0,0 +1,29 @@
+@group(0) @binding(0) var<uniform> shape: vec4<u32>;                        // [C, T, B]
+
+@group(0) @binding(1) var<storage, read> param: array<vec4<f32>>;           // (B)
+@group(0) @binding(2) var<storage, read> count: array<vec4<u32>>;           // (B, C)
+@group(0) @binding(3) var<storage, read_write> x: array<vec4<f32>>;         // (B, T, C)
+
+const BLOCK_SIZE: u32 = 128u;
+
+@compute @workgroup_size(128, 1, 1)
+fn penalty(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+    let stride = shape[0] / 4u;
+    let index = invocation_id.x;
+    let token = invocation_id.y;
+    let batch = invocation_id.z;
+
+    if index < stride {
+        let bti = (batch * shape[1] + token) * stride + index;
+        // repetition, presence and frequency penalties of this batch
+        let p = param[batch];
+        let n = vec4<f32>(count[batch * stride + index]);
+        let seen = n > vec4<f32>(0.0);
+
+        var value = x[bti];
+        let repeated = select(value * p.x, value / p.x, value > vec4<f32>(0.0));
+        value = select(value, repeated, seen);
+        value -= select(vec4<f32>(0.0), vec4<f32>(p.y), seen) + p.z * n;
+        x[bti] = value;
+    }
+}
@@ -0,0 +1,23 @@
+@group(0) @binding(0) var<uniform> shape: vec4<u32>;                        // [C, T, B]
+
+@group(0) @binding(1) var<storage, read> weight: array<f32>;                // (B, T)
+@group(0) @binding(2) var<storage, read> x: array<vec4<f32>>;               // (B, T, C)
+@group(0) @binding(3) var<storage, read_write> output: array<vec4<f32>>;    // (B, 1, C)
+
+const BLOCK_SIZE: u32 = 128u;
+
+@compute @workgroup_size(128, 1, 1)
+fn pool(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+    let stride = shape[0] / 4u;
+    let index = invocation_id.x;
+    let batch = invocation_id.z;
+
+    if index < stride {
+        var sum = vec4<f32>(0.0);
+        for (var token = 0u; token < shape[1]; token += 1u) {
+            let bt = batch * shape[1] + token;
+            sum += weight[bt] * x[bt * stride + index];
+        }
+        output[batch * stride + index] = sum;
+    }
+}
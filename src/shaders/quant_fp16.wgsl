@@ -1,10 +1,26 @@
 @group(0) @binding(0) var<uniform> shape: vec4<u32>;                        // [C, T, B]
 
+#ifdef ACT_F16
+@group(0) @binding(1) var<storage, read> input: array<vec2<u32>>;           // (B, T, C)
+#else
 @group(0) @binding(1) var<storage, read> input: array<vec4<f32>>;           // (B, T, C)
+#endif
 @group(0) @binding(2) var<storage, read_write> output: array<vec2<u32>>;    // (B, T, C)
 
 const BLOCK_SIZE: u32 = 128u;
 
+fn unpack4x16float(x: vec2<u32>) -> vec4<f32> {
+    return vec4<f32>(unpack2x16float(x.x), unpack2x16float(x.y));
+}
+
+fn load_input(index: u32) -> vec4<f32> {
+#ifdef ACT_F16
+    return unpack4x16float(input[index]);
+#else
+    return input[index];
+#endif
+}
+
 fn pack4x16float(x: vec4<f32>) -> vec2<u32> {
     return vec2<u32>(pack2x16float(x.xy), pack2x16float(x.zw));
 }
@@ -18,6 +34,6 @@ fn quantize(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
 
     if index < stride {
         let bti = (batch * shape[1] + token) * stride + index;
-        output[bti] = pack4x16float(input[bti]);
+        output[bti] = pack4x16float(load_input(bti));
     }
 }
@@ -0,0 +1,67 @@
+@group(0) @binding(0) var<uniform> shape: vec4<u32>;                        // [C, R]
+@group(0) @binding(1) var<storage, read> input: array<vec2<u32>>;           // (R, C)
+
+@group(0) @binding(2) var<storage, read_write> scale: array<f32>;           // (R)
+@group(0) @binding(3) var<storage, read_write> zero: array<f32>;            // (R)
+
+@group(0) @binding(4) var<storage, read_write> output: array<u32>;          // (R, C)
+
+const BLOCK_SIZE: u32 = 128u;
+
+var<workgroup> sketch_min: array<vec4<f32>, BLOCK_SIZE>;
+var<workgroup> sketch_max: array<vec4<f32>, BLOCK_SIZE>;
+var<workgroup> rs: f32;
+var<workgroup> rz: f32;
+
+fn unpack4x16float(x: vec2<u32>) -> vec4<f32> {
+    return vec4<f32>(unpack2x16float(x.x), unpack2x16float(x.y));
+}
+
+fn reduce_step(index: u32, stride: u32) {
+    if index < stride {
+        sketch_min[index] = min(sketch_min[index], sketch_min[index + stride]);
+        sketch_max[index] = max(sketch_max[index], sketch_max[index + stride]);
+    }
+    workgroupBarrier();
+}
+
+@compute @workgroup_size(128, 1, 1)
+fn quantize(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+    let index = invocation_id.x;
+    let batch = invocation_id.y;
+    let stride = shape.x / 4u;
+
+    sketch_min[index] = vec4<f32>(1.0e30);
+    sketch_max[index] = vec4<f32>(-1.0e30);
+    for (var i = index; i < stride; i += BLOCK_SIZE) {
+        let value = unpack4x16float(input[stride * batch + i]);
+        sketch_min[index] = min(sketch_min[index], value);
+        sketch_max[index] = max(sketch_max[index], value);
+    }
+    workgroupBarrier();
+
+    reduce_step(index, 64u);
+    reduce_step(index, 32u);
+    reduce_step(index, 16u);
+    reduce_step(index, 8u);
+    reduce_step(index, 4u);
+    reduce_step(index, 2u);
+    reduce_step(index, 1u);
+
+    if index == 0u {
+        // the range always covers 0 so that it can be represented exactly by the zero point
+        let lo = min(min(min(sketch_min[0].x, sketch_min[0].y), min(sketch_min[0].z, sketch_min[0].w)), 0.0);
+        let hi = max(max(max(sketch_max[0].x, sketch_max[0].y), max(sketch_max[0].z, sketch_max[0].w)), 0.0);
+        rs = max(hi - lo, 1.0e-12) / 255.0;
+        rz = round(-lo / rs);
+        scale[batch] = rs;
+        zero[batch] = rz;
+    }
+    workgroupBarrier();
+
+    for (var i = index; i < stride; i += BLOCK_SIZE) {
+        let value = unpack4x16float(input[stride * batch + i]);
+        let q = clamp(round(value / rs) + rz, vec4<f32>(0.0), vec4<f32>(255.0));
+        output[stride * batch + i] = pack4x8unorm(q / 255.0);
+    }
+}
@@ -0,0 +1,53 @@
+struct Param {
+    seed: u32,
+    // `[low, high]` for the uniform distribution, `[mean, std]` for the normal distribution
+    a: f32,
+    b: f32,
+    _pad: u32,
+};
+
+@group(0) @binding(0) var<uniform> shape: vec4<u32>;                        // [C, T, B]
+@group(0) @binding(1) var<uniform> param: Param;
+@group(0) @binding(2) var<storage, read_write> output: array<f32>;          // (B, T, C)
+
+const BLOCK_SIZE: u32 = 128u;
+const TAU: f32 = 6.283185307179586;
+
+// PCG hash, see "Hash Functions for GPU Rendering" (Jarzynski & Olano, 2020)
+fn pcg(v: u32) -> u32 {
+    let state = v * 747796405u + 2891336453u;
+    let word = ((state >> ((state >> 28u) + 4u)) ^ state) * 277803737u;
+    return (word >> 22u) ^ word;
+}
+
+// uniform in `[0, 1)`, from the 24 high bits of the hash of the counter
+fn rand(counter: u32) -> f32 {
+    return f32(pcg(counter + pcg(param.seed)) >> 8u) / 16777216.0;
+}
+
+@compute @workgroup_size(128, 1, 1)
+fn rand_uniform(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+    let index = invocation_id.x;
+    let token = invocation_id.y;
+    let batch = invocation_id.z;
+
+    if index < shape[0] {
+        let bti = (batch * shape[1] + token) * shape[0] + index;
+        output[bti] = mix(param.a, param.b, rand(bti));
+    }
+}
+
+@compute @workgroup_size(128, 1, 1)
+fn rand_normal(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+    let index = invocation_id.x;
+    let token = invocation_id.y;
+    let batch = invocation_id.z;
+
+    if index < shape[0] {
+        let bti = (batch * shape[1] + token) * shape[0] + index;
+        // Box-Muller transform
+        let u = 1.0 - rand(2u * bti);
+        let v = rand(2u * bti + 1u);
+        output[bti] = param.a + param.b * sqrt(-2.0 * log(u)) * cos(TAU * v);
+    }
+}
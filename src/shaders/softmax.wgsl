@@ -1,6 +1,9 @@
 @group(0) @binding(0) var<uniform> shape: vec4<u32>;                        // [C, T, B]
 
 @group(0) @binding(1) var<storage, read_write> x: array<vec4<f32>>;         // (B, T, C)
+#ifdef TEMPERATURE
+@group(0) @binding(2) var<storage, read> temperature: array<f32>;           // (B)
+#endif
 // @group(0) @binding(2) var<storage, read_write> output: array<vec4<f32>>; // (B, T, C)
 
 const BLOCK_SIZE: u32 = 128u;
@@ -9,6 +12,14 @@ var<workgroup> sketch: array<vec4<f32>, BLOCK_SIZE>;
 var<workgroup> sum: f32;
 var<workgroup> maximum: f32;
 
+fn load_x(index: u32, batch: u32) -> vec4<f32> {
+#ifdef TEMPERATURE
+    return x[index] / temperature[batch];
+#else
+    return x[index];
+#endif
+}
+
 fn reduce_max(index: u32, stride: u32) {
     if index < stride {
         sketch[index] = max(sketch[index], sketch[index + stride]);
@@ -23,6 +34,14 @@ fn reduce_sum(index: u32, stride: u32) {
     workgroupBarrier();
 }
 
+fn horizontal_sum(x: vec4<f32>) -> f32 {
+#ifdef DETERMINISTIC
+    return (x.x + x.y) + (x.z + x.w);
+#else
+    return dot(x, vec4<f32>(1.0));
+#endif
+}
+
 @compute @workgroup_size(128, 1, 1)
 fn softmax(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
     let stride = shape[0] / 4u;
@@ -34,7 +53,7 @@ fn softmax(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
 
     sketch[index] = vec4<f32>(-1.0e30);
     for (var i = index; i < stride; i += BLOCK_SIZE) {
-        let value = x[bb + i];
+        let value = load_x(bb + i, batch);
         sketch[index] = max(sketch[index], value);
     }
     workgroupBarrier();
@@ -57,7 +76,7 @@ fn softmax(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
 
     sketch[index] = vec4<f32>(0.0);
     for (var i = index; i < stride; i += BLOCK_SIZE) {
-        let value = x[bb + i];
+        let value = load_x(bb + i, batch);
         sketch[index] += exp(value - maximum);
     }
     workgroupBarrier();
@@ -71,12 +90,12 @@ fn softmax(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
     reduce_sum(index, 1u);
 
     if index == 0u {
-        sum = dot(sketch[0], vec4<f32>(1.0));
+        sum = horizontal_sum(sketch[0]);
     }
     workgroupBarrier();
 
     for (var i = index; i < stride; i += BLOCK_SIZE) {
-        let value = x[bb + i];
+        let value = load_x(bb + i, batch);
         x[bb + i] = exp(value - maximum) / sum;
     }
 }
\ No newline at end of file
@@ -1,9 +1,37 @@
 @group(0) @binding(0) var<uniform> shape: vec4<u32>;                        // [C, T, B]
 
+#ifdef ACT_F16
+@group(0) @binding(1) var<storage, read_write> x: array<vec2<u32>>;         // (B, T, C)
+#else
 @group(0) @binding(1) var<storage, read_write> x: array<vec4<f32>>;         // (B, T, C)
+#endif
 
 const BLOCK_SIZE: u32 = 128u;
 
+fn pack4x16float(x: vec4<f32>) -> vec2<u32> {
+    return vec2<u32>(pack2x16float(x.xy), pack2x16float(x.zw));
+}
+
+fn unpack4x16float(x: vec2<u32>) -> vec4<f32> {
+    return vec4<f32>(unpack2x16float(x.x), unpack2x16float(x.y));
+}
+
+fn load_x(index: u32) -> vec4<f32> {
+#ifdef ACT_F16
+    return unpack4x16float(x[index]);
+#else
+    return x[index];
+#endif
+}
+
+fn store_x(index: u32, value: vec4<f32>) {
+#ifdef ACT_F16
+    x[index] = pack4x16float(value);
+#else
+    x[index] = value;
+#endif
+}
+
 @compute @workgroup_size(128, 1, 1)
 fn squared_relu(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
     let stride = shape[0] / 4u;
@@ -13,7 +41,7 @@ fn squared_relu(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
 
     if index < stride {
         let bti = (batch * shape[1] + token) * stride + index;
-        let p = max(x[bti], vec4<f32>(0.0));
-        x[bti] = p * p;
+        let p = max(load_x(bti), vec4<f32>(0.0));
+        store_x(bti, p * p);
     }
 }
\ No newline at end of file
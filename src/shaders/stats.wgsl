@@ -0,0 +1,103 @@
+struct View {
+    stride: vec4<u32>,
+    offset: vec4<u32>,
+    shape: vec4<u32>,
+    step: vec4<u32>,
+};
+
+@group(0) @binding(0) var<uniform> shape: vec4<u32>;                        // [C, T, B]
+@group(0) @binding(1) var<uniform> view: View;                              // [8, 1, 1]
+
+#ifdef ACT_F16
+@group(0) @binding(2) var<storage, read> x: array<vec2<u32>>;               // (B, T, C)
+#else
+@group(0) @binding(2) var<storage, read> x: array<vec4<f32>>;               // (B, T, C)
+#endif
+@group(0) @binding(3) var<storage, read_write> output: array<vec4<u32>>;    // (min, max, sum, count), (non-finite, 0, 0, 0)
+
+const BLOCK_SIZE: u32 = 128u;
+const FLOAT_MAX: f32 = 3.402823e38;
+const EXPONENT: u32 = 0x7f800000u;
+
+var<workgroup> sketch_min: array<vec4<f32>, BLOCK_SIZE>;
+var<workgroup> sketch_max: array<vec4<f32>, BLOCK_SIZE>;
+var<workgroup> sketch_sum: array<vec4<f32>, BLOCK_SIZE>;
+var<workgroup> sketch_inf: array<u32, BLOCK_SIZE>;
+
+fn unpack4x16float(x: vec2<u32>) -> vec4<f32> {
+    return vec4<f32>(unpack2x16float(x.x), unpack2x16float(x.y));
+}
+
+fn load_x(index: u32) -> vec4<f32> {
+#ifdef ACT_F16
+    return unpack4x16float(x[index]);
+#else
+    return x[index];
+#endif
+}
+
+fn compute_index(index: u32) -> u32 {
+    let stride = view.stride.x / 4u;
+    let offset = view.offset.x / 4u;
+    return (view.offset.z * view.stride.y + view.offset.y) * stride + offset + index;
+}
+
+fn reduce(index: u32, stride: u32) {
+    if index < stride {
+        sketch_min[index] = min(sketch_min[index], sketch_min[index + stride]);
+        sketch_max[index] = max(sketch_max[index], sketch_max[index + stride]);
+        sketch_sum[index] += sketch_sum[index + stride];
+        sketch_inf[index] += sketch_inf[index + stride];
+    }
+    workgroupBarrier();
+}
+
+@compute @workgroup_size(128, 1, 1)
+fn stats(@builtin(local_invocation_id) invocation_id: vec3<u32>) {
+    let index = invocation_id.x;
+    let len = shape[0] / 4u * shape[1] * shape[2];
+
+    var _min = vec4<f32>(FLOAT_MAX);
+    var _max = vec4<f32>(-FLOAT_MAX);
+    var sum = vec4<f32>(0.0);
+    var inf = 0u;
+    for (var i = index; i < len; i += BLOCK_SIZE) {
+        let value = load_x(i);
+        // infinities and NaNs are counted apart, so that the rest of the statistics stay readable
+        let finite = (bitcast<vec4<u32>>(value) & vec4<u32>(EXPONENT)) != vec4<u32>(EXPONENT);
+        _min = select(_min, min(_min, value), finite);
+        _max = select(_max, max(_max, value), finite);
+        sum += select(vec4<f32>(0.0), value, finite);
+        inf += dot(select(vec4<u32>(1u), vec4<u32>(0u), finite), vec4<u32>(1u));
+    }
+    sketch_min[index] = _min;
+    sketch_max[index] = _max;
+    sketch_sum[index] = sum;
+    sketch_inf[index] = inf;
+    workgroupBarrier();
+
+    reduce(index, 64u);
+    reduce(index, 32u);
+    reduce(index, 16u);
+    reduce(index, 8u);
+    reduce(index, 4u);
+    reduce(index, 2u);
+    reduce(index, 1u);
+
+    if index == 0u {
+        let bb = compute_index(0u);
+        // floats are kept as their bits, so that the integer counts beside them are never taken for floats
+        let acc = output[bb];
+        let inf_acc = output[bb + 1u].x;
+
+        let m = sketch_min[0];
+        let n = sketch_max[0];
+        let _min = min(bitcast<f32>(acc.x), min(min(m.x, m.y), min(m.z, m.w)));
+        let _max = max(bitcast<f32>(acc.y), max(max(n.x, n.y), max(n.z, n.w)));
+        let sum = bitcast<f32>(acc.z) + dot(sketch_sum[0], vec4<f32>(1.0));
+        let count = acc.w + len * 4u - sketch_inf[0];
+
+        output[bb] = vec4<u32>(bitcast<u32>(_min), bitcast<u32>(_max), bitcast<u32>(sum), count);
+        output[bb + 1u] = vec4<u32>(inf_acc + sketch_inf[0], 0u, 0u, 0u);
+    }
+}
@@ -1,7 +1,8 @@
 struct View {
     stride: vec4<u32>,
     offset: vec4<u32>,
-    shape: vec4<u32>,  
+    shape: vec4<u32>,
+    step: vec4<u32>,
 };
 
 struct Cursor {
@@ -22,24 +23,105 @@ struct Input {
 @group(0) @binding(3) var<storage, read> time_decay: array<vec4<f32>>;  // (H, S)
 @group(0) @binding(4) var<storage, read> time_first: array<vec4<f32>>;  // (H, S)
 
+#ifdef ACT_F16
+@group(0) @binding(5) var<storage, read> k: array<vec2<u32>>;           // (A, H, S)
+@group(0) @binding(6) var<storage, read> v: array<vec2<u32>>;           // (A, H, S)
+@group(0) @binding(7) var<storage, read> r: array<vec2<u32>>;           // (A, H, S)
+#else
 @group(0) @binding(5) var<storage, read> k: array<vec4<f32>>;           // (A, H, S)
 @group(0) @binding(6) var<storage, read> v: array<vec4<f32>>;           // (A, H, S)
 @group(0) @binding(7) var<storage, read> r: array<vec4<f32>>;           // (A, H, S)
+#endif
 
+#ifdef ACT_F16
+@group(0) @binding(8) var<storage, read_write> x: array<vec2<u32>>;     // (A, H, S)
+#else
 @group(0) @binding(8) var<storage, read_write> x: array<vec4<f32>>;     // (A, H, S)
+#endif
+#ifdef STATE_F16
+@group(0) @binding(9) var<storage, read_write> state: array<vec2<u32>>; // (B, S + 1, C)
+#else
 @group(0) @binding(9) var<storage, read_write> state: array<vec4<f32>>; // (B, S + 1, C)
+#endif
 
 const BLOCK_SIZE: u32 = 32u;
+const TOKEN_BLOCK: u32 = 4u;
+// the head size `S`, set when the pipeline is built
+override HEAD_SIZE: u32;
 
-var<workgroup> shared_k: array<vec4<f32>, BLOCK_SIZE>;
-var<workgroup> shared_r: array<vec4<f32>, BLOCK_SIZE>;
+var<workgroup> shared_k: array<array<vec4<f32>, BLOCK_SIZE>, TOKEN_BLOCK>;
+var<workgroup> shared_r: array<array<vec4<f32>, BLOCK_SIZE>, TOKEN_BLOCK>;
 var<workgroup> shared_u: array<vec4<f32>, BLOCK_SIZE>;
 var<workgroup> shared_w: array<vec4<f32>, BLOCK_SIZE>;
 
+fn pack4x16float(x: vec4<f32>) -> vec2<u32> {
+    return vec2<u32>(pack2x16float(x.xy), pack2x16float(x.zw));
+}
+
+fn unpack4x16float(x: vec2<u32>) -> vec4<f32> {
+    return vec4<f32>(unpack2x16float(x.x), unpack2x16float(x.y));
+}
+
+fn load_k(index: u32) -> vec4<f32> {
+#ifdef ACT_F16
+    return unpack4x16float(k[index]);
+#else
+    return k[index];
+#endif
+}
+
+fn load_v(index: u32) -> vec4<f32> {
+#ifdef ACT_F16
+    return unpack4x16float(v[index]);
+#else
+    return v[index];
+#endif
+}
+
+fn load_r(index: u32) -> vec4<f32> {
+#ifdef ACT_F16
+    return unpack4x16float(r[index]);
+#else
+    return r[index];
+#endif
+}
+
+fn load_x(index: u32) -> vec4<f32> {
+#ifdef ACT_F16
+    return unpack4x16float(x[index]);
+#else
+    return x[index];
+#endif
+}
+
+fn store_x(index: u32, value: vec4<f32>) {
+#ifdef ACT_F16
+    x[index] = pack4x16float(value);
+#else
+    x[index] = value;
+#endif
+}
+
 fn compute_index(batch: u32, token: u32, index: u32) -> u32 {
     let stride = view.stride.x / 4u;
     let offset = view.offset.x / 4u;
-    return ((view.offset.z + batch) * view.stride.y + view.offset.y + token) * stride + offset + index;
+    return ((view.offset.z + batch * view.step.z) * view.stride.y + view.offset.y + token * view.step.y) * stride + offset + index;
+}
+
+fn load_state(index: u32) -> vec4<f32> {
+#ifdef STATE_F16
+    return unpack4x16float(state[index]);
+#else
+    return state[index];
+#endif
+}
+
+fn store_state(index: u32, value: vec4<f32>) {
+#ifdef STATE_F16
+    state[index] = pack4x16float(value);
+#else
+    state[index] = value;
+#endif
 }
 
 fn compute_cursor(x: u32) -> Cursor {
@@ -53,62 +135,100 @@ fn compute_cursor(x: u32) -> Cursor {
 
 @compute @workgroup_size(32, 1, 1)
 fn time_mix(in: Input) {
-    let stride = shape[0] / 4u;
+    let stride = HEAD_SIZE / 4u;
     let dim = shape[1] * stride;
 
     let index = in.uid.x;
     let head = in.tid.x / stride;
     let h = head * stride;
 
-    shared_u[in.tid.x] = time_first[index];
-    shared_w[in.tid.x] = time_decay[index];
-
-    for (var t = 0u; t < shape[2]; t += 1u) {
-        let cursor = compute_cursor(cursors[t]);
-        if cursor.token == 0u {
-            state[compute_index(cursor.batch, 0u, index)] = x[(cursor.token + cursor.len - 1u) * dim + index];
-        }
+    if index < dim {
+        shared_u[in.tid.x] = time_first[index];
+        shared_w[in.tid.x] = time_decay[index];
+    }
 
-        let bti = t * dim + index;
+    // tokens are taken a block at a time, so that the state is loaded and stored once for each batch in the block
+    for (var t0 = 0u; t0 < shape[2]; t0 += TOKEN_BLOCK) {
+        let count = min(TOKEN_BLOCK, shape[2] - t0);
 
         workgroupBarrier();
-        shared_k[in.tid.x] = k[bti];
-        shared_r[in.tid.x] = r[bti];
+        if index < dim {
+            for (var i = 0u; i < count; i += 1u) {
+                let bti = (t0 + i) * dim + index;
+                shared_k[i][in.tid.x] = load_k(bti);
+                shared_r[i][in.tid.x] = load_r(bti);
+            }
+        }
         workgroupBarrier();
 
-        let vv = v[bti];
-        var y = vec4<f32>(0.0);
+        if index >= dim {
+            continue;
+        }
+
+        var batch: array<u32, TOKEN_BLOCK>;
+        var vv: array<vec4<f32>, TOKEN_BLOCK>;
+        var y = array<vec4<f32>, TOKEN_BLOCK>();
+        for (var i = 0u; i < count; i += 1u) {
+            let t = t0 + i;
+            let cursor = compute_cursor(cursors[t]);
+            if t == cursor.token {
+                // no output of this block is stored yet, so the input of the last token is still there
+                store_state(compute_index(cursor.batch, 0u, index), load_x((cursor.token + cursor.len - 1u) * dim + index));
+            }
+            batch[i] = cursor.batch;
+            vv[i] = load_v(t * dim + index);
+        }
+
         for (var j = 0u; j < stride; j += 1u) {
-            let kk = shared_k[h + j];
-            let rr = shared_r[h + j];
             let uu = shared_u[h + j];
             let ww = shared_w[h + j];
 
             var ss: array<vec4<f32>, 4>;
             var kv: array<vec4<f32>, 4>;
+            var bji = 0u;
+
+            for (var i = 0u; i < count; i += 1u) {
+                if i == 0u || batch[i] != batch[i - 1u] {
+                    if i > 0u {
+                        store_state(bji + dim * 0u, ss[0]);
+                        store_state(bji + dim * 1u, ss[1]);
+                        store_state(bji + dim * 2u, ss[2]);
+                        store_state(bji + dim * 3u, ss[3]);
+                    }
+                    bji = compute_index(batch[i], j * 4u + 1u, index);
+                    ss[0] = load_state(bji + dim * 0u);
+                    ss[1] = load_state(bji + dim * 1u);
+                    ss[2] = load_state(bji + dim * 2u);
+                    ss[3] = load_state(bji + dim * 3u);
+                }
+
+                let kk = shared_k[i][h + j];
+                let rr = shared_r[i][h + j];
+
+                kv[0] = kk[0] * vv[i];
+                kv[1] = kk[1] * vv[i];
+                kv[2] = kk[2] * vv[i];
+                kv[3] = kk[3] * vv[i];
+
+                y[i] += rr[0] * fma(vec4<f32>(uu[0]), kv[0], ss[0]);
+                y[i] += rr[1] * fma(vec4<f32>(uu[1]), kv[1], ss[1]);
+                y[i] += rr[2] * fma(vec4<f32>(uu[2]), kv[2], ss[2]);
+                y[i] += rr[3] * fma(vec4<f32>(uu[3]), kv[3], ss[3]);
+
+                ss[0] = fma(vec4<f32>(ww[0]), ss[0], kv[0]);
+                ss[1] = fma(vec4<f32>(ww[1]), ss[1], kv[1]);
+                ss[2] = fma(vec4<f32>(ww[2]), ss[2], kv[2]);
+                ss[3] = fma(vec4<f32>(ww[3]), ss[3], kv[3]);
+            }
+
+            store_state(bji + dim * 0u, ss[0]);
+            store_state(bji + dim * 1u, ss[1]);
+            store_state(bji + dim * 2u, ss[2]);
+            store_state(bji + dim * 3u, ss[3]);
+        }
 
-            let bji = compute_index(cursor.batch, j * 4u + 1u, index);
-
-            ss[0] = state[bji + dim * 0u];
-            ss[1] = state[bji + dim * 1u];
-            ss[2] = state[bji + dim * 2u];
-            ss[3] = state[bji + dim * 3u];
-
-            kv[0] = kk[0] * vv;
-            kv[1] = kk[1] * vv;
-            kv[2] = kk[2] * vv;
-            kv[3] = kk[3] * vv;
-
-            y += rr[0] * fma(vec4<f32>(uu[0]), kv[0], ss[0]);
-            y += rr[1] * fma(vec4<f32>(uu[1]), kv[1], ss[1]);
-            y += rr[2] * fma(vec4<f32>(uu[2]), kv[2], ss[2]);
-            y += rr[3] * fma(vec4<f32>(uu[3]), kv[3], ss[3]);
-
-            state[bji + dim * 0u] = fma(vec4<f32>(ww[0]), ss[0], kv[0]);
-            state[bji + dim * 1u] = fma(vec4<f32>(ww[1]), ss[1], kv[1]);
-            state[bji + dim * 2u] = fma(vec4<f32>(ww[2]), ss[2], kv[2]);
-            state[bji + dim * 3u] = fma(vec4<f32>(ww[3]), ss[3], kv[3]);
+        for (var i = 0u; i < count; i += 1u) {
+            store_x((t0 + i) * dim + index, y[i]);
         }
-        x[bti] = y;
     }
-}
\ No newline at end of file
+}
@@ -1,7 +1,8 @@
 struct View {
     stride: vec4<u32>,
     offset: vec4<u32>,
-    shape: vec4<u32>,  
+    shape: vec4<u32>,
+    step: vec4<u32>,
 };
 
 struct Cursor {
@@ -15,16 +16,28 @@ struct Cursor {
 @group(0) @binding(2) var<storage, read> cursors: array<u32>;               // [A]
 
 @group(0) @binding(3) var<storage, read> time_mix: array<vec2<u32>>;        // (C)
+#ifdef ACT_F16
+@group(0) @binding(4) var<storage, read> x: array<vec2<u32>>;               // (1, A, C)
+#else
 @group(0) @binding(4) var<storage, read> x: array<vec4<f32>>;               // (1, A, C)
+#endif
+#ifdef STATE_F16
+@group(0) @binding(5) var<storage, read> sx: array<vec2<u32>>;              // (B, 1, C)
+#else
 @group(0) @binding(5) var<storage, read> sx: array<vec4<f32>>;              // (B, 1, C)
+#endif
+#ifdef ACT_F16
+@group(0) @binding(6) var<storage, read_write> output: array<vec2<u32>>;    // (1, A, C)
+#else
 @group(0) @binding(6) var<storage, read_write> output: array<vec4<f32>>;    // (1, A, C)
+#endif
 
 const BLOCK_SIZE: u32 = 128u;
 
 fn compute_index(batch: u32, token: u32, index: u32) -> u32 {
     let stride = view.stride.x / 4u;
     let offset = view.offset.x / 4u;
-    return ((view.offset.z + batch) * view.stride.y + view.offset.y + token) * stride + offset + index;
+    return ((view.offset.z + batch * view.step.z) * view.stride.y + view.offset.y + token * view.step.y) * stride + offset + index;
 }
 
 fn compute_cursor(x: u32) -> Cursor {
@@ -40,6 +53,34 @@ fn unpack4x16float(x: vec2<u32>) -> vec4<f32> {
     return vec4<f32>(unpack2x16float(x.x), unpack2x16float(x.y));
 }
 
+fn pack4x16float(x: vec4<f32>) -> vec2<u32> {
+    return vec2<u32>(pack2x16float(x.xy), pack2x16float(x.zw));
+}
+
+fn load_x(index: u32) -> vec4<f32> {
+#ifdef ACT_F16
+    return unpack4x16float(x[index]);
+#else
+    return x[index];
+#endif
+}
+
+fn store_output(index: u32, value: vec4<f32>) {
+#ifdef ACT_F16
+    output[index] = pack4x16float(value);
+#else
+    output[index] = value;
+#endif
+}
+
+fn load_sx(index: u32) -> vec4<f32> {
+#ifdef STATE_F16
+    return unpack4x16float(sx[index]);
+#else
+    return sx[index];
+#endif
+}
+
 @compute @workgroup_size(128, 1, 1)
 fn token_shift(@builtin(global_invocation_id) invocation_id: vec3<u32>, @builtin(num_workgroups) num_blocks: vec3<u32>) {
     let stride = shape[0] / 4u;
@@ -54,8 +95,8 @@ fn token_shift(@builtin(global_invocation_id) invocation_id: vec3<u32>, @builtin
 
     let bti = stack * stride + index;
     if token == 0u {
-        output[bti] = mix(sx[compute_index(cursor.batch, 0u, index)], x[bti], unpack4x16float(time_mix[index]));
+        store_output(bti, mix(load_sx(compute_index(cursor.batch, 0u, index)), load_x(bti), unpack4x16float(time_mix[index])));
     } else {
-        output[bti] = mix(x[bti - stride], x[bti], unpack4x16float(time_mix[index]));
+        store_output(bti, mix(load_x(bti - stride), load_x(bti), unpack4x16float(time_mix[index])));
     }
 }
\ No newline at end of file
@@ -0,0 +1,77 @@
+@group(0) @binding(0) var<uniform> shape: vec4<u32>;                        // [C, T, B]
+
+@group(0) @binding(1) var<storage, read> x: array<vec4<f32>>;               // (B, T, C)
+@group(0) @binding(2) var<storage, read> tokens: array<u32>;                // (B, T)
+@group(0) @binding(3) var<storage, read_write> output: array<u32>;          // (B, 2)
+
+const BLOCK_SIZE: u32 = 128u;
+
+var<workgroup> sketch: array<f32, BLOCK_SIZE>;
+var<workgroup> sketch_index: array<u32, BLOCK_SIZE>;
+
+fn reduce_argmax(index: u32, stride: u32) {
+    if index < stride {
+        let value = sketch[index + stride];
+        let position = sketch_index[index + stride];
+        if value > sketch[index] || (value == sketch[index] && position < sketch_index[index]) {
+            sketch[index] = value;
+            sketch_index[index] = position;
+        }
+    }
+    workgroupBarrier();
+}
+
+@compute @workgroup_size(128, 1, 1)
+fn verify(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+    let stride = shape[0] / 4u;
+    let index = invocation_id.x;
+    let batch = invocation_id.z;
+
+    var accepted = 0u;
+    var choice = 0u;
+    var rejected = false;
+
+    // every position is reduced to keep the barriers in uniform control flow, but only the first mismatch counts
+    for (var token = 0u; token < shape[1]; token += 1u) {
+        let bb = (batch * shape[1] + token) * stride;
+
+        var maximum = bitcast<f32>(0xff800000u);
+        var position = 0u;
+        for (var i = index; i < stride; i += BLOCK_SIZE) {
+            var value = x[bb + i];
+            for (var j = 0u; j < 4u; j += 1u) {
+                if value[j] > maximum {
+                    maximum = value[j];
+                    position = i * 4u + j;
+                }
+            }
+        }
+        sketch[index] = maximum;
+        sketch_index[index] = position;
+        workgroupBarrier();
+
+        reduce_argmax(index, 64u);
+        reduce_argmax(index, 32u);
+        reduce_argmax(index, 16u);
+        reduce_argmax(index, 8u);
+        reduce_argmax(index, 4u);
+        reduce_argmax(index, 2u);
+        reduce_argmax(index, 1u);
+
+        if !rejected {
+            choice = sketch_index[0];
+            let next = batch * shape[1] + token + 1u;
+            if token + 1u < shape[1] && tokens[next] == choice {
+                accepted += 1u;
+            } else {
+                rejected = true;
+            }
+        }
+        workgroupBarrier();
+    }
+
+    if index == 0u {
+        output[batch * 2u] = accepted;
+        output[batch * 2u + 1u] = choice;
+    }
+}
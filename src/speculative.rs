@@ -0,0 +1,230 @@
+//! Self-speculative decoding: drafting tokens with the first layers of a model and verifying them with all of them.
+
+use std::ops::Range;
+
+use anyhow::Result;
+use itertools::Itertools;
+
+use crate::model::{Model, ModelError, ModelState};
+
+fn argmax(logits: &[f32]) -> u16 {
+    logits
+        .iter()
+        .position_min_by(|x, y| y.total_cmp(x))
+        .unwrap_or_default() as u16
+}
+
+/// Greedy decoding of one batch of a state, several tokens per full pass.
+///
+/// Each round drafts up to `num_draft` tokens with [`Model::run_layers`] on a copy of the batch,
+/// then runs them all through the full model at once with [`Model::verify`], which checks them on the GPU.
+/// Drafts are kept up to the first one the full model disagrees with, which it replaces,
+/// so the output is the same as greedy decoding with the full model, only with fewer full passes.
+///
+/// The `scratch` state must have the same shape as `state`. It holds the draft, and then a copy of the batch
+/// to roll back to when a draft is rejected.
+pub struct SelfSpeculative<'a, M: Model> {
+    model: &'a M,
+    state: &'a M::ModelState,
+    scratch: &'a M::ModelState,
+    batch: usize,
+    layers: Range<usize>,
+    num_draft: usize,
+    /// The last generated token, which hasn't been run through `state` yet.
+    next: Option<u16>,
+    drafted: usize,
+    accepted: usize,
+}
+
+impl<'a, M: Model> SelfSpeculative<'a, M> {
+    /// Drafts 4 tokens at a time with the first half of the layers, on batch 0.
+    pub fn new(model: &'a M, state: &'a M::ModelState, scratch: &'a M::ModelState) -> Self {
+        let layers = 0..(model.info().num_layer / 2).max(1);
+        Self {
+            model,
+            state,
+            scratch,
+            batch: 0,
+            layers,
+            num_draft: 4,
+            next: None,
+            drafted: 0,
+            accepted: 0,
+        }
+    }
+
+    pub fn with_batch(self, batch: usize) -> Self {
+        Self { batch, ..self }
+    }
+
+    /// Layers of the truncated pass that drafts tokens.
+    pub fn with_layers(self, layers: Range<usize>) -> Self {
+        Self { layers, ..self }
+    }
+
+    /// Drafts per round. The next token and the drafts must fit in a token chunk of the model.
+    pub fn with_num_draft(self, num_draft: usize) -> Self {
+        Self { num_draft, ..self }
+    }
+
+    /// Fraction of drafted tokens the full model agreed with so far.
+    pub fn acceptance(&self) -> f32 {
+        match self.drafted {
+            0 => 0.0,
+            drafted => self.accepted as f32 / drafted as f32,
+        }
+    }
+
+    /// Feed `prompt` after what was generated before, then greedily generate `count` tokens.
+    /// Nothing is generated if there is neither a prompt nor an earlier output to continue from.
+    pub fn generate(&mut self, prompt: &[u16], count: usize) -> Result<Vec<u16>> {
+        let max = self.state.max_batch();
+        if self.batch >= max {
+            let batch = self.batch;
+            return Err(ModelError::BatchOutOfRange { batch, max }.into());
+        }
+
+        let input = self.next.iter().chain(prompt).copied().collect_vec();
+        if count == 0 || input.is_empty() {
+            return Ok(vec![]);
+        }
+        let logits = self.run(self.state, input, self.full_layers())?;
+        let mut output = vec![argmax(&logits)];
+
+        while output.len() < count {
+            let next = output[output.len() - 1];
+            let num_draft = self.num_draft.min(count - output.len() - 1);
+
+            self.state
+                .blit_batch(self.scratch, self.batch, self.batch)?;
+            let mut draft = Vec::with_capacity(num_draft);
+            let mut token = next;
+            for _ in 0..num_draft {
+                let logits = self.run(self.scratch, vec![token], self.layers.clone())?;
+                token = argmax(&logits);
+                draft.push(token);
+            }
+
+            // back up the batch, then verify all the drafts in one pass
+            self.state
+                .blit_batch(self.scratch, self.batch, self.batch)?;
+            let input = [next]
+                .into_iter()
+                .chain(draft.iter().copied())
+                .collect_vec();
+            let (accepted, token) = self.model.verify(&input, self.batch, self.state)?;
+            output.extend_from_slice(&draft[..accepted]);
+            output.push(token);
+
+            // the state has run past the first rejected draft, so redo it from the backup
+            if accepted < num_draft {
+                self.scratch
+                    .blit_batch(self.state, self.batch, self.batch)?;
+                self.run(self.state, input[..=accepted].to_vec(), self.full_layers())?;
+            }
+
+            self.drafted += num_draft;
+            self.accepted += accepted;
+        }
+
+        self.next = output.last().copied();
+        Ok(output)
+    }
+
+    fn full_layers(&self) -> Range<usize> {
+        0..self.model.info().num_layer
+    }
+
+    fn tokens(&self, input: Vec<u16>) -> Vec<Vec<u16>> {
+        let mut tokens = vec![vec![]; self.state.max_batch()];
+        tokens[self.batch] = input;
+        tokens
+    }
+
+    /// Run `input` on the batch through `layers`, returning the logits after the last token.
+    fn run(
+        &self,
+        state: &M::ModelState,
+        input: Vec<u16>,
+        layers: Range<usize>,
+    ) -> Result<Vec<f32>> {
+        let mut tokens = self.tokens(input);
+        let mut logits = vec![];
+        while !tokens[self.batch].is_empty() {
+            let output = self.model.run_layers(&mut tokens, state, layers.clone())?;
+            if let Some(output) = output.into_iter().nth(self.batch).flatten() {
+                logits = output;
+            }
+        }
+        Ok(logits)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use super::{argmax, SelfSpeculative};
+    use crate::model::{
+        synthetic::{testing::create_context, SyntheticBuilder},
+        v5, Model, ModelBuilder, ModelVersion, StateBuilder,
+    };
+
+    #[test]
+    fn test_self_speculative() -> Result<()> {
+        let context = match create_context() {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+
+        let builder = SyntheticBuilder::new(ModelVersion::V5).with_num_layer(4);
+        let info = builder.info();
+        let data = builder.build()?;
+        let model: v5::Model = ModelBuilder::new(&context, &data)
+            .with_head_chunk_size(info.num_vocab)
+            .with_token_chunk_size(8)
+            .build()?;
+
+        let prompt = [12u16, 55, 8, 91, 200];
+        let state: v5::ModelState = StateBuilder::new(&context, &info).with_max_batch(2).build();
+        let mut tokens = vec![vec![], prompt.to_vec()];
+        let mut expected = vec![];
+        while expected.len() < 12 {
+            if let Some(logits) = model.run(&mut tokens, &state)?[1].take() {
+                let token = argmax(&logits);
+                expected.push(token);
+                tokens = vec![vec![], vec![token]];
+            }
+        }
+
+        // a rough draft from the first layer still gives the output of the full model
+        let state: v5::ModelState = StateBuilder::new(&context, &info).with_max_batch(2).build();
+        let scratch: v5::ModelState = StateBuilder::new(&context, &info).with_max_batch(2).build();
+        let mut speculative = SelfSpeculative::new(&model, &state, &scratch)
+            .with_batch(1)
+            .with_layers(0..1)
+            .with_num_draft(3);
+        let mut output = speculative.generate(&prompt, 7)?;
+        output.extend(speculative.generate(&[], 5)?);
+        assert_eq!(output, expected);
+
+        // drafting with every layer is always right
+        let state: v5::ModelState = StateBuilder::new(&context, &info).with_max_batch(2).build();
+        let mut speculative = SelfSpeculative::new(&model, &state, &scratch)
+            .with_batch(1)
+            .with_layers(0..info.num_layer)
+            .with_num_draft(5);
+        assert_eq!(speculative.generate(&prompt, 12)?, expected);
+        assert_eq!(speculative.acceptance(), 1.0);
+
+        assert!(model
+            .run_layers(&mut vec![vec![1], vec![]], &state, 2..2)
+            .is_err());
+        assert!(model
+            .run_layers(&mut vec![vec![1], vec![]], &state, 0..5)
+            .is_err());
+        assert!(model.verify(&[1; 9], 1, &state).is_err());
+        assert!(model.verify(&[1], 2, &state).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,263 @@
+//! Prompts built from a template, with variables, few-shot examples and a token budget.
+
+use std::collections::HashMap;
+
+use crate::tokenizer::{Tokenizer, TokenizerError};
+
+#[derive(Debug)]
+pub enum TemplateError {
+    /// A `{` at this byte offset has no matching `}`.
+    Unclosed(usize),
+    /// A `}` at this byte offset has no matching `{`; write `}}` for a literal one.
+    Unopened(usize),
+    /// The variable isn't given a value.
+    Missing(String),
+    /// Even without any example, the prompt takes more tokens than allowed.
+    TooLong {
+        tokens: usize,
+        max: usize,
+    },
+    Tokenizer(TokenizerError),
+}
+
+impl std::fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TemplateError::Unclosed(offset) => write!(f, "unclosed brace at {offset}"),
+            TemplateError::Unopened(offset) => write!(f, "unopened brace at {offset}"),
+            TemplateError::Missing(name) => write!(f, "missing variable: {name}"),
+            TemplateError::TooLong { tokens, max } => {
+                write!(f, "prompt too long: {tokens} tokens, max {max}")
+            }
+            TemplateError::Tokenizer(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl std::error::Error for TemplateError {}
+
+impl From<TokenizerError> for TemplateError {
+    fn from(value: TokenizerError) -> Self {
+        Self::Tokenizer(value)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    Text(String),
+    Variable(String),
+}
+
+/// A prompt with `{name}` slots for variables, e.g. `"User: {input}\n\nAssistant:"`.
+/// Braces are written `{{` and `}}` outside of slots.
+///
+/// The slot `{examples}` is special: it holds the few-shot examples, each rendered with the template
+/// given to [`Template::with_examples`], and is dropped from the end when the prompt is over budget.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Template {
+    parts: Vec<Part>,
+    example: Option<Box<Template>>,
+    separator: String,
+}
+
+impl Template {
+    pub const EXAMPLES: &'static str = "examples";
+
+    pub fn new(source: &str) -> Result<Self, TemplateError> {
+        let mut parts = vec![];
+        let mut text = String::new();
+        let mut chars = source.char_indices().peekable();
+        while let Some((offset, char)) = chars.next() {
+            match char {
+                '{' if chars.next_if(|&(_, next)| next == '{').is_some() => text.push('{'),
+                '}' if chars.next_if(|&(_, next)| next == '}').is_some() => text.push('}'),
+                '{' => {
+                    let start = offset + 1;
+                    let end = source[start..]
+                        .find('}')
+                        .ok_or(TemplateError::Unclosed(offset))?;
+                    if !text.is_empty() {
+                        parts.push(Part::Text(std::mem::take(&mut text)));
+                    }
+                    let name = source[start..start + end].trim().to_owned();
+                    parts.push(Part::Variable(name));
+                    while chars.next_if(|&(index, _)| index <= start + end).is_some() {}
+                }
+                '}' => return Err(TemplateError::Unopened(offset)),
+                char => text.push(char),
+            }
+        }
+        if !text.is_empty() {
+            parts.push(Part::Text(text));
+        }
+        Ok(Self {
+            parts,
+            example: None,
+            separator: String::new(),
+        })
+    }
+
+    /// Render each few-shot example with `example` in the `{examples}` slot, with `separator` between them.
+    pub fn with_examples(self, example: Template, separator: impl Into<String>) -> Self {
+        Self {
+            example: Some(Box::new(example)),
+            separator: separator.into(),
+            ..self
+        }
+    }
+
+    /// Names of the variables, in order of first appearance, not counting `{examples}`.
+    pub fn variables(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = vec![];
+        for part in &self.parts {
+            match part {
+                Part::Variable(name) if name == Self::EXAMPLES => {}
+                Part::Variable(name) if !names.contains(&name.as_str()) => names.push(name),
+                _ => {}
+            }
+        }
+        names
+    }
+
+    /// Fill in the variables and the examples, each of which gives the variables of the example template.
+    pub fn render(
+        &self,
+        variables: &HashMap<&str, &str>,
+        examples: &[HashMap<&str, &str>],
+    ) -> Result<String, TemplateError> {
+        let mut output = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Text(text) => output.push_str(text),
+                Part::Variable(name) if name == Self::EXAMPLES => {
+                    let Some(example) = &self.example else {
+                        return Err(TemplateError::Missing(name.clone()));
+                    };
+                    for (index, variables) in examples.iter().enumerate() {
+                        if index > 0 {
+                            output.push_str(&self.separator);
+                        }
+                        output.push_str(&example.render(variables, &[])?);
+                    }
+                }
+                Part::Variable(name) => match variables.get(name.as_str()) {
+                    Some(value) => output.push_str(value),
+                    None => return Err(TemplateError::Missing(name.clone())),
+                },
+            }
+        }
+        Ok(output)
+    }
+
+    /// Render and tokenize the prompt within `max_tokens`, keeping as many of the leading examples as fit.
+    pub fn encode(
+        &self,
+        tokenizer: &Tokenizer,
+        variables: &HashMap<&str, &str>,
+        examples: &[HashMap<&str, &str>],
+        max_tokens: usize,
+    ) -> Result<Vec<u16>, TemplateError> {
+        // fewer examples never take more tokens, so the most that fit are found by bisection
+        let encode = |count: usize| -> Result<Vec<u16>, TemplateError> {
+            let prompt = self.render(variables, &examples[..count])?;
+            Ok(tokenizer.encode(prompt.as_bytes())?)
+        };
+        let tokens = encode(examples.len())?;
+        if tokens.len() <= max_tokens {
+            return Ok(tokens);
+        }
+        let tokens = encode(0)?;
+        if tokens.len() > max_tokens {
+            return Err(TemplateError::TooLong {
+                tokens: tokens.len(),
+                max: max_tokens,
+            });
+        }
+        let (mut fit, mut tokens, mut over) = (0, tokens, examples.len());
+        while over - fit > 1 {
+            let count = (fit + over) / 2;
+            let encoded = encode(count)?;
+            match encoded.len() <= max_tokens {
+                true => (fit, tokens) = (count, encoded),
+                false => over = count,
+            }
+        }
+        Ok(tokens)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use anyhow::Result;
+
+    use super::{Template, TemplateError};
+    use crate::tokenizer::Tokenizer;
+
+    /// A vocabulary of one token per printable ASCII character.
+    fn tokenizer() -> Result<Tokenizer> {
+        let vocab = (b' '..=b'~')
+            .map(|byte| (byte.to_string(), (byte as char).to_string()))
+            .chain([("10".to_owned(), "\n".to_owned())])
+            .collect::<HashMap<_, _>>();
+        Ok(Tokenizer::new(&serde_json::to_string(&vocab)?)?)
+    }
+
+    #[test]
+    fn test_template() -> Result<()> {
+        let template = Template::new("{examples}\n\nQ: {input} {{}}\nA:")?
+            .with_examples(Template::new("Q: {q}\nA: {a}")?, "\n\n");
+        assert_eq!(template.variables(), ["input"]);
+
+        let variables = HashMap::from([("input", "1+1")]);
+        let examples = [
+            HashMap::from([("q", "2+2"), ("a", "4")]),
+            HashMap::from([("q", "3+3"), ("a", "6")]),
+        ];
+        assert_eq!(
+            template.render(&variables, &examples)?,
+            "Q: 2+2\nA: 4\n\nQ: 3+3\nA: 6\n\nQ: 1+1 {}\nA:"
+        );
+        assert!(matches!(
+            template.render(&HashMap::new(), &examples),
+            Err(TemplateError::Missing(name)) if name == "input"
+        ));
+
+        assert!(matches!(
+            Template::new("{input"),
+            Err(TemplateError::Unclosed(0))
+        ));
+        assert!(matches!(
+            Template::new("a}"),
+            Err(TemplateError::Unopened(1))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_budget() -> Result<()> {
+        let tokenizer = tokenizer()?;
+        let template =
+            Template::new("{examples}|{input}")?.with_examples(Template::new("{q}={a}")?, ",");
+        let variables = HashMap::from([("input", "x")]);
+        let examples = [
+            HashMap::from([("q", "a"), ("a", "1")]),
+            HashMap::from([("q", "b"), ("a", "2")]),
+            HashMap::from([("q", "c"), ("a", "3")]),
+        ];
+
+        let all = template.encode(&tokenizer, &variables, &examples, 64)?;
+        assert_eq!(tokenizer.decode(&all)?, b"a=1,b=2,c=3|x");
+
+        // the last examples are dropped to fit
+        let tokens = template.encode(&tokenizer, &variables, &examples, 9)?;
+        assert_eq!(tokenizer.decode(&tokens)?, b"a=1,b=2|x");
+
+        assert!(matches!(
+            template.encode(&tokenizer, &variables, &examples, 1),
+            Err(TemplateError::TooLong { tokens: 2, max: 1 })
+        ));
+        Ok(())
+    }
+}
@@ -0,0 +1,204 @@
+//! Sub-allocation of small tensors from shared buffers.
+
+use std::{
+    marker::PhantomData,
+    sync::{Arc, Mutex},
+};
+
+use wgpu::{Buffer, BufferDescriptor};
+
+use super::{
+    shape::Shape, Kind, ReadWrite, TensorBuffer, TensorCpu, TensorError, TensorGpu, TensorInit,
+    TensorShape,
+};
+use crate::{context::Context, num::Scalar};
+
+/// Places small, long-lived tensors, e.g. the per-layer vectors of a model, side by side in a few large buffers,
+/// instead of creating one buffer for each of them.
+///
+/// Tensors are bound at their offsets and keep their chunk alive.
+/// Space is never reused, so the arena is for tensors that live as long as the model.
+///
+/// The arena is for weights that are only read once loaded. A dispatch that writes a tensor of the arena
+/// must not bind any other tensor of it, since wgpu rejects a buffer bound as both read-only and writable storage;
+/// this is why loading only writes arena tensors from tensors with buffers of their own.
+#[derive(Debug)]
+pub struct TensorArena {
+    context: Context,
+    /// The chunk being filled and the end of its used part.
+    chunk: Mutex<Option<(Arc<Buffer>, u64)>>,
+}
+
+impl TensorArena {
+    /// Size of each shared buffer.
+    pub const CHUNK_SIZE: u64 = 4 << 20;
+    /// Tensors larger than this get buffers of their own.
+    pub const MAX_TENSOR_SIZE: u64 = 64 << 10;
+
+    pub fn new(context: &Context) -> Self {
+        Self {
+            context: context.clone(),
+            chunk: Mutex::new(None),
+        }
+    }
+
+    /// The buffer and offset of a new tensor of `size` bytes.
+    fn allocate(&self, size: u64) -> (Arc<Buffer>, u64) {
+        let alignment = self
+            .context
+            .device
+            .limits()
+            .min_storage_buffer_offset_alignment as u64;
+        // leave room for clearing the tensor, which works in whole words
+        let size = size.next_multiple_of(wgpu::COPY_BUFFER_ALIGNMENT);
+
+        let mut chunk = self.chunk.lock().unwrap();
+        if let Some((buffer, end)) = chunk.as_mut() {
+            let offset = end.next_multiple_of(alignment);
+            if offset + size <= Self::CHUNK_SIZE {
+                *end = offset + size;
+                return (buffer.clone(), offset);
+            }
+        }
+
+        let buffer: Arc<Buffer> = self
+            .context
+            .device
+            .create_buffer(&BufferDescriptor {
+                label: Some("tensor arena"),
+                size: Self::CHUNK_SIZE,
+                usage: ReadWrite::buffer_usages(),
+                mapped_at_creation: false,
+            })
+            .into();
+        self.context.track(&buffer);
+        *chunk = Some((buffer.clone(), size));
+        (buffer, 0)
+    }
+
+    /// Create a zeroed tensor, in a shared buffer if it is small enough.
+    pub fn tensor_init<T: Scalar>(&self, shape: Shape) -> TensorGpu<T, ReadWrite> {
+        let size = (shape.len() * T::size()) as u64;
+        if size == 0 || size > Self::MAX_TENSOR_SIZE {
+            return TensorGpu::init(&self.context, shape);
+        }
+
+        let (buffer, offset) = self.allocate(size);
+        TensorGpu {
+            context: self.context.clone(),
+            shape,
+            data: TensorBuffer {
+                meta: self.context.request_shape_uniform(shape),
+                buffer,
+                offset,
+            },
+            phantom: PhantomData,
+        }
+    }
+
+    /// Upload `host`, in a shared buffer if it is small enough.
+    pub fn upload<T: Scalar>(
+        &self,
+        host: &TensorCpu<T>,
+    ) -> Result<TensorGpu<T, ReadWrite>, TensorError> {
+        let tensor = self.tensor_init(host.shape());
+        tensor.load(host)?;
+        Ok(tensor)
+    }
+
+    /// Copy `tensor`, e.g. one on another context, into the arena through the host.
+    pub fn transfer<T: Scalar>(
+        &self,
+        tensor: &TensorGpu<T, ReadWrite>,
+    ) -> Result<TensorGpu<T, ReadWrite>, TensorError> {
+        self.upload(&tensor.back())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use wgpu::{CommandEncoderDescriptor, ComputePassDescriptor, PowerPreference};
+
+    use super::TensorArena;
+    use crate::{
+        context::{Context, ContextBuilder, Instance},
+        tensor::{
+            ops::{TensorCommand, TensorOp, TensorPass},
+            shape::Shape,
+            ReadWrite, TensorCpu, TensorGpu, TensorInit,
+        },
+    };
+
+    fn create_context() -> Result<Context, anyhow::Error> {
+        let adapter = pollster::block_on(async {
+            let instance = Instance::new();
+            instance.adapter(PowerPreference::HighPerformance).await
+        })?;
+        let context = pollster::block_on(async { ContextBuilder::new(adapter).build().await })?;
+        Ok(context)
+    }
+
+    #[test]
+    fn test_arena() -> Result<(), anyhow::Error> {
+        let context = match create_context() {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+        let arena = TensorArena::new(&context);
+        let alignment = context.device.limits().min_storage_buffer_offset_alignment as u64;
+
+        let data: Vec<Vec<f32>> = (0..4)
+            .map(|index| (0..36).map(|x| (index * 100 + x) as f32).collect())
+            .collect();
+        let tensors: Vec<_> = data
+            .iter()
+            .map(|data| {
+                let host = TensorCpu::from_data(&context, Shape::new(36, 1, 1, 1), data)?;
+                arena.upload(&host)
+            })
+            .collect::<Result<_, _>>()?;
+
+        for (index, tensor) in tensors.iter().enumerate() {
+            assert!(Arc::ptr_eq(&tensor.buffer, &tensors[0].buffer));
+            assert_eq!(tensor.offset % alignment, 0);
+            if index > 0 {
+                assert!(tensor.offset > tensors[index - 1].offset);
+            }
+        }
+
+        // ops on one tensor must leave its neighbours alone;
+        // the source has its own buffer, as an op may not read and write the same arena
+        let source: TensorGpu<f32, ReadWrite> =
+            TensorGpu::from_data(&context, Shape::new(36, 1, 1, 1), &data[0])?;
+        let op = TensorOp::blit(
+            source.view(.., .., .., ..)?,
+            tensors[1].view(.., .., .., ..)?,
+        )?;
+        let mut encoder = context
+            .device
+            .create_command_encoder(&CommandEncoderDescriptor::default());
+        let mut pass = encoder.begin_compute_pass(&ComputePassDescriptor::default());
+        pass.execute_tensor_op(&op);
+        drop(pass);
+        encoder.clear_tensor(&tensors[2]);
+        context.queue.submit(Some(encoder.finish()));
+
+        let expected = [
+            data[0].clone(),
+            data[0].clone(),
+            vec![0.0; 36],
+            data[3].clone(),
+        ];
+        for (tensor, expected) in tensors.iter().zip(expected) {
+            assert_eq!(tensor.back().to_vec(), expected);
+        }
+
+        let large = arena.tensor_init::<f32>(Shape::new(1 << 16, 1, 1, 1));
+        assert_eq!(large.offset, 0);
+        assert!(!Arc::ptr_eq(&large.buffer, &tensors[0].buffer));
+
+        Ok(())
+    }
+}
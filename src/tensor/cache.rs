@@ -1,14 +1,64 @@
 use std::{
     collections::HashMap,
+    convert::Infallible,
     hash::Hash,
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
 };
 
+/// Requests served by a cache so far, and the entries it holds now.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: usize,
+}
+
+impl CacheStats {
+    /// Fraction of requests served from the cache, or 0 if there were none.
+    pub fn hit_rate(&self) -> f32 {
+        match self.hits + self.misses {
+            0 => 0.0,
+            total => self.hits as f32 / total as f32,
+        }
+    }
+}
+
+/// Hit and miss counters, for caches keeping their entries elsewhere.
+#[derive(Debug, Default)]
+pub struct CacheCounter {
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl CacheCounter {
+    #[inline]
+    pub fn hit(&self) {
+        self.hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn miss(&self) {
+        self.misses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn stats(&self, entries: usize) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            entries,
+        }
+    }
+}
+
 #[allow(clippy::type_complexity)]
 #[derive(Debug)]
 pub struct ResourceCache<K, V> {
     max_count: usize,
     map: Mutex<HashMap<K, (Arc<V>, usize)>>,
+    counter: CacheCounter,
 }
 
 impl<K, V> Default for ResourceCache<K, V> {
@@ -16,6 +66,7 @@ impl<K, V> Default for ResourceCache<K, V> {
         Self {
             max_count: 16,
             map: Default::default(),
+            counter: Default::default(),
         }
     }
 }
@@ -28,12 +79,30 @@ where
         Self {
             max_count,
             map: Default::default(),
+            counter: Default::default(),
         }
     }
 
     pub fn request(&self, key: K, f: impl FnOnce() -> V) -> Arc<V> {
+        match self.try_request(key, || Ok::<_, Infallible>(f())) {
+            Ok(value) => value,
+            Err(err) => match err {},
+        }
+    }
+
+    /// Like [`ResourceCache::request`], but with a fallible constructor; nothing is cached if it fails.
+    pub fn try_request<E>(&self, key: K, f: impl FnOnce() -> Result<V, E>) -> Result<Arc<V>, E> {
         let mut map = self.map.lock().unwrap();
-        let (value, _) = map.remove(&key).unwrap_or_else(|| (Arc::new(f()), 0));
+        let value = match map.remove(&key) {
+            Some((value, _)) => {
+                self.counter.hit();
+                value
+            }
+            None => {
+                self.counter.miss();
+                Arc::new(f()?)
+            }
+        };
         map.insert(key, (value.clone(), 0));
         if self.max_count > 0 {
             map.retain(|_, (_, count)| {
@@ -41,6 +110,46 @@ where
                 *count <= self.max_count
             });
         }
-        value
+        Ok(value)
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.counter.stats(self.map.lock().unwrap().len())
+    }
+
+    /// Drop all entries. Values still held elsewhere stay alive until released; the counters are kept.
+    pub fn clear(&self) {
+        self.map.lock().unwrap().clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CacheStats, ResourceCache};
+
+    #[test]
+    fn test_cache_stats() {
+        let cache = ResourceCache::new(2);
+        assert_eq!(*cache.request(1, || "a"), "a");
+        assert_eq!(*cache.request(1, || "b"), "a");
+        cache.request(2, || "c");
+        cache.request(3, || "d");
+
+        // the least recently requested entry is evicted past the limit
+        let stats = cache.stats();
+        assert_eq!(
+            stats,
+            CacheStats {
+                hits: 1,
+                misses: 3,
+                entries: 2
+            }
+        );
+        assert_eq!(stats.hit_rate(), 0.25);
+
+        cache.clear();
+        assert_eq!(cache.stats().entries, 0);
+        assert_eq!(*cache.request(1, || "e"), "e");
+        assert_eq!(cache.stats().misses, 4);
     }
 }
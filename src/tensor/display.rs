@@ -0,0 +1,163 @@
+//! Printing tensors in a summarized form, with only the items at the edges of long axes.
+
+use std::fmt;
+
+use super::{shape::Shape, TensorCpu};
+use crate::num::Scalar;
+
+/// A [`TensorCpu`] formatted with its own options; see [`TensorCpu::display`].
+///
+/// Formatter precision applies to each item, e.g., `format!("{:.3}", tensor.display())`.
+#[derive(Debug, Clone)]
+pub struct TensorDisplay<'a, 'b, T: Scalar> {
+    tensor: &'a TensorCpu<'b, T>,
+    edge_items: [usize; 4],
+    threshold: usize,
+}
+
+impl<'a, 'b, T: Scalar> TensorDisplay<'a, 'b, T> {
+    /// Tensors with more items than this are summarized.
+    pub const THRESHOLD: usize = 1000;
+    /// Items kept at each end of a summarized axis.
+    pub const EDGE_ITEMS: usize = 3;
+
+    pub fn new(tensor: &'a TensorCpu<'b, T>) -> Self {
+        Self {
+            tensor,
+            edge_items: [Self::EDGE_ITEMS; 4],
+            threshold: Self::THRESHOLD,
+        }
+    }
+
+    /// Keep this many items at each end of every summarized axis.
+    pub fn with_edge_items(self, edge_items: usize) -> Self {
+        Self {
+            edge_items: [edge_items; 4],
+            ..self
+        }
+    }
+
+    /// Keep this many items at each end of `axis` when summarized, e.g., more along the fastest-moving one.
+    pub fn with_axis_edge_items(mut self, axis: usize, edge_items: usize) -> Self {
+        self.edge_items[axis] = edge_items;
+        self
+    }
+
+    /// Summarize tensors with more than this many items. Set to `usize::MAX` to print all of them.
+    pub fn with_threshold(self, threshold: usize) -> Self {
+        Self { threshold, ..self }
+    }
+
+    /// Indices of the items printed along `axis`, with `None` where the rest are left out.
+    fn indices(&self, axis: usize) -> Vec<Option<usize>> {
+        let dim = self.tensor.shape[axis];
+        let edge = self.edge_items[axis];
+        match self.tensor.shape.len() > self.threshold && dim > 2 * edge {
+            true => (0..edge)
+                .map(Some)
+                .chain([None])
+                .chain((dim - edge..dim).map(Some))
+                .collect(),
+            false => (0..dim).map(Some).collect(),
+        }
+    }
+
+    fn fmt_axis(&self, f: &mut fmt::Formatter<'_>, axis: usize, mut index: Shape) -> fmt::Result
+    where
+        T: fmt::Display,
+    {
+        write!(f, "[")?;
+        for (position, item) in self.indices(axis).into_iter().enumerate() {
+            if position > 0 {
+                match axis {
+                    0 => write!(f, ", ")?,
+                    // inner axes start on a new line, aligned below the brackets they are in
+                    _ => write!(f, ",{}{:indent$}", "\n".repeat(axis), "", indent = 4 - axis)?,
+                }
+            }
+            match (axis, item) {
+                (_, None) => write!(f, "...")?,
+                (0, Some(item)) => {
+                    index[0] = item;
+                    let value = self.tensor[(index[0], index[1], index[2], index[3])];
+                    match f.precision() {
+                        Some(precision) => write!(f, "{value:.precision$}")?,
+                        None => write!(f, "{value}")?,
+                    }
+                }
+                (_, Some(item)) => {
+                    index[axis] = item;
+                    self.fmt_axis(f, axis - 1, index)?;
+                }
+            }
+        }
+        write!(f, "]")
+    }
+}
+
+impl<T: Scalar + fmt::Display> fmt::Display for TensorDisplay<'_, '_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Tensor {:?} {}", T::DATA_TYPE, self.tensor.shape)?;
+        self.fmt_axis(f, 3, Shape::default())
+    }
+}
+
+impl<T: Scalar + fmt::Display> fmt::Display for TensorCpu<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&TensorDisplay::new(self), f)
+    }
+}
+
+impl<'b, T: Scalar> TensorCpu<'b, T> {
+    /// Format the tensor with options other than the defaults of its [`Display`](fmt::Display).
+    pub fn display(&self) -> TensorDisplay<'_, 'b, T> {
+        TensorDisplay::new(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use wgpu::PowerPreference;
+
+    use crate::{
+        context::{Context, ContextBuilder, Instance},
+        tensor::{shape::Shape, TensorCpu, TensorInit},
+    };
+
+    fn create_context() -> Result<Context, anyhow::Error> {
+        let adapter = pollster::block_on(async {
+            let instance = Instance::new();
+            instance.adapter(PowerPreference::HighPerformance).await
+        })?;
+        let context = pollster::block_on(async { ContextBuilder::new(adapter).build().await })?;
+        Ok(context)
+    }
+
+    #[test]
+    fn test_display() -> Result<(), anyhow::Error> {
+        let context = match create_context() {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+
+        let shape = Shape::new(3, 2, 1, 1);
+        let x: Vec<_> = (0..shape.len()).map(|x| x as f32).collect();
+        let x = TensorCpu::from_data(&context, shape, x)?;
+        assert_eq!(
+            format!("{x:.1}"),
+            "Tensor F32 (3, 2, 1, 1)\n[[[[0.0, 1.0, 2.0],\n   [3.0, 4.0, 5.0]]]]"
+        );
+
+        let shape = Shape::new(8, 4, 1, 1);
+        let x: Vec<_> = (0..shape.len() as u32).collect();
+        let x = TensorCpu::from_data(&context, shape, x)?;
+        let display = x.display().with_threshold(16).with_edge_items(1);
+        assert_eq!(
+            format!("{display}"),
+            "Tensor U32 (8, 4, 1, 1)\n[[[[0, ..., 7],\n   ...,\n   [24, ..., 31]]]]"
+        );
+        let display = display.with_axis_edge_items(0, 2);
+        assert!(format!("{display}").contains("[24, 25, ..., 30, 31]"));
+        Ok(())
+    }
+}
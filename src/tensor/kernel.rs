@@ -0,0 +1,512 @@
+//! User-written compute kernels.
+//!
+//! A [`Kernel`] is compiled from WGSL at runtime by [`Context::register_kernel`].
+//! Its bindings are reflected from the shader, so that resources can be bound by variable name
+//! instead of hand-writing bind group layouts.
+
+use std::{borrow::Cow, collections::BTreeMap, sync::Arc};
+
+use itertools::Itertools;
+use naga::{
+    proc::Layouter,
+    valid::{Capabilities, ModuleInfo, ValidationFlags, Validator},
+    AddressSpace, EntryPoint, Module, ShaderStage, StorageAccess,
+};
+use wgpu::{
+    BindGroupDescriptor, BindGroupEntry, BindGroupLayout, BindGroupLayoutDescriptor,
+    BindGroupLayoutEntry, BindingResource, BindingType, BufferBindingType, BufferSize,
+    BufferUsages, ComputePipeline, ComputePipelineDescriptor, PipelineLayoutDescriptor,
+    ShaderModuleDescriptor, ShaderSource, ShaderStages,
+};
+
+use super::ops::TensorOp;
+use crate::context::Context;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KernelBindingType {
+    Uniform,
+    Storage { read_only: bool },
+}
+
+/// A resource binding of a kernel, as declared in its shader.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KernelBinding {
+    pub name: String,
+    pub group: u32,
+    pub binding: u32,
+    pub ty: KernelBindingType,
+    /// Minimum size in bytes of the bound buffer. For runtime-sized arrays, this counts one element.
+    pub min_size: u64,
+}
+
+impl KernelBinding {
+    /// The layout entry of this binding, as it would be declared by hand.
+    pub fn layout_entry(&self) -> BindGroupLayoutEntry {
+        let ty = match self.ty {
+            KernelBindingType::Uniform => BufferBindingType::Uniform,
+            KernelBindingType::Storage { read_only } => BufferBindingType::Storage { read_only },
+        };
+        BindGroupLayoutEntry {
+            binding: self.binding,
+            visibility: ShaderStages::COMPUTE,
+            ty: BindingType::Buffer {
+                ty,
+                has_dynamic_offset: false,
+                min_binding_size: BufferSize::new(self.min_size),
+            },
+            count: None,
+        }
+    }
+
+    /// Check that `resource` is a buffer that can be bound here.
+    pub fn validate(&self, resource: &BindingResource) -> Result<(), KernelError> {
+        let BindingResource::Buffer(binding) = resource else {
+            return Err(KernelError::BindingResource(self.name.clone()));
+        };
+        let usage = match self.ty {
+            KernelBindingType::Uniform => BufferUsages::UNIFORM,
+            KernelBindingType::Storage { .. } => BufferUsages::STORAGE,
+        };
+        if !binding.buffer.usage().contains(usage) {
+            return Err(KernelError::BindingUsage {
+                name: self.name.clone(),
+                ty: self.ty,
+            });
+        }
+        let size = match binding.size {
+            Some(size) => size.get(),
+            None => binding.buffer.size().saturating_sub(binding.offset),
+        };
+        if size < self.min_size {
+            return Err(KernelError::BindingSize {
+                name: self.name.clone(),
+                size,
+                min: self.min_size,
+            });
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct Kernel {
+    name: String,
+    pipeline: Arc<ComputePipeline>,
+    layouts: Vec<BindGroupLayout>,
+    bindings: Vec<KernelBinding>,
+    workgroup_size: [u32; 3],
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KernelError {
+    Parse(String),
+    Validate(String),
+    Layout(String),
+    EntryPoint(String),
+    Unsupported(String),
+    UnknownBinding(String),
+    MissingBinding(String),
+    BindingResource(String),
+    BindingUsage { name: String, ty: KernelBindingType },
+    BindingSize { name: String, size: u64, min: u64 },
+}
+
+impl std::fmt::Display for KernelError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KernelError::Parse(error) => write!(f, "failed to parse kernel: {error}"),
+            KernelError::Validate(error) => write!(f, "failed to validate kernel: {error}"),
+            KernelError::Layout(error) => write!(f, "failed to lay out kernel types: {error}"),
+            KernelError::EntryPoint(name) => write!(f, "compute entry point {name} not found"),
+            KernelError::Unsupported(name) => {
+                write!(
+                    f,
+                    "binding {name} is neither a uniform nor a storage buffer"
+                )
+            }
+            KernelError::UnknownBinding(name) => write!(f, "kernel has no binding named {name}"),
+            KernelError::MissingBinding(name) => write!(f, "binding {name} is not provided"),
+            KernelError::BindingResource(name) => write!(f, "binding {name} must be a buffer"),
+            KernelError::BindingUsage { name, ty } => {
+                write!(
+                    f,
+                    "buffer bound to {name} lacks the usage of a {ty:?} binding"
+                )
+            }
+            KernelError::BindingSize { name, size, min } => write!(
+                f,
+                "buffer bound to {name} is {size} bytes, less than the minimum {min}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for KernelError {}
+
+/// Parse and validate a WGSL module.
+pub(crate) fn parse_wgsl(shader: &str) -> Result<(Module, ModuleInfo), KernelError> {
+    let module = naga::front::wgsl::parse_str(shader)
+        .map_err(|err| KernelError::Parse(err.emit_to_string(shader)))?;
+    let info = Validator::new(ValidationFlags::all(), Capabilities::all())
+        .validate(&module)
+        .map_err(|err| KernelError::Validate(err.emit_to_string(shader)))?;
+    Ok((module, info))
+}
+
+/// Find a compute entry point and reflect the bindings it uses, ordered by group and binding.
+fn reflect<'a>(
+    module: &'a Module,
+    info: &ModuleInfo,
+    entry_point: &str,
+) -> Result<(&'a EntryPoint, Vec<KernelBinding>), KernelError> {
+    let (index, entry) = module
+        .entry_points
+        .iter()
+        .enumerate()
+        .find(|(_, entry)| entry.stage == ShaderStage::Compute && entry.name == entry_point)
+        .ok_or_else(|| KernelError::EntryPoint(entry_point.into()))?;
+    let uses = info.get_entry_point(index);
+
+    let mut layouter = Layouter::default();
+    layouter
+        .update(module.to_ctx())
+        .map_err(|err| KernelError::Layout(err.to_string()))?;
+
+    let mut bindings = vec![];
+    for (handle, var) in module.global_variables.iter() {
+        let (Some(binding), false) = (&var.binding, uses[handle].is_empty()) else {
+            continue;
+        };
+        let name = var.name.clone().unwrap_or_default();
+        let ty = match var.space {
+            AddressSpace::Uniform => KernelBindingType::Uniform,
+            AddressSpace::Storage { access } => KernelBindingType::Storage {
+                read_only: !access.contains(StorageAccess::STORE),
+            },
+            _ => return Err(KernelError::Unsupported(name)),
+        };
+        bindings.push(KernelBinding {
+            name,
+            group: binding.group,
+            binding: binding.binding,
+            ty,
+            min_size: layouter[var.ty].size as u64,
+        });
+    }
+    bindings.sort_by_key(|binding| (binding.group, binding.binding));
+    Ok((entry, bindings))
+}
+
+/// Reflect the bindings used by `entry_point` of a WGSL shader, ordered by group and binding.
+/// Their [`layout_entry`](KernelBinding::layout_entry) can be used to build bind group layouts.
+pub fn reflect_bindings(
+    shader: &str,
+    entry_point: &str,
+) -> Result<Vec<KernelBinding>, KernelError> {
+    let (module, info) = parse_wgsl(shader)?;
+    let (_, bindings) = reflect(&module, &info, entry_point)?;
+    Ok(bindings)
+}
+
+impl Context {
+    /// Compile a user-written WGSL compute kernel.
+    ///
+    /// The pipeline layout is derived from the bindings used by `entry_point`,
+    /// which resources are later validated against.
+    pub fn register_kernel(
+        &self,
+        name: &str,
+        shader: &str,
+        entry_point: &str,
+    ) -> Result<Kernel, KernelError> {
+        let (module, info) = parse_wgsl(shader)?;
+        let (entry, bindings) = reflect(&module, &info, entry_point)?;
+
+        let count = bindings.last().map_or(0, |binding| binding.group + 1);
+        let layouts = (0..count)
+            .map(|group| {
+                let entries = bindings
+                    .iter()
+                    .filter(|binding| binding.group == group)
+                    .map(KernelBinding::layout_entry)
+                    .collect_vec();
+                self.device
+                    .create_bind_group_layout(&BindGroupLayoutDescriptor {
+                        label: Some(name),
+                        entries: &entries,
+                    })
+            })
+            .collect_vec();
+        let layout = self
+            .device
+            .create_pipeline_layout(&PipelineLayoutDescriptor {
+                label: Some(name),
+                bind_group_layouts: &layouts.iter().collect_vec(),
+                push_constant_ranges: &[],
+            });
+
+        let module = self.device.create_shader_module(ShaderModuleDescriptor {
+            label: Some(name),
+            source: ShaderSource::Wgsl(Cow::Borrowed(shader)),
+        });
+        let pipeline = self
+            .device
+            .create_compute_pipeline(&ComputePipelineDescriptor {
+                label: Some(name),
+                layout: Some(&layout),
+                module: &module,
+                entry_point,
+            })
+            .into();
+
+        Ok(Kernel {
+            name: name.into(),
+            pipeline,
+            layouts,
+            bindings,
+            workgroup_size: entry.workgroup_size,
+        })
+    }
+}
+
+impl Kernel {
+    #[inline]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Bindings used by the entry point, ordered by group and binding.
+    #[inline]
+    pub fn bindings(&self) -> &[KernelBinding] {
+        &self.bindings
+    }
+
+    #[inline]
+    pub fn workgroup_size(&self) -> [u32; 3] {
+        self.workgroup_size
+    }
+
+    /// Bind resources to the kernel by the names of their variables in the shader.
+    /// Every binding the entry point uses must be provided, with a buffer of the right usage and size.
+    ///
+    /// Tensors are bound with [`TensorGpu::binding`](super::TensorGpu::binding) for their data
+    /// and [`TensorView::meta_binding`](super::TensorView::meta_binding) for their views.
+    pub fn op(
+        &self,
+        context: &Context,
+        resources: &[(&str, BindingResource)],
+        dispatch: [u32; 3],
+    ) -> Result<TensorOp, KernelError> {
+        if let Some((name, _)) = resources
+            .iter()
+            .find(|(name, _)| !self.bindings.iter().any(|binding| binding.name == *name))
+        {
+            return Err(KernelError::UnknownBinding(name.to_string()));
+        }
+
+        let mut groups: BTreeMap<u32, Vec<BindGroupEntry>> = BTreeMap::new();
+        for binding in &self.bindings {
+            let resource = resources
+                .iter()
+                .find(|(name, _)| *name == binding.name)
+                .map(|(_, resource)| resource.clone())
+                .ok_or_else(|| KernelError::MissingBinding(binding.name.clone()))?;
+            binding.validate(&resource)?;
+            groups
+                .entry(binding.group)
+                .or_default()
+                .push(BindGroupEntry {
+                    binding: binding.binding,
+                    resource,
+                });
+        }
+
+        let bindings = self
+            .layouts
+            .iter()
+            .enumerate()
+            .map(|(group, layout)| {
+                context.device.create_bind_group(&BindGroupDescriptor {
+                    label: Some(&self.name),
+                    layout,
+                    entries: groups.get(&(group as u32)).map_or(&[], Vec::as_slice),
+                })
+            })
+            .collect();
+
+        Ok(TensorOp::Atom {
+            pipeline: self.pipeline.clone(),
+            bindings,
+            dispatch,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use itertools::Itertools;
+    use wgpu::{BindingType, BufferBindingType, BufferSize, PowerPreference};
+
+    use super::{reflect_bindings, KernelBindingType, KernelError};
+    use crate::{
+        context::{Context, ContextBuilder, Instance},
+        tensor::{
+            ops::{TensorCommand, TensorPass},
+            shape::Shape,
+            ReadBack, ReadWrite, TensorCpu, TensorGpu, TensorInit, Uniform,
+        },
+    };
+
+    fn create_context() -> Result<Context> {
+        let adapter = pollster::block_on(async {
+            let instance = Instance::new();
+            instance.adapter(PowerPreference::HighPerformance).await
+        })?;
+        let context = pollster::block_on(async { ContextBuilder::new(adapter).build().await })?;
+        Ok(context)
+    }
+
+    const SHADER: &str = "
+        @group(0) @binding(0) var<uniform> factor: vec4<f32>;
+        @group(0) @binding(1) var<storage, read> input: array<f32>;
+        @group(0) @binding(2) var<storage, read_write> output: array<f32>;
+        @group(0) @binding(3) var<storage, read_write> unused: array<f32>;
+
+        @compute @workgroup_size(64, 1, 1)
+        fn scale(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+            let index = invocation_id.x;
+            if index < arrayLength(&output) {
+                output[index] = input[index] * factor.x;
+            }
+        }
+    ";
+
+    #[test]
+    fn test_reflect_bindings() -> Result<()> {
+        let bindings = reflect_bindings(SHADER, "scale")?;
+        let sizes = bindings
+            .iter()
+            .map(|binding| (binding.name.as_str(), binding.binding, binding.min_size))
+            .collect_vec();
+        assert_eq!(
+            sizes,
+            vec![("factor", 0, 16), ("input", 1, 4), ("output", 2, 4)]
+        );
+
+        let entry = bindings[0].layout_entry();
+        assert_eq!(
+            entry.ty,
+            BindingType::Buffer {
+                ty: BufferBindingType::Uniform,
+                has_dynamic_offset: false,
+                min_binding_size: BufferSize::new(16),
+            }
+        );
+        assert!(matches!(
+            reflect_bindings("fn broken(", "scale"),
+            Err(KernelError::Parse(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_kernel() -> Result<()> {
+        let context = match create_context() {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+
+        let kernel = context.register_kernel("scale", SHADER, "scale")?;
+        assert_eq!(kernel.workgroup_size(), [64, 1, 1]);
+        let bindings: Vec<_> = kernel
+            .bindings()
+            .iter()
+            .map(|binding| (binding.name.as_str(), binding.ty))
+            .collect();
+        assert_eq!(
+            bindings,
+            vec![
+                ("factor", KernelBindingType::Uniform),
+                ("input", KernelBindingType::Storage { read_only: true }),
+                ("output", KernelBindingType::Storage { read_only: false }),
+            ]
+        );
+        assert!(matches!(
+            context.register_kernel("scale", SHADER, "missing"),
+            Err(KernelError::EntryPoint(_))
+        ));
+
+        let shape = Shape::new(100, 1, 1, 1);
+        let data = (0..100).map(|x| x as f32).collect::<Vec<_>>();
+        let input: TensorGpu<f32, ReadWrite> = context.tensor_from_data(shape, data.clone())?;
+        let output: TensorGpu<f32, ReadWrite> = context.tensor_init(shape);
+        let factor: TensorGpu<f32, Uniform> =
+            TensorGpu::from_data(&context, Shape::new(4, 1, 1, 1), &[2.0, 0.0, 0.0, 0.0])?;
+
+        assert!(matches!(
+            kernel.op(&context, &[("input", input.binding())], [2, 1, 1]),
+            Err(KernelError::MissingBinding(_))
+        ));
+        assert!(matches!(
+            kernel.op(
+                &context,
+                &[
+                    ("factor", input.binding()),
+                    ("input", input.binding()),
+                    ("output", output.binding()),
+                ],
+                [2, 1, 1],
+            ),
+            Err(KernelError::BindingUsage { .. })
+        ));
+        let small: TensorGpu<f32, Uniform> =
+            TensorGpu::from_data(&context, Shape::new(2, 1, 1, 1), &[2.0, 0.0])?;
+        assert_eq!(
+            kernel
+                .op(
+                    &context,
+                    &[
+                        ("factor", small.binding()),
+                        ("input", input.binding()),
+                        ("output", output.binding()),
+                    ],
+                    [2, 1, 1],
+                )
+                .err(),
+            Some(KernelError::BindingSize {
+                name: "factor".into(),
+                size: 8,
+                min: 16
+            })
+        );
+
+        let op = kernel.op(
+            &context,
+            &[
+                ("factor", factor.binding()),
+                ("input", input.binding()),
+                ("output", output.binding()),
+            ],
+            [2, 1, 1],
+        )?;
+
+        let mut encoder = context
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor::default());
+        let mut pass = encoder.begin_compute_pass(&wgpu::ComputePassDescriptor::default());
+        pass.execute_tensor_op(&op);
+        drop(pass);
+
+        let map = TensorGpu::<f32, ReadBack>::init(&context, shape);
+        encoder.copy_tensor(&output, &map)?;
+        context.queue.submit(Some(encoder.finish()));
+
+        let output = TensorCpu::from(map).to_vec();
+        for (x, y) in data.iter().zip(output.iter()) {
+            assert_eq!(x * 2.0, *y);
+        }
+        assert_eq!(output.len(), shape.len());
+        Ok(())
+    }
+}
@@ -1,9 +1,14 @@
 use std::{
     borrow::Cow,
     marker::PhantomData,
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    task::Waker,
 };
 
+use half::f16;
 use itertools::Itertools;
 use web_rwkv_derive::Kind;
 use wgpu::{
@@ -13,18 +18,24 @@ use wgpu::{
 };
 
 use crate::{context::Context, num::Scalar};
-use shape::{IntoBytes, Shape, TensorDimension, TensorSlice};
+use shape::{IntoBytes, Shape, TensorDimension, TensorSlice, UniformLayout};
 
-use self::{ops::TensorCommand, shape::TensorAxis};
+use self::{ops::TensorCommand, random::TensorRandom, shape::TensorAxis};
 
+pub mod arena;
 pub mod cache;
+pub mod display;
+pub mod kernel;
 pub mod ops;
+pub mod random;
 pub mod shape;
 
 #[derive(Debug, Clone)]
 pub struct TensorBuffer {
     pub meta: Arc<Buffer>,
     pub buffer: Arc<Buffer>,
+    /// Where the tensor starts in `buffer`, which other tensors may share, see [`arena::TensorArena`].
+    pub offset: u64,
 }
 
 impl TensorBuffer {
@@ -41,7 +52,7 @@ impl TensorBuffer {
     pub fn binding(&self) -> BindingResource {
         BindingResource::Buffer(BufferBinding {
             buffer: &self.buffer,
-            offset: 0,
+            offset: self.offset,
             size: None,
         })
     }
@@ -71,7 +82,7 @@ pub trait Kind: sealed::Sealed {
 
 /// Tensor is a uniform buffer.
 #[derive(Debug, Kind)]
-#[usage(UNIFORM, COPY_DST)]
+#[usage(UNIFORM, COPY_DST, COPY_SRC)]
 pub struct Uniform;
 
 /// Tensor is a storage buffer with can be copied to other buffers.
@@ -90,6 +101,7 @@ pub enum TensorError {
     Type,
     Size(usize, usize),
     Shape(Shape, Shape),
+    Broadcast(Shape, Shape),
     Deduce,
     BatchOutOfRange {
         batch: usize,
@@ -100,7 +112,12 @@ pub enum TensorError {
         start: usize,
         end: usize,
     },
+    IndexOutOfRange {
+        dim: usize,
+        index: isize,
+    },
     Contiguous,
+    Step(usize),
     Pipeline(&'static str),
 }
 
@@ -111,6 +128,9 @@ impl std::fmt::Display for TensorError {
             TensorError::Type => write!(f, "data type mismatch"),
             TensorError::Size(a, b) => write!(f, "data size not match: {a} vs. {b}"),
             TensorError::Shape(a, b) => write!(f, "tensor shape {a} doesn't match {b}"),
+            TensorError::Broadcast(a, b) => {
+                write!(f, "tensor shapes {a} and {b} cannot be broadcast together")
+            }
             TensorError::Deduce => write!(f, "cannot deduce dimension"),
             TensorError::BatchOutOfRange { batch, max } => {
                 write!(f, "batch {batch} out of range of max {max}")
@@ -119,7 +139,11 @@ impl std::fmt::Display for TensorError {
                 f,
                 "slice {start}..{end} out of range for dimension size {dim}",
             ),
+            TensorError::IndexOutOfRange { dim, index } => {
+                write!(f, "index {index} out of range for dimension size {dim}")
+            }
             TensorError::Contiguous => write!(f, "slice not contiguous"),
+            TensorError::Step(step) => write!(f, "invalid slice step {step}"),
             TensorError::Pipeline(name) => write!(f, "pipeline {name} not found"),
         }
     }
@@ -127,21 +151,38 @@ impl std::fmt::Display for TensorError {
 
 impl std::error::Error for TensorError {}
 
-#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, IntoBytes)]
 pub struct View {
     pub stride: Shape,
     pub offset: Shape,
     pub shape: Shape,
+    pub step: Shape,
 }
 
-impl IntoBytes for View {
-    fn into_bytes(self) -> Vec<u8> {
-        [
-            self.stride.into_bytes(),
-            self.offset.into_bytes(),
-            self.shape.into_bytes(),
-        ]
-        .concat()
+impl View {
+    /// Apply a slice on top of this view, composing offsets and steps into a view of the same tensor.
+    fn compose(&self, slice: impl TensorSlice) -> Result<Self, TensorError> {
+        let (start, _) = slice.shape_bounds(self.shape)?;
+        let steps = slice.shape_steps();
+
+        let mut offset = self.offset;
+        let mut step = self.step;
+        for index in 0..4 {
+            offset[index] += start[index] * self.step[index];
+            step[index] *= steps[index];
+        }
+
+        // items along the fastest axis are packed into vectors in kernels, so they can't be strided
+        if step[0] != 1 {
+            return Err(TensorError::Step(step[0]));
+        }
+
+        Ok(Self {
+            stride: self.stride,
+            offset,
+            shape: slice.sliced_shape(self.shape)?,
+            step,
+        })
     }
 }
 
@@ -205,14 +246,7 @@ pub trait TensorInit<'a, T: Scalar>: Sized {
         if tensor.dtype() != T::DATA_TYPE {
             return Err(TensorError::Type);
         }
-        let shape = match *tensor.shape() {
-            [] => Shape::new(0, 0, 0, 0),
-            [x] => Shape::new(x, 1, 1, 1),
-            [y, x] => Shape::new(x, y, 1, 1),
-            [z, y, x] => Shape::new(x, y, z, 1),
-            [w, z, y, x] => Shape::new(x, y, z, w),
-            _ => return Err(TensorError::Deduce),
-        };
+        let shape = Shape::from_safetensors(tensor.shape())?;
         Self::from_data(context, shape, bytemuck::cast_slice(tensor.data()))
     }
 }
@@ -225,6 +259,13 @@ pub trait TensorShape: Sized {
             .then_some(())
             .ok_or(TensorError::Shape(self.shape(), shape))
     }
+
+    /// Like [`TensorShape::check_shape`], but only in debug builds, for shapes fixed once checked,
+    /// e.g. those of buffers allocated for the shape they are used with.
+    #[inline]
+    fn debug_check_shape(&self, shape: Shape) {
+        debug_assert_eq!(self.shape(), shape);
+    }
 }
 
 pub trait TensorReshape: Sized {
@@ -373,6 +414,7 @@ impl<'a, T: Scalar, K: Kind> TensorInit<'a, T> for TensorGpu<T, K> {
                 mapped_at_creation: false,
             })
             .into();
+        context.track(&buffer);
 
         Self {
             context: context.clone(),
@@ -380,6 +422,7 @@ impl<'a, T: Scalar, K: Kind> TensorInit<'a, T> for TensorGpu<T, K> {
             data: TensorBuffer {
                 meta: context.request_shape_uniform(shape),
                 buffer,
+                offset: 0,
             },
             phantom: PhantomData,
         }
@@ -408,7 +451,7 @@ impl<T: Scalar, K: Kind> TensorReshape for TensorGpu<T, K> {
             shape,
             data: TensorBuffer {
                 meta,
-                buffer: self.data.buffer.clone(),
+                ..self.data.clone()
             },
             ..self.clone()
         })
@@ -433,11 +476,16 @@ impl<T: Scalar, K: Kind> From<TensorCpu<'_, T>> for TensorGpu<T, K> {
                 usage: K::buffer_usages(),
             })
             .into();
+        context.track(&buffer);
 
         Self {
             context,
             shape,
-            data: TensorBuffer { meta, buffer },
+            data: TensorBuffer {
+                meta,
+                buffer,
+                offset: 0,
+            },
             phantom: PhantomData,
         }
     }
@@ -452,7 +500,9 @@ impl<T: Scalar> From<TensorGpu<T, ReadBack>> for TensorCpu<'_, T> {
             ..
         } = value;
 
-        let slice = buffer.slice(..);
+        // the buffer may be larger than the tensor, see `TensorGpu::shrink`
+        let size = (shape.len() * T::size()) as u64;
+        let slice = buffer.slice(..size);
         slice.map_async(MapMode::Read, |_| ());
 
         context.device.poll(wgpu::MaintainBase::Wait);
@@ -475,9 +525,11 @@ impl<T: Scalar> From<TensorGpu<T, ReadBack>> for TensorCpu<'_, T> {
 impl<T: Scalar, K: Kind> TensorGpu<T, K> {
     pub fn load(&self, host: &TensorCpu<T>) -> Result<(), TensorError> {
         host.check_shape(self.shape)?;
-        self.context
-            .queue
-            .write_buffer(&self.buffer, 0, bytemuck::cast_slice(&host.data[..]));
+        self.context.write_buffer(
+            &self.buffer,
+            self.offset,
+            bytemuck::cast_slice(&host.data[..]),
+        );
         Ok(())
     }
 
@@ -489,15 +541,150 @@ impl<T: Scalar, K: Kind> TensorGpu<T, K> {
                 max: self.shape[2],
             });
         }
-        let offset = (T::size() * self.shape[0] * self.shape[1] * batch) as u64;
+        self.load_slice(host, .., .., batch, ..)
+    }
+
+    /// Write the host tensor into a contiguous slice of this tensor.
+    pub fn load_slice(
+        &self,
+        host: &TensorCpu<'_, T>,
+        x: impl TensorAxis,
+        y: impl TensorAxis,
+        z: impl TensorAxis,
+        w: impl TensorAxis,
+    ) -> Result<(), TensorError> {
+        let slice = (x, y, z, w);
+        host.check_shape(slice.sliced_shape(self.shape)?)?;
+        let (start, _) = slice.contiguous_bounds(self.shape)?;
+        let offset = self.offset + (T::size() * start) as u64;
         self.context
-            .queue
             .write_buffer(&self.buffer, offset, bytemuck::cast_slice(&host.data[..]));
         Ok(())
     }
 
-    pub fn destroy(self) {
-        self.buffer.destroy();
+    /// Create a tensor and have `fill` write its bytes into the mapped buffer in place,
+    /// e.g. straight from a memory-mapped or streamed checkpoint, with no host copy in between.
+    /// The bytes need not be aligned for `T`.
+    pub fn init_with<E>(
+        context: &Context,
+        shape: Shape,
+        fill: impl FnOnce(&mut [u8]) -> Result<(), E>,
+    ) -> Result<Self, E> {
+        let size = (shape.len() * T::size()) as u64;
+        let buffer: Arc<Buffer> = context
+            .device
+            .create_buffer(&BufferDescriptor {
+                label: None,
+                size: size.next_multiple_of(wgpu::COPY_BUFFER_ALIGNMENT),
+                usage: K::buffer_usages(),
+                mapped_at_creation: true,
+            })
+            .into();
+        {
+            let mut data = buffer.slice(..).get_mapped_range_mut();
+            fill(&mut data[..size as usize])?;
+        }
+        buffer.unmap();
+        context.track(&buffer);
+
+        Ok(Self {
+            context: context.clone(),
+            shape,
+            data: TensorBuffer {
+                meta: context.request_shape_uniform(shape),
+                buffer,
+                offset: 0,
+            },
+            phantom: PhantomData,
+        })
+    }
+
+    /// A tensor of `shape` over the front of this one's buffer, which must have room for it,
+    /// e.g. to run a smaller shape in buffers allocated for the largest one.
+    pub fn shrink(&self, shape: Shape) -> Result<Self, TensorError> {
+        if shape.len() > self.shape.len() {
+            return Err(TensorError::Size(shape.len(), self.shape.len()));
+        }
+        let meta = self.context.request_shape_uniform(shape);
+        Ok(Self {
+            shape,
+            data: TensorBuffer {
+                meta,
+                ..self.data.clone()
+            },
+            ..self.clone()
+        })
+    }
+
+    /// Free the device memory of the tensor now instead of when the driver gets to it.
+    ///
+    /// Clones and reshapes of the tensor share its buffer; while any of them is alive the buffer is left
+    /// to be freed when the last of them drops, so that they are never left pointing at destroyed memory.
+    /// Returns whether the buffer was destroyed.
+    pub fn destroy(self) -> bool {
+        match Arc::try_unwrap(self.data.buffer) {
+            Ok(buffer) => {
+                buffer.destroy();
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// Copy the tensor into a new [`ReadBack`] one and submit the copy.
+    /// Only tensors that can be copied from, i.e. not [`ReadBack`] ones, may be read.
+    fn copy_back(&self) -> TensorGpu<T, ReadBack> {
+        let map: TensorGpu<T, ReadBack> = self.context.tensor_init(self.shape);
+        let mut encoder = self
+            .context
+            .device
+            .create_command_encoder(&CommandEncoderDescriptor::default());
+        let size = self.size() as u64;
+        encoder.copy_buffer_to_buffer(&self.buffer, self.offset, &map.buffer, 0, size);
+        self.context.queue.submit(Some(encoder.finish()));
+        map
+    }
+}
+
+impl<T: Scalar> TensorGpu<T, Uniform> {
+    /// Copy the uniform to another context, e.g. one on another adapter, through the host.
+    pub fn transfer(&self, to: &Context) -> Result<Self, TensorError> {
+        Self::from_data(to, self.shape, Vec::from(TensorCpu::from(self.copy_back())))
+    }
+
+    /// Create a uniform holding a parameter struct, whose size must be a multiple of that of `T`.
+    pub fn from_params<P: bytemuck::Pod>(
+        context: &Context,
+        params: &P,
+    ) -> Result<Self, TensorError> {
+        let size = std::mem::size_of::<P>();
+        if size == 0 || !size.is_multiple_of(T::size()) {
+            return Err(TensorError::Type);
+        }
+        let data: Vec<T> = bytemuck::pod_collect_to_vec(bytemuck::bytes_of(params));
+        let shape = Shape::new(data.len(), 1, 1, 1);
+        Self::from_data(context, shape, data)
+    }
+
+    /// Overwrite the uniform in place, so that ops already bound to it read the new data on their next submission.
+    pub fn update(&self, data: &[T]) -> Result<(), TensorError> {
+        if data.len() != self.shape.len() {
+            return Err(TensorError::Size(data.len(), self.shape.len()));
+        }
+        self.context
+            .write_buffer(&self.buffer, 0, bytemuck::cast_slice(data));
+        Ok(())
+    }
+
+    /// Overwrite the uniform in place with a parameter struct of exactly the same size.
+    pub fn update_params<P: bytemuck::Pod>(&self, params: &P) -> Result<(), TensorError> {
+        let data = bytemuck::bytes_of(params);
+        let size = self.shape.len() * T::size();
+        if data.len() != size {
+            return Err(TensorError::Size(data.len(), size));
+        }
+        self.context.write_buffer(&self.buffer, 0, data);
+        Ok(())
     }
 }
 
@@ -559,6 +746,35 @@ impl<'a, T: Scalar> TensorCpu<'a, T> {
         }
     }
 
+    /// Each batch of a `[C, T, B]` tensor in order, as a `[C, T, 1]` tensor borrowing from this one.
+    pub fn iter_batches<'b>(&'b self) -> impl Iterator<Item = TensorCpu<'b, T>> + 'b {
+        let shape = Shape::new(self.shape[0], self.shape[1], 1, 1);
+        self.iter_chunks(shape, self.shape[2] * self.shape[3])
+    }
+
+    /// Each token of each batch of a `[C, T, B]` tensor in order, as a `[C, 1, 1]` tensor borrowing from this one.
+    pub fn iter_tokens<'b>(&'b self) -> impl Iterator<Item = TensorCpu<'b, T>> + 'b {
+        let shape = Shape::new(self.shape[0], 1, 1, 1);
+        self.iter_chunks(shape, self.shape[1] * self.shape[2] * self.shape[3])
+    }
+
+    fn iter_chunks<'b>(
+        &'b self,
+        shape: Shape,
+        count: usize,
+    ) -> impl Iterator<Item = TensorCpu<'b, T>> + 'b {
+        // borrow the data alone, so that the iterator doesn't capture the lifetime of the tensor's own data
+        let context = self.context.clone();
+        let data: &'b [T] = &self.data;
+        let len = shape.len();
+        (0..count).map(move |index| TensorCpu {
+            context: context.clone(),
+            shape,
+            data: Cow::Borrowed(&data[index * len..(index + 1) * len]),
+            phantom: PhantomData,
+        })
+    }
+
     /// Split the tensor along the highest plural axis.
     pub fn split(self, axis: usize) -> Result<Vec<Self>, TensorError> {
         match axis {
@@ -580,14 +796,26 @@ impl<'a, T: Scalar> TensorCpu<'a, T> {
 
     /// Concat a batch of tensors.
     pub fn stack(batches: Vec<Self>) -> Result<Self, TensorError> {
-        let (context, mut shape) = match batches.first() {
-            Some(batch) => (batch.context.clone(), batch.shape),
+        let shape = match batches.first() {
+            Some(batch) => batch.shape,
             None => return Err(TensorError::Empty),
         };
-
         batches.iter().try_for_each(|batch| {
             batch.check_shape(Shape::new(shape[0], shape[1], batch.shape[2], 1))
         })?;
+        Self::stack_unchecked(batches)
+    }
+
+    /// Like [`TensorCpu::stack`], but with the shapes of the batches only checked in debug builds,
+    /// for batches sliced to the same shape in hot loops, e.g. token embeddings.
+    pub fn stack_unchecked(batches: Vec<Self>) -> Result<Self, TensorError> {
+        let (context, mut shape) = match batches.first() {
+            Some(batch) => (batch.context.clone(), batch.shape),
+            None => return Err(TensorError::Empty),
+        };
+        batches.iter().for_each(|batch| {
+            batch.debug_check_shape(Shape::new(shape[0], shape[1], batch.shape[2], 1))
+        });
 
         let num_batch: usize = batches.iter().map(|batch| batch.shape[2]).sum();
         shape[2] = num_batch;
@@ -614,8 +842,7 @@ impl<'a, T: Scalar> TensorCpu<'a, T> {
         w: impl TensorAxis,
     ) -> Result<TensorCpu<'a, T>, TensorError> {
         let slice = (x, y, z, w);
-        let (start, end) = slice.shape_bounds(self.shape)?;
-        let shape = end - start;
+        let shape = slice.sliced_shape(self.shape)?;
 
         let (start, end) = slice.contiguous_bounds(self.shape)?;
         let data = match &self.data {
@@ -631,6 +858,43 @@ impl<'a, T: Scalar> TensorCpu<'a, T> {
         })
     }
 
+    /// Like [`Tensor::slice`], but only checked in debug builds, for lookups in hot loops, e.g. of token embeddings.
+    ///
+    /// # Safety
+    /// The slice must be within the shape of the tensor, and contiguous.
+    #[inline]
+    pub unsafe fn slice_unchecked(
+        &self,
+        x: impl TensorAxis,
+        y: impl TensorAxis,
+        z: impl TensorAxis,
+        w: impl TensorAxis,
+    ) -> TensorCpu<'a, T> {
+        let slice = (x, y, z, w);
+        debug_assert!(slice.contiguous_bounds(self.shape).is_ok());
+
+        // SAFETY: the caller guarantees the slice is within the shape
+        let (start, end) = unsafe { slice.shape_bounds_unchecked(self.shape) };
+        let mut shape = end - start;
+        for (dim, step) in shape.iter_mut().zip(slice.shape_steps().iter()) {
+            *dim = dim.div_ceil(*step);
+        }
+        let start = self.shape.shape_index(start);
+        let end = start + shape.len();
+        // SAFETY: the slice is contiguous and within the shape, so are its items within the data
+        let data = match &self.data {
+            Cow::Borrowed(data) => Cow::Borrowed(unsafe { data.get_unchecked(start..end) }),
+            Cow::Owned(data) => Cow::Owned(unsafe { data.get_unchecked(start..end) }.to_owned()),
+        };
+
+        Self {
+            context: self.context.clone(),
+            shape,
+            data,
+            phantom: PhantomData,
+        }
+    }
+
     pub fn into_slice(
         self,
         x: impl TensorAxis,
@@ -639,8 +903,7 @@ impl<'a, T: Scalar> TensorCpu<'a, T> {
         w: impl TensorAxis,
     ) -> Result<Self, TensorError> {
         let slice = (x, y, z, w);
-        let (start, end) = slice.shape_bounds(self.shape)?;
-        let shape = end - start;
+        let shape = slice.sliced_shape(self.shape)?;
 
         let (start, end) = slice.contiguous_bounds(self.shape)?;
         let data = match self.data {
@@ -671,7 +934,24 @@ impl<T: Scalar> TensorShape for TensorView<'_, T> {
     }
 }
 
-impl<T: Scalar> TensorView<'_, T> {
+impl<'a, T: Scalar> TensorView<'a, T> {
+    /// Slice this view further. The resulting view still refers to the original tensor.
+    pub fn view(
+        &self,
+        x: impl TensorAxis,
+        y: impl TensorAxis,
+        z: impl TensorAxis,
+        w: impl TensorAxis,
+    ) -> Result<TensorView<'a, T>, TensorError> {
+        let view = self.view.compose((x, y, z, w))?;
+        let meta = self.tensor.context.request_view_uniform(view);
+        Ok(TensorView {
+            tensor: self.tensor,
+            meta,
+            view,
+        })
+    }
+
     #[inline]
     pub fn data(&self) -> &TensorBuffer {
         &self.tensor.data
@@ -692,7 +972,78 @@ impl<T: Scalar> TensorView<'_, T> {
     }
 }
 
+/// A view into a model state, which may be stored in either precision.
+#[derive(Debug, Clone)]
+pub enum StateView<'a> {
+    F32(TensorView<'a, f32>),
+    F16(TensorView<'a, f16>),
+}
+
+impl<'a> From<TensorView<'a, f32>> for StateView<'a> {
+    fn from(value: TensorView<'a, f32>) -> Self {
+        Self::F32(value)
+    }
+}
+
+impl<'a> From<TensorView<'a, f16>> for StateView<'a> {
+    fn from(value: TensorView<'a, f16>) -> Self {
+        Self::F16(value)
+    }
+}
+
+impl TensorShape for StateView<'_> {
+    #[inline]
+    fn shape(&self) -> Shape {
+        match self {
+            StateView::F32(view) => view.shape(),
+            StateView::F16(view) => view.shape(),
+        }
+    }
+}
+
+impl StateView<'_> {
+    #[inline]
+    pub fn meta_binding(&self) -> BindingResource<'_> {
+        match self {
+            StateView::F32(view) => view.meta_binding(),
+            StateView::F16(view) => view.meta_binding(),
+        }
+    }
+
+    #[inline]
+    pub fn binding(&self) -> BindingResource<'_> {
+        match self {
+            StateView::F32(view) => view.binding(),
+            StateView::F16(view) => view.binding(),
+        }
+    }
+
+    /// The `#ifdef` symbol kernels are compiled with for this state, if any.
+    pub(crate) fn define(&self) -> Option<&'static str> {
+        match self {
+            StateView::F32(_) => None,
+            StateView::F16(_) => Some("STATE_F16"),
+        }
+    }
+}
+
 impl<T: Scalar> TensorGpu<T, ReadWrite> {
+    /// Copy the tensor to another context, e.g. one on another adapter, through the host.
+    pub fn transfer(&self, to: &Context) -> Result<Self, TensorError> {
+        Self::from_data(to, self.shape, Vec::from(self.back()))
+    }
+
+    /// Read the tensor back to the host, blocking until the copy is done.
+    /// Work submitted before on the context is done first, so this also waits for it.
+    pub fn back(&self) -> TensorCpu<'static, T> {
+        TensorCpu::from(self.copy_back())
+    }
+
+    /// Like [`TensorGpu::back`], but returns a future of the tensor instead of blocking, e.g. on the web.
+    pub fn back_async(&self) -> TensorBack<'static, T> {
+        TensorBack::new(self.copy_back())
+    }
+
     pub fn view(
         &self,
         x: impl TensorAxis,
@@ -700,13 +1051,13 @@ impl<T: Scalar> TensorGpu<T, ReadWrite> {
         z: impl TensorAxis,
         w: impl TensorAxis,
     ) -> Result<TensorView<'_, T>, TensorError> {
-        let slice = (x, y, z, w);
-        let (start, end) = slice.shape_bounds(self.shape)?;
         let view = View {
             stride: self.shape,
-            offset: start,
-            shape: end - start,
-        };
+            offset: Shape::default(),
+            shape: self.shape,
+            step: Shape::new(1, 1, 1, 1),
+        }
+        .compose((x, y, z, w))?;
         let meta = self.context.request_view_uniform(view);
         Ok(TensorView {
             tensor: self,
@@ -714,6 +1065,24 @@ impl<T: Scalar> TensorGpu<T, ReadWrite> {
             view,
         })
     }
+
+    /// Views of each batch of a `[C, T, B]` tensor in order, of shape `[C, T, 1]`.
+    pub fn iter_batches(&self) -> impl Iterator<Item = TensorView<'_, T>> {
+        (0..self.shape[2]).map(|batch| {
+            self.view(.., .., batch, ..)
+                .expect("batch within the shape")
+        })
+    }
+
+    /// Views of each token of each batch of a `[C, T, B]` tensor in order, of shape `[C, 1, 1]`.
+    pub fn iter_tokens(&self) -> impl Iterator<Item = TensorView<'_, T>> {
+        (0..self.shape[2]).flat_map(move |batch| {
+            (0..self.shape[1]).map(move |token| {
+                self.view(.., token, batch, ..)
+                    .expect("token within the shape")
+            })
+        })
+    }
 }
 
 impl<T: Scalar> DeepClone for TensorGpu<T, ReadWrite> {
@@ -821,7 +1190,12 @@ impl<T: Scalar> TryFrom<Vec<TensorCpu<'_, T>>> for TensorStack<'_, T> {
 #[derive(Debug, Clone)]
 pub struct TensorBack<'a, T: Scalar> {
     map: TensorGpu<T, ReadBack>,
+    /// Whether mapping the buffer has been requested.
     mapped: Arc<Mutex<bool>>,
+    /// Whether the buffer is mapped and can be read.
+    ready: Arc<AtomicBool>,
+    /// Waker of the last poll, woken once the buffer is mapped.
+    waker: Arc<Mutex<Option<Waker>>>,
     phantom: PhantomData<&'a T>,
 }
 
@@ -830,6 +1204,8 @@ impl<'a, T: Scalar> TensorBack<'a, T> {
         Self {
             map,
             mapped: Arc::new(Mutex::new(false)),
+            ready: Arc::new(AtomicBool::new(false)),
+            waker: Arc::new(Mutex::new(None)),
             phantom: PhantomData,
         }
     }
@@ -848,27 +1224,50 @@ impl<'a, T: Scalar> std::future::Future for TensorBack<'a, T> {
             data: TensorBuffer { buffer, .. },
             ..
         } = self.map.clone();
+        let size = (shape.len() * T::size()) as u64;
+        let slice = buffer.slice(..size);
+
+        *self.waker.lock().unwrap() = Some(cx.waker().clone());
         let mut mapped = self.mapped.lock().unwrap();
-        let slice = buffer.slice(..);
-
-        if *mapped {
-            let data = {
-                let map = slice.get_mapped_range();
-                Vec::from(bytemuck::cast_slice(&map))
-            };
-            buffer.unmap();
-            std::task::Poll::Ready(TensorCpu {
-                context,
-                shape,
-                data: Cow::from(data),
-                phantom: PhantomData,
-            })
-        } else {
-            let waker = cx.waker().clone();
-            slice.map_async(MapMode::Read, move |_| waker.wake());
+        if !*mapped {
+            let ready = self.ready.clone();
+            let waker = self.waker.clone();
+            slice.map_async(MapMode::Read, move |_| {
+                ready.store(true, Ordering::Release);
+                if let Some(waker) = waker.lock().unwrap().take() {
+                    waker.wake();
+                }
+            });
             *mapped = true;
-            std::task::Poll::Pending
         }
+
+        // natively, the mapping only completes while the device is polled,
+        // so without a poll thread on the context this polls it and asks to be polled again
+        #[cfg(not(target_arch = "wasm32"))]
+        let spin = !context.has_poll_thread();
+        #[cfg(not(target_arch = "wasm32"))]
+        if spin {
+            context.device.poll(wgpu::MaintainBase::Poll);
+        }
+        if !self.ready.load(Ordering::Acquire) {
+            #[cfg(not(target_arch = "wasm32"))]
+            if spin {
+                cx.waker().wake_by_ref();
+            }
+            return std::task::Poll::Pending;
+        }
+
+        let data = {
+            let map = slice.get_mapped_range();
+            Vec::from(bytemuck::cast_slice(&map))
+        };
+        buffer.unmap();
+        std::task::Poll::Ready(TensorCpu {
+            context,
+            shape,
+            data: Cow::from(data),
+            phantom: PhantomData,
+        })
     }
 }
 
@@ -894,6 +1293,30 @@ impl<'a> Context {
         Tensor::from_data(self, shape, data)
     }
 
+    /// Sample a tensor from the uniform distribution over `[low, high)`.
+    #[inline]
+    pub fn rand_uniform<Tensor: TensorRandom>(
+        &self,
+        shape: Shape,
+        low: f32,
+        high: f32,
+        seed: u32,
+    ) -> Result<Tensor, TensorError> {
+        Tensor::rand_uniform(self, shape, low, high, seed)
+    }
+
+    /// Sample a tensor from the normal distribution with given `mean` and `std`.
+    #[inline]
+    pub fn rand_normal<Tensor: TensorRandom>(
+        &self,
+        shape: Shape,
+        mean: f32,
+        std: f32,
+        seed: u32,
+    ) -> Result<Tensor, TensorError> {
+        Tensor::rand_normal(self, shape, mean, std, seed)
+    }
+
     #[inline]
     pub fn tensor_init<T: Scalar, Tensor: TensorInit<'a, T>>(&self, shape: Shape) -> Tensor {
         Tensor::init(self, shape)
@@ -916,12 +1339,17 @@ mod sealed {
 
 #[cfg(test)]
 mod tests {
-    use wgpu::PowerPreference;
+    use std::time::Duration;
+
+    use wgpu::{CommandEncoderDescriptor, ComputePassDescriptor, PowerPreference};
 
     use super::Shape;
     use crate::{
         context::{Context, ContextBuilder, Instance},
-        tensor::{TensorCpu, TensorInit, TensorShape},
+        tensor::{
+            ops::{TensorCommand, TensorOp, TensorPass},
+            ReadBack, ReadWrite, TensorCpu, TensorGpu, TensorInit, TensorShape, Uniform,
+        },
     };
 
     fn create_context() -> Result<Context, anyhow::Error> {
@@ -929,12 +1357,7 @@ mod tests {
             let instance = Instance::new();
             instance.adapter(PowerPreference::HighPerformance).await
         })?;
-        let context = pollster::block_on(async {
-            ContextBuilder::new(adapter)
-                .with_default_pipelines()
-                .build()
-                .await
-        })?;
+        let context = pollster::block_on(async { ContextBuilder::new(adapter).build().await })?;
         Ok(context)
     }
 
@@ -969,4 +1392,199 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_iter() -> Result<(), anyhow::Error> {
+        let context = match create_context() {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+
+        let shape = Shape::new(4, 2, 3, 1);
+        let x: Vec<_> = (0..shape.len()).map(|x| x as f32).collect();
+        let x = TensorCpu::from_data(&context, shape, x)?;
+
+        let batches: Vec<_> = x.iter_batches().map(|batch| batch.to_vec()).collect();
+        assert_eq!(batches.len(), 3);
+        assert_eq!(batches[1], (8..16).map(|x| x as f32).collect::<Vec<_>>());
+        let tokens: Vec<_> = x.iter_tokens().collect();
+        assert_eq!(tokens.len(), 6);
+        tokens[3].check_shape(Shape::new(4, 1, 1, 1))?;
+        assert_eq!(tokens[3].to_vec(), [12.0, 13.0, 14.0, 15.0]);
+
+        let x: TensorGpu<f32, ReadWrite> = x.into();
+        let views: Vec<_> = x.iter_batches().collect();
+        views[2].check_shape(Shape::new(4, 2, 1, 1))?;
+        let views: Vec<_> = x.iter_tokens().collect();
+        assert_eq!(views.len(), 6);
+        views[5].check_shape(Shape::new(4, 1, 1, 1))?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_uniform_update() -> Result<(), anyhow::Error> {
+        let context = match create_context() {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+
+        let shape = Shape::new(4, 2, 1, 1);
+        let input: TensorGpu<f32, ReadWrite> =
+            context.tensor_from_data(shape, vec![1.0; shape.len()])?;
+        let output: TensorGpu<f32, ReadWrite> = context.tensor_init(shape);
+        let map: TensorGpu<f32, ReadBack> = context.tensor_init(shape);
+
+        let factor = TensorGpu::<f32, Uniform>::from_params(&context, &[2.0f32, 0.0, 0.0, 0.0])?;
+        assert_eq!(factor.shape(), Shape::new(4, 1, 1, 1));
+        assert!(factor.update(&[1.0]).is_err());
+        assert!(factor.update_params(&[0.0f32; 2]).is_err());
+
+        // the op is created once and re-submitted after each update
+        let op = TensorOp::blend(&factor, &input, &output)?;
+        let run = || -> Result<Vec<f32>, anyhow::Error> {
+            let mut encoder = context
+                .device
+                .create_command_encoder(&CommandEncoderDescriptor::default());
+            let mut pass = encoder.begin_compute_pass(&ComputePassDescriptor::default());
+            pass.execute_tensor_op(&op);
+            drop(pass);
+            encoder.copy_tensor(&output, &map)?;
+            context.queue.submit(Some(encoder.finish()));
+            Ok(TensorCpu::from(map.clone()).to_vec())
+        };
+
+        assert_eq!(run()?, vec![2.0; 8]);
+
+        factor.update(&[1.0, 1.0, 0.0, 0.0])?;
+        assert_eq!(run()?, vec![3.0; 8]);
+
+        factor.update_params(&[0.5f32, 2.0, 0.0, 0.0])?;
+        assert_eq!(run()?, vec![6.5; 8]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_back() -> Result<(), anyhow::Error> {
+        let context = match create_context() {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+
+        let shape = Shape::new(4, 3, 2, 1);
+        let data = (0..shape.len()).map(|x| x as f32).collect::<Vec<_>>();
+        let tensor: TensorGpu<f32, ReadWrite> = context.tensor_from_data(shape, data.clone())?;
+        assert_eq!(tensor.back().to_vec(), data);
+        assert_eq!(pollster::block_on(tensor.back_async()).to_vec(), data);
+
+        // a shrunk tensor reads back only its own front of the buffer
+        let tensor = tensor.shrink(Shape::new(4, 2, 1, 1))?;
+        assert_eq!(tensor.back().to_vec(), data[..8]);
+        assert_eq!(
+            pollster::block_on(tensor.back_async()).shape(),
+            tensor.shape()
+        );
+        assert!(tensor.shrink(shape).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_back_poll_thread() -> Result<(), anyhow::Error> {
+        let adapter = pollster::block_on(async {
+            let instance = Instance::new();
+            instance.adapter(PowerPreference::HighPerformance).await
+        });
+        let adapter = match adapter {
+            Ok(adapter) => adapter,
+            Err(_) => return Ok(()),
+        };
+        let builder = ContextBuilder::new(adapter).with_poll_thread(Duration::from_millis(1));
+        let context = pollster::block_on(async { builder.build().await })?;
+        assert!(context.has_poll_thread());
+
+        // the executor only polls again once woken, which takes the poll thread to map the buffer
+        let shape = Shape::new(4, 16, 1, 1);
+        let data = (0..shape.len()).map(|x| x as f32).collect::<Vec<_>>();
+        let tensor: TensorGpu<f32, ReadWrite> = context.tensor_from_data(shape, data.clone())?;
+        for _ in 0..4 {
+            assert_eq!(pollster::block_on(tensor.back_async()).to_vec(), data);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_load() -> Result<(), anyhow::Error> {
+        let context = match create_context() {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+
+        // larger than a single staging chunk, so that the belt allocates a dedicated one
+        let len = Context::STAGING_CHUNK_SIZE as usize / 4 + 1024;
+        let shape = Shape::new(len / 4, 4, 1, 1);
+        let tensor: TensorGpu<f32, ReadWrite> = context.tensor_init(shape);
+        let map: TensorGpu<f32, ReadBack> = context.tensor_init(shape);
+
+        let read_back = || -> Result<Vec<f32>, anyhow::Error> {
+            let mut encoder = context
+                .device
+                .create_command_encoder(&CommandEncoderDescriptor::default());
+            encoder.copy_tensor(&tensor, &map)?;
+            context.queue.submit(Some(encoder.finish()));
+            Ok(TensorCpu::from(map.clone()).to_vec())
+        };
+
+        for round in 0..3 {
+            let data = (0..len).map(|x| (x + round) as f32).collect::<Vec<_>>();
+            tensor.load(&TensorCpu::from_data(&context, shape, data.clone())?)?;
+            assert_eq!(read_back()?, data);
+        }
+
+        // small uploads are written through the queue
+        let shape = Shape::new(4, 1, 16, 1);
+        let tensor: TensorGpu<f32, ReadWrite> = context.tensor_init(shape);
+        let map: TensorGpu<f32, ReadBack> = context.tensor_init(shape);
+        for batch in 0..16 {
+            let data = vec![batch as f32; 4];
+            tensor.load_batch(
+                &TensorCpu::from_data(&context, Shape::new(4, 1, 1, 1), data)?,
+                batch,
+            )?;
+        }
+
+        let mut encoder = context
+            .device
+            .create_command_encoder(&CommandEncoderDescriptor::default());
+        encoder.copy_tensor(&tensor, &map)?;
+        context.queue.submit(Some(encoder.finish()));
+        let output = TensorCpu::from(map).to_vec();
+        let expected = (0..16).flat_map(|x| vec![x as f32; 4]).collect::<Vec<_>>();
+        assert_eq!(output, expected);
+
+        // write a few rows of one batch in a stacked tensor
+        let shape = Shape::new(4, 6, 2, 1);
+        let tensor: TensorGpu<f32, ReadWrite> = context.tensor_init(shape);
+        let map: TensorGpu<f32, ReadBack> = context.tensor_init(shape);
+        let host = TensorCpu::from_data(&context, Shape::new(4, 2, 1, 1), vec![1.0; 8])?;
+        tensor.load_slice(&host, .., 2..4, 1, ..)?;
+        assert!(tensor.load_slice(&host, .., 2..5, 1, ..).is_err());
+        assert!(tensor.load_slice(&host, 0..2, 2..6, 1, ..).is_err());
+
+        let mut encoder = context
+            .device
+            .create_command_encoder(&CommandEncoderDescriptor::default());
+        encoder.copy_tensor(&tensor, &map)?;
+        context.queue.submit(Some(encoder.finish()));
+        let output = TensorCpu::from(map).to_vec();
+        let expected = (0..shape.len())
+            .map(|index| match index {
+                32..=39 => 1.0,
+                _ => 0.0,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(output, expected);
+
+        Ok(())
+    }
 }
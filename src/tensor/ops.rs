@@ -1,10 +1,26 @@
+use std::sync::Arc;
+
 use half::f16;
+use safetensors::Dtype;
 use wgpu::{
-    BindGroup, BindGroupDescriptor, BindGroupEntry, CommandEncoder, ComputePass, ComputePipeline,
+    BindGroup, BindGroupDescriptor, BindGroupEntry, Buffer, BufferSize, CommandEncoder,
+    ComputePass, ComputePassDescriptor, ComputePipeline,
+};
+
+use super::{
+    Kind, ReadWrite, Shape, StateView, TensorError, TensorGpu, TensorShape, TensorView, Uniform,
 };
+use crate::num::{Float, Precision, Scalar};
+
+/// The `#ifdef` symbol a kernel is compiled with when `F` is stored in half precision.
+fn half<F: Float>(symbol: &'static str) -> Option<&'static str> {
+    (F::PRECISION == Precision::F16).then_some(symbol)
+}
 
-use super::{Kind, ReadWrite, Shape, TensorError, TensorGpu, TensorShape, TensorView, Uniform};
-use crate::num::Scalar;
+/// Collect the `#ifdef` symbols that apply to a kernel.
+fn defines<const N: usize>(symbols: [Option<&'static str>; N]) -> Vec<&'static str> {
+    symbols.into_iter().flatten().collect()
+}
 
 pub trait TensorCommand<T: Scalar, K: Kind> {
     fn copy_tensor(
@@ -19,6 +35,9 @@ pub trait TensorCommand<T: Scalar, K: Kind> {
         destination: &TensorGpu<T, K>,
         batch: usize,
     ) -> Result<(), TensorError>;
+
+    /// Zero out the whole tensor.
+    fn clear_tensor(&mut self, tensor: &TensorGpu<T, K>);
 }
 
 impl<T: Scalar, K: Kind> TensorCommand<T, K> for CommandEncoder {
@@ -29,7 +48,13 @@ impl<T: Scalar, K: Kind> TensorCommand<T, K> for CommandEncoder {
     ) -> Result<(), TensorError> {
         destination.check_shape(source.shape())?;
         let size = destination.size() as u64;
-        self.copy_buffer_to_buffer(&source.buffer, 0, &destination.buffer, 0, size);
+        self.copy_buffer_to_buffer(
+            &source.buffer,
+            source.offset,
+            &destination.buffer,
+            destination.offset,
+            size,
+        );
         Ok(())
     }
 
@@ -47,10 +72,171 @@ impl<T: Scalar, K: Kind> TensorCommand<T, K> for CommandEncoder {
             });
         }
         let size = destination.size() as u64;
-        let offset = (T::size() * source.shape[0] * source.shape[1] * batch) as u64;
-        self.copy_buffer_to_buffer(&source.buffer, offset, &destination.buffer, 0, size);
+        let offset = source.offset + (T::size() * source.shape[0] * source.shape[1] * batch) as u64;
+        self.copy_buffer_to_buffer(
+            &source.buffer,
+            offset,
+            &destination.buffer,
+            destination.offset,
+            size,
+        );
+        Ok(())
+    }
+
+    fn clear_tensor(&mut self, tensor: &TensorGpu<T, K>) {
+        self.clear_buffer(&tensor.buffer, tensor.offset, clear_size(tensor));
+    }
+}
+
+/// Ops and buffer commands recorded once and replayed into any encoder,
+/// so that sub-graphs (a layer, a whole token step) need not be rebuilt every time.
+/// Consecutive ops are replayed within a single compute pass.
+#[derive(Debug, Default)]
+pub struct TensorSequence {
+    steps: Vec<TensorStep>,
+}
+
+#[derive(Debug)]
+enum TensorStep {
+    Op(TensorOp),
+    Copy {
+        source: Arc<Buffer>,
+        source_offset: u64,
+        destination: Arc<Buffer>,
+        destination_offset: u64,
+        size: u64,
+    },
+    Clear {
+        buffer: Arc<Buffer>,
+        offset: u64,
+        size: Option<BufferSize>,
+    },
+}
+
+impl TensorSequence {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.steps.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.steps.is_empty()
+    }
+
+    pub fn push(&mut self, op: TensorOp) {
+        self.steps.push(TensorStep::Op(op));
+    }
+
+    /// Append all steps of another sequence after the ones of this.
+    pub fn append(&mut self, mut other: TensorSequence) {
+        self.steps.append(&mut other.steps);
+    }
+
+    pub fn replay(&self, encoder: &mut CommandEncoder) {
+        let mut steps = self.steps.iter().peekable();
+        while let Some(step) = steps.next() {
+            match step {
+                TensorStep::Op(op) => {
+                    let mut pass = encoder.begin_compute_pass(&ComputePassDescriptor::default());
+                    pass.execute_tensor_op(op);
+                    while let Some(TensorStep::Op(op)) = steps.peek() {
+                        pass.execute_tensor_op(op);
+                        steps.next();
+                    }
+                }
+                TensorStep::Copy {
+                    source,
+                    source_offset,
+                    destination,
+                    destination_offset,
+                    size,
+                } => encoder.copy_buffer_to_buffer(
+                    source,
+                    *source_offset,
+                    destination,
+                    *destination_offset,
+                    *size,
+                ),
+                TensorStep::Clear {
+                    buffer,
+                    offset,
+                    size,
+                } => encoder.clear_buffer(buffer, *offset, *size),
+            }
+        }
+    }
+}
+
+impl From<TensorOp> for TensorSequence {
+    fn from(op: TensorOp) -> Self {
+        Self {
+            steps: vec![TensorStep::Op(op)],
+        }
+    }
+}
+
+impl<T: Scalar, K: Kind> TensorCommand<T, K> for TensorSequence {
+    fn copy_tensor(
+        &mut self,
+        source: &TensorGpu<T, ReadWrite>,
+        destination: &TensorGpu<T, K>,
+    ) -> Result<(), TensorError> {
+        destination.check_shape(source.shape())?;
+        self.steps.push(TensorStep::Copy {
+            source: source.buffer.clone(),
+            source_offset: source.offset,
+            destination: destination.buffer.clone(),
+            destination_offset: destination.offset,
+            size: destination.size() as u64,
+        });
+        Ok(())
+    }
+
+    fn copy_tensor_batch(
+        &mut self,
+        source: &TensorGpu<T, ReadWrite>,
+        destination: &TensorGpu<T, K>,
+        batch: usize,
+    ) -> Result<(), TensorError> {
+        destination.check_shape(Shape::new(source.shape[0], source.shape[1], 1, 1))?;
+        if batch >= source.shape[2] {
+            return Err(TensorError::BatchOutOfRange {
+                batch,
+                max: source.shape[2],
+            });
+        }
+        self.steps.push(TensorStep::Copy {
+            source: source.buffer.clone(),
+            source_offset: source.offset
+                + (T::size() * source.shape[0] * source.shape[1] * batch) as u64,
+            destination: destination.buffer.clone(),
+            destination_offset: destination.offset,
+            size: destination.size() as u64,
+        });
         Ok(())
     }
+
+    fn clear_tensor(&mut self, tensor: &TensorGpu<T, K>) {
+        self.steps.push(TensorStep::Clear {
+            buffer: tensor.buffer.clone(),
+            offset: tensor.offset,
+            size: clear_size(tensor),
+        });
+    }
+}
+
+/// Bytes [`TensorCommand::clear_tensor`] clears: the whole buffer of a tensor owning it,
+/// or the tensor rounded up to the copy alignment, which the arena leaves room for.
+fn clear_size<T: Scalar, K: Kind>(tensor: &TensorGpu<T, K>) -> Option<BufferSize> {
+    match tensor.offset {
+        0 => None,
+        _ => BufferSize::new((tensor.size() as u64).next_multiple_of(wgpu::COPY_BUFFER_ALIGNMENT)),
+    }
 }
 
 pub trait TensorPass<'a> {
@@ -78,18 +264,21 @@ impl<'b, 'a: 'b> TensorPass<'a> for ComputePass<'b> {
     }
 }
 
-pub enum TensorOp<'a> {
+#[derive(Debug)]
+pub enum TensorOp {
     Atom {
-        pipeline: &'a ComputePipeline,
+        pipeline: Arc<ComputePipeline>,
         bindings: Vec<BindGroup>,
         dispatch: [u32; 3],
     },
-    List(Vec<TensorOp<'a>>),
+    List(Vec<TensorOp>),
 }
 
-impl<'a> TensorOp<'a> {
+impl<'a> TensorOp {
     pub const BLOCK_SIZE: u32 = 128;
     pub const NF4_BLOCK_SIZE: usize = 64;
+    /// Empty statistics for [`TensorOp::stats`] to fold into.
+    pub const STATS_INIT: [u32; 8] = [f32::MAX.to_bits(), (-f32::MAX).to_bits(), 0, 0, 0, 0, 0, 0];
 
     #[inline]
     fn round(x: u32, div: u32) -> u32 {
@@ -128,21 +317,18 @@ impl<'a> TensorOp<'a> {
         })
     }
 
-    /// Layer normalization applied on `x`, with weight `w` and bias `b`.
+    /// Softmax of `x`, each batch divided by its `temperature` first in the same pass.
     /// - `x` shape: `[C, T, B]`.
-    /// - `w` shape: `[C, 1, 1]`.
-    /// - `b` shape: `[C, 1, 1]`.
-    pub fn layer_norm(
-        w: &'a TensorGpu<f16, ReadWrite>,
-        b: &'a TensorGpu<f16, ReadWrite>,
+    /// - `temperature` shape: `[B, 1, 1]`.
+    pub fn softmax_temperature(
         x: &'a TensorGpu<f32, ReadWrite>,
+        temperature: &'a TensorGpu<f32, ReadWrite>,
     ) -> Result<Self, TensorError> {
         let shape = x.shape();
-        w.check_shape(Shape::new(shape[0], 1, 1, 1))?;
-        b.check_shape(Shape::new(shape[0], 1, 1, 1))?;
+        temperature.check_shape(Shape::new(shape[2], 1, 1, 1))?;
 
         let context = &x.context;
-        let pipeline = context.pipeline("layer_norm")?;
+        let pipeline = context.pipeline_with("softmax", &["TEMPERATURE"])?;
         let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
             label: None,
             layout: &pipeline.get_bind_group_layout(0),
@@ -153,15 +339,11 @@ impl<'a> TensorOp<'a> {
                 },
                 BindGroupEntry {
                     binding: 1,
-                    resource: w.binding(),
+                    resource: x.binding(),
                 },
                 BindGroupEntry {
                     binding: 2,
-                    resource: b.binding(),
-                },
-                BindGroupEntry {
-                    binding: 3,
-                    resource: x.binding(),
+                    resource: temperature.binding(),
                 },
             ],
         })];
@@ -173,21 +355,23 @@ impl<'a> TensorOp<'a> {
         })
     }
 
-    /// Group normalization applied on `x`, with weight `w` and bias `b`.
-    /// - `x` shape: `[S, H, A]`.
-    /// - `w` shape: `[S, H, 1]`.
-    /// - `b` shape: `[S, H, 1]`.
-    pub fn group_norm(
-        w: &'a TensorGpu<f16, ReadWrite>,
-        b: &'a TensorGpu<f16, ReadWrite>,
+    /// Apply repetition, presence and frequency penalties to logits `x`,
+    /// given how many times each token has appeared in each batch.
+    /// Each entry of `penalty` holds the repetition, presence and frequency penalties of a batch, followed by a padding.
+    /// - `x` shape: `[C, T, B]`.
+    /// - `penalty` shape: `[4, B, 1]`.
+    /// - `count` shape: `[C, B, 1]`.
+    pub fn penalty(
+        penalty: &'a TensorGpu<f32, ReadWrite>,
+        count: &'a TensorGpu<u32, ReadWrite>,
         x: &'a TensorGpu<f32, ReadWrite>,
     ) -> Result<Self, TensorError> {
         let shape = x.shape();
-        w.check_shape(Shape::new(shape[0], shape[1], 1, 1))?;
-        b.check_shape(Shape::new(shape[0], shape[1], 1, 1))?;
+        penalty.check_shape(Shape::new(4, shape[2], 1, 1))?;
+        count.check_shape(Shape::new(shape[0], shape[2], 1, 1))?;
 
         let context = &x.context;
-        let pipeline = context.pipeline("group_norm")?;
+        let pipeline = context.pipeline("penalty")?;
         let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
             label: None,
             layout: &pipeline.get_bind_group_layout(0),
@@ -198,11 +382,11 @@ impl<'a> TensorOp<'a> {
                 },
                 BindGroupEntry {
                     binding: 1,
-                    resource: w.binding(),
+                    resource: penalty.binding(),
                 },
                 BindGroupEntry {
                     binding: 2,
-                    resource: b.binding(),
+                    resource: count.binding(),
                 },
                 BindGroupEntry {
                     binding: 3,
@@ -214,52 +398,42 @@ impl<'a> TensorOp<'a> {
         Ok(Self::Atom {
             pipeline,
             bindings,
-            dispatch: [1, shape[1] as u32, shape[2] as u32],
+            dispatch: [
+                Self::block_count(shape[0] as u32 / 4),
+                shape[1] as u32,
+                shape[2] as u32,
+            ],
         })
     }
 
-    /// Fp32 matrix-vector multiplication.
-    /// - `matrix` shape: `[C, R, 1]`.
-    /// - `input` shape: `[C, T, B]`.
-    /// - `output` shape: `[R, T, B]`.
-    pub fn matmul_vec_fp16(
-        matrix: &'a TensorGpu<f16, ReadWrite>,
-        input: TensorView<'a, f32>,
-        output: TensorView<'a, f32>,
+    /// Set the logits `x` of tokens whose bit is cleared in `mask` to negative infinity.
+    /// Token `i` of a batch is allowed if bit `i % 32` of word `i / 32` of its mask is set.
+    /// - `x` shape: `[C, T, B]`.
+    /// - `mask` shape: `[C / 32 (rounded up), B, 1]`.
+    pub fn mask(
+        mask: &'a TensorGpu<u32, ReadWrite>,
+        x: &'a TensorGpu<f32, ReadWrite>,
     ) -> Result<Self, TensorError> {
-        let shape = output.shape();
-        matrix.check_shape(Shape::new(input.shape()[0], shape[0], 1, 1))?;
-        input.check_shape(Shape::new(matrix.shape[0], shape[1], shape[2], 1))?;
+        let shape = x.shape();
+        mask.check_shape(Shape::new(shape[0].div_ceil(32), shape[2], 1, 1))?;
 
-        let context = &output.tensor.context;
-        let pipeline = context.pipeline("matmul_vec_fp16")?;
+        let context = &x.context;
+        let pipeline = context.pipeline("mask")?;
         let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
             label: None,
             layout: &pipeline.get_bind_group_layout(0),
             entries: &[
                 BindGroupEntry {
                     binding: 0,
-                    resource: matrix.meta_binding(),
+                    resource: x.meta_binding(),
                 },
                 BindGroupEntry {
                     binding: 1,
-                    resource: input.meta_binding(),
+                    resource: mask.binding(),
                 },
                 BindGroupEntry {
                     binding: 2,
-                    resource: output.meta_binding(),
-                },
-                BindGroupEntry {
-                    binding: 3,
-                    resource: matrix.binding(),
-                },
-                BindGroupEntry {
-                    binding: 4,
-                    resource: input.binding(),
-                },
-                BindGroupEntry {
-                    binding: 5,
-                    resource: output.binding(),
+                    resource: x.binding(),
                 },
             ],
         })];
@@ -267,77 +441,50 @@ impl<'a> TensorOp<'a> {
         Ok(Self::Atom {
             pipeline,
             bindings,
-            dispatch: [matrix.shape[1] as u32 / 4, shape[1] as u32, shape[2] as u32],
+            dispatch: [
+                Self::block_count(shape[0] as u32 / 4),
+                shape[1] as u32,
+                shape[2] as u32,
+            ],
         })
     }
 
-    /// Int8 matrix-vector multiplication.
-    /// - `matrix` shape: `[C, R, 1]`.
-    /// - `mx` and `rx` shape: `[C, 1, 1]`.
-    /// - `my` and `ry` shape: `[R, 1, 1]`.
-    /// - `input` shape: `[C, T, B]`.
-    /// - `output` shape: `[R, T, B]`.
-    pub fn matmul_vec_int8(
-        matrix: &'a TensorGpu<u8, ReadWrite>,
-        mx: &'a TensorGpu<f32, ReadWrite>,
-        rx: &'a TensorGpu<f32, ReadWrite>,
-        my: &'a TensorGpu<f32, ReadWrite>,
-        ry: &'a TensorGpu<f32, ReadWrite>,
-        input: TensorView<'a, f32>,
-        output: TensorView<'a, f32>,
+    /// Check the tokens run in a stacked pass against the greedy choices of its logits `x`.
+    /// Token `t + 1` of a batch is accepted if it is the argmax of the logits at position `t` (the first one on ties),
+    /// up to the first rejected one. `output` gets the number of tokens accepted after the first,
+    /// and the argmax at the position after the last accepted token.
+    /// - `x` shape: `[C, T, B]`.
+    /// - `tokens` shape: `[T, B, 1]`.
+    /// - `output` shape: `[2, B, 1]`.
+    pub fn verify(
+        x: &'a TensorGpu<f32, ReadWrite>,
+        tokens: &'a TensorGpu<u32, ReadWrite>,
+        output: &'a TensorGpu<u32, ReadWrite>,
     ) -> Result<Self, TensorError> {
-        let shape = output.shape();
-        matrix.check_shape(Shape::new(input.shape()[0], shape[0], 1, 1))?;
-        input.check_shape(Shape::new(matrix.shape[0], shape[1], shape[2], 1))?;
-        mx.check_shape(Shape::new(matrix.shape[0], 1, 1, 1))?;
-        rx.check_shape(Shape::new(matrix.shape[0], 1, 1, 1))?;
-        my.check_shape(Shape::new(matrix.shape[1], 1, 1, 1))?;
-        ry.check_shape(Shape::new(matrix.shape[1], 1, 1, 1))?;
+        let shape = x.shape();
+        tokens.check_shape(Shape::new(shape[1], shape[2], 1, 1))?;
+        output.check_shape(Shape::new(2, shape[2], 1, 1))?;
 
-        let context = &matrix.context;
-        let pipeline = context.pipeline("matmul_vec_int8")?;
+        let context = &x.context;
+        let pipeline = context.pipeline("verify")?;
         let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
             label: None,
             layout: &pipeline.get_bind_group_layout(0),
             entries: &[
                 BindGroupEntry {
                     binding: 0,
-                    resource: matrix.meta_binding(),
+                    resource: x.meta_binding(),
                 },
                 BindGroupEntry {
                     binding: 1,
-                    resource: input.meta_binding(),
+                    resource: x.binding(),
                 },
                 BindGroupEntry {
                     binding: 2,
-                    resource: output.meta_binding(),
+                    resource: tokens.binding(),
                 },
                 BindGroupEntry {
                     binding: 3,
-                    resource: matrix.binding(),
-                },
-                BindGroupEntry {
-                    binding: 4,
-                    resource: mx.binding(),
-                },
-                BindGroupEntry {
-                    binding: 5,
-                    resource: rx.binding(),
-                },
-                BindGroupEntry {
-                    binding: 6,
-                    resource: my.binding(),
-                },
-                BindGroupEntry {
-                    binding: 7,
-                    resource: ry.binding(),
-                },
-                BindGroupEntry {
-                    binding: 8,
-                    resource: input.binding(),
-                },
-                BindGroupEntry {
-                    binding: 9,
                     resource: output.binding(),
                 },
             ],
@@ -346,68 +493,43 @@ impl<'a> TensorOp<'a> {
         Ok(Self::Atom {
             pipeline,
             bindings,
-            dispatch: [matrix.shape[1] as u32 / 4, shape[1] as u32, shape[2] as u32],
+            dispatch: [1, 1, shape[2] as u32],
         })
     }
 
-    /// NFloat4 matrix-vector multiplication.
-    /// - `matrix` shape: `[C, R, 1]`.
-    /// - `absmax` shape: `[C / S, R, 1]`.
-    /// - `input` shape: `[C, T, B]`.
-    /// - `output` shape: `[R, T, B]`.
-    pub fn matmul_vec_nf4(
-        matrix: &'a TensorGpu<u8, ReadWrite>,
-        absmax: &'a TensorGpu<f16, ReadWrite>,
-        quant: &'a TensorGpu<f32, Uniform>,
-        input: TensorView<'a, f16>,
-        output: TensorView<'a, f32>,
+    /// Log-likelihood of `tokens` under the logits `x` at the same positions, i.e., log-softmax picked at each token.
+    /// - `x` shape: `[C, T, B]`.
+    /// - `tokens` shape: `[T, B, 1]`.
+    /// - `output` shape: `[T, B, 1]`.
+    pub fn score(
+        x: &'a TensorGpu<f32, ReadWrite>,
+        tokens: &'a TensorGpu<u32, ReadWrite>,
+        output: &'a TensorGpu<f32, ReadWrite>,
     ) -> Result<Self, TensorError> {
-        let shape = output.shape();
-        matrix.check_shape(Shape::new(input.shape()[0] / 2, shape[0], 1, 1))?;
-        input.check_shape(Shape::new(input.shape()[0], shape[1], shape[2], 1))?;
-        absmax.check_shape(Shape::new(
-            input.shape()[0] / Self::NF4_BLOCK_SIZE,
-            shape[0],
-            1,
-            1,
-        ))?;
+        let shape = x.shape();
+        tokens.check_shape(Shape::new(shape[1], shape[2], 1, 1))?;
+        output.check_shape(Shape::new(shape[1], shape[2], 1, 1))?;
 
-        let context = &matrix.context;
-        let pipeline = context.pipeline("matmul_vec_nf4")?;
+        let context = &x.context;
+        let pipeline = context.pipeline("score")?;
         let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
             label: None,
             layout: &pipeline.get_bind_group_layout(0),
             entries: &[
-                // BindGroupEntry {
-                //     binding: 0,
-                //     resource: matrix.meta_binding(),
-                // },
+                BindGroupEntry {
+                    binding: 0,
+                    resource: x.meta_binding(),
+                },
                 BindGroupEntry {
                     binding: 1,
-                    resource: input.meta_binding(),
+                    resource: x.binding(),
                 },
                 BindGroupEntry {
                     binding: 2,
-                    resource: output.meta_binding(),
+                    resource: tokens.binding(),
                 },
                 BindGroupEntry {
                     binding: 3,
-                    resource: quant.binding(),
-                },
-                BindGroupEntry {
-                    binding: 4,
-                    resource: matrix.binding(),
-                },
-                BindGroupEntry {
-                    binding: 5,
-                    resource: absmax.binding(),
-                },
-                BindGroupEntry {
-                    binding: 6,
-                    resource: input.binding(),
-                },
-                BindGroupEntry {
-                    binding: 7,
                     resource: output.binding(),
                 },
             ],
@@ -416,52 +538,72 @@ impl<'a> TensorOp<'a> {
         Ok(Self::Atom {
             pipeline,
             bindings,
-            dispatch: [matrix.shape[1] as u32 / 4, shape[1] as u32, shape[2] as u32],
+            dispatch: [1, shape[1] as u32, shape[2] as u32],
         })
     }
 
-    /// Fp16 matrix-matrix multiplication.
-    /// - `matrix` shape: `[K, M, B]`.
-    /// - `input` shape: `[K, N, B]`.
-    /// - `output` shape: `[M, N, B]`.
-    pub fn matmul_mat_fp16(
-        matrix: TensorView<'a, f16>,
-        input: TensorView<'a, f16>,
-        output: TensorView<'a, f32>,
+    /// Pool the tokens of `x` into one, summing them by `weight`.
+    /// - `weight` shape: `[T, B, 1]`.
+    /// - `x` shape: `[C, T, B]`.
+    /// - `output` shape: `[C, 1, B]`.
+    pub fn pool(
+        weight: &'a TensorGpu<f32, ReadWrite>,
+        x: &'a TensorGpu<f32, ReadWrite>,
+        output: &'a TensorGpu<f32, ReadWrite>,
     ) -> Result<Self, TensorError> {
-        let shape = output.shape();
-        matrix.check_shape(Shape::new(matrix.shape()[0], shape[0], shape[2], 1))?;
-        input.check_shape(Shape::new(input.shape()[0], shape[1], shape[2], 1))?;
+        let shape = x.shape();
+        weight.check_shape(Shape::new(shape[1], shape[2], 1, 1))?;
+        output.check_shape(Shape::new(shape[0], 1, shape[2], 1))?;
 
-        let context = &output.tensor.context;
-        let pipeline = context.pipeline("matmul_mat_fp16")?;
+        let context = &x.context;
+        let pipeline = context.pipeline("pool")?;
         let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
             label: None,
             layout: &pipeline.get_bind_group_layout(0),
             entries: &[
                 BindGroupEntry {
                     binding: 0,
-                    resource: matrix.meta_binding(),
+                    resource: x.meta_binding(),
                 },
                 BindGroupEntry {
                     binding: 1,
-                    resource: input.meta_binding(),
+                    resource: weight.binding(),
                 },
                 BindGroupEntry {
                     binding: 2,
-                    resource: output.meta_binding(),
+                    resource: x.binding(),
                 },
                 BindGroupEntry {
                     binding: 3,
-                    resource: matrix.binding(),
+                    resource: output.binding(),
                 },
+            ],
+        })];
+
+        Ok(Self::Atom {
+            pipeline,
+            bindings,
+            dispatch: [Self::block_count(shape[0] as u32 / 4), 1, shape[2] as u32],
+        })
+    }
+
+    /// Scale each token of `x` to unit L2 norm.
+    /// - `x` shape: `[C, T, B]`.
+    pub fn l2_norm(x: &'a TensorGpu<f32, ReadWrite>) -> Result<Self, TensorError> {
+        let shape = x.shape();
+        let context = &x.context;
+        let pipeline = context.pipeline("l2_norm")?;
+        let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
+            label: None,
+            layout: &pipeline.get_bind_group_layout(0),
+            entries: &[
                 BindGroupEntry {
-                    binding: 4,
-                    resource: input.binding(),
+                    binding: 0,
+                    resource: x.meta_binding(),
                 },
                 BindGroupEntry {
-                    binding: 5,
-                    resource: output.binding(),
+                    binding: 1,
+                    resource: x.binding(),
                 },
             ],
         })];
@@ -469,79 +611,40 @@ impl<'a> TensorOp<'a> {
         Ok(Self::Atom {
             pipeline,
             bindings,
-            dispatch: [
-                Self::round(Self::round(shape[0] as u32, 4), 8),
-                Self::round(Self::round(shape[1] as u32, 4), 8),
-                shape[2] as u32,
-            ],
+            dispatch: [1, shape[1] as u32, shape[2] as u32],
         })
     }
 
-    /// Int8 matrix-matrix multiplication.
-    /// - `matrix` shape: `[K, M, B]`.
-    /// - `input` shape: `[K, N, B]`.
-    /// - `output` shape: `[M, N, B]`.
-    pub fn matmul_mat_int8(
-        matrix: TensorView<'a, u8>,
-        mx: &'a TensorGpu<f32, ReadWrite>,
-        rx: &'a TensorGpu<f32, ReadWrite>,
-        my: &'a TensorGpu<f32, ReadWrite>,
-        ry: &'a TensorGpu<f32, ReadWrite>,
-        input: TensorView<'a, f16>,
-        output: TensorView<'a, f32>,
+    /// Fold the min, max, sum and count of the finite elements of `x`, and the count of the others, into `output`.
+    /// Floats are stored as their bits; initialize with [`TensorOp::STATS_INIT`].
+    /// - `x` shape: `[C, T, B]`.
+    /// - `output` shape: `[8, 1, 1]`.
+    pub fn stats<F: Float>(
+        x: &'a TensorGpu<F, ReadWrite>,
+        output: TensorView<'a, u32>,
     ) -> Result<Self, TensorError> {
-        let shape = output.shape();
-        matrix.check_shape(Shape::new(matrix.shape()[0], shape[0], shape[2], 1))?;
-        input.check_shape(Shape::new(input.shape()[0], shape[1], shape[2], 1))?;
-        mx.check_shape(Shape::new(matrix.shape()[0], shape[2], 1, 1))?;
-        rx.check_shape(Shape::new(matrix.shape()[0], shape[2], 1, 1))?;
-        my.check_shape(Shape::new(matrix.shape()[1], shape[2], 1, 1))?;
-        ry.check_shape(Shape::new(matrix.shape()[1], shape[2], 1, 1))?;
+        output.check_shape(Shape::new(8, 1, 1, 1))?;
 
-        let context = &output.tensor.context;
-        let pipeline = context.pipeline("matmul_mat_int8")?;
+        let context = &x.context;
+        let pipeline = context.pipeline_with("stats", &defines([half::<F>("ACT_F16")]))?;
         let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
             label: None,
             layout: &pipeline.get_bind_group_layout(0),
             entries: &[
                 BindGroupEntry {
                     binding: 0,
-                    resource: matrix.meta_binding(),
+                    resource: x.meta_binding(),
                 },
                 BindGroupEntry {
                     binding: 1,
-                    resource: input.meta_binding(),
+                    resource: output.meta_binding(),
                 },
                 BindGroupEntry {
                     binding: 2,
-                    resource: output.meta_binding(),
+                    resource: x.binding(),
                 },
                 BindGroupEntry {
                     binding: 3,
-                    resource: mx.binding(),
-                },
-                BindGroupEntry {
-                    binding: 4,
-                    resource: rx.binding(),
-                },
-                BindGroupEntry {
-                    binding: 5,
-                    resource: my.binding(),
-                },
-                BindGroupEntry {
-                    binding: 6,
-                    resource: ry.binding(),
-                },
-                BindGroupEntry {
-                    binding: 7,
-                    resource: matrix.binding(),
-                },
-                BindGroupEntry {
-                    binding: 8,
-                    resource: input.binding(),
-                },
-                BindGroupEntry {
-                    binding: 9,
                     resource: output.binding(),
                 },
             ],
@@ -550,38 +653,45 @@ impl<'a> TensorOp<'a> {
         Ok(Self::Atom {
             pipeline,
             bindings,
-            dispatch: [
-                Self::round(Self::round(shape[0] as u32, 4), 8),
-                Self::round(Self::round(shape[1] as u32, 4), 8),
-                shape[2] as u32,
-            ],
+            dispatch: [1, 1, 1],
         })
     }
 
-    /// Add `input` onto `output`.
-    pub fn add(
-        input: &'a TensorGpu<f32, ReadWrite>,
+    /// Cosine similarity between each query and each row of `matrix`, e.g. to look up stored embeddings.
+    /// - `query` shape: `[C, 1, B]`.
+    /// - `matrix` shape: `[C, N, 1]`.
+    /// - `output` shape: `[N, B, 1]`.
+    pub fn cosine_similarity(
+        query: &'a TensorGpu<f32, ReadWrite>,
+        matrix: &'a TensorGpu<f32, ReadWrite>,
         output: &'a TensorGpu<f32, ReadWrite>,
     ) -> Result<Self, TensorError> {
-        let shape = output.shape;
-        input.check_shape(shape)?;
+        let shape = matrix.shape();
+        let num_batch = query.shape()[2];
+        matrix.check_shape(Shape::new(shape[0], shape[1], 1, 1))?;
+        query.check_shape(Shape::new(shape[0], 1, num_batch, 1))?;
+        output.check_shape(Shape::new(shape[1], num_batch, 1, 1))?;
 
-        let context = &output.context;
-        let pipeline = context.pipeline("add")?;
+        let context = &matrix.context;
+        let pipeline = context.pipeline("cosine")?;
         let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
             label: None,
             layout: &pipeline.get_bind_group_layout(0),
             entries: &[
                 BindGroupEntry {
                     binding: 0,
-                    resource: output.meta_binding(),
+                    resource: matrix.meta_binding(),
                 },
                 BindGroupEntry {
                     binding: 1,
-                    resource: input.binding(),
+                    resource: query.binding(),
                 },
                 BindGroupEntry {
                     binding: 2,
+                    resource: matrix.binding(),
+                },
+                BindGroupEntry {
+                    binding: 3,
                     resource: output.binding(),
                 },
             ],
@@ -590,95 +700,70 @@ impl<'a> TensorOp<'a> {
         Ok(Self::Atom {
             pipeline,
             bindings,
-            dispatch: [
-                Self::block_count(shape[0] as u32 / 4),
-                shape[1] as u32,
-                shape[2] as u32,
-            ],
+            dispatch: [1, shape[1] as u32, num_batch as u32],
         })
     }
 
-    pub fn token_shift(
-        cursors: &'a TensorGpu<u32, ReadWrite>,
-        time_mix: &'a TensorGpu<f16, ReadWrite>,
-        x: &'a TensorGpu<f32, ReadWrite>,
-        sx: TensorView<f32>,
-        output: &'a TensorGpu<f32, ReadWrite>,
+    /// Layer normalization applied on `x`, with weight `w` and bias `b`.
+    /// - `x` shape: `[C, T, B]`.
+    /// - `w` shape: `[C, 1, 1]`.
+    /// - `b` shape: `[C, 1, 1]`.
+    pub fn layer_norm<F: Float>(
+        w: &'a TensorGpu<f16, ReadWrite>,
+        b: &'a TensorGpu<f16, ReadWrite>,
+        x: &'a TensorGpu<F, ReadWrite>,
     ) -> Result<Self, TensorError> {
-        let shape = output.shape;
-        let num_batch = sx.shape()[2];
-        // cursors.check_shape(Shape::new(shape[1], 1, 1, 1))?;
-        time_mix.check_shape(Shape::new(shape[0], 1, 1, 1))?;
-        x.check_shape(shape)?;
-        sx.check_shape(Shape::new(shape[0], sx.shape()[1], num_batch, 1))?;
+        let shape = x.shape();
+        w.check_shape(Shape::new(shape[0], 1, 1, 1))?;
+        b.check_shape(Shape::new(shape[0], 1, 1, 1))?;
 
-        let context = &output.context;
-        let pipeline = context.pipeline("token_shift")?;
+        let context = &x.context;
+        let pipeline = context.pipeline_with("layer_norm", &defines([half::<F>("ACT_F16")]))?;
         let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
             label: None,
             layout: &pipeline.get_bind_group_layout(0),
             entries: &[
                 BindGroupEntry {
                     binding: 0,
-                    resource: output.meta_binding(),
+                    resource: x.meta_binding(),
                 },
                 BindGroupEntry {
                     binding: 1,
-                    resource: sx.meta_binding(),
+                    resource: w.binding(),
                 },
                 BindGroupEntry {
                     binding: 2,
-                    resource: cursors.binding(),
+                    resource: b.binding(),
                 },
                 BindGroupEntry {
                     binding: 3,
-                    resource: time_mix.binding(),
-                },
-                BindGroupEntry {
-                    binding: 4,
                     resource: x.binding(),
                 },
-                BindGroupEntry {
-                    binding: 5,
-                    resource: sx.binding(),
-                },
-                BindGroupEntry {
-                    binding: 6,
-                    resource: output.binding(),
-                },
             ],
         })];
 
         Ok(Self::Atom {
             pipeline,
             bindings,
-            dispatch: [Self::block_count(shape[0] as u32 / 4), shape[1] as u32, 1],
+            dispatch: [1, shape[1] as u32, shape[2] as u32],
         })
     }
 
-    #[allow(clippy::too_many_arguments)]
-    pub fn time_mix(
-        cursors: &'a TensorGpu<u32, ReadWrite>,
-        time_decay: &'a TensorGpu<f32, ReadWrite>,
-        time_first: &'a TensorGpu<f32, ReadWrite>,
-        k: &'a TensorGpu<f32, ReadWrite>,
-        v: &'a TensorGpu<f32, ReadWrite>,
-        r: &'a TensorGpu<f32, ReadWrite>,
-        x: &'a TensorGpu<f32, ReadWrite>,
-        state: TensorView<f32>,
+    /// Group normalization applied on `x`, with weight `w` and bias `b`.
+    /// - `x` shape: `[S, H, A]`.
+    /// - `w` shape: `[S, H, 1]`.
+    /// - `b` shape: `[S, H, 1]`.
+    pub fn group_norm<F: Float>(
+        w: &'a TensorGpu<f16, ReadWrite>,
+        b: &'a TensorGpu<f16, ReadWrite>,
+        x: &'a TensorGpu<F, ReadWrite>,
     ) -> Result<Self, TensorError> {
-        let shape = x.shape;
-        let num_batch = state.shape()[2];
-
-        k.check_shape(shape)?;
-        v.check_shape(shape)?;
-        r.check_shape(shape)?;
-        time_decay.check_shape(Shape::new(shape[0], 1, 1, 1))?;
-        time_first.check_shape(Shape::new(shape[0], 1, 1, 1))?;
-        state.check_shape(Shape::new(shape[0], 4, num_batch, 1))?;
+        let shape = x.shape();
+        w.check_shape(Shape::new(shape[0], shape[1], 1, 1))?;
+        b.check_shape(Shape::new(shape[0], shape[1], 1, 1))?;
 
         let context = &x.context;
-        let pipeline = context.pipeline("time_mix")?;
+        let pipeline = context.pipeline_with("group_norm", &defines([half::<F>("ACT_F16")]))?;
         let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
             label: None,
             layout: &pipeline.get_bind_group_layout(0),
@@ -689,117 +774,69 @@ impl<'a> TensorOp<'a> {
                 },
                 BindGroupEntry {
                     binding: 1,
-                    resource: state.meta_binding(),
+                    resource: w.binding(),
                 },
                 BindGroupEntry {
                     binding: 2,
-                    resource: cursors.binding(),
+                    resource: b.binding(),
                 },
                 BindGroupEntry {
                     binding: 3,
-                    resource: time_decay.binding(),
-                },
-                BindGroupEntry {
-                    binding: 4,
-                    resource: time_first.binding(),
-                },
-                BindGroupEntry {
-                    binding: 5,
-                    resource: k.binding(),
-                },
-                BindGroupEntry {
-                    binding: 6,
-                    resource: v.binding(),
-                },
-                BindGroupEntry {
-                    binding: 7,
-                    resource: r.binding(),
-                },
-                BindGroupEntry {
-                    binding: 8,
                     resource: x.binding(),
                 },
-                BindGroupEntry {
-                    binding: 9,
-                    resource: state.binding(),
-                },
             ],
         })];
 
         Ok(Self::Atom {
             pipeline,
             bindings,
-            dispatch: [Self::block_count(shape[0] as u32 / 4), 1, 1],
+            dispatch: [1, shape[1] as u32, shape[2] as u32],
         })
     }
 
-    #[allow(clippy::too_many_arguments)]
-    pub fn time_mix_v5(
-        cursors: &'a TensorGpu<u32, ReadWrite>,
-        time_decay: &'a TensorGpu<f32, ReadWrite>,
-        time_first: &'a TensorGpu<f32, ReadWrite>,
-        k: &'a TensorGpu<f32, ReadWrite>,
-        v: &'a TensorGpu<f32, ReadWrite>,
-        r: &'a TensorGpu<f32, ReadWrite>,
-        x: &'a TensorGpu<f32, ReadWrite>,
-        state: TensorView<f32>,
+    /// Fp32 matrix-vector multiplication.
+    /// Accumulates in f32 regardless of the input and output types.
+    /// - `matrix` shape: `[C, R, 1]`.
+    /// - `input` shape: `[C, T, B]`.
+    /// - `output` shape: `[R, T, B]`.
+    pub fn matmul_vec_fp16<I: Float, O: Float>(
+        matrix: &'a TensorGpu<f16, ReadWrite>,
+        input: TensorView<'a, I>,
+        output: TensorView<'a, O>,
     ) -> Result<Self, TensorError> {
-        let shape = x.shape;
-        let dim = shape[0] * shape[1];
-        let num_batch = state.shape()[2];
-
-        k.check_shape(shape)?;
-        v.check_shape(shape)?;
-        r.check_shape(shape)?;
-        time_decay.check_shape(Shape::new(shape[0], shape[1], 1, 1))?;
-        time_first.check_shape(Shape::new(shape[0], shape[1], 1, 1))?;
-        state.check_shape(Shape::new(dim, shape[0] + 1, num_batch, 1))?;
+        let shape = output.shape();
+        matrix.check_shape(Shape::new(input.shape()[0], shape[0], 1, 1))?;
+        input.check_shape(Shape::new(matrix.shape[0], shape[1], shape[2], 1))?;
 
-        let context = &x.context;
-        let pipeline = context.pipeline("time_mix_v5")?;
+        let context = &output.tensor.context;
+        let pipeline = context.pipeline_specialized(
+            "matmul_vec_fp16",
+            &defines([half::<I>("IN_F16"), half::<O>("OUT_F16")]),
+            &[("COLUMNS", matrix.shape[0] as u32)],
+        )?;
         let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
             label: None,
             layout: &pipeline.get_bind_group_layout(0),
             entries: &[
-                BindGroupEntry {
-                    binding: 0,
-                    resource: x.meta_binding(),
-                },
                 BindGroupEntry {
                     binding: 1,
-                    resource: state.meta_binding(),
+                    resource: input.meta_binding(),
                 },
                 BindGroupEntry {
                     binding: 2,
-                    resource: cursors.binding(),
+                    resource: output.meta_binding(),
                 },
                 BindGroupEntry {
                     binding: 3,
-                    resource: time_decay.binding(),
+                    resource: matrix.binding(),
                 },
                 BindGroupEntry {
                     binding: 4,
-                    resource: time_first.binding(),
+                    resource: input.binding(),
                 },
                 BindGroupEntry {
                     binding: 5,
-                    resource: k.binding(),
-                },
-                BindGroupEntry {
-                    binding: 6,
-                    resource: v.binding(),
-                },
-                BindGroupEntry {
-                    binding: 7,
-                    resource: r.binding(),
-                },
-                BindGroupEntry {
-                    binding: 8,
-                    resource: x.binding(),
-                },
-                BindGroupEntry {
-                    binding: 9,
-                    resource: state.binding(),
+                    resource: output.binding(),
                 },
             ],
         })];
@@ -807,64 +844,84 @@ impl<'a> TensorOp<'a> {
         Ok(Self::Atom {
             pipeline,
             bindings,
-            dispatch: [Self::round(dim as u32 / 4, 32), 1, 1],
+            dispatch: [matrix.shape[1] as u32 / 4, shape[1] as u32, shape[2] as u32],
         })
     }
 
-    pub fn silu(
-        input: &'a TensorGpu<f32, ReadWrite>,
-        output: &'a TensorGpu<f32, ReadWrite>,
+    /// Int8 matrix-vector multiplication.
+    /// Accumulates in f32 regardless of the input and output types.
+    /// - `matrix` shape: `[C, R, 1]`.
+    /// - `mx` and `rx` shape: `[C, 1, 1]`.
+    /// - `my` and `ry` shape: `[R, 1, 1]`.
+    /// - `input` shape: `[C, T, B]`.
+    /// - `output` shape: `[R, T, B]`.
+    pub fn matmul_vec_int8<I: Float, O: Float>(
+        matrix: &'a TensorGpu<u8, ReadWrite>,
+        mx: &'a TensorGpu<f32, ReadWrite>,
+        rx: &'a TensorGpu<f32, ReadWrite>,
+        my: &'a TensorGpu<f32, ReadWrite>,
+        ry: &'a TensorGpu<f32, ReadWrite>,
+        input: TensorView<'a, I>,
+        output: TensorView<'a, O>,
     ) -> Result<Self, TensorError> {
-        let shape = output.shape;
-        input.check_shape(shape)?;
+        let shape = output.shape();
+        matrix.check_shape(Shape::new(input.shape()[0], shape[0], 1, 1))?;
+        input.check_shape(Shape::new(matrix.shape[0], shape[1], shape[2], 1))?;
+        mx.check_shape(Shape::new(matrix.shape[0], 1, 1, 1))?;
+        rx.check_shape(Shape::new(matrix.shape[0], 1, 1, 1))?;
+        my.check_shape(Shape::new(matrix.shape[1], 1, 1, 1))?;
+        ry.check_shape(Shape::new(matrix.shape[1], 1, 1, 1))?;
 
-        let context = &output.context;
-        let pipeline = context.pipeline("silu")?;
+        let context = &matrix.context;
+        let pipeline = context.pipeline_specialized(
+            "matmul_vec_int8",
+            &defines([
+                half::<I>("IN_F16"),
+                half::<O>("OUT_F16"),
+                // rows stay aligned for `vec4<u32>` loads if their length is a multiple of 16
+                matrix.shape[0].is_multiple_of(16).then_some("WEIGHT_VEC4"),
+            ]),
+            &[("COLUMNS", matrix.shape[0] as u32)],
+        )?;
         let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
             label: None,
             layout: &pipeline.get_bind_group_layout(0),
             entries: &[
                 BindGroupEntry {
-                    binding: 0,
+                    binding: 1,
+                    resource: input.meta_binding(),
+                },
+                BindGroupEntry {
+                    binding: 2,
                     resource: output.meta_binding(),
                 },
                 BindGroupEntry {
-                    binding: 1,
-                    resource: input.binding(),
+                    binding: 3,
+                    resource: matrix.binding(),
                 },
                 BindGroupEntry {
-                    binding: 2,
-                    resource: output.binding(),
+                    binding: 4,
+                    resource: mx.binding(),
                 },
-            ],
-        })];
-
-        Ok(Self::Atom {
-            pipeline,
-            bindings,
-            dispatch: [
-                Self::block_count(shape[0] as u32 / 4),
-                shape[1] as u32,
-                shape[2] as u32,
-            ],
-        })
-    }
-
-    pub fn squared_relu(x: &'a TensorGpu<f32, ReadWrite>) -> Result<Self, TensorError> {
-        let shape = x.shape;
-        let context = &x.context;
-        let pipeline = context.pipeline("squared_relu")?;
-        let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
-            label: None,
-            layout: &pipeline.get_bind_group_layout(0),
-            entries: &[
                 BindGroupEntry {
-                    binding: 0,
-                    resource: x.meta_binding(),
+                    binding: 5,
+                    resource: rx.binding(),
                 },
                 BindGroupEntry {
-                    binding: 1,
-                    resource: x.binding(),
+                    binding: 6,
+                    resource: my.binding(),
+                },
+                BindGroupEntry {
+                    binding: 7,
+                    resource: ry.binding(),
+                },
+                BindGroupEntry {
+                    binding: 8,
+                    resource: input.binding(),
+                },
+                BindGroupEntry {
+                    binding: 9,
+                    resource: output.binding(),
                 },
             ],
         })];
@@ -872,61 +929,69 @@ impl<'a> TensorOp<'a> {
         Ok(Self::Atom {
             pipeline,
             bindings,
-            dispatch: [
-                Self::block_count(shape[0] as u32 / 4),
-                shape[1] as u32,
-                shape[2] as u32,
-            ],
+            dispatch: [matrix.shape[1] as u32 / 4, shape[1] as u32, shape[2] as u32],
         })
     }
 
-    pub fn channel_mix(
-        cursors: &'a TensorGpu<u32, ReadWrite>,
-        r: &'a TensorGpu<f32, ReadWrite>,
-        v: &'a TensorGpu<f32, ReadWrite>,
-        x: &'a TensorGpu<f32, ReadWrite>,
-        state: TensorView<'a, f32>,
+    /// Asymmetric Int8 matrix-vector multiplication.
+    /// Accumulates in f32 regardless of the input and output types.
+    /// - `matrix` shape: `[C, R, 1]`.
+    /// - `scale` and `zero` shape: `[R, 1, 1]`.
+    /// - `input` shape: `[C, T, B]`.
+    /// - `output` shape: `[R, T, B]`.
+    pub fn matmul_vec_int8_asym<I: Float, O: Float>(
+        matrix: &'a TensorGpu<u8, ReadWrite>,
+        scale: &'a TensorGpu<f32, ReadWrite>,
+        zero: &'a TensorGpu<f32, ReadWrite>,
+        input: TensorView<'a, I>,
+        output: TensorView<'a, O>,
     ) -> Result<Self, TensorError> {
-        let shape = x.shape;
-        let num_batch = state.shape()[2];
-        // cursors.check_shape(Shape::new(shape[1], 1, 1, 1))?;
-        v.check_shape(shape)?;
-        r.check_shape(shape)?;
-        state.check_shape(Shape::new(shape[0], 1, num_batch, 1))?;
+        let shape = output.shape();
+        matrix.check_shape(Shape::new(input.shape()[0], shape[0], 1, 1))?;
+        input.check_shape(Shape::new(matrix.shape[0], shape[1], shape[2], 1))?;
+        scale.check_shape(Shape::new(matrix.shape[1], 1, 1, 1))?;
+        zero.check_shape(Shape::new(matrix.shape[1], 1, 1, 1))?;
 
-        let context = &x.context;
-        let pipeline = context.pipeline("channel_mix")?;
+        let context = &matrix.context;
+        let pipeline = context.pipeline_with(
+            "matmul_vec_int8_asym",
+            &defines([half::<I>("IN_F16"), half::<O>("OUT_F16")]),
+        )?;
         let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
             label: None,
             layout: &pipeline.get_bind_group_layout(0),
             entries: &[
                 BindGroupEntry {
                     binding: 0,
-                    resource: x.meta_binding(),
+                    resource: matrix.meta_binding(),
                 },
                 BindGroupEntry {
                     binding: 1,
-                    resource: state.meta_binding(),
+                    resource: input.meta_binding(),
                 },
                 BindGroupEntry {
                     binding: 2,
-                    resource: cursors.binding(),
+                    resource: output.meta_binding(),
                 },
                 BindGroupEntry {
                     binding: 3,
-                    resource: r.binding(),
+                    resource: matrix.binding(),
                 },
                 BindGroupEntry {
                     binding: 4,
-                    resource: v.binding(),
+                    resource: scale.binding(),
                 },
                 BindGroupEntry {
                     binding: 5,
-                    resource: x.binding(),
+                    resource: zero.binding(),
                 },
                 BindGroupEntry {
                     binding: 6,
-                    resource: state.binding(),
+                    resource: input.binding(),
+                },
+                BindGroupEntry {
+                    binding: 7,
+                    resource: output.binding(),
                 },
             ],
         })];
@@ -934,38 +999,69 @@ impl<'a> TensorOp<'a> {
         Ok(Self::Atom {
             pipeline,
             bindings,
-            dispatch: [Self::block_count(shape[0] as u32 / 4), shape[1] as u32, 1],
+            dispatch: [matrix.shape[1] as u32 / 4, shape[1] as u32, shape[2] as u32],
         })
     }
 
-    /// Copy the content of `input` into `output`, given an `offset`.
-    pub fn blit(
-        input: TensorView<'a, f32>,
-        output: TensorView<'a, f32>,
+    /// NFloat4 matrix-vector multiplication.
+    /// Accumulates in f32 regardless of the input and output types.
+    /// - `matrix` shape: `[C, R, 1]`.
+    /// - `absmax` shape: `[C / S, R, 1]`.
+    /// - `input` shape: `[C, T, B]`.
+    /// - `output` shape: `[R, T, B]`.
+    pub fn matmul_vec_nf4<O: Float>(
+        matrix: &'a TensorGpu<u8, ReadWrite>,
+        absmax: &'a TensorGpu<f16, ReadWrite>,
+        quant: &'a TensorGpu<f32, Uniform>,
+        input: TensorView<'a, f16>,
+        output: TensorView<'a, O>,
     ) -> Result<Self, TensorError> {
         let shape = output.shape();
-        input.check_shape(shape)?;
+        matrix.check_shape(Shape::new(input.shape()[0] / 2, shape[0], 1, 1))?;
+        input.check_shape(Shape::new(input.shape()[0], shape[1], shape[2], 1))?;
+        absmax.check_shape(Shape::new(
+            input.shape()[0] / Self::NF4_BLOCK_SIZE,
+            shape[0],
+            1,
+            1,
+        ))?;
 
-        let context = &input.tensor.context;
-        let pipeline = context.pipeline("blit")?;
+        let context = &matrix.context;
+        let pipeline = context.pipeline_with("matmul_vec_nf4", &defines([half::<O>("OUT_F16")]))?;
         let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
             label: None,
             layout: &pipeline.get_bind_group_layout(0),
             entries: &[
+                // BindGroupEntry {
+                //     binding: 0,
+                //     resource: matrix.meta_binding(),
+                // },
                 BindGroupEntry {
-                    binding: 0,
+                    binding: 1,
                     resource: input.meta_binding(),
                 },
                 BindGroupEntry {
-                    binding: 1,
+                    binding: 2,
                     resource: output.meta_binding(),
                 },
                 BindGroupEntry {
-                    binding: 2,
+                    binding: 3,
+                    resource: quant.binding(),
+                },
+                BindGroupEntry {
+                    binding: 4,
+                    resource: matrix.binding(),
+                },
+                BindGroupEntry {
+                    binding: 5,
+                    resource: absmax.binding(),
+                },
+                BindGroupEntry {
+                    binding: 6,
                     resource: input.binding(),
                 },
                 BindGroupEntry {
-                    binding: 3,
+                    binding: 7,
                     resource: output.binding(),
                 },
             ],
@@ -974,43 +1070,53 @@ impl<'a> TensorOp<'a> {
         Ok(Self::Atom {
             pipeline,
             bindings,
-            dispatch: [
-                Self::block_count(shape[0] as u32 / 4),
-                shape[1] as u32,
-                shape[2] as u32,
-            ],
+            dispatch: [matrix.shape[1] as u32 / 4, shape[1] as u32, shape[2] as u32],
         })
     }
 
-    pub fn blend(
-        factor: &'a TensorGpu<f32, Uniform>,
-        input: &'a TensorGpu<f32, ReadWrite>,
-        output: &'a TensorGpu<f32, ReadWrite>,
+    /// Fp16 matrix-matrix multiplication.
+    /// Accumulates in f32 regardless of the input and output types.
+    /// - `matrix` shape: `[K, M, B]`.
+    /// - `input` shape: `[K, N, B]`.
+    /// - `output` shape: `[M, N, B]`.
+    pub fn matmul_mat_fp16<O: Float>(
+        matrix: TensorView<'a, f16>,
+        input: TensorView<'a, f16>,
+        output: TensorView<'a, O>,
     ) -> Result<Self, TensorError> {
         let shape = output.shape();
-        input.check_shape(shape)?;
-        factor.check_shape(Shape::new(4, 1, 1, 1))?;
+        matrix.check_shape(Shape::new(matrix.shape()[0], shape[0], shape[2], 1))?;
+        input.check_shape(Shape::new(input.shape()[0], shape[1], shape[2], 1))?;
 
-        let context = &output.context;
-        let pipeline = context.pipeline("blend")?;
+        let context = &output.tensor.context;
+        let pipeline =
+            context.pipeline_with("matmul_mat_fp16", &defines([half::<O>("OUT_F16")]))?;
         let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
             label: None,
             layout: &pipeline.get_bind_group_layout(0),
             entries: &[
                 BindGroupEntry {
                     binding: 0,
-                    resource: output.meta_binding(),
+                    resource: matrix.meta_binding(),
                 },
                 BindGroupEntry {
                     binding: 1,
-                    resource: factor.binding(),
+                    resource: input.meta_binding(),
                 },
                 BindGroupEntry {
                     binding: 2,
-                    resource: input.binding(),
+                    resource: output.meta_binding(),
                 },
                 BindGroupEntry {
                     binding: 3,
+                    resource: matrix.binding(),
+                },
+                BindGroupEntry {
+                    binding: 4,
+                    resource: input.binding(),
+                },
+                BindGroupEntry {
+                    binding: 5,
                     resource: output.binding(),
                 },
             ],
@@ -1020,37 +1126,49 @@ impl<'a> TensorOp<'a> {
             pipeline,
             bindings,
             dispatch: [
-                Self::block_count(shape[0] as u32 / 4),
-                shape[1] as u32,
+                Self::round(Self::round(shape[0] as u32, 4), 8),
+                Self::round(Self::round(shape[1] as u32, 4), 8),
                 shape[2] as u32,
             ],
         })
     }
 
-    pub fn blend_lora(
-        factor: &'a TensorGpu<f32, Uniform>,
-        xa: TensorView<'a, f16>,
-        xb: TensorView<'a, f16>,
-        output: TensorView<'a, f16>,
+    /// Int8 matrix-matrix multiplication.
+    /// Accumulates in f32 regardless of the input and output types.
+    /// - `matrix` shape: `[K, M, B]`.
+    /// - `input` shape: `[K, N, B]`.
+    /// - `output` shape: `[M, N, B]`.
+    pub fn matmul_mat_int8<O: Float>(
+        matrix: TensorView<'a, u8>,
+        mx: &'a TensorGpu<f32, ReadWrite>,
+        rx: &'a TensorGpu<f32, ReadWrite>,
+        my: &'a TensorGpu<f32, ReadWrite>,
+        ry: &'a TensorGpu<f32, ReadWrite>,
+        input: TensorView<'a, f16>,
+        output: TensorView<'a, O>,
     ) -> Result<Self, TensorError> {
         let shape = output.shape();
-        factor.check_shape(Shape::new(4, 1, 1, 1))?;
-        xa.check_shape(Shape::new(xa.shape()[0], shape[0], shape[2], 1))?;
-        xb.check_shape(Shape::new(xb.shape()[0], shape[1], shape[2], 1))?;
+        matrix.check_shape(Shape::new(matrix.shape()[0], shape[0], shape[2], 1))?;
+        input.check_shape(Shape::new(input.shape()[0], shape[1], shape[2], 1))?;
+        mx.check_shape(Shape::new(matrix.shape()[0], shape[2], 1, 1))?;
+        rx.check_shape(Shape::new(matrix.shape()[0], shape[2], 1, 1))?;
+        my.check_shape(Shape::new(matrix.shape()[1], shape[2], 1, 1))?;
+        ry.check_shape(Shape::new(matrix.shape()[1], shape[2], 1, 1))?;
 
         let context = &output.tensor.context;
-        let pipeline = context.pipeline("blend_lora")?;
+        let pipeline =
+            context.pipeline_with("matmul_mat_int8", &defines([half::<O>("OUT_F16")]))?;
         let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
             label: None,
             layout: &pipeline.get_bind_group_layout(0),
             entries: &[
                 BindGroupEntry {
                     binding: 0,
-                    resource: xa.meta_binding(),
+                    resource: matrix.meta_binding(),
                 },
                 BindGroupEntry {
                     binding: 1,
-                    resource: xb.meta_binding(),
+                    resource: input.meta_binding(),
                 },
                 BindGroupEntry {
                     binding: 2,
@@ -1058,22 +1176,34 @@ impl<'a> TensorOp<'a> {
                 },
                 BindGroupEntry {
                     binding: 3,
-                    resource: factor.binding(),
+                    resource: mx.binding(),
                 },
                 BindGroupEntry {
                     binding: 4,
-                    resource: xa.binding(),
+                    resource: rx.binding(),
                 },
                 BindGroupEntry {
                     binding: 5,
-                    resource: xb.binding(),
+                    resource: my.binding(),
                 },
                 BindGroupEntry {
                     binding: 6,
-                    resource: output.binding(),
+                    resource: ry.binding(),
                 },
-            ],
-        })];
+                BindGroupEntry {
+                    binding: 7,
+                    resource: matrix.binding(),
+                },
+                BindGroupEntry {
+                    binding: 8,
+                    resource: input.binding(),
+                },
+                BindGroupEntry {
+                    binding: 9,
+                    resource: output.binding(),
+                },
+            ],
+        })];
 
         Ok(Self::Atom {
             pipeline,
@@ -1086,21 +1216,62 @@ impl<'a> TensorOp<'a> {
         })
     }
 
-    pub fn half(output: &'a TensorGpu<f32, ReadWrite>) -> Result<Self, TensorError> {
+    /// Asymmetric Int8 matrix-matrix multiplication.
+    /// Accumulates in f32 regardless of the input and output types.
+    /// - `matrix` shape: `[K, M, B]`.
+    /// - `scale` and `zero` shape: `[M, B, 1]`.
+    /// - `input` shape: `[K, N, B]`.
+    /// - `output` shape: `[M, N, B]`.
+    pub fn matmul_mat_int8_asym<O: Float>(
+        matrix: TensorView<'a, u8>,
+        scale: &'a TensorGpu<f32, ReadWrite>,
+        zero: &'a TensorGpu<f32, ReadWrite>,
+        input: TensorView<'a, f16>,
+        output: TensorView<'a, O>,
+    ) -> Result<Self, TensorError> {
         let shape = output.shape();
+        matrix.check_shape(Shape::new(matrix.shape()[0], shape[0], shape[2], 1))?;
+        input.check_shape(Shape::new(input.shape()[0], shape[1], shape[2], 1))?;
+        scale.check_shape(Shape::new(matrix.shape()[1], shape[2], 1, 1))?;
+        zero.check_shape(Shape::new(matrix.shape()[1], shape[2], 1, 1))?;
 
-        let context = &output.context;
-        let pipeline = context.pipeline("half")?;
+        let context = &output.tensor.context;
+        let pipeline =
+            context.pipeline_with("matmul_mat_int8_asym", &defines([half::<O>("OUT_F16")]))?;
         let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
             label: None,
             layout: &pipeline.get_bind_group_layout(0),
             entries: &[
                 BindGroupEntry {
                     binding: 0,
-                    resource: output.meta_binding(),
+                    resource: matrix.meta_binding(),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: input.meta_binding(),
                 },
                 BindGroupEntry {
                     binding: 2,
+                    resource: output.meta_binding(),
+                },
+                BindGroupEntry {
+                    binding: 3,
+                    resource: scale.binding(),
+                },
+                BindGroupEntry {
+                    binding: 4,
+                    resource: zero.binding(),
+                },
+                BindGroupEntry {
+                    binding: 5,
+                    resource: matrix.binding(),
+                },
+                BindGroupEntry {
+                    binding: 6,
+                    resource: input.binding(),
+                },
+                BindGroupEntry {
+                    binding: 7,
                     resource: output.binding(),
                 },
             ],
@@ -1110,95 +1281,23 @@ impl<'a> TensorOp<'a> {
             pipeline,
             bindings,
             dispatch: [
-                Self::block_count(shape[0] as u32 / 4),
-                shape[1] as u32,
+                Self::round(Self::round(shape[0] as u32, 4), 8),
+                Self::round(Self::round(shape[1] as u32, 4), 8),
                 shape[2] as u32,
             ],
         })
     }
 
-    pub fn quantize_mat_int8(
-        input: &'a TensorGpu<f16, ReadWrite>,
-        mx: &'a TensorGpu<f32, ReadWrite>,
-        rx: &'a TensorGpu<f32, ReadWrite>,
-        my: &'a TensorGpu<f32, ReadWrite>,
-        ry: &'a TensorGpu<f32, ReadWrite>,
-        output: &'a TensorGpu<u8, ReadWrite>,
-    ) -> Result<Self, TensorError> {
-        let shape = output.shape;
-        input.check_shape(shape)?;
-        mx.check_shape(Shape::new(shape[0], 1, 1, 1))?;
-        rx.check_shape(Shape::new(shape[0], 1, 1, 1))?;
-        my.check_shape(Shape::new(shape[1], 1, 1, 1))?;
-        ry.check_shape(Shape::new(shape[1], 1, 1, 1))?;
-
-        let context = &output.context;
-        let entries = &[
-            BindGroupEntry {
-                binding: 0,
-                resource: output.meta_binding(),
-            },
-            BindGroupEntry {
-                binding: 1,
-                resource: input.binding(),
-            },
-            BindGroupEntry {
-                binding: 2,
-                resource: mx.binding(),
-            },
-            BindGroupEntry {
-                binding: 3,
-                resource: rx.binding(),
-            },
-            BindGroupEntry {
-                binding: 4,
-                resource: my.binding(),
-            },
-            BindGroupEntry {
-                binding: 5,
-                resource: ry.binding(),
-            },
-            BindGroupEntry {
-                binding: 6,
-                resource: output.binding(),
-            },
-        ];
-        let create_op = |name: &'static str, dispatch| -> Result<Self, TensorError> {
-            let pipeline = context.pipeline(name)?;
-            let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
-                label: None,
-                layout: &pipeline.get_bind_group_layout(0),
-                entries,
-            })];
-            Ok(Self::Atom {
-                pipeline,
-                bindings,
-                dispatch,
-            })
-        };
-
-        let my = create_op("quant_mat_int8_my", [1, shape[1] as u32, 1])?;
-        let ry = create_op("quant_mat_int8_ry", [1, shape[1] as u32, 1])?;
-        let mx = create_op("quant_mat_int8_mx", [1, shape[0] as u32 / 4, 1])?;
-        let rx = create_op("quant_mat_int8_rx", [1, shape[0] as u32 / 4, 1])?;
-        let quantize = create_op("quant_mat_int8", [shape[0] as u32 / 4, shape[1] as u32, 1])?;
-
-        if shape[1] > shape[0] {
-            Ok(Self::List(vec![my, mx, rx, ry, quantize]))
-        } else {
-            Ok(Self::List(vec![mx, my, rx, ry, quantize]))
-        }
-    }
-
-    pub fn quantize_fp16(
-        input: &'a TensorGpu<f32, ReadWrite>,
-        output: &'a TensorGpu<f16, ReadWrite>,
+    /// Add `input` onto `output`.
+    pub fn add<F: Float>(
+        input: &'a TensorGpu<F, ReadWrite>,
+        output: &'a TensorGpu<F, ReadWrite>,
     ) -> Result<Self, TensorError> {
         let shape = output.shape;
         input.check_shape(shape)?;
 
         let context = &output.context;
-        let pipeline = context.pipeline("quant_fp16")?;
+        let pipeline = context.pipeline_with("add", &defines([half::<F>("ACT_F16")]))?;
         let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
             label: None,
             layout: &pipeline.get_bind_group_layout(0),
@@ -1229,201 +1328,1498 @@ impl<'a> TensorOp<'a> {
         })
     }
 
-    pub fn quantize_mat_nf4(
-        input: &'a TensorGpu<f16, ReadWrite>,
-        quant: &'a TensorGpu<f32, Uniform>,
-        absmax: &'a TensorGpu<f16, ReadWrite>,
-        output: &'a TensorGpu<u8, ReadWrite>,
+    pub fn token_shift<F: Float>(
+        cursors: &'a TensorGpu<u32, ReadWrite>,
+        time_mix: &'a TensorGpu<f16, ReadWrite>,
+        x: &'a TensorGpu<F, ReadWrite>,
+        sx: impl Into<StateView<'a>>,
+        output: &'a TensorGpu<F, ReadWrite>,
     ) -> Result<Self, TensorError> {
-        let context = &output.context;
-        let shape = output.shape();
-        let input_shape = Shape::new(shape[0] << 1, shape[1], shape[2], shape[3]);
-        let absmax_shape = Shape::new(
-            input_shape[0] / Self::NF4_BLOCK_SIZE,
-            shape[1],
-            shape[2],
-            shape[3],
-        );
-
-        input.check_shape(input_shape)?;
-        absmax.check_shape(absmax_shape)?;
-
-        let absmax_f32: TensorGpu<f32, ReadWrite> = context.tensor_init(absmax_shape);
+        let sx = sx.into();
+        let shape = output.shape;
+        let num_batch = sx.shape()[2];
+        // cursors.check_shape(Shape::new(shape[1], 1, 1, 1))?;
+        time_mix.check_shape(Shape::new(shape[0], 1, 1, 1))?;
+        x.check_shape(shape)?;
+        sx.check_shape(Shape::new(shape[0], sx.shape()[1], num_batch, 1))?;
 
-        let pipeline = context.pipeline("quant_mat_nf4_absmax")?;
+        let context = &output.context;
+        let pipeline =
+            context.pipeline_with("token_shift", &defines([half::<F>("ACT_F16"), sx.define()]))?;
         let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
             label: None,
             layout: &pipeline.get_bind_group_layout(0),
             entries: &[
                 BindGroupEntry {
                     binding: 0,
-                    resource: absmax_f32.meta_binding(),
+                    resource: output.meta_binding(),
                 },
                 BindGroupEntry {
                     binding: 1,
-                    resource: quant.binding(),
+                    resource: sx.meta_binding(),
                 },
                 BindGroupEntry {
                     binding: 2,
-                    resource: input.binding(),
+                    resource: cursors.binding(),
                 },
                 BindGroupEntry {
                     binding: 3,
-                    resource: absmax_f32.binding(),
+                    resource: time_mix.binding(),
                 },
                 BindGroupEntry {
                     binding: 4,
+                    resource: x.binding(),
+                },
+                BindGroupEntry {
+                    binding: 5,
+                    resource: sx.binding(),
+                },
+                BindGroupEntry {
+                    binding: 6,
                     resource: output.binding(),
                 },
             ],
         })];
-        let compute_absmax = Self::Atom {
+
+        Ok(Self::Atom {
             pipeline,
             bindings,
-            dispatch: [
-                Self::block_count(absmax_shape[0] as u32),
-                absmax_shape[1] as u32,
-                absmax_shape[2] as u32,
-            ],
-        };
+            dispatch: [Self::block_count(shape[0] as u32 / 4), shape[1] as u32, 1],
+        })
+    }
 
-        let pipeline = context.pipeline("quant_mat_nf4")?;
+    #[allow(clippy::too_many_arguments)]
+    pub fn time_mix<F: Float>(
+        cursors: &'a TensorGpu<u32, ReadWrite>,
+        time_decay: &'a TensorGpu<f32, ReadWrite>,
+        time_first: &'a TensorGpu<f32, ReadWrite>,
+        k: &'a TensorGpu<F, ReadWrite>,
+        v: &'a TensorGpu<F, ReadWrite>,
+        r: &'a TensorGpu<F, ReadWrite>,
+        x: &'a TensorGpu<F, ReadWrite>,
+        state: TensorView<f32>,
+    ) -> Result<Self, TensorError> {
+        let shape = x.shape;
+        let num_batch = state.shape()[2];
+
+        k.check_shape(shape)?;
+        v.check_shape(shape)?;
+        r.check_shape(shape)?;
+        time_decay.check_shape(Shape::new(shape[0], 1, 1, 1))?;
+        time_first.check_shape(Shape::new(shape[0], 1, 1, 1))?;
+        state.check_shape(Shape::new(shape[0], 4, num_batch, 1))?;
+
+        let context = &x.context;
+        let pipeline = context.pipeline_with("time_mix", &defines([half::<F>("ACT_F16")]))?;
         let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
             label: None,
             layout: &pipeline.get_bind_group_layout(0),
             entries: &[
                 BindGroupEntry {
                     binding: 0,
-                    resource: output.meta_binding(),
+                    resource: x.meta_binding(),
                 },
                 BindGroupEntry {
                     binding: 1,
-                    resource: quant.binding(),
+                    resource: state.meta_binding(),
                 },
                 BindGroupEntry {
                     binding: 2,
-                    resource: input.binding(),
+                    resource: cursors.binding(),
                 },
                 BindGroupEntry {
                     binding: 3,
-                    resource: absmax_f32.binding(),
+                    resource: time_decay.binding(),
                 },
                 BindGroupEntry {
                     binding: 4,
-                    resource: output.binding(),
+                    resource: time_first.binding(),
                 },
-            ],
-        })];
-        let quantize = Self::Atom {
-            pipeline,
-            bindings,
-            dispatch: [
-                Self::block_count(shape[0] as u32),
-                shape[1] as u32,
-                shape[2] as u32,
-            ],
-        };
-
-        let pipeline = context.pipeline("quant_fp16")?;
-        let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
-            label: None,
-            layout: &pipeline.get_bind_group_layout(0),
-            entries: &[
                 BindGroupEntry {
-                    binding: 0,
-                    resource: absmax.meta_binding(),
+                    binding: 5,
+                    resource: k.binding(),
                 },
                 BindGroupEntry {
-                    binding: 1,
-                    resource: absmax_f32.binding(),
+                    binding: 6,
+                    resource: v.binding(),
                 },
                 BindGroupEntry {
-                    binding: 2,
-                    resource: absmax.binding(),
+                    binding: 7,
+                    resource: r.binding(),
+                },
+                BindGroupEntry {
+                    binding: 8,
+                    resource: x.binding(),
+                },
+                BindGroupEntry {
+                    binding: 9,
+                    resource: state.binding(),
                 },
             ],
         })];
-        let quantize_absmax = Self::Atom {
+
+        Ok(Self::Atom {
             pipeline,
             bindings,
-            dispatch: [
-                Self::block_count(absmax_shape[0] as u32 / 4),
-                absmax_shape[1] as u32,
-                absmax_shape[2] as u32,
+            dispatch: [Self::block_count(shape[0] as u32 / 4), 1, 1],
+        })
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn time_mix_v5<F: Float>(
+        cursors: &'a TensorGpu<u32, ReadWrite>,
+        time_decay: &'a TensorGpu<f32, ReadWrite>,
+        time_first: &'a TensorGpu<f32, ReadWrite>,
+        k: &'a TensorGpu<F, ReadWrite>,
+        v: &'a TensorGpu<F, ReadWrite>,
+        r: &'a TensorGpu<F, ReadWrite>,
+        x: &'a TensorGpu<F, ReadWrite>,
+        state: impl Into<StateView<'a>>,
+    ) -> Result<Self, TensorError> {
+        let state = state.into();
+        let shape = x.shape;
+        let dim = shape[0] * shape[1];
+        let num_batch = state.shape()[2];
+
+        k.check_shape(shape)?;
+        v.check_shape(shape)?;
+        r.check_shape(shape)?;
+        time_decay.check_shape(Shape::new(shape[0], shape[1], 1, 1))?;
+        time_first.check_shape(Shape::new(shape[0], shape[1], 1, 1))?;
+        state.check_shape(Shape::new(dim, shape[0] + 1, num_batch, 1))?;
+
+        let context = &x.context;
+        let pipeline = context.pipeline_specialized(
+            "time_mix_v5",
+            &defines([half::<F>("ACT_F16"), state.define()]),
+            &[("HEAD_SIZE", shape[0] as u32)],
+        )?;
+        let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
+            label: None,
+            layout: &pipeline.get_bind_group_layout(0),
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: x.meta_binding(),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: state.meta_binding(),
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: cursors.binding(),
+                },
+                BindGroupEntry {
+                    binding: 3,
+                    resource: time_decay.binding(),
+                },
+                BindGroupEntry {
+                    binding: 4,
+                    resource: time_first.binding(),
+                },
+                BindGroupEntry {
+                    binding: 5,
+                    resource: k.binding(),
+                },
+                BindGroupEntry {
+                    binding: 6,
+                    resource: v.binding(),
+                },
+                BindGroupEntry {
+                    binding: 7,
+                    resource: r.binding(),
+                },
+                BindGroupEntry {
+                    binding: 8,
+                    resource: x.binding(),
+                },
+                BindGroupEntry {
+                    binding: 9,
+                    resource: state.binding(),
+                },
+            ],
+        })];
+
+        Ok(Self::Atom {
+            pipeline,
+            bindings,
+            dispatch: [Self::round(dim as u32 / 4, 32), 1, 1],
+        })
+    }
+
+    pub fn silu<F: Float>(
+        input: &'a TensorGpu<F, ReadWrite>,
+        output: &'a TensorGpu<F, ReadWrite>,
+    ) -> Result<Self, TensorError> {
+        let shape = output.shape;
+        input.check_shape(shape)?;
+
+        let context = &output.context;
+        let pipeline = context.pipeline_with("silu", &defines([half::<F>("ACT_F16")]))?;
+        let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
+            label: None,
+            layout: &pipeline.get_bind_group_layout(0),
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: output.meta_binding(),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: input.binding(),
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: output.binding(),
+                },
+            ],
+        })];
+
+        Ok(Self::Atom {
+            pipeline,
+            bindings,
+            dispatch: [
+                Self::block_count(shape[0] as u32 / 4),
+                shape[1] as u32,
+                shape[2] as u32,
+            ],
+        })
+    }
+
+    pub fn squared_relu<F: Float>(x: &'a TensorGpu<F, ReadWrite>) -> Result<Self, TensorError> {
+        let shape = x.shape;
+        let context = &x.context;
+        let pipeline = context.pipeline_with("squared_relu", &defines([half::<F>("ACT_F16")]))?;
+        let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
+            label: None,
+            layout: &pipeline.get_bind_group_layout(0),
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: x.meta_binding(),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: x.binding(),
+                },
+            ],
+        })];
+
+        Ok(Self::Atom {
+            pipeline,
+            bindings,
+            dispatch: [
+                Self::block_count(shape[0] as u32 / 4),
+                shape[1] as u32,
+                shape[2] as u32,
+            ],
+        })
+    }
+
+    pub fn channel_mix<F: Float>(
+        cursors: &'a TensorGpu<u32, ReadWrite>,
+        r: &'a TensorGpu<F, ReadWrite>,
+        v: &'a TensorGpu<F, ReadWrite>,
+        x: &'a TensorGpu<F, ReadWrite>,
+        state: impl Into<StateView<'a>>,
+    ) -> Result<Self, TensorError> {
+        let state = state.into();
+        let shape = x.shape;
+        let num_batch = state.shape()[2];
+        // cursors.check_shape(Shape::new(shape[1], 1, 1, 1))?;
+        v.check_shape(shape)?;
+        r.check_shape(shape)?;
+        state.check_shape(Shape::new(shape[0], 1, num_batch, 1))?;
+
+        let context = &x.context;
+        let pipeline = context.pipeline_with(
+            "channel_mix",
+            &defines([half::<F>("ACT_F16"), state.define()]),
+        )?;
+        let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
+            label: None,
+            layout: &pipeline.get_bind_group_layout(0),
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: x.meta_binding(),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: state.meta_binding(),
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: cursors.binding(),
+                },
+                BindGroupEntry {
+                    binding: 3,
+                    resource: r.binding(),
+                },
+                BindGroupEntry {
+                    binding: 4,
+                    resource: v.binding(),
+                },
+                BindGroupEntry {
+                    binding: 5,
+                    resource: x.binding(),
+                },
+                BindGroupEntry {
+                    binding: 6,
+                    resource: state.binding(),
+                },
+            ],
+        })];
+
+        Ok(Self::Atom {
+            pipeline,
+            bindings,
+            dispatch: [Self::block_count(shape[0] as u32 / 4), shape[1] as u32, 1],
+        })
+    }
+
+    /// Copy the content of `input` into `output`, given an `offset`.
+    pub fn blit<F: Float>(
+        input: TensorView<'a, F>,
+        output: TensorView<'a, F>,
+    ) -> Result<Self, TensorError> {
+        let shape = output.shape();
+        input.check_shape(shape)?;
+
+        let context = &input.tensor.context;
+        let pipeline = context.pipeline_with("blit", &defines([half::<F>("ACT_F16")]))?;
+        let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
+            label: None,
+            layout: &pipeline.get_bind_group_layout(0),
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: input.meta_binding(),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: output.meta_binding(),
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: input.binding(),
+                },
+                BindGroupEntry {
+                    binding: 3,
+                    resource: output.binding(),
+                },
+            ],
+        })];
+
+        Ok(Self::Atom {
+            pipeline,
+            bindings,
+            dispatch: [
+                Self::block_count(shape[0] as u32 / 4),
+                shape[1] as u32,
+                shape[2] as u32,
             ],
+        })
+    }
+
+    /// Copy `input` into the `f32` tensor `output`, converting it if it is `f16`.
+    pub fn blit_f32<F: Float>(
+        input: TensorView<'a, F>,
+        output: TensorView<'a, f32>,
+    ) -> Result<Self, TensorError> {
+        match F::DATA_TYPE {
+            Dtype::F32 => Self::cast_with("blit", input, output),
+            Dtype::F16 => Self::cast_with("cast_f32", input, output),
+            _ => Err(TensorError::Type),
+        }
+    }
+
+    /// Convert elements between `f32` and `f16`.
+    pub fn cast<I: Scalar, O: Scalar>(
+        input: TensorView<'a, I>,
+        output: TensorView<'a, O>,
+    ) -> Result<Self, TensorError> {
+        let name = match (I::DATA_TYPE, O::DATA_TYPE) {
+            (Dtype::F32, Dtype::F16) => "cast_f16",
+            (Dtype::F16, Dtype::F32) => "cast_f32",
+            _ => return Err(TensorError::Type),
         };
+        Self::cast_with(name, input, output)
+    }
+
+    /// Run a kernel with the bindings shared by `blit`, `cast_f16` and `cast_f32`.
+    fn cast_with<I: Scalar, O: Scalar>(
+        name: &'static str,
+        input: TensorView<'a, I>,
+        output: TensorView<'a, O>,
+    ) -> Result<Self, TensorError> {
+        let shape = output.shape();
+        input.check_shape(shape)?;
+
+        let context = &output.tensor.context;
+        let pipeline = context.pipeline(name)?;
+        let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
+            label: None,
+            layout: &pipeline.get_bind_group_layout(0),
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: input.meta_binding(),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: output.meta_binding(),
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: input.binding(),
+                },
+                BindGroupEntry {
+                    binding: 3,
+                    resource: output.binding(),
+                },
+            ],
+        })];
+
+        Ok(Self::Atom {
+            pipeline,
+            bindings,
+            dispatch: [
+                Self::block_count(shape[0] as u32 / 4),
+                shape[1] as u32,
+                shape[2] as u32,
+            ],
+        })
+    }
+
+    /// Set all elements in the view to `value`.
+    pub fn fill(output: TensorView<'a, f32>, value: f32) -> Result<Self, TensorError> {
+        let shape = output.shape();
+
+        let context = &output.tensor.context;
+        let pipeline = context.pipeline("fill")?;
+        let value = context.request_value_uniform(value);
+        let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
+            label: None,
+            layout: &pipeline.get_bind_group_layout(0),
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: output.meta_binding(),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: value.as_entire_binding(),
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: output.binding(),
+                },
+            ],
+        })];
+
+        Ok(Self::Atom {
+            pipeline,
+            bindings,
+            dispatch: [
+                Self::block_count(shape[0] as u32 / 4),
+                shape[1] as u32,
+                shape[2] as u32,
+            ],
+        })
+    }
+
+    pub fn blend(
+        factor: &'a TensorGpu<f32, Uniform>,
+        input: &'a TensorGpu<f32, ReadWrite>,
+        output: &'a TensorGpu<f32, ReadWrite>,
+    ) -> Result<Self, TensorError> {
+        let shape = output.shape();
+        input.check_shape(shape)?;
+        factor.check_shape(Shape::new(4, 1, 1, 1))?;
+
+        let context = &output.context;
+        let pipeline = context.pipeline("blend")?;
+        let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
+            label: None,
+            layout: &pipeline.get_bind_group_layout(0),
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: output.meta_binding(),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: factor.binding(),
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: input.binding(),
+                },
+                BindGroupEntry {
+                    binding: 3,
+                    resource: output.binding(),
+                },
+            ],
+        })];
+
+        Ok(Self::Atom {
+            pipeline,
+            bindings,
+            dispatch: [
+                Self::block_count(shape[0] as u32 / 4),
+                shape[1] as u32,
+                shape[2] as u32,
+            ],
+        })
+    }
+
+    pub fn blend_lora<F: Float>(
+        factor: &'a TensorGpu<f32, Uniform>,
+        xa: TensorView<'a, f16>,
+        xb: TensorView<'a, f16>,
+        output: TensorView<'a, F>,
+    ) -> Result<Self, TensorError> {
+        let shape = output.shape();
+        factor.check_shape(Shape::new(4, 1, 1, 1))?;
+        xa.check_shape(Shape::new(xa.shape()[0], shape[0], shape[2], 1))?;
+        xb.check_shape(Shape::new(xb.shape()[0], shape[1], shape[2], 1))?;
+
+        let context = &output.tensor.context;
+        let pipeline = context.pipeline_with("blend_lora", &defines([half::<F>("ACT_F16")]))?;
+        let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
+            label: None,
+            layout: &pipeline.get_bind_group_layout(0),
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: xa.meta_binding(),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: xb.meta_binding(),
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: output.meta_binding(),
+                },
+                BindGroupEntry {
+                    binding: 3,
+                    resource: factor.binding(),
+                },
+                BindGroupEntry {
+                    binding: 4,
+                    resource: xa.binding(),
+                },
+                BindGroupEntry {
+                    binding: 5,
+                    resource: xb.binding(),
+                },
+                BindGroupEntry {
+                    binding: 6,
+                    resource: output.binding(),
+                },
+            ],
+        })];
+
+        Ok(Self::Atom {
+            pipeline,
+            bindings,
+            dispatch: [
+                Self::round(Self::round(shape[0] as u32, 4), 8),
+                Self::round(Self::round(shape[1] as u32, 4), 8),
+                shape[2] as u32,
+            ],
+        })
+    }
+
+    pub fn half<F: Float>(output: &'a TensorGpu<F, ReadWrite>) -> Result<Self, TensorError> {
+        let shape = output.shape();
+
+        let context = &output.context;
+        let pipeline = context.pipeline_with("half", &defines([half::<F>("ACT_F16")]))?;
+        let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
+            label: None,
+            layout: &pipeline.get_bind_group_layout(0),
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: output.meta_binding(),
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: output.binding(),
+                },
+            ],
+        })];
+
+        Ok(Self::Atom {
+            pipeline,
+            bindings,
+            dispatch: [
+                Self::block_count(shape[0] as u32 / 4),
+                shape[1] as u32,
+                shape[2] as u32,
+            ],
+        })
+    }
+
+    /// Scale `output` in place by `factor.x`.
+    pub fn discount<F: Float>(
+        factor: &'a TensorGpu<f32, Uniform>,
+        output: &'a TensorGpu<F, ReadWrite>,
+    ) -> Result<Self, TensorError> {
+        let shape = output.shape();
+        factor.check_shape(Shape::new(4, 1, 1, 1))?;
+
+        let context = &output.context;
+        let pipeline = context.pipeline_with("discount", &defines([half::<F>("ACT_F16")]))?;
+        let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
+            label: None,
+            layout: &pipeline.get_bind_group_layout(0),
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: output.meta_binding(),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: factor.binding(),
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: output.binding(),
+                },
+            ],
+        })];
+
+        Ok(Self::Atom {
+            pipeline,
+            bindings,
+            dispatch: [
+                Self::block_count(shape[0] as u32 / 4),
+                shape[1] as u32,
+                shape[2] as u32,
+            ],
+        })
+    }
+
+    /// Fill `output` with samples from the uniform distribution.
+    /// - `param` holds `[seed, low, high, 0]`, where `low` and `high` are bits of `f32`.
+    pub fn rand_uniform(
+        param: &'a TensorGpu<u32, Uniform>,
+        output: &'a TensorGpu<f32, ReadWrite>,
+    ) -> Result<Self, TensorError> {
+        Self::rand("rand_uniform", param, output)
+    }
+
+    /// Fill `output` with samples from the normal distribution.
+    /// - `param` holds `[seed, mean, std, 0]`, where `mean` and `std` are bits of `f32`.
+    pub fn rand_normal(
+        param: &'a TensorGpu<u32, Uniform>,
+        output: &'a TensorGpu<f32, ReadWrite>,
+    ) -> Result<Self, TensorError> {
+        Self::rand("rand_normal", param, output)
+    }
+
+    fn rand(
+        name: &'static str,
+        param: &'a TensorGpu<u32, Uniform>,
+        output: &'a TensorGpu<f32, ReadWrite>,
+    ) -> Result<Self, TensorError> {
+        let shape = output.shape();
+        param.check_shape(Shape::new(4, 1, 1, 1))?;
+
+        let context = &output.context;
+        let pipeline = context.pipeline(name)?;
+        let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
+            label: None,
+            layout: &pipeline.get_bind_group_layout(0),
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: output.meta_binding(),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: param.binding(),
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: output.binding(),
+                },
+            ],
+        })];
+
+        Ok(Self::Atom {
+            pipeline,
+            bindings,
+            dispatch: [
+                Self::block_count(shape[0] as u32),
+                shape[1] as u32,
+                (shape[2] * shape[3]) as u32,
+            ],
+        })
+    }
+
+    pub fn quantize_mat_int8(
+        input: &'a TensorGpu<f16, ReadWrite>,
+        mx: &'a TensorGpu<f32, ReadWrite>,
+        rx: &'a TensorGpu<f32, ReadWrite>,
+        my: &'a TensorGpu<f32, ReadWrite>,
+        ry: &'a TensorGpu<f32, ReadWrite>,
+        output: &'a TensorGpu<u8, ReadWrite>,
+    ) -> Result<Self, TensorError> {
+        let shape = output.shape;
+        input.check_shape(shape)?;
+        mx.check_shape(Shape::new(shape[0], 1, 1, 1))?;
+        rx.check_shape(Shape::new(shape[0], 1, 1, 1))?;
+        my.check_shape(Shape::new(shape[1], 1, 1, 1))?;
+        ry.check_shape(Shape::new(shape[1], 1, 1, 1))?;
+
+        let context = &output.context;
+        let entries = &[
+            BindGroupEntry {
+                binding: 0,
+                resource: output.meta_binding(),
+            },
+            BindGroupEntry {
+                binding: 1,
+                resource: input.binding(),
+            },
+            BindGroupEntry {
+                binding: 2,
+                resource: mx.binding(),
+            },
+            BindGroupEntry {
+                binding: 3,
+                resource: rx.binding(),
+            },
+            BindGroupEntry {
+                binding: 4,
+                resource: my.binding(),
+            },
+            BindGroupEntry {
+                binding: 5,
+                resource: ry.binding(),
+            },
+            BindGroupEntry {
+                binding: 6,
+                resource: output.binding(),
+            },
+        ];
+        let create_op = |name: &'static str, dispatch| -> Result<Self, TensorError> {
+            let pipeline = context.pipeline(name)?;
+            let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
+                label: None,
+                layout: &pipeline.get_bind_group_layout(0),
+                entries,
+            })];
+            Ok(Self::Atom {
+                pipeline,
+                bindings,
+                dispatch,
+            })
+        };
+
+        let my = create_op("quant_mat_int8_my", [1, shape[1] as u32, 1])?;
+        let ry = create_op("quant_mat_int8_ry", [1, shape[1] as u32, 1])?;
+        let mx = create_op("quant_mat_int8_mx", [1, shape[0] as u32 / 4, 1])?;
+        let rx = create_op("quant_mat_int8_rx", [1, shape[0] as u32 / 4, 1])?;
+        let quantize = create_op("quant_mat_int8", [shape[0] as u32 / 4, shape[1] as u32, 1])?;
+
+        if shape[1] > shape[0] {
+            Ok(Self::List(vec![my, mx, rx, ry, quantize]))
+        } else {
+            Ok(Self::List(vec![mx, my, rx, ry, quantize]))
+        }
+    }
+
+    /// Asymmetric Int8 quantization with a per-row scale and zero point.
+    /// - `input` and `output` shape: `[C, R, 1]`.
+    /// - `scale` and `zero` shape: `[R, 1, 1]`.
+    pub fn quantize_mat_int8_asym(
+        input: &'a TensorGpu<f16, ReadWrite>,
+        scale: &'a TensorGpu<f32, ReadWrite>,
+        zero: &'a TensorGpu<f32, ReadWrite>,
+        output: &'a TensorGpu<u8, ReadWrite>,
+    ) -> Result<Self, TensorError> {
+        let shape = output.shape;
+        input.check_shape(shape)?;
+        scale.check_shape(Shape::new(shape[1], 1, 1, 1))?;
+        zero.check_shape(Shape::new(shape[1], 1, 1, 1))?;
+
+        let context = &output.context;
+        let pipeline = context.pipeline("quant_mat_int8_asym")?;
+        let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
+            label: None,
+            layout: &pipeline.get_bind_group_layout(0),
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: output.meta_binding(),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: input.binding(),
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: scale.binding(),
+                },
+                BindGroupEntry {
+                    binding: 3,
+                    resource: zero.binding(),
+                },
+                BindGroupEntry {
+                    binding: 4,
+                    resource: output.binding(),
+                },
+            ],
+        })];
+
+        Ok(Self::Atom {
+            pipeline,
+            bindings,
+            dispatch: [1, shape[1] as u32, 1],
+        })
+    }
+
+    pub fn quantize_fp16<F: Float>(
+        input: &'a TensorGpu<F, ReadWrite>,
+        output: &'a TensorGpu<f16, ReadWrite>,
+    ) -> Result<Self, TensorError> {
+        let shape = output.shape;
+        input.check_shape(shape)?;
+
+        let context = &output.context;
+        let pipeline = context.pipeline_with("quant_fp16", &defines([half::<F>("ACT_F16")]))?;
+        let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
+            label: None,
+            layout: &pipeline.get_bind_group_layout(0),
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: output.meta_binding(),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: input.binding(),
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: output.binding(),
+                },
+            ],
+        })];
+
+        Ok(Self::Atom {
+            pipeline,
+            bindings,
+            dispatch: [
+                Self::block_count(shape[0] as u32 / 4),
+                shape[1] as u32,
+                shape[2] as u32,
+            ],
+        })
+    }
+
+    pub fn quantize_mat_nf4(
+        input: &'a TensorGpu<f16, ReadWrite>,
+        quant: &'a TensorGpu<f32, Uniform>,
+        absmax: &'a TensorGpu<f16, ReadWrite>,
+        output: &'a TensorGpu<u8, ReadWrite>,
+    ) -> Result<Self, TensorError> {
+        let context = &output.context;
+        let shape = output.shape();
+        let input_shape = Shape::new(shape[0] << 1, shape[1], shape[2], shape[3]);
+        let absmax_shape = Shape::new(
+            input_shape[0] / Self::NF4_BLOCK_SIZE,
+            shape[1],
+            shape[2],
+            shape[3],
+        );
+
+        input.check_shape(input_shape)?;
+        absmax.check_shape(absmax_shape)?;
+
+        let absmax_f32: TensorGpu<f32, ReadWrite> = context.tensor_init(absmax_shape);
+
+        let pipeline = context.pipeline("quant_mat_nf4_absmax")?;
+        let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
+            label: None,
+            layout: &pipeline.get_bind_group_layout(0),
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: absmax_f32.meta_binding(),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: quant.binding(),
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: input.binding(),
+                },
+                BindGroupEntry {
+                    binding: 3,
+                    resource: absmax_f32.binding(),
+                },
+                BindGroupEntry {
+                    binding: 4,
+                    resource: output.binding(),
+                },
+            ],
+        })];
+        let compute_absmax = Self::Atom {
+            pipeline,
+            bindings,
+            dispatch: [
+                Self::block_count(absmax_shape[0] as u32),
+                absmax_shape[1] as u32,
+                absmax_shape[2] as u32,
+            ],
+        };
+
+        let pipeline = context.pipeline("quant_mat_nf4")?;
+        let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
+            label: None,
+            layout: &pipeline.get_bind_group_layout(0),
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: output.meta_binding(),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: quant.binding(),
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: input.binding(),
+                },
+                BindGroupEntry {
+                    binding: 3,
+                    resource: absmax_f32.binding(),
+                },
+                BindGroupEntry {
+                    binding: 4,
+                    resource: output.binding(),
+                },
+            ],
+        })];
+        let quantize = Self::Atom {
+            pipeline,
+            bindings,
+            dispatch: [
+                Self::block_count(shape[0] as u32),
+                shape[1] as u32,
+                shape[2] as u32,
+            ],
+        };
+
+        let pipeline = context.pipeline("quant_fp16")?;
+        let bindings = vec![context.device.create_bind_group(&BindGroupDescriptor {
+            label: None,
+            layout: &pipeline.get_bind_group_layout(0),
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: absmax.meta_binding(),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: absmax_f32.binding(),
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: absmax.binding(),
+                },
+            ],
+        })];
+        let quantize_absmax = Self::Atom {
+            pipeline,
+            bindings,
+            dispatch: [
+                Self::block_count(absmax_shape[0] as u32 / 4),
+                absmax_shape[1] as u32,
+                absmax_shape[2] as u32,
+            ],
+        };
+
+        Ok(Self::List(vec![compute_absmax, quantize, quantize_absmax]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::PI;
+
+    use half::f16;
+    use itertools::Itertools;
+    use wgpu::{CommandEncoderDescriptor, ComputePassDescriptor, PowerPreference};
+    // use wgpu_profiler::GpuProfiler;
+
+    use super::{TensorOp, TensorPass, TensorSequence};
+    use crate::{
+        context::{Context, ContextBuilder, Instance},
+        num::Scalar,
+        tensor::{
+            ops::TensorCommand, shape::TensorAxis, ReadBack, ReadWrite, Shape, TensorCpu,
+            TensorGpu, TensorInit, TensorShape,
+        },
+    };
+
+    fn is_approx(a: f32, b: f32) -> bool {
+        (a - b).abs() <= f32::max(f32::EPSILON, f32::max(a.abs(), b.abs()) * f32::EPSILON)
+    }
+
+    fn is_approx_eps(a: f32, b: f32, eps: f32) -> bool {
+        (a - b).abs() <= f32::max(eps, f32::max(a.abs(), b.abs()) * eps)
+    }
+
+    fn create_context() -> Result<Context, anyhow::Error> {
+        let adapter = pollster::block_on(async {
+            let instance = Instance::new();
+            instance.adapter(PowerPreference::HighPerformance).await
+        })?;
+        let context = pollster::block_on(async {
+            ContextBuilder::new(adapter)
+                // .with_features(Features::TIMESTAMP_QUERY | Features::TIMESTAMP_QUERY_INSIDE_PASSES)
+                .build()
+                .await
+        })?;
+        Ok(context)
+    }
+
+    #[test]
+    fn test_copy() -> Result<(), anyhow::Error> {
+        let context = match create_context() {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+        fastrand::seed(42);
+
+        let x = vec![0.0, 1.5, 2.0, -1.0];
+        let shape = Shape::new(x.len(), 1, 1, 1);
+
+        let x_device: TensorGpu<_, _> = context.tensor_from_data(shape, x.clone())?;
+        let x_map = context.tensor_init(x_device.shape());
+
+        let mut encoder = context
+            .device
+            .create_command_encoder(&CommandEncoderDescriptor::default());
+        encoder.copy_tensor(&x_device, &x_map)?;
+        context.queue.submit(Some(encoder.finish()));
+
+        let x_host = TensorCpu::from(x_map);
+        let x_host = Vec::from(x_host);
+
+        assert_eq!(x, x_host);
+        Ok(())
+    }
+
+    #[test]
+    fn test_softmax() -> Result<(), anyhow::Error> {
+        let context = match create_context() {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+        fastrand::seed(42);
+
+        const C: usize = 1000;
+        const T: usize = 3;
+        const B: usize = 2;
+
+        let x = [(); C * T * B]
+            .map(|_| 10.0 * (fastrand::f32() - 0.5))
+            .to_vec();
+        let shape = Shape::new(C, T, B, 1);
+
+        let x_dev: TensorGpu<_, _> = context.tensor_from_data(shape, x.clone())?;
+        let x_map = context.tensor_init(x_dev.shape());
+
+        let softmax = TensorOp::softmax(&x_dev)?;
+
+        let mut encoder = context
+            .device
+            .create_command_encoder(&CommandEncoderDescriptor::default());
+
+        let mut pass = encoder.begin_compute_pass(&ComputePassDescriptor::default());
+        pass.execute_tensor_op(&softmax);
+        drop(pass);
+
+        encoder.copy_tensor(&x_dev, &x_map)?;
+        context.queue.submit(Some(encoder.finish()));
+
+        let x_host = TensorCpu::from(x_map);
+        let x_host = Vec::from(x_host);
+
+        let mut ans = vec![];
+        for x in &x.into_iter().chunks(C) {
+            let x = x.collect_vec().into_iter();
+            let max = x.clone().reduce(f32::max).unwrap_or_default();
+            let x = x.map(|x| (x - max).exp());
+            let sum: f32 = x.clone().sum();
+            let mut x: Vec<_> = x.map(|x| x / sum).collect();
+            ans.append(&mut x);
+        }
+
+        for (index, (a, b)) in Iterator::zip(x_host.into_iter(), ans.into_iter()).enumerate() {
+            assert!(
+                is_approx(a, b),
+                "Failed at index {index}, computed: {a} vs. answer: {b}"
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_softmax_temperature() -> Result<(), anyhow::Error> {
+        let context = match create_context() {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+        fastrand::seed(42);
+
+        const C: usize = 1000;
+        const T: usize = 3;
+        const B: usize = 2;
+
+        let x = [(); C * T * B]
+            .map(|_| 10.0 * (fastrand::f32() - 0.5))
+            .to_vec();
+        let temperature = vec![0.5, 2.0];
+        let shape = Shape::new(C, T, B, 1);
+
+        let x_dev: TensorGpu<_, _> = context.tensor_from_data(shape, x.clone())?;
+        let x_map = context.tensor_init(x_dev.shape());
+        let temperature_dev: TensorGpu<_, _> =
+            context.tensor_from_data(Shape::new(B, 1, 1, 1), temperature.clone())?;
+
+        let softmax = TensorOp::softmax_temperature(&x_dev, &temperature_dev)?;
+
+        let mut encoder = context
+            .device
+            .create_command_encoder(&CommandEncoderDescriptor::default());
+
+        let mut pass = encoder.begin_compute_pass(&ComputePassDescriptor::default());
+        pass.execute_tensor_op(&softmax);
+        drop(pass);
+
+        encoder.copy_tensor(&x_dev, &x_map)?;
+        context.queue.submit(Some(encoder.finish()));
+
+        let x_host = TensorCpu::from(x_map);
+        let x_host = Vec::from(x_host);
+
+        let mut ans = vec![];
+        for (index, x) in (&x.into_iter().chunks(C)).into_iter().enumerate() {
+            let temperature = temperature[index / T];
+            let x = x.map(|x| x / temperature).collect_vec().into_iter();
+            let max = x.clone().reduce(f32::max).unwrap_or_default();
+            let x = x.map(|x| (x - max).exp());
+            let sum: f32 = x.clone().sum();
+            let mut x: Vec<_> = x.map(|x| x / sum).collect();
+            ans.append(&mut x);
+        }
+
+        for (index, (a, b)) in x_host.into_iter().zip(ans).enumerate() {
+            assert!(
+                is_approx(a, b),
+                "Failed at index {index}, computed: {a} vs. answer: {b}"
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_penalty() -> Result<(), anyhow::Error> {
+        let context = match create_context() {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+        fastrand::seed(42);
+
+        const C: usize = 1000;
+        const T: usize = 2;
+        const B: usize = 2;
+
+        let x = [(); C * T * B]
+            .map(|_| 10.0 * (fastrand::f32() - 0.5))
+            .to_vec();
+        let count = [(); C * B].map(|_| fastrand::u32(0..3)).to_vec();
+        let penalty = vec![1.5, 0.5, 0.25, 0.0, 1.0, 0.0, 1.0, 0.0];
+
+        let shape = Shape::new(C, T, B, 1);
+        let x_dev: TensorGpu<_, _> = context.tensor_from_data(shape, x.clone())?;
+        let x_map = context.tensor_init(shape);
+        let count_dev = context.tensor_from_data(Shape::new(C, B, 1, 1), count.clone())?;
+        let penalty_dev = context.tensor_from_data(Shape::new(4, B, 1, 1), penalty.clone())?;
+
+        let op = TensorOp::penalty(&penalty_dev, &count_dev, &x_dev)?;
+
+        let mut encoder = context
+            .device
+            .create_command_encoder(&CommandEncoderDescriptor::default());
+
+        let mut pass = encoder.begin_compute_pass(&ComputePassDescriptor::default());
+        pass.execute_tensor_op(&op);
+        drop(pass);
+
+        encoder.copy_tensor(&x_dev, &x_map)?;
+        context.queue.submit(Some(encoder.finish()));
+
+        let x_host = Vec::from(TensorCpu::from(x_map));
+
+        let mut ans = vec![];
+        for (index, x) in x.into_iter().enumerate() {
+            let batch = index / (C * T);
+            let n = count[batch * C + index % C] as f32;
+            let [repetition, presence, frequency, _] = penalty[batch * 4..batch * 4 + 4] else {
+                unreachable!()
+            };
+            let x = match n > 0.0 {
+                true if x > 0.0 => x / repetition - presence,
+                true => x * repetition - presence,
+                false => x,
+            };
+            ans.push(x - frequency * n);
+        }
+
+        for (index, (a, b)) in x_host.into_iter().zip(ans).enumerate() {
+            assert!(
+                is_approx(a, b),
+                "Failed at index {index}, computed: {a} vs. answer: {b}"
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_mask() -> Result<(), anyhow::Error> {
+        let context = match create_context() {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+        fastrand::seed(42);
+
+        const C: usize = 1000;
+        const T: usize = 2;
+        const B: usize = 2;
+        const W: usize = C.div_ceil(32);
+
+        let x = [(); C * T * B].map(|_| fastrand::f32()).to_vec();
+        let mask = [(); W * B].map(|_| fastrand::u32(..)).to_vec();
+
+        let shape = Shape::new(C, T, B, 1);
+        let x_dev: TensorGpu<_, _> = context.tensor_from_data(shape, x.clone())?;
+        let x_map = context.tensor_init(shape);
+        let mask_dev = context.tensor_from_data(Shape::new(W, B, 1, 1), mask.clone())?;
+
+        let op = TensorOp::mask(&mask_dev, &x_dev)?;
+
+        let mut encoder = context
+            .device
+            .create_command_encoder(&CommandEncoderDescriptor::default());
+
+        let mut pass = encoder.begin_compute_pass(&ComputePassDescriptor::default());
+        pass.execute_tensor_op(&op);
+        drop(pass);
+
+        encoder.copy_tensor(&x_dev, &x_map)?;
+        context.queue.submit(Some(encoder.finish()));
+
+        let x_host = Vec::from(TensorCpu::from(x_map));
+        for (index, (a, b)) in x_host.into_iter().zip(x).enumerate() {
+            let (batch, token) = (index / (C * T), index % C);
+            let allowed = mask[batch * W + token / 32] & (1 << (token % 32)) != 0;
+            let b = if allowed { b } else { f32::NEG_INFINITY };
+            assert_eq!(a, b, "Failed at index {index}");
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify() -> Result<(), anyhow::Error> {
+        let context = match create_context() {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+        fastrand::seed(42);
+
+        const C: usize = 1000;
+        const T: usize = 5;
+        const B: usize = 3;
+
+        let x = [(); C * T * B].map(|_| fastrand::f32()).to_vec();
+        let argmax = |batch: usize, token: usize| {
+            let start = (batch * T + token) * C;
+            x[start..start + C]
+                .iter()
+                .enumerate()
+                .fold(
+                    (0, f32::MIN),
+                    |(i, a), (j, &b)| if b > a { (j, b) } else { (i, a) },
+                )
+                .0 as u32
+        };
+
+        // batch 0 accepts everything, batch 1 rejects the token at position 3, batch 2 the first draft
+        let mut tokens = vec![0u32; T * B];
+        for token in 1..T {
+            tokens[token] = argmax(0, token - 1);
+            tokens[T + token] = argmax(1, token - 1);
+        }
+        tokens[T + 3] = (tokens[T + 3] + 1) % C as u32;
+        tokens[2 * T + 1] = (argmax(2, 0) + 1) % C as u32;
+
+        let x_dev: TensorGpu<_, _> = context.tensor_from_data(Shape::new(C, T, B, 1), x.clone())?;
+        let tokens_dev = context.tensor_from_data(Shape::new(T, B, 1, 1), tokens)?;
+        let output_dev = context.tensor_init(Shape::new(2, B, 1, 1));
+        let output_map = context.tensor_init(Shape::new(2, B, 1, 1));
+
+        let op = TensorOp::verify(&x_dev, &tokens_dev, &output_dev)?;
+
+        let mut encoder = context
+            .device
+            .create_command_encoder(&CommandEncoderDescriptor::default());
+
+        let mut pass = encoder.begin_compute_pass(&ComputePassDescriptor::default());
+        pass.execute_tensor_op(&op);
+        drop(pass);
+
+        encoder.copy_tensor(&output_dev, &output_map)?;
+        context.queue.submit(Some(encoder.finish()));
+
+        let output = Vec::from(TensorCpu::from(output_map));
+        assert_eq!(
+            output,
+            vec![4, argmax(0, 4), 2, argmax(1, 2), 0, argmax(2, 0)]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_score() -> Result<(), anyhow::Error> {
+        let context = match create_context() {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+        fastrand::seed(42);
+
+        const C: usize = 1000;
+        const T: usize = 3;
+        const B: usize = 2;
+
+        let x = [(); C * T * B].map(|_| 10.0 * fastrand::f32()).to_vec();
+        let tokens = [(); T * B].map(|_| fastrand::u32(..C as u32)).to_vec();
 
-        Ok(Self::List(vec![compute_absmax, quantize, quantize_absmax]))
-    }
-}
+        let x_dev: TensorGpu<_, _> = context.tensor_from_data(Shape::new(C, T, B, 1), x.clone())?;
+        let tokens_dev = context.tensor_from_data(Shape::new(T, B, 1, 1), tokens.clone())?;
+        let output_dev = context.tensor_init(Shape::new(T, B, 1, 1));
+        let output_map = context.tensor_init(Shape::new(T, B, 1, 1));
 
-#[cfg(test)]
-mod tests {
-    use std::f32::consts::PI;
+        let op = TensorOp::score(&x_dev, &tokens_dev, &output_dev)?;
 
-    use half::f16;
-    use itertools::Itertools;
-    use wgpu::{CommandEncoderDescriptor, ComputePassDescriptor, PowerPreference};
-    // use wgpu_profiler::GpuProfiler;
+        let mut encoder = context
+            .device
+            .create_command_encoder(&CommandEncoderDescriptor::default());
 
-    use super::{TensorOp, TensorPass};
-    use crate::{
-        context::{Context, ContextBuilder, Instance},
-        tensor::{ops::TensorCommand, Shape, TensorCpu, TensorGpu, TensorInit, TensorShape},
-    };
+        let mut pass = encoder.begin_compute_pass(&ComputePassDescriptor::default());
+        pass.execute_tensor_op(&op);
+        drop(pass);
 
-    fn is_approx(a: f32, b: f32) -> bool {
-        (a - b).abs() <= f32::max(f32::EPSILON, f32::max(a.abs(), b.abs()) * f32::EPSILON)
-    }
+        encoder.copy_tensor(&output_dev, &output_map)?;
+        context.queue.submit(Some(encoder.finish()));
 
-    fn is_approx_eps(a: f32, b: f32, eps: f32) -> bool {
-        (a - b).abs() <= f32::max(eps, f32::max(a.abs(), b.abs()) * eps)
-    }
+        let output = Vec::from(TensorCpu::from(output_map));
+        for (index, (a, token)) in output.into_iter().zip(tokens).enumerate() {
+            let x = &x[index * C..(index + 1) * C];
+            let max = x.iter().fold(f32::MIN, |a, &b| a.max(b));
+            let sum: f32 = x.iter().map(|&x| (x - max).exp()).sum();
+            let b = x[token as usize] - max - sum.ln();
+            assert!(
+                is_approx_eps(a, b, 1.0e-4),
+                "Failed at index {index}, {a} != {b}"
+            );
+        }
 
-    fn create_context() -> Result<Context, anyhow::Error> {
-        let adapter = pollster::block_on(async {
-            let instance = Instance::new();
-            instance.adapter(PowerPreference::HighPerformance).await
-        })?;
-        let context = pollster::block_on(async {
-            ContextBuilder::new(adapter)
-                .with_default_pipelines()
-                // .with_features(Features::TIMESTAMP_QUERY | Features::TIMESTAMP_QUERY_INSIDE_PASSES)
-                .build()
-                .await
-        })?;
-        Ok(context)
+        Ok(())
     }
 
     #[test]
-    fn test_copy() -> Result<(), anyhow::Error> {
+    fn test_pool() -> Result<(), anyhow::Error> {
         let context = match create_context() {
             Ok(context) => context,
             Err(_) => return Ok(()),
         };
         fastrand::seed(42);
 
-        let x = vec![0.0, 1.5, 2.0, -1.0];
-        let shape = Shape::new(x.len(), 1, 1, 1);
+        const C: usize = 1000;
+        const T: usize = 5;
+        const B: usize = 2;
 
-        let x_device: TensorGpu<_, _> = context.tensor_from_data(shape, x.clone())?;
-        let x_map = context.tensor_init(x_device.shape());
+        let x = [(); C * T * B].map(|_| fastrand::f32() - 0.5).to_vec();
+        let weight = [(); T * B].map(|_| fastrand::f32()).to_vec();
+
+        let x_dev: TensorGpu<_, _> = context.tensor_from_data(Shape::new(C, T, B, 1), x.clone())?;
+        let weight_dev = context.tensor_from_data(Shape::new(T, B, 1, 1), weight.clone())?;
+        let output_dev = context.tensor_init(Shape::new(C, 1, B, 1));
+        let output_map = context.tensor_init(Shape::new(C, 1, B, 1));
+
+        let ops = TensorOp::List(vec![
+            TensorOp::pool(&weight_dev, &x_dev, &output_dev)?,
+            TensorOp::l2_norm(&output_dev)?,
+        ]);
 
         let mut encoder = context
             .device
             .create_command_encoder(&CommandEncoderDescriptor::default());
-        encoder.copy_tensor(&x_device, &x_map)?;
+
+        let mut pass = encoder.begin_compute_pass(&ComputePassDescriptor::default());
+        pass.execute_tensor_op(&ops);
+        drop(pass);
+
+        encoder.copy_tensor(&output_dev, &output_map)?;
         context.queue.submit(Some(encoder.finish()));
 
-        let x_host = TensorCpu::from(x_map);
-        let x_host = Vec::from(x_host);
+        let output = Vec::from(TensorCpu::from(output_map));
+        for batch in 0..B {
+            let mut expected = vec![0.0f32; C];
+            for token in 0..T {
+                let w = weight[batch * T + token];
+                let start = (batch * T + token) * C;
+                for (y, x) in expected.iter_mut().zip(&x[start..start + C]) {
+                    *y += w * x;
+                }
+            }
+            let norm = expected.iter().map(|x| x * x).sum::<f32>().sqrt();
+            for (index, (a, b)) in output[batch * C..(batch + 1) * C]
+                .iter()
+                .zip(expected)
+                .enumerate()
+            {
+                let b = b / norm;
+                assert!(
+                    is_approx_eps(*a, b, 1.0e-4),
+                    "Failed at index {index}, {a} != {b}"
+                );
+            }
+        }
 
-        assert_eq!(x, x_host);
         Ok(())
     }
 
     #[test]
-    fn test_softmax() -> Result<(), anyhow::Error> {
+    fn test_cosine_similarity() -> Result<(), anyhow::Error> {
         let context = match create_context() {
             Ok(context) => context,
             Err(_) => return Ok(()),
@@ -1431,49 +2827,48 @@ mod tests {
         fastrand::seed(42);
 
         const C: usize = 1000;
-        const T: usize = 3;
+        const N: usize = 7;
         const B: usize = 2;
 
-        let x = [(); C * T * B]
-            .map(|_| 10.0 * (fastrand::f32() - 0.5))
-            .to_vec();
-        let shape = Shape::new(C, T, B, 1);
+        let query = [(); C * B].map(|_| fastrand::f32() - 0.5).to_vec();
+        let mut matrix = [(); C * N].map(|_| fastrand::f32() - 0.5).to_vec();
+        // a scaled copy of the first query is as similar as it gets
+        for (x, q) in matrix[..C].iter_mut().zip(&query[..C]) {
+            *x = 3.0 * q;
+        }
 
-        let x_dev: TensorGpu<_, _> = context.tensor_from_data(shape, x.clone())?;
-        let x_map = context.tensor_init(x_dev.shape());
+        let query_dev = context.tensor_from_data(Shape::new(C, 1, B, 1), query.clone())?;
+        let matrix_dev = context.tensor_from_data(Shape::new(C, N, 1, 1), matrix.clone())?;
+        let output_dev = context.tensor_init(Shape::new(N, B, 1, 1));
+        let output_map = context.tensor_init(Shape::new(N, B, 1, 1));
 
-        let softmax = TensorOp::softmax(&x_dev)?;
+        let op = TensorOp::cosine_similarity(&query_dev, &matrix_dev, &output_dev)?;
 
         let mut encoder = context
             .device
             .create_command_encoder(&CommandEncoderDescriptor::default());
 
         let mut pass = encoder.begin_compute_pass(&ComputePassDescriptor::default());
-        pass.execute_tensor_op(&softmax);
+        pass.execute_tensor_op(&op);
         drop(pass);
 
-        encoder.copy_tensor(&x_dev, &x_map)?;
+        encoder.copy_tensor(&output_dev, &output_map)?;
         context.queue.submit(Some(encoder.finish()));
 
-        let x_host = TensorCpu::from(x_map);
-        let x_host = Vec::from(x_host);
-
-        let mut ans = vec![];
-        for x in &x.into_iter().chunks(C) {
-            let x = x.collect_vec().into_iter();
-            let max = x.clone().reduce(f32::max).unwrap_or_default();
-            let x = x.map(|x| (x - max).exp());
-            let sum: f32 = x.clone().sum();
-            let mut x: Vec<_> = x.map(|x| x / sum).collect();
-            ans.append(&mut x);
-        }
-
-        for (index, (a, b)) in Iterator::zip(x_host.into_iter(), ans.into_iter()).enumerate() {
+        let output = Vec::from(TensorCpu::from(output_map));
+        for (index, a) in output.iter().enumerate() {
+            let (batch, row) = (index / N, index % N);
+            let q = &query[batch * C..(batch + 1) * C];
+            let k = &matrix[row * C..(row + 1) * C];
+            let dot: f32 = q.iter().zip(k).map(|(q, k)| q * k).sum();
+            let norm = |x: &[f32]| x.iter().map(|x| x * x).sum::<f32>().sqrt();
+            let b = dot / (norm(q) * norm(k));
             assert!(
-                is_approx(a, b),
-                "Failed at index {index}, computed: {a} vs. answer: {b}"
+                is_approx_eps(*a, b, 1.0e-4),
+                "Failed at index {index}, {a} != {b}"
             );
         }
+        assert!(is_approx_eps(output[0], 1.0, 1.0e-4));
 
         Ok(())
     }
@@ -1890,6 +3285,134 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_matmul_int8() -> Result<(), anyhow::Error> {
+        let context = match create_context() {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+        fastrand::seed(42);
+
+        const C: usize = 1536;
+        const R: usize = 1024;
+        const T: usize = 7;
+
+        fn read_back<T: Scalar>(
+            context: &Context,
+            tensor: &TensorGpu<T, ReadWrite>,
+        ) -> Result<Vec<T>, anyhow::Error> {
+            let map = TensorGpu::<T, ReadBack>::init(context, tensor.shape());
+            let mut encoder = context
+                .device
+                .create_command_encoder(&CommandEncoderDescriptor::default());
+            encoder.copy_tensor(tensor, &map)?;
+            context.queue.submit(Some(encoder.finish()));
+            Ok(Vec::from(TensorCpu::from(map)))
+        }
+
+        let matrix = vec![(); C * R]
+            .into_iter()
+            .map(|_| 2.0 * fastrand::f32() - 0.5)
+            .map(f16::from_f32)
+            .collect_vec();
+        let input = vec![(); C * T]
+            .into_iter()
+            .map(|_| 2.0 * fastrand::f32() - 1.0)
+            .collect_vec();
+
+        let matrix_f16_shape = Shape::new(C, R, 1, 1);
+        let input_shape = Shape::new(C, T, 1, 1);
+        let output_shape = Shape::new(R, T, 1, 1);
+
+        let input_dev: TensorGpu<f32, ReadWrite> = context.tensor_from_data(input_shape, &input)?;
+        let output_dev: TensorGpu<f32, ReadWrite> = context.tensor_init(output_shape);
+
+        let execute = |op: TensorOp| -> Result<Vec<f32>, anyhow::Error> {
+            let mut encoder = context
+                .device
+                .create_command_encoder(&CommandEncoderDescriptor::default());
+            let mut pass = encoder.begin_compute_pass(&ComputePassDescriptor::default());
+            pass.execute_tensor_op(&op);
+            drop(pass);
+            context.queue.submit(Some(encoder.finish()));
+            read_back(&context, &output_dev)
+        };
+
+        // compute the answer on the CPU from the dequantized matrix
+        let check = |dequant: &dyn Fn(usize, usize) -> f32, output: Vec<f32>| {
+            for token in 0..T {
+                for row in 0..R {
+                    let answer = (0..C)
+                        .map(|col| dequant(row, col) * input[token * C + col])
+                        .sum::<f32>();
+                    let computed = output[token * R + row];
+                    assert!(
+                        is_approx_eps(computed, answer, 1.0e-3),
+                        "Failed at token {token} row {row}, computed: {computed} vs. answer: {answer}"
+                    );
+                }
+            }
+        };
+
+        // symmetric min-max
+        {
+            let matrix_dev = context.tensor_from_data(matrix_f16_shape, &matrix)?;
+            let matrix_u8_dev = context.tensor_init(matrix_f16_shape);
+            let mx = context.tensor_init(Shape::new(C, 1, 1, 1));
+            let rx = context.tensor_init(Shape::new(C, 1, 1, 1));
+            let my = context.tensor_init(Shape::new(R, 1, 1, 1));
+            let ry = context.tensor_init(Shape::new(R, 1, 1, 1));
+
+            let output = execute(TensorOp::List(vec![
+                TensorOp::quantize_mat_int8(&matrix_dev, &mx, &rx, &my, &ry, &matrix_u8_dev)?,
+                TensorOp::matmul_vec_int8(
+                    &matrix_u8_dev,
+                    &mx,
+                    &rx,
+                    &my,
+                    &ry,
+                    input_dev.view(.., .., .., ..)?,
+                    output_dev.view(.., .., .., ..)?,
+                )?,
+            ]))?;
+
+            let w = read_back(&context, &matrix_u8_dev)?;
+            let [mx, rx, my, ry] = [&mx, &rx, &my, &ry].map(|x| read_back(&context, x).unwrap());
+            let dequant = |row: usize, col: usize| {
+                let q = w[row * C + col] as f32 / 255.0;
+                q * ry[row] * rx[col] + my[row] + mx[col]
+            };
+            check(&dequant, output);
+        }
+
+        // asymmetric with per-row zero points
+        {
+            let matrix_dev = context.tensor_from_data(matrix_f16_shape, &matrix)?;
+            let matrix_u8_dev = context.tensor_init(matrix_f16_shape);
+            let scale = context.tensor_init(Shape::new(R, 1, 1, 1));
+            let zero = context.tensor_init(Shape::new(R, 1, 1, 1));
+
+            let output = execute(TensorOp::List(vec![
+                TensorOp::quantize_mat_int8_asym(&matrix_dev, &scale, &zero, &matrix_u8_dev)?,
+                TensorOp::matmul_vec_int8_asym(
+                    &matrix_u8_dev,
+                    &scale,
+                    &zero,
+                    input_dev.view(.., .., .., ..)?,
+                    output_dev.view(.., .., .., ..)?,
+                )?,
+            ]))?;
+
+            let w = read_back(&context, &matrix_u8_dev)?;
+            let [scale, zero] = [&scale, &zero].map(|x| read_back(&context, x).unwrap());
+            let dequant =
+                |row: usize, col: usize| (w[row * C + col] as f32 - zero[row]) * scale[row];
+            check(&dequant, output);
+        }
+
+        Ok(())
+    }
+
     #[test]
     fn test_blit() -> Result<(), anyhow::Error> {
         let context = match create_context() {
@@ -1942,4 +3465,255 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_sequence() -> Result<(), anyhow::Error> {
+        let context = match create_context() {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+
+        let shape = Shape::new(4, 2, 1, 1);
+        let input: TensorGpu<f32, ReadWrite> = context.tensor_init(shape);
+        let x: TensorGpu<f32, ReadWrite> = context.tensor_init(shape);
+        let output: TensorGpu<f32, ReadWrite> = context.tensor_init(shape);
+        let map = TensorGpu::init(&context, shape);
+
+        // output = 2 * input, recorded once
+        let mut sequence = TensorSequence::new();
+        sequence.clear_tensor(&output);
+        sequence.push(TensorOp::blit(
+            input.view(.., .., .., ..)?,
+            x.view(.., .., .., ..)?,
+        )?);
+        sequence.push(TensorOp::add(&x, &output)?);
+        sequence.push(TensorOp::add(&x, &output)?);
+        sequence.copy_tensor(&output, &map)?;
+        assert_eq!(sequence.len(), 5);
+
+        for round in 0..3 {
+            let data = (0..shape.len()).map(|x| (x + round) as f32).collect_vec();
+            input.load(&context.tensor_from_data(shape, data.clone())?)?;
+
+            let mut encoder = context
+                .device
+                .create_command_encoder(&CommandEncoderDescriptor::default());
+            sequence.replay(&mut encoder);
+            context.queue.submit(Some(encoder.finish()));
+
+            let output = Vec::from(TensorCpu::from(map.clone()));
+            let expected = data.iter().map(|x| 2.0 * x).collect_vec();
+            assert_eq!(output, expected);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_blit_step() -> Result<(), anyhow::Error> {
+        let context = match create_context() {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+
+        let shape = Shape::new(4, 6, 2, 1);
+        let input = (0..shape.len()).map(|x| x as f32).collect_vec();
+        let input = TensorGpu::from_data(&context, shape, input)?;
+
+        // every other row of the second batch, then every third row of both batches
+        let output: TensorGpu<f32, _> = context.tensor_init(Shape::new(4, 3, 1, 1));
+        let input_view = input.view(.., (..).with_step(2), 1, ..)?;
+        assert_eq!(input_view.shape(), output.shape());
+        let first = TensorOp::blit(input_view, output.view(.., .., .., ..)?)?;
+
+        let stacked: TensorGpu<f32, _> = context.tensor_init(Shape::new(4, 2, 2, 1));
+        let input_view = input.view(.., (1..).with_step(3), .., ..)?;
+        assert_eq!(input_view.shape(), stacked.shape());
+        let second = TensorOp::blit(input_view, stacked.view(.., .., .., ..)?)?;
+
+        // a view of a view: rows 2 and 4 of the second batch
+        let composed: TensorGpu<f32, _> = context.tensor_init(Shape::new(4, 2, 1, 1));
+        let input_view = input.view(.., (..).with_step(2), .., ..)?;
+        let input_view = input_view.view(.., 1.., -1, ..)?;
+        assert_eq!(input_view.shape(), composed.shape());
+        let third = TensorOp::blit(input_view, composed.view(.., .., .., ..)?)?;
+
+        assert!(input.view((..).with_step(2), .., .., ..).is_err());
+
+        let output_map = TensorGpu::init(&context, output.shape());
+        let stacked_map = TensorGpu::init(&context, stacked.shape());
+        let composed_map = TensorGpu::init(&context, composed.shape());
+
+        let mut encoder = context
+            .device
+            .create_command_encoder(&CommandEncoderDescriptor::default());
+
+        let ops = TensorOp::List(vec![first, second, third]);
+        let mut pass = encoder.begin_compute_pass(&ComputePassDescriptor::default());
+        pass.execute_tensor_op(&ops);
+        drop(pass);
+
+        encoder.copy_tensor(&output, &output_map)?;
+        encoder.copy_tensor(&stacked, &stacked_map)?;
+        encoder.copy_tensor(&composed, &composed_map)?;
+        context.queue.submit(Some(encoder.finish()));
+
+        let output_host = Vec::from(TensorCpu::from(output_map));
+        let stacked_host = Vec::from(TensorCpu::from(stacked_map));
+        let composed_host = Vec::from(TensorCpu::from(composed_map));
+
+        let rows = |rows: &[usize]| {
+            rows.iter()
+                .flat_map(|row| (0..4).map(move |x| (row * 4 + x) as f32))
+                .collect_vec()
+        };
+        assert_eq!(output_host, rows(&[6, 8, 10]));
+        assert_eq!(stacked_host, rows(&[1, 4, 7, 10]));
+        assert_eq!(composed_host, rows(&[8, 10]));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_fill() -> Result<(), anyhow::Error> {
+        let context = match create_context() {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+
+        let shape = Shape::new(8, 3, 2, 1);
+        let x: TensorGpu<f32, _> = context.tensor_from_data(shape, vec![1.0; shape.len()])?;
+        let y: TensorGpu<f32, _> = context.tensor_from_data(shape, vec![1.0; shape.len()])?;
+        let x_map = TensorGpu::init(&context, shape);
+        let y_map = TensorGpu::init(&context, shape);
+
+        // fill the last row of each batch, then the first half of the second batch
+        let ops = TensorOp::List(vec![
+            TensorOp::fill(x.view(.., -1, .., ..)?, 2.0)?,
+            TensorOp::fill(x.view(..4, .., 1, ..)?, f32::MIN)?,
+        ]);
+
+        let mut encoder = context
+            .device
+            .create_command_encoder(&CommandEncoderDescriptor::default());
+
+        let mut pass = encoder.begin_compute_pass(&ComputePassDescriptor::default());
+        pass.execute_tensor_op(&ops);
+        drop(pass);
+
+        encoder.clear_tensor(&y);
+        encoder.copy_tensor(&x, &x_map)?;
+        encoder.copy_tensor(&y, &y_map)?;
+        context.queue.submit(Some(encoder.finish()));
+
+        let x_host = Vec::from(TensorCpu::from(x_map));
+        let y_host = Vec::from(TensorCpu::from(y_map));
+
+        let expected = (0..shape.len())
+            .map(|index| {
+                let (x, y, z) = (index % 8, index / 8 % 3, index / 24);
+                match (x, y, z) {
+                    (0..=3, _, 1) => f32::MIN,
+                    (_, 2, _) => 2.0,
+                    _ => 1.0,
+                }
+            })
+            .collect_vec();
+        assert_eq!(x_host, expected);
+        assert_eq!(y_host, vec![0.0; shape.len()]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_cast() -> Result<(), anyhow::Error> {
+        let context = match create_context() {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+        fastrand::seed(42);
+
+        let shape = Shape::new(16, 3, 2, 1);
+        let x = (0..shape.len())
+            .map(|_| 10.0 * fastrand::f32() - 5.0)
+            .collect_vec();
+        let x_f32: TensorGpu<f32, _> = context.tensor_from_data(shape, x.clone())?;
+        let x_f16: TensorGpu<f16, _> = context.tensor_init(shape);
+        let y_f32: TensorGpu<f32, _> = context.tensor_init(shape);
+        let y_map = TensorGpu::init(&context, shape);
+
+        // only the second batch of the round trip result comes from a sub-view
+        let ops = TensorOp::List(vec![
+            TensorOp::cast(x_f32.view(.., .., .., ..)?, x_f16.view(.., .., .., ..)?)?,
+            TensorOp::cast(x_f16.view(.., .., 1, ..)?, y_f32.view(.., .., 1, ..)?)?,
+        ]);
+        assert!(TensorOp::cast(x_f32.view(.., .., .., ..)?, y_f32.view(.., .., .., ..)?).is_err());
+
+        let mut encoder = context
+            .device
+            .create_command_encoder(&CommandEncoderDescriptor::default());
+
+        let mut pass = encoder.begin_compute_pass(&ComputePassDescriptor::default());
+        pass.execute_tensor_op(&ops);
+        drop(pass);
+
+        encoder.copy_tensor(&y_f32, &y_map)?;
+        context.queue.submit(Some(encoder.finish()));
+
+        let y_host = Vec::from(TensorCpu::from(y_map));
+        let half = shape.len() / 2;
+        assert_eq!(y_host[..half], vec![0.0; half]);
+        for (a, b) in y_host[half..].iter().zip_eq(x[half..].iter()) {
+            assert_eq!(*a, f16::from_f32(*b).to_f32());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_blit_f32() -> Result<(), anyhow::Error> {
+        let context = match create_context() {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+        fastrand::seed(42);
+
+        let shape = Shape::new(16, 3, 2, 1);
+        let x = (0..shape.len())
+            .map(|_| 10.0 * fastrand::f32() - 5.0)
+            .collect_vec();
+        let x_f32: TensorGpu<f32, _> = context.tensor_from_data(shape, x.clone())?;
+        let x_f16: TensorGpu<f16, _> =
+            context.tensor_from_data(shape, x.iter().copied().map(f16::from_f32).collect_vec())?;
+        let y_f32: TensorGpu<f32, _> = context.tensor_init(shape);
+        let z_f32: TensorGpu<f32, _> = context.tensor_init(shape);
+        let y_map = TensorGpu::init(&context, shape);
+        let z_map = TensorGpu::init(&context, shape);
+
+        let ops = TensorOp::List(vec![
+            TensorOp::blit_f32(x_f32.view(.., .., .., ..)?, y_f32.view(.., .., .., ..)?)?,
+            TensorOp::blit_f32(x_f16.view(.., .., .., ..)?, z_f32.view(.., .., .., ..)?)?,
+        ]);
+
+        let mut encoder = context
+            .device
+            .create_command_encoder(&CommandEncoderDescriptor::default());
+
+        let mut pass = encoder.begin_compute_pass(&ComputePassDescriptor::default());
+        pass.execute_tensor_op(&ops);
+        drop(pass);
+
+        encoder.copy_tensor(&y_f32, &y_map)?;
+        encoder.copy_tensor(&z_f32, &z_map)?;
+        context.queue.submit(Some(encoder.finish()));
+
+        let y_host = Vec::from(TensorCpu::from(y_map));
+        let z_host = Vec::from(TensorCpu::from(z_map));
+        assert_eq!(y_host, x);
+        for (a, b) in z_host.iter().zip_eq(x.iter()) {
+            assert_eq!(*a, f16::from_f32(*b).to_f32());
+        }
+
+        Ok(())
+    }
 }
@@ -0,0 +1,197 @@
+use std::f32::consts::TAU;
+
+use wgpu::{CommandEncoderDescriptor, ComputePassDescriptor};
+
+use super::{
+    ops::{TensorOp, TensorPass},
+    shape::Shape,
+    ReadWrite, TensorCpu, TensorError, TensorGpu, TensorInit, Uniform,
+};
+use crate::context::Context;
+
+/// Tensors that can be filled with random numbers.
+///
+/// Both CPU and GPU tensors draw from the same counter-based generator,
+/// so the same `seed` gives the same uniform samples on either device.
+pub trait TensorRandom: Sized {
+    /// Sample from the uniform distribution over `[low, high)`.
+    fn rand_uniform(
+        context: &Context,
+        shape: Shape,
+        low: f32,
+        high: f32,
+        seed: u32,
+    ) -> Result<Self, TensorError>;
+
+    /// Sample from the normal distribution with given `mean` and `std`.
+    fn rand_normal(
+        context: &Context,
+        shape: Shape,
+        mean: f32,
+        std: f32,
+        seed: u32,
+    ) -> Result<Self, TensorError>;
+}
+
+/// PCG hash, matching the one in `rand.wgsl`.
+fn pcg(v: u32) -> u32 {
+    let state = v.wrapping_mul(747796405).wrapping_add(2891336453);
+    let word = ((state >> ((state >> 28) + 4)) ^ state).wrapping_mul(277803737);
+    (word >> 22) ^ word
+}
+
+fn rand(seed: u32, counter: u32) -> f32 {
+    (pcg(counter.wrapping_add(pcg(seed))) >> 8) as f32 / 16777216.0
+}
+
+impl TensorRandom for TensorCpu<'_, f32> {
+    fn rand_uniform(
+        context: &Context,
+        shape: Shape,
+        low: f32,
+        high: f32,
+        seed: u32,
+    ) -> Result<Self, TensorError> {
+        let data: Vec<_> = (0..shape.len() as u32)
+            .map(|index| {
+                let x = rand(seed, index);
+                low * (1.0 - x) + high * x
+            })
+            .collect();
+        Self::from_data(context, shape, data)
+    }
+
+    fn rand_normal(
+        context: &Context,
+        shape: Shape,
+        mean: f32,
+        std: f32,
+        seed: u32,
+    ) -> Result<Self, TensorError> {
+        let data: Vec<_> = (0..shape.len() as u32)
+            .map(|index| {
+                let u = 1.0 - rand(seed, 2 * index);
+                let v = rand(seed, 2 * index + 1);
+                mean + std * (-2.0 * u.ln()).sqrt() * (TAU * v).cos()
+            })
+            .collect();
+        Self::from_data(context, shape, data)
+    }
+}
+
+type RandOp = for<'a> fn(
+    &'a TensorGpu<u32, Uniform>,
+    &'a TensorGpu<f32, ReadWrite>,
+) -> Result<TensorOp, TensorError>;
+
+impl TensorGpu<f32, ReadWrite> {
+    fn rand(
+        context: &Context,
+        shape: Shape,
+        param: [f32; 2],
+        seed: u32,
+        op: RandOp,
+    ) -> Result<Self, TensorError> {
+        let param = [seed, param[0].to_bits(), param[1].to_bits(), 0];
+        let param = TensorGpu::<u32, Uniform>::from_params(context, &param)?;
+        let output: Self = context.tensor_init(shape);
+
+        let op = op(&param, &output)?;
+        let mut encoder = context
+            .device
+            .create_command_encoder(&CommandEncoderDescriptor::default());
+        let mut pass = encoder.begin_compute_pass(&ComputePassDescriptor::default());
+        pass.execute_tensor_op(&op);
+        drop(pass);
+        context.queue.submit(Some(encoder.finish()));
+
+        Ok(output)
+    }
+}
+
+impl TensorRandom for TensorGpu<f32, ReadWrite> {
+    fn rand_uniform(
+        context: &Context,
+        shape: Shape,
+        low: f32,
+        high: f32,
+        seed: u32,
+    ) -> Result<Self, TensorError> {
+        Self::rand(context, shape, [low, high], seed, |param, output| {
+            TensorOp::rand_uniform(param, output)
+        })
+    }
+
+    fn rand_normal(
+        context: &Context,
+        shape: Shape,
+        mean: f32,
+        std: f32,
+        seed: u32,
+    ) -> Result<Self, TensorError> {
+        Self::rand(context, shape, [mean, std], seed, |param, output| {
+            TensorOp::rand_normal(param, output)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use itertools::Itertools;
+    use wgpu::PowerPreference;
+
+    use crate::{
+        context::{Context, ContextBuilder, Instance},
+        tensor::{shape::Shape, ReadWrite, TensorCpu, TensorGpu},
+    };
+
+    fn create_context() -> Result<Context, anyhow::Error> {
+        let adapter = pollster::block_on(async {
+            let instance = Instance::new();
+            instance.adapter(PowerPreference::HighPerformance).await
+        })?;
+        let context = pollster::block_on(async { ContextBuilder::new(adapter).build().await })?;
+        Ok(context)
+    }
+
+    fn moments(x: &[f32]) -> (f32, f32) {
+        let mean = x.iter().sum::<f32>() / x.len() as f32;
+        let var = x.iter().map(|x| (x - mean).powi(2)).sum::<f32>() / x.len() as f32;
+        (mean, var.sqrt())
+    }
+
+    #[test]
+    fn test_rand() -> Result<(), anyhow::Error> {
+        let context = match create_context() {
+            Ok(context) => context,
+            Err(_) => return Ok(()),
+        };
+        let shape = Shape::new(1000, 10, 3, 1);
+
+        let cpu: TensorCpu<f32> = context.rand_uniform(shape, -1.0, 3.0, 42)?;
+        let gpu: TensorGpu<f32, ReadWrite> = context.rand_uniform(shape, -1.0, 3.0, 42)?;
+        let gpu = gpu.back().to_vec();
+        for (a, b) in cpu.iter().zip_eq(gpu.iter()) {
+            assert!((a - b).abs() < 1e-6, "{a} vs. {b}");
+        }
+        assert!(gpu.iter().all(|x| (-1.0..3.0).contains(x)));
+        let (mean, std) = moments(&gpu);
+        assert!((mean - 1.0).abs() < 0.05, "mean: {mean}");
+        assert!((std - 4.0 / 12.0f32.sqrt()).abs() < 0.05, "std: {std}");
+
+        let other: TensorCpu<f32> = context.rand_uniform(shape, -1.0, 3.0, 43)?;
+        assert_ne!(cpu.to_vec(), other.to_vec());
+
+        let cpu: TensorCpu<f32> = context.rand_normal(shape, 1.0, 2.0, 7)?;
+        let gpu: TensorGpu<f32, ReadWrite> = context.rand_normal(shape, 1.0, 2.0, 7)?;
+        let gpu = gpu.back().to_vec();
+        for (a, b) in cpu.iter().zip_eq(gpu.iter()) {
+            assert!((a - b).abs() < 1e-3, "{a} vs. {b}");
+        }
+        let (mean, std) = moments(&gpu);
+        assert!((mean - 1.0).abs() < 0.05, "mean: {mean}");
+        assert!((std - 2.0).abs() < 0.05, "std: {std}");
+
+        Ok(())
+    }
+}
@@ -6,10 +6,81 @@ use web_rwkv_derive::{Deref, DerefMut};
 
 use super::TensorError;
 
+pub use web_rwkv_derive::IntoBytes;
+
 pub trait IntoBytes {
     fn into_bytes(self) -> Vec<u8>;
 }
 
+/// Alignment and size of a type in the WGSL uniform address space, for packing structs of them with
+/// `#[derive(IntoBytes)]` the way kernels read them.
+///
+/// Structs that WGSL would pad between fields are rejected, so the padding must be spelled out:
+///
+/// ```compile_fail
+/// use web_rwkv::tensor::shape::{IntoBytes, Shape, UniformLayout};
+///
+/// #[derive(IntoBytes)]
+/// struct Params {
+///     scale: f32,
+///     // a `vec4<u32>` must start at a multiple of 16 bytes
+///     shape: Shape,
+/// }
+/// ```
+///
+/// ```
+/// use web_rwkv::tensor::shape::{IntoBytes, Shape, UniformLayout};
+///
+/// #[derive(IntoBytes)]
+/// struct Params {
+///     scale: f32,
+///     _pad0: u32,
+///     _pad1: [u32; 2],
+///     shape: Shape,
+/// }
+///
+/// assert_eq!(<Params as UniformLayout>::SIZE, 32);
+/// ```
+pub trait UniformLayout {
+    const ALIGN: usize;
+    const SIZE: usize;
+}
+
+macro_rules! impl_uniform_scalar {
+    ($($t:ty),*) => {$(
+        impl IntoBytes for $t {
+            fn into_bytes(self) -> Vec<u8> {
+                bytemuck::bytes_of(&self).to_vec()
+            }
+        }
+
+        impl UniformLayout for $t {
+            const ALIGN: usize = 4;
+            const SIZE: usize = 4;
+        }
+
+        // `vec2`, `vec3` and `vec4`
+        impl<const N: usize> IntoBytes for [$t; N] {
+            fn into_bytes(self) -> Vec<u8> {
+                bytemuck::cast_slice(&self).to_vec()
+            }
+        }
+
+        impl<const N: usize> UniformLayout for [$t; N] {
+            const ALIGN: usize = {
+                assert!(matches!(N, 2..=4), "only vectors of 2 to 4 items are supported");
+                match N {
+                    2 => 8,
+                    _ => 16,
+                }
+            };
+            const SIZE: usize = 4 * N;
+        }
+    )*};
+}
+
+impl_uniform_scalar!(f32, u32, i32);
+
 /// The shape of a [`Tensor`].
 /// Note that the fastest-moving axis occupies the lowest shape index, which is opposite to that in `torch`.
 #[derive(
@@ -30,6 +101,18 @@ impl Shape {
         shape
     }
 
+    /// The shape of a safetensors tensor, whose dimensions are stored outermost first.
+    pub fn from_safetensors(shape: &[usize]) -> Result<Self, TensorError> {
+        match *shape {
+            [] => Ok(Self::new(0, 0, 0, 0)),
+            [x] => Ok(Self::new(x, 1, 1, 1)),
+            [y, x] => Ok(Self::new(x, y, 1, 1)),
+            [z, y, x] => Ok(Self::new(x, y, z, 1)),
+            [w, z, y, x] => Ok(Self::new(x, y, z, w)),
+            _ => Err(TensorError::Deduce),
+        }
+    }
+
     pub fn len(&self) -> usize {
         self.0.into_iter().product()
     }
@@ -38,6 +121,46 @@ impl Shape {
         self.0.into_iter().any(|x| x == 0)
     }
 
+    /// The shape both `a` and `b` stretch to, when each of their dimensions either matches or is 1.
+    pub fn broadcast(a: Shape, b: Shape) -> Result<Shape, TensorError> {
+        let mut shape = a;
+        for (dim, &other) in shape.iter_mut().zip(b.iter()) {
+            *dim = match (*dim, other) {
+                (x, y) if x == y => x,
+                (1, y) => y,
+                (x, 1) => x,
+                _ => return Err(TensorError::Broadcast(a, b)),
+            };
+        }
+        Ok(shape)
+    }
+
+    /// Whether `a` and `b` can be broadcast together, see [`Shape::broadcast`].
+    pub fn is_broadcastable(a: Shape, b: Shape) -> bool {
+        Self::broadcast(a, b).is_ok()
+    }
+
+    /// Whether this shape stretches to `shape` without the latter changing.
+    pub fn broadcasts_to(&self, shape: Shape) -> bool {
+        Self::broadcast(*self, shape).is_ok_and(|broadcast| broadcast == shape)
+    }
+
+    /// Whether a tensor of this shape can be reshaped into `shape`, i.e., they hold as many elements.
+    pub fn is_reshapable(&self, shape: Shape) -> bool {
+        self.len() == shape.len()
+    }
+
+    /// Reshape into the given dimensions, keeping the number of elements; see [`TensorDimension::deduce`].
+    pub fn reshape(
+        &self,
+        x: TensorDimension,
+        y: TensorDimension,
+        z: TensorDimension,
+        w: TensorDimension,
+    ) -> Result<Shape, TensorError> {
+        TensorDimension::deduce(*self, x, y, z, w)
+    }
+
     /// Convert a shaped index into a linear index.
     pub fn shape_index(&self, indices: Shape) -> usize {
         Iterator::zip(self.0.into_iter().rev(), indices.0.into_iter().rev())
@@ -52,6 +175,12 @@ impl IntoBytes for Shape {
     }
 }
 
+/// Packed as a `vec4<u32>`.
+impl UniformLayout for Shape {
+    const ALIGN: usize = 16;
+    const SIZE: usize = 16;
+}
+
 impl std::cmp::PartialOrd for Shape {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
         use Ordering::Equal;
@@ -141,11 +270,81 @@ impl std::ops::SubAssign<Shape> for Shape {
 
 pub trait TensorSlice {
     fn shape_bounds(&self, shape: Shape) -> Result<(Shape, Shape), TensorError>;
+    fn shape_steps(&self) -> Shape;
     fn contiguous_bounds(&self, shape: Shape) -> Result<(usize, usize), TensorError>;
+
+    /// Like [`TensorSlice::shape_bounds`], but only checked in debug builds.
+    ///
+    /// # Safety
+    /// The slice must be within `shape`.
+    unsafe fn shape_bounds_unchecked(&self, shape: Shape) -> (Shape, Shape) {
+        // SAFETY: the caller guarantees the slice is within `shape`
+        unsafe { self.shape_bounds(shape).unwrap_unchecked() }
+    }
+
+    /// The shape of the slice, with steps taken into account.
+    fn sliced_shape(&self, shape: Shape) -> Result<Shape, TensorError> {
+        let (start, end) = self.shape_bounds(shape)?;
+        let step = self.shape_steps();
+        let mut shape = end - start;
+        for (dim, step) in shape.iter_mut().zip(step.iter()) {
+            *dim = dim.div_ceil(*step);
+        }
+        Ok(shape)
+    }
 }
 
 pub trait TensorAxis: Clone + PartialEq + Eq + Hash {
     fn bounds(&self, dim: usize) -> Result<(usize, usize), TensorError>;
+
+    /// Like [`TensorAxis::bounds`], but only checked in debug builds.
+    ///
+    /// # Safety
+    /// The axis must be within `dim`.
+    unsafe fn bounds_unchecked(&self, dim: usize) -> (usize, usize) {
+        // SAFETY: the caller guarantees the axis is within `dim`
+        unsafe { self.bounds(dim).unwrap_unchecked() }
+    }
+
+    /// Distance between two consecutive selected items along the axis.
+    fn step(&self) -> usize {
+        1
+    }
+
+    /// Select every `step`-th item within the axis bounds.
+    fn with_step(self, step: usize) -> Stepped<Self>
+    where
+        Self: Sized,
+    {
+        Stepped { axis: self, step }
+    }
+}
+
+/// An axis selecting every `step`-th item, created by [`TensorAxis::with_step`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Stepped<A: TensorAxis> {
+    pub axis: A,
+    pub step: usize,
+}
+
+impl<A: TensorAxis> TensorAxis for Stepped<A> {
+    fn bounds(&self, dim: usize) -> Result<(usize, usize), TensorError> {
+        match self.step {
+            0 => Err(TensorError::Step(0)),
+            _ => self.axis.bounds(dim),
+        }
+    }
+
+    #[inline]
+    unsafe fn bounds_unchecked(&self, dim: usize) -> (usize, usize) {
+        debug_assert_ne!(self.step, 0);
+        // SAFETY: the caller guarantees the axis is within `dim`
+        unsafe { self.axis.bounds_unchecked(dim) }
+    }
+
+    fn step(&self) -> usize {
+        self.step * self.axis.step()
+    }
 }
 
 #[inline]
@@ -157,24 +356,46 @@ fn check_bounds(dim: usize, start: usize, end: usize) -> Result<(usize, usize),
     }
 }
 
+/// Like [`check_bounds`], but only in debug builds.
+#[inline]
+fn debug_check_bounds(dim: usize, start: usize, end: usize) -> (usize, usize) {
+    debug_assert!(check_bounds(dim, start, end).is_ok());
+    (start, end)
+}
+
 impl TensorAxis for usize {
     fn bounds(&self, dim: usize) -> Result<(usize, usize), TensorError> {
         let start = *self;
         let end = start + 1;
         check_bounds(dim, start, end)
     }
+
+    #[inline]
+    unsafe fn bounds_unchecked(&self, dim: usize) -> (usize, usize) {
+        debug_check_bounds(dim, *self, *self + 1)
+    }
 }
 
 impl TensorAxis for std::ops::RangeFull {
     fn bounds(&self, dim: usize) -> Result<(usize, usize), TensorError> {
         Ok((0, dim))
     }
+
+    #[inline]
+    unsafe fn bounds_unchecked(&self, dim: usize) -> (usize, usize) {
+        (0, dim)
+    }
 }
 
 impl TensorAxis for std::ops::Range<usize> {
     fn bounds(&self, dim: usize) -> Result<(usize, usize), TensorError> {
         check_bounds(dim, self.start, self.end)
     }
+
+    #[inline]
+    unsafe fn bounds_unchecked(&self, dim: usize) -> (usize, usize) {
+        debug_check_bounds(dim, self.start, self.end)
+    }
 }
 
 impl TensorAxis for std::ops::RangeInclusive<usize> {
@@ -183,26 +404,149 @@ impl TensorAxis for std::ops::RangeInclusive<usize> {
         let end = self.end() + 1;
         check_bounds(dim, start, end)
     }
+
+    #[inline]
+    unsafe fn bounds_unchecked(&self, dim: usize) -> (usize, usize) {
+        debug_check_bounds(dim, *self.start(), self.end() + 1)
+    }
 }
 
 impl TensorAxis for std::ops::RangeFrom<usize> {
     fn bounds(&self, dim: usize) -> Result<(usize, usize), TensorError> {
         check_bounds(dim, self.start, dim)
     }
+
+    #[inline]
+    unsafe fn bounds_unchecked(&self, dim: usize) -> (usize, usize) {
+        debug_check_bounds(dim, self.start, dim)
+    }
 }
 
 impl TensorAxis for std::ops::RangeTo<usize> {
     fn bounds(&self, dim: usize) -> Result<(usize, usize), TensorError> {
         check_bounds(dim, 0, self.end)
     }
+
+    #[inline]
+    unsafe fn bounds_unchecked(&self, dim: usize) -> (usize, usize) {
+        debug_check_bounds(dim, 0, self.end)
+    }
 }
 
 impl TensorAxis for std::ops::RangeToInclusive<usize> {
     fn bounds(&self, dim: usize) -> Result<(usize, usize), TensorError> {
         check_bounds(dim, 0, self.end + 1)
     }
+
+    #[inline]
+    unsafe fn bounds_unchecked(&self, dim: usize) -> (usize, usize) {
+        debug_check_bounds(dim, 0, self.end + 1)
+    }
+}
+
+/// Resolve a possibly negative index, which counts from the end of the axis.
+#[inline]
+fn resolve_index(dim: usize, index: isize) -> usize {
+    match index {
+        index if index < 0 => dim.saturating_sub(index.unsigned_abs()),
+        index => index as usize,
+    }
+}
+
+macro_rules! impl_signed_axis {
+    ($($t:ty),*) => {$(
+        impl TensorAxis for $t {
+            fn bounds(&self, dim: usize) -> Result<(usize, usize), TensorError> {
+                let index = *self as isize;
+                if index < 0 && index.unsigned_abs() > dim {
+                    return Err(TensorError::IndexOutOfRange { dim, index });
+                }
+                let start = resolve_index(dim, index);
+                check_bounds(dim, start, start + 1)
+            }
+
+            #[inline]
+            unsafe fn bounds_unchecked(&self, dim: usize) -> (usize, usize) {
+                debug_assert!(self.bounds(dim).is_ok());
+                let start = resolve_index(dim, *self as isize);
+                (start, start + 1)
+            }
+        }
+
+        impl TensorAxis for std::ops::Range<$t> {
+            fn bounds(&self, dim: usize) -> Result<(usize, usize), TensorError> {
+                let start = resolve_index(dim, self.start as isize);
+                let end = resolve_index(dim, self.end as isize);
+                check_bounds(dim, start, end)
+            }
+
+            #[inline]
+            unsafe fn bounds_unchecked(&self, dim: usize) -> (usize, usize) {
+                let start = resolve_index(dim, self.start as isize);
+                let end = resolve_index(dim, self.end as isize);
+                debug_check_bounds(dim, start, end)
+            }
+        }
+
+        impl TensorAxis for std::ops::RangeInclusive<$t> {
+            fn bounds(&self, dim: usize) -> Result<(usize, usize), TensorError> {
+                let start = resolve_index(dim, *self.start() as isize);
+                let end = resolve_index(dim, *self.end() as isize) + 1;
+                check_bounds(dim, start, end)
+            }
+
+            #[inline]
+            unsafe fn bounds_unchecked(&self, dim: usize) -> (usize, usize) {
+                let start = resolve_index(dim, *self.start() as isize);
+                let end = resolve_index(dim, *self.end() as isize) + 1;
+                debug_check_bounds(dim, start, end)
+            }
+        }
+
+        impl TensorAxis for std::ops::RangeFrom<$t> {
+            fn bounds(&self, dim: usize) -> Result<(usize, usize), TensorError> {
+                let start = resolve_index(dim, self.start as isize);
+                check_bounds(dim, start, dim)
+            }
+
+            #[inline]
+            unsafe fn bounds_unchecked(&self, dim: usize) -> (usize, usize) {
+                let start = resolve_index(dim, self.start as isize);
+                debug_check_bounds(dim, start, dim)
+            }
+        }
+
+        impl TensorAxis for std::ops::RangeTo<$t> {
+            fn bounds(&self, dim: usize) -> Result<(usize, usize), TensorError> {
+                let end = resolve_index(dim, self.end as isize);
+                check_bounds(dim, 0, end)
+            }
+
+            #[inline]
+            unsafe fn bounds_unchecked(&self, dim: usize) -> (usize, usize) {
+                let end = resolve_index(dim, self.end as isize);
+                debug_check_bounds(dim, 0, end)
+            }
+        }
+
+        impl TensorAxis for std::ops::RangeToInclusive<$t> {
+            fn bounds(&self, dim: usize) -> Result<(usize, usize), TensorError> {
+                let end = resolve_index(dim, self.end as isize) + 1;
+                check_bounds(dim, 0, end)
+            }
+
+            #[inline]
+            unsafe fn bounds_unchecked(&self, dim: usize) -> (usize, usize) {
+                let end = resolve_index(dim, self.end as isize) + 1;
+                debug_check_bounds(dim, 0, end)
+            }
+        }
+    )*};
 }
 
+// signed indices count from the end of the axis when negative, e.g., `-1` is the last item
+impl_signed_axis!(i32, isize);
+
 // impl<T: std::ops::RangeBounds<usize>> TensorAxis for T {
 //     fn bounds(&self, dim: usize) -> Result<(usize, usize), TensorError> {
 //         let start = match self.start_bound() {
@@ -252,6 +596,24 @@ where
         Ok((start, end))
     }
 
+    #[inline]
+    unsafe fn shape_bounds_unchecked(&self, shape: Shape) -> (Shape, Shape) {
+        let mut start = Shape::default();
+        let mut end = Shape::default();
+        // SAFETY: the caller guarantees the slice is within `shape`
+        unsafe {
+            (start[0], end[0]) = self.0.bounds_unchecked(shape[0]);
+            (start[1], end[1]) = self.1.bounds_unchecked(shape[1]);
+            (start[2], end[2]) = self.2.bounds_unchecked(shape[2]);
+            (start[3], end[3]) = self.3.bounds_unchecked(shape[3]);
+        }
+        (start, end)
+    }
+
+    fn shape_steps(&self) -> Shape {
+        Shape::new(self.0.step(), self.1.step(), self.2.step(), self.3.step())
+    }
+
     fn contiguous_bounds(&self, shape: Shape) -> Result<(usize, usize), TensorError> {
         use SliceFillState::{Full, NotFull};
         use SliceQuantState::{One, Plural, Zero};
@@ -268,7 +630,19 @@ where
             _ => NotFull,
         };
 
-        let (start, end) = self.shape_bounds(shape)?;
+        // a stepped axis is only contiguous if it selects no more than one item
+        let (start, _) = self.shape_bounds(shape)?;
+        let sliced = self.sliced_shape(shape)?;
+        let step = self.shape_steps();
+        if sliced
+            .iter()
+            .zip(step.iter())
+            .any(|(&dim, &step)| dim > 1 && step > 1)
+        {
+            return Err(TensorError::Contiguous);
+        }
+        let end = start + sliced;
+
         let (_, valid) = start.iter().zip(end.iter()).zip(shape.iter()).fold(
             (Full, true),
             |(state, valid), ((&start, &end), &dim)| match (state, valid) {
@@ -330,10 +704,10 @@ mod tests {
     use itertools::Itertools;
     use wgpu::PowerPreference;
 
-    use super::{Shape, TensorSlice};
+    use super::{IntoBytes, Shape, TensorAxis, TensorDimension, TensorSlice, UniformLayout};
     use crate::{
         context::{Context, ContextBuilder, Instance},
-        tensor::{TensorCpu, TensorInit},
+        tensor::{TensorCpu, TensorError, TensorInit, TensorShape},
     };
 
     fn create_context() -> Result<Context, anyhow::Error> {
@@ -341,12 +715,7 @@ mod tests {
             let instance = Instance::new();
             instance.adapter(PowerPreference::HighPerformance).await
         })?;
-        let context = pollster::block_on(async {
-            ContextBuilder::new(adapter)
-                .with_default_pipelines()
-                .build()
-                .await
-        })?;
+        let context = pollster::block_on(async { ContextBuilder::new(adapter).build().await })?;
         Ok(context)
     }
 
@@ -358,6 +727,62 @@ mod tests {
         assert_eq!(index, 35 + 42 * 1024 + 9 * 1024 * 768);
     }
 
+    #[test]
+    fn test_into_bytes() {
+        #[derive(IntoBytes)]
+        struct Params {
+            scale: f32,
+            pad: u32,
+            offset: [u32; 2],
+            shape: Shape,
+            bias: f32,
+        }
+
+        // the struct is padded to 16 bytes after `bias`
+        assert_eq!(<Params as UniformLayout>::SIZE, 48);
+        let bytes = Params {
+            scale: 1.0,
+            pad: 0,
+            offset: [5, 6],
+            shape: Shape::new(1, 2, 3, 4),
+            bias: 2.0,
+        }
+        .into_bytes();
+        assert_eq!(bytes.len(), 48);
+
+        let words: &[u32] = bytemuck::cast_slice(&bytes);
+        assert_eq!(&words[..4], &[1.0f32.to_bits(), 0, 5, 6]);
+        assert_eq!(&words[4..8], &[1, 2, 3, 4]);
+        assert_eq!(&words[8..12], &[2.0f32.to_bits(), 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_broadcast() -> Result<(), TensorError> {
+        let a = Shape::new(64, 1, 4, 1);
+        let b = Shape::new(64, 8, 1, 1);
+        assert_eq!(Shape::broadcast(a, b)?, Shape::new(64, 8, 4, 1));
+        assert!(Shape::is_broadcastable(b, a));
+        assert!(!a.broadcasts_to(b));
+        assert!(a.broadcasts_to(Shape::new(64, 8, 4, 1)));
+
+        let c = Shape::new(32, 8, 1, 1);
+        assert_eq!(Shape::broadcast(b, c), Err(TensorError::Broadcast(b, c)));
+        assert!(!Shape::is_broadcastable(a, c));
+
+        use TensorDimension::{Auto, Dimension, Full};
+        assert!(a.is_reshapable(Shape::new(256, 1, 1, 1)));
+        assert!(!a.is_reshapable(b));
+        assert_eq!(
+            a.reshape(Dimension(16), Auto, Full, Full)?,
+            Shape::new(16, 4, 4, 1)
+        );
+        assert_eq!(
+            a.reshape(Dimension(48), Auto, Full, Full),
+            Err(TensorError::Size(192, 256))
+        );
+        Ok(())
+    }
+
     #[test]
     fn test_slice() -> Result<(), anyhow::Error> {
         let context = match create_context() {
@@ -401,6 +826,36 @@ mod tests {
         let y: Vec<_> = x.slice(.., .., 1..2, ..)?.into();
         assert_eq!(y, vec![8.0, 9.0, 10.0, 11.0, 12.0, 13.0, 14.0, 15.0]);
 
+        let y: Vec<_> = x.slice(.., 0..2, (1..3).with_step(2), ..)?.into();
+        assert_eq!(y, vec![8.0, 9.0, 10.0, 11.0, 12.0, 13.0, 14.0, 15.0]);
+        assert_eq!(
+            (.., (0..2).with_step(2), (0..3).with_step(2), ..).sliced_shape(shape)?,
+            Shape::new(4, 1, 2, 1)
+        );
+        assert!(x.slice(.., 1, (0..3).with_step(2), ..).is_err());
+        assert!(x.slice(.., (0..2).with_step(0), .., ..).is_err());
+
+        let y: Vec<_> = x.slice(.., -1, -2, ..)?.into();
+        assert_eq!(y, vec![12.0, 13.0, 14.0, 15.0]);
+
+        let y: Vec<_> = x.slice(.., .., -2.., ..)?.into();
+        assert_eq!(y, (8..24).map(|x| x as f32).collect_vec());
+        assert_eq!(
+            (..=-2, -3..-1, .., ..).shape_bounds(shape)?,
+            (Shape::new(0, 0, 0, 0), Shape::new(3, 1, 3, 1))
+        );
+        assert!(x.slice(.., -3, .., ..).is_err());
+
+        // the unchecked slices match the checked ones
+        let y: Vec<_> = unsafe { x.slice_unchecked(.., 1, -1, ..) }.into();
+        assert_eq!(y, Vec::from(x.slice(.., 1, -1, ..)?));
+        let y = unsafe { x.slice_unchecked(.., 0..2, (1..3).with_step(2), ..) };
+        y.check_shape(Shape::new(4, 2, 1, 1))?;
+        assert_eq!(
+            y.to_vec(),
+            x.slice(.., 0..2, (1..3).with_step(2), ..)?.to_vec()
+        );
+
         let y: Vec<_> = x.into_slice(2.., 1.., ..0, ..)?.into();
         assert_eq!(y, Vec::<f32>::new());
 
@@ -1,12 +1,14 @@
 use ahash::{AHashMap as HashMap, AHashSet as HashSet};
 use derive_getters::Getters;
-use std::collections::BTreeMap;
+use std::{collections::BTreeMap, ops::Range};
 
 #[derive(Debug)]
 pub enum TokenizerError {
     FailedToParseVocabulary(serde_json::Error),
     NoMatchingTokenFound,
     OutOfRangeToken(u16),
+    /// The data is not an index saved by [`Tokenizer::to_bytes`], or of another version.
+    InvalidIndex,
 }
 
 impl std::fmt::Display for TokenizerError {
@@ -21,6 +23,9 @@ impl std::fmt::Display for TokenizerError {
             TokenizerError::OutOfRangeToken(token) => {
                 write!(fmt, "out of range token: {token}")?;
             }
+            TokenizerError::InvalidIndex => {
+                write!(fmt, "invalid tokenizer index")?;
+            }
         }
 
         Ok(())
@@ -104,17 +109,141 @@ impl Tokenizer {
         })
     }
 
+    /// Magic bytes and version at the start of an index saved by [`Tokenizer::to_bytes`].
+    pub const INDEX_MAGIC: &'static [u8; 8] = b"RWKVTOK1";
+
+    /// Save the built matcher to a compact binary index, which [`Tokenizer::from_bytes`] loads
+    /// much faster than [`Tokenizer::new`] builds it from the JSON vocabulary.
+    ///
+    /// The index holds the vocabulary, then the token lengths tried for each pair of first bytes,
+    /// all in little-endian, e.g. to ship it next to the vocabulary or cache it on first launch.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut output = Self::INDEX_MAGIC.to_vec();
+
+        let vocab: Vec<_> = self
+            .token_index_to_bytes
+            .iter()
+            .enumerate()
+            .filter(|(_, bytes)| !bytes.is_empty())
+            .collect();
+        output.extend((vocab.len() as u32).to_le_bytes());
+        for (token, bytes) in vocab {
+            output.extend((token as u16).to_le_bytes());
+            output.extend((bytes.len() as u32).to_le_bytes());
+            output.extend(bytes);
+        }
+
+        // only pairs starting longer tokens are stored, the rest try single bytes
+        let lengths: Vec<_> = self
+            .first_bytes_to_lengths
+            .iter()
+            .enumerate()
+            .filter(|(_, lengths)| lengths[..] != [1])
+            .collect();
+        output.extend((lengths.len() as u32).to_le_bytes());
+        for (key, lengths) in lengths {
+            output.extend((key as u16).to_le_bytes());
+            output.extend((lengths.len() as u16).to_le_bytes());
+            for length in lengths.iter() {
+                output.extend(length.to_le_bytes());
+            }
+        }
+        output
+    }
+
+    /// Load a matcher saved by [`Tokenizer::to_bytes`].
+    pub fn from_bytes(data: &[u8]) -> Result<Self, TokenizerError> {
+        struct Reader<'a>(&'a [u8]);
+
+        impl<'a> Reader<'a> {
+            fn take(&mut self, len: usize) -> Result<&'a [u8], TokenizerError> {
+                if len > self.0.len() {
+                    return Err(TokenizerError::InvalidIndex);
+                }
+                let (head, tail) = self.0.split_at(len);
+                self.0 = tail;
+                Ok(head)
+            }
+
+            fn u16(&mut self) -> Result<u16, TokenizerError> {
+                let bytes = self.take(2)?;
+                Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+            }
+
+            fn u32(&mut self) -> Result<u32, TokenizerError> {
+                let bytes = self.take(4)?;
+                Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+            }
+        }
+
+        let mut reader = Reader(data);
+        if reader.take(Self::INDEX_MAGIC.len())? != Self::INDEX_MAGIC {
+            return Err(TokenizerError::InvalidIndex);
+        }
+
+        let mut token_index_to_bytes = Vec::new();
+        token_index_to_bytes.resize_with(u16::MAX as usize, Vec::new);
+        let mut bytes_to_token_index = HashMap::new();
+        for _ in 0..reader.u32()? {
+            let token = reader.u16()?;
+            let len = reader.u32()? as usize;
+            let bytes = reader.take(len)?.to_vec();
+            let entry = token_index_to_bytes
+                .get_mut(token as usize)
+                .ok_or(TokenizerError::InvalidIndex)?;
+            bytes_to_token_index.insert(bytes.clone(), token);
+            *entry = bytes;
+        }
+
+        let single: Box<[u16]> = Box::new([1]);
+        let mut first_bytes_to_lengths = vec![single; u16::MAX as usize];
+        for _ in 0..reader.u32()? {
+            let key = reader.u16()? as usize;
+            let len = reader.u16()?;
+            let lengths = (0..len).map(|_| reader.u16()).collect::<Result<_, _>>()?;
+            *first_bytes_to_lengths
+                .get_mut(key)
+                .ok_or(TokenizerError::InvalidIndex)? = lengths;
+        }
+
+        if !reader.0.is_empty() {
+            return Err(TokenizerError::InvalidIndex);
+        }
+        Ok(Self {
+            first_bytes_to_lengths,
+            bytes_to_token_index,
+            token_index_to_bytes,
+        })
+    }
+
     pub fn encode(&self, input: &[u8]) -> Result<Vec<u16>, TokenizerError> {
         let mut output = Vec::new();
         self.encode_into(input, &mut output)?;
         Ok(output)
     }
 
-    pub fn encode_into(
+    pub fn encode_into(&self, input: &[u8], output: &mut Vec<u16>) -> Result<(), TokenizerError> {
+        self.encode_each(input, |token, _| output.push(token))
+    }
+
+    /// Encode `input`, along with the span in bytes of the input each token covers, e.g. for highlighting tokens
+    /// or re-encoding only the part of a text after an edit.
+    pub fn encode_with_offsets(
+        &self,
+        input: &[u8],
+    ) -> Result<Vec<(u16, Range<usize>)>, TokenizerError> {
+        let mut output = Vec::new();
+        self.encode_each(input, |token, span| output.push((token, span)))?;
+        Ok(output)
+    }
+
+    /// Encode `input` greedily, calling `f` with each token and its span in the input.
+    fn encode_each(
         &self,
         mut input: &[u8],
-        output: &mut Vec<u16>,
+        mut f: impl FnMut(u16, Range<usize>),
     ) -> Result<(), TokenizerError> {
+        let mut offset = 0;
         'next_token: while !input.is_empty() {
             let lengths = if input.len() >= 2 {
                 let key = u16::from_ne_bytes([input[0], input[1]]) as usize;
@@ -130,8 +259,9 @@ impl Tokenizer {
                 }
 
                 if let Some(&token_index) = self.bytes_to_token_index.get(&input[..length]) {
-                    output.push(token_index);
+                    f(token_index, offset..offset + length);
                     input = &input[length..];
+                    offset += length;
                     continue 'next_token;
                 }
             }
@@ -160,4 +290,93 @@ impl Tokenizer {
 
         Ok(())
     }
+
+    /// Decode `tokens` to the bytes of the vocabulary as they are, without any conversion to text,
+    /// for output that may not be valid UTF-8, e.g. from vocabularies with raw byte entries.
+    /// Tokens outside the vocabulary decode to nothing.
+    pub fn decode_bytes(&self, tokens: &[u16]) -> Vec<u8> {
+        self.decode_bytes_iter(tokens.iter().copied())
+            .flatten()
+            .copied()
+            .collect()
+    }
+
+    /// The bytes of each of `tokens` in turn, borrowed from the vocabulary, e.g. to pass sampled tokens on as they come.
+    /// A token may end in the middle of a UTF-8 character, so joining the text of each is up to the caller.
+    /// Tokens outside the vocabulary decode to nothing.
+    pub fn decode_bytes_iter<'a>(
+        &'a self,
+        tokens: impl IntoIterator<Item = u16> + 'a,
+    ) -> impl Iterator<Item = &'a [u8]> + 'a {
+        tokens.into_iter().map(|token| {
+            self.token_index_to_bytes
+                .get(token as usize)
+                .map_or(&[][..], Vec::as_slice)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Tokenizer;
+
+    #[test]
+    fn test_decode_bytes() -> Result<(), anyhow::Error> {
+        // "€" is split over two tokens, and one token is not UTF-8 at all
+        const VOCAB: &str = r#"{"1": "a", "2": [226, 130], "3": [172], "4": [255, 254]}"#;
+        let tokenizer = Tokenizer::new(VOCAB)?;
+
+        let tokens = [1, 2, 3, 4, 1];
+        let bytes = tokenizer.decode_bytes(&tokens);
+        assert_eq!(bytes, b"a\xe2\x82\xac\xff\xfea");
+        assert_eq!(bytes, tokenizer.decode(&tokens)?);
+        assert_eq!(tokenizer.decode_bytes(&[1, u16::MAX]), b"a");
+
+        let chunks: Vec<_> = tokenizer.decode_bytes_iter(tokens).collect();
+        assert_eq!(chunks[1], [226, 130]);
+        assert!(std::str::from_utf8(chunks[1]).is_err());
+        assert_eq!(chunks.concat(), bytes);
+        Ok(())
+    }
+
+    #[test]
+    fn test_index() -> Result<(), anyhow::Error> {
+        const VOCAB: &str = r#"{"1": "a", "2": "b", "3": "ab", "4": "€", "5": [255, 254]}"#;
+        let tokenizer = Tokenizer::new(VOCAB)?;
+        let data = tokenizer.to_bytes();
+        let loaded = Tokenizer::from_bytes(&data)?;
+
+        assert_eq!(loaded.token_index_to_bytes, tokenizer.token_index_to_bytes);
+        assert_eq!(loaded.bytes_to_token_index, tokenizer.bytes_to_token_index);
+        assert_eq!(
+            loaded.first_bytes_to_lengths,
+            tokenizer.first_bytes_to_lengths
+        );
+        let input = "aab€b".as_bytes();
+        assert_eq!(loaded.encode(input)?, tokenizer.encode(input)?);
+        assert_eq!(loaded.to_bytes(), data);
+
+        assert!(Tokenizer::from_bytes(&data[..data.len() - 1]).is_err());
+        assert!(Tokenizer::from_bytes(&[data.as_slice(), &[0]].concat()).is_err());
+        assert!(Tokenizer::from_bytes(VOCAB.as_bytes()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_encode_with_offsets() -> Result<(), anyhow::Error> {
+        const VOCAB: &str = r#"{"1": "a", "2": "b", "3": "ab", "4": "€"}"#;
+        let tokenizer = Tokenizer::new(VOCAB)?;
+
+        let input = "aab€b".as_bytes();
+        let encoded = tokenizer.encode_with_offsets(input)?;
+        assert_eq!(encoded, vec![(1, 0..1), (3, 1..3), (4, 3..6), (2, 6..7)]);
+        let tokens: Vec<_> = encoded.iter().map(|(token, _)| *token).collect();
+        assert_eq!(tokens, tokenizer.encode(input)?);
+        for (token, span) in encoded {
+            assert_eq!(tokenizer.decode(&[token])?, &input[span]);
+        }
+
+        assert!(tokenizer.encode_with_offsets(b"abc").is_err());
+        Ok(())
+    }
 }